@@ -0,0 +1,171 @@
+//! GICv2 virtualization support, for boards without a GICv3.
+//!
+//! Virtual interrupts are injected through the list registers of the GICH virtual interface
+//! control block, and MSIs of passthrough devices target the GICv2m frame if the GIC has one.
+//! Both are located through the host device tree.
+use alloc::vec::Vec;
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::HostPhysAddr;
+use fdt_parser::{Fdt, Node};
+use memory_addr::VirtAddr;
+
+use crate::vmm::fdt::get_host_fdt;
+
+const GIC_V2_COMPATIBLES: &[&str] = &[
+    "arm,gic-400",
+    "arm,cortex-a15-gic",
+    "arm,cortex-a9-gic",
+    "arm,cortex-a7-gic",
+];
+const GIC_V2M_COMPATIBLE: &str = "arm,gic-v2m-frame";
+
+/// Index of the GICH region in the `reg` property of a GICv2 node.
+const REG_INDEX_GICH: usize = 2;
+
+const GICH_HCR: usize = 0x000;
+const GICH_VTR: usize = 0x004;
+const GICH_ELRSR0: usize = 0x030;
+const GICH_LR0: usize = 0x100;
+
+const GICH_HCR_EN: u32 = 1 << 0;
+const GICH_LR_VIRTUAL_ID_MASK: u32 = 0x3ff;
+const GICH_LR_STATE_SHIFT: u32 = 28;
+const GICH_LR_STATE_PENDING: u32 = 0b01;
+
+const V2M_MSI_TYPER: usize = 0x008;
+/// Offset of the doorbell register in a GICv2m frame.
+pub const V2M_MSI_SETSPI_NS: usize = 0x040;
+
+/// A GICv2m MSI frame.
+#[derive(Debug, Clone, Copy)]
+pub struct V2mFrame {
+    /// Physical base of the frame.
+    pub base: HostPhysAddr,
+    /// First SPI the frame raises.
+    pub spi_base: usize,
+    /// Number of SPIs the frame raises.
+    pub num_spis: usize,
+}
+
+impl V2mFrame {
+    /// The address devices write to raise an SPI.
+    pub fn doorbell(&self) -> u64 {
+        (self.base.as_usize() + V2M_MSI_SETSPI_NS) as u64
+    }
+
+    /// Whether the frame can raise `irq`.
+    pub fn contains(&self, irq: usize) -> bool {
+        (self.spi_base..self.spi_base + self.num_spis).contains(&irq)
+    }
+}
+
+struct GicV2 {
+    gich: VirtAddr,
+    num_lrs: usize,
+    v2m: Option<V2mFrame>,
+}
+
+static GIC_V2: spin::Once<Option<GicV2>> = spin::Once::new();
+
+fn is_compatible(node: &Node, compatibles: &[&str]) -> bool {
+    node.propertys()
+        .filter(|prop| prop.name == "compatible")
+        .any(|prop| {
+            prop.raw_value()
+                .split(|b| *b == 0)
+                .any(|s| compatibles.iter().any(|c| c.as_bytes() == s))
+        })
+}
+
+fn read_u32(base: VirtAddr, offset: usize) -> u32 {
+    unsafe { ((base.as_usize() + offset) as *const u32).read_volatile() }
+}
+
+fn write_u32(base: VirtAddr, offset: usize, val: u32) {
+    unsafe { ((base.as_usize() + offset) as *mut u32).write_volatile(val) }
+}
+
+fn probe() -> Option<GicV2> {
+    let fdt = Fdt::from_bytes(get_host_fdt()).ok()?;
+    let nodes: Vec<Node> = fdt.all_nodes().collect();
+    let gic = nodes
+        .iter()
+        .find(|node| is_compatible(node, GIC_V2_COMPATIBLES))?;
+    let Some(gich_base) = gic.reg().and_then(|mut reg| reg.nth(REG_INDEX_GICH)) else {
+        warn!("GICv2 {} has no GICH region", gic.name());
+        return None;
+    };
+    let gich = axhal::mem::phys_to_virt((gich_base.address as usize).into());
+    let num_lrs = (read_u32(gich, GICH_VTR) & 0x3f) as usize + 1;
+
+    let v2m = nodes
+        .iter()
+        .find(|node| is_compatible(node, &[GIC_V2M_COMPATIBLE]))
+        .and_then(|node| node.reg()?.next())
+        .map(|reg| {
+            let base = HostPhysAddr::from(reg.address as usize);
+            let typer = read_u32(axhal::mem::phys_to_virt(base), V2M_MSI_TYPER);
+            V2mFrame {
+                base,
+                spi_base: ((typer >> 16) & 0x3ff) as usize,
+                num_spis: (typer & 0x3ff) as usize,
+            }
+        });
+
+    info!(
+        "GICv2 GICH at {:#x} with {} list registers, v2m {:x?}",
+        gich_base.address, num_lrs, v2m
+    );
+    Some(GicV2 { gich, num_lrs, v2m })
+}
+
+fn gic_v2() -> Option<&'static GicV2> {
+    GIC_V2.call_once(probe).as_ref()
+}
+
+/// Returns the GICv2m frame of the host GIC, if it is a GICv2 with one.
+pub fn v2m_frame() -> Option<V2mFrame> {
+    gic_v2().and_then(|gic| gic.v2m)
+}
+
+/// Injects `vector` into the current vCPU through a free list register.
+pub fn inject_interrupt_gic_v2(vector: usize) {
+    let Some(gic) = gic_v2() else {
+        panic!("GICv2 virtual interface not found in the host device tree");
+    };
+    debug!("Injecting virtual interrupt: vector={vector}");
+
+    let elrsr =
+        read_u32(gic.gich, GICH_ELRSR0) as u64 | (read_u32(gic.gich, GICH_ELRSR0 + 4) as u64) << 32;
+    let mut free_lr = None;
+    for i in 0..gic.num_lrs {
+        if elrsr & (1 << i) != 0 {
+            free_lr.get_or_insert(i);
+            continue;
+        }
+        let lr = read_u32(gic.gich, GICH_LR0 + i * 4);
+        if (lr & GICH_LR_VIRTUAL_ID_MASK) as usize == vector
+            && lr >> GICH_LR_STATE_SHIFT & 0b11 != 0
+        {
+            debug!("Virtual interrupt {vector} already pending/active in LR{i}, skipping");
+            return;
+        }
+    }
+
+    let Some(lr) = free_lr else {
+        warn!("No free list register to inject IRQ {vector}, dropped");
+        return;
+    };
+    // Group 0, priority 0, software interrupt.
+    write_u32(
+        gic.gich,
+        GICH_LR0 + lr * 4,
+        (vector as u32 & GICH_LR_VIRTUAL_ID_MASK) | GICH_LR_STATE_PENDING << GICH_LR_STATE_SHIFT,
+    );
+    let hcr = read_u32(gic.gich, GICH_HCR);
+    if hcr & GICH_HCR_EN == 0 {
+        write_u32(gic.gich, GICH_HCR, hcr | GICH_HCR_EN);
+    }
+    debug!("Virtual interrupt {vector} injected successfully in LR{lr}");
+}
@@ -1,7 +1,13 @@
 use aarch64_cpu_ext::registers::*;
 
+use crate::vmm::VCpuRef;
+
 mod api;
 pub mod cache;
+pub mod gicv2;
+pub mod pmu;
+pub mod spe;
+pub mod stage2;
 
 pub fn inject_interrupt(irq: usize) {
     debug!("Injecting virtual interrupt: {irq}");
@@ -10,25 +16,8 @@ pub fn inject_interrupt(irq: usize) {
         .expect("Failed to get GIC driver")
         .lock()
         .unwrap();
-    if let Some(gic) = gic.typed_mut::<arm_gic_driver::v2::Gic>() {
-        use arm_gic_driver::{
-            IntId,
-            v2::{VirtualInterruptConfig, VirtualInterruptState},
-        };
-
-        let gich = gic.hypervisor_interface().expect("Failed to get GICH");
-        gich.enable();
-        gich.set_virtual_interrupt(
-            0,
-            VirtualInterruptConfig::software(
-                unsafe { IntId::raw(irq as _) },
-                None,
-                0,
-                VirtualInterruptState::Pending,
-                false,
-                true,
-            ),
-        );
+    if gic.typed_mut::<arm_gic_driver::v2::Gic>().is_some() {
+        gicv2::inject_interrupt_gic_v2(irq);
         return;
     }
 
@@ -107,6 +96,139 @@ pub fn inject_interrupt_gic_v3(vector: usize) {
     debug!("Virtual interrupt {vector} injected successfully in LR{free_lr}");
 }
 
+/// Issues the SMC `fid` with `args` in `x1`-`x6` and the client ID `client` in `w7` to the
+/// firmware, returns `x0`-`x3`.
+pub fn smc_call(fid: u64, args: [u64; 6], client: u16) -> [u64; 4] {
+    let mut ret = [fid, args[0], args[1], args[2]];
+    // SAFETY: SMC Calling Convention 1.1: the callee may clobber `x4`-`x17` and preserves the rest.
+    unsafe {
+        core::arch::asm!(
+            "smc #0",
+            inout("x0") ret[0],
+            inout("x1") ret[1],
+            inout("x2") ret[2],
+            inout("x3") ret[3],
+            inout("x4") args[3] => _,
+            inout("x5") args[4] => _,
+            inout("x6") args[5] => _,
+            inout("x7") client as u64 => _,
+            out("x8") _,
+            out("x9") _,
+            out("x10") _,
+            out("x11") _,
+            out("x12") _,
+            out("x13") _,
+            out("x14") _,
+            out("x15") _,
+            out("x16") _,
+            out("x17") _,
+        );
+    }
+    ret
+}
+
+/// Whether the CPU has a GICv4.1 CPU interface, able to take virtual LPIs directly.
+pub fn has_gicv4() -> bool {
+    // ID_AA64PFR0_EL1.GIC, 0b0011 for the GICv4.1 system register interface.
+    (ID_AA64PFR0_EL1.get() >> 24) & 0xf >= 0b0011
+}
+
+/// Level of nested virtualization support of the CPU: 0 for none, 1 for FEAT_NV, 2 for FEAT_NV2.
+pub fn nested_virt_level() -> u8 {
+    // ID_AA64MMFR2_EL1.NV.
+    ((ID_AA64MMFR2_EL1.get() >> 24) & 0xf) as u8
+}
+
+/// Returns a random number from the generator of the CPU (FEAT_RNG), `None` without one or if it
+/// failed to produce a number in reasonable time.
+pub fn hw_random() -> Option<u64> {
+    // ID_AA64ISAR0_EL1.RNDR.
+    if (ID_AA64ISAR0_EL1.get() >> 60) & 0xf == 0 {
+        return None;
+    }
+    let (value, ok): (u64, u64);
+    // SAFETY: reading RNDR has no side effect, it clears NZCV.Z on success.
+    unsafe {
+        core::arch::asm!(
+            "mrs {value}, s3_3_c2_c4_0",
+            "cset {ok}, ne",
+            value = out(reg) value,
+            ok = out(reg) ok,
+            options(nomem, nostack),
+        );
+    }
+    (ok != 0).then_some(value)
+}
+
+/// Returns when the virtual timer of the guest which last ran on this CPU fires, in ticks of the
+/// physical counter, `None` if the timer is disabled or masked. Only meaningful right after the
+/// guest exits, the vCPU leaves its timer in place until another vCPU enters.
+pub fn guest_vtimer_deadline() -> Option<u64> {
+    let (ctl, cval, offset): (u64, u64, u64);
+    // SAFETY: reading the timer registers has no side effect.
+    unsafe {
+        core::arch::asm!(
+            "mrs {ctl}, cntv_ctl_el0",
+            "mrs {cval}, cntv_cval_el0",
+            "mrs {offset}, cntvoff_el2",
+            ctl = out(reg) ctl,
+            cval = out(reg) cval,
+            offset = out(reg) offset,
+            options(nomem, nostack),
+        );
+    }
+    // CNTV_CTL_EL0.ENABLE set, IMASK clear.
+    if ctl & 0b11 != 0b01 {
+        return None;
+    }
+    cval.checked_add(offset)
+}
+
+/// Number of registers in [`GuestRegs`].
+pub const GUEST_REGS: usize = 34;
+
+/// The registers of a guest, in the order of the `user_regs_struct` of aarch64 ELF core files:
+/// `x0`-`x30`, `sp`, `pc` and `pstate`.
+pub type GuestRegs = [u64; GUEST_REGS];
+
+/// The frame the backend saves the registers of the guest to on each exit, at the start of its
+/// `repr(C)` vCPU, where its exit path expects it (`Aarch64ContextFrame` of `arm_vcpu`).
+#[repr(C)]
+struct GuestFrame {
+    gpr: [u64; 31],
+    sp_el0: u64,
+    elr: u64,
+    spsr: u64,
+}
+
+/// Returns the registers of `vcpu`, which must be the last vCPU which ran on this CPU: the stack
+/// pointer of EL1 stays in the CPU until another vCPU enters.
+pub fn guest_regs(vcpu: &VCpuRef) -> Option<GuestRegs> {
+    let arch: *const _ = vcpu.get_arch_vcpu();
+    // SAFETY: the vCPU is not running and its trap frame is at its start, see `GuestFrame`.
+    let frame = unsafe { &*arch.cast::<GuestFrame>() };
+    let mut regs = [0; GUEST_REGS];
+    regs[..31].copy_from_slice(&frame.gpr);
+    // SPSR.M[0] selects SP_ELx over SP_EL0.
+    regs[31] = if frame.spsr & 1 != 0 {
+        let sp: u64;
+        // SAFETY: reading SP_EL1 at EL2 has no side effect.
+        unsafe { core::arch::asm!("mrs {}, sp_el1", out(reg) sp, options(nomem, nostack)) };
+        sp
+    } else {
+        frame.sp_el0
+    };
+    regs[32] = frame.elr;
+    regs[33] = frame.spsr;
+    Some(regs)
+}
+
+/// Requests the performance level `level` of this CPU, always `false`: the frequency of the CPUs
+/// belongs to the firmware (SCMI), which the hypervisor doesn't drive, the level is only recorded.
+pub fn set_cpu_perf(_level: u8) -> bool {
+    false
+}
+
 pub fn hardware_check() {
     let pa_bits = match ID_AA64MMFR0_EL1.read_as_enum(ID_AA64MMFR0_EL1::PARange) {
         Some(ID_AA64MMFR0_EL1::PARange::Value::Bits_32) => 32,
@@ -0,0 +1,153 @@
+//! PMUv3 registers, for the vPMU of [`crate::vmm::pmu`].
+//!
+//! The event counters are split between the guest and the hypervisor by `MDCR_EL2.HPMN`: the
+//! guest sees and programs the counters below it (and the cycle counter), the hypervisor keeps the
+//! others. The state of the guest part is saved and restored around the runs of its vCPUs. A
+//! counter of the hypervisor may count the instructions of the guests, see
+//! [`start_guest_instructions`].
+
+/// Most event counters of a PMUv3.
+pub const MAX_COUNTERS: usize = 31;
+/// The cycle counter bit of `PMCNTENSET_EL0` and friends.
+const CYCLE_COUNTER: u64 = 1 << 31;
+/// `MDCR_EL2.HPMN`.
+const MDCR_HPMN_MASK: u64 = 0x1f;
+/// `MDCR_EL2.HPMD`, guest counters don't count at EL2.
+const MDCR_HPMD: u64 = 1 << 17;
+
+macro_rules! read_sysreg {
+    ($reg:literal) => {{
+        let value: u64;
+        unsafe { core::arch::asm!(concat!("mrs {}, ", $reg), out(reg) value) };
+        value
+    }};
+}
+
+macro_rules! write_sysreg {
+    ($reg:literal, $value:expr) => {
+        unsafe { core::arch::asm!(concat!("msr ", $reg, ", {}"), in(reg) $value as u64) }
+    };
+}
+
+/// Number of event counters of the PMU, 0 without a PMUv3.
+pub fn counters() -> usize {
+    // ID_AA64DFR0_EL1.PMUVer, 0b1111 for an IMPLEMENTATION DEFINED PMU.
+    match (read_sysreg!("id_aa64dfr0_el1") >> 8) & 0xf {
+        0 | 0xf => 0,
+        // PMCR_EL0.N
+        _ => ((read_sysreg!("pmcr_el0") >> 11) & 0x1f) as usize,
+    }
+}
+
+/// The guest part of the PMU state of a vCPU.
+#[derive(Debug, Clone)]
+pub struct PmuState {
+    pub pmcr: u64,
+    pub cntenset: u64,
+    pub ovsset: u64,
+    pub intenset: u64,
+    pub ccntr: u64,
+    pub ccfiltr: u64,
+    pub userenr: u64,
+    pub evtypers: [u64; MAX_COUNTERS],
+    pub evcntrs: [u64; MAX_COUNTERS],
+}
+
+impl PmuState {
+    pub const fn new() -> Self {
+        Self {
+            pmcr: 0,
+            cntenset: 0,
+            ovsset: 0,
+            intenset: 0,
+            ccntr: 0,
+            ccfiltr: 0,
+            userenr: 0,
+            evtypers: [0; MAX_COUNTERS],
+            evcntrs: [0; MAX_COUNTERS],
+        }
+    }
+}
+
+fn guest_mask(counters: usize) -> u64 {
+    ((1 << counters) - 1) | CYCLE_COUNTER
+}
+
+/// Gives the first `counters` event counters and the cycle counter to the guest about to run on
+/// this CPU, with their state in `state`.
+pub fn load(state: &PmuState, counters: usize) {
+    let mask = guest_mask(counters);
+    let mdcr = read_sysreg!("mdcr_el2");
+    write_sysreg!(
+        "mdcr_el2",
+        (mdcr & !MDCR_HPMN_MASK) | counters as u64 | MDCR_HPMD
+    );
+    for idx in 0..counters {
+        write_sysreg!("pmselr_el0", idx);
+        unsafe { core::arch::asm!("isb") };
+        write_sysreg!("pmxevtyper_el0", state.evtypers[idx]);
+        write_sysreg!("pmxevcntr_el0", state.evcntrs[idx]);
+    }
+    write_sysreg!("pmccntr_el0", state.ccntr);
+    write_sysreg!("pmccfiltr_el0", state.ccfiltr);
+    write_sysreg!("pmuserenr_el0", state.userenr);
+    write_sysreg!("pmovsclr_el0", mask);
+    write_sysreg!("pmovsset_el0", state.ovsset & mask);
+    write_sysreg!("pmintenset_el1", state.intenset & mask);
+    write_sysreg!("pmcntenset_el0", state.cntenset & mask);
+    write_sysreg!("pmcr_el0", state.pmcr);
+}
+
+/// Saves the state of the guest counters in `state` after the guest left this CPU, stops them
+/// and hands all the counters back to the hypervisor.
+pub fn save(state: &mut PmuState, counters: usize) {
+    let mask = guest_mask(counters);
+    state.pmcr = read_sysreg!("pmcr_el0");
+    state.cntenset = read_sysreg!("pmcntenset_el0") & mask;
+    state.ovsset = read_sysreg!("pmovsset_el0") & mask;
+    state.intenset = read_sysreg!("pmintenset_el1") & mask;
+    state.ccntr = read_sysreg!("pmccntr_el0");
+    state.ccfiltr = read_sysreg!("pmccfiltr_el0");
+    state.userenr = read_sysreg!("pmuserenr_el0");
+    for idx in 0..counters {
+        write_sysreg!("pmselr_el0", idx);
+        unsafe { core::arch::asm!("isb") };
+        state.evtypers[idx] = read_sysreg!("pmxevtyper_el0");
+        state.evcntrs[idx] = read_sysreg!("pmxevcntr_el0");
+    }
+    write_sysreg!("pmcntenclr_el0", mask);
+    write_sysreg!("pmintenclr_el1", mask);
+    write_sysreg!("pmovsclr_el0", mask);
+    write_sysreg!("pmuserenr_el0", 0);
+    let mdcr = read_sysreg!("mdcr_el2");
+    write_sysreg!(
+        "mdcr_el2",
+        (mdcr & !(MDCR_HPMN_MASK | MDCR_HPMD)) | counters() as u64
+    );
+}
+
+/// `INST_RETIRED`, counts the instructions retired.
+const INST_RETIRED: u64 = 0x08;
+/// `MDCR_EL2.HPME`, enables the counters of the hypervisor.
+const MDCR_HPME: u64 = 1 << 7;
+
+/// Makes event counter `idx`, kept by the hypervisor, count the instructions retired at EL1 and
+/// EL0 on this CPU, i.e. by the guest about to run, and returns its value.
+pub fn start_guest_instructions(idx: usize) -> u32 {
+    let mdcr = read_sysreg!("mdcr_el2");
+    let hpmn = (mdcr & MDCR_HPMN_MASK).min(idx as u64);
+    write_sysreg!("mdcr_el2", (mdcr & !MDCR_HPMN_MASK) | hpmn | MDCR_HPME);
+    write_sysreg!("pmselr_el0", idx);
+    unsafe { core::arch::asm!("isb") };
+    // PMEVTYPER<n>_EL0.NSH clear, EL2 is not counted.
+    write_sysreg!("pmxevtyper_el0", INST_RETIRED);
+    write_sysreg!("pmcntenset_el0", 1u64 << idx);
+    read_sysreg!("pmxevcntr_el0") as u32
+}
+
+/// Returns the value of event counter `idx`.
+pub fn read_counter(idx: usize) -> u32 {
+    write_sysreg!("pmselr_el0", idx);
+    unsafe { core::arch::asm!("isb") };
+    read_sysreg!("pmxevcntr_el0") as u32
+}
@@ -0,0 +1,133 @@
+//! Statistical Profiling Extension registers, for the SPE passthrough of [`crate::vmm::spe`].
+//!
+//! The profiling buffer belongs to EL2 or to EL1 according to `MDCR_EL2.E2PB`. It is given to
+//! the guest, with the sampling controls, while a vCPU of the profiling VM runs, and taken back
+//! when the vCPU exits, the accesses of the other guests trapping. The registers are named by
+//! their encodings so that the assembler needs no SPE support.
+
+/// `MDCR_EL2.E2PB`.
+const MDCR_E2PB_MASK: u64 = 0b11 << 12;
+/// `MDCR_EL2.E2PB` value giving the buffer to EL1 without trapping its registers.
+const MDCR_E2PB_EL1: u64 = 0b11 << 12;
+/// `MDCR_EL2.TPMS`, accesses to the sampling controls trap.
+const MDCR_TPMS: u64 = 1 << 14;
+/// `PMBIDR_EL1.P`, the buffer is owned by a higher exception level.
+const PMBIDR_P: u64 = 1 << 4;
+/// `PMBSR_EL1.S`, the buffer management interrupt is asserted.
+pub const PMBSR_S: u64 = 1 << 17;
+/// `PMBSR_EL1.EC`.
+const PMBSR_EC_SHIFT: u64 = 26;
+const PMBSR_EC_MASK: u64 = 0x3f;
+/// `PMBSR_EL1.EC` of a stage-2 data abort on a buffer write.
+const PMBSR_EC_STAGE2_ABORT: u64 = 0b10_0101;
+
+macro_rules! read_sysreg {
+    ($reg:literal) => {{
+        let value: u64;
+        unsafe { core::arch::asm!(concat!("mrs {}, ", $reg), out(reg) value) };
+        value
+    }};
+}
+
+macro_rules! write_sysreg {
+    ($reg:literal, $value:expr) => {
+        unsafe { core::arch::asm!(concat!("msr ", $reg, ", {}"), in(reg) $value as u64) }
+    };
+}
+
+/// Whether the CPU implements SPE with a profiling buffer the hypervisor can give to guests.
+pub fn is_supported() -> bool {
+    // ID_AA64DFR0_EL1.PMSVer
+    (read_sysreg!("id_aa64dfr0_el1") >> 32) & 0xf != 0
+        // PMBIDR_EL1
+        && read_sysreg!("s3_0_c9_c10_7") & PMBIDR_P == 0
+}
+
+/// The profiling state of a guest.
+#[derive(Debug, Clone)]
+pub struct SpeState {
+    pub pmscr: u64,
+    pub pmsicr: u64,
+    pub pmsirr: u64,
+    pub pmsfcr: u64,
+    pub pmsevfr: u64,
+    pub pmslatfr: u64,
+    pub pmblimitr: u64,
+    pub pmbptr: u64,
+    pub pmbsr: u64,
+}
+
+impl SpeState {
+    pub const fn new() -> Self {
+        Self {
+            pmscr: 0,
+            pmsicr: 0,
+            pmsirr: 0,
+            pmsfcr: 0,
+            pmsevfr: 0,
+            pmslatfr: 0,
+            pmblimitr: 0,
+            pmbptr: 0,
+            pmbsr: 0,
+        }
+    }
+
+    /// Whether the buffer write that stopped the profiling faulted at stage 2.
+    pub fn stage2_abort(&self) -> bool {
+        self.pmbsr & PMBSR_S != 0
+            && (self.pmbsr >> PMBSR_EC_SHIFT) & PMBSR_EC_MASK == PMBSR_EC_STAGE2_ABORT
+    }
+}
+
+/// Gives the profiling buffer and the sampling controls to the guest about to run on this CPU,
+/// with their state in `state`.
+pub fn load(state: &SpeState) {
+    // PMSCR_EL2, the hypervisor is not sampled.
+    write_sysreg!("s3_4_c9_c9_0", 0);
+    write_sysreg!("s3_0_c9_c9_2", state.pmsicr);
+    write_sysreg!("s3_0_c9_c9_3", state.pmsirr);
+    write_sysreg!("s3_0_c9_c9_4", state.pmsfcr);
+    write_sysreg!("s3_0_c9_c9_5", state.pmsevfr);
+    write_sysreg!("s3_0_c9_c9_6", state.pmslatfr);
+    write_sysreg!("s3_0_c9_c10_1", state.pmbptr);
+    write_sysreg!("s3_0_c9_c10_3", state.pmbsr);
+    let mdcr = read_sysreg!("mdcr_el2");
+    write_sysreg!(
+        "mdcr_el2",
+        (mdcr & !(MDCR_E2PB_MASK | MDCR_TPMS)) | MDCR_E2PB_EL1
+    );
+    unsafe { core::arch::asm!("isb") };
+    // The buffer and the sampling are enabled last.
+    write_sysreg!("s3_0_c9_c10_0", state.pmblimitr);
+    write_sysreg!("s3_0_c9_c9_0", state.pmscr);
+    unsafe { core::arch::asm!("isb") };
+}
+
+/// Stops the profiling of the guest that left this CPU, saving its state in `state`, and takes
+/// the profiling buffer back.
+pub fn save(state: &mut SpeState) {
+    state.pmscr = read_sysreg!("s3_0_c9_c9_0");
+    write_sysreg!("s3_0_c9_c9_0", 0);
+    // Drain the sampled records into the buffer before it is disabled: `psb csync; dsb nsh`.
+    unsafe { core::arch::asm!("isb", "hint #17", "dsb nsh") };
+    state.pmblimitr = read_sysreg!("s3_0_c9_c10_0");
+    write_sysreg!("s3_0_c9_c10_0", 0);
+    unsafe { core::arch::asm!("isb") };
+    state.pmbptr = read_sysreg!("s3_0_c9_c10_1");
+    state.pmbsr = read_sysreg!("s3_0_c9_c10_3");
+    state.pmsicr = read_sysreg!("s3_0_c9_c9_2");
+    state.pmsirr = read_sysreg!("s3_0_c9_c9_3");
+    state.pmsfcr = read_sysreg!("s3_0_c9_c9_4");
+    state.pmsevfr = read_sysreg!("s3_0_c9_c9_5");
+    state.pmslatfr = read_sysreg!("s3_0_c9_c9_6");
+    // The interrupt is the guest's, it is asserted again with the state.
+    write_sysreg!("s3_0_c9_c10_3", 0);
+    release();
+}
+
+/// Gives the profiling buffer to the hypervisor, the profiling registers trapping from EL1.
+pub fn release() {
+    let mdcr = read_sysreg!("mdcr_el2");
+    write_sysreg!("mdcr_el2", (mdcr & !MDCR_E2PB_MASK) | MDCR_TPMS);
+    unsafe { core::arch::asm!("isb") };
+}
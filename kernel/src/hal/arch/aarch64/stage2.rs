@@ -0,0 +1,63 @@
+//! Stage-2 translation table descriptors (VMSAv8-64, 4 KB granule), for [`crate::vmm::stage2`].
+//!
+//! Levels are numbered from the starting level of `VTCR_EL2`, the root table being level 0. A block
+//! is turned into a table in place with break-before-make: the block descriptor is invalidated and
+//! its TLB entries are invalidated before the table descriptor is written.
+use axaddrspace::HostPhysAddr;
+
+/// Descriptor types in bits 1:0, a page at the last level.
+const DESC_BLOCK: u64 = 0b01;
+const DESC_TABLE: u64 = 0b11;
+const DESC_TYPE_MASK: u64 = 0b11;
+/// Output address in bits 47:12.
+const OA_MASK: u64 = 0x0000_ffff_ffff_f000;
+
+/// Number of levels of the stage-2 tables of the VMs.
+pub fn levels() -> usize {
+    let vtcr: u64;
+    unsafe { core::arch::asm!("mrs {}, vtcr_el2", out(reg) vtcr) };
+    // VTCR_EL2.SL0 with a 4 KB granule: 0 starts at level 2, 1 at level 1, 2 at level 0.
+    2 + ((vtcr >> 6) & 0b11) as usize
+}
+
+/// Returns the table `pte` at `level` points to, `None` if it's invalid or maps a block.
+pub fn table_of(pte: u64, level: usize) -> Option<HostPhysAddr> {
+    (level + 1 < levels() && pte & DESC_TYPE_MASK == DESC_TABLE)
+        .then(|| HostPhysAddr::from((pte & OA_MASK) as usize))
+}
+
+/// Whether `pte` at `level` maps a block.
+pub fn is_block(pte: u64, level: usize) -> bool {
+    level + 1 < levels() && pte & DESC_TYPE_MASK == DESC_BLOCK
+}
+
+/// Returns the descriptor at `level + 1` mapping the part of block `pte` at `offset`, with the
+/// attributes of the block.
+pub fn split_entry(pte: u64, level: usize, offset: usize) -> u64 {
+    let ty = if level + 2 == levels() {
+        DESC_TABLE
+    } else {
+        DESC_BLOCK
+    };
+    (pte & !(OA_MASK | DESC_TYPE_MASK)) | ((pte & OA_MASK) + offset as u64) | ty
+}
+
+/// Returns the descriptor of `table`.
+pub fn table_entry(table: HostPhysAddr) -> u64 {
+    table.as_usize() as u64 | DESC_TABLE
+}
+
+/// Replaces the block descriptor at `slot` with `table`, which maps the same.
+///
+/// # Safety
+///
+/// `slot` must be a descriptor of a live stage-2 table.
+pub unsafe fn replace_block(slot: *mut u64, table: u64) {
+    unsafe {
+        slot.write_volatile(0);
+        // The VMID of the VM isn't at hand, the stage-1 and stage-2 entries of all VMIDs go.
+        core::arch::asm!("dsb ishst", "tlbi alle1is", "dsb ish");
+        slot.write_volatile(table);
+        core::arch::asm!("dsb ishst", "isb");
+    }
+}
@@ -0,0 +1,7 @@
+use memory_addr::VirtAddr;
+
+use crate::hal::CacheOp;
+
+/// Caches are coherent with DMA on the supported platforms, and the base ISA has no cache
+/// maintenance instructions.
+pub fn dcache_range(_op: CacheOp, _addr: VirtAddr, _size: usize) {}
@@ -0,0 +1,72 @@
+use crate::vmm::VCpuRef;
+
+pub mod cache;
+pub mod stage2;
+
+/// Supervisor-level interrupt causes seen by the guest.
+pub const IRQ_S_SOFT: usize = 1;
+pub const IRQ_S_TIMER: usize = 5;
+pub const IRQ_S_EXT: usize = 9;
+
+/// SBI extension ID of the hypercalls of axvisor, "HVC". The guest makes a hypercall with an
+/// `ecall` to this extension, the hypercall number in `a6` (the SBI function ID) and its arguments
+/// in `a0`-`a5`. The vCPU backend turns it into a hypercall exit and the return value goes to `a0`.
+pub const SBI_EXT_HVC: usize = 0x48_5643;
+
+/// `hvip`, the pending VS-level interrupts injected by the hypervisor.
+const CSR_HVIP: usize = 0x645;
+
+/// Returns a random number from the generator of the CPU, always `None`: the `seed` CSR of Zkr is
+/// only accessible to HS-mode if the firmware allows it, which can't be probed without trapping.
+pub fn hw_random() -> Option<u64> {
+    None
+}
+
+/// Requests the performance level `level` of this CPU, always `false`: SBI has no CPU frequency
+/// extension, the level is only recorded.
+pub fn set_cpu_perf(_level: u8) -> bool {
+    false
+}
+
+/// Number of registers in [`GuestRegs`].
+pub const GUEST_REGS: usize = 32;
+
+/// The registers of a guest, in the order of the `user_regs_struct` of riscv64 ELF core files:
+/// `pc` and `x1`-`x31`.
+pub type GuestRegs = [u64; GUEST_REGS];
+
+/// Returns the registers of `vcpu`, always `None`: the backend keeps the registers of the guest
+/// private.
+pub fn guest_regs(_vcpu: &VCpuRef) -> Option<GuestRegs> {
+    None
+}
+
+pub fn hardware_check() {}
+
+/// Sets the bits of `mask` in `hvip` to their values in `pending`.
+///
+/// `hvip` is per physical CPU, this must be called by the vCPU task right before it enters the
+/// guest.
+pub fn set_vs_interrupts(mask: usize, pending: usize) {
+    let set = mask & pending;
+    let clear = mask & !pending;
+    unsafe {
+        core::arch::asm!(
+            "csrc {hvip}, {clear}",
+            "csrs {hvip}, {set}",
+            hvip = const CSR_HVIP,
+            clear = in(reg) clear,
+            set = in(reg) set,
+        );
+    }
+}
+
+/// Makes the supervisor-level interrupt `irq` pending in the guest running on this CPU.
+pub fn inject_interrupt(irq: usize) {
+    debug!("Injecting virtual interrupt: {irq}");
+    match irq {
+        // A VS-level interrupt is one bit above its supervisor-level cause in `hvip`.
+        IRQ_S_SOFT | IRQ_S_TIMER | IRQ_S_EXT => set_vs_interrupts(1 << (irq + 1), usize::MAX),
+        _ => warn!("Cannot inject interrupt {irq} through hvip"),
+    }
+}
@@ -0,0 +1,48 @@
+//! G-stage page table entries (Sv39x4), for [`crate::vmm::stage2`].
+//!
+//! The root table is level 0. A superpage is turned into a table in place without fencing: the
+//! translation doesn't change, so its TLB entries stay valid.
+use axaddrspace::HostPhysAddr;
+
+const PTE_V: u64 = 1 << 0;
+/// Read, write and execute, at least one of them is set in a leaf.
+const PTE_RWX: u64 = 0b1110;
+/// Physical page number in bits 53:10.
+const PPN_MASK: u64 = 0x003f_ffff_ffff_fc00;
+const PPN_SHIFT: u32 = 10;
+
+/// Number of levels of the G-stage tables of the VMs.
+pub fn levels() -> usize {
+    3
+}
+
+/// Returns the table `pte` at `level` points to, `None` if it's invalid or a leaf.
+pub fn table_of(pte: u64, level: usize) -> Option<HostPhysAddr> {
+    (level + 1 < levels() && pte & PTE_V != 0 && pte & PTE_RWX == 0)
+        .then(|| HostPhysAddr::from(((pte & PPN_MASK) >> PPN_SHIFT << 12) as usize))
+}
+
+/// Whether `pte` at `level` maps a superpage.
+pub fn is_block(pte: u64, level: usize) -> bool {
+    level + 1 < levels() && pte & PTE_V != 0 && pte & PTE_RWX != 0
+}
+
+/// Returns the entry at `level + 1` mapping the part of superpage `pte` at `offset`, with the
+/// attributes of the superpage.
+pub fn split_entry(pte: u64, _level: usize, offset: usize) -> u64 {
+    (pte & !PPN_MASK) | ((pte & PPN_MASK) + ((offset as u64 >> 12) << PPN_SHIFT))
+}
+
+/// Returns the entry of `table`.
+pub fn table_entry(table: HostPhysAddr) -> u64 {
+    ((table.as_usize() as u64 >> 12) << PPN_SHIFT) | PTE_V
+}
+
+/// Replaces the superpage entry at `slot` with `table`, which maps the same.
+///
+/// # Safety
+///
+/// `slot` must be an entry of a live G-stage table.
+pub unsafe fn replace_block(slot: *mut u64, table: u64) {
+    unsafe { slot.write_volatile(table) };
+}
@@ -1,4 +1,177 @@
+use core::arch::x86_64::__cpuid;
+
+use crate::vmm::VCpuRef;
+
 pub mod cache;
+pub mod pmu;
+pub mod stage2;
+
+const MSR_IA32_VMX_PINBASED_CTLS: u32 = 0x481;
+const MSR_IA32_VMX_PROCBASED_CTLS: u32 = 0x482;
+const MSR_IA32_VMX_PROCBASED_CTLS2: u32 = 0x48b;
+const MSR_IA32_VMX_EPT_VPID_CAP: u32 = 0x48c;
+/// Maximum non-turbo ratio in bits 15:8, minimum ratio in bits 47:40.
+const MSR_PLATFORM_INFO: u32 = 0xce;
+/// Target performance state, the ratio in bits 15:8.
+pub const MSR_IA32_PERF_CTL: u32 = 0x199;
+/// "Activate secondary controls" in the allowed-1 settings of the primary controls.
+const VMX_ACTIVATE_SECONDARY: u32 = 1 << 31;
+/// "Virtualize x2APIC mode", "APIC-register virtualization" and "virtual-interrupt delivery".
+const VMX_APICV: u32 = (1 << 4) | (1 << 8) | (1 << 9);
+/// "Process posted interrupts" in the allowed-1 settings of the pin-based controls.
+const VMX_POSTED_INTERRUPTS: u32 = 1 << 7;
+/// "VMCS shadowing" in the allowed-1 settings of the secondary controls.
+const VMX_VMCS_SHADOWING: u32 = 1 << 14;
+/// 2-MByte and 1-GByte EPT pages in `IA32_VMX_EPT_VPID_CAP`.
+const EPT_2M_PAGES: u32 = 1 << 16;
+const EPT_1G_PAGES: u32 = 1 << 17;
+
+/// APIC virtualization features of the CPU.
+#[derive(Debug, Clone, Copy)]
+pub struct ApicAssist {
+    /// Intel APICv.
+    pub apicv: bool,
+    /// AMD AVIC.
+    pub avic: bool,
+    /// Intel posted-interrupt processing, on top of APICv.
+    pub posted: bool,
+}
+
+fn rdmsr_low(msr: u32) -> u32 {
+    let low: u32;
+    unsafe {
+        core::arch::asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") _);
+    }
+    low
+}
+
+fn rdmsr_high(msr: u32) -> u32 {
+    let high: u32;
+    unsafe {
+        core::arch::asm!("rdmsr", in("ecx") msr, out("eax") _, out("edx") high);
+    }
+    high
+}
+
+/// Detects the APIC virtualization features of the CPU.
+pub fn apic_assist() -> ApicAssist {
+    let vmx = unsafe { __cpuid(1) }.ecx & (1 << 5) != 0;
+    let apicv = vmx
+        && rdmsr_high(MSR_IA32_VMX_PROCBASED_CTLS) & VMX_ACTIVATE_SECONDARY != 0
+        && rdmsr_high(MSR_IA32_VMX_PROCBASED_CTLS2) & VMX_APICV == VMX_APICV;
+
+    let svm = unsafe { __cpuid(0x8000_0000) }.eax >= 0x8000_000a
+        && unsafe { __cpuid(0x8000_0001) }.ecx & (1 << 2) != 0;
+    let avic = svm && unsafe { __cpuid(0x8000_000a) }.edx & (1 << 13) != 0;
+
+    let posted = apicv && rdmsr_high(MSR_IA32_VMX_PINBASED_CTLS) & VMX_POSTED_INTERRUPTS != 0;
+
+    ApicAssist {
+        apicv,
+        avic,
+        posted,
+    }
+}
+
+/// Whether the CPU can shadow VMCS fields, so that the VMREAD and VMWRITE of a guest hypervisor
+/// don't exit.
+pub fn has_vmcs_shadowing() -> bool {
+    unsafe { __cpuid(1) }.ecx & (1 << 5) != 0
+        && rdmsr_high(MSR_IA32_VMX_PROCBASED_CTLS) & VMX_ACTIVATE_SECONDARY != 0
+        && rdmsr_high(MSR_IA32_VMX_PROCBASED_CTLS2) & VMX_VMCS_SHADOWING != 0
+}
+
+/// Whether the second-stage tables (EPT or NPT) can map 2 MB and 1 GB pages.
+pub fn stage2_block_sizes() -> (bool, bool) {
+    if unsafe { __cpuid(1) }.ecx & (1 << 5) != 0 {
+        let cap = rdmsr_low(MSR_IA32_VMX_EPT_VPID_CAP);
+        (cap & EPT_2M_PAGES != 0, cap & EPT_1G_PAGES != 0)
+    } else {
+        // NPT uses the host page table format: 2 MB pages always, 1 GB pages with Page1GB.
+        (true, unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0)
+    }
+}
+
+/// Returns a random number from the generator of the CPU (RDRAND), `None` without one or if it
+/// failed to produce a number in reasonable time.
+pub fn hw_random() -> Option<u64> {
+    // CPUID.01H:ECX.RDRAND.
+    if unsafe { __cpuid(1) }.ecx & (1 << 30) == 0 {
+        return None;
+    }
+    // RDRAND may run dry for a moment, a few retries are enough unless the generator is broken.
+    for _ in 0..10 {
+        let (value, ok): (u64, u8);
+        // SAFETY: RDRAND has no side effect, it sets CF on success.
+        unsafe {
+            core::arch::asm!(
+                "rdrand {value}",
+                "setc {ok}",
+                value = out(reg) value,
+                ok = out(reg_byte) ok,
+                options(nomem, nostack),
+            );
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Returns the minimum and maximum non-turbo ratios of the CPU, `None` without Enhanced Intel
+/// SpeedStep.
+pub fn perf_ratios() -> Option<(u32, u32)> {
+    // CPUID.01H:ECX.EIST.
+    if unsafe { __cpuid(1) }.ecx & (1 << 7) == 0 {
+        return None;
+    }
+    let max = (rdmsr_low(MSR_PLATFORM_INFO) >> 8) & 0xff;
+    let min = (rdmsr_high(MSR_PLATFORM_INFO) >> 8) & 0xff;
+    (min != 0 && min <= max).then_some((min, max))
+}
+
+/// Requests the performance level `level` of this CPU, a percentage from its minimum to its
+/// maximum non-turbo ratio, through `IA32_PERF_CTL`. Returns `false` if the CPU has no
+/// SpeedStep, the level is then only recorded.
+pub fn set_cpu_perf(level: u8) -> bool {
+    let Some((min, max)) = perf_ratios() else {
+        return false;
+    };
+    let ratio = min + (max - min) * level.min(100) as u32 / 100;
+    pmu::wrmsr(MSR_IA32_PERF_CTL, (ratio as u64) << 8);
+    true
+}
+
+/// Number of registers in [`GuestRegs`].
+pub const GUEST_REGS: usize = 27;
+
+/// The registers of a guest, in the order of the `user_regs_struct` of x86_64 ELF core files:
+/// `r15`-`r12`, `rbp`, `rbx`, `r11`-`r8`, `rax`, `rcx`, `rdx`, `rsi`, `rdi`, `orig_rax`, `rip`,
+/// `cs`, `eflags`, `rsp`, `ss`, `fs_base`, `gs_base`, `ds`, `es`, `fs` and `gs`.
+pub type GuestRegs = [u64; GUEST_REGS];
+
+/// Returns the registers of `vcpu`, which must not run. The backend exposes the general-purpose
+/// registers, `rip` and `rsp`, the segment registers and `eflags` read as 0.
+pub fn guest_regs(vcpu: &VCpuRef) -> Option<GuestRegs> {
+    // `rip` and `rsp` are in the VMCS, which must be loaded on this CPU to be read. A vCPU whose
+    // run failed is still bound.
+    let bound = vcpu.bind().is_ok();
+    let arch = vcpu.get_arch_vcpu();
+    let gprs = arch.regs();
+    let mut regs = [0; GUEST_REGS];
+    regs[..15].copy_from_slice(&[
+        gprs.r15, gprs.r14, gprs.r13, gprs.r12, gprs.rbp, gprs.rbx, gprs.r11, gprs.r10, gprs.r9,
+        gprs.r8, gprs.rax, gprs.rcx, gprs.rdx, gprs.rsi, gprs.rdi,
+    ]);
+    // `orig_rax`, the guest is not in a system call of the hypervisor.
+    regs[15] = u64::MAX;
+    regs[16] = arch.rip() as u64;
+    regs[19] = arch.stack_pointer() as u64;
+    if bound {
+        let _ = vcpu.unbind();
+    }
+    Some(regs)
+}
 
 pub fn hardware_check() {}
-pub fn inject_interrupt(_vector: u8) {}
@@ -0,0 +1,141 @@
+//! Intel architectural performance monitoring, for the vPMU of [`crate::vmm::pmu`].
+//!
+//! The general-purpose counters are split between the guest and the hypervisor: the guest gets
+//! the first ones, whose MSRs it accesses through the emulation of [`crate::vmm::pmu`], and their
+//! state is loaded into the hardware around the runs of its vCPUs. The fixed counters are kept by
+//! the hypervisor.
+use core::arch::x86_64::__cpuid;
+
+/// Most general-purpose counters given to a guest.
+pub const MAX_COUNTERS: usize = 8;
+
+pub const MSR_IA32_PMC0: u32 = 0xc1;
+pub const MSR_IA32_PERFEVTSEL0: u32 = 0x186;
+pub const MSR_IA32_FIXED_CTR0: u32 = 0x309;
+pub const MSR_IA32_FIXED_CTR_CTRL: u32 = 0x38d;
+pub const MSR_IA32_PERF_GLOBAL_STATUS: u32 = 0x38e;
+pub const MSR_IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+pub const MSR_IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+const MSR_IA32_PERF_CAPABILITIES: u32 = 0x345;
+/// Full-width aliases of the `IA32_PMCx`.
+pub const MSR_IA32_A_PMC0: u32 = 0x4c1;
+
+/// `FW_WRITE` of `IA32_PERF_CAPABILITIES`, the `IA32_A_PMCx` are available.
+const PERF_CAP_FW_WRITE: u64 = 1 << 13;
+
+/// `EN` of `IA32_PERFEVTSELx`.
+pub const PERFEVTSEL_EN: u64 = 1 << 22;
+/// `INT` of `IA32_PERFEVTSELx`, raise a PMI on overflow.
+pub const PERFEVTSEL_INT: u64 = 1 << 20;
+
+pub fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe { core::arch::asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high) };
+    (high as u64) << 32 | low as u64
+}
+
+pub fn wrmsr(msr: u32, value: u64) {
+    unsafe {
+        core::arch::asm!(
+            "wrmsr",
+            in("ecx") msr,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32
+        )
+    };
+}
+
+/// Number of general-purpose counters, 0 without architectural performance monitoring version 2
+/// or later (which has `IA32_PERF_GLOBAL_CTRL`).
+pub fn counters() -> usize {
+    if unsafe { __cpuid(0) }.eax < 0xa {
+        return 0;
+    }
+    let eax = unsafe { __cpuid(0xa) }.eax;
+    if eax & 0xff < 2 {
+        return 0;
+    }
+    ((eax >> 8) & 0xff) as usize
+}
+
+/// MSR base to write the counters with: writes to `IA32_PMCx` only set the low 32 bits, the
+/// full-width aliases are used if the CPU has them.
+fn pmc_write_base() -> u32 {
+    static BASE: spin::Once<u32> = spin::Once::new();
+    *BASE.call_once(|| {
+        // CPUID.01H:ECX.PDCM, `IA32_PERF_CAPABILITIES` is available.
+        let pdcm = unsafe { __cpuid(1) }.ecx & (1 << 15) != 0;
+        if pdcm && rdmsr(MSR_IA32_PERF_CAPABILITIES) & PERF_CAP_FW_WRITE != 0 {
+            MSR_IA32_A_PMC0
+        } else {
+            MSR_IA32_PMC0
+        }
+    })
+}
+
+/// Width of the general-purpose counters in bits.
+pub fn counter_width() -> u32 {
+    (unsafe { __cpuid(0xa) }.eax >> 16) & 0xff
+}
+
+/// The guest part of the PMU state of a vCPU.
+#[derive(Debug, Clone)]
+pub struct PmuState {
+    pub evtsels: [u64; MAX_COUNTERS],
+    pub pmcs: [u64; MAX_COUNTERS],
+    /// `IA32_PERF_GLOBAL_CTRL` of the guest, its counters only.
+    pub global_ctrl: u64,
+}
+
+impl PmuState {
+    pub const fn new() -> Self {
+        Self {
+            evtsels: [0; MAX_COUNTERS],
+            pmcs: [0; MAX_COUNTERS],
+            global_ctrl: 0,
+        }
+    }
+
+    /// Whether a counter of the guest may be counting.
+    pub fn active(&self, counters: usize) -> bool {
+        self.global_ctrl != 0
+            && self.evtsels[..counters]
+                .iter()
+                .any(|evtsel| evtsel & PERFEVTSEL_EN != 0)
+    }
+}
+
+fn guest_mask(counters: usize) -> u64 {
+    (1 << counters) - 1
+}
+
+/// Loads the guest counters of `state` before the guest runs on this CPU.
+pub fn load(state: &PmuState, counters: usize) {
+    if !state.active(counters) {
+        return;
+    }
+    for idx in 0..counters {
+        wrmsr(MSR_IA32_PERFEVTSEL0 + idx as u32, state.evtsels[idx]);
+        wrmsr(pmc_write_base() + idx as u32, state.pmcs[idx]);
+    }
+    let host = rdmsr(MSR_IA32_PERF_GLOBAL_CTRL) & !guest_mask(counters);
+    wrmsr(
+        MSR_IA32_PERF_GLOBAL_CTRL,
+        host | (state.global_ctrl & guest_mask(counters)),
+    );
+}
+
+/// Saves the guest counts in `state` after the guest left this CPU and stops its counters.
+pub fn save(state: &mut PmuState, counters: usize) {
+    if !state.active(counters) {
+        return;
+    }
+    let global_ctrl = rdmsr(MSR_IA32_PERF_GLOBAL_CTRL);
+    wrmsr(
+        MSR_IA32_PERF_GLOBAL_CTRL,
+        global_ctrl & !guest_mask(counters),
+    );
+    for idx in 0..counters {
+        state.pmcs[idx] = rdmsr(MSR_IA32_PMC0 + idx as u32);
+    }
+}
@@ -0,0 +1,79 @@
+//! Second-stage page table entries, EPT on Intel and NPT on AMD, for [`crate::vmm::stage2`].
+//!
+//! Both have 4 levels, the root table being level 0. A large page is turned into a table in place
+//! without invalidating it: the translation doesn't change, so its TLB entries stay valid.
+use core::arch::x86_64::__cpuid;
+
+use axaddrspace::HostPhysAddr;
+
+/// Physical address in bits 51:12.
+const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+/// Large page in bit 7 of both formats.
+const PAGE_SIZE: u64 = 1 << 7;
+/// Read, write and execute of an EPT entry, at least one of them is set in a present entry.
+const EPT_RWX: u64 = 0b111;
+/// Present, writable and user of an NPT entry.
+const NPT_PRESENT: u64 = 1 << 0;
+const NPT_TABLE: u64 = 0b111;
+/// PAT bit of an NPT large page, bit 7 in a 4 KB page.
+const NPT_LARGE_PAT: u64 = 1 << 12;
+
+/// Number of levels of the second-stage tables of the VMs.
+pub fn levels() -> usize {
+    4
+}
+
+/// Whether the second stage is EPT (VMX), not NPT.
+fn ept() -> bool {
+    unsafe { __cpuid(1) }.ecx & (1 << 5) != 0
+}
+
+fn present(pte: u64) -> bool {
+    if ept() {
+        pte & EPT_RWX != 0
+    } else {
+        pte & NPT_PRESENT != 0
+    }
+}
+
+/// Returns the table `pte` at `level` points to, `None` if it's not present or maps a large page.
+pub fn table_of(pte: u64, level: usize) -> Option<HostPhysAddr> {
+    (level + 1 < levels() && present(pte) && pte & PAGE_SIZE == 0)
+        .then(|| HostPhysAddr::from((pte & ADDR_MASK) as usize))
+}
+
+/// Whether `pte` at `level` maps a large page.
+pub fn is_block(pte: u64, level: usize) -> bool {
+    level + 1 < levels() && present(pte) && pte & PAGE_SIZE != 0
+}
+
+/// Returns the entry at `level + 1` mapping the part of large page `pte` at `offset`, with the
+/// attributes of the page.
+pub fn split_entry(pte: u64, level: usize, offset: usize) -> u64 {
+    let page = level + 2 == levels();
+    let mut attrs = pte & !(ADDR_MASK | PAGE_SIZE);
+    let mut addr = pte & ADDR_MASK;
+    if !ept() {
+        // The PAT bit of a large page is in its address field, and moves to bit 7 in a page.
+        attrs &= !NPT_LARGE_PAT;
+        addr &= !NPT_LARGE_PAT;
+        if pte & NPT_LARGE_PAT != 0 {
+            attrs |= if page { PAGE_SIZE } else { NPT_LARGE_PAT };
+        }
+    }
+    attrs | (addr + offset as u64) | if page { 0 } else { PAGE_SIZE }
+}
+
+/// Returns the entry of `table`.
+pub fn table_entry(table: HostPhysAddr) -> u64 {
+    table.as_usize() as u64 | if ept() { EPT_RWX } else { NPT_TABLE }
+}
+
+/// Replaces the large page entry at `slot` with `table`, which maps the same.
+///
+/// # Safety
+///
+/// `slot` must be an entry of a live second-stage table.
+pub unsafe fn replace_block(slot: *mut u64, table: u64) {
+    unsafe { slot.write_volatile(table) };
+}
@@ -14,6 +14,7 @@ use axvm::{AxVMHal, AxVMPerCpu};
 
 #[cfg_attr(target_arch = "aarch64", path = "arch/aarch64/mod.rs")]
 #[cfg_attr(target_arch = "x86_64", path = "arch/x86_64/mod.rs")]
+#[cfg_attr(target_arch = "riscv64", path = "arch/riscv64/mod.rs")]
 pub mod arch;
 
 use crate::{hal::arch::hardware_check, task::AsVCpuTask, vmm};
@@ -30,11 +31,33 @@ pub enum CacheOp {
     CleanAndInvalidate,
 }
 
+/// Paging handler of the second-stage page tables of the VMs, charging their frames to
+/// [`MemSubsystem::PageTables`](vmm::memstat::MemSubsystem::PageTables).
+pub struct VmPagingHandler;
+
+impl PagingHandler for VmPagingHandler {
+    fn alloc_frame() -> Option<HostPhysAddr> {
+        let frame = axhal::paging::PagingHandlerImpl::alloc_frame()?;
+        vmm::memstat::charge(vmm::memstat::MemSubsystem::PageTables, None, PAGE_SIZE_4K);
+        Some(frame)
+    }
+
+    fn dealloc_frame(paddr: HostPhysAddr) {
+        axhal::paging::PagingHandlerImpl::dealloc_frame(paddr);
+        vmm::memstat::uncharge(vmm::memstat::MemSubsystem::PageTables, None, PAGE_SIZE_4K);
+    }
+
+    #[inline]
+    fn phys_to_virt(paddr: HostPhysAddr) -> HostVirtAddr {
+        axhal::paging::PagingHandlerImpl::phys_to_virt(paddr)
+    }
+}
+
 /// Implementation for `AxVMHal` trait.
 pub struct AxVMHalImpl;
 
 impl AxVMHal for AxVMHalImpl {
-    type PagingHandler = axhal::paging::PagingHandlerImpl;
+    type PagingHandler = VmPagingHandler;
 
     fn virt_to_phys(vaddr: HostVirtAddr) -> HostPhysAddr {
         axhal::mem::virt_to_phys(vaddr)
@@ -62,9 +85,7 @@ impl AxVMHal for AxVMHalImpl {
     }
 
     fn inject_irq_to_vcpu(vm_id: usize, vcpu_id: usize, irq: usize) -> AxResult {
-        vmm::with_vm_and_vcpu_on_pcpu(vm_id, vcpu_id, move |_, vcpu| {
-            vcpu.inject_interrupt(irq).unwrap();
-        })
+        vmm::posted::post(vm_id, vcpu_id, irq)
     }
 }
 
@@ -72,16 +93,16 @@ pub struct AxMmHalImpl;
 
 impl AxMmHal for AxMmHalImpl {
     fn alloc_frame() -> Option<HostPhysAddr> {
-        <AxVMHalImpl as AxVMHal>::PagingHandler::alloc_frame()
+        axhal::paging::PagingHandlerImpl::alloc_frame()
     }
 
     fn dealloc_frame(paddr: HostPhysAddr) {
-        <AxVMHalImpl as AxVMHal>::PagingHandler::dealloc_frame(paddr)
+        axhal::paging::PagingHandlerImpl::dealloc_frame(paddr)
     }
 
     #[inline]
     fn phys_to_virt(paddr: HostPhysAddr) -> HostVirtAddr {
-        <AxVMHalImpl as AxVMHal>::PagingHandler::phys_to_virt(paddr)
+        axhal::paging::PagingHandlerImpl::phys_to_virt(paddr)
     }
 
     fn virt_to_phys(vaddr: axaddrspace::HostVirtAddr) -> axaddrspace::HostPhysAddr {
@@ -139,6 +160,11 @@ pub(crate) fn enable_virtualization() {
             percpu
                 .hardware_enable()
                 .expect("Failed to enable virtualization");
+            // The profiling buffer is the hypervisor's outside the runs of the VM using it.
+            #[cfg(target_arch = "aarch64")]
+            if arch::spe::is_supported() {
+                arch::spe::release();
+            }
 
             info!("Hardware virtualization support enabled on core {cpu_id}");
 
@@ -260,9 +286,11 @@ mod vmm_api_impl {
         <AxVMHalImpl as AxVMHal>::inject_irq_to_vcpu(vm_id, vcpu_id, vector as usize).unwrap();
     }
 
-    extern fn notify_vcpu_timer_expired(_vm_id: VMId, _vcpu_id: VCpuId) {
-        todo!("notify_vcpu_timer_expired")
-        // vmm::timer::notify_timer_expired(vm_id, vcpu_id);
+    extern fn notify_vcpu_timer_expired(vm_id: VMId, vcpu_id: VCpuId) {
+        #[cfg(target_arch = "aarch64")]
+        vmm::vtimer::notify_expired(vm_id, vcpu_id);
+        #[cfg(not(target_arch = "aarch64"))]
+        warn!("VM[{vm_id}] VCpu[{vcpu_id}] unexpected emulated timer expiry");
     }
 }
 
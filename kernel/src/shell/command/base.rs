@@ -6,7 +6,7 @@ use std::io::{self, Read, Write};
 use std::println;
 use std::string::{String, ToString};
 
-use crate::shell::command::{CommandNode, FlagDef, ParsedCommand};
+use crate::shell::command::{CommandNode, FlagDef, OptionDef, ParsedCommand};
 
 #[cfg(feature = "fs")]
 macro_rules! print_err {
@@ -334,6 +334,24 @@ fn do_exit(cmd: &ParsedCommand) {
     std::process::exit(exit_code);
 }
 
+fn do_shutdown(cmd: &ParsedCommand) {
+    use crate::vmm::shutdown::{self, ShutdownSource};
+    use core::time::Duration;
+
+    let grace = match cmd.options.get("timeout").map(|s| s.parse::<u64>()) {
+        None => None,
+        Some(Ok(secs)) => Some(Duration::from_secs(secs)),
+        Some(Err(_)) => {
+            println!("Invalid timeout");
+            return;
+        }
+    };
+    match shutdown::request(ShutdownSource::Shell, grace) {
+        Ok(()) => println!("Shutting down, the guests are asked to power off..."),
+        Err(e) => println!("Failed to shut down: {:?}", e),
+    }
+}
+
 fn do_log(cmd: &ParsedCommand) {
     let args = &cmd.positional_args;
 
@@ -359,6 +377,382 @@ fn do_log(cmd: &ParsedCommand) {
     println!("Log level set to: {:?}", log::max_level());
 }
 
+#[cfg(target_arch = "aarch64")]
+fn do_sgi(cmd: &ParsedCommand) {
+    use crate::vmm::vsgi::{self, SgiMode};
+
+    if let Some(mode) = cmd.positional_args.first() {
+        let mode = match mode.as_str() {
+            "lr" => SgiMode::ListRegs,
+            "soft" => SgiMode::Software,
+            mode => {
+                println!("Unknown SGI mode: {}", mode);
+                println!("Available modes: lr, soft");
+                return;
+            }
+        };
+        vsgi::set_mode(mode);
+    }
+
+    let stats = vsgi::stats();
+    println!("SGI delivery: {:?}", vsgi::mode());
+    println!(
+        "GICv4.1 vSGIs: {}",
+        if vsgi::vsgi_capable() {
+            "available, not set up by the vCPU backend"
+        } else {
+            "not available"
+        }
+    );
+    println!(
+        "SGIs between vCPUs: {} posted, {} injected through the VM",
+        stats.posted, stats.software
+    );
+}
+
+fn do_lockup(cmd: &ParsedCommand) {
+    use crate::vmm::lockup::{self, LockupAction};
+    use crate::vmm::percpu;
+
+    let args = &cmd.positional_args;
+    let show_trace = cmd.flags.get("trace").unwrap_or(&false);
+
+    if let Some(action) = args.first() {
+        let action = match action.as_str() {
+            "log" => LockupAction::Log,
+            "recover" => LockupAction::Recover,
+            "panic" => LockupAction::Panic,
+            action => {
+                println!("Unknown lockup action: {}", action);
+                println!("Available actions: log, recover, panic");
+                return;
+            }
+        };
+        lockup::set_action(action);
+    }
+
+    println!("Lockup action: {:?}", lockup::action());
+    let reentries = crate::vmm::hypercall_reentries();
+    if reentries > 0 {
+        println!("Hypercall reentries: {} REFUSED", reentries);
+    }
+    let blocks = percpu::blocks();
+    for cpu in lockup::status() {
+        println!(
+            "CPU {}: heartbeat {} ms ago{}",
+            cpu.cpu_id,
+            cpu.heartbeat_age_ns / 1_000_000,
+            if cpu.stuck { ", STUCK" } else { "" }
+        );
+        if let Some(block) = blocks.get(cpu.cpu_id)
+            && let Some((vm_id, vcpu_id)) = block.current
+        {
+            println!(
+                "  {} VM[{}] VCpu[{}], last exit {} [{:#x}, {:#x}], {} entries, {} exits{}",
+                if block.in_guest { "in" } else { "last ran" },
+                vm_id,
+                vcpu_id,
+                block.last_exit,
+                block.scratch[0],
+                block.scratch[1],
+                block.entries,
+                block.exits,
+                if block.inconsistencies > 0 {
+                    format!(", {} INCONSISTENT world switches", block.inconsistencies)
+                } else {
+                    String::new()
+                }
+            );
+        }
+        if *show_trace {
+            for event in cpu.trace {
+                println!(
+                    "  [{}.{:09}] {:?} VM[{}] VCpu[{}] {}",
+                    event.time_ns / 1_000_000_000,
+                    event.time_ns % 1_000_000_000,
+                    event.kind,
+                    event.vm_id,
+                    event.vcpu_id,
+                    event.exit
+                );
+            }
+        }
+    }
+}
+
+fn do_mem(_cmd: &ParsedCommand) {
+    use super::vm::format_memory_size;
+    use crate::vmm::{memstat, vm_list};
+
+    let guest_memory: usize = vm_list::get_vm_list()
+        .iter()
+        .map(|vm| {
+            vm.memory_regions()
+                .iter()
+                .map(|region| region.size())
+                .sum::<usize>()
+        })
+        .sum();
+    println!("Guest memory:   {}", format_memory_size(guest_memory));
+    println!("Hypervisor memory:");
+    for (subsystem, bytes) in memstat::totals() {
+        println!(
+            "  {:<14}{}",
+            format!("{}:", subsystem.name()),
+            format_memory_size(bytes)
+        );
+    }
+    let shared = crate::vmm::imgshare::shared_image_stats();
+    if shared.frames > 0 {
+        println!(
+            "Shared image pages: {} mapped {} times",
+            shared.frames, shared.mappings
+        );
+    }
+    let vms = memstat::charged_vms();
+    if !vms.is_empty() {
+        println!("Hypervisor memory by VM:");
+    }
+    for vm_id in vms {
+        let usage: std::vec::Vec<_> = memstat::vm_usage(vm_id)
+            .into_iter()
+            .filter(|(_, bytes)| *bytes > 0)
+            .map(|(subsystem, bytes)| format!("{} {}", subsystem.name(), format_memory_size(bytes)))
+            .collect();
+        println!(
+            "  VM[{}]{}: {}",
+            vm_id,
+            if vm_list::get_vm_by_id(vm_id).is_none() {
+                " (destroyed)"
+            } else {
+                ""
+            },
+            usage.join(", ")
+        );
+    }
+}
+
+fn do_groups(_cmd: &ParsedCommand) {
+    use super::vm::format_memory_size;
+    use crate::vmm::group;
+
+    let groups = group::groups();
+    if groups.is_empty() {
+        println!("No VM group");
+        return;
+    }
+    for group in groups {
+        let vms: std::vec::Vec<_> = group
+            .vms
+            .iter()
+            .map(|vm_id| format!("VM[{}]", vm_id))
+            .collect();
+        println!(
+            "Group {}{}: {}",
+            group.id,
+            match &group.name {
+                Some(name) => format!(" `{}`", name),
+                None => String::new(),
+            },
+            if vms.is_empty() {
+                "empty".into()
+            } else {
+                vms.join(", ")
+            }
+        );
+        println!(
+            "  Memory: {} of {}, {} allocations refused",
+            format_memory_size(group.memory_used),
+            if group.memory_limit == 0 {
+                "no limit".into()
+            } else {
+                format_memory_size(group.memory_limit)
+            },
+            group.refusals
+        );
+        if let Some(consumed_ns) = group.cpu_consumed_ns {
+            println!(
+                "  CPU: cap {}%, {} us used in the current period",
+                group.cpu_cap,
+                consumed_ns / 1_000
+            );
+        }
+    }
+}
+
+fn do_pmu(cmd: &ParsedCommand) {
+    use crate::vmm::{pmu, vm_list};
+
+    match cmd.positional_args.as_slice() {
+        [] => {}
+        [sub, count] if sub == "reserve" => {
+            let Ok(count) = count.parse::<usize>() else {
+                println!("Invalid counter count: {}", count);
+                return;
+            };
+            if let Err(e) = pmu::set_reserved(count) {
+                println!("Failed to reserve {} counters: {:?}", count, e);
+                return;
+            }
+        }
+        _ => {
+            println!("Usage: pmu [reserve N]");
+            return;
+        }
+    }
+
+    println!("Host counters:     {}", pmu::host_counters());
+    println!("Reserved counters: {}", pmu::reserved());
+    for vm in vm_list::get_vm_list() {
+        let Some(cfg) = pmu::pmu_config(vm.id()) else {
+            continue;
+        };
+        let events = match &cfg.events {
+            Some(events) => {
+                let events: std::vec::Vec<_> =
+                    events.iter().map(|event| format!("{:#x}", event)).collect();
+                events.join(",")
+            }
+            None => "all".into(),
+        };
+        println!(
+            "  VM[{}]: {} counters, events {}",
+            vm.id(),
+            cfg.counters,
+            events
+        );
+    }
+}
+
+fn do_cpufreq(_cmd: &ParsedCommand) {
+    use crate::vmm::{cpufreq, vm_list};
+
+    let cpus = cpufreq::cpu_freq_stats();
+    if cpus.is_empty() {
+        println!("No VM takes part in the CPU frequency coordination");
+        return;
+    }
+    for cpu in cpus {
+        println!(
+            "CPU {}: target {}%, applied {}, {} changes",
+            cpu.cpu_id,
+            cpu.target,
+            match cpu.applied {
+                Some(level) => format!("{}%", level),
+                None => "-".into(),
+            },
+            cpu.changes
+        );
+    }
+    for vm in vm_list::get_vm_list() {
+        if let Some(freq) = cpufreq::vm_freq_stats(vm.id()) {
+            println!(
+                "  VM[{}]: {}% demanded (from {}% to {}%)",
+                vm.id(),
+                freq.demand,
+                freq.min_perf,
+                freq.max_perf
+            );
+        }
+    }
+}
+
+fn do_trace(cmd: &ParsedCommand) {
+    use crate::vmm::trace;
+    use std::vec::Vec;
+
+    match cmd.positional_args.first().map(|s| s.as_str()) {
+        Some("on") => {
+            let class_mask = match cmd.options.get("class").map(|s| trace::parse_classes(s)) {
+                None => 0,
+                Some(Ok(mask)) => mask,
+                Some(Err(e)) => {
+                    println!("Invalid trace classes: {:?}", e);
+                    println!("Available classes: exit, hypercall, irq, ivc");
+                    return;
+                }
+            };
+            let vm_id = match cmd.options.get("vm").map(|s| s.parse::<usize>()) {
+                None => None,
+                Some(Ok(vm_id)) => Some(vm_id),
+                Some(Err(_)) => {
+                    println!("Invalid VM ID");
+                    return;
+                }
+            };
+            trace::enable(class_mask, vm_id);
+        }
+        Some("off") => trace::disable(),
+        None | Some("show") => {}
+        Some(op) => {
+            println!("Unknown trace operation: {}", op);
+            return;
+        }
+    }
+
+    let (class_mask, vm_id) = trace::filter();
+    if class_mask == 0 {
+        println!("Tracing: off");
+    } else {
+        let classes: Vec<_> = (0..u8::BITS as u8)
+            .filter(|class| class_mask & (1 << class) != 0)
+            .map(trace::class_name)
+            .collect();
+        match vm_id {
+            Some(vm_id) => println!("Tracing: {} of VM[{}]", classes.join(","), vm_id),
+            None => println!("Tracing: {} of all VMs", classes.join(",")),
+        }
+    }
+    if cmd.positional_args.first().map(|s| s.as_str()) != Some("show") {
+        return;
+    }
+    for record in trace::records() {
+        println!(
+            "  [{}.{:09}] CPU {} VM[{}] VCpu[{}] {} {:#x} {:#x}",
+            record.time_ns / 1_000_000_000,
+            record.time_ns % 1_000_000_000,
+            record.cpu,
+            record.vm_id,
+            record.vcpu_id,
+            trace::class_name(record.class),
+            record.arg0,
+            record.arg1
+        );
+    }
+}
+
+fn do_reclaim(cmd: &ParsedCommand) {
+    use crate::vmm::reclaim;
+    use std::vec::Vec;
+
+    if let Some(order) = cmd.positional_args.first()
+        && let Err(e) = reclaim::set_order(order)
+    {
+        println!("Invalid reclaim order: {:?}", e);
+        println!("Available steps: compress, caches, balloon");
+        return;
+    }
+
+    let order: Vec<_> = reclaim::order().iter().map(|step| step.name()).collect();
+    println!("Reclaim order: {}", order.join(","));
+    let (actions, runs, failed) = reclaim::actions();
+    println!("Reclaim runs: {} ({} freed nothing)", runs, failed);
+    for action in actions {
+        let from = match action.vm_id {
+            Some(vm_id) => format!("VM[{}]", vm_id),
+            None => "hypervisor".into(),
+        };
+        println!(
+            "  [{}.{:09}] {} freed {} bytes of {}",
+            action.time_ns / 1_000_000_000,
+            action.time_ns % 1_000_000_000,
+            action.step.name(),
+            action.bytes,
+            from
+        );
+    }
+}
+
 #[cfg(feature = "fs")]
 fn do_mv(cmd: &ParsedCommand) {
     let args = &cmd.positional_args;
@@ -748,6 +1142,96 @@ pub fn build_base_cmd(tree: &mut BTreeMap<String, CommandNode>) {
             .with_usage("log [LEVEL]"),
     );
 
+    // lockup Command
+    tree.insert(
+        "lockup".to_string(),
+        CommandNode::new("Show CPU heartbeats and vCPUs or set the lockup action")
+            .with_handler(do_lockup)
+            .with_usage("lockup [--trace] [log|recover|panic]")
+            .with_flag(
+                FlagDef::new("trace", "Show the trace ring of each CPU")
+                    .with_short('t')
+                    .with_long("trace"),
+            ),
+    );
+
+    // sgi Command
+    #[cfg(target_arch = "aarch64")]
+    tree.insert(
+        "sgi".to_string(),
+        CommandNode::new("Show or set how the SGIs between vCPUs are delivered")
+            .with_handler(do_sgi)
+            .with_usage("sgi [lr|soft]"),
+    );
+
+    // shutdown Command
+    tree.insert(
+        "shutdown".to_string(),
+        CommandNode::new("Shut down the guests and power off the system")
+            .with_handler(do_shutdown)
+            .with_usage("shutdown [--timeout SECS]")
+            .with_option(
+                OptionDef::new("timeout", "Time the guests have to shut down, in seconds")
+                    .with_long("timeout"),
+            ),
+    );
+
+    // reclaim Command
+    tree.insert(
+        "reclaim".to_string(),
+        CommandNode::new("Show the memory reclaim actions or set the reclaim order")
+            .with_handler(do_reclaim)
+            .with_usage("reclaim [STEP,...]"),
+    );
+
+    // mem Command
+    tree.insert(
+        "mem".to_string(),
+        CommandNode::new("Show the hypervisor memory by subsystem and by VM")
+            .with_handler(do_mem)
+            .with_usage("mem"),
+    );
+
+    // groups Command
+    tree.insert(
+        "groups".to_string(),
+        CommandNode::new("Show the VM groups and their memory and CPU usage")
+            .with_handler(do_groups)
+            .with_usage("groups"),
+    );
+
+    // pmu Command
+    tree.insert(
+        "pmu".to_string(),
+        CommandNode::new("Show the performance counters of the VMs or reserve counters")
+            .with_handler(do_pmu)
+            .with_usage("pmu [reserve N]"),
+    );
+
+    // cpufreq Command
+    tree.insert(
+        "cpufreq".to_string(),
+        CommandNode::new("Show the CPU performance levels demanded by the VMs")
+            .with_handler(do_cpufreq)
+            .with_usage("cpufreq"),
+    );
+
+    // trace Command
+    tree.insert(
+        "trace".to_string(),
+        CommandNode::new("Turn hypervisor event tracing on or off, or show the trace records")
+            .with_handler(do_trace)
+            .with_usage("trace [on|off|show] [--vm ID] [--class CLASS,...]")
+            .with_option(OptionDef::new("vm", "Only trace this VM").with_long("vm"))
+            .with_option(
+                OptionDef::new(
+                    "class",
+                    "Event classes to trace (exit, hypercall, irq, ivc)",
+                )
+                .with_long("class"),
+            ),
+    );
+
     // touch Command
     #[cfg(feature = "fs")]
     tree.insert(
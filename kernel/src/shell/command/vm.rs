@@ -68,7 +68,7 @@ fn can_resume_vm(status: VMStatus) -> Result<(), &'static str> {
 }
 
 /// Format memory size in a human-readable way.
-fn format_memory_size(bytes: usize) -> String {
+pub(super) fn format_memory_size(bytes: usize) -> String {
     if bytes < 1024 {
         format!("{}B", bytes)
     } else if bytes < 1024 * 1024 {
@@ -95,6 +95,18 @@ fn vm_help(_cmd: &ParsedCommand) {
     println!("  resume    Resume a suspended virtual machine");
     println!("  restart   Restart a virtual machine");
     println!("  delete    Delete a virtual machine");
+    println!("  power     Send power requests to a virtual machine");
+    println!("  bench     Measure the per-exit lookup costs");
+    println!("  dump      Dump the memory and vCPU state of a running VM");
+    println!("  hangs     Show the stuck vCPUs detected so far");
+    println!("  crashes   Show the guest crashes handled so far");
+    println!("  sched     Show or set the CPU shares of the VMs");
+    println!("  time      Show or set the guest time of a VM");
+    println!("  trace     Show the trace events stamped with trace contexts");
+    println!("  quiesce   Freeze or thaw the filesystems of a guest");
+    println!("  template  Capture, list or remove the templates of guests");
+    println!("  stamp     Create instances of a template");
+    println!("  bridge    Show the IVC channels bridged to the peer node");
     println!();
     println!("Information commands:");
     println!("  list      Show table of all VMs");
@@ -119,7 +131,7 @@ fn vm_create(cmd: &ParsedCommand) {
         return;
     }
 
-    let initial_vm_count = vm_list::get_vm_list().len();
+    let initial_vm_count = vm_list::vm_count();
 
     for config_path in args.iter() {
         println!("Creating VM from config: {}", config_path);
@@ -147,7 +159,7 @@ fn vm_create(cmd: &ParsedCommand) {
     }
 
     // Check the actual number of VMs created
-    let final_vm_count = vm_list::get_vm_list().len();
+    let final_vm_count = vm_list::vm_count();
     let created_count = final_vm_count - initial_vm_count;
 
     if created_count > 0 {
@@ -465,6 +477,7 @@ fn suspend_vm_by_id(vm_id: usize) {
 
         // Set VM status to Suspended
         vm.set_vm_status(VMStatus::Suspended);
+        crate::vmm::guest_time::pause(vm_id);
         info!("VM[{}] status set to Suspended", vm_id);
 
         Ok(())
@@ -565,6 +578,8 @@ fn resume_vm_by_id(vm_id: usize) {
         }
 
         // Set VM status back to Running
+        crate::vmm::guest_time::resume(vm_id);
+        crate::vmm::crash::rearm(vm_id);
         vm.set_vm_status(VMStatus::Running);
 
         // Notify all VCpus to wake up
@@ -725,6 +740,9 @@ fn delete_vm_by_id(vm_id: usize, keep_data: bool) {
                 }
             }
 
+            // Release the PCI devices and the hypervisor-emulated devices of the VM.
+            crate::vmm::teardown_vm_resources(&vm);
+
             if keep_data {
                 println!("✓ VM[{}] deleted (configuration and data preserved)", vm_id);
             } else {
@@ -768,6 +786,118 @@ fn delete_vm_by_id(vm_id: usize, keep_data: bool) {
     println!("✓ VM[{}] deletion completed", vm_id);
 }
 
+/// Send power requests to VMs or update the host power supply state shared with them.
+fn vm_power(cmd: &ParsedCommand) {
+    use crate::vmm::power::{self, BatteryStatus, PowerEvent};
+
+    let args = &cmd.positional_args;
+    let Some(action) = args.first() else {
+        println!("Error: No action specified");
+        println!("Usage: vm power <suspend|shutdown> <VM_ID>...");
+        println!("       vm power supply [--ac on|off] [--capacity PERCENT] [--status STATUS]");
+        return;
+    };
+
+    let event = match action.as_str() {
+        "suspend" => PowerEvent::SUSPEND_REQUEST,
+        "shutdown" => PowerEvent::SHUTDOWN_REQUEST,
+        "supply" => {
+            let mut supply = power::host_power_supply();
+            if let Some(ac) = cmd.options.get("ac") {
+                supply.ac_online = match ac.as_str() {
+                    "on" => true,
+                    "off" => false,
+                    _ => {
+                        println!("Error: Invalid AC state: {} (expected on/off)", ac);
+                        return;
+                    }
+                };
+            }
+            if let Some(capacity) = cmd.options.get("capacity") {
+                match capacity.parse::<u8>() {
+                    Ok(capacity) if capacity <= 100 => {
+                        supply.battery_present = true;
+                        supply.capacity = capacity;
+                    }
+                    _ => {
+                        println!("Error: Invalid capacity: {} (expected 0-100)", capacity);
+                        return;
+                    }
+                }
+            }
+            if let Some(status) = cmd.options.get("status") {
+                supply.status = match status.as_str() {
+                    "charging" => BatteryStatus::Charging,
+                    "discharging" => BatteryStatus::Discharging,
+                    "full" => BatteryStatus::Full,
+                    "unknown" => BatteryStatus::Unknown,
+                    _ => {
+                        println!("Error: Invalid battery status: {}", status);
+                        return;
+                    }
+                };
+            }
+            power::update_host_power_supply(supply);
+            println!("✓ Host power supply: {:?}", supply);
+            return;
+        }
+        _ => {
+            println!("Error: Unknown power action: {}", action);
+            return;
+        }
+    };
+
+    if args.len() < 2 {
+        println!("Error: No VM specified");
+        println!("Usage: vm power {} <VM_ID>...", action);
+        return;
+    }
+
+    for vm_name in &args[1..] {
+        match vm_name.parse::<usize>() {
+            Ok(vm_id) => match power::request_vm_power_event(vm_id, event) {
+                Ok(()) => println!("✓ VM[{}] {} request sent", vm_id, action),
+                Err(e) => println!(
+                    "✗ Failed to send {} request to VM[{}]: {:?}",
+                    action, vm_id, e
+                ),
+            },
+            Err(_) => println!("Error: Invalid VM ID: {}", vm_name),
+        }
+    }
+}
+
+/// Measure the per-exit lookup costs against the VMs currently created.
+fn vm_bench(cmd: &ParsedCommand) {
+    let iters = match cmd.options.get("iters").map(|s| s.parse::<usize>()) {
+        None => 100_000,
+        Some(Ok(iters)) if iters > 0 => iters,
+        Some(_) => {
+            println!("Error: Invalid iteration count");
+            return;
+        }
+    };
+
+    let vms = vm_list::get_vm_list();
+    if vms.is_empty() {
+        println!("No virtual machines found.");
+        return;
+    }
+    let vcpu_count: usize = vms.iter().map(|vm| vm.vcpu_num()).sum();
+    println!(
+        "Benchmarking with {} VMs, {} vCPUs, {} iterations...",
+        vms.len(),
+        vcpu_count,
+        iters
+    );
+    drop(vms);
+
+    println!("{:<20} {:>10}", "LOOKUP", "NS/OP");
+    for result in crate::vmm::bench::run(iters) {
+        println!("{:<20} {:>10}", result.name, result.ns_per_op);
+    }
+}
+
 #[cfg(feature = "fs")]
 fn vm_list_simple() {
     let vms = vm_list::get_vm_list();
@@ -924,6 +1054,13 @@ fn show_vm_basic_details(vm_id: usize, show_config: bool, show_stats: bool) {
         // Basic Information
         println!("  VM ID:     {}", vm.id());
         println!("  Name:      {}", vm.with_config(|cfg| cfg.name()));
+        if let Some(identity) = crate::vmm::identity::identity(vm_id) {
+            println!(
+                "  UUID:      {}",
+                crate::vmm::identity::format_uuid(&identity.uuid)
+            );
+            println!("  Hostname:  {}", identity.hostname);
+        }
         println!("  Status:    {}", status.as_str_with_icon());
         println!("  VCPUs:     {}", vm.vcpu_num());
 
@@ -986,6 +1123,29 @@ fn show_vm_basic_details(vm_id: usize, show_config: bool, show_stats: bool) {
                     println!("  DTB Address:    {:#x}", dtb_addr.as_usize());
                 }
             });
+            for (handle, target) in crate::vmm::peers::vm_peers(vm_id) {
+                let target = match target {
+                    crate::vmm::peers::PeerTarget::Id(id) => format!("VM[{}]", id),
+                    crate::vmm::peers::PeerTarget::Name(name) => name,
+                };
+                println!("  {:<16}{}", format!("Peer {}:", handle), target);
+            }
+            if let Some(services) = crate::vmm::services::vm_services(vm_id) {
+                for channel in &services.channels {
+                    let name = crate::vmm::services::unpadded(&channel.name);
+                    let from = match channel.peer {
+                        crate::vmm::services::SERVICE_PEER_SELF => "published".into(),
+                        peer => format!("from peer {}", peer),
+                    };
+                    println!(
+                        "  {:<16}key {:#x}, {}",
+                        format!("{}:", name),
+                        channel.key,
+                        from
+                    );
+                }
+                println!("  Devices:        {}", services.devices.len());
+            }
         }
 
         // Device Summary
@@ -1000,6 +1160,335 @@ fn show_vm_basic_details(vm_id: usize, show_config: bool, show_stats: bool) {
                 "  SysReg Devices: {}",
                 vm.get_devices().iter_sys_reg_dev().count()
             );
+
+            if let Some(posted) = crate::vmm::posted::posted_stats(vm_id) {
+                println!(
+                    "  Posted IRQs:    {} ({} IPIs to running vCPUs)",
+                    posted.posted, posted.ipis
+                );
+            }
+            let blocks = crate::vmm::blocks::block_stats(vm_id);
+            println!(
+                "  Stage-2 Blocks: {} x 1G, {} x 2M, {} split",
+                blocks.blocks_1g, blocks.blocks_2m, blocks.splits
+            );
+            if let Some(lazy) = crate::vmm::lazymem::lazy_memory_stats(vm_id) {
+                println!(
+                    "  Lazy Memory:    {} of {} populated, limit {}, {} faults",
+                    format_memory_size(lazy.populated),
+                    format_memory_size(lazy.size),
+                    lazy.limit.map_or("none".into(), format_memory_size),
+                    lazy.faults
+                );
+                if lazy.shared > 0 || lazy.cow_breaks > 0 {
+                    println!(
+                        "  Shared Images:  {} mapped shared, {} pages copied on write",
+                        format_memory_size(lazy.shared),
+                        lazy.cow_breaks
+                    );
+                }
+            }
+            if let Some(dirty) = crate::vmm::dirty::dirty_stats(vm_id) {
+                println!(
+                    "  Checkpoints:    last {}, {} of {} dirtied since, {} write faults",
+                    crate::vmm::coredump::last_checkpoint(vm_id).unwrap_or(0),
+                    format_memory_size(dirty.dirty),
+                    format_memory_size(dirty.tracked),
+                    dirty.faults
+                );
+            }
+            if let Some(heatmap) = crate::vmm::heatmap::heatmap_stats(vm_id) {
+                println!(
+                    "  Heatmap:        {} of {} cells of {} warm, {} intervals, {} write faults",
+                    heatmap.warm,
+                    heatmap.cells,
+                    format_memory_size(heatmap.granule),
+                    heatmap.intervals,
+                    heatmap.faults
+                );
+            }
+            if let Some(vmi) = crate::vmm::vmi::vmi_stats(vm_id) {
+                println!(
+                    "  VMI Events:     {} to VM[{}], {} watched pages, {} watched registers",
+                    vmi.events, vmi.manager_vm_id, vmi.watched_pages, vmi.watched_regs
+                );
+            }
+            if let Some(ioreq) = crate::vmm::ioreq::ioreq_stats(vm_id) {
+                println!(
+                    "  I/O Requests:   {} to VM[{}], {} ranges",
+                    ioreq.requests, ioreq.server_vm_id, ioreq.ranges
+                );
+            }
+            for (idx, vhost) in crate::vmm::virtio::vhost_stats(vm_id).iter().enumerate() {
+                let backend = vhost
+                    .backend_vm_id
+                    .map_or("no backend".into(), |id| format!("VM[{}]", id));
+                println!(
+                    "  Vhost Device {}: ID {} served by {}, {} kicks, {} interrupts",
+                    idx, vhost.device_id, backend, vhost.kicks, vhost.interrupts
+                );
+            }
+            if let Some(fb) = crate::vmm::framebuffer::framebuffer_stats(vm_id) {
+                let display = match (fb.host, fb.display_vm_id) {
+                    (true, _) => "the host display".into(),
+                    (false, Some(id)) => format!("VM[{}]", id),
+                    (false, None) => "no display VM".into(),
+                };
+                println!(
+                    "  Framebuffer:    {}x{}, {} buffers, shown by {}, {} flips, {} releases",
+                    fb.width, fb.height, fb.buffers, display, fb.flips, fb.releases
+                );
+            }
+            if let Some(uart) = crate::vmm::uartshare::shared_uart_stats(vm_id) {
+                let owner = if uart.owner { "owned" } else { "not owned" };
+                println!(
+                    "  Shared UART:    {}, {} handovers, {} accesses",
+                    owner, uart.handovers, uart.accesses
+                );
+            }
+            if crate::vmm::seal::is_sealed(vm_id) {
+                println!("  Snapshots:      sealed (AES-256-GCM)");
+            }
+            if let Some(stamp) = crate::vmm::template::stamp_of(vm_id) {
+                println!(
+                    "  Template:       instance {} of {:?}",
+                    stamp.instance, stamp.template
+                );
+            }
+            let unknown_hvc = crate::vmm::unknown_hvc::action_of(vm_id);
+            if unknown_hvc != crate::vmm::unknown_hvc::UnknownHvcAction::Reject {
+                println!("  Unknown HVCs:   {:?}", unknown_hvc);
+            }
+            if crate::vmm::unknown_hvc::is_handler(vm_id) {
+                println!("  HVC Handler:    yes");
+            }
+            if let Some((_, key)) =
+                crate::vmm::conmux::attached().filter(|(manager, _)| *manager == vm_id)
+            {
+                println!("  Consoles:       all guests, IVC channel {:#x}", key);
+            }
+            let (running, unfetched) = crate::vmm::deferred::pending_calls(vm_id);
+            if running + unfetched > 0 {
+                println!("  Async Calls:    {} running, {} completed", running, unfetched);
+            }
+
+            if let Some(stats) = crate::vmm::stats::snapshot(vm_id) {
+                let header = &stats.header;
+                println!(
+                    "  IVC Traffic:    {}",
+                    format_memory_size(header.ivc_bytes as usize)
+                );
+                if header.period_ns > 0 {
+                    println!(
+                        "  Last Period:    {} exits/s, {} IRQs/s, {}% of a CPU in guest",
+                        header.period_exits * 1_000_000_000 / header.period_ns,
+                        header.period_irqs * 1_000_000_000 / header.period_ns,
+                        header.period_run_ns * 100 / header.period_ns
+                    );
+                }
+                if let Some(budget) = crate::vmm::exit_budget::exit_budget_stats(vm_id) {
+                    println!(
+                        "  Exit Budget:    {} exits per {} ms, {:?}: {} storms, held {} ms",
+                        budget.max_exits,
+                        budget.window_ns / 1_000_000,
+                        budget.action,
+                        budget.storms,
+                        budget.held_ns / 1_000_000
+                    );
+                }
+                println!();
+                println!("VCpu Exits:");
+                for (vcpu_id, vcpu) in stats.vcpus.iter().enumerate() {
+                    let exits: Vec<_> = vcpu
+                        .exits
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, exits)| **exits > 0)
+                        .map(|(code, exits)| {
+                            format!("{} {}", crate::vmm::hang::exit_name(code as u8), exits)
+                        })
+                        .collect();
+                    println!(
+                        "  VCpu[{}]: run {} ms, steal {} ms, {} IRQs, exits: {}",
+                        vcpu_id,
+                        vcpu.run_ns / 1_000_000,
+                        vcpu.steal_ns / 1_000_000,
+                        vcpu.injected_irqs,
+                        if exits.is_empty() {
+                            "none".into()
+                        } else {
+                            exits.join(", ")
+                        }
+                    );
+                }
+            }
+
+            if let Some(group_id) = crate::vmm::group::vm_group(vm_id) {
+                println!();
+                println!("VM group: {} (see `groups`)", group_id);
+            }
+
+            if let Some(freq) = crate::vmm::cpufreq::vm_freq_stats(vm_id) {
+                println!();
+                println!(
+                    "CPU performance: {}% demanded (from {}% to {}%), requested {}, {} requests",
+                    freq.demand,
+                    freq.min_perf,
+                    freq.max_perf,
+                    match freq.request {
+                        Some(level) => format!("{}%", level),
+                        None => "-".into(),
+                    },
+                    freq.requests
+                );
+            }
+
+            if let Some(sleep) = crate::vmm::sleep::sleep_stats(vm_id) {
+                println!();
+                println!(
+                    "Suspend to RAM: {}, {} times for {} ms, {} zero pages freed, last woken by {}",
+                    if sleep.asleep { "asleep" } else { "awake" },
+                    sleep.sleeps,
+                    sleep.slept_ns / 1_000_000,
+                    sleep.freed_pages,
+                    match sleep.last_wake {
+                        Some(reason) => format!("{:?}", reason),
+                        None => "-".into(),
+                    }
+                );
+            }
+
+            let idle = crate::vmm::idle::idle_stats(vm_id);
+            if !idle.is_empty() {
+                println!();
+                println!("Halts:");
+                for (vcpu_id, halts) in idle {
+                    println!(
+                        "  VCpu[{}]: {} halts, {} not blocked, idle {} ms",
+                        vcpu_id,
+                        halts.halts,
+                        halts.pending + halts.expired,
+                        halts.idle_ns / 1_000_000
+                    );
+                    println!(
+                        "    woken by {} interrupts and {} deadlines, late by avg {} ns, max {} ns",
+                        halts.interrupt_wakeups,
+                        halts.deadline_wakeups,
+                        halts.total_late_ns / halts.deadline_wakeups.max(1),
+                        halts.max_late_ns
+                    );
+                }
+            }
+
+            if let Some(policy) = crate::vmm::irqpolicy::irq_policy_stats(vm_id)
+                && (policy.send_rate > 0 || policy.high_priority)
+            {
+                println!();
+                println!(
+                    "Interrupt Policy: {} priority, {}, {} sent to other VMs, {} throttled",
+                    if policy.high_priority { "high" } else { "normal" },
+                    if policy.send_rate > 0 {
+                        format!("{} per second", policy.send_rate)
+                    } else {
+                        "no limit".into()
+                    },
+                    policy.sent,
+                    policy.throttled
+                );
+            }
+
+            let doorbells = crate::vmm::doorbell::doorbell_stats(vm_id);
+            if !doorbells.is_empty() {
+                println!();
+                println!("Inbound Doorbells:");
+                for db in doorbells {
+                    println!(
+                        "  VM[{}] -> VCpu[{}] vector {:#x}: {} delivered, avg {} ns, max {} ns",
+                        db.source_vm,
+                        db.target_vcpu,
+                        db.vector,
+                        db.delivered,
+                        db.avg_latency_ns,
+                        db.max_latency_ns
+                    );
+                }
+            }
+
+            #[cfg(target_arch = "aarch64")]
+            {
+                let timers = crate::vmm::vtimer::timer_stats(vm_id);
+                if !timers.is_empty() {
+                    println!();
+                    println!("Physical Timer Expirations:");
+                    for (vcpu_id, count) in timers {
+                        println!("  VCpu[{}]: {}", vcpu_id, count);
+                    }
+                }
+                if let Some(smc) = crate::vmm::smc::smc_stats(vm_id) {
+                    println!();
+                    println!(
+                        "SMC Filter: {} forwarded, {} emulated, {} denied",
+                        smc.forwarded, smc.emulated, smc.denied
+                    );
+                }
+                if let Some(spe) = crate::vmm::spe::spe_stats(vm_id) {
+                    println!();
+                    println!(
+                        "SPE: buffer irq {}, {} interrupts, {} stage-2 aborts",
+                        spe.irq, spe.interrupts, spe.stage2_aborts
+                    );
+                }
+            }
+            #[cfg(target_arch = "x86_64")]
+            if let Some(pv) = crate::vmm::pvcompat::stats(vm_id) {
+                println!();
+                println!(
+                    "PV Compat ({:?}): {} translated, {} unsupported",
+                    pv.abi, pv.translated, pv.unsupported
+                );
+            }
+
+            let usage: Vec<_> = crate::vmm::memstat::vm_usage(vm_id)
+                .into_iter()
+                .filter(|(_, bytes)| *bytes > 0)
+                .collect();
+            if !usage.is_empty() {
+                println!();
+                println!("Hypervisor Memory:");
+                for (subsystem, bytes) in usage {
+                    println!(
+                        "  {:<16}{}",
+                        format!("{}:", subsystem.name()),
+                        format_memory_size(bytes)
+                    );
+                }
+            }
+
+            let nics = crate::vmm::virtio::net_stats(vm_id);
+            if !nics.is_empty() {
+                println!();
+                println!("Virtio-net Ports:");
+                for (bridge, stats) in nics {
+                    println!(
+                        "  {}: {} TX, {} RX, {} RX dropped",
+                        bridge, stats.tx_frames, stats.rx_frames, stats.rx_dropped
+                    );
+                }
+            }
+            if let Some(io) = crate::vmm::virtio::blk_io_stats(vm_id) {
+                println!();
+                println!("Virtio-blk I/O:");
+                println!(
+                    "  {} reads ({} bytes), {} writes ({} bytes), {} flushes",
+                    io.reads, io.read_bytes, io.writes, io.write_bytes, io.flushes
+                );
+                println!(
+                    "  {} merged, {} ms throttled, {} ms queued",
+                    io.merged,
+                    io.throttled_ns / 1_000_000,
+                    io.queued_ns / 1_000_000
+                );
+            }
         }
 
         println!();
@@ -1024,6 +1513,13 @@ fn show_vm_full_details(vm_id: usize) {
         println!("Basic Information:");
         println!("  VM ID:     {}", vm.id());
         println!("  Name:      {}", vm.with_config(|cfg| cfg.name()));
+        if let Some(identity) = crate::vmm::identity::identity(vm_id) {
+            println!(
+                "  UUID:      {}",
+                crate::vmm::identity::format_uuid(&identity.uuid)
+            );
+            println!("  Hostname:  {}", identity.hostname);
+        }
         println!("  Status:    {}", status.as_str_with_icon());
         println!("  VCPUs:     {}", vm.vcpu_num());
 
@@ -1150,6 +1646,32 @@ fn show_vm_full_details(vm_id: usize) {
             println!("  BSP Entry:      {:#x}", cfg.bsp_entry().as_usize());
             println!("  AP Entry:       {:#x}", cfg.ap_entry().as_usize());
             println!("  Interrupt Mode: {:?}", cfg.interrupt_mode());
+            let traps = crate::vmm::traps::trap_config(vm_id);
+            println!(
+                "  Traps:          WFI {}, WFE {}, sysreg {:?}",
+                if traps.wfi { "on" } else { "off" },
+                if traps.wfe { "on" } else { "off" },
+                traps.sysreg
+            );
+            if let Some(pmu) = crate::vmm::pmu::pmu_config(vm_id) {
+                println!(
+                    "  vPMU:           {} counters, {}",
+                    pmu.counters,
+                    match &pmu.events {
+                        Some(events) => format!("{} events allowed", events.len()),
+                        None => "all events".into(),
+                    }
+                );
+            }
+            #[cfg(target_arch = "aarch64")]
+            if let Some((base, lpis, dropped)) = crate::vmm::vits::its_stats(vm_id) {
+                println!(
+                    "  ITS:            {:#x}, {} LPIs injected, {} MSIs dropped",
+                    base.as_usize(),
+                    lpis,
+                    dropped
+                );
+            }
 
             if let Some(dtb_addr) = cfg.image_config().dtb_load_gpa {
                 println!("  DTB Address:    {:#x}", dtb_addr.as_usize());
@@ -1206,6 +1728,16 @@ fn show_vm_full_details(vm_id: usize) {
                     println!();
                     println!("  Passthrough SPIs: {:?}", spis);
                 }
+                let direct: Vec<_> = crate::vmm::direct_irq::direct_irqs()
+                    .into_iter()
+                    .filter(|&(_, owner, _)| owner == vm_id)
+                    .collect();
+                if !direct.is_empty() {
+                    println!("  Direct IRQs:");
+                    for (irq, _, trapped) in direct {
+                        println!("    - {} (trapped {} times)", irq, trapped);
+                    }
+                }
             }
 
             // Show emulated devices
@@ -1258,6 +1790,610 @@ fn show_vm_full_details(vm_id: usize) {
     }
 }
 
+/// Writes a core dump as hex lines, to stream it over the console.
+struct HexStream<W: std::io::Write> {
+    inner: W,
+    line: Vec<u8>,
+}
+
+impl<W: std::io::Write> HexStream<W> {
+    const BYTES_PER_LINE: usize = 32;
+
+    fn flush_line(&mut self) -> std::io::Result<()> {
+        if self.line.is_empty() {
+            return Ok(());
+        }
+        let mut hex = String::with_capacity(Self::BYTES_PER_LINE * 2 + 1);
+        for byte in self.line.drain(..) {
+            hex.push_str(&format!("{:02x}", byte));
+        }
+        hex.push('\n');
+        self.inner.write_all(hex.as_bytes())
+    }
+}
+
+impl<W: std::io::Write> std::io::Write for HexStream<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for &byte in buf {
+            self.line.push(byte);
+            if self.line.len() == Self::BYTES_PER_LINE {
+                self.flush_line()?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.flush_line()?;
+        self.inner.flush()
+    }
+}
+
+/// Dump the memory and vCPU state of a running VM without stopping it.
+fn vm_dump(cmd: &ParsedCommand) {
+    use crate::vmm::{coredump, quiesce};
+
+    let args = &cmd.positional_args;
+    let stream = cmd.flags.get("stream").unwrap_or(&false);
+    let quiesce = *cmd.flags.get("quiesce").unwrap_or(&false);
+    let output = cmd.options.get("output");
+    let checkpoint = match cmd.options.get("checkpoint").map(|s| s.as_str()) {
+        None => None,
+        Some("full") => Some(coredump::CheckpointKind::Full),
+        Some("incremental") => Some(coredump::CheckpointKind::Incremental),
+        Some("last") => Some(coredump::CheckpointKind::Last),
+        Some(kind) => {
+            println!("Error: Unknown checkpoint kind: {}", kind);
+            return;
+        }
+    };
+
+    let Some(vm_id) = args.first().and_then(|arg| arg.parse::<usize>().ok()) else {
+        println!("Error: No valid VM ID specified");
+        println!(
+            "Usage: vm dump [--quiesce] [--checkpoint full|incremental|last] [--output FILE | --stream] <VM_ID>"
+        );
+        return;
+    };
+    let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+        println!("✗ VM[{}] not found", vm_id);
+        return;
+    };
+    if output.is_some() == *stream || (output.is_some() && !cfg!(feature = "fs")) {
+        println!("Error: Specify exactly one of --output FILE or --stream");
+        return;
+    }
+
+    // Freezing the filesystems makes the dump application-consistent.
+    let frozen = quiesce
+        && match quiesce::freeze(vm_id, quiesce::DEFAULT_QUIESCE_TIMEOUT) {
+            Ok(()) => true,
+            Err(e) => {
+                println!(
+                    "  ⚠ Failed to freeze the filesystems of VM[{}], dumping anyway: {:?}",
+                    vm_id, e
+                );
+                false
+            }
+        };
+
+    let result = match output {
+        None => {
+            println!("-----BEGIN AXVISOR CORE VM[{}]-----", vm_id);
+            let mut out = HexStream {
+                inner: std::io::stdout(),
+                line: Vec::new(),
+            };
+            let result = match checkpoint {
+                Some(kind) => coredump::checkpoint_vm(&vm, &mut out, kind),
+                None => coredump::dump_vm(&vm, &mut out),
+            };
+            println!("-----END AXVISOR CORE VM[{}]-----", vm_id);
+            result
+        }
+        #[cfg(feature = "fs")]
+        Some(path) => match std::fs::File::create(path.as_str()) {
+            Ok(mut file) => match checkpoint {
+                Some(kind) => coredump::checkpoint_vm(&vm, &mut file, kind),
+                None => coredump::dump_vm(&vm, &mut file),
+            },
+            Err(e) => Err(axerrno::ax_err_type!(
+                Io,
+                format!("failed to create {}, err {:?}", path, e)
+            )),
+        },
+        #[cfg(not(feature = "fs"))]
+        Some(_) => unreachable!(),
+    };
+
+    if frozen && let Err(e) = quiesce::thaw(vm_id, quiesce::DEFAULT_QUIESCE_TIMEOUT) {
+        println!("✗ Failed to thaw the filesystems of VM[{}]: {:?}", vm_id, e);
+    }
+
+    match result {
+        Ok(summary) => {
+            println!(
+                "✓ VM[{}] dumped: {} of memory, {} bytes in total",
+                vm_id,
+                format_memory_size(summary.memory_bytes),
+                summary.file_bytes
+            );
+            if let Some(sequence) = summary.checkpoint {
+                println!("  Checkpoint {} of the chain", sequence);
+            }
+            if summary.sealed {
+                println!("  Sealed with the snapshot key of the VM (AES-256-GCM)");
+            }
+            if !summary.quiesced {
+                println!("  ⚠ Some vCPUs did not pause, the dumped memory may be inconsistent");
+            }
+            if quiesce && !frozen {
+                println!("  ⚠ The filesystems were not frozen, the dump is only crash-consistent");
+            }
+        }
+        Err(e) => println!("✗ Failed to dump VM[{}]: {:?}", vm_id, e),
+    }
+}
+
+/// Freeze or thaw the filesystems of a guest running a quiesce agent.
+fn vm_quiesce(cmd: &ParsedCommand) {
+    use crate::vmm::quiesce;
+
+    let args = &cmd.positional_args;
+    let usage = "Usage: vm quiesce <freeze|thaw|status> <VM_ID> [--timeout SECS]";
+    let (Some(action), Some(vm_id)) = (
+        args.first(),
+        args.get(1).and_then(|arg| arg.parse::<usize>().ok()),
+    ) else {
+        println!("Error: No action or valid VM ID specified");
+        println!("{}", usage);
+        return;
+    };
+    let timeout = match cmd.options.get("timeout").map(|s| s.parse::<u64>()) {
+        None => quiesce::DEFAULT_QUIESCE_TIMEOUT,
+        Some(Ok(secs)) if secs > 0 => core::time::Duration::from_secs(secs),
+        Some(_) => {
+            println!("Error: Invalid timeout (expected seconds > 0)");
+            return;
+        }
+    };
+
+    let result = match action.as_str() {
+        "freeze" => quiesce::freeze(vm_id, timeout),
+        "thaw" => quiesce::thaw(vm_id, timeout),
+        "status" => {
+            match quiesce::state(vm_id) {
+                Some(state) => println!("VM[{}] filesystems: {:?}", vm_id, state),
+                None => println!("VM[{}] has no quiesce agent", vm_id),
+            }
+            return;
+        }
+        _ => {
+            println!("Error: Unknown quiesce action: {}", action);
+            println!("{}", usage);
+            return;
+        }
+    };
+    match result {
+        Ok(()) => println!("✓ VM[{}] {} done", vm_id, action),
+        Err(e) => println!("✗ Failed to {} VM[{}]: {:?}", action, vm_id, e),
+    }
+}
+
+/// Capture, list or remove the templates of guests.
+fn vm_template(cmd: &ParsedCommand) {
+    use crate::vmm::template;
+
+    let args = &cmd.positional_args;
+    let usage = "Usage: vm template <capture <VM_ID> | list | remove <NAME>>";
+    match args.first().map(|s| s.as_str()) {
+        Some("capture") => {
+            let Some(vm_id) = args.get(1).and_then(|arg| arg.parse::<usize>().ok()) else {
+                println!("Error: No valid VM ID specified");
+                println!("{}", usage);
+                return;
+            };
+            let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+                println!("✗ VM[{}] not found", vm_id);
+                return;
+            };
+            match template::capture(&vm) {
+                Ok(bytes) => println!(
+                    "✓ VM[{}] captured: {} of memory",
+                    vm_id,
+                    format_memory_size(bytes)
+                ),
+                Err(e) => println!("✗ Failed to capture VM[{}]: {:?}", vm_id, e),
+            }
+        }
+        Some("list") => {
+            let templates = template::templates();
+            if templates.is_empty() {
+                println!("No template.");
+                return;
+            }
+            println!(
+                "{:<16} {:<8} {:>12} {:>10}",
+                "NAME", "SOURCE", "CAPTURED", "INSTANCES"
+            );
+            for t in templates {
+                println!(
+                    "{:<16} {:<8} {:>12} {:>10}",
+                    t.name,
+                    t.source.map_or_else(|| "-".into(), |id| id.to_string()),
+                    t.captured.map_or_else(|| "-".into(), format_memory_size),
+                    t.instances
+                );
+            }
+        }
+        Some("remove") => {
+            let Some(name) = args.get(1) else {
+                println!("Error: No template name specified");
+                println!("{}", usage);
+                return;
+            };
+            match template::remove(name) {
+                Ok(()) => println!("✓ Template {:?} removed", name),
+                Err(e) => println!("✗ Failed to remove template {:?}: {:?}", name, e),
+            }
+        }
+        _ => {
+            println!("Error: Unknown or missing template action");
+            println!("{}", usage);
+        }
+    }
+}
+
+/// Create instances of a template.
+fn vm_stamp(cmd: &ParsedCommand) {
+    let args = &cmd.positional_args;
+    let boot = *cmd.flags.get("boot").unwrap_or(&false);
+    let (Some(name), Some(count)) = (
+        args.first(),
+        args.get(1).and_then(|arg| arg.parse::<usize>().ok()),
+    ) else {
+        println!("Error: No template name or valid count specified");
+        println!("Usage: vm stamp [--boot] <NAME> <COUNT>");
+        return;
+    };
+
+    let started_ns = std::os::arceos::modules::axhal::time::monotonic_time_nanos();
+    match crate::vmm::template::stamp(name, count, boot) {
+        Ok(ids) => println!(
+            "✓ {} instance(s) of {:?} created in {} ms: {:?}",
+            ids.len(),
+            name,
+            (std::os::arceos::modules::axhal::time::monotonic_time_nanos() - started_ns)
+                / 1_000_000,
+            ids
+        ),
+        Err(e) => println!("✗ Failed to stamp {:?}: {:?}", name, e),
+    }
+}
+
+/// Show the stuck vCPUs detected so far.
+fn vm_hangs(_cmd: &ParsedCommand) {
+    let events = crate::vmm::hang::events();
+    if events.is_empty() {
+        println!("No stuck vCPU detected.");
+        return;
+    }
+    println!(
+        "{:<6} {:<6} {:<14} {:>10} {:>10} {:>12} {:<18} {:<8} {:>5}",
+        "VM", "VCPU", "WHERE", "STALL(ms)", "EXITS", "INSTRS", "LAST EXIT", "STATE", "PCPU"
+    );
+    for event in events {
+        println!(
+            "{:<6} {:<6} {:<14} {:>10} {:>10} {:>12} {:<18} {:<8} {:>5}",
+            event.vm_id,
+            event.vcpu_id,
+            format!("{:?}", event.kind),
+            event.stalled_ms,
+            event.exits,
+            event
+                .instructions
+                .map_or("-".into(), |instructions| instructions.to_string()),
+            event.last_exit,
+            format!("{:?}", event.vcpu_state),
+            event.pcpu
+        );
+        if let Some(snapshot) = &event.regs {
+            for (idx, chunk) in snapshot.regs.chunks(4).enumerate() {
+                let regs: Vec<String> = chunk.iter().map(|reg| format!("{:#018x}", reg)).collect();
+                println!("    r{:<2}: {}", idx * 4, regs.join(" "));
+            }
+        }
+    }
+}
+
+/// Run the guest ABI conformance checks against a VM.
+fn vm_abi_check(cmd: &ParsedCommand) {
+    use crate::vmm::abi::{self, CheckOutcome};
+
+    let Some(vm_id) = cmd
+        .positional_args
+        .first()
+        .and_then(|arg| arg.parse::<usize>().ok())
+    else {
+        println!("Error: No valid VM ID specified");
+        println!("Usage: vm abi-check <VM_ID>");
+        return;
+    };
+    let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+        println!("✗ VM[{}] not found", vm_id);
+        return;
+    };
+    let checks = match abi::check_vm(&vm) {
+        Ok(checks) => checks,
+        Err(e) => {
+            println!("✗ Failed to check VM[{}]: {:?}", vm_id, e);
+            return;
+        }
+    };
+    let (mut failed, mut skipped) = (0, 0);
+    for check in &checks {
+        match &check.outcome {
+            CheckOutcome::Passed => println!("  ✓ {}", check.name),
+            CheckOutcome::Failed(why) => {
+                failed += 1;
+                println!("  ✗ {}: {}", check.name, why);
+            }
+            CheckOutcome::Skipped(why) => {
+                skipped += 1;
+                println!("  - {}: skipped, {}", check.name, why);
+            }
+        }
+    }
+    if failed == 0 {
+        println!(
+            "✓ VM[{}] ABI conformance: {} checks passed, {} skipped",
+            vm_id,
+            checks.len() - skipped,
+            skipped
+        );
+    } else {
+        println!(
+            "✗ VM[{}] ABI conformance: {} of {} checks failed",
+            vm_id,
+            failed,
+            checks.len()
+        );
+    }
+}
+
+/// Show the IVC channels exported to and imported from the peer node.
+fn vm_bridge(_cmd: &ParsedCommand) {
+    use crate::vmm::bridge::REMOTE_VM_ID_BASE;
+
+    let Some(status) = crate::vmm::bridge::status() else {
+        println!("No bridge configured.");
+        return;
+    };
+    println!(
+        "Bridge: {:?} link, node {}, peer node {} {}",
+        status.kind,
+        status.node,
+        1 - status.node,
+        if status.peer_up { "up" } else { "down" }
+    );
+    if status.dropped > 0 {
+        println!("  {} messages dropped (peer mailbox full)", status.dropped);
+    }
+    println!(
+        "{:<10} {:<8} {:<10} {:>10}  {}",
+        "DIR", "VM", "KEY", "SIZE", "SUBSCRIBERS"
+    );
+    for (vm_id, key, size) in &status.exported {
+        let subscribers: Vec<_> = status
+            .attached
+            .iter()
+            .filter(|(_, publisher, k)| publisher == vm_id && k == key)
+            .map(|(proxy, _, _)| format!("{}", proxy - REMOTE_VM_ID_BASE))
+            .collect();
+        println!(
+            "{:<10} {:<8} {:<10} {:>10}  {}",
+            "export",
+            vm_id,
+            format!("{:#x}", key),
+            format_memory_size(*size),
+            subscribers.join(",")
+        );
+    }
+    for (proxy, key) in &status.imported {
+        println!(
+            "{:<10} {:<8} {:<10} {:>10}  -",
+            "import",
+            format!("{}@{}", proxy - REMOTE_VM_ID_BASE, 1 - status.node),
+            format!("{:#x}", key),
+            "-"
+        );
+    }
+}
+
+/// Show the guest crashes handled by the crash policies.
+fn vm_crashes(_cmd: &ParsedCommand) {
+    let events = crate::vmm::crash::events();
+    if events.is_empty() {
+        println!("No guest crash handled.");
+        return;
+    }
+    println!(
+        "{:>14} {:<6} {:<6} {:<10} {:<10} {:>12}  {}",
+        "TIME(ns)", "VM", "VCPU", "REASON", "ACTION", "DUMPED", "DETAIL"
+    );
+    for event in events {
+        println!(
+            "{:>14} {:<6} {:<6} {:<10} {:<10} {:>12}  {}",
+            event.info.time_ns,
+            event.info.vm_id,
+            event.info.vcpu_id,
+            format!("{:?}", event.info.reason),
+            event.action,
+            event.dumped.map_or_else(|| "-".into(), format_memory_size),
+            event.info.detail
+        );
+    }
+}
+
+/// Show the trace events stamped with the trace contexts of the guests.
+fn vm_trace(_cmd: &ParsedCommand) {
+    use crate::vmm::tracectx::TraceCtxEvent;
+
+    let events = crate::vmm::tracectx::events();
+    if events.is_empty() {
+        println!("No trace context event recorded.");
+        return;
+    }
+    println!(
+        "{:>14} {:<6} {:<6} {:<32} {}",
+        "TIME(ns)", "VM", "VCPU", "CONTEXT", "EVENT"
+    );
+    for event in events {
+        let what = match event.event {
+            TraceCtxEvent::Hypercall { nr } => format!("hypercall {:#x}", nr),
+            TraceCtxEvent::Forward { to_vm } => format!("forward to VM[{}]", to_vm),
+            TraceCtxEvent::Take { from_vm } => format!("take from VM[{}]", from_vm),
+        };
+        println!(
+            "{:>14} {:<6} {:<6} {:<32} {}",
+            event.time_ns,
+            event.vm_id,
+            event.vcpu_id,
+            format!("{:?}", event.ctx),
+            what
+        );
+    }
+}
+
+/// Show the CPU shares and run times of the VMs, or set the share of one.
+fn vm_sched(cmd: &ParsedCommand) {
+    use crate::vmm::sched;
+
+    let weight = cmd.options.get("weight");
+    let cap = cmd.options.get("cap");
+    if weight.is_some() || cap.is_some() {
+        let Some(vm_id) = cmd
+            .positional_args
+            .first()
+            .and_then(|arg| arg.parse::<usize>().ok())
+        else {
+            println!("Error: No valid VM ID specified");
+            println!("Usage: vm sched [--weight N] [--cap PCT] [VM_ID]");
+            return;
+        };
+        let Some(current) = sched::share_stats()
+            .into_iter()
+            .find(|stats| stats.vm_id == vm_id)
+        else {
+            println!("✗ VM[{}] has no CPU share", vm_id);
+            return;
+        };
+        let weight = match weight.map(|w| w.parse::<u32>()) {
+            None => current.weight,
+            Some(Ok(weight)) => weight,
+            Some(Err(_)) => {
+                println!("Error: Invalid weight: {}", weight.unwrap());
+                return;
+            }
+        };
+        let cap = match cap.map(|c| c.parse::<u32>()) {
+            None => current.cap,
+            Some(Ok(cap)) => cap,
+            Some(Err(_)) => {
+                println!("Error: Invalid cap: {}", cap.unwrap());
+                return;
+            }
+        };
+        match sched::set_vm_share(vm_id, weight, cap) {
+            Ok(()) => println!("✓ VM[{}] CPU share: weight {}, cap {}%", vm_id, weight, cap),
+            Err(e) => println!("✗ Failed to set the CPU share of VM[{}]: {:?}", vm_id, e),
+        }
+        return;
+    }
+
+    let vm_id = cmd
+        .positional_args
+        .first()
+        .and_then(|arg| arg.parse::<usize>().ok());
+    let stats: Vec<_> = sched::share_stats()
+        .into_iter()
+        .filter(|stats| vm_id.is_none_or(|vm_id| stats.vm_id == vm_id))
+        .collect();
+    if stats.is_empty() {
+        println!("No VM with a CPU share.");
+        return;
+    }
+    let period_ns = sched::SHARE_PERIOD.as_nanos() as u64;
+    println!(
+        "{:<6} {:>7} {:>6} {:>9}  {}",
+        "VM", "WEIGHT", "CAP", "LAST(%)", "VCPU RUNTIME(ms)"
+    );
+    for stats in stats {
+        let runtimes: Vec<String> = stats
+            .vcpu_runtime_ns
+            .iter()
+            .enumerate()
+            .map(|(vcpu_id, ns)| format!("{}:{}", vcpu_id, ns / 1_000_000))
+            .collect();
+        println!(
+            "{:<6} {:>7} {:>6} {:>9}  {}",
+            stats.vm_id,
+            stats.weight,
+            if stats.cap == 0 {
+                "-".to_string()
+            } else {
+                format!("{}%", stats.cap)
+            },
+            stats.last_period_ns * 100 / period_ns,
+            runtimes.join(" ")
+        );
+    }
+}
+
+/// Show the guest time and the stolen time of a VM, or set its guest time.
+fn vm_time(cmd: &ParsedCommand) {
+    use crate::vmm::guest_time;
+
+    let Some(vm_id) = cmd
+        .positional_args
+        .first()
+        .and_then(|arg| arg.parse::<usize>().ok())
+    else {
+        println!("Error: No valid VM ID specified");
+        println!("Usage: vm time [--set NS] <VM_ID>");
+        return;
+    };
+    if let Some(set) = cmd.options.get("set") {
+        let Ok(guest_ns) = set.parse::<u64>() else {
+            println!("Error: Invalid guest time: {}", set);
+            return;
+        };
+        match guest_time::set_guest_time_ns(vm_id, guest_ns) {
+            Ok(()) => println!("✓ VM[{}] guest time set to {} ns", vm_id, guest_ns),
+            Err(e) => println!("✗ Failed to set the guest time of VM[{}]: {:?}", vm_id, e),
+        }
+        return;
+    }
+
+    let Some(stats) = guest_time::stats(vm_id) else {
+        println!("✗ VM[{}] not found", vm_id);
+        return;
+    };
+    println!(
+        "VM[{}] guest time: {}.{:09} s{}",
+        vm_id,
+        stats.guest_time_ns / 1_000_000_000,
+        stats.guest_time_ns % 1_000_000_000,
+        if stats.paused { " (paused)" } else { "" }
+    );
+    if let Some(offset) = guest_time::counter_offset_ticks(vm_id) {
+        println!("  Counter offset: {} ticks", offset);
+    }
+    for (vcpu_id, steal_ns) in stats.vcpu_steal_ns.iter().enumerate() {
+        println!("  VCpu {}: stolen {} us", vcpu_id, steal_ns / 1_000);
+    }
+}
+
 /// Build the VM command tree and register it.
 pub fn build_vm_cmd(tree: &mut BTreeMap<String, CommandNode>) {
     #[cfg(feature = "fs")]
@@ -1370,6 +2506,101 @@ pub fn build_vm_cmd(tree: &mut BTreeMap<String, CommandNode>) {
                 .with_long("stats"),
         );
 
+    let power_cmd = CommandNode::new("Send power requests or update the host power supply")
+        .with_handler(vm_power)
+        .with_usage("vm power <suspend|shutdown|supply> [OPTIONS] [VM_ID...]")
+        .with_option(OptionDef::new("ac", "AC adapter state (on, off)").with_long("ac"))
+        .with_option(
+            OptionDef::new("capacity", "Battery capacity in percent").with_long("capacity"),
+        )
+        .with_option(
+            OptionDef::new("status", "Battery status (charging, discharging, full)")
+                .with_long("status"),
+        );
+
+    let bench_cmd = CommandNode::new("Measure the per-exit lookup costs against all VMs")
+        .with_handler(vm_bench)
+        .with_usage("vm bench [OPTIONS]")
+        .with_option(
+            OptionDef::new("iters", "Iterations per benchmark (default 100000)").with_long("iters"),
+        );
+
+    let dump_cmd = CommandNode::new("Dump the memory and vCPU state of a running VM")
+        .with_handler(vm_dump)
+        .with_usage(
+            "vm dump [--quiesce] [--checkpoint full|incremental|last] [--output FILE | --stream] <VM_ID>",
+        )
+        .with_option(
+            OptionDef::new("output", "Core file to write")
+                .with_short('o')
+                .with_long("output"),
+        )
+        .with_flag(
+            FlagDef::new("stream", "Stream the core as hex over the console").with_long("stream"),
+        )
+        .with_flag(
+            FlagDef::new("quiesce", "Freeze the guest filesystems during the dump")
+                .with_long("quiesce"),
+        )
+        .with_option(
+            OptionDef::new(
+                "checkpoint",
+                "Dump as a checkpoint: full, incremental (dirty pages only) or last",
+            )
+            .with_long("checkpoint"),
+        );
+
+    let hangs_cmd = CommandNode::new("Show the stuck vCPUs detected so far")
+        .with_handler(vm_hangs)
+        .with_usage("vm hangs");
+
+    let abi_check_cmd = CommandNode::new("Run the guest ABI conformance checks against a VM")
+        .with_handler(vm_abi_check)
+        .with_usage("vm abi-check <VM_ID>");
+
+    let sched_cmd = CommandNode::new("Show or set the CPU shares of the VMs")
+        .with_handler(vm_sched)
+        .with_usage("vm sched [--weight N] [--cap PCT] [VM_ID]")
+        .with_option(OptionDef::new("weight", "Relative weight (1-65535)").with_long("weight"))
+        .with_option(
+            OptionDef::new("cap", "Percentage of one CPU per period, 0 for no cap")
+                .with_long("cap"),
+        );
+
+    let time_cmd = CommandNode::new("Show or set the guest time of a VM")
+        .with_handler(vm_time)
+        .with_usage("vm time [--set NS] <VM_ID>")
+        .with_option(OptionDef::new("set", "Guest time to set, in nanoseconds").with_long("set"));
+
+    let crashes_cmd = CommandNode::new("Show the guest crashes handled so far")
+        .with_handler(vm_crashes)
+        .with_usage("vm crashes");
+
+    let trace_cmd = CommandNode::new("Show the trace events stamped with trace contexts")
+        .with_handler(vm_trace)
+        .with_usage("vm trace");
+
+    let quiesce_cmd = CommandNode::new("Freeze or thaw the filesystems of a guest")
+        .with_handler(vm_quiesce)
+        .with_usage("vm quiesce <freeze|thaw|status> <VM_ID> [--timeout SECS]")
+        .with_option(
+            OptionDef::new("timeout", "Time the guest agent has to answer, in seconds")
+                .with_long("timeout"),
+        );
+
+    let template_cmd = CommandNode::new("Capture, list or remove the templates of guests")
+        .with_handler(vm_template)
+        .with_usage("vm template <capture <VM_ID> | list | remove <NAME>>");
+
+    let stamp_cmd = CommandNode::new("Create instances of a template")
+        .with_handler(vm_stamp)
+        .with_usage("vm stamp [--boot] <NAME> <COUNT>")
+        .with_flag(FlagDef::new("boot", "Boot the instances once created").with_long("boot"));
+
+    let bridge_cmd = CommandNode::new("Show the IVC channels bridged to the peer node")
+        .with_handler(vm_bridge)
+        .with_usage("vm bridge");
+
     // main VM command
     let mut vm_node = CommandNode::new("Virtual machine management")
         .with_handler(vm_help)
@@ -1392,6 +2623,19 @@ pub fn build_vm_cmd(tree: &mut BTreeMap<String, CommandNode>) {
         .add_subcommand("resume", resume_cmd)
         .add_subcommand("restart", restart_cmd)
         .add_subcommand("delete", delete_cmd)
+        .add_subcommand("power", power_cmd)
+        .add_subcommand("bench", bench_cmd)
+        .add_subcommand("dump", dump_cmd)
+        .add_subcommand("hangs", hangs_cmd)
+        .add_subcommand("abi-check", abi_check_cmd)
+        .add_subcommand("crashes", crashes_cmd)
+        .add_subcommand("sched", sched_cmd)
+        .add_subcommand("time", time_cmd)
+        .add_subcommand("trace", trace_cmd)
+        .add_subcommand("quiesce", quiesce_cmd)
+        .add_subcommand("template", template_cmd)
+        .add_subcommand("stamp", stamp_cmd)
+        .add_subcommand("bridge", bridge_cmd)
         .add_subcommand("list", list_cmd)
         .add_subcommand("show", show_cmd);
 
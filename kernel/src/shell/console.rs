@@ -0,0 +1,170 @@
+//! Console I/O of the shell.
+//!
+//! Input is received on the console interrupt when the platform has one: the handler drains the
+//! UART into a ring of [`RX_CAPACITY`] bytes and wakes up the shell, which sleeps otherwise.
+//! Without an interrupt, the shell polls the UART every [`POLL_INTERVAL`] rather than spinning,
+//! so that either way it doesn't take a CPU from the guests. Bytes received while the ring is
+//! full are dropped.
+//!
+//! While the UART is lent to a VM (see [`crate::vmm::uartshare`]), its input goes to the VM and
+//! the shell gets none. The escape key of the shared UART is taken on the way.
+//!
+//! The output of the line editor goes through [`ConsoleTx`], which buffers the escape sequences
+//! and text of a line update and writes them to the console at once when flushed.
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use std::io::{self, Write};
+use std::os::arceos::api::task::{self, AxWaitQueueHandle};
+use std::os::arceos::modules::axhal;
+use std::thread;
+
+use kspin::SpinNoIrq;
+
+use crate::vmm::uartshare;
+
+/// Size of the input ring.
+pub const RX_CAPACITY: usize = 256;
+/// Size of the output buffer of [`ConsoleTx`].
+pub const TX_CAPACITY: usize = 256;
+/// Interval of the polling of the UART without a console interrupt.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+struct RxRing {
+    buf: [u8; RX_CAPACITY],
+    head: usize,
+    len: usize,
+}
+
+impl RxRing {
+    const fn new() -> Self {
+        Self {
+            buf: [0; RX_CAPACITY],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len < RX_CAPACITY {
+            self.buf[(self.head + self.len) % RX_CAPACITY] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % RX_CAPACITY;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+static RX: SpinNoIrq<RxRing> = SpinNoIrq::new(RxRing::new());
+/// The shell waiting for input.
+static RX_WAIT: AxWaitQueueHandle = AxWaitQueueHandle::new();
+/// Whether input is received on the console interrupt.
+static IRQ_DRIVEN: AtomicBool = AtomicBool::new(false);
+
+/// Receives the console input on its interrupt, if the platform has one.
+pub fn init() {
+    match axhal::console::irq_num() {
+        Some(irq) if axhal::irq::register(irq, on_rx_irq) => {
+            IRQ_DRIVEN.store(true, Ordering::Release);
+            info!("Shell console input on irq {}", irq);
+        }
+        _ => info!("Shell console input polled every {:?}", POLL_INTERVAL),
+    }
+}
+
+fn on_rx_irq() {
+    if uartshare::forward_irq() {
+        return;
+    }
+    let mut buf = [0u8; 16];
+    let mut rx = RX.lock();
+    'drain: loop {
+        let len = axhal::console::read_bytes(&mut buf);
+        if len == 0 {
+            break;
+        }
+        for byte in &buf[..len] {
+            if *byte == uartshare::ESCAPE_KEY && uartshare::escape() {
+                // What follows is the input of the VM now owning the UART.
+                break 'drain;
+            }
+            rx.push(*byte);
+        }
+    }
+    drop(rx);
+    task::ax_wait_queue_wake(&RX_WAIT, 1);
+}
+
+/// Reads a byte of input, waiting for one.
+pub fn read_byte() -> u8 {
+    loop {
+        if let Some(byte) = RX.lock().pop() {
+            return byte;
+        }
+        if IRQ_DRIVEN.load(Ordering::Acquire) {
+            task::ax_wait_queue_wait_until(&RX_WAIT, || RX.lock().len > 0, None);
+        } else {
+            let mut byte = [0u8; 1];
+            if !uartshare::is_lent() && axhal::console::read_bytes(&mut byte) == 1 {
+                if byte[0] == uartshare::ESCAPE_KEY && uartshare::escape() {
+                    continue;
+                }
+                return byte[0];
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Buffered console output, written at once on flush or when the buffer is full.
+pub struct ConsoleTx {
+    buf: [u8; TX_CAPACITY],
+    len: usize,
+}
+
+impl ConsoleTx {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; TX_CAPACITY],
+            len: 0,
+        }
+    }
+}
+
+impl Default for ConsoleTx {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for ConsoleTx {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        if self.len == TX_CAPACITY {
+            self.flush()?;
+        }
+        let len = bytes.len().min(TX_CAPACITY - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        axhal::console::write_bytes(&self.buf[..self.len]);
+        self.len = 0;
+        Ok(())
+    }
+}
+
+impl Drop for ConsoleTx {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
@@ -1,4 +1,5 @@
 mod command;
+mod console;
 
 use std::io::prelude::*;
 use std::println;
@@ -18,8 +19,8 @@ const MAX_LINE_LEN: usize = 256;
 
 // Initialize the console shell.
 pub fn console_init() {
-    let mut stdin = std::io::stdin();
-    let mut stdout = std::io::stdout();
+    console::init();
+    let mut stdout = console::ConsoleTx::new();
     let mut history = CommandHistory::new(100);
 
     let mut buf = [0; MAX_LINE_LEN];
@@ -44,14 +45,7 @@ pub fn console_init() {
     print_prompt();
 
     loop {
-        let mut temp_buf = [0u8; 1];
-
-        let ch = match stdin.read(&mut temp_buf) {
-            Ok(1) => temp_buf[0],
-            _ => {
-                continue;
-            }
-        };
+        let ch = console::read_byte();
 
         match input_state {
             InputState::Normal => {
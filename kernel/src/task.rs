@@ -1,7 +1,10 @@
 use alloc::sync::{Arc, Weak};
 use std::os::arceos::modules::axtask::{TaskExt, TaskInner};
 
-use crate::vmm::{VCpuRef, VM, VMRef};
+use crate::vmm::{
+    HyperCallNesting, VCpuRef, VM, VMRef, affinity::PendingAffinity, hang::VCpuProgress,
+    regs::VCpuRegs,
+};
 
 /// Task extended data for the hypervisor.
 pub struct VCpuTask {
@@ -9,6 +12,14 @@ pub struct VCpuTask {
     pub vm: Weak<VM>,
     /// The virtual CPU.
     pub vcpu: VCpuRef,
+    /// Forward progress of the vCPU, watched by [`crate::vmm::hang`].
+    pub progress: VCpuProgress,
+    /// CPU mask set at runtime, applied by the task itself, see [`crate::vmm::affinity`].
+    pub pending_affinity: PendingAffinity,
+    /// Hypercalls the vCPU is in, see [`HyperCallNesting`].
+    pub hypercall_nesting: HyperCallNesting,
+    /// Snapshots of the registers of the vCPU, see [`crate::vmm::regs`].
+    pub regs: VCpuRegs,
 }
 
 impl VCpuTask {
@@ -17,6 +28,10 @@ impl VCpuTask {
         Self {
             vm: Arc::downgrade(vm),
             vcpu,
+            progress: VCpuProgress::new(),
+            pending_affinity: PendingAffinity::new(),
+            hypercall_nesting: HyperCallNesting::new(),
+            regs: VCpuRegs::new(),
         }
     }
 
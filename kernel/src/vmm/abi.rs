@@ -0,0 +1,936 @@
+//! Conformance checks of the guest ABI.
+//!
+//! The parts of the guest ABI defined by axvisor itself (hypercall numbers and arguments, the
+//! layouts of the pages shared with guests, the virtio-mmio transport) are the same on every
+//! architecture. The checks below pin them down at compile time, so that a change of the ABI,
+//! or an architecture-specific definition diverging from the others, breaks the build of every
+//! target instead of silently breaking the guests of one of them.
+//!
+//! What is architecture-specific, and how:
+//!
+//! | | aarch64 | riscv64 | x86_64 |
+//! |---|---|---|---|
+//! | Hypercall instruction and registers | decoded by the vCPU backend into a hypercall exit with a number and six arguments | same, from an `ecall` to the SBI extension [`SBI_EXT_HVC`](crate::hal::arch::SBI_EXT_HVC) with the number in `a6` | same, from a `VMCALL` (`VMMCALL` on AMD) with the number in `rax` |
+//! | Hypercall return value | `set_return_value` of the vCPU backend | same (`a0`) | same (`rax`) |
+//! | Interrupt numbers of the config (`irq`, `vector`, ...) | GIC INTIDs | PLIC sources, or IMSIC identities without a PLIC (with `[vintc]`), see [`crate::vmm::vintc`] | vectors, latched in the emulated local APIC with `[x2apic]` (interrupts of the hypervisor included), see [`crate::vmm::x2apic`] |
+//! | Emulated timer | physical timer PPI 30, see [`crate::vmm::vtimer`] | CLINT `mtimecmp`, see [`crate::vmm::vintc`] | x2APIC timer, TSC-deadline mode included, see [`crate::vmm::x2apic`] |
+//!
+//! The virtio devices (see [`crate::vmm::virtio`]) have no architecture-specific code: their
+//! register window, feature bits and queue handling are shared by all targets, and only the
+//! interrupt number they raise follows the table above.
+//!
+//! # Runtime checks
+//!
+//! What the build can't check is run against a guest by [`check_vm`] (`vm abi-check` in the
+//! shell), on every target:
+//!
+//! - every fast hypercall is dispatched: either by the vCPU loop, or by exactly one registered
+//!   service, registrations failing at boot;
+//! - the virtio-mmio transport of each device of the VM behaves as the specification says when
+//!   the guest accesses its registers: identity registers, 32-bit register accesses only, feature
+//!   selection, queue selection, feature negotiation refusing legacy drivers and unknown features,
+//!   queue setup and device reset.
+//!
+//! The VM is paused while the checks run.
+//!
+//! # Unit tests
+//!
+//! The encoding and decoding of the hypercall numbers and arguments and of the records shared with
+//! guests are unit tested, see the tests at the end of this file. No selftest guest runs the ABI
+//! from the guest side on each target yet.
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::{offset_of, size_of};
+
+use axerrno::{AxResult, ax_err};
+use axvm::VMStatus;
+
+use super::VMRef;
+use super::affinity::AFFINITY_SELF;
+use super::bridge::{
+    BRIDGE_EXPORT_FREE, BRIDGE_EXPORT_LIVE, BRIDGE_MAGIC, BRIDGE_MAILBOX_SLOTS,
+    BRIDGE_MAX_EXPORTS, BRIDGE_MSG_ATTACH, BRIDGE_MSG_DETACH, BRIDGE_MSG_KICK, BRIDGE_NAME_LEN,
+    BRIDGE_VERSION, BridgeExport, BridgeMessage, BridgeNodeHeader,
+};
+use super::conmux::{
+    CONSOLE_DETACH, CONSOLE_FRAME_ALIGN, CONSOLE_MUX_MAGIC, CONSOLE_MUX_STREAM_OFFSET,
+    CONSOLE_MUX_VERSION, CONSOLE_SRC_UEFI_SERIAL, CONSOLE_SRC_VIRTIO, ConsoleFrame,
+    ConsoleMuxHeader,
+};
+use super::coredump::{
+    self, DUMP_CHUNK_FREE, DUMP_CHUNK_READY, DUMP_STREAM_DATA_OFFSET, DUMP_STREAM_END,
+    DUMP_STREAM_MAGIC, DUMP_STREAM_VERSION, DumpStreamHeader, NT_PRSTATUS,
+};
+use super::cpufreq::{CPUFREQ_DEFAULT, CPUFREQ_MAX};
+use super::crash::{CRASH_RECORD_MAGIC, CRASH_RECORD_VERSION, CrashReason, CrashRecord};
+use super::deferred::{ASYNC_DONE, ASYNC_FAILED, ASYNC_PENDING, AsyncResult};
+use super::doorbell::{MAX_DOORBELL_VMS, MAX_INBOUND_DOORBELLS, MAX_OUTBOUND_DOORBELLS};
+use super::framebuffer::{
+    FB_ATTACH, FB_DETACH, FB_EVENT_FLIP, FB_EVENT_GONE, FB_FORMAT_XRGB8888, FB_MAGIC, FB_RELEASE,
+    FB_VERSION, FbHeader, MAX_FB_BUFFERS,
+};
+use super::group::{GROUP_CREATE, GROUP_DESTROY, GROUP_MOVE, GROUP_NONE};
+use super::heatmap::{HEATMAP_MAGIC, HEATMAP_VERSION, HeatmapHeader, MAX_HEATMAP_CELLS};
+use super::hvc::{
+    self, AXVISOR_FAST_HVC_BASE, HVC_CALL_ASYNC, HVC_CONSOLE_ATTACH, HVC_CPUFREQ_REQUEST,
+    HVC_FB_CONTROL, HVC_FB_FLIP, HVC_FORWARD_COMPLETE, HVC_FS_QUIESCE, HVC_GET_RESULT,
+    HVC_HEATMAP_QUERY, HVC_IOREQ_CONTROL, HVC_IVC_BROADCAST, HVC_IVC_KICK, HVC_IVC_WAIT_SPACE,
+    HVC_RT_DOORBELL, HVC_STATS_QUERY, HVC_SYSTEM_SHUTDOWN, HVC_TRACE_CONTEXT, HVC_TRACE_EXPORT,
+    HVC_UART_ASSIGN, HVC_VCPU_SET_AFFINITY, HVC_VHOST_CONTROL, HVC_VIRTIO_HOTPLUG, HVC_VM_DEFINE,
+    HVC_VM_DUMP, HVC_VM_GET_VCPU_REGS, HVC_VM_GROUP, HVC_VM_READ_GUEST_MEM, HVC_VM_READY,
+    HVC_VM_SET_SHARES, HVC_VM_WRITE_GUEST_MEM, HVC_VMI_CONTROL, HVC_WATCHDOG_KICK,
+};
+use super::hvinfo::{
+    ABI_LEVEL, HV_ARCH_AARCH64, HV_ARCH_RISCV64, HV_ARCH_X86_64, HV_INFO_MAGIC, HV_INFO_VERSION,
+    HvInfo, VM_INFO_F_MANAGER, VM_INFO_F_NESTED, VM_INFO_F_PARTITIONED, VM_INFO_F_STAMPED,
+    VM_INFO_MAGIC, VmInfo,
+};
+use super::introspect::{MAX_INTROSPECT_SIZE, VCpuRegs};
+use super::ioreq::{
+    IOREQ_ATTACH, IOREQ_COMPLETE, IOREQ_DETACH, IOREQ_DONE, IOREQ_FREE, IOREQ_MAGIC, IOREQ_MAP,
+    IOREQ_PENDING, IOREQ_SLOTS_OFFSET, IOREQ_UNMAP, IOREQ_VERSION, IoreqHeader, IoreqSlot,
+    MAX_IOREQ_RANGES,
+};
+use super::irqpolicy::IRQ_THROTTLED;
+use super::ivc::{
+    IVC_CHANNEL_BROADCAST, IVC_RING_F_NO_KICK, IVC_RING_F_PEER_GONE, IVC_RING_MAGIC,
+    IVC_RING_VERSION, IVCBroadcastHeader, IVCChannelHeader, IVCNotifyMode, IVCRing, IVCRingHeader,
+};
+use super::lifecycle::{
+    LIFECYCLE_CAPACITY, LIFECYCLE_MAGIC, LIFECYCLE_RECORDS_OFFSET, LIFECYCLE_VERSION,
+    LifecycleEvent, LifecycleLogHeader, LifecycleRecord,
+};
+use super::power::PowerEvent;
+use super::quiesce::{QUIESCE_AGENT_GONE, QUIESCE_AGENT_READY, QUIESCE_FROZEN, QUIESCE_THAWED};
+use super::reboot::PowerRequest;
+use super::regs::{GUEST_REGS, MAX_GUEST_REGS};
+use super::services::{
+    SERVICE_DEV_DOORBELL, SERVICE_DEV_FRAMEBUFFER, SERVICE_DEV_POWER, SERVICE_DEV_VIRTIO_BLK,
+    SERVICE_DEV_VIRTIO_CONSOLE, SERVICE_DEV_VIRTIO_HOTPLUG, SERVICE_DEV_VIRTIO_NET,
+    SERVICE_DEV_VIRTIO_RNG, SERVICE_DEV_VIRTIO_VHOST, SERVICE_DEV_VIRTIO_VSOCK, SERVICE_PEER_SELF,
+    SERVICE_VM_UNRESOLVED, SERVICES_MAGIC, SERVICES_VERSION, ServiceChannel, ServiceDevice,
+    ServicePeer, ServicesHeader,
+};
+use super::stats::{
+    STATS_EXIT_REASONS, STATS_MAGIC, STATS_SELF, STATS_VERSION, StatsHeader, VCpuStatsRecord,
+};
+use super::trace::{
+    TRACE_ALL_VMS, TRACE_CLASS_EXIT, TRACE_CLASS_HYPERCALL, TRACE_CLASS_IRQ, TRACE_CLASS_IVC,
+    TRACE_EXPORT_MAGIC, TRACE_EXPORT_VERSION, TRACE_IVC_BROADCAST_BEGIN,
+    TRACE_IVC_BROADCAST_COMMIT, TRACE_IVC_KICK, TraceExportHeader, TraceRecord,
+};
+use super::tracectx::{TRACE_CTX_SET, TRACE_CTX_TAKE, TraceContext};
+use super::virtio::{
+    self, HOTPLUG_FORCE_UNPLUG, HOTPLUG_MAGIC, HOTPLUG_PLUG, HOTPLUG_UNPLUG, HOTPLUG_VERSION,
+    HotplugEvent, MAX_HOTPLUG_SLOTS, MAX_VHOST_MEM_REGIONS, MAX_VHOST_QUEUES, VHOST_ATTACH,
+    VHOST_CONFIG_OFFSET, VHOST_CONFIG_SIZE, VHOST_DETACH, VHOST_EVENT_GONE, VHOST_EVENT_STATUS,
+    VHOST_MAGIC, VHOST_MEM_OFFSET, VHOST_NOTIFY, VHOST_QUEUES_OFFSET, VHOST_VERSION, VhostHeader,
+    VhostMemRegion, VhostQueue,
+};
+use super::uartshare::{ESCAPE_KEY, UART_TO_HYPERVISOR};
+use super::unknown_hvc::{
+    FORWARD_DONE, FORWARD_FREE, FORWARD_MAGIC, FORWARD_PENDING, FORWARD_SLOTS,
+    FORWARD_SLOTS_OFFSET, FORWARD_VERSION, ForwardHeader, ForwardSlot,
+};
+use super::vmdef::{VM_DEF_DTBO, VM_DEF_TOML};
+use super::vmi::{
+    VMI_ACCESS_EXEC, VMI_ACCESS_READ, VMI_ACCESS_WRITE, VMI_EVENT_MEM, VMI_EVENT_REG,
+    VMI_EVENTS_OFFSET, VMI_MAGIC, VMI_SINGLE_STEP, VMI_SUBSCRIBE, VMI_UNSUBSCRIBE, VMI_VERSION,
+    VMI_WATCH_MEM, VMI_WATCH_REG, VmiEvent, VmiLogHeader,
+};
+
+// Fast hypercall numbers.
+const _: () = assert!(AXVISOR_FAST_HVC_BASE == 0x1000_0000);
+const _: () = assert!(HVC_RT_DOORBELL == AXVISOR_FAST_HVC_BASE);
+const _: () = assert!(HVC_IVC_KICK == AXVISOR_FAST_HVC_BASE + 1);
+const _: () = assert!(HVC_IVC_BROADCAST == AXVISOR_FAST_HVC_BASE + 2);
+const _: () = assert!(HVC_WATCHDOG_KICK == AXVISOR_FAST_HVC_BASE + 3);
+const _: () = assert!(HVC_VM_DEFINE == AXVISOR_FAST_HVC_BASE + 4);
+const _: () = assert!(HVC_VCPU_SET_AFFINITY == AXVISOR_FAST_HVC_BASE + 5);
+const _: () = assert!(HVC_VM_SET_SHARES == AXVISOR_FAST_HVC_BASE + 6);
+const _: () = assert!(HVC_TRACE_CONTEXT == AXVISOR_FAST_HVC_BASE + 7);
+const _: () = assert!(HVC_TRACE_EXPORT == AXVISOR_FAST_HVC_BASE + 8);
+const _: () = assert!(HVC_STATS_QUERY == AXVISOR_FAST_HVC_BASE + 9);
+const _: () = assert!(HVC_VM_READY == AXVISOR_FAST_HVC_BASE + 10);
+const _: () = assert!(HVC_SYSTEM_SHUTDOWN == AXVISOR_FAST_HVC_BASE + 11);
+const _: () = assert!(HVC_VIRTIO_HOTPLUG == AXVISOR_FAST_HVC_BASE + 12);
+const _: () = assert!(HVC_FS_QUIESCE == AXVISOR_FAST_HVC_BASE + 13);
+const _: () = assert!(HVC_IVC_WAIT_SPACE == AXVISOR_FAST_HVC_BASE + 14);
+const _: () = assert!(HVC_FORWARD_COMPLETE == AXVISOR_FAST_HVC_BASE + 15);
+const _: () = assert!(HVC_CONSOLE_ATTACH == AXVISOR_FAST_HVC_BASE + 16);
+const _: () = assert!(HVC_CALL_ASYNC == AXVISOR_FAST_HVC_BASE + 17);
+const _: () = assert!(HVC_GET_RESULT == AXVISOR_FAST_HVC_BASE + 18);
+const _: () = assert!(HVC_VM_READ_GUEST_MEM == AXVISOR_FAST_HVC_BASE + 19);
+const _: () = assert!(HVC_VM_WRITE_GUEST_MEM == AXVISOR_FAST_HVC_BASE + 20);
+const _: () = assert!(HVC_VM_GET_VCPU_REGS == AXVISOR_FAST_HVC_BASE + 21);
+const _: () = assert!(HVC_HEATMAP_QUERY == AXVISOR_FAST_HVC_BASE + 22);
+const _: () = assert!(HVC_VMI_CONTROL == AXVISOR_FAST_HVC_BASE + 23);
+const _: () = assert!(HVC_IOREQ_CONTROL == AXVISOR_FAST_HVC_BASE + 24);
+const _: () = assert!(HVC_VHOST_CONTROL == AXVISOR_FAST_HVC_BASE + 25);
+const _: () = assert!(HVC_FB_FLIP == AXVISOR_FAST_HVC_BASE + 26);
+const _: () = assert!(HVC_FB_CONTROL == AXVISOR_FAST_HVC_BASE + 27);
+const _: () = assert!(HVC_UART_ASSIGN == AXVISOR_FAST_HVC_BASE + 28);
+const _: () = assert!(HVC_CPUFREQ_REQUEST == AXVISOR_FAST_HVC_BASE + 29);
+const _: () = assert!(HVC_VM_GROUP == AXVISOR_FAST_HVC_BASE + 30);
+const _: () = assert!(HVC_VM_DUMP == AXVISOR_FAST_HVC_BASE + 31);
+#[cfg(target_arch = "riscv64")]
+const _: () = assert!(crate::hal::arch::SBI_EXT_HVC == 0x48_5643);
+const _: () = assert!(AFFINITY_SELF == u64::MAX);
+
+// Trace context operations and size.
+const _: () = assert!(TRACE_CTX_SET == 0);
+const _: () = assert!(TRACE_CTX_TAKE == 1);
+const _: () = assert!(size_of::<TraceContext>() == 16);
+
+// Trace export classes, operations and layouts.
+const _: () = assert!(TRACE_ALL_VMS == u64::MAX);
+const _: () = assert!(TRACE_CLASS_EXIT == 0);
+const _: () = assert!(TRACE_CLASS_HYPERCALL == 1);
+const _: () = assert!(TRACE_CLASS_IRQ == 2);
+const _: () = assert!(TRACE_CLASS_IVC == 3);
+const _: () = assert!(TRACE_IVC_KICK == 0);
+const _: () = assert!(TRACE_IVC_BROADCAST_BEGIN == 1);
+const _: () = assert!(TRACE_IVC_BROADCAST_COMMIT == 2);
+const _: () = assert!(TRACE_EXPORT_MAGIC == u32::from_le_bytes(*b"AXTR"));
+const _: () = assert!(TRACE_EXPORT_VERSION == 1);
+const _: () = assert!(size_of::<TraceExportHeader>() == 16);
+const _: () = assert!(offset_of!(TraceExportHeader, magic) == 0);
+const _: () = assert!(offset_of!(TraceExportHeader, version) == 4);
+const _: () = assert!(offset_of!(TraceExportHeader, record_size) == 6);
+const _: () = assert!(offset_of!(TraceExportHeader, count) == 8);
+const _: () = assert!(offset_of!(TraceExportHeader, lost) == 12);
+const _: () = assert!(size_of::<TraceRecord>() == 32);
+const _: () = assert!(offset_of!(TraceRecord, time_ns) == 0);
+const _: () = assert!(offset_of!(TraceRecord, vm_id) == 8);
+const _: () = assert!(offset_of!(TraceRecord, vcpu_id) == 12);
+const _: () = assert!(offset_of!(TraceRecord, cpu) == 14);
+const _: () = assert!(offset_of!(TraceRecord, class) == 15);
+const _: () = assert!(offset_of!(TraceRecord, arg0) == 16);
+const _: () = assert!(offset_of!(TraceRecord, arg1) == 24);
+
+// Statistics query and layouts.
+const _: () = assert!(STATS_SELF == u64::MAX);
+const _: () = assert!(STATS_MAGIC == u32::from_le_bytes(*b"AXST"));
+const _: () = assert!(STATS_VERSION == 1);
+const _: () = assert!(STATS_EXIT_REASONS == 15);
+const _: () = assert!(size_of::<StatsHeader>() == 80);
+const _: () = assert!(offset_of!(StatsHeader, magic) == 0);
+const _: () = assert!(offset_of!(StatsHeader, version) == 4);
+const _: () = assert!(offset_of!(StatsHeader, vm_id) == 8);
+const _: () = assert!(offset_of!(StatsHeader, vcpus) == 16);
+const _: () = assert!(offset_of!(StatsHeader, vcpu_record_size) == 20);
+const _: () = assert!(offset_of!(StatsHeader, sampled_at_ns) == 24);
+const _: () = assert!(offset_of!(StatsHeader, ivc_bytes) == 32);
+const _: () = assert!(offset_of!(StatsHeader, period_ns) == 40);
+const _: () = assert!(offset_of!(StatsHeader, period_exits) == 48);
+const _: () = assert!(offset_of!(StatsHeader, period_run_ns) == 56);
+const _: () = assert!(offset_of!(StatsHeader, period_irqs) == 64);
+const _: () = assert!(size_of::<VCpuStatsRecord>() == 144);
+const _: () = assert!(offset_of!(VCpuStatsRecord, exits) == 0);
+const _: () = assert!(offset_of!(VCpuStatsRecord, run_ns) == 120);
+const _: () = assert!(offset_of!(VCpuStatsRecord, steal_ns) == 128);
+const _: () = assert!(offset_of!(VCpuStatsRecord, injected_irqs) == 136);
+
+// Formats of runtime VM definitions.
+const _: () = assert!(VM_DEF_TOML == 0);
+const _: () = assert!(VM_DEF_DTBO == 1);
+
+// Doorbell limits, guests size their doorbell tables with them.
+const _: () = assert!(MAX_DOORBELL_VMS == 128);
+const _: () = assert!(MAX_OUTBOUND_DOORBELLS == 8);
+const _: () = assert!(MAX_INBOUND_DOORBELLS == 16);
+
+// IVC channel types and constants.
+const _: () = assert!(IVCNotifyMode::Poll as u16 == 1);
+const _: () = assert!(IVCNotifyMode::Kick as u16 == 2);
+const _: () = assert!(IVCNotifyMode::Watermark as u16 == 4);
+const _: () = assert!(IVC_CHANNEL_BROADCAST == 3);
+const _: () = assert!(IVC_RING_MAGIC == u32::from_le_bytes(*b"IVCR"));
+const _: () = assert!(IVC_RING_VERSION == 2);
+const _: () = assert!(IVC_RING_F_NO_KICK == 1 << 0);
+const _: () = assert!(IVC_RING_F_PEER_GONE == 1 << 31);
+
+// Layout of the IVC channel header, at the start of every channel.
+const _: () = assert!(size_of::<IVCChannelHeader>() == 16);
+const _: () = assert!(offset_of!(IVCChannelHeader, publisher_id) == 0);
+const _: () = assert!(offset_of!(IVCChannelHeader, key) == 8);
+
+// Layout of the ring header, right after the channel header.
+const _: () = assert!(size_of::<IVCRing>() == 32);
+const _: () = assert!(offset_of!(IVCRing, head) == 0);
+const _: () = assert!(offset_of!(IVCRing, tail) == 4);
+const _: () = assert!(offset_of!(IVCRing, flags) == 8);
+const _: () = assert!(offset_of!(IVCRing, watermarks) == 12);
+const _: () = assert!(offset_of!(IVCRing, producer_credits) == 16);
+const _: () = assert!(offset_of!(IVCRing, consumer_credits) == 20);
+const _: () = assert!(offset_of!(IVCRing, space_waiters) == 24);
+const _: () = assert!(size_of::<IVCRingHeader>() == 88);
+const _: () = assert!(offset_of!(IVCRingHeader, magic) == 0);
+const _: () = assert!(offset_of!(IVCRingHeader, version) == 4);
+const _: () = assert!(offset_of!(IVCRingHeader, notify) == 6);
+const _: () = assert!(offset_of!(IVCRingHeader, slot_size) == 8);
+const _: () = assert!(offset_of!(IVCRingHeader, slot_count) == 12);
+const _: () = assert!(offset_of!(IVCRingHeader, slot_offsets) == 16);
+const _: () = assert!(offset_of!(IVCRingHeader, rings) == 24);
+
+// Layout of the broadcast header page.
+const _: () = assert!(size_of::<IVCBroadcastHeader>() == 16);
+const _: () = assert!(offset_of!(IVCBroadcastHeader, version) == 0);
+const _: () = assert!(offset_of!(IVCBroadcastHeader, updated_at_ns) == 8);
+
+// Hypervisor information pages.
+const _: () = assert!(HV_INFO_MAGIC == u32::from_le_bytes(*b"AXHV"));
+const _: () = assert!(VM_INFO_MAGIC == u32::from_le_bytes(*b"AXVM"));
+const _: () = assert!(HV_INFO_VERSION == 1);
+const _: () = assert!(ABI_LEVEL == 31);
+const _: () = assert!(HV_ARCH_AARCH64 == 1);
+const _: () = assert!(HV_ARCH_RISCV64 == 2);
+const _: () = assert!(HV_ARCH_X86_64 == 3);
+const _: () = assert!(VM_INFO_F_MANAGER == 1 << 0);
+const _: () = assert!(VM_INFO_F_PARTITIONED == 1 << 1);
+const _: () = assert!(VM_INFO_F_NESTED == 1 << 2);
+const _: () = assert!(VM_INFO_F_STAMPED == 1 << 3);
+const _: () = assert!(size_of::<HvInfo>() == 80);
+const _: () = assert!(offset_of!(HvInfo, magic) == 0);
+const _: () = assert!(offset_of!(HvInfo, version) == 4);
+const _: () = assert!(offset_of!(HvInfo, abi_level) == 8);
+const _: () = assert!(offset_of!(HvInfo, arch) == 12);
+const _: () = assert!(offset_of!(HvInfo, hv_major) == 16);
+const _: () = assert!(offset_of!(HvInfo, hv_minor) == 18);
+const _: () = assert!(offset_of!(HvInfo, hv_patch) == 20);
+const _: () = assert!(offset_of!(HvInfo, host_cpus) == 24);
+const _: () = assert!(offset_of!(HvInfo, fast_hvc_base) == 32);
+const _: () = assert!(offset_of!(HvInfo, counter_freq_hz) == 40);
+const _: () = assert!(offset_of!(HvInfo, platform) == 48);
+const _: () = assert!(size_of::<VmInfo>() == 120);
+const _: () = assert!(offset_of!(VmInfo, magic) == 0);
+const _: () = assert!(offset_of!(VmInfo, version) == 4);
+const _: () = assert!(offset_of!(VmInfo, vm_id) == 8);
+const _: () = assert!(offset_of!(VmInfo, vcpus) == 16);
+const _: () = assert!(offset_of!(VmInfo, flags) == 20);
+const _: () = assert!(offset_of!(VmInfo, memory_size) == 24);
+const _: () = assert!(offset_of!(VmInfo, name) == 32);
+const _: () = assert!(offset_of!(VmInfo, instance) == 96);
+const _: () = assert!(offset_of!(VmInfo, uuid) == 104);
+
+// Services page, after the hypervisor information pages.
+const _: () = assert!(SERVICES_MAGIC == u32::from_le_bytes(*b"AXSV"));
+const _: () = assert!(SERVICES_VERSION == 1);
+const _: () = assert!(SERVICE_VM_UNRESOLVED == u32::MAX);
+const _: () = assert!(SERVICE_PEER_SELF == u32::MAX);
+const _: () = assert!(SERVICE_DEV_VIRTIO_NET == 1);
+const _: () = assert!(SERVICE_DEV_VIRTIO_BLK == 2);
+const _: () = assert!(SERVICE_DEV_VIRTIO_VSOCK == 3);
+const _: () = assert!(SERVICE_DEV_POWER == 4);
+const _: () = assert!(SERVICE_DEV_DOORBELL == 5);
+const _: () = assert!(SERVICE_DEV_VIRTIO_CONSOLE == 6);
+const _: () = assert!(SERVICE_DEV_VIRTIO_HOTPLUG == 7);
+const _: () = assert!(SERVICE_DEV_VIRTIO_VHOST == 8);
+const _: () = assert!(SERVICE_DEV_VIRTIO_RNG == 9);
+const _: () = assert!(SERVICE_DEV_FRAMEBUFFER == 10);
+const _: () = assert!(size_of::<ServicesHeader>() == 16);
+const _: () = assert!(offset_of!(ServicesHeader, magic) == 0);
+const _: () = assert!(offset_of!(ServicesHeader, version) == 4);
+const _: () = assert!(offset_of!(ServicesHeader, peers) == 6);
+const _: () = assert!(offset_of!(ServicesHeader, channels) == 8);
+const _: () = assert!(offset_of!(ServicesHeader, devices) == 10);
+const _: () = assert!(size_of::<ServicePeer>() == 40);
+const _: () = assert!(offset_of!(ServicePeer, handle) == 0);
+const _: () = assert!(offset_of!(ServicePeer, vm_id) == 4);
+const _: () = assert!(offset_of!(ServicePeer, name) == 8);
+const _: () = assert!(size_of::<ServiceChannel>() == 48);
+const _: () = assert!(offset_of!(ServiceChannel, peer) == 0);
+const _: () = assert!(offset_of!(ServiceChannel, key) == 8);
+const _: () = assert!(offset_of!(ServiceChannel, name) == 16);
+const _: () = assert!(size_of::<ServiceDevice>() == 56);
+const _: () = assert!(offset_of!(ServiceDevice, kind) == 0);
+const _: () = assert!(offset_of!(ServiceDevice, irq) == 4);
+const _: () = assert!(offset_of!(ServiceDevice, base) == 8);
+const _: () = assert!(offset_of!(ServiceDevice, arg) == 16);
+const _: () = assert!(offset_of!(ServiceDevice, name) == 24);
+
+// Virtio hot-plug operations and controller.
+const _: () = assert!(HOTPLUG_PLUG == 0);
+const _: () = assert!(HOTPLUG_UNPLUG == 1);
+const _: () = assert!(HOTPLUG_FORCE_UNPLUG == 2);
+const _: () = assert!(HOTPLUG_MAGIC == u32::from_le_bytes(*b"AXHP"));
+const _: () = assert!(HOTPLUG_VERSION == 1);
+const _: () = assert!(MAX_HOTPLUG_SLOTS == 32);
+
+// Filesystem quiesce.
+const _: () = assert!(QUIESCE_AGENT_READY == 0);
+const _: () = assert!(QUIESCE_FROZEN == 1);
+const _: () = assert!(QUIESCE_THAWED == 2);
+const _: () = assert!(QUIESCE_AGENT_GONE == 3);
+const _: () = assert!(PowerEvent::FREEZE_REQUEST.bits() == 1 << 3);
+const _: () = assert!(PowerEvent::THAW_REQUEST.bits() == 1 << 4);
+const _: () = assert!(HotplugEvent::PLUGGED.bits() == 1 << 0);
+const _: () = assert!(HotplugEvent::UNPLUG_REQUESTED.bits() == 1 << 1);
+const _: () = assert!(HotplugEvent::UNPLUGGED.bits() == 1 << 2);
+
+// Lifecycle log page.
+const _: () = assert!(LIFECYCLE_MAGIC == u32::from_le_bytes(*b"AXLC"));
+const _: () = assert!(LIFECYCLE_VERSION == 1);
+const _: () = assert!(LIFECYCLE_RECORDS_OFFSET == 64);
+const _: () = assert!(LIFECYCLE_CAPACITY == 63);
+const _: () = assert!(LifecycleEvent::Created as u32 == 1);
+const _: () = assert!(LifecycleEvent::Booted as u32 == 2);
+const _: () = assert!(LifecycleEvent::Ready as u32 == 3);
+const _: () = assert!(LifecycleEvent::Crashed as u32 == 4);
+const _: () = assert!(LifecycleEvent::Destroyed as u32 == 5);
+const _: () = assert!(LifecycleEvent::PowerRequest as u32 == 6);
+const _: () = assert!(PowerRequest::PowerOff as u32 == 1);
+const _: () = assert!(PowerRequest::Reboot as u32 == 2);
+const _: () = assert!(size_of::<LifecycleLogHeader>() == 24);
+const _: () = assert!(offset_of!(LifecycleLogHeader, magic) == 0);
+const _: () = assert!(offset_of!(LifecycleLogHeader, version) == 4);
+const _: () = assert!(offset_of!(LifecycleLogHeader, record_size) == 6);
+const _: () = assert!(offset_of!(LifecycleLogHeader, capacity) == 8);
+const _: () = assert!(offset_of!(LifecycleLogHeader, head) == 16);
+const _: () = assert!(size_of::<LifecycleRecord>() == 64);
+const _: () = assert!(offset_of!(LifecycleRecord, sequence) == 0);
+const _: () = assert!(offset_of!(LifecycleRecord, time_ns) == 8);
+const _: () = assert!(offset_of!(LifecycleRecord, vm_id) == 16);
+const _: () = assert!(offset_of!(LifecycleRecord, event) == 20);
+const _: () = assert!(offset_of!(LifecycleRecord, detail) == 24);
+const _: () = assert!(offset_of!(LifecycleRecord, name) == 32);
+
+// Crash records written to the IVC channel of the manager VM.
+const _: () = assert!(CRASH_RECORD_MAGIC == u32::from_le_bytes(*b"AXCR"));
+const _: () = assert!(CRASH_RECORD_VERSION == 2);
+const _: () = assert!(CrashReason::FailEntry as u32 == 1);
+const _: () = assert!(CrashReason::RunError as u32 == 2);
+const _: () = assert!(size_of::<CrashRecord>() == 336);
+const _: () = assert!(offset_of!(CrashRecord, magic) == 0);
+const _: () = assert!(offset_of!(CrashRecord, version) == 4);
+const _: () = assert!(offset_of!(CrashRecord, sequence) == 8);
+const _: () = assert!(offset_of!(CrashRecord, vm_id) == 16);
+const _: () = assert!(offset_of!(CrashRecord, vcpu_id) == 24);
+const _: () = assert!(offset_of!(CrashRecord, reason) == 28);
+const _: () = assert!(offset_of!(CrashRecord, time_ns) == 32);
+const _: () = assert!(offset_of!(CrashRecord, window_gpa) == 40);
+const _: () = assert!(offset_of!(CrashRecord, window_size) == 48);
+const _: () = assert!(offset_of!(CrashRecord, reg_count) == 56);
+const _: () = assert!(offset_of!(CrashRecord, _reserved) == 60);
+const _: () = assert!(offset_of!(CrashRecord, regs) == 64);
+
+// Bridge window halves, shared with the peer node, which may run on another architecture.
+const _: () = assert!(BRIDGE_MAGIC == u32::from_le_bytes(*b"AXBR"));
+const _: () = assert!(BRIDGE_VERSION == 1);
+const _: () = assert!(BRIDGE_MAX_EXPORTS == 32);
+const _: () = assert!(BRIDGE_MAILBOX_SLOTS == 64);
+const _: () = assert!(BRIDGE_NAME_LEN == 32);
+const _: () = assert!(BRIDGE_EXPORT_FREE == 0);
+const _: () = assert!(BRIDGE_EXPORT_LIVE == 1);
+const _: () = assert!(BRIDGE_MSG_KICK == 1);
+const _: () = assert!(BRIDGE_MSG_ATTACH == 2);
+const _: () = assert!(BRIDGE_MSG_DETACH == 3);
+const _: () = assert!(size_of::<BridgeExport>() == 64);
+const _: () = assert!(offset_of!(BridgeExport, state) == 0);
+const _: () = assert!(offset_of!(BridgeExport, vm_id) == 4);
+const _: () = assert!(offset_of!(BridgeExport, key) == 8);
+const _: () = assert!(offset_of!(BridgeExport, offset) == 16);
+const _: () = assert!(offset_of!(BridgeExport, size) == 24);
+const _: () = assert!(offset_of!(BridgeExport, name) == 32);
+const _: () = assert!(size_of::<BridgeMessage>() == 24);
+const _: () = assert!(offset_of!(BridgeMessage, kind) == 0);
+const _: () = assert!(offset_of!(BridgeMessage, sender_vm) == 4);
+const _: () = assert!(offset_of!(BridgeMessage, publisher_vm) == 8);
+const _: () = assert!(offset_of!(BridgeMessage, publisher_node) == 12);
+const _: () = assert!(offset_of!(BridgeMessage, key) == 16);
+const _: () = assert!(size_of::<BridgeNodeHeader>() == 3616);
+const _: () = assert!(offset_of!(BridgeNodeHeader, magic) == 0);
+const _: () = assert!(offset_of!(BridgeNodeHeader, version) == 4);
+const _: () = assert!(offset_of!(BridgeNodeHeader, node) == 6);
+const _: () = assert!(offset_of!(BridgeNodeHeader, boot_id) == 8);
+const _: () = assert!(offset_of!(BridgeNodeHeader, generation) == 16);
+const _: () = assert!(offset_of!(BridgeNodeHeader, mbox_head) == 20);
+const _: () = assert!(offset_of!(BridgeNodeHeader, mbox_tail) == 24);
+const _: () = assert!(offset_of!(BridgeNodeHeader, exports) == 32);
+const _: () = assert!(offset_of!(BridgeNodeHeader, mailbox) == 2080);
+
+// Request page of the hypercall handler VMs.
+const _: () = assert!(FORWARD_MAGIC == u32::from_le_bytes(*b"AXFW"));
+const _: () = assert!(FORWARD_VERSION == 1);
+const _: () = assert!(FORWARD_SLOTS_OFFSET == 64);
+const _: () = assert!(FORWARD_SLOTS == 50);
+const _: () = assert!(FORWARD_FREE == 0);
+const _: () = assert!(FORWARD_PENDING == 1);
+const _: () = assert!(FORWARD_DONE == 2);
+const _: () = assert!(size_of::<ForwardHeader>() == 16);
+const _: () = assert!(offset_of!(ForwardHeader, magic) == 0);
+const _: () = assert!(offset_of!(ForwardHeader, version) == 4);
+const _: () = assert!(offset_of!(ForwardHeader, slot_size) == 6);
+const _: () = assert!(offset_of!(ForwardHeader, slots) == 8);
+const _: () = assert!(size_of::<ForwardSlot>() == 80);
+const _: () = assert!(offset_of!(ForwardSlot, state) == 0);
+const _: () = assert!(offset_of!(ForwardSlot, vm_id) == 4);
+const _: () = assert!(offset_of!(ForwardSlot, vcpu_id) == 8);
+const _: () = assert!(offset_of!(ForwardSlot, code) == 16);
+const _: () = assert!(offset_of!(ForwardSlot, args) == 24);
+const _: () = assert!(offset_of!(ForwardSlot, ret) == 72);
+
+// Console stream of the manager VMs.
+const _: () = assert!(CONSOLE_DETACH == u64::MAX);
+const _: () = assert!(CONSOLE_MUX_MAGIC == u32::from_le_bytes(*b"AXCM"));
+const _: () = assert!(CONSOLE_MUX_VERSION == 1);
+const _: () = assert!(CONSOLE_MUX_STREAM_OFFSET == 64);
+const _: () = assert!(CONSOLE_FRAME_ALIGN == 8);
+const _: () = assert!(CONSOLE_SRC_VIRTIO == 1);
+const _: () = assert!(CONSOLE_SRC_UEFI_SERIAL == 2);
+const _: () = assert!(size_of::<ConsoleMuxHeader>() == 24);
+const _: () = assert!(offset_of!(ConsoleMuxHeader, magic) == 0);
+const _: () = assert!(offset_of!(ConsoleMuxHeader, version) == 4);
+const _: () = assert!(offset_of!(ConsoleMuxHeader, frame_size) == 6);
+const _: () = assert!(offset_of!(ConsoleMuxHeader, capacity) == 8);
+const _: () = assert!(offset_of!(ConsoleMuxHeader, head) == 16);
+const _: () = assert!(size_of::<ConsoleFrame>() == 8);
+const _: () = assert!(offset_of!(ConsoleFrame, vm_id) == 0);
+const _: () = assert!(offset_of!(ConsoleFrame, len) == 4);
+const _: () = assert!(offset_of!(ConsoleFrame, source) == 6);
+
+// Results of the asynchronous hypercalls.
+const _: () = assert!(ASYNC_PENDING == 0);
+const _: () = assert!(ASYNC_DONE == 1);
+const _: () = assert!(ASYNC_FAILED == 2);
+const _: () = assert!(size_of::<AsyncResult>() == 16);
+const _: () = assert!(offset_of!(AsyncResult, state) == 0);
+const _: () = assert!(offset_of!(AsyncResult, error) == 4);
+const _: () = assert!(offset_of!(AsyncResult, value) == 8);
+
+// Introspection of guests by the manager VMs.
+const _: () = assert!(MAX_INTROSPECT_SIZE == 0x10000);
+const _: () = assert!(size_of::<VCpuRegs>() == 328);
+const _: () = assert!(offset_of!(VCpuRegs, vcpu_id) == 0);
+const _: () = assert!(offset_of!(VCpuRegs, state) == 4);
+const _: () = assert!(offset_of!(VCpuRegs, pcpu) == 8);
+const _: () = assert!(offset_of!(VCpuRegs, last_exit) == 12);
+const _: () = assert!(offset_of!(VCpuRegs, exits) == 16);
+const _: () = assert!(offset_of!(VCpuRegs, exit_details) == 24);
+const _: () = assert!(offset_of!(VCpuRegs, reg_count) == 40);
+const _: () = assert!(offset_of!(VCpuRegs, regs_taken_ns) == 48);
+const _: () = assert!(offset_of!(VCpuRegs, regs) == 56);
+const _: () = assert!(MAX_GUEST_REGS == 34);
+const _: () = assert!(GUEST_REGS <= MAX_GUEST_REGS);
+
+// Heatmaps of guest RAM.
+const _: () = assert!(HEATMAP_MAGIC == u32::from_le_bytes(*b"AXHM"));
+const _: () = assert!(HEATMAP_VERSION == 1);
+const _: () = assert!(MAX_HEATMAP_CELLS == 0x10000);
+const _: () = assert!(size_of::<HeatmapHeader>() == 32);
+const _: () = assert!(offset_of!(HeatmapHeader, magic) == 0);
+const _: () = assert!(offset_of!(HeatmapHeader, version) == 4);
+const _: () = assert!(offset_of!(HeatmapHeader, cells) == 8);
+const _: () = assert!(offset_of!(HeatmapHeader, intervals) == 12);
+const _: () = assert!(offset_of!(HeatmapHeader, granule) == 16);
+const _: () = assert!(offset_of!(HeatmapHeader, base_gpa) == 24);
+
+// Introspection events.
+const _: () = assert!(VMI_SUBSCRIBE == 0);
+const _: () = assert!(VMI_UNSUBSCRIBE == 1);
+const _: () = assert!(VMI_WATCH_MEM == 2);
+const _: () = assert!(VMI_WATCH_REG == 3);
+const _: () = assert!(VMI_SINGLE_STEP == 4);
+const _: () = assert!(VMI_ACCESS_READ == 1);
+const _: () = assert!(VMI_ACCESS_WRITE == 2);
+const _: () = assert!(VMI_ACCESS_EXEC == 4);
+const _: () = assert!(VMI_EVENT_MEM == 1);
+const _: () = assert!(VMI_EVENT_REG == 2);
+const _: () = assert!(VMI_MAGIC == u32::from_le_bytes(*b"AXVI"));
+const _: () = assert!(VMI_VERSION == 1);
+const _: () = assert!(VMI_EVENTS_OFFSET == 64);
+const _: () = assert!(size_of::<VmiLogHeader>() == 24);
+const _: () = assert!(VMI_EVENTS_OFFSET >= size_of::<VmiLogHeader>());
+const _: () = assert!(offset_of!(VmiLogHeader, magic) == 0);
+const _: () = assert!(offset_of!(VmiLogHeader, version) == 4);
+const _: () = assert!(offset_of!(VmiLogHeader, event_size) == 6);
+const _: () = assert!(offset_of!(VmiLogHeader, capacity) == 8);
+const _: () = assert!(offset_of!(VmiLogHeader, head) == 16);
+const _: () = assert!(size_of::<VmiEvent>() == 32);
+const _: () = assert!(offset_of!(VmiEvent, kind) == 0);
+const _: () = assert!(offset_of!(VmiEvent, vcpu_id) == 4);
+const _: () = assert!(offset_of!(VmiEvent, time_ns) == 8);
+const _: () = assert!(offset_of!(VmiEvent, addr) == 16);
+const _: () = assert!(offset_of!(VmiEvent, value) == 24);
+
+// I/O requests of emulated ranges.
+const _: () = assert!(IOREQ_ATTACH == 0);
+const _: () = assert!(IOREQ_DETACH == 1);
+const _: () = assert!(IOREQ_MAP == 2);
+const _: () = assert!(IOREQ_UNMAP == 3);
+const _: () = assert!(IOREQ_COMPLETE == 4);
+const _: () = assert!(IOREQ_FREE == 0);
+const _: () = assert!(IOREQ_PENDING == 1);
+const _: () = assert!(IOREQ_DONE == 2);
+const _: () = assert!(IOREQ_MAGIC == u32::from_le_bytes(*b"AXIO"));
+const _: () = assert!(IOREQ_VERSION == 1);
+const _: () = assert!(IOREQ_SLOTS_OFFSET == 64);
+const _: () = assert!(MAX_IOREQ_RANGES == 64);
+const _: () = assert!(size_of::<IoreqHeader>() == 24);
+const _: () = assert!(IOREQ_SLOTS_OFFSET >= size_of::<IoreqHeader>());
+const _: () = assert!(offset_of!(IoreqHeader, magic) == 0);
+const _: () = assert!(offset_of!(IoreqHeader, version) == 4);
+const _: () = assert!(offset_of!(IoreqHeader, slot_size) == 6);
+const _: () = assert!(offset_of!(IoreqHeader, slots) == 8);
+const _: () = assert!(offset_of!(IoreqHeader, requests) == 16);
+const _: () = assert!(size_of::<IoreqSlot>() == 24);
+const _: () = assert!(offset_of!(IoreqSlot, state) == 0);
+const _: () = assert!(offset_of!(IoreqSlot, is_write) == 4);
+const _: () = assert!(offset_of!(IoreqSlot, size) == 5);
+const _: () = assert!(offset_of!(IoreqSlot, addr) == 8);
+const _: () = assert!(offset_of!(IoreqSlot, data) == 16);
+
+// Virtio devices served by driver VMs.
+const _: () = assert!(VHOST_ATTACH == 0);
+const _: () = assert!(VHOST_DETACH == 1);
+const _: () = assert!(VHOST_NOTIFY == 2);
+const _: () = assert!(VHOST_EVENT_STATUS == 1 << 0);
+const _: () = assert!(VHOST_EVENT_GONE == 1 << 1);
+const _: () = assert!(VHOST_MAGIC == u32::from_le_bytes(*b"AXVH"));
+const _: () = assert!(VHOST_VERSION == 1);
+const _: () = assert!(MAX_VHOST_QUEUES == 8);
+const _: () = assert!(MAX_VHOST_MEM_REGIONS == 8);
+const _: () = assert!(VHOST_QUEUES_OFFSET == 64);
+const _: () = assert!(VHOST_MEM_OFFSET == 320);
+const _: () = assert!(VHOST_CONFIG_OFFSET == 512);
+const _: () = assert!(VHOST_CONFIG_SIZE == 256);
+const _: () = assert!(size_of::<VhostHeader>() == 48);
+const _: () = assert!(VHOST_QUEUES_OFFSET >= size_of::<VhostHeader>());
+const _: () = assert!(
+    VHOST_MEM_OFFSET >= VHOST_QUEUES_OFFSET + MAX_VHOST_QUEUES * size_of::<VhostQueue>()
+);
+const _: () = assert!(
+    VHOST_CONFIG_OFFSET >= VHOST_MEM_OFFSET + MAX_VHOST_MEM_REGIONS * size_of::<VhostMemRegion>()
+);
+const _: () = assert!(offset_of!(VhostHeader, magic) == 0);
+const _: () = assert!(offset_of!(VhostHeader, version) == 4);
+const _: () = assert!(offset_of!(VhostHeader, queues) == 6);
+const _: () = assert!(offset_of!(VhostHeader, device_id) == 8);
+const _: () = assert!(offset_of!(VhostHeader, status) == 12);
+const _: () = assert!(offset_of!(VhostHeader, driver_features) == 16);
+const _: () = assert!(offset_of!(VhostHeader, kicks) == 24);
+const _: () = assert!(offset_of!(VhostHeader, events) == 28);
+const _: () = assert!(offset_of!(VhostHeader, mem_regions) == 32);
+const _: () = assert!(offset_of!(VhostHeader, notifications) == 40);
+const _: () = assert!(size_of::<VhostQueue>() == 32);
+const _: () = assert!(offset_of!(VhostQueue, size) == 0);
+const _: () = assert!(offset_of!(VhostQueue, ready) == 2);
+const _: () = assert!(offset_of!(VhostQueue, desc) == 8);
+const _: () = assert!(offset_of!(VhostQueue, driver) == 16);
+const _: () = assert!(offset_of!(VhostQueue, device) == 24);
+const _: () = assert!(size_of::<VhostMemRegion>() == 24);
+const _: () = assert!(offset_of!(VhostMemRegion, gpa) == 0);
+const _: () = assert!(offset_of!(VhostMemRegion, size) == 8);
+const _: () = assert!(offset_of!(VhostMemRegion, backend_gpa) == 16);
+
+// Framebuffers shown by display VMs.
+const _: () = assert!(FB_ATTACH == 0);
+const _: () = assert!(FB_DETACH == 1);
+const _: () = assert!(FB_RELEASE == 2);
+const _: () = assert!(FB_EVENT_FLIP == 1 << 0);
+const _: () = assert!(FB_EVENT_GONE == 1 << 1);
+const _: () = assert!(FB_MAGIC == u32::from_le_bytes(*b"AXFB"));
+const _: () = assert!(FB_VERSION == 1);
+const _: () = assert!(FB_FORMAT_XRGB8888 == 1);
+const _: () = assert!(MAX_FB_BUFFERS == 4);
+const _: () = assert!(size_of::<FbHeader>() == 48);
+const _: () = assert!(offset_of!(FbHeader, magic) == 0);
+const _: () = assert!(offset_of!(FbHeader, version) == 4);
+const _: () = assert!(offset_of!(FbHeader, buffers) == 6);
+const _: () = assert!(offset_of!(FbHeader, width) == 8);
+const _: () = assert!(offset_of!(FbHeader, height) == 12);
+const _: () = assert!(offset_of!(FbHeader, stride) == 16);
+const _: () = assert!(offset_of!(FbHeader, format) == 20);
+const _: () = assert!(offset_of!(FbHeader, buffer_size) == 24);
+const _: () = assert!(offset_of!(FbHeader, front) == 32);
+const _: () = assert!(offset_of!(FbHeader, events) == 36);
+const _: () = assert!(offset_of!(FbHeader, flips) == 40);
+
+// Shared UART.
+const _: () = assert!(UART_TO_HYPERVISOR == u64::MAX);
+const _: () = assert!(ESCAPE_KEY == 0x1d);
+
+// Interrupt policy.
+const _: () = assert!(IRQ_THROTTLED == -2);
+
+// CPU frequency.
+const _: () = assert!(CPUFREQ_DEFAULT == 0);
+const _: () = assert!(CPUFREQ_MAX == 100);
+
+// VM groups.
+const _: () = assert!(GROUP_CREATE == 0);
+const _: () = assert!(GROUP_MOVE == 1);
+const _: () = assert!(GROUP_DESTROY == 2);
+const _: () = assert!(GROUP_NONE == 0);
+
+// Core dumps streamed to the manager VMs.
+const _: () = assert!(NT_PRSTATUS == 1);
+const _: () = assert!(DUMP_STREAM_MAGIC == u32::from_le_bytes(*b"AXDS"));
+const _: () = assert!(DUMP_STREAM_VERSION == 1);
+const _: () = assert!(DUMP_STREAM_DATA_OFFSET == 64);
+const _: () = assert!(DUMP_CHUNK_FREE == 0);
+const _: () = assert!(DUMP_CHUNK_READY == 1);
+const _: () = assert!(DUMP_STREAM_END == 2);
+const _: () = assert!(size_of::<DumpStreamHeader>() == 32);
+const _: () = assert!(offset_of!(DumpStreamHeader, state) == 8);
+const _: () = assert!(offset_of!(DumpStreamHeader, error) == 12);
+const _: () = assert!(offset_of!(DumpStreamHeader, offset) == 16);
+const _: () = assert!(offset_of!(DumpStreamHeader, size) == 24);
+
+/// Fast hypercalls handled by the vCPU loop, see [`crate::vmm::vcpus`].
+const VCPU_LOOP_HVCS: [u64; 12] = [
+    HVC_RT_DOORBELL,
+    HVC_IVC_KICK,
+    HVC_IVC_BROADCAST,
+    HVC_WATCHDOG_KICK,
+    HVC_VCPU_SET_AFFINITY,
+    HVC_VM_SET_SHARES,
+    HVC_TRACE_CONTEXT,
+    HVC_VM_READY,
+    HVC_SYSTEM_SHUTDOWN,
+    HVC_VIRTIO_HOTPLUG,
+    HVC_FS_QUIESCE,
+    HVC_IVC_WAIT_SPACE,
+];
+
+/// Fast hypercalls handled by a service, see [`crate::vmm::hvc::HvcService`].
+const SERVICE_HVCS: [u64; 20] = [
+    HVC_VM_DEFINE,
+    HVC_TRACE_EXPORT,
+    HVC_STATS_QUERY,
+    HVC_FORWARD_COMPLETE,
+    HVC_CONSOLE_ATTACH,
+    HVC_CALL_ASYNC,
+    HVC_GET_RESULT,
+    HVC_VM_READ_GUEST_MEM,
+    HVC_VM_WRITE_GUEST_MEM,
+    HVC_VM_GET_VCPU_REGS,
+    HVC_HEATMAP_QUERY,
+    HVC_VMI_CONTROL,
+    HVC_IOREQ_CONTROL,
+    HVC_VHOST_CONTROL,
+    HVC_FB_FLIP,
+    HVC_FB_CONTROL,
+    HVC_UART_ASSIGN,
+    HVC_CPUFREQ_REQUEST,
+    HVC_VM_GROUP,
+    HVC_VM_DUMP,
+];
+
+// Every fast hypercall is handled somewhere.
+const _: () = assert!(
+    VCPU_LOOP_HVCS.len() + SERVICE_HVCS.len()
+        == (HVC_VM_DUMP - AXVISOR_FAST_HVC_BASE) as usize + 1
+);
+
+/// Outcome of a runtime conformance check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckOutcome {
+    Passed,
+    Failed(String),
+    /// The check doesn't apply to the current state of the guest.
+    Skipped(&'static str),
+}
+
+/// A runtime conformance check, see [`check_vm`].
+#[derive(Debug, Clone)]
+pub struct ConformanceCheck {
+    /// What was checked.
+    pub name: String,
+    pub outcome: CheckOutcome,
+}
+
+fn check_hypercalls(checks: &mut Vec<ConformanceCheck>) {
+    for nr in VCPU_LOOP_HVCS {
+        checks.push(ConformanceCheck {
+            name: format!("hypercall {:#x}", nr),
+            outcome: match hvc::service_of(nr) {
+                None => CheckOutcome::Passed,
+                Some(service) => CheckOutcome::Failed(format!(
+                    "claimed by service {}, which never sees it",
+                    service.name
+                )),
+            },
+        });
+    }
+    for nr in SERVICE_HVCS {
+        checks.push(ConformanceCheck {
+            name: format!("hypercall {:#x}", nr),
+            outcome: match hvc::service_of(nr) {
+                Some(_) => CheckOutcome::Passed,
+                None => CheckOutcome::Failed("no service registered".into()),
+            },
+        });
+    }
+}
+
+/// Runs the runtime conformance checks against the guest of `vm`, see the [module docs](self).
+///
+/// A running VM is paused for the duration of the checks and resumed afterwards; a suspended VM
+/// stays suspended.
+pub fn check_vm(vm: &VMRef) -> AxResult<Vec<ConformanceCheck>> {
+    let status = vm.vm_status();
+    if !matches!(status, VMStatus::Running | VMStatus::Suspended) {
+        return ax_err!(
+            BadState,
+            format!("VM[{}] is {:?}, not running", vm.id(), status)
+        );
+    }
+    if status == VMStatus::Running {
+        coredump::suspend(vm);
+    }
+    if !coredump::wait_quiesced(vm) {
+        if status == VMStatus::Running {
+            coredump::resume(vm);
+        }
+        return ax_err!(TimedOut, format!("VM[{}] vCPUs did not pause", vm.id()));
+    }
+
+    let mut checks = Vec::new();
+    check_hypercalls(&mut checks);
+    virtio::check_conformance(vm, &mut checks);
+    if status == VMStatus::Running {
+        coredump::resume(vm);
+    }
+    Ok(checks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the bytes of `value`, as written to a page shared with a guest.
+    fn encode<T>(value: &T) -> Vec<u8> {
+        // SAFETY: `value` is a `repr(C)` record of the guest ABI, without padding.
+        unsafe { core::slice::from_raw_parts((value as *const T).cast::<u8>(), size_of::<T>()) }
+            .to_vec()
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn fast_hypercalls_are_dispatched_once() {
+        let mut dispatched: Vec<u64> = VCPU_LOOP_HVCS
+            .iter()
+            .chain(&SERVICE_HVCS)
+            .copied()
+            .collect();
+        dispatched.sort_unstable();
+        let defined: Vec<u64> = (AXVISOR_FAST_HVC_BASE..=HVC_VM_DUMP).collect();
+        assert_eq!(dispatched, defined);
+    }
+
+    #[test]
+    fn hypercall_counts_are_bounded() {
+        assert_eq!(hvc::arg_count(0, 16, "count").unwrap(), 0);
+        assert_eq!(hvc::arg_count(16, 16, "count").unwrap(), 16);
+        assert!(hvc::arg_count(17, 16, "count").is_err());
+        assert!(hvc::arg_count(u64::MAX, 16, "count").is_err());
+    }
+
+    #[test]
+    fn ivc_notify_modes_round_trip() {
+        for mode in [
+            IVCNotifyMode::Poll,
+            IVCNotifyMode::Kick,
+            IVCNotifyMode::Watermark,
+        ] {
+            assert_eq!(IVCNotifyMode::try_from(mode as u64).ok(), Some(mode));
+        }
+        assert!(IVCNotifyMode::try_from(0).is_err());
+        // The broadcast channel type isn't a notification mode.
+        assert!(IVCNotifyMode::try_from(IVC_CHANNEL_BROADCAST).is_err());
+    }
+
+    #[test]
+    fn crash_reasons_encode_as_signals() {
+        assert_eq!(CrashReason::FailEntry as u32, 1);
+        assert_eq!(CrashReason::FailEntry.signal(), 4);
+        assert_eq!(CrashReason::RunError as u32, 2);
+        assert_eq!(CrashReason::RunError.signal(), 11);
+    }
+
+    #[test]
+    fn trace_records_encode_little_endian() {
+        let record = TraceRecord {
+            time_ns: 0x0102_0304_0506_0708,
+            vm_id: 3,
+            vcpu_id: 0x0405,
+            cpu: 6,
+            class: TRACE_CLASS_IVC,
+            arg0: 0x1122_3344_5566_7788,
+            arg1: u64::MAX,
+        };
+        let bytes = encode(&record);
+        assert_eq!(u64_at(&bytes, 0), record.time_ns);
+        assert_eq!(u32_at(&bytes, 8), 3);
+        assert_eq!(bytes[12..14], [0x05, 0x04]);
+        assert_eq!(bytes[14], 6);
+        assert_eq!(bytes[15], TRACE_CLASS_IVC);
+        assert_eq!(u64_at(&bytes, 16), record.arg0);
+        assert_eq!(u64_at(&bytes, 24), u64::MAX);
+    }
+
+    #[test]
+    fn vmi_events_decode_from_their_ring_slot() {
+        let capacity = 4;
+        let mut data = vec![0u8; VMI_EVENTS_OFFSET + capacity * size_of::<VmiEvent>()];
+        for n in 0..6u64 {
+            let event = VmiEvent {
+                kind: VMI_EVENT_REG,
+                vcpu_id: n as u32,
+                time_ns: 1000 + n,
+                addr: 0xc000_0080,
+                value: n << 32,
+            };
+            let slot = (n as usize % capacity) * size_of::<VmiEvent>();
+            data[VMI_EVENTS_OFFSET + slot..][..size_of::<VmiEvent>()]
+                .copy_from_slice(&encode(&event));
+        }
+        // Events 4 and 5 overwrote events 0 and 1, a reader decodes them at `n % capacity`.
+        for n in 2..6u64 {
+            let slot = &data[VMI_EVENTS_OFFSET + (n as usize % capacity) * size_of::<VmiEvent>()..];
+            assert_eq!(u32_at(slot, 0), VMI_EVENT_REG);
+            assert_eq!(u32_at(slot, 4), n as u32);
+            assert_eq!(u64_at(slot, 8), 1000 + n);
+            assert_eq!(u64_at(slot, 16), 0xc000_0080);
+            assert_eq!(u64_at(slot, 24), n << 32);
+        }
+    }
+
+    #[test]
+    fn crash_records_decode_their_registers() {
+        let mut regs = [0; MAX_GUEST_REGS];
+        regs.iter_mut()
+            .enumerate()
+            .for_each(|(i, reg)| *reg = i as u64 * 0x101);
+        let record = CrashRecord {
+            magic: CRASH_RECORD_MAGIC,
+            version: CRASH_RECORD_VERSION,
+            sequence: 7,
+            vm_id: 2,
+            vcpu_id: 1,
+            reason: CrashReason::RunError as u32,
+            time_ns: 42,
+            window_gpa: 0x8000_0000,
+            window_size: 0x1000,
+            reg_count: MAX_GUEST_REGS as u32,
+            _reserved: 0,
+            regs,
+        };
+        let bytes = encode(&record);
+        assert_eq!(u32_at(&bytes, 0), CRASH_RECORD_MAGIC);
+        assert_eq!(u32_at(&bytes, 4), CRASH_RECORD_VERSION);
+        assert_eq!(u32_at(&bytes, 28), CrashReason::RunError as u32);
+        assert_eq!(u32_at(&bytes, 56) as usize, MAX_GUEST_REGS);
+        for (i, reg) in regs.iter().enumerate() {
+            assert_eq!(u64_at(&bytes, 64 + i * 8), *reg);
+        }
+    }
+}
@@ -0,0 +1,562 @@
+//! ACPI tables of x86 guests.
+//!
+//! x86 guests have no device tree: modern kernels find their CPUs and platform devices in ACPI
+//! tables. A VM whose config has an `[acpi]` section gets a minimal set of tables, written to its
+//! memory before it boots:
+//!
+//! ```toml
+//! [acpi]
+//! # Guest physical address of the tables, 16-byte aligned and in guest memory. Defaults to
+//! # 0xe_0000, in the BIOS area scanned for the RSDP.
+//! gpa = 0x000e_0000
+//! # I/O APIC described in the MADT, usually passed through. Optional.
+//! ioapic_base = 0xfec0_0000
+//! # Guest physical base of the page of the sleep and reset registers, page-aligned and outside
+//! # guest memory. Optional.
+//! power_gpa = 0xfed0_0000
+//! ```
+//!
+//! The tables are:
+//! - the RSDP, at `gpa`, pointing to both the RSDT and the XSDT;
+//! - a hardware-reduced FADT: there are no fixed ACPI hardware registers (PM timer, SCI). With
+//!   `power_gpa`, it points to the sleep control and status registers and to the reset register,
+//!   at offsets 0, 1 and 2 of the page. Entering S5 powers the VM off and writing the reset value
+//!   reboots it, see [`crate::vmm::reboot`]. Entering S3 suspends the VM to RAM if its config lets
+//!   it, see [`crate::vmm::sleep`];
+//! - the MADT, with a local APIC per vCPU, whose APIC ID is the vCPU ID as in
+//!   [`crate::vmm::x2apic`], and the I/O APIC if configured;
+//! - the DSDT, with a `LNRO0005` (virtio-mmio) device per virtio device of the VM config,
+//!   virtio-console devices included (see [`crate::vmm::virtio`]). Their interrupt is the
+//!   configured vector, and the `_S5_` sleep type with `power_gpa`, `_S3_` too if the VM may
+//!   suspend to RAM.
+//!
+//! The BIOS of the VM must leave the range of the tables alone and report it as reserved, which
+//! is what BIOSes do with the default one. Hot-plugged virtio devices aren't described.
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use axaddrspace::GuestPhysAddr;
+use axaddrspace::device::AccessWidth;
+use axerrno::{AxResult, ax_err, ax_err_type};
+use memory_addr::PAGE_SIZE_4K;
+
+use crate::vmm::VMRef;
+use crate::vmm::images::load_vm_image_from_memory;
+use crate::vmm::mmio::{self, MmioTrapHandler};
+use crate::vmm::reboot::{self, PowerRequest};
+use crate::vmm::sleep;
+
+const DEFAULT_GPA: usize = 0xe_0000;
+/// End of the BIOS area scanned for the RSDP.
+const BIOS_AREA_END: usize = 0x10_0000;
+
+const LOCAL_APIC_BASE: u32 = 0xfee0_0000;
+
+const OEM_ID: &[u8; 6] = b"AXVSOR";
+const OEM_TABLE_ID: &[u8; 8] = b"AXVISOR ";
+const CREATOR_ID: &[u8; 4] = b"AXVS";
+const HYPERVISOR_VENDOR: u64 = u64::from_le_bytes(*b"AXVISOR\0");
+
+const HEADER_LEN: usize = 36;
+const RSDP_LEN: usize = 36;
+const FADT_LEN: usize = 276;
+
+/// `Flags` of the FADT: the platform has no fixed ACPI hardware.
+const FADT_F_HW_REDUCED_ACPI: u32 = 1 << 20;
+/// `Flags` of the FADT: the reset register is supported.
+const FADT_F_RESET_REG_SUP: u32 = 1 << 10;
+/// `IAPC_BOOT_ARCH` of the FADT: there is no VGA.
+const FADT_BOOT_VGA_NOT_PRESENT: u16 = 1 << 2;
+
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_LOCAL_X2APIC: u8 = 9;
+const MADT_LAPIC_ENABLED: u32 = 1;
+
+/// Offsets of the registers in the page at `power_gpa`.
+const REG_SLEEP_CONTROL: usize = 0;
+const REG_SLEEP_STATUS: usize = 1;
+const REG_RESET: usize = 2;
+/// Value of the reset register rebooting the VM.
+const RESET_VALUE: u8 = 1;
+/// `SLP_TYP` and `SLP_EN` of the sleep control register.
+const SLP_TYP_SHIFT: u8 = 2;
+const SLP_TYP_MASK: u8 = 0x7;
+const SLP_EN: u8 = 1 << 5;
+/// Sleep type of S3 (suspend to RAM), in `_S3_` and `SLP_TYP`.
+const SLP_TYP_S3: u8 = 3;
+/// Sleep type of S5 (soft off), in `_S5_` and `SLP_TYP`.
+const SLP_TYP_S5: u8 = 5;
+/// `WAK_STS` of the sleep status register.
+const WAK_STS: u8 = 1 << 7;
+
+/// Hardware ID of virtio-mmio devices.
+const VIRTIO_MMIO_HID: &str = "LNRO0005";
+const VIRTIO_MMIO_SIZE: u32 = 0x1000;
+
+fn checksum(bytes: &[u8]) -> u8 {
+    0u8.wrapping_sub(bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)))
+}
+
+/// Returns a table with a header for `signature` and `body`, its checksum set.
+fn table(signature: &[u8; 4], revision: u8, body: &[u8]) -> Vec<u8> {
+    let mut table = Vec::with_capacity(HEADER_LEN + body.len());
+    table.extend_from_slice(signature);
+    table.extend_from_slice(&((HEADER_LEN + body.len()) as u32).to_le_bytes());
+    table.push(revision);
+    table.push(0); // checksum
+    table.extend_from_slice(OEM_ID);
+    table.extend_from_slice(OEM_TABLE_ID);
+    table.extend_from_slice(&1u32.to_le_bytes()); // OEM revision
+    table.extend_from_slice(CREATOR_ID);
+    table.extend_from_slice(&1u32.to_le_bytes()); // creator revision
+    table.extend_from_slice(body);
+    table[9] = checksum(&table);
+    table
+}
+
+fn rsdp(rsdt: u32, xsdt: u64) -> Vec<u8> {
+    let mut rsdp = Vec::with_capacity(RSDP_LEN);
+    rsdp.extend_from_slice(b"RSD PTR ");
+    rsdp.push(0); // checksum of the ACPI 1.0 part
+    rsdp.extend_from_slice(OEM_ID);
+    rsdp.push(2); // revision
+    rsdp.extend_from_slice(&rsdt.to_le_bytes());
+    rsdp.extend_from_slice(&(RSDP_LEN as u32).to_le_bytes());
+    rsdp.extend_from_slice(&xsdt.to_le_bytes());
+    rsdp.push(0); // extended checksum
+    rsdp.extend_from_slice(&[0; 3]);
+    rsdp[8] = checksum(&rsdp[..20]);
+    rsdp[32] = checksum(&rsdp);
+    rsdp
+}
+
+/// A Generic Address Structure of a byte register in system memory.
+fn gas(addr: u64) -> [u8; 12] {
+    let mut gas = [0, 8, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+    gas[4..].copy_from_slice(&addr.to_le_bytes());
+    gas
+}
+
+fn fadt(dsdt: u64, power_gpa: Option<u64>) -> Vec<u8> {
+    let mut body = [0u8; FADT_LEN - HEADER_LEN];
+    let mut put = |offset: usize, bytes: &[u8]| {
+        body[offset - HEADER_LEN..offset - HEADER_LEN + bytes.len()].copy_from_slice(bytes)
+    };
+    put(40, &(dsdt as u32).to_le_bytes());
+    put(109, &FADT_BOOT_VGA_NOT_PRESENT.to_le_bytes());
+    let mut flags = FADT_F_HW_REDUCED_ACPI;
+    if let Some(base) = power_gpa {
+        flags |= FADT_F_RESET_REG_SUP;
+        put(116, &gas(base + REG_RESET as u64));
+        put(128, &[RESET_VALUE]);
+        put(244, &gas(base + REG_SLEEP_CONTROL as u64));
+        put(256, &gas(base + REG_SLEEP_STATUS as u64));
+    }
+    put(112, &flags.to_le_bytes());
+    put(131, &[5]); // minor version, ACPI 6.5
+    put(140, &dsdt.to_le_bytes());
+    put(268, &HYPERVISOR_VENDOR.to_le_bytes());
+    table(b"FACP", 6, &body)
+}
+
+fn madt(vcpus: usize, ioapic_base: Option<u32>) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&LOCAL_APIC_BASE.to_le_bytes());
+    body.extend_from_slice(&0u32.to_le_bytes()); // no 8259
+    for id in 0..vcpus as u32 {
+        if id < 0xff {
+            body.extend_from_slice(&[MADT_LOCAL_APIC, 8, id as u8, id as u8]);
+            body.extend_from_slice(&MADT_LAPIC_ENABLED.to_le_bytes());
+        } else {
+            body.extend_from_slice(&[MADT_LOCAL_X2APIC, 16, 0, 0]);
+            body.extend_from_slice(&id.to_le_bytes());
+            body.extend_from_slice(&MADT_LAPIC_ENABLED.to_le_bytes());
+            body.extend_from_slice(&id.to_le_bytes()); // ACPI processor UID
+        }
+    }
+    if let Some(base) = ioapic_base {
+        body.extend_from_slice(&[MADT_IO_APIC, 12, 0, 0]);
+        body.extend_from_slice(&base.to_le_bytes());
+        body.extend_from_slice(&0u32.to_le_bytes()); // GSI base
+    }
+    table(b"APIC", 5, &body)
+}
+
+/// Encoders of the few AML constructs of the DSDT.
+mod aml {
+    use alloc::vec::Vec;
+
+    const NAME_OP: u8 = 0x08;
+    const BYTE_PREFIX: u8 = 0x0a;
+    const DWORD_PREFIX: u8 = 0x0c;
+    const STRING_PREFIX: u8 = 0x0d;
+    const SCOPE_OP: u8 = 0x10;
+    const BUFFER_OP: u8 = 0x11;
+    const PACKAGE_OP: u8 = 0x12;
+    const EXT_OP_PREFIX: u8 = 0x5b;
+    const DEVICE_OP: u8 = 0x82;
+
+    const MEMORY32_FIXED: u8 = 0x86;
+    const EXTENDED_INTERRUPT: u8 = 0x89;
+    const END_TAG: u8 = 0x79;
+    /// Extended interrupt flags: consumer, edge-triggered, active high, exclusive.
+    const INTERRUPT_CONSUMER_EDGE: u8 = 0x03;
+
+    /// Appends `body` prefixed with its package length.
+    fn package(out: &mut Vec<u8>, body: &[u8]) {
+        // The length includes its own encoding, one to four bytes.
+        let len = body.len();
+        let total = match len + 1 {
+            n if n < 0x40 => n,
+            n if n + 1 < 0x1000 => n + 1,
+            n if n + 2 < 0x10_0000 => n + 2,
+            n => n + 3,
+        };
+        let extra = match total {
+            n if n < 0x40 => 0,
+            n if n < 0x1000 => 1,
+            n if n < 0x10_0000 => 2,
+            _ => 3,
+        };
+        if extra == 0 {
+            out.push(total as u8);
+        } else {
+            out.push(((extra as u8) << 6) | (total & 0xf) as u8);
+            for i in 0..extra {
+                out.push((total >> (4 + 8 * i)) as u8);
+            }
+        }
+        out.extend_from_slice(body);
+    }
+
+    pub fn name_seg(out: &mut Vec<u8>, name: &str) {
+        debug_assert_eq!(name.len(), 4);
+        out.extend_from_slice(name.as_bytes());
+    }
+
+    pub fn string(out: &mut Vec<u8>, s: &str) {
+        out.push(STRING_PREFIX);
+        out.extend_from_slice(s.as_bytes());
+        out.push(0);
+    }
+
+    pub fn dword(out: &mut Vec<u8>, val: u32) {
+        out.push(DWORD_PREFIX);
+        out.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// `Name(name, value)`, `value` encoded by `data`.
+    pub fn name(out: &mut Vec<u8>, name: &str, data: impl FnOnce(&mut Vec<u8>)) {
+        out.push(NAME_OP);
+        name_seg(out, name);
+        data(out);
+    }
+
+    /// `Package() { values }`, of byte constants.
+    pub fn byte_package(out: &mut Vec<u8>, values: &[u8]) {
+        out.push(PACKAGE_OP);
+        let mut inner = vec![values.len() as u8];
+        for val in values {
+            inner.extend_from_slice(&[BYTE_PREFIX, *val]);
+        }
+        package(out, &inner);
+    }
+
+    /// `Scope(path) { body }`.
+    pub fn scope(out: &mut Vec<u8>, path: &[u8], body: &[u8]) {
+        out.push(SCOPE_OP);
+        let mut inner = path.to_vec();
+        inner.extend_from_slice(body);
+        package(out, &inner);
+    }
+
+    /// `Device(name) { body }`.
+    pub fn device(out: &mut Vec<u8>, name: &str, body: &[u8]) {
+        out.extend_from_slice(&[EXT_OP_PREFIX, DEVICE_OP]);
+        let mut inner = Vec::new();
+        name_seg(&mut inner, name);
+        inner.extend_from_slice(body);
+        package(out, &inner);
+    }
+
+    /// `ResourceTemplate() { Memory32Fixed(ReadWrite, base, size) Interrupt(...) { irq } }`.
+    pub fn mmio_resources(out: &mut Vec<u8>, base: u32, size: u32, irq: u32) {
+        let mut template = vec![MEMORY32_FIXED, 9, 0, 1];
+        template.extend_from_slice(&base.to_le_bytes());
+        template.extend_from_slice(&size.to_le_bytes());
+        template.extend_from_slice(&[EXTENDED_INTERRUPT, 6, 0, INTERRUPT_CONSUMER_EDGE, 1]);
+        template.extend_from_slice(&irq.to_le_bytes());
+        template.extend_from_slice(&[END_TAG, 0]);
+
+        out.push(BUFFER_OP);
+        let mut inner = vec![BYTE_PREFIX, template.len() as u8];
+        inner.extend_from_slice(&template);
+        package(out, &inner);
+    }
+}
+
+/// A virtio-mmio device of the VM config.
+struct VirtioDevice {
+    base: u32,
+    irq: u32,
+}
+
+fn virtio_devices(raw_cfg: &toml::Table) -> AxResult<Vec<VirtioDevice>> {
+    let device = |section: &str, entry: &toml::Value| {
+        let int = |key: &str| {
+            entry
+                .get(key)
+                .and_then(|v| v.as_integer())
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or_else(|| {
+                    ax_err_type!(
+                        InvalidInput,
+                        format!(
+                            "acpi: `{}` device with a missing or invalid `{}`",
+                            section, key
+                        )
+                    )
+                })
+        };
+        Ok::<_, axerrno::AxError>(VirtioDevice {
+            base: int("base")?,
+            irq: int("irq")?,
+        })
+    };
+
+    let mut devices = Vec::new();
+    for section in ["virtio_net", "virtio_blk", "virtio_console"] {
+        for entry in raw_cfg
+            .get(section)
+            .and_then(|v| v.as_array())
+            .map(|entries| entries.as_slice())
+            .unwrap_or_default()
+        {
+            devices.push(device(section, entry)?);
+        }
+    }
+    if let Some(entry) = raw_cfg.get("virtio_vsock") {
+        devices.push(device("virtio_vsock", entry)?);
+    }
+    Ok(devices)
+}
+
+fn dsdt(devices: &[VirtioDevice], sleep_states: bool, s3: bool) -> Vec<u8> {
+    let mut sb = Vec::new();
+    for (uid, device) in devices.iter().enumerate() {
+        let mut body = Vec::new();
+        aml::name(&mut body, "_HID", |out| aml::string(out, VIRTIO_MMIO_HID));
+        aml::name(&mut body, "_UID", |out| aml::dword(out, uid as u32));
+        aml::name(&mut body, "_CRS", |out| {
+            aml::mmio_resources(out, device.base, VIRTIO_MMIO_SIZE, device.irq)
+        });
+        aml::device(&mut sb, &format!("V{:03X}", uid), &body);
+    }
+    let mut aml = Vec::new();
+    if sleep_states && s3 {
+        aml::name(&mut aml, "_S3_", |out| {
+            aml::byte_package(out, &[SLP_TYP_S3, 0])
+        });
+    }
+    if sleep_states {
+        aml::name(&mut aml, "_S5_", |out| {
+            aml::byte_package(out, &[SLP_TYP_S5, 0])
+        });
+    }
+    aml::scope(&mut aml, b"\\_SB_", &sb);
+    table(b"DSDT", 2, &aml)
+}
+
+fn align8(offset: usize) -> usize {
+    offset.next_multiple_of(8)
+}
+
+/// Builds the ACPI tables of a VM with `vcpus` vCPUs, placed at `gpa`.
+fn build_tables(
+    gpa: usize,
+    vcpus: usize,
+    ioapic_base: Option<u32>,
+    power_gpa: Option<usize>,
+    s3: bool,
+    devices: &[VirtioDevice],
+) -> Vec<u8> {
+    let madt = madt(vcpus, ioapic_base);
+    let dsdt = dsdt(devices, power_gpa.is_some(), s3);
+
+    // RSDP, XSDT, RSDT, FADT, MADT then DSDT
+    let xsdt_off = align8(RSDP_LEN);
+    let rsdt_off = align8(xsdt_off + HEADER_LEN + 2 * 8);
+    let fadt_off = align8(rsdt_off + HEADER_LEN + 2 * 4);
+    let madt_off = align8(fadt_off + FADT_LEN);
+    let dsdt_off = align8(madt_off + madt.len());
+    let addr = |offset: usize| (gpa + offset) as u64;
+
+    let fadt = fadt(addr(dsdt_off), power_gpa.map(|base| base as u64));
+    let xsdt_body: Vec<u8> = [addr(fadt_off), addr(madt_off)]
+        .iter()
+        .flat_map(|a| a.to_le_bytes())
+        .collect();
+    let rsdt_body: Vec<u8> = [addr(fadt_off) as u32, addr(madt_off) as u32]
+        .iter()
+        .flat_map(|a| a.to_le_bytes())
+        .collect();
+
+    let mut tables = vec![0u8; dsdt_off + dsdt.len()];
+    let mut place =
+        |offset: usize, bytes: &[u8]| tables[offset..offset + bytes.len()].copy_from_slice(bytes);
+    place(0, &rsdp(addr(rsdt_off) as u32, addr(xsdt_off)));
+    place(xsdt_off, &table(b"XSDT", 1, &xsdt_body));
+    place(rsdt_off, &table(b"RSDT", 1, &rsdt_body));
+    place(fadt_off, &fadt);
+    place(madt_off, &madt);
+    place(dsdt_off, &dsdt);
+    tables
+}
+
+/// The sleep and reset registers of a VM, see the module documentation.
+struct PowerRegisters;
+
+impl MmioTrapHandler for PowerRegisters {
+    fn handle_read(&self, vm: &VMRef, addr: GuestPhysAddr, _width: AccessWidth) -> AxResult<usize> {
+        match addr.as_usize() & (PAGE_SIZE_4K - 1) {
+            REG_SLEEP_STATUS if sleep::wake_status(vm.id()) => Ok(WAK_STS as usize),
+            _ => Ok(0),
+        }
+    }
+
+    fn handle_write(
+        &self,
+        vm: &VMRef,
+        addr: GuestPhysAddr,
+        width: AccessWidth,
+        val: usize,
+    ) -> AxResult {
+        if !matches!(width, AccessWidth::Byte) {
+            return ax_err!(InvalidInput, "ACPI sleep and reset registers are 8-bit");
+        }
+        let val = val as u8;
+        match addr.as_usize() & (PAGE_SIZE_4K - 1) {
+            REG_SLEEP_CONTROL
+                if val & SLP_EN != 0 && (val >> SLP_TYP_SHIFT) & SLP_TYP_MASK == SLP_TYP_S5 =>
+            {
+                reboot::on_guest_request(vm, PowerRequest::PowerOff)
+            }
+            REG_SLEEP_CONTROL
+                if val & SLP_EN != 0
+                    && (val >> SLP_TYP_SHIFT) & SLP_TYP_MASK == SLP_TYP_S3
+                    && sleep::is_enabled(vm.id()) =>
+            {
+                // Returns once the VM wakes up.
+                sleep::suspend(vm)
+            }
+            REG_SLEEP_STATUS if val & WAK_STS != 0 => sleep::clear_wake_status(vm.id()),
+            REG_RESET if val == RESET_VALUE => reboot::on_guest_request(vm, PowerRequest::Reboot),
+            reg => trace!(
+                "VM[{}] ACPI register write {:#x} to {:#x} ignored",
+                vm.id(),
+                val,
+                reg
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// Writes the ACPI tables described in the `[acpi]` section of `raw_cfg` to the memory of the VM.
+///
+/// Does nothing if the VM config has no `[acpi]` section. Must run after the images of the VM are
+/// loaded, so that none of them overwrites the tables.
+pub fn setup_vm_acpi(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("acpi").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let gpa = match cfg.get("gpa") {
+        None => DEFAULT_GPA,
+        Some(v) => v
+            .as_integer()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| {
+                ax_err_type!(
+                    InvalidInput,
+                    "acpi config: `gpa` must be below 4 GiB, the RSDT holds 32-bit addresses"
+                )
+            })? as usize,
+    };
+    if gpa % 16 != 0 {
+        return ax_err!(InvalidInput, "acpi config: `gpa` must be 16-byte aligned");
+    }
+    let ioapic_base = match cfg.get("ioapic_base") {
+        None => None,
+        Some(v) => Some(
+            v.as_integer()
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or_else(|| ax_err_type!(InvalidInput, "acpi config: invalid `ioapic_base`"))?,
+        ),
+    };
+
+    let power_gpa = match cfg.get("power_gpa") {
+        None => None,
+        Some(v) => Some(
+            v.as_integer()
+                .map(|v| v as usize)
+                .filter(|base| base % PAGE_SIZE_4K == 0)
+                .ok_or_else(|| {
+                    ax_err_type!(
+                        InvalidInput,
+                        "acpi config: `power_gpa` must be page-aligned"
+                    )
+                })?,
+        ),
+    };
+
+    let devices = virtio_devices(raw_cfg)?;
+    let s3 = sleep::is_enabled(vm.id());
+    let tables = build_tables(gpa, vm.vcpu_num(), ioapic_base, power_gpa, s3, &devices);
+    if !vm.memory_regions().iter().any(|region| {
+        region.gpa.as_usize() <= gpa && gpa + tables.len() <= region.gpa.as_usize() + region.size()
+    }) {
+        return ax_err!(
+            InvalidInput,
+            format!(
+                "acpi config: {:#x}..{:#x} is not in guest memory",
+                gpa,
+                gpa + tables.len()
+            )
+        );
+    }
+    if gpa + tables.len() > BIOS_AREA_END && gpa < BIOS_AREA_END {
+        warn!(
+            "VM[{}] ACPI tables cross the end of the BIOS area at {:#x}",
+            vm.id(),
+            BIOS_AREA_END
+        );
+    }
+    load_vm_image_from_memory(&tables, gpa.into(), vm.clone())?;
+    if let Some(base) = power_gpa {
+        if vm.memory_regions().iter().any(|region| {
+            region.gpa.as_usize() < base + PAGE_SIZE_4K
+                && base < region.gpa.as_usize() + region.size()
+        }) {
+            return ax_err!(
+                InvalidInput,
+                "acpi config: `power_gpa` must be outside guest memory"
+            );
+        }
+        mmio::register_trap(vm.id(), base.into(), PAGE_SIZE_4K, Arc::new(PowerRegisters))?;
+        info!(
+            "VM[{}] ACPI sleep and reset registers at {:#x}",
+            vm.id(),
+            base
+        );
+    }
+    info!(
+        "VM[{}] ACPI tables at {:#x}: {} vCPUs, {} virtio devices, {:#x} bytes",
+        vm.id(),
+        gpa,
+        vm.vcpu_num(),
+        devices.len(),
+        tables.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,354 @@
+//! Physical CPU affinity of vCPUs.
+//!
+//! By default a vCPU runs on the physical CPU given by `phys_cpu_ids` in its VM config, or on any
+//! physical CPU. The `[affinity]` section of a VM config sets the mask of physical CPUs of each
+//! vCPU instead, bit `N` being physical CPU `N`, and can dedicate these CPUs to the VM:
+//!
+//! ```toml
+//! [affinity]
+//! vcpu0 = 0x4
+//! vcpu1 = 0x8
+//! # Keep the vCPUs of other VMs off CPUs 2 and 3, e.g. for a real-time guest.
+//! dedicated = true
+//! ```
+//!
+//! The CPUs of a dedicated VM are removed from the masks of the vCPUs of all other VMs. A VM whose
+//! vCPUs would be left without a CPU is rejected. Host tasks that are not vCPUs are not restricted.
+//!
+//! Masks can be changed at runtime with the
+//! [`HVC_VCPU_SET_AFFINITY`](crate::vmm::hvc::HVC_VCPU_SET_AFFINITY) hypercall
+//! (`HVCpuSetAffinity`): `args[0]` is the peer handle of the VM, or [`AFFINITY_SELF`] for the
+//! caller, `args[1]` the vCPU ID and `args[2]` the new mask. Guests may change the masks of their
+//! own vCPUs, manager VMs (see [`crate::vmm::vmdef`]) those of any VM. A vCPU task applies its new
+//! mask itself before it next enters the guest, migrating to an allowed CPU if needed.
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::os::arceos::api::task::{AxCpuMask, ax_set_current_affinity};
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use spin::Mutex;
+
+use crate::task::{AsVCpuTask, VCpuTask};
+use crate::vmm::{VCpuRef, VMRef, peers, vcpus, vm_list, vmdef};
+
+/// Target VM of `HVCpuSetAffinity` standing for the caller itself.
+pub const AFFINITY_SELF: u64 = u64::MAX;
+
+const VCPU_PREFIX: &str = "vcpu";
+
+/// The `[affinity]` section of a VM, or the masks set at runtime.
+struct VmAffinity {
+    /// Physical CPU mask of each vCPU, 0 if not set.
+    masks: Vec<usize>,
+    dedicated: bool,
+    /// Set for partitioned and gang scheduled VMs, whose masks can't change at runtime, see
+    /// [`crate::vmm::sched`].
+    pinned: bool,
+}
+
+impl VmAffinity {
+    fn cpus(&self) -> usize {
+        self.masks.iter().fold(0, |cpus, mask| cpus | mask)
+    }
+}
+
+static AFFINITIES: Mutex<BTreeMap<usize, VmAffinity>> = Mutex::new(BTreeMap::new());
+
+/// A physical CPU mask to apply to a running vCPU task, 0 if none.
+pub struct PendingAffinity(AtomicUsize);
+
+impl PendingAffinity {
+    pub const fn new() -> Self {
+        Self(AtomicUsize::new(0))
+    }
+}
+
+fn host_cpus() -> usize {
+    let cpu_num = axruntime::cpu_count();
+    if cpu_num >= usize::BITS as usize {
+        usize::MAX
+    } else {
+        (1 << cpu_num) - 1
+    }
+}
+
+/// Returns the CPUs dedicated to VMs other than `vm_id`.
+fn dedicated_to_others(affinities: &BTreeMap<usize, VmAffinity>, vm_id: usize) -> usize {
+    affinities
+        .iter()
+        .filter(|(&id, affinity)| id != vm_id && affinity.dedicated)
+        .fold(0, |cpus, (_, affinity)| cpus | affinity.cpus())
+}
+
+/// Checks that `cpus` can be dedicated to VM `vm_id`: they are not dedicated to another VM and
+/// every vCPU mask of the other VMs keeps a CPU.
+fn check_dedicated(
+    affinities: &BTreeMap<usize, VmAffinity>,
+    vm_id: usize,
+    cpus: usize,
+) -> AxResult {
+    let taken = cpus & dedicated_to_others(affinities, vm_id);
+    if taken != 0 {
+        return ax_err!(
+            ResourceBusy,
+            format!("CPUs {:#x} are dedicated to other VMs", taken)
+        );
+    }
+    for (&id, affinity) in affinities.iter().filter(|(&id, _)| id != vm_id) {
+        if let Some(vcpu_id) = affinity
+            .masks
+            .iter()
+            .position(|&mask| mask != 0 && mask & !cpus == 0)
+        {
+            return ax_err!(
+                ResourceBusy,
+                format!(
+                    "CPUs {:#x} would leave VM[{}] VCpu[{}] without a CPU",
+                    cpus, id, vcpu_id
+                )
+            );
+        }
+    }
+    Ok(())
+}
+
+fn check_mask(affinities: &BTreeMap<usize, VmAffinity>, vm_id: usize, mask: usize) -> AxResult {
+    if mask == 0 || mask & !host_cpus() != 0 {
+        return ax_err!(InvalidInput, format!("invalid CPU mask {:#x}", mask));
+    }
+    if mask & !dedicated_to_others(affinities, vm_id) == 0 {
+        return ax_err!(
+            ResourceBusy,
+            format!("CPUs {:#x} are dedicated to other VMs", mask)
+        );
+    }
+    Ok(())
+}
+
+/// Sets the CPU masks described in the `[affinity]` section of `raw_cfg` for the VM.
+///
+/// Does nothing if the VM config has no `[affinity]` section.
+pub fn setup_vm_affinity(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("affinity").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+
+    let mut affinity = VmAffinity {
+        masks: vec![0; vm.vcpu_num()],
+        dedicated: false,
+        pinned: false,
+    };
+    let mut affinities = AFFINITIES.lock();
+    for (key, value) in cfg {
+        if key == "dedicated" {
+            affinity.dedicated = value.as_bool().ok_or_else(|| {
+                ax_err_type!(
+                    InvalidInput,
+                    "affinity config: `dedicated` must be a boolean"
+                )
+            })?;
+            continue;
+        }
+        let vcpu_id = key
+            .strip_prefix(VCPU_PREFIX)
+            .and_then(|n| n.parse::<usize>().ok())
+            .filter(|&id| id < vm.vcpu_num())
+            .ok_or_else(|| {
+                ax_err_type!(
+                    InvalidInput,
+                    format!("affinity config: invalid vCPU `{}`", key)
+                )
+            })?;
+        let mask = value.as_integer().map_or(0, |v| v as usize);
+        check_mask(&affinities, vm.id(), mask)?;
+        affinity.masks[vcpu_id] = mask;
+    }
+    if affinity.dedicated {
+        if affinity.masks.contains(&0) {
+            return ax_err!(
+                InvalidInput,
+                "affinity config: a dedicated VM needs a mask for every vCPU"
+            );
+        }
+        check_dedicated(&affinities, vm.id(), affinity.cpus())?;
+    }
+
+    info!(
+        "VM[{}] vCPU affinity {:x?}{}",
+        vm.id(),
+        affinity.masks,
+        if affinity.dedicated {
+            ", dedicated"
+        } else {
+            ""
+        }
+    );
+    let dedicated = affinity.dedicated;
+    affinities.insert(vm.id(), affinity);
+    drop(affinities);
+    if dedicated {
+        update_running_vcpus();
+    }
+    Ok(())
+}
+
+/// Returns the mask set in the `[affinity]` section of VM `vm_id` for a vCPU, if any.
+pub fn configured_mask(vm_id: usize, vcpu_id: usize) -> Option<usize> {
+    AFFINITIES
+        .lock()
+        .get(&vm_id)
+        .and_then(|affinity| affinity.masks.get(vcpu_id).copied())
+        .filter(|&mask| mask != 0)
+}
+
+/// Pins the vCPUs of the VM to `masks` for good, and dedicates their CPUs to it if `dedicated`.
+pub fn pin(vm: &VMRef, masks: Vec<usize>, dedicated: bool) -> AxResult {
+    let mut affinities = AFFINITIES.lock();
+    for &mask in &masks {
+        check_mask(&affinities, vm.id(), mask)?;
+    }
+    let affinity = VmAffinity {
+        masks,
+        dedicated,
+        pinned: true,
+    };
+    if dedicated {
+        check_dedicated(&affinities, vm.id(), affinity.cpus())?;
+    }
+    affinities.insert(vm.id(), affinity);
+    drop(affinities);
+    if dedicated {
+        update_running_vcpus();
+    }
+    Ok(())
+}
+
+/// Removes the CPU masks of a VM, called when the VM is destroyed. The CPUs dedicated to it are
+/// given back to the other VMs.
+pub fn teardown_vm_affinity(vm_id: usize) {
+    let removed = AFFINITIES.lock().remove(&vm_id);
+    if removed.is_some_and(|affinity| affinity.dedicated) {
+        update_running_vcpus();
+    }
+}
+
+/// Returns the physical CPU mask `vcpu` of `vm` runs on.
+pub fn vcpu_mask(vm: &VMRef, vcpu: &VCpuRef) -> usize {
+    let affinities = AFFINITIES.lock();
+    let own = affinities.get(&vm.id());
+    let mask = own
+        .and_then(|affinity| affinity.masks.get(vcpu.id()).copied())
+        .filter(|&mask| mask != 0)
+        .or_else(|| vcpu.phys_cpu_set())
+        .unwrap_or_else(host_cpus);
+    if own.is_some_and(|affinity| affinity.dedicated) {
+        return mask;
+    }
+    let allowed = mask & !dedicated_to_others(&affinities, vm.id());
+    if allowed == 0 {
+        warn!(
+            "VM[{}] VCpu[{}] only allowed on dedicated CPUs {:#x}",
+            vm.id(),
+            vcpu.id(),
+            mask
+        );
+        return mask;
+    }
+    allowed
+}
+
+/// Recomputes the masks of all vCPU tasks, they apply them before they next enter the guest.
+fn update_running_vcpus() {
+    for vm in vm_list::get_vm_list() {
+        for vcpu in vm.vcpu_list() {
+            let mask = vcpu_mask(&vm, vcpu);
+            vcpus::with_vcpu_task(vm.id(), vcpu.id(), |task| {
+                task.as_vcpu_task()
+                    .pending_affinity
+                    .0
+                    .store(mask, Ordering::Release)
+            });
+        }
+    }
+}
+
+/// Moves the current vCPU task to its new CPU mask, if it has one. Called by the vCPU task
+/// before it enters the guest.
+#[inline]
+pub fn apply_pending(task: &VCpuTask) {
+    let mask = task.pending_affinity.0.swap(0, Ordering::Acquire);
+    if mask == 0 {
+        return;
+    }
+    if let Err(e) = ax_set_current_affinity(AxCpuMask::from_raw_bits(mask)) {
+        warn!(
+            "VCpu[{}] failed to set CPU mask {:#x}: {:?}",
+            task.vcpu.id(),
+            mask,
+            e
+        );
+    }
+}
+
+/// Handles the [`HVC_VCPU_SET_AFFINITY`](crate::vmm::hvc::HVC_VCPU_SET_AFFINITY) hypercall of VM
+/// `vm_id`.
+pub fn set_vcpu_affinity(vm_id: usize, args: [u64; 6]) -> AxResult {
+    let target_vm_id = if args[0] == AFFINITY_SELF {
+        vm_id
+    } else if vmdef::is_manager(vm_id) {
+        peers::resolve(vm_id, args[0] as usize)?
+    } else {
+        return ax_err!(
+            PermissionDenied,
+            format!("VM[{}] may only set the affinity of its own vCPUs", vm_id)
+        );
+    };
+    let vm = vm_list::get_vm_by_id(target_vm_id)
+        .ok_or_else(|| ax_err_type!(NotFound, format!("VM[{}] not found", target_vm_id)))?;
+    let vcpu_id = args[1] as usize;
+    if vcpu_id >= vm.vcpu_num() {
+        return ax_err!(
+            InvalidInput,
+            format!("VM[{}] has no VCpu[{}]", target_vm_id, vcpu_id)
+        );
+    }
+    let mask = args[2] as usize;
+
+    let mut affinities = AFFINITIES.lock();
+    if affinities
+        .get(&target_vm_id)
+        .is_some_and(|affinity| affinity.pinned)
+    {
+        return ax_err!(
+            BadState,
+            format!("VM[{}] is partitioned, its vCPUs are pinned", target_vm_id)
+        );
+    }
+    check_mask(&affinities, target_vm_id, mask)?;
+    if let Some(affinity) = affinities.get(&target_vm_id)
+        && affinity.dedicated
+    {
+        let mut masks = affinity.masks.clone();
+        masks[vcpu_id] = mask;
+        let cpus = masks.iter().fold(0, |cpus, mask| cpus | mask);
+        check_dedicated(&affinities, target_vm_id, cpus)?;
+    }
+    affinities
+        .entry(target_vm_id)
+        .or_insert_with(|| VmAffinity {
+            masks: vec![0; vm.vcpu_num()],
+            dedicated: false,
+            pinned: false,
+        })
+        .masks[vcpu_id] = mask;
+    drop(affinities);
+
+    info!(
+        "VM[{}] VCpu[{}] CPU mask set to {:#x} by VM[{}]",
+        target_vm_id, vcpu_id, mask, vm_id
+    );
+    update_running_vcpus();
+    Ok(())
+}
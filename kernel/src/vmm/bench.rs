@@ -0,0 +1,61 @@
+//! Micro-benchmarks of the lookups on the VM exit paths.
+//!
+//! Every VM exit resolves some per-VM state (the VM itself, a vCPU task, an MMIO trap, the route
+//! of a host interrupt to be injected). These lookups are all keyed by VM ID in ordered maps, so
+//! their cost must stay flat as the number of VMs grows. `vm bench` in the shell runs them against
+//! the VMs currently created, e.g. with a config using the `[scale]` section to create 64 VMs.
+use alloc::vec::Vec;
+use core::hint::black_box;
+
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::GuestPhysAddr;
+
+use crate::vmm::{mmio, pci, vcpus, vm_list};
+
+/// Result of a benchmark.
+#[derive(Debug, Clone, Copy)]
+pub struct BenchResult {
+    pub name: &'static str,
+    pub iters: usize,
+    pub ns_per_op: u64,
+}
+
+fn measure(name: &'static str, iters: usize, mut op: impl FnMut(usize)) -> BenchResult {
+    let start = axhal::time::monotonic_time_nanos();
+    for i in 0..iters {
+        op(i);
+    }
+    let elapsed = axhal::time::monotonic_time_nanos() - start;
+    BenchResult {
+        name,
+        iters,
+        ns_per_op: elapsed / iters.max(1) as u64,
+    }
+}
+
+/// Runs each benchmark for `iters` iterations, cycling through all VMs.
+///
+/// Returns nothing if there is no VM.
+pub fn run(iters: usize) -> Vec<BenchResult> {
+    let vm_ids: Vec<usize> = vm_list::get_vm_list().iter().map(|vm| vm.id()).collect();
+    if vm_ids.is_empty() {
+        return Vec::new();
+    }
+    let pick = |i: usize| vm_ids[i % vm_ids.len()];
+
+    vec![
+        measure("vm lookup", iters, |i| {
+            black_box(vm_list::get_vm_by_id(pick(i)));
+        }),
+        measure("vcpu task lookup", iters, |i| {
+            black_box(vcpus::with_vcpu_task(pick(i), 0, |task| task.cpu_id()));
+        }),
+        measure("mmio trap lookup", iters, |i| {
+            black_box(mmio::find_trap(pick(i), GuestPhysAddr::from(i << 12)).is_some());
+        }),
+        measure("irq route lookup", iters, |i| {
+            black_box(pci::irq_route(i % 1024));
+        }),
+    ]
+}
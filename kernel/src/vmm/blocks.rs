@@ -0,0 +1,193 @@
+//! Block (huge page) mappings in the stage-2 of the VMs.
+//!
+//! Guest RAM is mapped with 2 MB and 1 GB blocks wherever the guest and host addresses are both
+//! aligned, which saves TLB entries and page table walks. The largest block can be lowered in the
+//! VM config:
+//!
+//! ```toml
+//! [stage2]
+//! # Largest block mapping guest RAM: "1G" (default), "2M", or "4K" for pages only.
+//! max_block = "2M"
+//! ```
+//!
+//! 1 GB blocks are used only if the stage-2 supports them, which on x86_64 depends on the CPU.
+//! Guest RAM is allocated 2 MB aligned, so 1 GB blocks are mostly used for identity-mapped RAM.
+//!
+//! Guest RAM is mapped with [`stage2::map_region_block`]. When a part of a block needs other
+//! permissions, e.g., an IVC channel mapped over guest RAM, the block is split with
+//! [`split_blocks`], in place (see [`stage2::split_block`]): a 1 GB block into 2 MB blocks, the
+//! ones overlapping the part into pages, after which the caller maps the part. The rest of the
+//! block stays mapped meanwhile. Split blocks are not merged back.
+use alloc::collections::BTreeMap;
+
+use axaddrspace::{AxMmHal, GuestPhysAddr, MappingFlags};
+use axerrno::{AxResult, ax_err};
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::hal::AxMmHalImpl;
+use crate::vmm::VMRef;
+use crate::vmm::stage2::{self, BLOCK_1G, BLOCK_2M};
+
+/// Flags of the mappings of guest RAM.
+pub const RAM_FLAGS: MappingFlags = MappingFlags::READ
+    .union(MappingFlags::WRITE)
+    .union(MappingFlags::EXECUTE)
+    .union(MappingFlags::USER);
+
+#[derive(Default)]
+struct VmBlocks {
+    /// Sizes of the block mappings of the VM, indexed by guest physical address.
+    blocks: BTreeMap<usize, usize>,
+    splits: u64,
+}
+
+/// Block mappings of the VMs, indexed by VM ID.
+static BLOCKS: Mutex<BTreeMap<usize, VmBlocks>> = Mutex::new(BTreeMap::new());
+/// Largest block of the VMs that lowered it in their config, indexed by VM ID.
+static MAX_BLOCK: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+/// Block mappings of a VM.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BlockStats {
+    pub blocks_2m: usize,
+    pub blocks_1g: usize,
+    /// Blocks split, into pages or into 2 MB blocks.
+    pub splits: u64,
+}
+
+/// The largest block the stage-2 can map.
+fn hw_max_block() -> usize {
+    #[cfg(target_arch = "x86_64")]
+    {
+        match crate::hal::arch::stage2_block_sizes() {
+            (_, true) => BLOCK_1G,
+            (true, false) => BLOCK_2M,
+            (false, false) => PAGE_SIZE_4K,
+        }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        BLOCK_1G
+    }
+}
+
+fn max_block(vm_id: usize) -> usize {
+    let hw = hw_max_block();
+    MAX_BLOCK
+        .lock()
+        .get(&vm_id)
+        .map_or(hw, |max| (*max).min(hw))
+}
+
+/// Splits the blocks of the VM overlapping `[gpa, gpa + size)` into pages, so that the range can
+/// be mapped with other permissions.
+pub fn split_blocks(vm: &VMRef, gpa: GuestPhysAddr, size: usize) -> AxResult {
+    let (start, end) = (gpa.as_usize(), gpa.as_usize() + size);
+    let mut all = BLOCKS.lock();
+    let Some(vm_blocks) = all.get_mut(&vm.id()) else {
+        return Ok(());
+    };
+    // The 2 MB blocks of a split 1 GB block overlapping the range are split in turn.
+    while let Some((g, block_size)) = vm_blocks
+        .blocks
+        .range(..end)
+        .find(|(g, block_size)| **g + **block_size > start)
+        .map(|(g, block_size)| (*g, *block_size))
+    {
+        stage2::split_block(vm, g.into(), block_size)?;
+        vm_blocks.blocks.remove(&g);
+        if block_size == BLOCK_1G {
+            vm_blocks
+                .blocks
+                .extend((g..g + BLOCK_1G).step_by(BLOCK_2M).map(|g| (g, BLOCK_2M)));
+        }
+        vm_blocks.splits += 1;
+        debug!(
+            "VM[{}] split {:#x} bytes block at {:#x}",
+            vm.id(),
+            block_size,
+            g
+        );
+    }
+    Ok(())
+}
+
+/// Maps the guest RAM of the VM with blocks, after applying the `[stage2]` section of `raw_cfg`.
+///
+/// Must run before anything is mapped over guest RAM.
+pub fn setup_vm_blocks(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    if let Some(cfg) = raw_cfg.get("stage2").and_then(|v| v.as_table())
+        && let Some(max) = cfg.get("max_block")
+    {
+        let max = match max.as_str() {
+            Some("1G") => BLOCK_1G,
+            Some("2M") => BLOCK_2M,
+            Some("4K") => PAGE_SIZE_4K,
+            _ => {
+                return ax_err!(
+                    InvalidInput,
+                    "stage2 config: `max_block` must be \"1G\", \"2M\" or \"4K\""
+                );
+            }
+        };
+        MAX_BLOCK.lock().insert(vm.id(), max);
+    }
+    if max_block(vm.id()) == PAGE_SIZE_4K {
+        return Ok(());
+    }
+
+    for region in vm.memory_regions() {
+        if !region.needs_dealloc {
+            continue;
+        }
+        let hpa = AxMmHalImpl::virt_to_phys(region.hva);
+        stage2::unmap_region(vm, region.gpa, region.size())?;
+        let blocks = stage2::map_region_block(
+            vm,
+            region.gpa,
+            hpa,
+            region.size(),
+            RAM_FLAGS,
+            max_block(vm.id()),
+        )?;
+        BLOCKS
+            .lock()
+            .entry(vm.id())
+            .or_default()
+            .blocks
+            .extend(blocks);
+    }
+    let stats = block_stats(vm.id());
+    info!(
+        "VM[{}] guest RAM mapped with {} 1G and {} 2M blocks",
+        vm.id(),
+        stats.blocks_1g,
+        stats.blocks_2m
+    );
+    Ok(())
+}
+
+/// Drops the block mappings of a VM, called when the VM is destroyed.
+pub fn teardown_vm_blocks(vm_id: usize) {
+    BLOCKS.lock().remove(&vm_id);
+    MAX_BLOCK.lock().remove(&vm_id);
+}
+
+/// Returns the block mappings of VM `vm_id`.
+pub fn block_stats(vm_id: usize) -> BlockStats {
+    let all = BLOCKS.lock();
+    let Some(vm_blocks) = all.get(&vm_id) else {
+        return BlockStats::default();
+    };
+    let blocks_1g = vm_blocks
+        .blocks
+        .values()
+        .filter(|block_size| **block_size == BLOCK_1G)
+        .count();
+    BlockStats {
+        blocks_2m: vm_blocks.blocks.len() - blocks_1g,
+        blocks_1g,
+        splits: vm_blocks.splits,
+    }
+}
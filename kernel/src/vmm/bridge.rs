@@ -0,0 +1,786 @@
+//! Bridging of IVC channels between two axvisor nodes.
+//!
+//! On a multi-board or multi-die system, two axvisor instances ("nodes") share a memory window
+//! over a physical link: a PCIe NTB memory window, or memory shared by the dies. The window is
+//! split into two halves of the same size and layout, each owned by one node and written by the
+//! other through the link. The link is described in the config of any VM, the first VM describing
+//! it brings the bridge up:
+//!
+//! ```toml
+//! [bridge.link]
+//! # "ntb" for a PCIe non-transparent bridge, "shmem" for memory shared by the dies.
+//! type = "ntb"
+//! # Number of this node, 0 or 1.
+//! node = 0
+//! # Physical base of the half owned by this node.
+//! local = 0x8_4000_0000
+//! # Physical base of the half owned by the peer node, as seen from this node (e.g. the NTB
+//! # memory window BAR).
+//! remote = 0x40_0000_0000
+//! # Size of each half.
+//! size = 0x100_0000
+//! ```
+//!
+//! Both links are plain memory windows, the translation of an NTB is left to the firmware.
+//! Other transports (e.g. Ethernet) are not supported.
+//!
+//! # Exported channels
+//!
+//! A VM lists the keys of its channels visible to the peer node:
+//!
+//! ```toml
+//! [bridge]
+//! export = [0x10, 0x20]
+//! ```
+//!
+//! The region of an exported raw or ring channel is allocated from the half of this node instead
+//! of the host memory, and the channel is listed in the directory at the start of the half (see
+//! [`BridgeNodeHeader`]) while it exists. Broadcast channels cannot be exported.
+//!
+//! # Remote channels
+//!
+//! Each node polls the directory of its peer and registers every channel exported there as a
+//! channel of a proxy VM, whose ID is [`REMOTE_VM_ID_BASE`] plus the ID of the publisher on the
+//! peer node. A guest names the remote publisher by a peer handle resolving to `name@node` (see
+//! [`crate::vmm::peers`]) and subscribes to its channels with the usual hypercalls, the region is
+//! mapped from the half of the peer:
+//!
+//! ```toml
+//! [peers]
+//! peer1 = "rtos@1"
+//! ```
+//!
+//! # Notifications
+//!
+//! Kicks, attaches and detaches involving a proxy VM are forwarded as [`BridgeMessage`]s to the
+//! mailbox of the peer node, in the header of its half. The peer replays them on behalf of the
+//! proxy of the sender, so the guests see the same kicks, credits and space waits as for a local
+//! peer. The mailbox is polled, which adds up to `poll_us` (default 100) to the latency of a
+//! remote kick.
+//!
+//! When the peer node reboots (its `boot_id` changes) or its half goes away, all its proxy VMs
+//! are detached from the IVC registry as if they had stopped.
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use std::os::arceos::modules::axhal;
+use std::thread;
+
+use axaddrspace::{GuestPhysAddr, HostPhysAddr};
+use axerrno::{AxResult, ax_err, ax_err_type};
+use memory_addr::PAGE_SIZE_4K;
+use spin::{Mutex, Once};
+
+use crate::vmm::{VMRef, ivc};
+
+/// `magic` of a [`BridgeNodeHeader`], "AXBR".
+pub const BRIDGE_MAGIC: u32 = u32::from_le_bytes(*b"AXBR");
+/// Version of the layout of a half of the window.
+pub const BRIDGE_VERSION: u16 = 1;
+/// Number of entries of the directory of a node.
+pub const BRIDGE_MAX_EXPORTS: usize = 32;
+/// Number of slots of the mailbox of a node.
+pub const BRIDGE_MAILBOX_SLOTS: usize = 64;
+/// Length of the publisher names in the directory, NUL-padded.
+pub const BRIDGE_NAME_LEN: usize = 32;
+/// `state` of a free directory entry.
+pub const BRIDGE_EXPORT_FREE: u32 = 0;
+/// `state` of a directory entry describing a channel.
+pub const BRIDGE_EXPORT_LIVE: u32 = 1;
+/// Kicks the other end of a channel.
+pub const BRIDGE_MSG_KICK: u32 = 1;
+/// Subscribes the sender to a channel exported by the receiver.
+pub const BRIDGE_MSG_ATTACH: u32 = 2;
+/// Unsubscribes the sender from a channel exported by the receiver.
+pub const BRIDGE_MSG_DETACH: u32 = 3;
+
+/// IDs of the proxy VMs standing for the VMs of the peer node, see the [module docs](self).
+pub const REMOTE_VM_ID_BASE: usize = 0x10_0000;
+
+/// Default interval between two polls of the peer half.
+const DEFAULT_POLL_US: u64 = 100;
+
+/// A channel exported by a node, in its directory.
+#[repr(C)]
+pub struct BridgeExport {
+    /// `BRIDGE_EXPORT_*`, written last when an entry is filled.
+    pub state: AtomicU32,
+    /// ID of the publisher VM on the node.
+    pub vm_id: u32,
+    pub key: u64,
+    /// Offset of the region of the channel from the base of the half.
+    pub offset: u64,
+    pub size: u64,
+    /// Name of the publisher VM.
+    pub name: [u8; BRIDGE_NAME_LEN],
+}
+
+/// A notification forwarded to the peer node.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BridgeMessage {
+    /// `BRIDGE_MSG_*`.
+    pub kind: u32,
+    /// ID of the VM of the sending node the message is sent for.
+    pub sender_vm: u32,
+    /// ID of the publisher of the channel on its node.
+    pub publisher_vm: u32,
+    /// Node of the publisher.
+    pub publisher_node: u32,
+    pub key: u64,
+}
+
+/// The header of the half of a node, only written by the node except for `mbox_head` and the
+/// mailbox slots, written by its peer.
+#[repr(C)]
+pub struct BridgeNodeHeader {
+    /// [`BRIDGE_MAGIC`], written last when the node brings its half up.
+    pub magic: AtomicU32,
+    /// [`BRIDGE_VERSION`].
+    pub version: u16,
+    pub node: u16,
+    /// Changes on every boot of the node.
+    pub boot_id: u64,
+    /// Bumped on every change of `exports`.
+    pub generation: AtomicU32,
+    /// Number of messages posted, only written by the peer.
+    pub mbox_head: AtomicU32,
+    /// Number of messages handled, only written by the node.
+    pub mbox_tail: AtomicU32,
+    pub _reserved: u32,
+    pub exports: [BridgeExport; BRIDGE_MAX_EXPORTS],
+    pub mailbox: [BridgeMessage; BRIDGE_MAILBOX_SLOTS],
+}
+
+const _: () = assert!(core::mem::size_of::<BridgeNodeHeader>() <= PAGE_SIZE_4K);
+
+/// The physical link to the peer node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkKind {
+    Ntb,
+    Shmem,
+}
+
+struct Link {
+    kind: LinkKind,
+    node: u16,
+    local: HostPhysAddr,
+    remote: HostPhysAddr,
+    size: usize,
+    poll_interval: Duration,
+}
+
+impl Link {
+    fn header(&self, base: HostPhysAddr) -> &BridgeNodeHeader {
+        // SAFETY: both halves are reserved for the bridge and start with a header.
+        unsafe { &*axhal::mem::phys_to_virt(base).as_ptr().cast::<BridgeNodeHeader>() }
+    }
+
+    fn local_header(&self) -> &BridgeNodeHeader {
+        self.header(self.local)
+    }
+
+    fn remote_header(&self) -> &BridgeNodeHeader {
+        self.header(self.remote)
+    }
+
+    fn local_header_mut(&self) -> *mut BridgeNodeHeader {
+        axhal::mem::phys_to_virt(self.local).as_mut_ptr().cast()
+    }
+
+    fn remote_header_mut(&self) -> *mut BridgeNodeHeader {
+        axhal::mem::phys_to_virt(self.remote).as_mut_ptr().cast()
+    }
+
+    fn peer(&self) -> u16 {
+        1 - self.node
+    }
+}
+
+static LINK: Once<Link> = Once::new();
+
+/// The exported keys and the name of the VMs with a `[bridge]` section, by VM ID.
+static EXPORTERS: Mutex<BTreeMap<usize, (String, Vec<usize>)>> = Mutex::new(BTreeMap::new());
+
+/// State of the bridge. Always locked after the IVC registry, never held while calling into it.
+static BRIDGE: Mutex<BridgeState> = Mutex::new(BridgeState::new());
+
+struct BridgeState {
+    /// The allocated regions of the local half, `offset -> size`.
+    regions: BTreeMap<usize, usize>,
+    /// `boot_id` and `generation` of the peer as of the last scan of its directory.
+    peer_seen: Option<(u64, u32)>,
+    /// The remote channels registered with IVC, `(proxy_vm_id, key) -> handle` of the proxy.
+    imports: BTreeMap<(usize, usize), u64>,
+    /// The local channels subscribed to by remote VMs,
+    /// `(proxy_vm_id, publisher_vm_id, key) -> handle` of the proxy.
+    attached: BTreeMap<(usize, usize, usize), u64>,
+    /// Messages dropped because the mailbox of the peer was full.
+    dropped: u64,
+}
+
+impl BridgeState {
+    const fn new() -> Self {
+        Self {
+            regions: BTreeMap::new(),
+            peer_seen: None,
+            imports: BTreeMap::new(),
+            attached: BTreeMap::new(),
+            dropped: 0,
+        }
+    }
+}
+
+/// What the poller has to do once the bridge state is unlocked.
+enum Action {
+    Import {
+        proxy: usize,
+        key: usize,
+        base: HostPhysAddr,
+        size: usize,
+    },
+    Withdraw {
+        proxy: usize,
+        key: usize,
+    },
+    DropPeer(Vec<usize>),
+    Message(BridgeMessage),
+}
+
+/// Brings the link described in the `[bridge.link]` section of `cfg` up, if it's not up yet.
+fn init_link(cfg: &toml::Table) -> AxResult {
+    if LINK.is_completed() {
+        return Ok(());
+    }
+    let int = |name: &str| {
+        cfg.get(name)
+            .and_then(|v| v.as_integer())
+            .map(|v| v as usize)
+            .ok_or_else(|| {
+                ax_err_type!(InvalidInput, format!("bridge link config: missing `{}`", name))
+            })
+    };
+    let kind = match cfg.get("type").and_then(|v| v.as_str()) {
+        Some("ntb") => LinkKind::Ntb,
+        Some("shmem") => LinkKind::Shmem,
+        Some(kind) => {
+            return ax_err!(
+                Unsupported,
+                format!("bridge link type {:?} is not supported", kind)
+            );
+        }
+        None => return ax_err!(InvalidInput, "bridge link config: missing `type`"),
+    };
+    let node = int("node")?;
+    let size = int("size")?;
+    if node > 1 {
+        return ax_err!(InvalidInput, "bridge link config: `node` must be 0 or 1");
+    }
+    if size < 2 * PAGE_SIZE_4K || size % PAGE_SIZE_4K != 0 {
+        return ax_err!(
+            InvalidInput,
+            format!("bridge link config: invalid half size {:#x}", size)
+        );
+    }
+    let poll_us = cfg
+        .get("poll_us")
+        .and_then(|v| v.as_integer())
+        .map_or(DEFAULT_POLL_US, |v| v.max(1) as u64);
+    let link = Link {
+        kind,
+        node: node as u16,
+        local: HostPhysAddr::from(int("local")?),
+        remote: HostPhysAddr::from(int("remote")?),
+        size,
+        poll_interval: Duration::from_micros(poll_us),
+    };
+
+    // SAFETY: the local half is reserved for the bridge, the peer does not use it before the
+    // magic is written.
+    unsafe {
+        let header = link.local_header_mut();
+        core::ptr::write_bytes(header.cast::<u8>(), 0, PAGE_SIZE_4K);
+        (*header).version = BRIDGE_VERSION;
+        (*header).node = node as u16;
+        (*header).boot_id = axhal::time::monotonic_time_nanos() | 1;
+    }
+    link.local_header()
+        .magic
+        .store(BRIDGE_MAGIC, Ordering::Release);
+    info!(
+        "Bridge {:?} node {} up, local half {:#x}, remote half {:#x}, {:#x} bytes each",
+        link.kind, link.node, link.local, link.remote, link.size
+    );
+    LINK.call_once(|| link);
+    thread::spawn(|| {
+        let interval = LINK.get().unwrap().poll_interval;
+        loop {
+            thread::sleep(interval);
+            poll();
+        }
+    });
+    Ok(())
+}
+
+/// Brings the bridge up from the `[bridge]` section of `raw_cfg` and records the channels the VM
+/// exports.
+pub fn setup_vm_bridge(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("bridge").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    if let Some(link) = cfg.get("link").and_then(|v| v.as_table()) {
+        init_link(link)?;
+    }
+    let Some(export) = cfg.get("export") else {
+        return Ok(());
+    };
+    let keys = export
+        .as_array()
+        .and_then(|keys| {
+            keys.iter()
+                .map(|key| key.as_integer().map(|key| key as usize))
+                .collect::<Option<Vec<_>>>()
+        })
+        .ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                "bridge config: `export` must be a list of channel keys"
+            )
+        })?;
+    if !LINK.is_completed() {
+        return ax_err!(BadState, "bridge config: no link to export channels to");
+    }
+    let name = vm.with_config(|cfg| String::from(cfg.name()));
+    if name.len() >= BRIDGE_NAME_LEN {
+        return ax_err!(
+            InvalidInput,
+            format!("bridge config: VM name {:?} too long to export", name)
+        );
+    }
+    info!("VM[{}] exports IVC channels {:#x?} over the bridge", vm.id(), keys);
+    EXPORTERS.lock().insert(vm.id(), (name, keys));
+    Ok(())
+}
+
+/// Forgets the exported channels of a VM, called when the VM is destroyed. Its channels are gone
+/// already.
+pub fn teardown_vm_bridge(vm_id: usize) {
+    EXPORTERS.lock().remove(&vm_id);
+}
+
+/// Whether `vm_id` is the proxy of a VM of the peer node.
+pub fn is_remote(vm_id: usize) -> bool {
+    vm_id >= REMOTE_VM_ID_BASE
+}
+
+/// Whether the channel `key` of VM `vm_id` is exported to the peer node.
+pub fn is_exported(vm_id: usize, key: usize) -> bool {
+    EXPORTERS
+        .lock()
+        .get(&vm_id)
+        .is_some_and(|(_, keys)| keys.contains(&key))
+}
+
+/// Allocates a zeroed region of `size` bytes, whole pages, in the local half.
+pub fn alloc_region(size: usize) -> AxResult<HostPhysAddr> {
+    let link = LINK
+        .get()
+        .ok_or_else(|| ax_err_type!(BadState, "bridge is not up"))?;
+    let mut state = BRIDGE.lock();
+    // First fit after the header page.
+    let mut offset = PAGE_SIZE_4K;
+    for (start, len) in state.regions.iter() {
+        if offset + size <= *start {
+            break;
+        }
+        offset = start + len;
+    }
+    if offset + size > link.size {
+        return ax_err!(
+            NoMemory,
+            format!("no {:#x}-byte region left in the bridge window", size)
+        );
+    }
+    state.regions.insert(offset, size);
+    let base = link.local + offset;
+    // SAFETY: the region was just allocated in the local half.
+    unsafe { core::ptr::write_bytes(axhal::mem::phys_to_virt(base).as_mut_ptr(), 0, size) };
+    Ok(base)
+}
+
+/// Lists the channel `key` of VM `vm_id`, whose region was allocated with [`alloc_region`], in
+/// the directory of this node.
+pub fn export(vm_id: usize, key: usize, base: HostPhysAddr, size: usize) -> AxResult {
+    let link = LINK
+        .get()
+        .ok_or_else(|| ax_err_type!(BadState, "bridge is not up"))?;
+    let mut name = [0u8; BRIDGE_NAME_LEN];
+    if let Some((vm_name, _)) = EXPORTERS.lock().get(&vm_id) {
+        name[..vm_name.len()].copy_from_slice(vm_name.as_bytes());
+    }
+    let _state = BRIDGE.lock();
+    let header = link.local_header();
+    let idx = header
+        .exports
+        .iter()
+        .position(|entry| entry.state.load(Ordering::Relaxed) == BRIDGE_EXPORT_FREE)
+        .ok_or_else(|| ax_err_type!(NoMemory, "bridge directory is full"))?;
+    // SAFETY: the entry is free, the peer ignores it until its state is set.
+    unsafe {
+        let entry = &mut (*link.local_header_mut()).exports[idx];
+        entry.vm_id = vm_id as u32;
+        entry.key = key as u64;
+        entry.offset = (base.as_usize() - link.local.as_usize()) as u64;
+        entry.size = size as u64;
+        entry.name = name;
+    }
+    header.exports[idx]
+        .state
+        .store(BRIDGE_EXPORT_LIVE, Ordering::Release);
+    header.generation.fetch_add(1, Ordering::Release);
+    debug!(
+        "VM[{}] IVC channel {:#x} exported at {:#x} over the bridge",
+        vm_id, key, base
+    );
+    Ok(())
+}
+
+/// Removes the exported channel whose region starts at `base` from the directory and frees its
+/// region, called when the channel is dropped.
+pub fn release_region(base: HostPhysAddr) {
+    let Some(link) = LINK.get() else {
+        return;
+    };
+    let offset = base.as_usize() - link.local.as_usize();
+    let mut state = BRIDGE.lock();
+    state.regions.remove(&offset);
+    let header = link.local_header();
+    for entry in &header.exports {
+        if entry.state.load(Ordering::Relaxed) == BRIDGE_EXPORT_LIVE
+            && entry.offset == offset as u64
+        {
+            entry.state.store(BRIDGE_EXPORT_FREE, Ordering::Release);
+            header.generation.fetch_add(1, Ordering::Release);
+            // The remote subscribers see the channel withdrawn.
+            let channel = (entry.vm_id as usize, entry.key as usize);
+            state
+                .attached
+                .retain(|(_, vm_id, key), _| (*vm_id, *key) != channel);
+        }
+    }
+}
+
+/// Resolves the name of a VM of the peer node `node` to its proxy VM ID. The VM must export a
+/// channel.
+pub fn resolve_remote(name: &str, node: usize) -> AxResult<usize> {
+    let link = LINK
+        .get()
+        .ok_or_else(|| ax_err_type!(NotFound, "bridge is not up"))?;
+    if node != link.peer() as usize {
+        return ax_err!(NotFound, format!("no bridge to node {}", node));
+    }
+    let header = link.remote_header();
+    if header.magic.load(Ordering::Acquire) != BRIDGE_MAGIC {
+        return ax_err!(NotFound, format!("bridge node {} is down", node));
+    }
+    header
+        .exports
+        .iter()
+        .filter(|entry| entry.state.load(Ordering::Acquire) == BRIDGE_EXPORT_LIVE)
+        .find(|entry| entry_name(entry) == name)
+        .map(|entry| REMOTE_VM_ID_BASE + entry.vm_id as usize)
+        .ok_or_else(|| {
+            ax_err_type!(
+                NotFound,
+                format!("VM {:?} of node {} exports no channel", name, node)
+            )
+        })
+}
+
+fn entry_name(entry: &BridgeExport) -> &str {
+    let len = entry
+        .name
+        .iter()
+        .position(|c| *c == 0)
+        .unwrap_or(BRIDGE_NAME_LEN);
+    core::str::from_utf8(&entry.name[..len]).unwrap_or("")
+}
+
+/// Kicks the other end of the channel `key` of `publisher_vm_id` on the peer node, on behalf of
+/// the local VM `vm_id`. One of the two is a proxy VM.
+pub fn forward_kick(vm_id: usize, publisher_vm_id: usize, key: usize) {
+    forward(BRIDGE_MSG_KICK, vm_id, publisher_vm_id, key);
+}
+
+/// Subscribes the local VM `vm_id` to the channel `key` of the proxy VM `publisher_vm_id` on the
+/// peer node.
+pub fn forward_attach(vm_id: usize, publisher_vm_id: usize, key: usize) {
+    forward(BRIDGE_MSG_ATTACH, vm_id, publisher_vm_id, key);
+}
+
+/// Unsubscribes the local VM `vm_id` from the channel `key` of the proxy VM `publisher_vm_id`
+/// on the peer node.
+pub fn forward_detach(vm_id: usize, publisher_vm_id: usize, key: usize) {
+    forward(BRIDGE_MSG_DETACH, vm_id, publisher_vm_id, key);
+}
+
+fn forward(kind: u32, vm_id: usize, publisher_vm_id: usize, key: usize) {
+    let Some(link) = LINK.get() else {
+        return;
+    };
+    let (publisher_vm, publisher_node) = if is_remote(publisher_vm_id) {
+        (publisher_vm_id - REMOTE_VM_ID_BASE, link.peer())
+    } else {
+        (publisher_vm_id, link.node)
+    };
+    let msg = BridgeMessage {
+        kind,
+        sender_vm: vm_id as u32,
+        publisher_vm: publisher_vm as u32,
+        publisher_node: publisher_node as u32,
+        key: key as u64,
+    };
+
+    let mut state = BRIDGE.lock();
+    let header = link.remote_header();
+    if header.magic.load(Ordering::Acquire) != BRIDGE_MAGIC {
+        return;
+    }
+    let head = header.mbox_head.load(Ordering::Relaxed);
+    if head.wrapping_sub(header.mbox_tail.load(Ordering::Acquire)) as usize >= BRIDGE_MAILBOX_SLOTS
+    {
+        state.dropped += 1;
+        warn!("Bridge mailbox of node {} full, {:?} dropped", link.peer(), msg);
+        return;
+    }
+    // SAFETY: the slot is free and only written by this node, under the bridge lock.
+    unsafe {
+        (*link.remote_header_mut()).mailbox[head as usize % BRIDGE_MAILBOX_SLOTS] = msg;
+    }
+    header
+        .mbox_head
+        .store(head.wrapping_add(1), Ordering::Release);
+}
+
+/// Polls the half of the peer node: tracks its directory and handles its messages.
+fn poll() {
+    let Some(link) = LINK.get() else {
+        return;
+    };
+    let actions = {
+        let mut state = BRIDGE.lock();
+        let mut actions = Vec::new();
+        scan_peer(link, &mut state, &mut actions);
+        let header = link.local_header();
+        let head = header.mbox_head.load(Ordering::Acquire);
+        let mut tail = header.mbox_tail.load(Ordering::Relaxed);
+        while tail != head {
+            actions.push(Action::Message(
+                header.mailbox[tail as usize % BRIDGE_MAILBOX_SLOTS],
+            ));
+            tail = tail.wrapping_add(1);
+        }
+        header.mbox_tail.store(tail, Ordering::Release);
+        actions
+    };
+    for action in actions {
+        if let Err(e) = run(link, action) {
+            warn!("Bridge: {:?}", e);
+        }
+    }
+}
+
+/// Compares the directory of the peer with the channels imported, see the [module docs](self).
+fn scan_peer(link: &Link, state: &mut BridgeState, actions: &mut Vec<Action>) {
+    let header = link.remote_header();
+    let up = header.magic.load(Ordering::Acquire) == BRIDGE_MAGIC
+        && header.version == BRIDGE_VERSION
+        && header.node == link.peer();
+    let seen = up.then(|| (header.boot_id, header.generation.load(Ordering::Acquire)));
+    if seen == state.peer_seen {
+        return;
+    }
+    if state.peer_seen.map(|(boot, _)| boot) != seen.map(|(boot, _)| boot) {
+        // The peer rebooted or went down, everything attached to it is gone.
+        let mut proxies: Vec<usize> = state.imports.keys().map(|(proxy, _)| *proxy).collect();
+        proxies.extend(state.attached.keys().map(|(proxy, _, _)| *proxy));
+        proxies.sort_unstable();
+        proxies.dedup();
+        state.imports.clear();
+        state.attached.clear();
+        if !proxies.is_empty() {
+            info!("Bridge node {} is gone", link.peer());
+            actions.push(Action::DropPeer(proxies));
+        }
+    }
+    state.peer_seen = seen;
+    if !up {
+        return;
+    }
+
+    let mut live = Vec::new();
+    for entry in &header.exports {
+        if entry.state.load(Ordering::Acquire) != BRIDGE_EXPORT_LIVE {
+            continue;
+        }
+        let (offset, size) = (entry.offset as usize, entry.size as usize);
+        if offset < PAGE_SIZE_4K
+            || size == 0
+            || offset % PAGE_SIZE_4K != 0
+            || offset.saturating_add(size) > link.size
+        {
+            warn!("Bridge node {} exports a channel out of its half", link.peer());
+            continue;
+        }
+        let proxy = REMOTE_VM_ID_BASE + entry.vm_id as usize;
+        let key = entry.key as usize;
+        live.push((proxy, key));
+        if !state.imports.contains_key(&(proxy, key)) {
+            actions.push(Action::Import {
+                proxy,
+                key,
+                base: link.remote + offset,
+                size,
+            });
+        }
+    }
+    for (proxy, key) in state.imports.keys() {
+        if !live.contains(&(*proxy, *key)) {
+            actions.push(Action::Withdraw {
+                proxy: *proxy,
+                key: *key,
+            });
+        }
+    }
+}
+
+fn run(link: &Link, action: Action) -> AxResult {
+    match action {
+        Action::Import {
+            proxy,
+            key,
+            base,
+            size,
+        } => {
+            let handle = ivc::import_channel(proxy, key, base, size)?;
+            info!(
+                "Bridge: VM[{}] IVC channel {:#x} of node {} imported as VM[{:#x}]",
+                proxy - REMOTE_VM_ID_BASE,
+                key,
+                link.peer(),
+                proxy
+            );
+            BRIDGE.lock().imports.insert((proxy, key), handle);
+        }
+        Action::Withdraw { proxy, key } => {
+            BRIDGE.lock().imports.remove(&(proxy, key));
+            ivc::withdraw_channel(proxy, key);
+        }
+        Action::DropPeer(proxies) => {
+            for proxy in proxies {
+                ivc::teardown_vm_channels(proxy);
+            }
+        }
+        Action::Message(msg) => handle_message(link, msg)?,
+    }
+    Ok(())
+}
+
+fn handle_message(link: &Link, msg: BridgeMessage) -> AxResult {
+    let sender = REMOTE_VM_ID_BASE + msg.sender_vm as usize;
+    let key = msg.key as usize;
+    let local_publisher = msg.publisher_node == link.node as u32;
+    let publisher = if local_publisher {
+        msg.publisher_vm as usize
+    } else {
+        REMOTE_VM_ID_BASE + msg.publisher_vm as usize
+    };
+    match msg.kind {
+        BRIDGE_MSG_KICK => {
+            // Replayed by the proxy of the publisher or of the subscriber, whichever is remote.
+            let (vm_id, handle) = {
+                let state = BRIDGE.lock();
+                if local_publisher {
+                    (sender, state.attached.get(&(sender, publisher, key)).copied())
+                } else {
+                    (publisher, state.imports.get(&(publisher, key)).copied())
+                }
+            };
+            let handle = handle.ok_or_else(|| {
+                ax_err_type!(
+                    NotFound,
+                    format!("kick on unknown channel {:#x} of VM[{:#x}]", key, publisher)
+                )
+            })?;
+            ivc::kick(vm_id, 0, handle)
+        }
+        BRIDGE_MSG_ATTACH if local_publisher => {
+            if !is_exported(publisher, key) {
+                return ax_err!(
+                    PermissionDenied,
+                    format!("VM[{}] does not export channel {:#x}", publisher, key)
+                );
+            }
+            // The proxy has no GPA, the region is mapped by the peer node.
+            let (_, _, handle) = ivc::subscribe_to_channel_of_publisher(
+                publisher,
+                key,
+                sender,
+                GuestPhysAddr::from(0),
+                0,
+            )?;
+            BRIDGE
+                .lock()
+                .attached
+                .insert((sender, publisher, key), handle);
+            Ok(())
+        }
+        BRIDGE_MSG_DETACH if local_publisher => {
+            let Some(handle) = BRIDGE.lock().attached.remove(&(sender, publisher, key)) else {
+                return Ok(());
+            };
+            ivc::unsubscribe_from_channel(handle, sender).map(|_| ())
+        }
+        _ => ax_err!(InvalidInput, format!("invalid bridge message {:?}", msg)),
+    }
+}
+
+/// The state of the bridge, for the shell.
+#[derive(Debug, Clone)]
+pub struct BridgeStatus {
+    pub kind: LinkKind,
+    pub node: u16,
+    pub peer_up: bool,
+    /// `(publisher VM ID, key, size)` of the channels exported by this node.
+    pub exported: Vec<(usize, usize, usize)>,
+    /// `(proxy VM ID, key)` of the channels imported from the peer.
+    pub imported: Vec<(usize, usize)>,
+    /// `(proxy VM ID, publisher VM ID, key)` of the remote subscriptions to local channels.
+    pub attached: Vec<(usize, usize, usize)>,
+    pub dropped: u64,
+}
+
+/// Returns the state of the bridge, `None` if it's not up.
+pub fn status() -> Option<BridgeStatus> {
+    let link = LINK.get()?;
+    let state = BRIDGE.lock();
+    let exported = link
+        .local_header()
+        .exports
+        .iter()
+        .filter(|entry| entry.state.load(Ordering::Relaxed) == BRIDGE_EXPORT_LIVE)
+        .map(|entry| (entry.vm_id as usize, entry.key as usize, entry.size as usize))
+        .collect();
+    Some(BridgeStatus {
+        kind: link.kind,
+        node: link.node,
+        peer_up: state.peer_seen.is_some(),
+        exported,
+        imported: state.imports.keys().copied().collect(),
+        attached: state.attached.keys().copied().collect(),
+        dropped: state.dropped,
+    })
+}
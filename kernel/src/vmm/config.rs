@@ -1,12 +1,16 @@
 use axaddrspace::GuestPhysAddr;
-use axerrno::AxResult;
+use axerrno::{AxResult, ax_err_type};
 use axvm::{
     VMMemoryRegion,
     config::{AxVMConfig, AxVMCrateConfig, VmMemMappingType},
 };
 use core::alloc::Layout;
 
-use crate::vmm::{VM, images::ImageLoader, vm_list::push_vm};
+use crate::vmm::{
+    VM,
+    images::{ImageLoader, LinuxBoot},
+    vm_list::push_vm,
+};
 
 #[cfg(target_arch = "aarch64")]
 use crate::vmm::fdt::*;
@@ -124,6 +128,19 @@ pub fn get_vm_dtb_arc(_vm_cfg: &AxVMConfig) -> Option<Arc<[u8]>> {
     None
 }
 
+/// Parses the raw TOML config of a guest VM into a table.
+///
+/// `AxVMCrateConfig` only covers the settings understood by `axvm`, sections consumed by the
+/// hypervisor itself (e.g., `[pci]`) are looked up from the returned table.
+pub fn parse_raw_vm_config(raw_cfg: &str) -> AxResult<toml::Table> {
+    raw_cfg.parse::<toml::Table>().map_err(|e| {
+        ax_err_type!(
+            InvalidInput,
+            format!("Failed to parse raw VM config: {:?}", e)
+        )
+    })
+}
+
 pub fn init_guest_vms() {
     // Initialize the DTB cache in the fdt module
     #[cfg(target_arch = "aarch64")]
@@ -155,10 +172,52 @@ pub fn init_guest_vms() {
     }
 }
 
+/// Creates the guest VMs described by a raw config, returns the ID of the first one.
+///
+/// A config with a `[scale]` section is instantiated several times, which is used by scalability
+/// tests. Instance `i` gets the ID `base.id + i` and the name `base.name-i`:
+///
+/// ```toml
+/// [scale]
+/// instances = 64
+/// ```
 pub fn init_guest_vm(raw_cfg: &str) -> AxResult<usize> {
-    let vm_create_config =
-        AxVMCrateConfig::from_toml(raw_cfg).expect("Failed to resolve VM config");
+    let vm_create_config = AxVMCrateConfig::from_toml(raw_cfg).map_err(|e| {
+        ax_err_type!(
+            InvalidInput,
+            format!("Failed to resolve VM config: {:?}", e)
+        )
+    })?;
+    let raw_table = parse_raw_vm_config(raw_cfg)?;
+    let instances = instance_count(&raw_table);
+
+    let first_id = vm_create_config.base.id;
+    for idx in 0..instances {
+        let mut instance_config = vm_create_config.clone();
+        if idx > 0 {
+            instance_config.base.id = first_id + idx;
+            instance_config.base.name = format!("{}-{}", vm_create_config.base.name, idx);
+        }
+        init_guest_vm_instance(instance_config, &raw_table)?;
+    }
+    Ok(first_id)
+}
 
+/// Returns the number of VMs created from a raw config, see [`init_guest_vm`].
+pub fn instance_count(raw_table: &toml::Table) -> usize {
+    raw_table
+        .get("scale")
+        .and_then(|v| v.get("instances"))
+        .and_then(|v| v.as_integer())
+        .map_or(1, |v| v.max(1) as usize)
+}
+
+/// Creates a VM from its config and the raw TOML table it was parsed from, and sets up its
+/// resources. Returns the ID of the VM, which is left `Loaded`.
+pub fn init_guest_vm_instance(
+    vm_create_config: AxVMCrateConfig,
+    raw_table: &toml::Table,
+) -> AxResult<usize> {
     if let Some(linux) = super::images::get_image_header(&vm_create_config) {
         debug!(
             "VM[{}] Linux header: {:#x?}",
@@ -174,7 +233,7 @@ pub fn init_guest_vm(raw_cfg: &str) -> AxResult<usize> {
 
     // Handle FDT-related operations for aarch64
     #[cfg(target_arch = "aarch64")]
-    handle_fdt_operations(&mut vm_config, &vm_create_config);
+    handle_fdt_operations(&mut vm_config, &vm_create_config, raw_table)?;
 
     // info!("after parse_vm_interrupt, crate VM[{}] with config: {:#?}", vm_config.id(), vm_config);
     info!("Creating VM[{}] {:?}", vm_config.id(), vm_config.name());
@@ -184,7 +243,9 @@ pub fn init_guest_vm(raw_cfg: &str) -> AxResult<usize> {
     let vm_id = vm.id();
     push_vm(vm.clone());
 
-    vm_alloc_memorys(&vm_create_config, &vm);
+    super::lazymem::setup_vm_lazy_memory(&vm, raw_table, &vm_create_config.kernel.memory_regions)?;
+    super::template::setup_vm_stamp(&vm, raw_table)?;
+    vm_alloc_memorys(&vm_create_config, &vm, raw_table);
 
     let main_mem = vm
         .memory_regions()
@@ -193,18 +254,76 @@ pub fn init_guest_vm(raw_cfg: &str) -> AxResult<usize> {
         .expect("VM must have at least one memory region");
 
     config_guest_address(&vm, &main_mem);
+    super::uefi::setup_vm_uefi_boot(&vm, raw_table)?;
+    super::reboot::setup_vm_reboot(&vm, &vm_create_config, raw_table)?;
+    super::template::setup_vm_template(&vm, &vm_create_config, raw_table)?;
+    super::identity::setup_vm_identity(&vm, raw_table)?;
 
     // Load corresponding images for VM.
     info!("VM[{}] created success, loading images...", vm.id());
 
-    let mut loader = ImageLoader::new(main_mem, vm_create_config, vm.clone());
+    let linux = LinuxBoot::from_raw_config(raw_table)?;
+    let mut loader = ImageLoader::new(main_mem, vm_create_config, vm.clone(), linux);
     loader.load().expect("Failed to load VM images");
 
     if let Err(e) = vm.init() {
         panic!("VM[{}] setup failed: {:?}", vm.id(), e);
     }
 
+    super::blocks::setup_vm_blocks(&vm, raw_table)?;
+    super::security::setup_vm_security(&vm, raw_table)?;
+    super::seal::setup_vm_seal(&vm, raw_table)?;
+    super::posted::setup_vm_posted(&vm);
+    super::stats::setup_vm_stats(&vm);
+    super::peers::setup_vm_peers(&vm, raw_table)?;
+    super::bridge::setup_vm_bridge(&vm, raw_table)?;
+    super::pci::setup_vm_passthrough(&vm, raw_table)?;
+    super::heatmap::setup_vm_heatmap(&vm, raw_table)?;
+    super::power::setup_vm_power_device(&vm, raw_table)?;
+    super::doorbell::setup_vm_doorbells(&vm, raw_table)?;
+    super::virtio::setup_vm_virtio_devices(&vm, raw_table)?;
+    super::framebuffer::setup_vm_framebuffer(&vm, raw_table)?;
+    super::uartshare::setup_vm_shared_uart(&vm, raw_table)?;
+    super::sleep::setup_vm_sleep(&vm, raw_table)?;
+    #[cfg(target_arch = "aarch64")]
+    super::vits::setup_vm_its(&vm, raw_table)?;
+    #[cfg(target_arch = "riscv64")]
+    super::vintc::setup_vm_intc(&vm, raw_table)?;
+    #[cfg(target_arch = "x86_64")]
+    super::x2apic::setup_vm_x2apic(&vm, raw_table)?;
+    #[cfg(target_arch = "x86_64")]
+    super::acpi::setup_vm_acpi(&vm, raw_table)?;
+    #[cfg(target_arch = "x86_64")]
+    super::pvcompat::setup_vm_pv_compat(&vm, raw_table)?;
+    super::watchdog::setup_vm_watchdog(&vm, raw_table)?;
+    super::crash::setup_vm_crash(&vm, raw_table)?;
+    super::reclaim::setup_vm_reclaim(&vm, raw_table)?;
+    super::hang::setup_vm_hang_detect(&vm, raw_table)?;
+    super::exit_budget::setup_vm_exit_budget(&vm, raw_table)?;
+    super::guest_time::setup_vm_guest_time(&vm, raw_table)?;
+    #[cfg(target_arch = "aarch64")]
+    super::smc::setup_vm_smc(&vm, raw_table)?;
+    super::vmdef::setup_vm_manager(&vm, raw_table)?;
+    super::affinity::setup_vm_affinity(&vm, raw_table)?;
+    super::sched::setup_vm_scheduling(&vm, raw_table)?;
+    super::direct_irq::setup_vm_direct_irqs(&vm, raw_table)?;
+    super::irqpolicy::setup_vm_irq_policy(&vm, raw_table)?;
+    super::cpufreq::setup_vm_cpufreq(&vm, raw_table)?;
+    super::group::setup_vm_group(&vm, raw_table)?;
+    super::traps::setup_vm_traps(&vm, raw_table)?;
+    super::nested::setup_vm_nested(&vm, raw_table)?;
+    super::pmu::setup_vm_pmu(&vm, raw_table)?;
+    #[cfg(target_arch = "aarch64")]
+    super::spe::setup_vm_spe(&vm, raw_table)?;
+    super::tracectx::setup_vm_trace_ctx(&vm, raw_table)?;
+    super::lifecycle::setup_vm_lifecycle(&vm, raw_table)?;
+    super::unknown_hvc::setup_vm_unknown_hvc(&vm, raw_table)?;
+    super::shutdown::setup_vm_shutdown(&vm, raw_table)?;
+    super::services::setup_vm_services(&vm, raw_table)?;
+    super::hvinfo::setup_vm_hv_info(&vm, raw_table)?;
+
     vm.set_vm_status(axvm::VMStatus::Loaded);
+    super::lifecycle::emit(vm_id, super::lifecycle::LifecycleEvent::Created, 0);
 
     Ok(vm_id)
 }
@@ -229,11 +348,14 @@ fn config_guest_address(vm: &VM, main_memory: &VMMemoryRegion) {
     });
 }
 
-fn vm_alloc_memorys(vm_create_config: &AxVMCrateConfig, vm: &VM) {
+fn vm_alloc_memorys(vm_create_config: &AxVMCrateConfig, vm: &VM, raw_table: &toml::Table) {
     const MB: usize = 1024 * 1024;
     const ALIGN: usize = 2 * MB;
 
-    for memory in &vm_create_config.kernel.memory_regions {
+    for (idx, memory) in vm_create_config.kernel.memory_regions.iter().enumerate() {
+        if super::lazymem::is_lazy(raw_table, idx, memory) {
+            continue;
+        }
         match memory.map_type {
             VmMemMappingType::MapAlloc => {
                 vm.alloc_memory_region(
@@ -0,0 +1,204 @@
+//! Aggregation of the guest consoles into a manager VM.
+//!
+//! The consoles emulated by the hypervisor, the virtio-console devices (see
+//! [`crate::vmm::virtio`]) and the serial port of UEFI guests (see [`crate::vmm::uefi`]), print
+//! what their guest writes on the hypervisor console line by line. A manager VM (see
+//! [`crate::vmm::vmdef`]) gets the lines of all the other VMs as well, as a single stream, with
+//! the [`HVC_CONSOLE_ATTACH`] hypercall: `args[0]` is the key of a raw IVC channel it publishes,
+//! or [`CONSOLE_DETACH`] to stop. A single console utility in the manager then serves all the
+//! guests, the VMs created later included. One manager is attached at a time.
+//!
+//! The data of the channel is a [`ConsoleMuxHeader`] followed, from offset
+//! [`CONSOLE_MUX_STREAM_OFFSET`], by the stream: `capacity` bytes used as a ring. Each line is a
+//! [`ConsoleFrame`] naming its VM, followed by the `len` bytes of the line without its end, padded
+//! to [`CONSOLE_FRAME_ALIGN`] bytes. Frames wrap around the end of the ring like the bytes of the
+//! stream. `head` is the number of bytes written since the manager attached, updated after each
+//! frame, so byte `n` of the stream is at `n % capacity` and `head` is always at the start of a
+//! frame. A reader more than `capacity` bytes behind `head` lost frames, and resumes at `head`.
+//! The manager is notified of new frames with the vector it registered for the channel.
+//!
+//! [`HVC_CONSOLE_ATTACH`]: crate::vmm::hvc::HVC_CONSOLE_ATTACH
+use core::mem::{offset_of, size_of};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use axerrno::{AxResult, ax_err};
+use spin::Mutex;
+
+use crate::vmm::hvc::{self, HVC_CONSOLE_ATTACH, HvcService};
+use crate::vmm::ivc;
+
+/// `args[0]` of [`crate::vmm::hvc::HVC_CONSOLE_ATTACH`] detaching the caller.
+pub const CONSOLE_DETACH: u64 = u64::MAX;
+/// `magic` of [`ConsoleMuxHeader`], "AXCM".
+pub const CONSOLE_MUX_MAGIC: u32 = u32::from_le_bytes(*b"AXCM");
+/// Version of the layouts of the stream.
+pub const CONSOLE_MUX_VERSION: u16 = 1;
+/// Offset of the stream in the data of the channel.
+pub const CONSOLE_MUX_STREAM_OFFSET: usize = 64;
+/// Alignment of the frames in the stream.
+pub const CONSOLE_FRAME_ALIGN: usize = 8;
+
+/// Consoles, in `ConsoleFrame::source`.
+pub const CONSOLE_SRC_VIRTIO: u16 = 1;
+pub const CONSOLE_SRC_UEFI_SERIAL: u16 = 2;
+
+/// Header of the data of the channel.
+#[repr(C)]
+pub struct ConsoleMuxHeader {
+    pub magic: u32,
+    pub version: u16,
+    /// Size of a [`ConsoleFrame`].
+    pub frame_size: u16,
+    /// Size of the stream in bytes.
+    pub capacity: u32,
+    pub _reserved: u32,
+    /// Bytes written to the stream since the manager attached.
+    pub head: AtomicU64,
+}
+
+/// A line of the console of a VM.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ConsoleFrame {
+    pub vm_id: u32,
+    /// Bytes of the line following the frame.
+    pub len: u16,
+    /// The console of the line, `CONSOLE_SRC_*`.
+    pub source: u16,
+}
+
+/// The attached manager.
+struct Sink {
+    vm_id: usize,
+    key: usize,
+    head: u64,
+}
+
+static SINK: Mutex<Option<Sink>> = Mutex::new(None);
+
+/// The [`HVC_CONSOLE_ATTACH`](crate::vmm::hvc::HVC_CONSOLE_ATTACH) hypercall, for manager VMs.
+pub static HVC_SERVICE: HvcService = HvcService {
+    name: "console attach",
+    codes: HVC_CONSOLE_ATTACH..HVC_CONSOLE_ATTACH + 1,
+    permit: hvc::permit_managers,
+    deferrable: false,
+    handler: |vm, _, _, args| handle_attach(vm.id(), args).map(|_| 0),
+};
+
+/// Handles the [`HVC_CONSOLE_ATTACH`](crate::vmm::hvc::HVC_CONSOLE_ATTACH) hypercall of VM
+/// `vm_id`.
+fn handle_attach(vm_id: usize, args: [u64; 6]) -> AxResult {
+    let mut sink = SINK.lock();
+    if args[0] == CONSOLE_DETACH {
+        if sink.as_ref().is_some_and(|sink| sink.vm_id == vm_id) {
+            *sink = None;
+            info!("VM[{}] detached from the guest consoles", vm_id);
+        }
+        return Ok(());
+    }
+    if let Some(other) = sink.as_ref().filter(|sink| sink.vm_id != vm_id) {
+        return ax_err!(
+            ResourceBusy,
+            format!("VM[{}] is attached to the guest consoles", other.vm_id)
+        );
+    }
+
+    let key = args[0] as usize;
+    let capacity = ivc::write_raw_channel(vm_id, key, |data| {
+        let capacity = data.len().saturating_sub(CONSOLE_MUX_STREAM_OFFSET)
+            / CONSOLE_FRAME_ALIGN
+            * CONSOLE_FRAME_ALIGN;
+        if capacity < size_of::<ConsoleFrame>() + CONSOLE_FRAME_ALIGN {
+            return 0;
+        }
+        data.fill(0);
+        let header = ConsoleMuxHeader {
+            magic: CONSOLE_MUX_MAGIC,
+            version: CONSOLE_MUX_VERSION,
+            frame_size: size_of::<ConsoleFrame>() as u16,
+            capacity: capacity as u32,
+            _reserved: 0,
+            head: AtomicU64::new(0),
+        };
+        // SAFETY: the header fits in `data`, which is at least `CONSOLE_MUX_STREAM_OFFSET` long.
+        unsafe { data.as_mut_ptr().cast::<ConsoleMuxHeader>().write_unaligned(header) };
+        capacity
+    })?;
+    if capacity == 0 {
+        return ax_err!(InvalidInput, "IVC channel too small for the console stream");
+    }
+    *sink = Some(Sink {
+        vm_id,
+        key,
+        head: 0,
+    });
+    info!("VM[{}] attached to the guest consoles, channel {:#x}", vm_id, key);
+    Ok(())
+}
+
+/// Appends a line of the console `source` of VM `vm_id` to the stream of the attached manager,
+/// if any.
+pub fn feed(vm_id: usize, source: u16, line: &[u8]) {
+    let mut guard = SINK.lock();
+    let Some(sink) = guard.as_mut().filter(|sink| sink.vm_id != vm_id) else {
+        return;
+    };
+    let frame = ConsoleFrame {
+        vm_id: vm_id as u32,
+        len: line.len().min(u16::MAX as usize) as u16,
+        source,
+    };
+    let line = &line[..frame.len as usize];
+    let written = ivc::write_raw_channel(sink.vm_id, sink.key, |data| {
+        let capacity = data.len().saturating_sub(CONSOLE_MUX_STREAM_OFFSET)
+            / CONSOLE_FRAME_ALIGN
+            * CONSOLE_FRAME_ALIGN;
+        let size = (size_of::<ConsoleFrame>() + line.len()).next_multiple_of(CONSOLE_FRAME_ALIGN);
+        if size > capacity {
+            return 0;
+        }
+        let (header, stream) = data.split_at_mut(CONSOLE_MUX_STREAM_OFFSET);
+        let stream = &mut stream[..capacity];
+        // SAFETY: `ConsoleFrame` is plain data without padding.
+        let frame_bytes = unsafe {
+            core::slice::from_raw_parts(
+                (&frame as *const ConsoleFrame).cast::<u8>(),
+                size_of::<ConsoleFrame>(),
+            )
+        };
+        let padding = [0u8; CONSOLE_FRAME_ALIGN];
+        let padding = &padding[..size - frame_bytes.len() - line.len()];
+        let mut pos = sink.head as usize % capacity;
+        for bytes in [frame_bytes, line, padding] {
+            for byte in bytes {
+                stream[pos] = *byte;
+                pos = (pos + 1) % capacity;
+            }
+        }
+        let head = header[offset_of!(ConsoleMuxHeader, head)..].as_mut_ptr();
+        // SAFETY: `head` is 8-byte aligned in the page of the channel, and guests only read it.
+        unsafe { (*head.cast::<AtomicU64>()).store(sink.head + size as u64, Ordering::Release) };
+        size
+    });
+    match written {
+        Ok(size) => sink.head += size as u64,
+        Err(e) => {
+            // The channel is gone, the manager has to attach again.
+            warn!("VM[{}] detached from the guest consoles: {:?}", sink.vm_id, e);
+            *guard = None;
+        }
+    }
+}
+
+/// Detaches the VM from the guest consoles, called when the VM is destroyed.
+pub fn teardown_vm_conmux(vm_id: usize) {
+    let mut sink = SINK.lock();
+    if sink.as_ref().is_some_and(|sink| sink.vm_id == vm_id) {
+        *sink = None;
+    }
+}
+
+/// The manager VM attached to the guest consoles and its channel, for the shell.
+pub fn attached() -> Option<(usize, usize)> {
+    SINK.lock().as_ref().map(|sink| (sink.vm_id, sink.key))
+}
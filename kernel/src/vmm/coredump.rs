@@ -0,0 +1,819 @@
+//! Core dumps of guests.
+//!
+//! An on-demand dump briefly pauses the VM, writes its RAM and vCPU state as an ELF core file, and
+//! resumes it, so hangs that never reach a guest crash handler can be analysed offline. The guest
+//! keeps running afterwards. The dump of a crashed VM (see [`crate::vmm::crash`]) leaves it
+//! suspended and may be limited to a window of its memory.
+//!
+//! Layout of the core file:
+//!
+//! - A `PT_NOTE` segment with notes of owner `AXVISOR`: one [`NT_AXVISOR_VM`] note with the VM ID,
+//!   the number of vCPUs and the VM name, one [`NT_AXVISOR_VCPU`] note per vCPU, for a crash,
+//!   one [`NT_AXVISOR_CRASH`] note and, for a checkpoint, one [`NT_AXVISOR_CHECKPOINT`] note. It
+//!   is followed by an [`NT_PRSTATUS`] note of owner `CORE` per vCPU whose registers are known,
+//!   the one of the crashed vCPU first for a crash.
+//! - A `PT_LOAD` segment per guest memory region (or part of one in the window), with the guest
+//!   physical address in `p_paddr`.
+//!
+//! The [`NT_AXVISOR_VCPU`] notes hold the state tracked by the hypervisor (scheduling state and
+//! pCPU affinity). The registers are in `NT_PRSTATUS` notes laid out like those of the threads of
+//! a Linux process, which debuggers read as is: `pr_pid` is the vCPU ID plus one and `pr_reg` the
+//! registers in the order of [`GuestRegs`]. They are the registers each vCPU stopped with (see
+//! [`regs::current`]): a vCPU which didn't leave the guest in time (see
+//! [`CoreDumpSummary::quiesced`]) or whose backend doesn't expose them (riscv64) has none. The
+//! crashed vCPU has the registers it crashed with, and `pr_cursig` and `si_signo` are the signal
+//! of the crash (see [`CrashReason::signal`]), as debuggers take the first thread with a signal
+//! for the one that crashed.
+//!
+//! [`CrashReason::signal`]: crate::vmm::crash::CrashReason::signal
+//!
+//! Periodic snapshots of a long-running guest can be taken as a chain of checkpoints, see
+//! [`checkpoint_vm`]: a full checkpoint is a complete dump that also starts tracking the pages the
+//! guest dirties (see [`crate::vmm::dirty`]), and each incremental checkpoint after it only has
+//! `PT_LOAD` segments for the pages dirtied since the previous checkpoint of the chain. The notes,
+//! i.e., the VM and vCPU state, and the guest time are in every checkpoint, as they are small. A
+//! snapshot is restored by applying the full checkpoint, then the incremental ones in sequence.
+//!
+//! The core files of a VM with a snapshot key are sealed, see [`crate::vmm::seal`].
+//!
+//! # Streaming to a manager VM
+//!
+//! A manager VM (see [`crate::vmm::vmdef`]) takes the dump of another VM with the [`HVC_VM_DUMP`]
+//! hypercall, `args[0]` being the peer handle of the VM, which must allow introspection (see
+//! [`crate::vmm::security::Introspection`]), and `args[1]` the key of a raw IVC channel published
+//! by the caller. The hypercall returns at once and the core file, as [`dump_vm`] writes it, is
+//! streamed through the channel in chunks: the data of the channel is a [`DumpStreamHeader`]
+//! followed, from offset [`DUMP_STREAM_DATA_OFFSET`], by the chunk. For each chunk, the hypervisor
+//! waits for `state` to be [`DUMP_CHUNK_FREE`], writes the chunk, its offset in the file and its
+//! size, sets `state` to [`DUMP_CHUNK_READY`] and notifies the caller with the vector it
+//! registered for the channel. The manager copies the chunk and sets `state` back to
+//! [`DUMP_CHUNK_FREE`]. Once the file is complete, or the dump failed, `state` is
+//! [`DUMP_STREAM_END`], `offset` the size of the file and `error` 0 or the error code.
+//!
+//! The VM is paused until the whole file is streamed, and the dump fails if the manager doesn't
+//! consume a chunk within [`DUMP_STREAM_TIMEOUT`].
+//!
+//! [`HVC_VM_DUMP`]: crate::vmm::hvc::HVC_VM_DUMP
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Debug;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
+use std::io::{self, Write};
+use std::os::arceos::modules::axhal::time::busy_wait;
+use std::thread;
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxError, AxResult, ax_err, ax_err_type};
+use axvcpu::VCpuState;
+use axvm::VMStatus;
+use spin::Mutex;
+
+use crate::vmm::crash::CrashInfo;
+use crate::vmm::hvc::{self, HVC_VM_DUMP, HvcService};
+use crate::vmm::regs::{self, GUEST_REGS, GuestRegs};
+use crate::vmm::security::Introspection;
+use crate::vmm::{VMRef, dirty, guest_time, introspect, ivc, seal, vcpus};
+
+/// Note type of the VM description: `u32` VM ID, `u32` vCPU count, then the NUL-terminated name.
+pub const NT_AXVISOR_VM: u32 = 0x4158_0001;
+/// Note type of a vCPU: `u32` vCPU ID, `u32` [`VCpuState`] (see [`vcpu_state_code`]) and `u64`
+/// pCPU affinity mask, 0 if none.
+pub const NT_AXVISOR_VCPU: u32 = 0x4158_0002;
+/// Note type of a crash: `u32` ID of the crashed vCPU, `u32` [`CrashReason`](crate::vmm::crash::CrashReason), `u64` host time of
+/// the crash in nanoseconds, then the NUL-terminated description of the crash.
+pub const NT_AXVISOR_CRASH: u32 = 0x4158_0003;
+
+/// Note type of a checkpoint: `u64` sequence number in the chain, 1 for the full checkpoint,
+/// `u64` sequence number of the checkpoint it applies over, 0 for the full one, and `u64` guest
+/// time in nanoseconds (see [`crate::vmm::guest_time`]), 0 if the VM has none.
+pub const NT_AXVISOR_CHECKPOINT: u32 = 0x4158_0004;
+
+/// Note type of the registers of a vCPU, an `elf_prstatus` of owner `CORE` as in the core files
+/// of Linux, see the [module documentation](self).
+pub const NT_PRSTATUS: u32 = 1;
+
+const NOTE_OWNER: &[u8] = b"AXVISOR\0";
+const CORE_NOTE_OWNER: &[u8] = b"CORE\0";
+
+/// Offsets of `pr_info.si_signo`, `pr_cursig`, `pr_pid` and `pr_reg` in an `elf_prstatus`.
+const PRSTATUS_SIGNO_OFFSET: usize = 0;
+const PRSTATUS_CURSIG_OFFSET: usize = 12;
+const PRSTATUS_PID_OFFSET: usize = 32;
+const PRSTATUS_REGS_OFFSET: usize = 112;
+/// Size of an `elf_prstatus`, whose registers are followed by `pr_fpvalid` and padding.
+const PRSTATUS_SIZE: usize = PRSTATUS_REGS_OFFSET + GUEST_REGS * 8 + 8;
+
+/// `magic` of [`DumpStreamHeader`].
+pub const DUMP_STREAM_MAGIC: u32 = u32::from_le_bytes(*b"AXDS");
+/// Version of the layout of the channel of a streamed dump.
+pub const DUMP_STREAM_VERSION: u32 = 1;
+/// Offset of the chunk in the data of the channel.
+pub const DUMP_STREAM_DATA_OFFSET: usize = 64;
+/// States of the channel, in `DumpStreamHeader::state`.
+pub const DUMP_CHUNK_FREE: u32 = 0;
+pub const DUMP_CHUNK_READY: u32 = 1;
+pub const DUMP_STREAM_END: u32 = 2;
+/// How long the manager has to consume a chunk.
+pub const DUMP_STREAM_TIMEOUT: Duration = Duration::from_secs(10);
+const DUMP_STREAM_POLL: Duration = Duration::from_millis(1);
+
+const ELF_HEADER_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const ET_CORE: u16 = 4;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_R: u32 = 4;
+const PF_W: u32 = 2;
+
+#[cfg(target_arch = "aarch64")]
+const EM_HOST: u16 = 183;
+#[cfg(target_arch = "riscv64")]
+const EM_HOST: u16 = 243;
+#[cfg(target_arch = "x86_64")]
+const EM_HOST: u16 = 62;
+
+/// How long to wait for the vCPUs to leave the guest before dumping anyway.
+const PAUSE_TIMEOUT: Duration = Duration::from_secs(1);
+const PAUSE_POLL: Duration = Duration::from_millis(10);
+/// Guest memory is written in chunks of this size.
+const CHUNK_SIZE: usize = 0x10_0000;
+
+/// Summary of a core dump.
+#[derive(Debug, Clone, Copy)]
+pub struct CoreDumpSummary {
+    /// Bytes of guest memory dumped.
+    pub memory_bytes: usize,
+    /// Total size of the core file, sealed if it is.
+    pub file_bytes: usize,
+    /// Whether the core file is sealed.
+    pub sealed: bool,
+    /// Whether all vCPUs were out of the guest during the dump. If not, the memory of the
+    /// remaining ones may be inconsistent.
+    pub quiesced: bool,
+    /// Sequence number of the checkpoint, if the dump is one.
+    pub checkpoint: Option<u64>,
+}
+
+/// Kind of checkpoint to take, see [`checkpoint_vm`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointKind {
+    /// A complete dump, starting a new chain.
+    Full,
+    /// The pages dirtied since the previous checkpoint of the chain.
+    Incremental,
+    /// An incremental checkpoint ending the chain, which stops tracking the dirty pages.
+    Last,
+}
+
+/// A checkpoint written in a dump.
+#[derive(Debug, Clone, Copy)]
+struct Checkpoint {
+    sequence: u64,
+    guest_time_ns: u64,
+}
+
+/// Header of the data of the channel of a streamed dump, see the [module documentation](self).
+#[repr(C)]
+pub struct DumpStreamHeader {
+    pub magic: u32,
+    pub version: u32,
+    /// [`DUMP_CHUNK_FREE`], [`DUMP_CHUNK_READY`] or [`DUMP_STREAM_END`].
+    pub state: AtomicU32,
+    /// Error code of a failed dump, in the [`DUMP_STREAM_END`] state.
+    pub error: i32,
+    /// Offset of the chunk in the core file, the size of the file in the [`DUMP_STREAM_END`]
+    /// state.
+    pub offset: u64,
+    /// Bytes of the chunk.
+    pub size: u64,
+}
+
+/// Code of a vCPU state in [`NT_AXVISOR_VCPU`] notes.
+pub fn vcpu_state_code(state: VCpuState) -> u32 {
+    match state {
+        VCpuState::Invalid => 0,
+        VCpuState::Created => 1,
+        VCpuState::Free => 2,
+        VCpuState::Ready => 3,
+        VCpuState::Running => 4,
+        VCpuState::Blocked => 5,
+    }
+}
+
+fn io_err<E: Debug>(e: E) -> AxError {
+    ax_err_type!(Io, format!("core dump write failed: {:?}", e))
+}
+
+fn push_note(buf: &mut Vec<u8>, owner: &[u8], ty: u32, desc: &[u8]) {
+    buf.extend_from_slice(&(owner.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&ty.to_le_bytes());
+    buf.extend_from_slice(owner);
+    buf.resize(buf.len().next_multiple_of(4), 0);
+    buf.extend_from_slice(desc);
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+/// Returns the `elf_prstatus` of vCPU `vcpu_id` with registers `regs`, stopped by `signal` if it
+/// isn't 0.
+fn prstatus(vcpu_id: usize, regs: &GuestRegs, signal: u32) -> Vec<u8> {
+    let mut desc = vec![0; PRSTATUS_SIZE];
+    desc[PRSTATUS_SIGNO_OFFSET..][..4].copy_from_slice(&signal.to_le_bytes());
+    desc[PRSTATUS_CURSIG_OFFSET..][..2].copy_from_slice(&(signal as u16).to_le_bytes());
+    desc[PRSTATUS_PID_OFFSET..][..4].copy_from_slice(&(vcpu_id as u32 + 1).to_le_bytes());
+    for (i, reg) in regs.iter().enumerate() {
+        desc[PRSTATUS_REGS_OFFSET + i * 8..][..8].copy_from_slice(&reg.to_le_bytes());
+    }
+    desc
+}
+
+fn push_phdr(buf: &mut Vec<u8>, ty: u32, flags: u32, offset: usize, paddr: usize, size: usize) {
+    buf.extend_from_slice(&ty.to_le_bytes());
+    buf.extend_from_slice(&flags.to_le_bytes());
+    buf.extend_from_slice(&(offset as u64).to_le_bytes());
+    // p_vaddr, guest virtual addresses are unknown.
+    buf.extend_from_slice(&0u64.to_le_bytes());
+    buf.extend_from_slice(&(paddr as u64).to_le_bytes());
+    // p_filesz and p_memsz.
+    buf.extend_from_slice(&(size as u64).to_le_bytes());
+    buf.extend_from_slice(&(size as u64).to_le_bytes());
+    // p_align.
+    buf.extend_from_slice(&(if ty == PT_NOTE { 4u64 } else { 0 }).to_le_bytes());
+}
+
+fn elf_header(phnum: usize) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(ELF_HEADER_SIZE);
+    // ELFCLASS64, ELFDATA2LSB, EV_CURRENT, ELFOSABI_NONE.
+    buf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    buf.resize(16, 0);
+    buf.extend_from_slice(&ET_CORE.to_le_bytes());
+    buf.extend_from_slice(&EM_HOST.to_le_bytes());
+    buf.extend_from_slice(&1u32.to_le_bytes());
+    // e_entry, e_phoff, e_shoff.
+    buf.extend_from_slice(&0u64.to_le_bytes());
+    buf.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes());
+    buf.extend_from_slice(&0u64.to_le_bytes());
+    // e_flags, e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx.
+    buf.extend_from_slice(&0u32.to_le_bytes());
+    buf.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    buf.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    buf.extend_from_slice(&(phnum as u16).to_le_bytes());
+    buf.extend_from_slice(&[0; 6]);
+    buf
+}
+
+fn notes(vm: &VMRef, crash: Option<&CrashInfo>, checkpoint: Option<Checkpoint>) -> Vec<u8> {
+    let mut notes = Vec::new();
+
+    let mut desc = Vec::new();
+    desc.extend_from_slice(&(vm.id() as u32).to_le_bytes());
+    desc.extend_from_slice(&(vm.vcpu_num() as u32).to_le_bytes());
+    let name: String = vm.with_config(|cfg| cfg.name());
+    desc.extend_from_slice(name.as_bytes());
+    desc.push(0);
+    push_note(&mut notes, NOTE_OWNER, NT_AXVISOR_VM, &desc);
+
+    for vcpu in vm.vcpu_list() {
+        let mut desc = Vec::with_capacity(16);
+        desc.extend_from_slice(&(vcpu.id() as u32).to_le_bytes());
+        desc.extend_from_slice(&vcpu_state_code(vcpu.state()).to_le_bytes());
+        desc.extend_from_slice(&(vcpu.phys_cpu_set().unwrap_or(0) as u64).to_le_bytes());
+        push_note(&mut notes, NOTE_OWNER, NT_AXVISOR_VCPU, &desc);
+    }
+
+    if let Some(crash) = crash {
+        let mut desc = Vec::new();
+        desc.extend_from_slice(&(crash.vcpu_id as u32).to_le_bytes());
+        desc.extend_from_slice(&(crash.reason as u32).to_le_bytes());
+        desc.extend_from_slice(&crash.time_ns.to_le_bytes());
+        desc.extend_from_slice(crash.detail.as_bytes());
+        desc.push(0);
+        push_note(&mut notes, NOTE_OWNER, NT_AXVISOR_CRASH, &desc);
+    }
+
+    if let Some(checkpoint) = checkpoint {
+        let mut desc = Vec::with_capacity(24);
+        desc.extend_from_slice(&checkpoint.sequence.to_le_bytes());
+        desc.extend_from_slice(&(checkpoint.sequence - 1).to_le_bytes());
+        desc.extend_from_slice(&checkpoint.guest_time_ns.to_le_bytes());
+        push_note(&mut notes, NOTE_OWNER, NT_AXVISOR_CHECKPOINT, &desc);
+    }
+
+    if let Some(crash) = crash
+        && let Some(snapshot) = &crash.regs
+    {
+        let desc = prstatus(crash.vcpu_id, &snapshot.regs, crash.reason.signal());
+        push_note(&mut notes, CORE_NOTE_OWNER, NT_PRSTATUS, &desc);
+    }
+    for vcpu in vm.vcpu_list() {
+        if crash.is_some_and(|crash| crash.vcpu_id == vcpu.id()) {
+            continue;
+        }
+        if let Some(snapshot) = regs::current(vm.id(), vcpu.id()) {
+            let desc = prstatus(vcpu.id(), &snapshot.regs, 0);
+            push_note(&mut notes, CORE_NOTE_OWNER, NT_PRSTATUS, &desc);
+        }
+    }
+    notes
+}
+
+/// Sequence number of the last checkpoint of the VMs with a chain, indexed by VM ID.
+static CHECKPOINTS: Mutex<BTreeMap<usize, u64>> = Mutex::new(BTreeMap::new());
+
+/// Returns the sequence number of the last checkpoint of VM `vm_id`, `None` if it has no chain.
+pub fn last_checkpoint(vm_id: usize) -> Option<u64> {
+    CHECKPOINTS.lock().get(&vm_id).copied()
+}
+
+/// Number of core dumps being written.
+static DUMPS_IN_PROGRESS: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the number of core dumps being written.
+pub fn dumps_in_progress() -> usize {
+    DUMPS_IN_PROGRESS.load(Ordering::Acquire)
+}
+
+/// Suspends `vm` and stops its guest time.
+pub fn suspend(vm: &VMRef) {
+    vm.set_vm_status(VMStatus::Suspended);
+    guest_time::pause(vm.id());
+}
+
+/// Waits until the vCPUs of a suspended VM are out of the guest. Returns whether they all are.
+pub fn wait_quiesced(vm: &VMRef) -> bool {
+    let mut waited = Duration::ZERO;
+    loop {
+        let in_guest = vm
+            .vcpu_list()
+            .iter()
+            .any(|vcpu| matches!(vcpu.state(), VCpuState::Running | VCpuState::Ready));
+        if !in_guest {
+            return true;
+        }
+        if waited >= PAUSE_TIMEOUT {
+            return false;
+        }
+        busy_wait(PAUSE_POLL);
+        waited += PAUSE_POLL;
+    }
+}
+
+/// Resumes a VM paused with [`suspend`].
+pub fn resume(vm: &VMRef) {
+    guest_time::resume(vm.id());
+    vm.set_vm_status(VMStatus::Running);
+    vcpus::notify_all_vcpus(vm.id());
+}
+
+/// Writes a core dump of `vm` to `out`.
+///
+/// A running VM is paused for the duration of the dump and resumed afterwards; a suspended VM
+/// stays suspended.
+pub fn dump_vm<W: Write>(vm: &VMRef, out: &mut W) -> AxResult<CoreDumpSummary> {
+    let status = vm.vm_status();
+    if !matches!(status, VMStatus::Running | VMStatus::Suspended) {
+        return ax_err!(
+            BadState,
+            format!("VM[{}] is {:?}, not running", vm.id(), status)
+        );
+    }
+
+    let quiesced = if status == VMStatus::Running {
+        suspend(vm);
+        wait_quiesced(vm)
+    } else {
+        true
+    };
+    if !quiesced {
+        warn!(
+            "VM[{}] vCPUs did not pause within {:?}, dumping anyway",
+            vm.id(),
+            PAUSE_TIMEOUT
+        );
+    }
+
+    let segments = memory_in_window(vm, None);
+    let ret = write_core(vm, out, quiesced, &segments, None, None);
+    if status == VMStatus::Running {
+        resume(vm);
+    }
+    ret
+}
+
+/// Writes a checkpoint of `vm` of `kind` to `out`, see the [module documentation](self).
+///
+/// The VM is paused like for [`dump_vm`]. An incremental checkpoint fails with `BadState` if the
+/// VM has no chain, i.e., no full checkpoint was taken since the VM started or since the last
+/// checkpoint of the previous chain.
+pub fn checkpoint_vm<W: Write>(
+    vm: &VMRef,
+    out: &mut W,
+    kind: CheckpointKind,
+) -> AxResult<CoreDumpSummary> {
+    let status = vm.vm_status();
+    if !matches!(status, VMStatus::Running | VMStatus::Suspended) {
+        return ax_err!(
+            BadState,
+            format!("VM[{}] is {:?}, not running", vm.id(), status)
+        );
+    }
+    let previous = last_checkpoint(vm.id());
+    if kind != CheckpointKind::Full && previous.is_none() {
+        return ax_err!(
+            BadState,
+            format!("VM[{}] has no full checkpoint to start from", vm.id())
+        );
+    }
+
+    let quiesced = if status == VMStatus::Running {
+        suspend(vm);
+        wait_quiesced(vm)
+    } else {
+        true
+    };
+    if !quiesced {
+        warn!(
+            "VM[{}] vCPUs did not pause within {:?}, taking the checkpoint anyway",
+            vm.id(),
+            PAUSE_TIMEOUT
+        );
+    }
+
+    let ret = write_checkpoint(vm, out, quiesced, kind, previous.unwrap_or(0));
+    if status == VMStatus::Running {
+        resume(vm);
+    }
+    ret
+}
+
+fn write_checkpoint<W: Write>(
+    vm: &VMRef,
+    out: &mut W,
+    quiesced: bool,
+    kind: CheckpointKind,
+    previous: u64,
+) -> AxResult<CoreDumpSummary> {
+    let sequence = match kind {
+        CheckpointKind::Full => 1,
+        CheckpointKind::Incremental | CheckpointKind::Last => previous + 1,
+    };
+    let ret = write_checkpoint_file(vm, out, quiesced, kind, sequence);
+
+    // A chain with a checkpoint that failed to be written can't be restored past it.
+    if ret.is_ok() && kind != CheckpointKind::Last {
+        CHECKPOINTS.lock().insert(vm.id(), sequence);
+    } else {
+        CHECKPOINTS.lock().remove(&vm.id());
+        if dirty::is_tracking(vm.id()) {
+            dirty::stop_tracking(vm)?;
+        }
+    }
+    ret
+}
+
+fn write_checkpoint_file<W: Write>(
+    vm: &VMRef,
+    out: &mut W,
+    quiesced: bool,
+    kind: CheckpointKind,
+    sequence: u64,
+) -> AxResult<CoreDumpSummary> {
+    let segments = match kind {
+        CheckpointKind::Full => {
+            // The pages dirtied from now on go in the next checkpoint.
+            if dirty::is_tracking(vm.id()) {
+                dirty::take_dirty(vm)?;
+            } else {
+                dirty::start_tracking(vm)?;
+            }
+            memory_in_window(vm, None)
+        }
+        CheckpointKind::Incremental | CheckpointKind::Last => dirty::take_dirty(vm)?
+            .into_iter()
+            .flat_map(|range| memory_in_window(vm, Some(range)))
+            .collect(),
+    };
+    let checkpoint = Checkpoint {
+        sequence,
+        guest_time_ns: guest_time::guest_time_ns(vm.id()).unwrap_or(0),
+    };
+    write_core(vm, out, quiesced, &segments, None, Some(checkpoint))
+}
+
+/// Forgets the checkpoint chain of a VM, called when the VM is destroyed.
+pub fn teardown_vm_checkpoints(vm_id: usize) {
+    CHECKPOINTS.lock().remove(&vm_id);
+}
+
+/// Writes a core dump of `vm`, suspended by [`suspend`] after `crash`, to `out`.
+///
+/// Only the guest memory within `window` (a GPA and a size) is dumped if it is given. The VM stays
+/// suspended.
+pub fn dump_crashed_vm<W: Write>(
+    vm: &VMRef,
+    out: &mut W,
+    crash: &CrashInfo,
+    window: Option<(GuestPhysAddr, usize)>,
+) -> AxResult<CoreDumpSummary> {
+    let quiesced = wait_quiesced(vm);
+    if !quiesced {
+        warn!(
+            "VM[{}] vCPUs did not stop within {:?} after the crash, dumping anyway",
+            vm.id(),
+            PAUSE_TIMEOUT
+        );
+    }
+    let segments = memory_in_window(vm, window);
+    write_core(vm, out, quiesced, &segments, Some(crash), None)
+}
+
+/// Returns the parts of the guest memory of `vm` within `window`, as GPAs and host slices.
+pub fn memory_in_window(
+    vm: &VMRef,
+    window: Option<(GuestPhysAddr, usize)>,
+) -> Vec<(GuestPhysAddr, &[u8])> {
+    let (start, end) = match window {
+        Some((gpa, size)) => (gpa.as_usize(), gpa.as_usize().saturating_add(size)),
+        None => (0, usize::MAX),
+    };
+    vm.memory_regions()
+        .iter()
+        .filter_map(|region| {
+            let lo = region.gpa.as_usize().max(start);
+            let hi = (region.gpa.as_usize() + region.size()).min(end);
+            if lo >= hi {
+                return None;
+            }
+            let offset = lo - region.gpa.as_usize();
+            // SAFETY: the region is guest RAM mapped in the hypervisor for the lifetime of the VM.
+            let mem =
+                unsafe { core::slice::from_raw_parts(region.hva.as_ptr().add(offset), hi - lo) };
+            Some((GuestPhysAddr::from(lo), mem))
+        })
+        .collect()
+}
+
+fn write_core<W: Write>(
+    vm: &VMRef,
+    out: &mut W,
+    quiesced: bool,
+    segments: &[(GuestPhysAddr, &[u8])],
+    crash: Option<&CrashInfo>,
+    checkpoint: Option<Checkpoint>,
+) -> AxResult<CoreDumpSummary> {
+    DUMPS_IN_PROGRESS.fetch_add(1, Ordering::AcqRel);
+    let ret = match seal::sealed_writer(vm.id(), &mut *out).map_err(io_err) {
+        Ok(Some(mut sealed)) => {
+            write_core_file(vm, &mut sealed, quiesced, segments, crash, checkpoint).and_then(
+                |summary| {
+                    Ok(CoreDumpSummary {
+                        file_bytes: sealed.finish().map_err(io_err)?,
+                        sealed: true,
+                        ..summary
+                    })
+                },
+            )
+        }
+        Ok(None) => write_core_file(vm, out, quiesced, segments, crash, checkpoint),
+        Err(e) => Err(e),
+    };
+    DUMPS_IN_PROGRESS.fetch_sub(1, Ordering::AcqRel);
+    ret
+}
+
+fn write_core_file<W: Write>(
+    vm: &VMRef,
+    out: &mut W,
+    quiesced: bool,
+    segments: &[(GuestPhysAddr, &[u8])],
+    crash: Option<&CrashInfo>,
+    checkpoint: Option<Checkpoint>,
+) -> AxResult<CoreDumpSummary> {
+    let notes = notes(vm, crash, checkpoint);
+    let phnum = 1 + segments.len();
+
+    let notes_offset = ELF_HEADER_SIZE + phnum * PHDR_SIZE;
+    let mut header = elf_header(phnum);
+    push_phdr(&mut header, PT_NOTE, 0, notes_offset, 0, notes.len());
+    let mut offset = notes_offset + notes.len();
+    for (gpa, mem) in segments.iter() {
+        push_phdr(
+            &mut header,
+            PT_LOAD,
+            PF_R | PF_W,
+            offset,
+            gpa.as_usize(),
+            mem.len(),
+        );
+        offset += mem.len();
+    }
+
+    out.write_all(&header).map_err(io_err)?;
+    out.write_all(&notes).map_err(io_err)?;
+    let mut memory_bytes = 0;
+    for (_, mem) in segments.iter() {
+        for chunk in mem.chunks(CHUNK_SIZE) {
+            out.write_all(chunk).map_err(io_err)?;
+        }
+        memory_bytes += mem.len();
+    }
+    out.flush().map_err(io_err)?;
+
+    info!(
+        "VM[{}] core dump written: {} bytes of memory in {} segments",
+        vm.id(),
+        memory_bytes,
+        segments.len()
+    );
+    Ok(CoreDumpSummary {
+        memory_bytes,
+        file_bytes: offset,
+        sealed: false,
+        quiesced,
+        checkpoint: checkpoint.map(|checkpoint| checkpoint.sequence),
+    })
+}
+
+/// The [`HVC_VM_DUMP`](crate::vmm::hvc::HVC_VM_DUMP) hypercall, for manager VMs.
+pub static HVC_SERVICE: HvcService = HvcService {
+    name: "core dump",
+    codes: HVC_VM_DUMP..HVC_VM_DUMP + 1,
+    permit: hvc::permit_managers,
+    deferrable: false,
+    handler: |vm, _, _, args| start_stream(vm, args).map(|_| 0),
+};
+
+/// The channels dumps are streamed to, as the ID of the manager VM and the key.
+static STREAMS: Mutex<BTreeSet<(usize, usize)>> = Mutex::new(BTreeSet::new());
+
+/// Returns the header at the start of the data of the channel of a streamed dump.
+fn stream_header(data: &mut [u8]) -> *mut DumpStreamHeader {
+    data.as_mut_ptr().cast()
+}
+
+/// Handles the [`HVC_VM_DUMP`](crate::vmm::hvc::HVC_VM_DUMP) hypercall of the manager `vm`: starts
+/// streaming the dump on its own thread.
+fn start_stream(vm: &VMRef, args: [u64; 6]) -> AxResult {
+    let target = introspect::target_vm(vm, args[0], Introspection::Read)?;
+    if target.id() == vm.id() {
+        return ax_err!(InvalidInput, "a VM can't stream its own dump");
+    }
+    let key = args[1] as usize;
+    let channel = (vm.id(), key);
+    let mut streams = STREAMS.lock();
+    if streams.contains(&channel) {
+        return ax_err!(
+            ResourceBusy,
+            format!("IVC channel {:#x} already receives a dump", key)
+        );
+    }
+    let capacity = ivc::access_raw_channel(vm.id(), key, |data| {
+        if data.len() <= DUMP_STREAM_DATA_OFFSET {
+            return 0;
+        }
+        data[..DUMP_STREAM_DATA_OFFSET].fill(0);
+        let header = DumpStreamHeader {
+            magic: DUMP_STREAM_MAGIC,
+            version: DUMP_STREAM_VERSION,
+            state: AtomicU32::new(DUMP_CHUNK_FREE),
+            error: 0,
+            offset: 0,
+            size: 0,
+        };
+        // SAFETY: the header fits in `data`, which is longer than `DUMP_STREAM_DATA_OFFSET`.
+        unsafe { stream_header(data).write_unaligned(header) };
+        data.len() - DUMP_STREAM_DATA_OFFSET
+    })?;
+    if capacity == 0 {
+        return ax_err!(InvalidInput, "IVC channel too small for a dump");
+    }
+    streams.insert(channel);
+    drop(streams);
+
+    info!(
+        "VM[{}] streams the dump of VM[{}] to channel {:#x}",
+        vm.id(),
+        target.id(),
+        key
+    );
+    let mut stream = IvcStream {
+        vm_id: vm.id(),
+        key,
+        capacity,
+        offset: 0,
+        filled: 0,
+    };
+    thread::spawn(move || {
+        let result = dump_vm(&target, &mut stream);
+        if let Err(e) = &result {
+            warn!(
+                "VM[{}] dump streamed to VM[{}] failed: {:?}",
+                target.id(),
+                stream.vm_id,
+                e
+            );
+        }
+        if let Err(e) = stream.end(result.err().map_or(0, |e| e.code())) {
+            warn!("VM[{}] dump stream not ended: {:?}", target.id(), e);
+        }
+        STREAMS.lock().remove(&(stream.vm_id, stream.key));
+    });
+    Ok(())
+}
+
+/// Writes a core file to the channel of a streamed dump, see the
+/// [module documentation](self).
+struct IvcStream {
+    vm_id: usize,
+    key: usize,
+    /// Bytes of a chunk.
+    capacity: usize,
+    /// Offset in the file of the chunk being filled.
+    offset: u64,
+    /// Bytes of the chunk filled so far.
+    filled: usize,
+}
+
+impl IvcStream {
+    /// Waits for the manager to consume the last chunk.
+    fn wait_free(&self) -> AxResult {
+        let mut waited = Duration::ZERO;
+        loop {
+            let state = ivc::access_raw_channel(self.vm_id, self.key, |data| {
+                // SAFETY: the header starts the data of the channel, see `start_stream`.
+                unsafe { (*stream_header(data)).state.load(Ordering::Acquire) }
+            })?;
+            if state != DUMP_CHUNK_READY {
+                return Ok(());
+            }
+            if waited >= DUMP_STREAM_TIMEOUT {
+                return ax_err!(
+                    TimedOut,
+                    format!("VM[{}] doesn't consume the dump", self.vm_id)
+                );
+            }
+            thread::sleep(DUMP_STREAM_POLL);
+            waited += DUMP_STREAM_POLL;
+        }
+    }
+
+    /// Publishes the header with `state` and notifies the manager.
+    fn publish(&self, state: u32, error: i32, offset: u64, size: usize) -> AxResult {
+        ivc::write_raw_channel(self.vm_id, self.key, |data| {
+            let header = stream_header(data);
+            // SAFETY: as in `wait_free`, `state` is 4-byte aligned in the page of the channel.
+            unsafe {
+                (&raw mut (*header).error).write_volatile(error);
+                (&raw mut (*header).offset).write_volatile(offset);
+                (&raw mut (*header).size).write_volatile(size as u64);
+                (*header).state.store(state, Ordering::Release);
+            }
+        })
+    }
+
+    /// Hands the chunk filled so far to the manager.
+    fn send(&mut self) -> AxResult {
+        if self.filled == 0 {
+            return Ok(());
+        }
+        self.publish(DUMP_CHUNK_READY, 0, self.offset, self.filled)?;
+        self.offset += self.filled as u64;
+        self.filled = 0;
+        Ok(())
+    }
+
+    /// Ends the stream with error code `error`, 0 if the file is complete.
+    fn end(&mut self, error: i32) -> AxResult {
+        // The end of the stream is published even if the manager didn't consume the last chunk
+        // in time.
+        let consumed = self.wait_free();
+        self.publish(DUMP_STREAM_END, error, self.offset, 0)?;
+        consumed
+    }
+}
+
+impl Write for IvcStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.filled == 0 {
+            self.wait_free()?;
+        }
+        let len = buf.len().min(self.capacity - self.filled);
+        let start = DUMP_STREAM_DATA_OFFSET + self.filled;
+        ivc::access_raw_channel(self.vm_id, self.key, |data| {
+            data.get_mut(start..start + len)
+                .map(|chunk| chunk.copy_from_slice(&buf[..len]))
+        })?
+        .ok_or_else(|| ax_err_type!(BadState, "IVC channel of the dump shrank"))?;
+        self.filled += len;
+        if self.filled == self.capacity {
+            self.send()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
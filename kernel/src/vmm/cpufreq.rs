@@ -0,0 +1,371 @@
+//! Coordination of the CPU frequency between VMs.
+//!
+//! The CPUs are shared by VMs which want different things from their frequency: a real-time VM
+//! needs its CPUs at full speed to meet its deadlines, while a best-effort VM may want to save
+//! power. A VM takes part in the coordination with a `[cpufreq]` section in its VM config:
+//!
+//! ```toml
+//! [cpufreq]
+//! # Performance level, in percent of the maximum non-turbo frequency, the CPUs of the VM never
+//! # run below. 100 by default for partitioned and high-priority VMs, 1 otherwise.
+//! min_perf = 100
+//! # Highest performance level the VM may request, 100 by default.
+//! max_perf = 100
+//! ```
+//!
+//! Partitioned VMs (see [`crate::vmm::sched`]) and VMs with a high interrupt priority (see
+//! [`crate::vmm::irqpolicy`]) take part without a `[cpufreq]` section, with the defaults.
+//!
+//! A guest requests a level for its CPUs:
+//!
+//! - with the [`HVC_CPUFREQ_REQUEST`] hypercall, `args[0]` being the level in percent or
+//!   [`CPUFREQ_DEFAULT`] to drop its request. It returns the level granted to the VM;
+//! - on x86_64, by writing the target ratio to `IA32_PERF_CTL` as its SpeedStep driver does, the
+//!   ratio being scaled to a level between the minimum and maximum ratios of the host CPU.
+//!
+//! The demand of a VM is its request clamped to `[min_perf, max_perf]`, `min_perf` without a
+//! request. The target level of a physical CPU is the highest demand of the VMs whose vCPUs may run
+//! on it, so a best-effort VM sharing a CPU with a real-time VM can't lower the frequency
+//! underneath it, and the maximum level for the CPUs no such VM runs on. The targets are computed
+//! again when a demand changes and when a VM is created or destroyed, from the CPU masks of the
+//! vCPUs at that time (see [`crate::vmm::affinity`]); the CPUs whose target changed are
+//! interrupted to apply it. A vCPU entering the guest also raises its CPU to the demand of its VM,
+//! which covers the vCPUs moved since.
+//!
+//! The level is applied with [`set_cpu_perf`]: on x86_64 through `IA32_PERF_CTL` if the CPU has
+//! Enhanced Intel SpeedStep, between its minimum and maximum non-turbo ratios. On aarch64 and
+//! riscv64 the frequency belongs to the firmware and the levels are only computed and reported.
+//! The idle states of the CPUs aren't coordinated, an idle CPU halts with WFI or HLT.
+//!
+//! [`HVC_CPUFREQ_REQUEST`]: crate::vmm::hvc::HVC_CPUFREQ_REQUEST
+//! [`set_cpu_perf`]: crate::hal::arch::set_cpu_perf
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU8, AtomicU64, AtomicUsize, Ordering};
+
+use std::os::arceos::modules::axhal;
+
+use axerrno::{AxResult, ax_err};
+use spin::{Mutex, Once};
+
+use crate::hal::arch;
+use crate::vmm::hvc::{self, HVC_CPUFREQ_REQUEST, HvcService};
+use crate::vmm::{VMRef, affinity, irqpolicy, sched};
+
+/// `args[0]` of [`crate::vmm::hvc::HVC_CPUFREQ_REQUEST`] dropping the request of the VM.
+pub const CPUFREQ_DEFAULT: u64 = 0;
+/// Highest performance level, the maximum non-turbo frequency.
+pub const CPUFREQ_MAX: u64 = 100;
+
+struct VmFreq {
+    min_perf: u8,
+    max_perf: u8,
+    /// Level requested by the guest, [`CPUFREQ_DEFAULT`] if none.
+    request: AtomicU8,
+    /// Request clamped to `[min_perf, max_perf]`.
+    demand: AtomicU8,
+    /// Physical CPUs the vCPUs of the VM may run on, when the targets were last computed.
+    cpus: AtomicUsize,
+    requests: AtomicU64,
+    /// Last value written to `IA32_PERF_CTL` by the guest.
+    perf_ctl: AtomicU64,
+}
+
+impl VmFreq {
+    fn demand_of(&self, request: u8) -> u8 {
+        if request as u64 == CPUFREQ_DEFAULT {
+            self.min_perf
+        } else {
+            request.clamp(self.min_perf, self.max_perf)
+        }
+    }
+}
+
+/// Performance level of a physical CPU.
+struct CpuFreq {
+    target: AtomicU8,
+    /// Level last applied, 0 if none yet.
+    applied: AtomicU8,
+    changes: AtomicU64,
+}
+
+impl CpuFreq {
+    /// Applies `level` to the current CPU, which must be this one.
+    fn apply(&self, level: u8) {
+        if self.applied.swap(level, Ordering::AcqRel) != level {
+            arch::set_cpu_perf(level);
+            self.changes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+static VMS: Mutex<BTreeMap<usize, Arc<VmFreq>>> = Mutex::new(BTreeMap::new());
+static CPUS: Once<Vec<CpuFreq>> = Once::new();
+
+/// Performance level of a VM, see [`vm_freq_stats`].
+#[derive(Debug, Clone, Copy)]
+pub struct VmFreqStats {
+    pub min_perf: u8,
+    pub max_perf: u8,
+    /// Level requested by the guest, `None` if none.
+    pub request: Option<u8>,
+    pub demand: u8,
+    pub requests: u64,
+}
+
+/// Performance level of a physical CPU, see [`cpu_freq_stats`].
+#[derive(Debug, Clone, Copy)]
+pub struct CpuFreqStats {
+    pub cpu_id: usize,
+    pub target: u8,
+    /// Level last applied, `None` if none yet.
+    pub applied: Option<u8>,
+    /// Times the level of the CPU changed.
+    pub changes: u64,
+}
+
+pub static HVC_SERVICE: HvcService = HvcService {
+    name: "cpufreq",
+    codes: HVC_CPUFREQ_REQUEST..HVC_CPUFREQ_REQUEST + 1,
+    permit: hvc::permit_all,
+    deferrable: false,
+    handler: |vm, _, _, args| request(vm, args[0]).map(|level| level as usize),
+};
+
+fn perf_field(cfg: &toml::Table, key: &str, default: u8) -> AxResult<u8> {
+    match cfg.get(key) {
+        None => Ok(default),
+        Some(v) => match v.as_integer() {
+            Some(level) if (1..=CPUFREQ_MAX as i64).contains(&level) => Ok(level as u8),
+            _ => ax_err!(
+                InvalidInput,
+                format!("cpufreq config: `{}` must be from 1 to {}", key, CPUFREQ_MAX)
+            ),
+        },
+    }
+}
+
+/// Reads the `[cpufreq]` section of `raw_cfg`, called when the VM is created, once its scheduling
+/// mode and interrupt policy are known.
+pub fn setup_vm_cpufreq(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let real_time = sched::is_partitioned(vm.id()) || irqpolicy::is_high_priority(vm.id());
+    let defaults = toml::Table::new();
+    let cfg = match raw_cfg.get("cpufreq").and_then(|v| v.as_table()) {
+        Some(cfg) => cfg,
+        None if real_time => &defaults,
+        None => return Ok(()),
+    };
+    let default_min = if real_time { CPUFREQ_MAX as u8 } else { 1 };
+    let min_perf = perf_field(cfg, "min_perf", default_min)?;
+    let max_perf = perf_field(cfg, "max_perf", CPUFREQ_MAX as u8)?;
+    if min_perf > max_perf {
+        return ax_err!(
+            InvalidInput,
+            "cpufreq config: `min_perf` is above `max_perf`"
+        );
+    }
+
+    CPUS.call_once(|| {
+        (0..axruntime::cpu_count())
+            .map(|_| CpuFreq {
+                target: AtomicU8::new(CPUFREQ_MAX as u8),
+                applied: AtomicU8::new(0),
+                changes: AtomicU64::new(0),
+            })
+            .collect()
+    });
+    VMS.lock().insert(
+        vm.id(),
+        Arc::new(VmFreq {
+            min_perf,
+            max_perf,
+            request: AtomicU8::new(CPUFREQ_DEFAULT as u8),
+            demand: AtomicU8::new(min_perf),
+            cpus: AtomicUsize::new(vm_cpus(vm)),
+            requests: AtomicU64::new(0),
+            perf_ctl: AtomicU64::new(0),
+        }),
+    );
+    retarget();
+    info!(
+        "VM[{}] CPU performance from {}% to {}%",
+        vm.id(),
+        min_perf,
+        max_perf
+    );
+    Ok(())
+}
+
+/// Forgets the performance level of a VM, called when the VM is destroyed. Its CPUs fall back to
+/// the demands of the other VMs.
+pub fn teardown_vm_cpufreq(vm_id: usize) {
+    if VMS.lock().remove(&vm_id).is_some() {
+        retarget();
+    }
+}
+
+fn vm_freq(vm_id: usize) -> Option<Arc<VmFreq>> {
+    VMS.lock().get(&vm_id).cloned()
+}
+
+/// Returns the physical CPUs the vCPUs of `vm` may run on.
+fn vm_cpus(vm: &VMRef) -> usize {
+    vm.vcpu_list()
+        .iter()
+        .fold(0, |cpus, vcpu| cpus | affinity::vcpu_mask(vm, vcpu))
+}
+
+/// Applies the target of the current CPU, run on the CPUs whose target changed.
+fn apply_target() {
+    let cpu_id = axhal::percpu::this_cpu_id();
+    if let Some(cpu) = CPUS.get().and_then(|cpus| cpus.get(cpu_id)) {
+        cpu.apply(cpu.target.load(Ordering::Acquire));
+    }
+}
+
+/// Computes the targets of the CPUs from the demands of the VMs and interrupts the CPUs whose
+/// target changed to apply it.
+fn retarget() {
+    let Some(cpus) = CPUS.get() else {
+        return;
+    };
+    let mut targets: Vec<u8> = cpus.iter().map(|_| 0).collect();
+    for vm_freq in VMS.lock().values() {
+        let demand = vm_freq.demand.load(Ordering::Acquire);
+        let mask = vm_freq.cpus.load(Ordering::Relaxed);
+        for (cpu_id, target) in targets.iter_mut().enumerate().take(usize::BITS as usize) {
+            if mask & (1 << cpu_id) != 0 {
+                *target = (*target).max(demand);
+            }
+        }
+    }
+
+    let _guard = kernel_guard::NoPreempt::new();
+    let this_cpu = axhal::percpu::this_cpu_id();
+    for (cpu_id, (cpu, target)) in cpus.iter().zip(targets).enumerate() {
+        let target = if target == 0 { CPUFREQ_MAX as u8 } else { target };
+        if cpu.target.swap(target, Ordering::AcqRel) == target {
+            continue;
+        }
+        if cpu_id == this_cpu {
+            cpu.apply(target);
+        } else {
+            axipi::send_ipi_event_to_one(cpu_id, apply_target);
+        }
+    }
+}
+
+/// Requests performance level `level` for the CPUs of `vm`, see [`HVC_CPUFREQ_REQUEST`]. Returns
+/// the level granted to the VM, its CPUs may run faster for the other VMs sharing them.
+///
+/// [`HVC_CPUFREQ_REQUEST`]: crate::vmm::hvc::HVC_CPUFREQ_REQUEST
+pub fn request(vm: &VMRef, level: u64) -> AxResult<u8> {
+    let Some(vm_freq) = vm_freq(vm.id()) else {
+        return ax_err!(Unsupported, "VM has no `[cpufreq]` section");
+    };
+    if level > CPUFREQ_MAX {
+        return ax_err!(InvalidInput, "performance level above 100%");
+    }
+    let demand = vm_freq.demand_of(level as u8);
+    vm_freq.request.store(level as u8, Ordering::Relaxed);
+    vm_freq.requests.fetch_add(1, Ordering::Relaxed);
+    let cpus = vm_cpus(vm);
+    let moved = vm_freq.cpus.swap(cpus, Ordering::Relaxed) != cpus;
+    if vm_freq.demand.swap(demand, Ordering::AcqRel) != demand || moved {
+        debug!("VM[{}] requests {}%, granted {}%", vm.id(), level, demand);
+        retarget();
+    }
+    Ok(demand)
+}
+
+/// Handles a write of `vm` to MSR `msr` if it's `IA32_PERF_CTL` and the VM has a performance
+/// level, `None` otherwise.
+#[cfg(target_arch = "x86_64")]
+pub fn handle_msr_write(vm: &VMRef, msr: usize, value: u64) -> Option<AxResult> {
+    if msr != arch::MSR_IA32_PERF_CTL as usize {
+        return None;
+    }
+    let vm_freq = vm_freq(vm.id())?;
+    vm_freq.perf_ctl.store(value, Ordering::Relaxed);
+    // The level is only recorded if the host CPU has no ratios to scale to.
+    let Some((min, max)) = arch::perf_ratios() else {
+        return Some(Ok(()));
+    };
+    let ratio = ((value >> 8) & 0xff) as u32;
+    let level = if max == min {
+        CPUFREQ_MAX
+    } else {
+        (ratio.clamp(min, max) - min) as u64 * CPUFREQ_MAX / (max - min) as u64
+    };
+    Some(request(vm, level.max(1)).map(|_| ()))
+}
+
+/// Handles a read of VM `vm_id` from MSR `msr` if it's `IA32_PERF_CTL` and the VM has a
+/// performance level, returning the value last written by the guest, `None` otherwise.
+#[cfg(target_arch = "x86_64")]
+pub fn handle_msr_read(vm_id: usize, msr: usize) -> Option<AxResult<u64>> {
+    if msr != arch::MSR_IA32_PERF_CTL as usize {
+        return None;
+    }
+    Some(Ok(vm_freq(vm_id)?.perf_ctl.load(Ordering::Relaxed)))
+}
+
+/// Performance level of the VM of a vCPU, applied to its CPU when it enters the guest.
+pub struct VCpuFreq {
+    vm_freq: Arc<VmFreq>,
+}
+
+impl VCpuFreq {
+    /// Returns `None` if VM `vm_id` has no performance level.
+    pub fn new(vm_id: usize) -> Option<Self> {
+        Some(Self {
+            vm_freq: vm_freq(vm_id)?,
+        })
+    }
+
+    /// Raises the level of the current CPU to the demand of the VM if it's lower, called right
+    /// before the vCPU enters the guest.
+    pub fn enter(&self) {
+        let Some(cpus) = CPUS.get() else {
+            return;
+        };
+        let _guard = kernel_guard::NoPreempt::new();
+        if let Some(cpu) = cpus.get(axhal::percpu::this_cpu_id()) {
+            let demand = self.vm_freq.demand.load(Ordering::Acquire);
+            cpu.apply(cpu.target.load(Ordering::Acquire).max(demand));
+        }
+    }
+}
+
+/// Returns the performance level of VM `vm_id`, `None` if it has none.
+pub fn vm_freq_stats(vm_id: usize) -> Option<VmFreqStats> {
+    let vm_freq = vm_freq(vm_id)?;
+    let request = vm_freq.request.load(Ordering::Relaxed);
+    Some(VmFreqStats {
+        min_perf: vm_freq.min_perf,
+        max_perf: vm_freq.max_perf,
+        request: (request as u64 != CPUFREQ_DEFAULT).then_some(request),
+        demand: vm_freq.demand.load(Ordering::Relaxed),
+        requests: vm_freq.requests.load(Ordering::Relaxed),
+    })
+}
+
+/// Returns the performance levels of the physical CPUs, empty if no VM has one.
+pub fn cpu_freq_stats() -> Vec<CpuFreqStats> {
+    let Some(cpus) = CPUS.get() else {
+        return Vec::new();
+    };
+    cpus.iter()
+        .enumerate()
+        .map(|(cpu_id, cpu)| {
+            let applied = cpu.applied.load(Ordering::Relaxed);
+            CpuFreqStats {
+                cpu_id,
+                target: cpu.target.load(Ordering::Relaxed),
+                applied: (applied != 0).then_some(applied),
+                changes: cpu.changes.load(Ordering::Relaxed),
+            }
+        })
+        .collect()
+}
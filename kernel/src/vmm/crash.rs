@@ -0,0 +1,443 @@
+//! Crash detection and crash policies of guests.
+//!
+//! A vCPU crashes when the hardware refuses to enter the guest (an invalid guest state, the
+//! equivalent of a triple fault) or when the vCPU backend gives up on an exit it can't handle (an
+//! unhandled synchronous abort). Without a `[crash]` section the VM is stopped. With one, the VM
+//! is suspended, a dump is captured and the policy applies:
+//!
+//! ```toml
+//! [crash]
+//! # "halt" (the default) stops the VM, "restart" restarts it, "notify" leaves it suspended for
+//! # inspection (`vm dump`, `vm resume`, `vm stop`) and interrupts the manager VM.
+//! action = "notify"
+//! # Peer handle of the manager VM (see `crate::vmm::peers`) and the interrupt raised in it once
+//! # the dump is captured, whatever the action. Required by "notify" and `dump_ivc`.
+//! manager = 0
+//! manager_irq = 0x32
+//! # Window of guest memory captured in the dump, all of it by default.
+//! dump_gpa = 0x8000_0000
+//! dump_size = 0x10_0000
+//! # Core file written with the `fs` feature, see `crate::vmm::coredump`.
+//! dump_file = "/crash/vm1.core"
+//! # Or key of a raw IVC channel published by the manager VM, see below.
+//! dump_ivc = 0x20
+//! ```
+//!
+//! A dump to an IVC channel is a [`CrashRecord`] at the start of the data of the channel, followed
+//! by as much of the memory window as fits. Both kinds of dumps hold the registers the crashed vCPU
+//! had when it exited (see [`crate::vmm::regs`]), the state tracked by the hypervisor and the cause
+//! of the crash. In a core file, the registers of the crashed vCPU are in the first `NT_PRSTATUS`
+//! note, with the signal of [`CrashReason::signal`], so that debuggers show it as the thread that
+//! crashed.
+//!
+//! Only the first crash of a VM is handled until it is resumed or boots again.
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time::Duration;
+use std::os::arceos::modules::axhal;
+use std::thread;
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+use spin::Mutex;
+
+use crate::vmm::irq::IrqLine;
+use crate::vmm::lifecycle::{self, LifecycleEvent};
+use crate::vmm::regs::{self, MAX_GUEST_REGS, RegsSnapshot};
+use crate::vmm::{VMRef, coredump, ivc, peers, vcpus, watchdog};
+
+/// `magic` of [`CrashRecord`].
+pub const CRASH_RECORD_MAGIC: u32 = u32::from_le_bytes(*b"AXCR");
+/// Version of the layout of [`CrashRecord`].
+pub const CRASH_RECORD_VERSION: u32 = 2;
+
+const MAX_EVENTS: usize = 16;
+
+/// Why a vCPU crashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum CrashReason {
+    /// The hardware refused to enter the guest.
+    FailEntry = 1,
+    /// The vCPU backend failed to handle an exit.
+    RunError = 2,
+}
+
+impl CrashReason {
+    /// The signal a Linux process would have been killed with, in the `NT_PRSTATUS` note of the
+    /// crashed vCPU: `SIGILL` for a guest state the hardware refuses, `SIGSEGV` otherwise, as the
+    /// exits the backend can't handle are aborts.
+    pub fn signal(self) -> u32 {
+        match self {
+            Self::FailEntry => 4,
+            Self::RunError => 11,
+        }
+    }
+}
+
+/// A crash of a vCPU.
+#[derive(Debug, Clone)]
+pub struct CrashInfo {
+    pub vm_id: usize,
+    pub vcpu_id: usize,
+    pub reason: CrashReason,
+    /// Host time of the crash in nanoseconds.
+    pub time_ns: u64,
+    /// Description of the crash by the vCPU backend.
+    pub detail: String,
+    /// The registers of the vCPU when it crashed, `None` if the backend doesn't expose them.
+    pub regs: Option<RegsSnapshot>,
+}
+
+/// The crash record written to the IVC channel of a crash dump, followed by the memory window.
+#[repr(C)]
+pub struct CrashRecord {
+    pub magic: u32,
+    pub version: u32,
+    /// Number of crashes of the VM so far, to tell a new record from the previous one.
+    pub sequence: u64,
+    pub vm_id: u64,
+    pub vcpu_id: u32,
+    /// [`CrashReason`].
+    pub reason: u32,
+    pub time_ns: u64,
+    /// GPA of the memory window.
+    pub window_gpa: u64,
+    /// Bytes of the window following the record, truncated to the channel.
+    pub window_size: u64,
+    /// Registers in `regs`, 0 if the vCPU backend doesn't expose them.
+    pub reg_count: u32,
+    pub _reserved: u32,
+    /// The registers of the crashed vCPU, in the order of the `user_regs_struct` of the ELF core
+    /// files of the architecture (see [`crate::hal::arch::GuestRegs`]).
+    pub regs: [u64; MAX_GUEST_REGS],
+}
+
+/// What to do with a crashed VM.
+#[derive(Debug, Clone, Copy)]
+enum CrashAction {
+    Halt,
+    Restart,
+    Notify,
+}
+
+/// Where the dump of a crashed VM goes.
+#[derive(Debug, Clone)]
+enum CrashDump {
+    None,
+    #[cfg(feature = "fs")]
+    File(String),
+    Ivc(usize),
+}
+
+#[derive(Debug, Clone)]
+struct CrashPolicy {
+    action: CrashAction,
+    /// Peer handle of the manager VM and its interrupt.
+    manager: Option<(usize, usize)>,
+    window: Option<(GuestPhysAddr, usize)>,
+    dump: CrashDump,
+}
+
+struct VmCrash {
+    policy: CrashPolicy,
+    crashes: u64,
+    /// Whether a crash is being handled or waits for the VM to be resumed.
+    handling: bool,
+}
+
+/// Crash policies of the VMs with a `[crash]` section.
+static CRASH: Mutex<BTreeMap<usize, VmCrash>> = Mutex::new(BTreeMap::new());
+
+/// A handled crash.
+#[derive(Debug, Clone)]
+pub struct CrashEvent {
+    pub info: CrashInfo,
+    /// Bytes of guest memory dumped, `None` if the dump failed or there was none.
+    pub dumped: Option<usize>,
+    pub action: &'static str,
+}
+
+static EVENTS: Mutex<VecDeque<CrashEvent>> = Mutex::new(VecDeque::new());
+
+/// Records the crash policy described in the `[crash]` section of `raw_cfg` for the VM.
+///
+/// Does nothing if the VM config has no `[crash]` section.
+pub fn setup_vm_crash(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("crash").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let get = |key: &str| {
+        cfg.get(key)
+            .and_then(|v| v.as_integer())
+            .map(|v| v as usize)
+    };
+
+    let manager = match (get("manager"), get("manager_irq")) {
+        (Some(manager), Some(irq)) => Some((manager, irq)),
+        (None, None) => None,
+        _ => {
+            return ax_err!(
+                InvalidInput,
+                "crash config: `manager` and `manager_irq` go together"
+            );
+        }
+    };
+    let action = match cfg.get("action").and_then(|v| v.as_str()).unwrap_or("halt") {
+        "halt" => CrashAction::Halt,
+        "restart" => CrashAction::Restart,
+        "notify" if manager.is_some() => CrashAction::Notify,
+        "notify" => return ax_err!(InvalidInput, "crash config: \"notify\" needs a `manager`"),
+        action => {
+            return ax_err!(
+                InvalidInput,
+                format!("crash config: unsupported action `{}`", action)
+            );
+        }
+    };
+    let window = match (get("dump_gpa"), get("dump_size")) {
+        (Some(gpa), Some(size)) if size > 0 => Some((GuestPhysAddr::from(gpa), size)),
+        (None, None) => None,
+        _ => {
+            return ax_err!(
+                InvalidInput,
+                "crash config: `dump_gpa` and a non-zero `dump_size` go together"
+            );
+        }
+    };
+    let dump = match (cfg.get("dump_file"), get("dump_ivc")) {
+        (None, None) => CrashDump::None,
+        #[cfg(feature = "fs")]
+        (Some(path), None) => CrashDump::File(
+            path.as_str()
+                .ok_or_else(|| {
+                    axerrno::ax_err_type!(
+                        InvalidInput,
+                        "crash config: `dump_file` must be a string"
+                    )
+                })?
+                .into(),
+        ),
+        #[cfg(not(feature = "fs"))]
+        (Some(_), None) => {
+            return ax_err!(
+                Unsupported,
+                "crash config: `dump_file` needs the `fs` feature"
+            );
+        }
+        (None, Some(_)) if manager.is_none() => {
+            return ax_err!(InvalidInput, "crash config: `dump_ivc` needs a `manager`");
+        }
+        (None, Some(key)) => CrashDump::Ivc(key),
+        (Some(_), Some(_)) => {
+            return ax_err!(
+                InvalidInput,
+                "crash config: `dump_file` and `dump_ivc` are exclusive"
+            );
+        }
+    };
+
+    let policy = CrashPolicy {
+        action,
+        manager,
+        window,
+        dump,
+    };
+    info!("VM[{}] crash policy: {:?}", vm.id(), policy);
+    CRASH.lock().insert(
+        vm.id(),
+        VmCrash {
+            policy,
+            crashes: 0,
+            handling: false,
+        },
+    );
+    Ok(())
+}
+
+/// Forgets the crash policy of a VM, called when the VM is destroyed.
+pub fn teardown_vm_crash(vm_id: usize) {
+    CRASH.lock().remove(&vm_id);
+}
+
+/// Handles the crash of vCPU `vcpu_id` of `vm`, called by its vCPU task.
+///
+/// Returns false if the VM has no crash policy, in which case the caller stops the VM. Otherwise
+/// the VM is suspended, so that the vCPU task waits instead of entering the guest again, and the
+/// dump and the policy run on their own thread.
+pub fn on_crash(vm: &VMRef, vcpu_id: usize, reason: CrashReason, detail: String) -> bool {
+    lifecycle::emit(vm.id(), LifecycleEvent::Crashed, reason as u32);
+    let info = CrashInfo {
+        vm_id: vm.id(),
+        vcpu_id,
+        reason,
+        time_ns: axhal::time::monotonic_time_nanos(),
+        detail,
+        // The vCPU task just took the exit the vCPU crashed on, the snapshot is taken at once.
+        regs: regs::capture(vm, vcpu_id, Duration::ZERO),
+    };
+    let (policy, sequence) = {
+        let mut crash = CRASH.lock();
+        let Some(vm_crash) = crash.get_mut(&info.vm_id) else {
+            return false;
+        };
+        if vm_crash.handling {
+            warn!(
+                "VM[{}] VCpu[{}] crashed while a crash is being handled: {}",
+                info.vm_id, vcpu_id, info.detail
+            );
+            return true;
+        }
+        vm_crash.handling = true;
+        vm_crash.crashes += 1;
+        (vm_crash.policy.clone(), vm_crash.crashes)
+    };
+
+    error!(
+        "VM[{}] VCpu[{}] crashed ({:?}): {}, action {:?}",
+        info.vm_id, vcpu_id, reason, info.detail, policy.action
+    );
+    coredump::suspend(vm);
+    let vm = vm.clone();
+    thread::spawn(move || handle_crash(vm, info, policy, sequence));
+    true
+}
+
+/// Allows the next crash of VM `vm_id` to be handled, called when it boots or is resumed.
+pub fn rearm(vm_id: usize) {
+    if let Some(vm_crash) = CRASH.lock().get_mut(&vm_id) {
+        vm_crash.handling = false;
+    }
+}
+
+fn handle_crash(vm: VMRef, info: CrashInfo, policy: CrashPolicy, sequence: u64) {
+    let vm_id = info.vm_id;
+    let dumped = match dump(&vm, &info, &policy, sequence) {
+        Ok(dumped) => dumped,
+        Err(e) => {
+            warn!("VM[{}] crash dump failed: {:?}", vm_id, e);
+            None
+        }
+    };
+
+    if let Some((manager, irq)) = policy.manager {
+        match peers::resolve(vm_id, manager) {
+            Ok(manager_vm_id) => IrqLine::new(manager_vm_id, irq).raise(),
+            Err(e) => warn!("VM[{}] crash: manager not found: {:?}", vm_id, e),
+        }
+    }
+    let action = match policy.action {
+        CrashAction::Halt => {
+            if let Err(e) = vm.shutdown() {
+                error!("VM[{}] failed to stop after a crash: {:?}", vm_id, e);
+            }
+            vcpus::notify_all_vcpus(vm_id);
+            "halted"
+        }
+        CrashAction::Restart => {
+            watchdog::reset_vm(vm_id);
+            "restarted"
+        }
+        CrashAction::Notify => "suspended",
+    };
+
+    let mut events = EVENTS.lock();
+    if events.len() == MAX_EVENTS {
+        events.pop_front();
+    }
+    events.push_back(CrashEvent {
+        info,
+        dumped,
+        action,
+    });
+}
+
+/// Captures the dump of a crash, returns the bytes of guest memory dumped.
+fn dump(
+    vm: &VMRef,
+    info: &CrashInfo,
+    policy: &CrashPolicy,
+    sequence: u64,
+) -> AxResult<Option<usize>> {
+    match &policy.dump {
+        CrashDump::None => Ok(None),
+        #[cfg(feature = "fs")]
+        CrashDump::File(path) => {
+            let mut file = std::fs::File::create(path.as_str()).map_err(|e| {
+                axerrno::ax_err_type!(Io, format!("failed to create {}: {:?}", path, e))
+            })?;
+            let summary = coredump::dump_crashed_vm(vm, &mut file, info, policy.window)?;
+            Ok(Some(summary.memory_bytes))
+        }
+        CrashDump::Ivc(key) => {
+            // Checked by `setup_vm_crash`.
+            let (manager, _) = policy.manager.unwrap();
+            let manager_vm_id = peers::resolve(info.vm_id, manager)?;
+            let memory = coredump::memory_in_window(vm, policy.window);
+            let window_gpa = memory.first().map_or(0, |(gpa, _)| gpa.as_usize());
+            let written = ivc::write_raw_channel(manager_vm_id, *key, |data| {
+                write_record(data, info, sequence, window_gpa, &memory)
+            })?;
+            Ok(Some(written))
+        }
+    }
+}
+
+/// Writes the [`CrashRecord`] and the memory window to `data`, returns the bytes of the window
+/// written.
+fn write_record(
+    data: &mut [u8],
+    info: &CrashInfo,
+    sequence: u64,
+    window_gpa: usize,
+    memory: &[(GuestPhysAddr, &[u8])],
+) -> usize {
+    let record_size = core::mem::size_of::<CrashRecord>();
+    if data.len() < record_size {
+        return 0;
+    }
+    let (head, window) = data.split_at_mut(record_size);
+    window.fill(0);
+    let mut window_size = 0;
+    for (gpa, mem) in memory {
+        let offset = gpa.as_usize() - window_gpa;
+        if offset >= window.len() {
+            break;
+        }
+        let len = mem.len().min(window.len() - offset);
+        window[offset..offset + len].copy_from_slice(&mem[..len]);
+        window_size = offset + len;
+    }
+
+    let mut record = CrashRecord {
+        magic: CRASH_RECORD_MAGIC,
+        version: CRASH_RECORD_VERSION,
+        sequence,
+        vm_id: info.vm_id as u64,
+        vcpu_id: info.vcpu_id as u32,
+        reason: info.reason as u32,
+        time_ns: info.time_ns,
+        window_gpa: window_gpa as u64,
+        window_size: window_size as u64,
+        reg_count: 0,
+        _reserved: 0,
+        regs: [0; MAX_GUEST_REGS],
+    };
+    if let Some(snapshot) = &info.regs {
+        record.reg_count = snapshot.regs.len() as u32;
+        record.regs[..snapshot.regs.len()].copy_from_slice(&snapshot.regs);
+    }
+    // SAFETY: `head` is exactly the size of the record, which has no padding.
+    unsafe {
+        head.as_mut_ptr()
+            .cast::<CrashRecord>()
+            .write_unaligned(record)
+    };
+    window_size
+}
+
+/// Returns the crashes handled so far, oldest first.
+pub fn events() -> Vec<CrashEvent> {
+    EVENTS.lock().iter().cloned().collect()
+}
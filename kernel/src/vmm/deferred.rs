@@ -0,0 +1,186 @@
+//! Deferred completion of long-running hypercalls.
+//!
+//! Some hypercalls take long enough that blocking the calling vCPU for them would stall its
+//! guest, e.g. defining a VM, which loads its images. A guest calls those asynchronously with the
+//! [`HVC_CALL_ASYNC`] hypercall: `args[0]` is the number of the hypercall, `args[1]` the vector
+//! raised in the caller when it completes (0 for none) and `args[2..6]` its first four arguments.
+//! The hypercall is checked as if it was called directly, queued, and a token returned
+//! immediately. The work runs on a worker task of the hypervisor, outside of any hypercall of the
+//! caller (see [`MAX_HYPERCALL_DEPTH`]), and its result is kept until the guest fetches it with
+//! the [`HVC_GET_RESULT`] hypercall: `args[0]` is the token and `args[1]` the GPA of an
+//! [`AsyncResult`] written with the state of the call. A result fetched once is gone.
+//!
+//! Only the hypercall services registered as deferrable (see [`HvcService::deferrable`]) may be
+//! called asynchronously, and a VM has at most [`MAX_PENDING_CALLS`] calls whose results it has
+//! not fetched yet.
+//!
+//! [`HVC_CALL_ASYNC`]: crate::vmm::hvc::HVC_CALL_ASYNC
+//! [`HVC_GET_RESULT`]: crate::vmm::hvc::HVC_GET_RESULT
+//! [`MAX_HYPERCALL_DEPTH`]: crate::vmm::hvc::MAX_HYPERCALL_DEPTH
+//! [`HvcService::deferrable`]: crate::vmm::hvc::HvcService::deferrable
+use alloc::collections::BTreeMap;
+use std::thread;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use spin::Mutex;
+
+use crate::vmm::hvc::{self, HVC_CALL_ASYNC, HVC_GET_RESULT, HvcService};
+use crate::vmm::irq::IrqLine;
+use crate::vmm::{VMRef, vm_list};
+
+/// Calls of a VM whose results were not fetched yet, at most.
+pub const MAX_PENDING_CALLS: usize = 16;
+
+/// States of a call, in `AsyncResult::state`.
+pub const ASYNC_PENDING: u32 = 0;
+pub const ASYNC_DONE: u32 = 1;
+pub const ASYNC_FAILED: u32 = 2;
+
+/// State of an asynchronous call, as written by [`HVC_GET_RESULT`](crate::vmm::hvc::HVC_GET_RESULT).
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct AsyncResult {
+    /// [`ASYNC_PENDING`], [`ASYNC_DONE`] or [`ASYNC_FAILED`].
+    pub state: u32,
+    /// Error code of a failed call.
+    pub error: i32,
+    /// Return value of a completed call.
+    pub value: u64,
+}
+
+/// The [`HVC_CALL_ASYNC`] and [`HVC_GET_RESULT`] hypercalls.
+///
+/// [`HVC_CALL_ASYNC`]: crate::vmm::hvc::HVC_CALL_ASYNC
+/// [`HVC_GET_RESULT`]: crate::vmm::hvc::HVC_GET_RESULT
+pub static HVC_SERVICE: HvcService = HvcService {
+    name: "deferred completion",
+    codes: HVC_CALL_ASYNC..HVC_GET_RESULT + 1,
+    permit: hvc::permit_all,
+    deferrable: false,
+    handler: |vm, vcpu_id, nr, args| match nr {
+        HVC_CALL_ASYNC => submit(vm, vcpu_id, args).map(|token| token as usize),
+        _ => get_result(vm, args).map(|state| state as usize),
+    },
+};
+
+#[derive(Default)]
+struct VmCalls {
+    /// The last token given out, tokens start at 1 and are never reused.
+    last: u64,
+    /// Results of the calls, `None` while they run.
+    calls: BTreeMap<u64, Option<AxResult<usize>>>,
+}
+
+/// Calls of the VMs, indexed by VM ID.
+static CALLS: Mutex<BTreeMap<usize, VmCalls>> = Mutex::new(BTreeMap::new());
+
+/// Queues hypercall `args[0]` of vCPU `vcpu_id` of `vm`, returns its token.
+fn submit(vm: &VMRef, vcpu_id: usize, args: [u64; 6]) -> AxResult<u64> {
+    let nr = args[0];
+    let vector = args[1] as usize;
+    let service = hvc::service_of(nr)
+        .filter(|service| service.deferrable)
+        .ok_or_else(|| {
+            ax_err_type!(
+                Unsupported,
+                format!("hypercall {:#x} cannot be called asynchronously", nr)
+            )
+        })?;
+    (service.permit)(vm)?;
+    let call_args = [args[2], args[3], args[4], args[5], 0, 0];
+
+    let vm_id = vm.id();
+    let token = {
+        let mut calls = CALLS.lock();
+        let vm_calls = calls.entry(vm_id).or_default();
+        if vm_calls.calls.len() >= MAX_PENDING_CALLS {
+            return ax_err!(
+                ResourceBusy,
+                format!("VM[{}] has {} pending calls", vm_id, MAX_PENDING_CALLS)
+            );
+        }
+        vm_calls.last += 1;
+        vm_calls.calls.insert(vm_calls.last, None);
+        vm_calls.last
+    };
+    debug!(
+        "VM[{}] {} hypercall {:#x} deferred, token {}",
+        vm_id, service.name, nr, token
+    );
+
+    let vm = vm.clone();
+    thread::spawn(move || {
+        let result = (service.handler)(&vm, vcpu_id, nr, call_args);
+        if let Err(e) = &result {
+            warn!(
+                "VM[{}] deferred {} hypercall {:#x} failed: {:?}",
+                vm_id, service.name, nr, e
+            );
+        }
+        drop(vm);
+        // The VM may be gone, its calls with it.
+        let completed = match CALLS.lock().get_mut(&vm_id) {
+            Some(vm_calls) => vm_calls
+                .calls
+                .get_mut(&token)
+                .map(|call| *call = Some(result))
+                .is_some(),
+            None => false,
+        };
+        if completed && vector != 0 && vm_list::get_vm_by_id(vm_id).is_some() {
+            IrqLine::new(vm_id, vector).raise();
+        }
+    });
+    Ok(token)
+}
+
+/// Writes the state of the call `args[0]` of `vm` to the guest at `args[1]`, forgetting the call
+/// if it is over. Returns the state.
+fn get_result(vm: &VMRef, args: [u64; 6]) -> AxResult<u32> {
+    let token = args[0];
+    let buf = hvc::guest_ptr::<AsyncResult>(vm, args[1])?;
+    let result = {
+        let mut calls = CALLS.lock();
+        let call = calls
+            .get_mut(&vm.id())
+            .and_then(|vm_calls| vm_calls.calls.get_mut(&token))
+            .ok_or_else(|| ax_err_type!(NotFound, format!("no call with token {}", token)))?;
+        let result = match call {
+            None => AsyncResult {
+                state: ASYNC_PENDING,
+                error: 0,
+                value: 0,
+            },
+            Some(Ok(value)) => AsyncResult {
+                state: ASYNC_DONE,
+                error: 0,
+                value: *value as u64,
+            },
+            Some(Err(e)) => AsyncResult {
+                state: ASYNC_FAILED,
+                error: e.code(),
+                value: 0,
+            },
+        };
+        if result.state != ASYNC_PENDING {
+            calls.get_mut(&vm.id()).unwrap().calls.remove(&token);
+        }
+        result
+    };
+    vm.write_to_guest_of(buf, &result)?;
+    Ok(result.state)
+}
+
+/// Forgets the calls of the VM, called when the VM is destroyed. Calls still running complete
+/// on their worker but their results are dropped.
+pub fn teardown_vm_deferred(vm_id: usize) {
+    CALLS.lock().remove(&vm_id);
+}
+
+/// Number of calls of VM `vm_id` running and completed but not fetched, for the shell.
+pub fn pending_calls(vm_id: usize) -> (usize, usize) {
+    CALLS.lock().get(&vm_id).map_or((0, 0), |vm_calls| {
+        let running = vm_calls.calls.values().filter(|call| call.is_none()).count();
+        (running, vm_calls.calls.len() - running)
+    })
+}
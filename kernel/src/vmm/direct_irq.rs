@@ -0,0 +1,225 @@
+//! Direct delivery of physical interrupts to partitioned VMs ("no-trap IRQs").
+//!
+//! A partitioned VM (see [`crate::vmm::sched`]) can take some physical interrupts of its
+//! passthrough devices directly, without exiting to the hypervisor:
+//!
+//! ```toml
+//! [irq]
+//! direct = [0x30, 0x31]
+//! ```
+//!
+//! Only aarch64 VMs with `interrupt_mode = "passthrough"` can: the guest owns the GIC CPU
+//! interface of its dedicated CPUs, so an SPI routed to one of them is taken by the guest itself.
+//! Each direct interrupt must be a passthrough SPI of the VM, and belongs to it exclusively: it
+//! can't be direct or passthrough for another VM, nor routed to a guest by the hypervisor (see
+//! [`crate::vmm::pci`]). The GIC distributor is the guest's in that mode, so the guest routes the
+//! SPI to its own CPUs; an interrupt the hypervisor takes anyway, e.g. because it fired while the
+//! CPU ran the hypervisor, is injected to vCPU 0 of the VM as a fallback and counted.
+//!
+//! The other platforms have no such bypass exposed by their vCPU layer, x86 posted interrupts
+//! included, and reject direct interrupts.
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use cpumask::CpuMask;
+use spin::Mutex;
+
+use crate::vmm::{VMRef, vm_list};
+#[cfg(target_arch = "aarch64")]
+use crate::vmm::{pci, sched, stats};
+
+/// A direct interrupt and its owner.
+struct DirectIrq {
+    vm_id: usize,
+    /// Times the interrupt trapped to the hypervisor all the same.
+    trapped: AtomicU64,
+}
+
+/// Direct interrupts, indexed by physical interrupt number.
+static DIRECT_IRQS: Mutex<BTreeMap<usize, DirectIrq>> = Mutex::new(BTreeMap::new());
+
+fn parse_direct(raw_cfg: &toml::Table) -> AxResult<Vec<usize>> {
+    let Some(direct) = raw_cfg
+        .get("irq")
+        .and_then(|v| v.as_table())
+        .and_then(|irq| irq.get("direct"))
+    else {
+        return Ok(Vec::new());
+    };
+    direct
+        .as_array()
+        .and_then(|irqs| {
+            irqs.iter()
+                .map(|irq| {
+                    irq.as_integer()
+                        .filter(|&irq| irq >= 0)
+                        .map(|irq| irq as usize)
+                })
+                .collect::<Option<Vec<_>>>()
+        })
+        .ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                "irq config: `direct` must be an array of interrupt numbers"
+            )
+        })
+}
+
+/// Registers the direct interrupts described in the `[irq]` section of `raw_cfg` for the VM, and
+/// checks that its passthrough SPIs are not direct interrupts of another VM.
+///
+/// Must run after [`sched::setup_vm_scheduling`].
+pub fn setup_vm_direct_irqs(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let irqs = parse_direct(raw_cfg)?;
+    #[cfg(target_arch = "aarch64")]
+    {
+        let vm_id = vm.id();
+        let mut direct = DIRECT_IRQS.lock();
+        let spis: Vec<usize> = vm.with_config(|cfg| {
+            cfg.pass_through_spis()
+                .iter()
+                .map(|&spi| spi as usize)
+                .collect()
+        });
+        if let Some(spi) = spis
+            .iter()
+            .find(|spi| direct.get(spi).is_some_and(|irq| irq.vm_id != vm_id))
+        {
+            return ax_err!(
+                ResourceBusy,
+                format!(
+                    "passthrough SPI {} is a direct interrupt of VM[{}]",
+                    spi, direct[spi].vm_id
+                )
+            );
+        }
+        if irqs.is_empty() {
+            return Ok(());
+        }
+
+        if !sched::is_partitioned(vm_id) {
+            return ax_err!(
+                InvalidInput,
+                "irq config: direct interrupts need a partitioned VM"
+            );
+        }
+        if !vm.with_config(|cfg| {
+            matches!(
+                cfg.interrupt_mode(),
+                axvm::config::VMInterruptMode::Passthrough
+            )
+        }) {
+            return ax_err!(
+                Unsupported,
+                "irq config: direct interrupts need `interrupt_mode = \"passthrough\"`"
+            );
+        }
+        for &irq in &irqs {
+            if !spis.contains(&irq) {
+                return ax_err!(
+                    InvalidInput,
+                    format!("irq config: {} is not a passthrough SPI of the VM", irq)
+                );
+            }
+            if let Some(owner) = direct.get(&irq) {
+                return ax_err!(
+                    ResourceBusy,
+                    format!(
+                        "irq {} is already a direct interrupt of VM[{}]",
+                        irq, owner.vm_id
+                    )
+                );
+            }
+            if let Some(route) = pci::irq_route(irq) {
+                return ax_err!(
+                    ResourceBusy,
+                    format!(
+                        "irq {} is routed to VM[{}] by the hypervisor",
+                        irq, route.vm_id
+                    )
+                );
+            }
+            if let Some(other) = vm_list::get_vm_list().into_iter().find(|other| {
+                other.id() != vm_id
+                    && other.with_config(|cfg| {
+                        cfg.pass_through_spis()
+                            .iter()
+                            .any(|&spi| spi as usize == irq)
+                    })
+            }) {
+                return ax_err!(
+                    ResourceBusy,
+                    format!("irq {} is a passthrough SPI of VM[{}]", irq, other.id())
+                );
+            }
+        }
+        for &irq in &irqs {
+            direct.insert(
+                irq,
+                DirectIrq {
+                    vm_id,
+                    trapped: AtomicU64::new(0),
+                },
+            );
+        }
+        info!("VM[{}] direct interrupts {:?}", vm_id, irqs);
+        Ok(())
+    }
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        if irqs.is_empty() {
+            return Ok(());
+        }
+        ax_err!(
+            Unsupported,
+            format!(
+                "VM[{}] irq config: no direct interrupt delivery on this platform",
+                vm.id()
+            )
+        )
+    }
+}
+
+/// Forgets the direct interrupts of a VM, called when the VM is destroyed.
+pub fn teardown_vm_direct_irqs(vm_id: usize) {
+    DIRECT_IRQS.lock().retain(|_, irq| irq.vm_id != vm_id);
+}
+
+/// Returns the VM owning physical interrupt `irq` as a direct interrupt, if any.
+pub fn owner(irq: usize) -> Option<usize> {
+    DIRECT_IRQS.lock().get(&irq).map(|irq| irq.vm_id)
+}
+
+/// Injects a direct interrupt the hypervisor took to vCPU 0 of its VM, returns `false` if `irq`
+/// is not a direct interrupt.
+pub fn handle_host_irq(irq: usize) -> bool {
+    let vm_id = {
+        let direct = DIRECT_IRQS.lock();
+        let Some(direct_irq) = direct.get(&irq) else {
+            return false;
+        };
+        direct_irq.trapped.fetch_add(1, Ordering::Relaxed);
+        direct_irq.vm_id
+    };
+    if let Some(vm) = vm_list::get_vm_by_id(vm_id) {
+        match vm.inject_interrupt_to_vcpu(CpuMask::one_shot(0), irq) {
+            Ok(()) => stats::count_irq(vm_id, 0),
+            Err(e) => warn!(
+                "VM[{}] failed to inject trapped direct irq {}: {:?}",
+                vm_id, irq, e
+            ),
+        }
+    }
+    true
+}
+
+/// Returns the direct interrupts, with their owner and how many times they trapped.
+pub fn direct_irqs() -> Vec<(usize, usize, u64)> {
+    DIRECT_IRQS
+        .lock()
+        .iter()
+        .map(|(&irq, direct)| (irq, direct.vm_id, direct.trapped.load(Ordering::Relaxed)))
+        .collect()
+}
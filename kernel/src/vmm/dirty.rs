@@ -0,0 +1,270 @@
+//! Dirty page tracking of guest RAM, for incremental snapshots.
+//!
+//! While a VM is tracked, its RAM is mapped read-only in its stage-2: the first write of the guest
+//! to a page faults, marks the page dirty and maps it writable again. The writes of the hypervisor
+//! itself on behalf of the guest (the virtio devices) mark the pages they touch, see [`mark`].
+//! [`take_dirty`] returns the pages dirtied since the previous call and protects them again,
+//! which starts the next interval.
+//!
+//! Tracking splits the block mappings of guest RAM into pages (see [`crate::vmm::blocks`]), and
+//! they stay split once it stops. It's refused for a VM with passthrough devices: their DMA
+//! translates through the same page table and would fault on the read-only pages. It's refused
+//! too while the heat of the VM is sampled (see [`crate::vmm::heatmap`]) or pages of the VM are
+//! watched (see [`crate::vmm::vmi`]), which protect pages the same way, and while a device of the
+//! VM is served by a driver VM, whose writes aren't seen (see [`crate::vmm::virtio`]).
+//!
+//! Only the regions allocated at VM creation are tracked, like those dumped by
+//! [`crate::vmm::coredump`]; the regions populated on demand (see [`crate::vmm::lazymem`]) are
+//! not.
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use axaddrspace::{AxMmHal, GuestPhysAddr, HostPhysAddr, MappingFlags};
+use axerrno::{AxResult, ax_err};
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::hal::AxMmHalImpl;
+use crate::vmm::blocks::{self, RAM_FLAGS};
+use crate::vmm::{VMRef, heatmap, iommu, stage2, virtio, vmi};
+
+/// A tracked region of guest RAM.
+struct TrackedRegion {
+    gpa: usize,
+    hpa: HostPhysAddr,
+    size: usize,
+    /// One bit per page, set if the page was written since the last [`take_dirty`].
+    dirty: Vec<u64>,
+}
+
+impl TrackedRegion {
+    fn page_index(&self, gpa: usize) -> Option<usize> {
+        (self.gpa..self.gpa + self.size)
+            .contains(&gpa)
+            .then(|| (gpa - self.gpa) / PAGE_SIZE_4K)
+    }
+
+    fn is_dirty(&self, idx: usize) -> bool {
+        self.dirty[idx / 64] & (1 << (idx % 64)) != 0
+    }
+
+    fn set_dirty(&mut self, idx: usize) {
+        self.dirty[idx / 64] |= 1 << (idx % 64);
+    }
+}
+
+struct VmDirty {
+    regions: Vec<TrackedRegion>,
+    /// Write faults of the guest on protected pages.
+    faults: u64,
+    /// Calls of [`take_dirty`].
+    intervals: u64,
+}
+
+/// Dirty tracking of the tracked VMs, indexed by VM ID.
+static DIRTY: Mutex<BTreeMap<usize, VmDirty>> = Mutex::new(BTreeMap::new());
+
+/// Dirty tracking of a VM.
+#[derive(Debug, Clone, Copy)]
+pub struct DirtyStats {
+    /// Size of the tracked guest RAM.
+    pub tracked: usize,
+    /// Bytes dirtied in the current interval.
+    pub dirty: usize,
+    pub faults: u64,
+    pub intervals: u64,
+}
+
+/// Maps `[gpa, gpa + size)` of the VM again, to `hpa` with `flags`.
+pub(crate) fn remap(vm: &VMRef, gpa: usize, hpa: HostPhysAddr, size: usize, flags: MappingFlags) -> AxResult {
+    stage2::remap_region(vm, GuestPhysAddr::from(gpa), hpa, size, flags)
+}
+
+/// Starts tracking the dirty pages of the guest RAM of `vm`, with all pages clean.
+pub fn start_tracking(vm: &VMRef) -> AxResult {
+    let mut dirty = DIRTY.lock();
+    if dirty.contains_key(&vm.id()) {
+        return ax_err!(AlreadyExists, format!("VM[{}] is already tracked", vm.id()));
+    }
+    if iommu::has_domain(vm.id()) {
+        return ax_err!(
+            Unsupported,
+            format!(
+                "VM[{}] has passthrough DMA, its writes can't be tracked",
+                vm.id()
+            )
+        );
+    }
+    if heatmap::is_sampling(vm.id()) {
+        return ax_err!(
+            ResourceBusy,
+            format!("VM[{}] is sampled for its heatmap", vm.id())
+        );
+    }
+    if vmi::has_watches(vm.id()) {
+        return ax_err!(
+            ResourceBusy,
+            format!("VM[{}] has pages watched for VMI events", vm.id())
+        );
+    }
+    if virtio::has_vhost_backend(vm.id()) {
+        return ax_err!(
+            ResourceBusy,
+            format!("VM[{}] has devices served by a driver VM", vm.id())
+        );
+    }
+
+    let mut regions = Vec::new();
+    for region in vm.memory_regions() {
+        let (gpa, size) = (region.gpa.as_usize(), region.size());
+        let hpa = AxMmHalImpl::virt_to_phys(region.hva);
+        blocks::split_blocks(vm, region.gpa, size)?;
+        remap(vm, gpa, hpa, size, RAM_FLAGS - MappingFlags::WRITE)?;
+        regions.push(TrackedRegion {
+            gpa,
+            hpa,
+            size,
+            dirty: vec![0; (size / PAGE_SIZE_4K).div_ceil(64)],
+        });
+    }
+    info!(
+        "VM[{}] dirty tracking started, {:#x} bytes of RAM",
+        vm.id(),
+        regions.iter().map(|region| region.size).sum::<usize>()
+    );
+    dirty.insert(
+        vm.id(),
+        VmDirty {
+            regions,
+            faults: 0,
+            intervals: 0,
+        },
+    );
+    Ok(())
+}
+
+/// Stops tracking the dirty pages of `vm` and maps its RAM writable again.
+pub fn stop_tracking(vm: &VMRef) -> AxResult {
+    let Some(vm_dirty) = DIRTY.lock().remove(&vm.id()) else {
+        return ax_err!(NotFound, format!("VM[{}] is not tracked", vm.id()));
+    };
+    for region in vm_dirty.regions {
+        remap(vm, region.gpa, region.hpa, region.size, RAM_FLAGS)?;
+    }
+    info!("VM[{}] dirty tracking stopped", vm.id());
+    Ok(())
+}
+
+/// Whether the dirty pages of VM `vm_id` are tracked.
+pub fn is_tracking(vm_id: usize) -> bool {
+    DIRTY.lock().contains_key(&vm_id)
+}
+
+/// Handles a stage-2 fault of the VM at `gpa` with `access`.
+///
+/// Returns false if it's not a write to a tracked page, in which case the fault is for someone
+/// else.
+pub fn handle_fault(vm: &VMRef, gpa: GuestPhysAddr, access: MappingFlags) -> AxResult<bool> {
+    if !access.contains(MappingFlags::WRITE) {
+        return Ok(false);
+    }
+    let mut dirty = DIRTY.lock();
+    let Some(vm_dirty) = dirty.get_mut(&vm.id()) else {
+        return Ok(false);
+    };
+    let page = gpa.as_usize() & !(PAGE_SIZE_4K - 1);
+    let Some((region, idx)) = vm_dirty
+        .regions
+        .iter_mut()
+        .find_map(|region| region.page_index(page).map(|idx| (region, idx)))
+    else {
+        return Ok(false);
+    };
+    // The page may already be dirty, marked by the hypervisor or by another vCPU faulting on it
+    // meanwhile, mapping it again is harmless.
+    remap(
+        vm,
+        page,
+        region.hpa + (page - region.gpa),
+        PAGE_SIZE_4K,
+        RAM_FLAGS,
+    )?;
+    region.set_dirty(idx);
+    vm_dirty.faults += 1;
+    Ok(true)
+}
+
+/// Marks the pages of `[gpa, gpa + size)` dirty, called by the hypervisor when it writes guest
+/// memory through its own mapping.
+pub fn mark(vm_id: usize, gpa: GuestPhysAddr, size: usize) {
+    let mut dirty = DIRTY.lock();
+    let Some(vm_dirty) = dirty.get_mut(&vm_id) else {
+        return;
+    };
+    let start = gpa.as_usize() & !(PAGE_SIZE_4K - 1);
+    for page in (start..gpa.as_usize() + size).step_by(PAGE_SIZE_4K) {
+        if let Some((region, idx)) = vm_dirty
+            .regions
+            .iter_mut()
+            .find_map(|region| region.page_index(page).map(|idx| (region, idx)))
+        {
+            // The page stays read-only for the guest, which faults on its own first write.
+            region.set_dirty(idx);
+        }
+    }
+}
+
+/// Returns the guest physical ranges of the pages of `vm` dirtied since the previous call (or
+/// since [`start_tracking`]), adjacent pages merged, and protects them again.
+///
+/// The VM should be paused, so that the pages don't change between this call and their copy.
+pub fn take_dirty(vm: &VMRef) -> AxResult<Vec<(GuestPhysAddr, usize)>> {
+    let mut dirty = DIRTY.lock();
+    let Some(vm_dirty) = dirty.get_mut(&vm.id()) else {
+        return ax_err!(NotFound, format!("VM[{}] is not tracked", vm.id()));
+    };
+    let mut ranges: Vec<(GuestPhysAddr, usize)> = Vec::new();
+    for region in vm_dirty.regions.iter_mut() {
+        for idx in 0..region.size / PAGE_SIZE_4K {
+            if !region.is_dirty(idx) {
+                continue;
+            }
+            let page = region.gpa + idx * PAGE_SIZE_4K;
+            remap(
+                vm,
+                page,
+                region.hpa + idx * PAGE_SIZE_4K,
+                PAGE_SIZE_4K,
+                RAM_FLAGS - MappingFlags::WRITE,
+            )?;
+            match ranges.last_mut() {
+                Some((gpa, size)) if gpa.as_usize() + *size == page => *size += PAGE_SIZE_4K,
+                _ => ranges.push((GuestPhysAddr::from(page), PAGE_SIZE_4K)),
+            }
+        }
+        region.dirty.fill(0);
+    }
+    vm_dirty.intervals += 1;
+    Ok(ranges)
+}
+
+/// Returns the dirty tracking of VM `vm_id`, `None` if it's not tracked.
+pub fn dirty_stats(vm_id: usize) -> Option<DirtyStats> {
+    DIRTY.lock().get(&vm_id).map(|vm_dirty| DirtyStats {
+        tracked: vm_dirty.regions.iter().map(|region| region.size).sum(),
+        dirty: vm_dirty
+            .regions
+            .iter()
+            .flat_map(|region| region.dirty.iter())
+            .map(|word| word.count_ones() as usize * PAGE_SIZE_4K)
+            .sum(),
+        faults: vm_dirty.faults,
+        intervals: vm_dirty.intervals,
+    })
+}
+
+/// Forgets the tracking of a VM, called when the VM is destroyed.
+pub fn teardown_vm_dirty(vm_id: usize) {
+    DIRTY.lock().remove(&vm_id);
+}
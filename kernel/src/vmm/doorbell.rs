@@ -0,0 +1,364 @@
+//! Latency-bounded guest-to-guest doorbells.
+//!
+//! A doorbell is a pre-registered (source VM, target VM) pair, declared in the config of the
+//! source VM:
+//!
+//! ```toml
+//! [[doorbells]]
+//! # Doorbell index passed by the source guest to the `HVC_RT_DOORBELL` hypercall.
+//! id = 0
+//! # The VM whose vCPU is signaled.
+//! target_vm = 2
+//! # The vCPU of the target VM the interrupt is injected to, defaults to 0.
+//! target_vcpu = 0
+//! # The interrupt vector injected into the target vCPU.
+//! vector = 0x30
+//! ```
+//!
+//! Ringing a doorbell does not go through the generic hypercall dispatcher. All state lives in
+//! statically allocated tables of atomics, so the ring path performs no allocation, no logging
+//! and takes no lock while the target vCPU is in the guest: it resolves the doorbell with one
+//! atomic load, timestamps it, sets its bit in the pending mask of the target VM and kicks the
+//! target vCPU (see [`vcpus::kick`]), which interrupts the CPU running it in the guest or wakes it
+//! up if it is halted. Out of the guest, the kick looks the vCPU up under the lock of the vCPUs of
+//! the VMs, held with IRQs disabled for the lookup only, and takes the lock of the wait queue of
+//! the target VM if the vCPU is halted. Pending doorbells are injected by the target vCPU itself right before it enters
+//! the guest.
+//!
+//! The worst-case latency from the ring hypercall to the interrupt being pending in the target
+//! vCPU is therefore:
+//! - if the target vCPU is halted: the wake-up and context switch latency of its vCPU task;
+//! - if the target vCPU is running in the guest: the latency of the IPI to its CPU and of the
+//!   exit it causes;
+//! - otherwise: the time until the vCPU enters the guest again.
+//!
+//! The latency of every delivery is measured and the maximum and average are reported by
+//! [`doorbell_stats`] (and `vm show <VM_ID> --stats` in the shell).
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use std::os::arceos::modules::axhal;
+
+use alloc::vec::Vec;
+use axerrno::{AxResult, ax_err, ax_err_type};
+
+use crate::vmm::irqpolicy::{self, IRQ_THROTTLED};
+use crate::vmm::sleep::{self, WakeReason};
+use crate::vmm::{VCpuRef, VMRef, vcpus};
+
+/// Maximum number of VMs taking part in doorbells, VM IDs must be below this.
+pub const MAX_DOORBELL_VMS: usize = 128;
+/// Maximum number of doorbells a VM can ring.
+pub const MAX_OUTBOUND_DOORBELLS: usize = 8;
+/// Maximum number of doorbells targeting a VM.
+pub const MAX_INBOUND_DOORBELLS: usize = 16;
+
+/// A doorbell as seen by its target VM.
+struct Inbound {
+    /// `source VM ID + 1`, 0 if the slot is free.
+    source: AtomicUsize,
+    target_vcpu: AtomicUsize,
+    vector: AtomicUsize,
+    /// Time of the last ring in nanoseconds.
+    rung_at: AtomicU64,
+    delivered: AtomicU64,
+    total_latency_ns: AtomicU64,
+    max_latency_ns: AtomicU64,
+}
+
+impl Inbound {
+    const fn new() -> Self {
+        Self {
+            source: AtomicUsize::new(0),
+            target_vcpu: AtomicUsize::new(0),
+            vector: AtomicUsize::new(0),
+            rung_at: AtomicU64::new(0),
+            delivered: AtomicU64::new(0),
+            total_latency_ns: AtomicU64::new(0),
+            max_latency_ns: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Doorbell tables of a VM.
+struct VmDoorbells {
+    /// Outbound doorbells indexed by doorbell ID, encoded by [`encode_outbound`], 0 if unused.
+    outbound: [AtomicUsize; MAX_OUTBOUND_DOORBELLS],
+    inbound: [Inbound; MAX_INBOUND_DOORBELLS],
+    /// Bitmap of rung but not yet delivered inbound doorbells.
+    pending: AtomicU32,
+}
+
+impl VmDoorbells {
+    const fn new() -> Self {
+        Self {
+            outbound: [const { AtomicUsize::new(0) }; MAX_OUTBOUND_DOORBELLS],
+            inbound: [const { Inbound::new() }; MAX_INBOUND_DOORBELLS],
+            pending: AtomicU32::new(0),
+        }
+    }
+}
+
+static DOORBELLS: [VmDoorbells; MAX_DOORBELL_VMS] =
+    [const { VmDoorbells::new() }; MAX_DOORBELL_VMS];
+
+fn encode_outbound(target_vm: usize, inbound_idx: usize) -> usize {
+    ((target_vm + 1) << 8) | inbound_idx
+}
+
+fn decode_outbound(val: usize) -> (usize, usize) {
+    ((val >> 8) - 1, val & 0xff)
+}
+
+/// Rings doorbell `id` of the VM `vm_id`, the fast path of the `HVC_RT_DOORBELL` hypercall.
+///
+/// Returns 0 on success, -1 if the doorbell is not registered or [`IRQ_THROTTLED`] if the VM
+/// exceeded its interrupt rate, see [`crate::vmm::irqpolicy`].
+#[inline]
+pub fn ring(vm_id: usize, id: usize) -> isize {
+    let Some(val) = DOORBELLS
+        .get(vm_id)
+        .and_then(|t| t.outbound.get(id))
+        .map(|d| d.load(Ordering::Acquire))
+        .filter(|val| *val != 0)
+    else {
+        return -1;
+    };
+    if !irqpolicy::admit(vm_id) {
+        return IRQ_THROTTLED;
+    }
+    let (target_vm, idx) = decode_outbound(val);
+    let target = &DOORBELLS[target_vm];
+
+    target.inbound[idx]
+        .rung_at
+        .store(axhal::time::monotonic_time_nanos(), Ordering::Relaxed);
+    target.pending.fetch_or(1 << idx, Ordering::SeqCst);
+    sleep::wake(target_vm, WakeReason::Interrupt);
+    vcpus::kick(
+        target_vm,
+        target.inbound[idx].target_vcpu.load(Ordering::Relaxed),
+    );
+    0
+}
+
+/// Returns whether doorbells are pending for the vCPU `vcpu_id` of VM `vm_id`.
+#[inline]
+pub fn has_pending(vm_id: usize, vcpu_id: usize) -> bool {
+    let Some(table) = DOORBELLS.get(vm_id) else {
+        return false;
+    };
+    let mut pending = table.pending.load(Ordering::SeqCst);
+    while pending != 0 {
+        let idx = pending.trailing_zeros() as usize;
+        pending &= !(1 << idx);
+        if table.inbound[idx].target_vcpu.load(Ordering::Relaxed) == vcpu_id {
+            return true;
+        }
+    }
+    false
+}
+
+/// Injects the doorbells pending for the vCPU `vcpu` of VM `vm_id`, called by the vCPU task
+/// right before it enters the guest, after [`percpu::enter`](crate::vmm::percpu::enter). Returns
+/// the number of doorbells injected.
+#[inline]
+pub fn deliver_pending(vm_id: usize, vcpu: &VCpuRef) -> usize {
+    let Some(table) = DOORBELLS.get(vm_id) else {
+        return 0;
+    };
+    let mut injected = 0;
+    let mut pending = table.pending.load(Ordering::SeqCst);
+    while pending != 0 {
+        let idx = pending.trailing_zeros() as usize;
+        pending &= !(1 << idx);
+
+        let inbound = &table.inbound[idx];
+        if inbound.target_vcpu.load(Ordering::Relaxed) != vcpu.id() {
+            continue;
+        }
+        if table.pending.fetch_and(!(1 << idx), Ordering::AcqRel) & (1 << idx) == 0 {
+            // Delivered by a racing vCPU.
+            continue;
+        }
+        if vcpu
+            .inject_interrupt(inbound.vector.load(Ordering::Relaxed))
+            .is_ok()
+        {
+            injected += 1;
+        }
+
+        let latency = axhal::time::monotonic_time_nanos()
+            .saturating_sub(inbound.rung_at.load(Ordering::Relaxed));
+        inbound.delivered.fetch_add(1, Ordering::Relaxed);
+        inbound
+            .total_latency_ns
+            .fetch_add(latency, Ordering::Relaxed);
+        inbound.max_latency_ns.fetch_max(latency, Ordering::Relaxed);
+    }
+    injected
+}
+
+/// Registers the doorbells described in the `[[doorbells]]` array of `raw_cfg` for the VM.
+pub fn setup_vm_doorbells(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(entries) = raw_cfg.get("doorbells").and_then(|v| v.as_array()) else {
+        return Ok(());
+    };
+    let vm_id = vm.id();
+    if vm_id >= MAX_DOORBELL_VMS {
+        return ax_err!(
+            InvalidInput,
+            format!("VM[{}] ID too large for doorbells", vm_id)
+        );
+    }
+
+    // The doorbells registered before an invalid entry are unregistered.
+    let mut registered = Vec::with_capacity(entries.len());
+    for entry in entries {
+        match register(vm_id, entry) {
+            Ok(id) => registered.push(id),
+            Err(e) => {
+                for id in registered {
+                    unregister_outbound(&DOORBELLS[vm_id].outbound[id]);
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Registers the doorbell of the `[[doorbells]]` entry `entry` of VM `vm_id`, returns its ID.
+fn register(vm_id: usize, entry: &toml::Value) -> AxResult<usize> {
+    let get = |key: &str| {
+        entry
+            .get(key)
+            .and_then(|v| v.as_integer())
+            .map(|v| v as usize)
+            .ok_or_else(|| {
+                ax_err_type!(InvalidInput, format!("doorbell config: missing `{}`", key))
+            })
+    };
+    let id = get("id")?;
+    let target_vm = get("target_vm")?;
+    let target_vcpu = get("target_vcpu").unwrap_or(0);
+    let vector = get("vector")?;
+    if id >= MAX_OUTBOUND_DOORBELLS || target_vm >= MAX_DOORBELL_VMS {
+        return ax_err!(
+            InvalidInput,
+            format!("VM[{}] doorbell {} out of range", vm_id, id)
+        );
+    }
+
+    let outbound = &DOORBELLS[vm_id].outbound[id];
+    if outbound.load(Ordering::Acquire) != 0 {
+        return ax_err!(
+            AlreadyExists,
+            format!("VM[{}] doorbell {} registered twice", vm_id, id)
+        );
+    }
+
+    // Claim a free inbound slot in the target VM.
+    let idx = DOORBELLS[target_vm]
+        .inbound
+        .iter()
+        .position(|slot| {
+            slot.source
+                .compare_exchange(0, vm_id + 1, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        })
+        .ok_or_else(|| {
+            ax_err_type!(
+                NoMemory,
+                format!("VM[{}] has no free inbound doorbell slots", target_vm)
+            )
+        })?;
+    let inbound = &DOORBELLS[target_vm].inbound[idx];
+    inbound.target_vcpu.store(target_vcpu, Ordering::Relaxed);
+    inbound.vector.store(vector, Ordering::Relaxed);
+    inbound.delivered.store(0, Ordering::Relaxed);
+    inbound.total_latency_ns.store(0, Ordering::Relaxed);
+    inbound.max_latency_ns.store(0, Ordering::Relaxed);
+    outbound.store(encode_outbound(target_vm, idx), Ordering::Release);
+
+    info!(
+        "VM[{}] doorbell {} -> VM[{}] VCpu[{}] vector {:#x}",
+        vm_id, id, target_vm, target_vcpu, vector
+    );
+    Ok(id)
+}
+
+/// Unregisters the outbound doorbell `outbound`, freeing its slot in the target VM.
+fn unregister_outbound(outbound: &AtomicUsize) {
+    let val = outbound.swap(0, Ordering::AcqRel);
+    if val != 0 {
+        let (target_vm, idx) = decode_outbound(val);
+        DOORBELLS[target_vm]
+            .pending
+            .fetch_and(!(1 << idx), Ordering::AcqRel);
+        DOORBELLS[target_vm].inbound[idx]
+            .source
+            .store(0, Ordering::Release);
+    }
+}
+
+/// Unregisters all doorbells rung by or targeting a VM, called when the VM is destroyed.
+pub fn teardown_vm_doorbells(vm_id: usize) {
+    let Some(table) = DOORBELLS.get(vm_id) else {
+        return;
+    };
+
+    // Doorbells rung by this VM.
+    for outbound in &table.outbound {
+        unregister_outbound(outbound);
+    }
+
+    // Doorbells targeting this VM.
+    for (idx, inbound) in table.inbound.iter().enumerate() {
+        let source = inbound.source.swap(0, Ordering::AcqRel);
+        if source != 0 {
+            let encoded = encode_outbound(vm_id, idx);
+            for outbound in &DOORBELLS[source - 1].outbound {
+                let _ = outbound.compare_exchange(encoded, 0, Ordering::AcqRel, Ordering::Relaxed);
+            }
+        }
+    }
+    table.pending.store(0, Ordering::Release);
+}
+
+/// Delivery statistics of a doorbell targeting a VM.
+#[derive(Debug, Clone, Copy)]
+pub struct DoorbellStats {
+    pub source_vm: usize,
+    pub target_vcpu: usize,
+    pub vector: usize,
+    pub delivered: u64,
+    pub avg_latency_ns: u64,
+    pub max_latency_ns: u64,
+}
+
+/// Returns the delivery statistics of the doorbells targeting a VM.
+pub fn doorbell_stats(vm_id: usize) -> Vec<DoorbellStats> {
+    let Some(table) = DOORBELLS.get(vm_id) else {
+        return Vec::new();
+    };
+    table
+        .inbound
+        .iter()
+        .filter_map(|inbound| {
+            let source = inbound.source.load(Ordering::Acquire);
+            let delivered = inbound.delivered.load(Ordering::Relaxed);
+            (source != 0).then(|| DoorbellStats {
+                source_vm: source - 1,
+                target_vcpu: inbound.target_vcpu.load(Ordering::Relaxed),
+                vector: inbound.vector.load(Ordering::Relaxed),
+                delivered,
+                avg_latency_ns: inbound
+                    .total_latency_ns
+                    .load(Ordering::Relaxed)
+                    .checked_div(delivered)
+                    .unwrap_or(0),
+                max_latency_ns: inbound.max_latency_ns.load(Ordering::Relaxed),
+            })
+        })
+        .collect()
+}
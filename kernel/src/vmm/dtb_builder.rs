@@ -0,0 +1,362 @@
+//! Device tree of a guest built from its VM config.
+//!
+//! A VM without a DTB of its own normally gets a copy of the host device tree trimmed down to its
+//! CPUs and passthrough devices, which describes the host and not the VM: none of the devices
+//! emulated by the hypervisor are in it. A VM with a `[dtb_builder]` section gets a device tree
+//! generated from its config instead:
+//!
+//! ```toml
+//! [dtb_builder]
+//! # Passthrough device used as the console of the guest, its `stdout-path`. Optional.
+//! stdout_path = "/pl011@9000000"
+//! ```
+//!
+//! The tree holds:
+//! - the properties of the root node of the host, its address and size cells in particular;
+//! - a CPU node per vCPU, with the MPIDR of its physical CPU and the PSCI enable method, and the
+//!   PSCI node, whose calls are emulated by [`crate::vmm::psci`];
+//! - the memory regions of the VM, updated with their final addresses when the DTB is loaded;
+//! - the interrupt controller and the architected timer, as described by the host: the guest sees
+//!   the vGIC at the addresses of the GIC of the host;
+//! - the passthrough devices, copied from the host device tree with their ancestors;
+//! - a `virtio,mmio` node per virtio device (see [`crate::vmm::virtio`]), the power device (see
+//!   [`crate::vmm::power`]) and the virtio hot-plug controller, whose `reg` spans its slots too.
+//!   Hot-plugged devices aren't in the tree, the guest gets them from the controller;
+//! - a `simple-framebuffer` node for the first buffer of the framebuffer of the VM, if it has
+//!   one (see [`crate::vmm::framebuffer`]);
+//! - a `hypervisor` node, with the information pages of the VM (see [`crate::vmm::hvinfo`]) if it
+//!   has any. IVC channels are placed when they're published or subscribed to, the guest finds
+//!   them through their hypercalls and the services page.
+//!
+//! The command line and the initramfs are added to `/chosen` when the DTB is loaded, see
+//! [`crate::vmm::images::LinuxBoot`]. A VM can't have both a DTB and a `[dtb_builder]` section.
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use axvm::config::{AxVMConfig, AxVMCrateConfig};
+use fdt_parser::{Fdt, Node};
+use memory_addr::PAGE_SIZE_4K;
+use vm_fdt::FdtWriter;
+
+use crate::vmm::fdt::{build_node_path, copy_nodes, find_all_passthrough_devices};
+use crate::vmm::virtio::DEFAULT_HOTPLUG_SLOTS;
+
+/// First shared peripheral interrupt, configured interrupts are GIC interrupt IDs.
+const GIC_SPI_BASE: u64 = 32;
+/// `interrupts` type of an SPI.
+const GIC_SPI: u32 = 0;
+/// `interrupts` flags of an edge-triggered interrupt, the devices of the hypervisor raise edges.
+const IRQ_TYPE_EDGE_RISING: u32 = 1;
+
+/// Whether the VM config `raw_cfg` asks for a generated device tree.
+pub fn is_enabled(raw_cfg: &toml::Table) -> bool {
+    raw_cfg.contains_key("dtb_builder")
+}
+
+fn is_compatible(node: &Node, compatible: &str) -> bool {
+    node.propertys()
+        .find(|prop| prop.name == "compatible")
+        .is_some_and(|prop| {
+            prop.raw_value()
+                .split(|b| *b == 0)
+                .any(|s| s == compatible.as_bytes())
+        })
+}
+
+/// An emulated device of the VM config: a register window and an interrupt.
+struct EmulatedDevice {
+    name: &'static str,
+    compatible: &'static str,
+    base: u64,
+    size: u64,
+    irq: u64,
+}
+
+fn emulated_devices(raw_cfg: &toml::Table) -> AxResult<Vec<EmulatedDevice>> {
+    let device = |section: &str, entry: &toml::Value, name, compatible| {
+        let int = |key: &str| {
+            entry
+                .get(key)
+                .and_then(|v| v.as_integer())
+                .map(|v| v as u64)
+                .ok_or_else(|| {
+                    ax_err_type!(
+                        InvalidInput,
+                        format!("dtb_builder: `{}` device without `{}`", section, key)
+                    )
+                })
+        };
+        let irq = int("irq")?;
+        if irq < GIC_SPI_BASE {
+            return ax_err!(
+                InvalidInput,
+                format!(
+                    "dtb_builder: `{}` device irq {} is not an SPI",
+                    section, irq
+                )
+            );
+        }
+        Ok(EmulatedDevice {
+            name,
+            compatible,
+            base: int("base")?,
+            size: PAGE_SIZE_4K as u64,
+            irq,
+        })
+    };
+    let array = |section: &str| {
+        raw_cfg
+            .get(section)
+            .and_then(|v| v.as_array())
+            .cloned()
+            .unwrap_or_default()
+    };
+
+    let mut devices = Vec::new();
+    for section in [
+        "virtio_net",
+        "virtio_blk",
+        "virtio_console",
+        "virtio_rng",
+        "virtio_vhost",
+    ] {
+        for entry in array(section) {
+            devices.push(device(section, &entry, "virtio_mmio", "virtio,mmio")?);
+        }
+    }
+    if let Some(entry) = raw_cfg.get("virtio_vsock") {
+        devices.push(device("virtio_vsock", entry, "virtio_mmio", "virtio,mmio")?);
+    }
+    if let Some(entry) = raw_cfg.get("power") {
+        devices.push(device("power", entry, "power", "axvisor,power")?);
+    }
+    if let Some(entry) = raw_cfg.get("virtio_hotplug") {
+        // The controller page, followed by the pages of the slots.
+        let slots = entry
+            .get("slots")
+            .and_then(|v| v.as_integer())
+            .map_or(DEFAULT_HOTPLUG_SLOTS as u64, |v| v as u64);
+        let mut controller = device(
+            "virtio_hotplug",
+            entry,
+            "virtio_hotplug",
+            "axvisor,virtio-hotplug",
+        )?;
+        controller.size = (slots + 1) * PAGE_SIZE_4K as u64;
+        devices.push(controller);
+    }
+    Ok(devices)
+}
+
+/// Builds the device tree of a VM from its config and the host device tree `host_fdt`.
+///
+/// Passthrough devices are resolved as for a copied host device tree, which adds the devices they
+/// depend on to `vm_cfg`.
+pub fn build_guest_dtb(
+    host_fdt: &Fdt,
+    vm_cfg: &mut AxVMConfig,
+    crate_config: &AxVMCrateConfig,
+    raw_cfg: &toml::Table,
+) -> AxResult<Vec<u8>> {
+    let cfg = raw_cfg
+        .get("dtb_builder")
+        .and_then(|v| v.as_table())
+        .ok_or_else(|| ax_err_type!(InvalidInput, "dtb_builder config: must be a table"))?;
+    let stdout_path = match cfg.get("stdout_path") {
+        None => None,
+        Some(v) => Some(v.as_str().ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                "dtb_builder config: `stdout_path` must be a string"
+            )
+        })?),
+    };
+    let devices = emulated_devices(raw_cfg)?;
+
+    // The interrupt controller and the timer of the host.
+    let all_nodes: Vec<Node> = host_fdt.all_nodes().collect();
+    let mut gic = None;
+    let mut timer_path = None;
+    for (index, node) in all_nodes.iter().enumerate() {
+        if node.level != 2 {
+            continue;
+        }
+        if gic.is_none()
+            && node
+                .propertys()
+                .any(|prop| prop.name == "interrupt-controller")
+            && (is_compatible(node, "arm,gic-v3") || is_compatible(node, "arm,gic-400"))
+        {
+            let Some(phandle) = node.phandle() else {
+                return ax_err!(BadState, "dtb_builder: host GIC has no phandle");
+            };
+            gic = Some((
+                build_node_path(&all_nodes, index),
+                phandle.as_usize() as u32,
+            ));
+        } else if timer_path.is_none() && is_compatible(node, "arm,armv8-timer") {
+            timer_path = Some(build_node_path(&all_nodes, index));
+        }
+    }
+    let Some((gic_path, gic_phandle)) = gic else {
+        return ax_err!(
+            NotFound,
+            "dtb_builder: no GICv2 or GICv3 in the host device tree"
+        );
+    };
+    let mut platform_paths = vec![gic_path.clone()];
+    platform_paths.extend(timer_path.clone());
+
+    let passthrough_paths = find_all_passthrough_devices(vm_cfg, host_fdt);
+    if let Some(path) = stdout_path
+        && !passthrough_paths.iter().any(|p| p == path)
+    {
+        return ax_err!(
+            InvalidInput,
+            format!(
+                "dtb_builder config: `stdout_path` {} is not passed through",
+                path
+            )
+        );
+    }
+
+    let mut fdt = FdtWriter::new().unwrap();
+    let root = fdt.begin_node("").unwrap();
+    if let Some(host_root) = all_nodes.first() {
+        for prop in host_root.propertys() {
+            fdt.property(prop.name, prop.raw_value()).unwrap();
+        }
+    }
+
+    if let Some(path) = stdout_path {
+        let chosen = fdt.begin_node("chosen").unwrap();
+        fdt.property_string("stdout-path", path).unwrap();
+        fdt.end_node(chosen).unwrap();
+    }
+
+    // CPUs, started through PSCI
+    let phys_cpu_ids = crate_config
+        .base
+        .phys_cpu_ids
+        .clone()
+        .unwrap_or_else(|| (0..crate_config.base.cpu_num).collect());
+    let cpus = fdt.begin_node("cpus").unwrap();
+    fdt.property_u32("#address-cells", 2).unwrap();
+    fdt.property_u32("#size-cells", 0).unwrap();
+    for mpidr in phys_cpu_ids {
+        let cpu = fdt.begin_node(&format!("cpu@{:x}", mpidr)).unwrap();
+        fdt.property_string("device_type", "cpu").unwrap();
+        fdt.property_string("compatible", "arm,armv8").unwrap();
+        fdt.property_string("enable-method", "psci").unwrap();
+        fdt.property_u64("reg", mpidr as u64).unwrap();
+        fdt.end_node(cpu).unwrap();
+    }
+    fdt.end_node(cpus).unwrap();
+
+    let psci = fdt.begin_node("psci").unwrap();
+    fdt.property_string_list(
+        "compatible",
+        vec!["arm,psci-1.0".to_string(), "arm,psci-0.2".to_string()],
+    )
+    .unwrap();
+    fdt.property_string("method", "smc").unwrap();
+    fdt.end_node(psci).unwrap();
+
+    // Memory, as configured
+    let memory_regions = &crate_config.kernel.memory_regions;
+    if let Some(first) = memory_regions.first() {
+        let memory = fdt.begin_node(&format!("memory@{:x}", first.gpa)).unwrap();
+        fdt.property_string("device_type", "memory").unwrap();
+        let reg: Vec<u64> = memory_regions
+            .iter()
+            .flat_map(|region| [region.gpa as u64, region.size as u64])
+            .collect();
+        fdt.property_array_u64("reg", &reg).unwrap();
+        fdt.end_node(memory).unwrap();
+    }
+
+    // Interrupt controller and timer, then the passthrough devices
+    copy_nodes(host_fdt, &platform_paths, &mut fdt, |_, _| false);
+    copy_nodes(host_fdt, &passthrough_paths, &mut fdt, |node, path| {
+        path == gic_path
+            || timer_path.as_deref() == Some(path)
+            || (node.level == 2
+                && (matches!(node.name(), "chosen" | "aliases") || node.name().starts_with("psci")))
+    });
+
+    // Devices emulated by the hypervisor
+    for device in &devices {
+        let node = fdt
+            .begin_node(&format!("{}@{:x}", device.name, device.base))
+            .unwrap();
+        fdt.property_string("compatible", device.compatible)
+            .unwrap();
+        fdt.property_array_u64("reg", &[device.base, device.size])
+            .unwrap();
+        fdt.property_u32("interrupt-parent", gic_phandle).unwrap();
+        fdt.property_array_u32(
+            "interrupts",
+            &[
+                GIC_SPI,
+                (device.irq - GIC_SPI_BASE) as u32,
+                IRQ_TYPE_EDGE_RISING,
+            ],
+        )
+        .unwrap();
+        fdt.property_null("dma-coherent").unwrap();
+        fdt.end_node(node).unwrap();
+    }
+
+    if let Some(cfg) = raw_cfg.get("framebuffer") {
+        let int = |key: &str| {
+            cfg.get(key)
+                .and_then(|v| v.as_integer())
+                .map(|v| v as u64)
+        };
+        let (Some(base), Some(width), Some(height)) = (int("base"), int("width"), int("height"))
+        else {
+            return ax_err!(
+                InvalidInput,
+                "dtb_builder: framebuffer without `base`, `width` or `height`"
+            );
+        };
+        let stride = int("stride").unwrap_or(width * 4);
+        let node = fdt
+            .begin_node(&format!("framebuffer@{:x}", base))
+            .unwrap();
+        fdt.property_string("compatible", "simple-framebuffer")
+            .unwrap();
+        fdt.property_array_u64("reg", &[base, stride * height])
+            .unwrap();
+        fdt.property_u32("width", width as u32).unwrap();
+        fdt.property_u32("height", height as u32).unwrap();
+        fdt.property_u32("stride", stride as u32).unwrap();
+        fdt.property_string("format", "x8r8g8b8").unwrap();
+        fdt.end_node(node).unwrap();
+    }
+
+    let hypervisor = fdt.begin_node("hypervisor").unwrap();
+    fdt.property_string("compatible", "axvisor,hypervisor")
+        .unwrap();
+    if let Some(gpa) = raw_cfg
+        .get("hv_info")
+        .and_then(|v| v.get("gpa"))
+        .and_then(|v| v.as_integer())
+    {
+        fdt.property_array_u64("reg", &[gpa as u64, 3 * PAGE_SIZE_4K as u64])
+            .unwrap();
+    }
+    fdt.end_node(hypervisor).unwrap();
+
+    fdt.end_node(root).unwrap();
+    let dtb = fdt.finish().unwrap();
+    info!(
+        "VM[{}] device tree built: {} passthrough devices, {} emulated devices, {:#x} bytes",
+        crate_config.base.id,
+        passthrough_paths.len(),
+        devices.len(),
+        dtb.len()
+    );
+    Ok(dtb)
+}
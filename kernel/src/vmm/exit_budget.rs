@@ -0,0 +1,229 @@
+//! Exit budget of vCPUs, breaking exit storms.
+//!
+//! A guest hammering an emulated register or a hypercall in a tight loop takes an exit per
+//! iteration, and the hypervisor spends the CPU emulating them: with several VMs on a CPU, that
+//! time is taken from the others, real-time VMs included. A VM with an `[exit_budget]` section
+//! allows each of its vCPUs a number of exits per window, and breaks the storms beyond it:
+//!
+//! ```toml
+//! [exit_budget]
+//! # Exits a vCPU may take per window.
+//! max_exits = 20000
+//! # Length of the window in milliseconds, 10 by default.
+//! window_ms = 10
+//! # What to do with a vCPU over its budget:
+//! # - "alert" (default): log the storm only;
+//! # - "throttle": hold the vCPU out of the guest until the end of the window, capping its rate at
+//! #   the budget;
+//! # - "deschedule": hold the vCPU out of the guest for `penalty_us`, then start a new window.
+//! action = "throttle"
+//! # Time a descheduled vCPU is held, 1000 by default.
+//! penalty_us = 1000
+//! ```
+//!
+//! Exits for host interrupts are not the doing of the guest and are not counted. A held vCPU
+//! sleeps, leaving its CPU to the other tasks. Storms are logged when they start and end, and
+//! counted with the time vCPUs were held for `vm show --stats`.
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use std::os::arceos::modules::axhal;
+use std::thread;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use axvcpu::AxVCpuExitReason;
+use spin::Mutex;
+
+use crate::vmm::VMRef;
+
+/// Response to a vCPU over its exit budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStormAction {
+    Alert,
+    Throttle,
+    Deschedule,
+}
+
+struct ExitBudget {
+    max_exits: u64,
+    window_ns: u64,
+    action: ExitStormAction,
+    penalty: Duration,
+    storms: AtomicU64,
+    held_ns: AtomicU64,
+}
+
+/// Exit budget of a VM and the storms of its vCPUs.
+#[derive(Debug, Clone, Copy)]
+pub struct ExitBudgetStats {
+    pub max_exits: u64,
+    pub window_ns: u64,
+    pub action: ExitStormAction,
+    /// Storms since the VM was created.
+    pub storms: u64,
+    /// Time vCPUs were held out of the guest.
+    pub held_ns: u64,
+}
+
+/// Exit budgets of the VMs with an `[exit_budget]` section, indexed by VM ID.
+static BUDGETS: Mutex<BTreeMap<usize, Arc<ExitBudget>>> = Mutex::new(BTreeMap::new());
+
+/// Applies the `[exit_budget]` section of `raw_cfg` to the VM.
+///
+/// Does nothing if the VM config has no `[exit_budget]` section.
+pub fn setup_vm_exit_budget(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("exit_budget").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let get = |key: &str, default: Option<i64>| {
+        match cfg.get(key) {
+            None => default,
+            Some(v) => v.as_integer(),
+        }
+        .filter(|v| *v > 0)
+        .map(|v| v as u64)
+        .ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                format!("exit_budget config: `{}` must be a positive integer", key)
+            )
+        })
+    };
+    let max_exits = get("max_exits", None)?;
+    let window_ms = get("window_ms", Some(10))?;
+    let penalty_us = get("penalty_us", Some(1000))?;
+    let action = match cfg.get("action").map(|v| v.as_str()) {
+        None | Some(Some("alert")) => ExitStormAction::Alert,
+        Some(Some("throttle")) => ExitStormAction::Throttle,
+        Some(Some("deschedule")) => ExitStormAction::Deschedule,
+        _ => {
+            return ax_err!(
+                InvalidInput,
+                "exit_budget config: `action` must be \"alert\", \"throttle\" or \"deschedule\""
+            );
+        }
+    };
+
+    BUDGETS.lock().insert(
+        vm.id(),
+        Arc::new(ExitBudget {
+            max_exits,
+            window_ns: window_ms * 1_000_000,
+            action,
+            penalty: Duration::from_micros(penalty_us),
+            storms: AtomicU64::new(0),
+            held_ns: AtomicU64::new(0),
+        }),
+    );
+    info!(
+        "VM[{}] exit budget: {} exits per {} ms, action {:?}",
+        vm.id(),
+        max_exits,
+        window_ms,
+        action
+    );
+    Ok(())
+}
+
+/// Drops the exit budget of a VM, called when the VM is destroyed.
+pub fn teardown_vm_exit_budget(vm_id: usize) {
+    BUDGETS.lock().remove(&vm_id);
+}
+
+/// Returns the exit budget of VM `vm_id` and its storms, if it has a budget.
+pub fn exit_budget_stats(vm_id: usize) -> Option<ExitBudgetStats> {
+    let budgets = BUDGETS.lock();
+    let budget = budgets.get(&vm_id)?;
+    Some(ExitBudgetStats {
+        max_exits: budget.max_exits,
+        window_ns: budget.window_ns,
+        action: budget.action,
+        storms: budget.storms.load(Ordering::Relaxed),
+        held_ns: budget.held_ns.load(Ordering::Relaxed),
+    })
+}
+
+/// Exit budget of a vCPU, updated by its task.
+pub struct VCpuExitBudget {
+    budget: Arc<ExitBudget>,
+    vm_id: usize,
+    vcpu_id: usize,
+    window_start_ns: u64,
+    exits: u64,
+    /// Whether the vCPU went over its budget in the current window or in the previous one.
+    storming: bool,
+}
+
+impl VCpuExitBudget {
+    /// Returns the exit budget of vCPU `vcpu_id` of VM `vm_id`, `None` if the VM has no budget.
+    pub fn new(vm_id: usize, vcpu_id: usize) -> Option<Self> {
+        let budget = BUDGETS.lock().get(&vm_id)?.clone();
+        Some(Self {
+            budget,
+            vm_id,
+            vcpu_id,
+            window_start_ns: axhal::time::monotonic_time_nanos(),
+            exits: 0,
+            storming: false,
+        })
+    }
+
+    /// Counts an exit of the vCPU.
+    #[inline]
+    pub fn record_exit(&mut self, reason: &AxVCpuExitReason) {
+        if !matches!(reason, AxVCpuExitReason::ExternalInterrupt { .. }) {
+            self.exits += 1;
+        }
+    }
+
+    fn new_window(&mut self, now: u64) {
+        self.window_start_ns = now;
+        self.exits = 0;
+    }
+
+    /// Holds the vCPU out of the guest if it's over its budget. Called by the vCPU task after
+    /// each exit.
+    #[inline]
+    pub fn enforce(&mut self) {
+        let now = axhal::time::monotonic_time_nanos();
+        let elapsed = now - self.window_start_ns;
+        if elapsed >= self.budget.window_ns {
+            if self.storming && self.exits <= self.budget.max_exits {
+                info!("VM[{}] VCpu[{}] exit storm over", self.vm_id, self.vcpu_id);
+                self.storming = false;
+            }
+            self.new_window(now);
+            return;
+        }
+        if self.exits <= self.budget.max_exits {
+            return;
+        }
+
+        if !self.storming {
+            self.storming = true;
+            self.budget.storms.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "VM[{}] VCpu[{}] exit storm: over {} exits in {} us, action {:?}",
+                self.vm_id,
+                self.vcpu_id,
+                self.budget.max_exits,
+                elapsed / 1000,
+                self.budget.action
+            );
+        }
+        let hold = match self.budget.action {
+            // Counting goes on until the end of the window, which tells whether the storm is over.
+            ExitStormAction::Alert => return,
+            ExitStormAction::Throttle => Duration::from_nanos(self.budget.window_ns - elapsed),
+            ExitStormAction::Deschedule => self.budget.penalty,
+        };
+        thread::sleep(hold);
+        let after = axhal::time::monotonic_time_nanos();
+        self.budget
+            .held_ns
+            .fetch_add(after - now, Ordering::Relaxed);
+        // The storm is over after a whole window within the budget.
+        self.new_window(after);
+    }
+}
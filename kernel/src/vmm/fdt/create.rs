@@ -10,7 +10,11 @@ use fdt_parser::{Fdt, Node};
 use memory_addr::MemoryAddr;
 use vm_fdt::{FdtWriter, FdtWriterNode};
 
-use crate::vmm::{VMRef, images::load_vm_image_from_memory};
+use crate::vmm::{
+    VMRef,
+    identity::VmIdentity,
+    images::{LinuxBoot, load_vm_image_from_memory},
+};
 
 // use crate::vmm::fdt::print::{print_fdt, print_guest_fdt};
 /// Generate guest FDT and return DTB data
@@ -99,6 +103,55 @@ pub fn crate_guest_fdt(
     fdt_writer.finish().unwrap()
 }
 
+/// Copy nodes of `fdt` under the root node being written to `fdt_writer`: the nodes at
+/// `device_paths`, with their descendants and the ancestors leading to them.
+///
+/// Memory and CPU nodes are never copied, nor the subtrees for which `skip` returns true given the
+/// node and its path.
+pub fn copy_nodes(
+    fdt: &Fdt,
+    device_paths: &[String],
+    fdt_writer: &mut FdtWriter,
+    skip: impl Fn(&Node, &str) -> bool,
+) {
+    // The root node is open already, at level 1
+    let mut previous_node_level = 1;
+    let mut node_stack: Vec<FdtWriterNode> = Vec::new();
+    let mut skipped: Vec<String> = Vec::new();
+
+    let all_nodes: Vec<Node> = fdt.all_nodes().collect();
+    for (index, node) in all_nodes.iter().enumerate() {
+        let node_path = super::build_node_path(&all_nodes, index);
+        if skipped.iter().any(|path| {
+            node_path
+                .strip_prefix(path.as_str())
+                .is_some_and(|rest| rest.starts_with('/'))
+        }) {
+            continue;
+        }
+        match determine_node_action(node, &node_path, device_paths) {
+            NodeAction::Skip | NodeAction::RootNode | NodeAction::CpuNode => continue,
+            _ => {}
+        }
+        if skip(node, &node_path) {
+            skipped.push(node_path);
+            continue;
+        }
+
+        handle_node_level_change(fdt_writer, &mut node_stack, node.level, previous_node_level);
+        node_stack.push(fdt_writer.begin_node(node.name()).unwrap());
+        previous_node_level = node.level;
+
+        for prop in node.propertys() {
+            fdt_writer.property(prop.name, prop.raw_value()).unwrap();
+        }
+    }
+
+    while let Some(node) = node_stack.pop() {
+        fdt_writer.end_node(node).unwrap();
+    }
+}
+
 /// Node processing action enumeration
 enum NodeAction {
     /// Skip node, not included in guest FDT
@@ -260,9 +313,59 @@ fn add_memory_node(new_memory: &[VMMemoryRegion], new_fdt: &mut FdtWriter) {
     new_fdt.property_string("device_type", "memory").unwrap();
 }
 
-pub fn update_fdt(fdt_src: NonNull<u8>, dtb_size: usize, vm: VMRef) {
+/// Properties of `/chosen` written by [`add_chosen_props`] from the identity of the VM, replacing
+/// those of the source DTB.
+const CHOSEN_IDENTITY_PROPS: [&str; 4] = [
+    "axvisor,instance",
+    "axvisor,uuid",
+    "axvisor,hostname",
+    "rng-seed",
+];
+
+/// Add the command line, initramfs range, instance number (see [`crate::vmm::template`]) and
+/// identity (see [`crate::vmm::identity`]) of the guest to the `/chosen` node being written.
+fn add_chosen_props(
+    new_fdt: &mut FdtWriter,
+    cmdline: Option<String>,
+    linux: &LinuxBoot,
+    instance: Option<u32>,
+    identity: Option<&VmIdentity>,
+) {
+    if let Some(cmdline) = cmdline {
+        new_fdt.property_string("bootargs", &cmdline).unwrap();
+    }
+    if let Some((initrd_gpa, initrd_size)) = linux.initrd {
+        let start = initrd_gpa.as_usize() as u64;
+        info!(
+            "Adding initrd range: [{:#x}, {:#x})",
+            start,
+            start + initrd_size as u64
+        );
+        new_fdt.property_u64("linux,initrd-start", start).unwrap();
+        new_fdt
+            .property_u64("linux,initrd-end", start + initrd_size as u64)
+            .unwrap();
+    }
+    if let Some(instance) = instance {
+        new_fdt.property_u32("axvisor,instance", instance).unwrap();
+    }
+    if let Some(identity) = identity {
+        new_fdt.property("axvisor,uuid", &identity.uuid).unwrap();
+        new_fdt
+            .property_string("axvisor,hostname", &identity.hostname)
+            .unwrap();
+        if !identity.rng_seed.is_empty() {
+            new_fdt.property("rng-seed", &identity.rng_seed).unwrap();
+        }
+    }
+}
+
+pub fn update_fdt(fdt_src: NonNull<u8>, dtb_size: usize, vm: VMRef, linux: &LinuxBoot) {
     let mut new_fdt = FdtWriter::new().unwrap();
     let mut previous_node_level = 0;
+    let mut has_chosen = false;
+    let instance = crate::vmm::template::instance_of(vm.id());
+    let identity = crate::vmm::identity::identity(vm.id());
     let mut node_stack: Vec<FdtWriterNode> = Vec::new();
 
     let fdt_bytes = unsafe { core::slice::from_raw_parts(fdt_src.as_ptr(), dtb_size) };
@@ -290,27 +393,19 @@ pub fn update_fdt(fdt_src: NonNull<u8>, dtb_size: usize, vm: VMRef) {
         previous_node_level = node.level;
 
         if node.name() == "chosen" {
+            has_chosen = true;
+            let mut dt_bootargs = None;
             for prop in node.propertys() {
-                if prop.name.starts_with("linux,initrd-") {
+                if prop.name.starts_with("linux,initrd-")
+                    || CHOSEN_IDENTITY_PROPS.iter().any(|name| *name == prop.name)
+                {
                     info!(
                         "Skipping property: {}, belonging to node: {}",
                         prop.name,
                         node.name()
                     );
                 } else if prop.name == "bootargs" {
-                    let bootargs_str = prop.str();
-                    let modified_bootargs = bootargs_str.replace(" ro ", " rw ");
-
-                    if modified_bootargs != bootargs_str {
-                        info!(
-                            "Modifying bootargs: {} -> {}",
-                            bootargs_str, modified_bootargs
-                        );
-                    }
-
-                    new_fdt
-                        .property_string(prop.name, &modified_bootargs)
-                        .unwrap();
+                    dt_bootargs = Some(prop.str());
                 } else {
                     debug!(
                         "Find property: {}, belonging to node: {}",
@@ -320,6 +415,14 @@ pub fn update_fdt(fdt_src: NonNull<u8>, dtb_size: usize, vm: VMRef) {
                     new_fdt.property(prop.name, prop.raw_value()).unwrap();
                 }
             }
+
+            let cmdline = linux.cmdline(dt_bootargs);
+            if let Some(cmdline) = &cmdline
+                && dt_bootargs.is_none_or(|s| s != cmdline.as_str())
+            {
+                info!("Modifying bootargs: {:?} -> {}", dt_bootargs, cmdline);
+            }
+            add_chosen_props(&mut new_fdt, cmdline, linux, instance, identity.as_ref());
         } else {
             for prop in node.propertys() {
                 new_fdt.property(prop.name, prop.raw_value()).unwrap();
@@ -339,6 +442,19 @@ pub fn update_fdt(fdt_src: NonNull<u8>, dtb_size: usize, vm: VMRef) {
             let memory_node = new_fdt.begin_node("memory").unwrap();
             add_memory_node(&memory_regions, &mut new_fdt);
             new_fdt.end_node(memory_node).unwrap();
+
+            // add chosen node, if the source has none and there is something to pass
+            let cmdline = linux.cmdline(None);
+            if !has_chosen
+                && (cmdline.is_some()
+                    || linux.initrd.is_some()
+                    || instance.is_some()
+                    || identity.is_some())
+            {
+                let chosen_node = new_fdt.begin_node("chosen").unwrap();
+                add_chosen_props(&mut new_fdt, cmdline, linux, instance, identity.as_ref());
+                new_fdt.end_node(chosen_node).unwrap();
+            }
         }
     }
 
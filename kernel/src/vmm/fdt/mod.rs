@@ -10,6 +10,7 @@ mod print;
 
 use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
+use axerrno::{AxResult, ax_err};
 use axvm::config::{AxVMConfig, AxVMCrateConfig};
 use fdt_parser::Fdt;
 use lazyinit::LazyInit;
@@ -18,7 +19,7 @@ use spin::Mutex;
 pub use parser::*;
 // pub use print::print_fdt;
 pub use create::*;
-pub use device::build_node_path;
+pub use device::{build_node_path, find_all_passthrough_devices};
 
 use crate::vmm::config::{config, get_vm_dtb_arc};
 
@@ -35,6 +36,25 @@ pub fn dtb_cache() -> &'static Mutex<BTreeMap<usize, Vec<u8>>> {
     GENERATED_DTB_CACHE.get().unwrap()
 }
 
+/// Drops the cached DTBs of the VMs whose images are loaded already, returns the bytes freed.
+///
+/// Images are not reloaded when a VM restarts, so the DTB of a loaded VM is never read again.
+pub fn shrink_dtb_cache() -> usize {
+    let Some(cache) = GENERATED_DTB_CACHE.get() else {
+        return 0;
+    };
+    let mut freed = 0;
+    cache.lock().retain(|vm_id, dtb| {
+        let loaded = crate::vmm::vm_list::get_vm_by_id(*vm_id)
+            .is_some_and(|vm| vm.vm_status() != axvm::VMStatus::Loading);
+        if loaded {
+            freed += dtb.capacity();
+        }
+        !loaded
+    });
+    freed
+}
+
 /// Generate guest FDT cache the result
 /// # Return Value
 /// Returns the generated DTB data and stores it in the global cache
@@ -45,14 +65,40 @@ pub fn crate_guest_fdt_with_cache(dtb_data: Vec<u8>, crate_config: &AxVMCrateCon
 }
 
 /// Handle all FDT-related operations for aarch64 architecture
-pub fn handle_fdt_operations(vm_config: &mut AxVMConfig, vm_create_config: &AxVMCrateConfig) {
+pub fn handle_fdt_operations(
+    vm_config: &mut AxVMConfig,
+    vm_create_config: &AxVMCrateConfig,
+    raw_cfg: &toml::Table,
+) -> AxResult {
     let host_fdt_bytes = get_host_fdt();
     let host_fdt = Fdt::from_bytes(host_fdt_bytes)
         .map_err(|e| format!("Failed to parse FDT: {e:#?}"))
         .expect("Failed to parse FDT");
     set_phys_cpu_sets(vm_config, &host_fdt, vm_create_config);
 
-    if let Some(provided_dtb) = get_developer_provided_dtb(vm_config, vm_create_config) {
+    let provided_dtb = get_developer_provided_dtb(vm_config, vm_create_config);
+    if crate::vmm::dtb_builder::is_enabled(raw_cfg) {
+        if provided_dtb.is_some() {
+            return ax_err!(
+                InvalidInput,
+                format!(
+                    "VM[{}] has both a DTB and a `[dtb_builder]` section",
+                    vm_config.id()
+                )
+            );
+        }
+        info!(
+            "VM[{}] building DTB from the configuration file.",
+            vm_config.id()
+        );
+        let dtb_data = crate::vmm::dtb_builder::build_guest_dtb(
+            &host_fdt,
+            vm_config,
+            vm_create_config,
+            raw_cfg,
+        )?;
+        crate_guest_fdt_with_cache(dtb_data, vm_create_config);
+    } else if let Some(provided_dtb) = provided_dtb {
         info!("VM[{}] found DTB , parsing...", vm_config.id());
         update_provided_fdt(&provided_dtb, host_fdt_bytes, vm_create_config);
     } else {
@@ -74,6 +120,7 @@ pub fn handle_fdt_operations(vm_config: &mut AxVMConfig, vm_create_config: &AxVM
             vm_config.id()
         );
     }
+    Ok(())
 }
 
 pub fn get_developer_provided_dtb(
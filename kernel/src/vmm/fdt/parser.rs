@@ -2,7 +2,7 @@
 
 use alloc::{string::ToString, vec::Vec};
 use axvm::config::{AxVMConfig, AxVMCrateConfig, PassThroughDeviceConfig};
-use fdt_parser::{Fdt, FdtHeader, PciRange, PciSpace};
+use fdt_parser::{Fdt, FdtHeader, Node, PciRange, PciSpace};
 
 use crate::vmm::fdt::crate_guest_fdt_with_cache;
 use crate::vmm::fdt::create::update_cpu_node;
@@ -216,6 +216,18 @@ fn add_pci_ranges_config(vm_cfg: &mut AxVMConfig, node_name: &str, range: &PciRa
     );
 }
 
+/// Whether `node` describes a device emulated by the hypervisor: virtio-mmio transports and the
+/// `axvisor,*` devices, see [`crate::vmm::dtb_builder`].
+fn is_emulated_device(node: &Node) -> bool {
+    node.propertys()
+        .find(|prop| prop.name == "compatible")
+        .is_some_and(|prop| {
+            prop.raw_value()
+                .split(|b| *b == 0)
+                .any(|s| s == b"virtio,mmio" || s.starts_with(b"axvisor,"))
+        })
+}
+
 pub fn parse_passthrough_devices_address(vm_cfg: &mut AxVMConfig, dtb: &[u8]) {
     let devices = vm_cfg.pass_through_devices().to_vec();
     if !devices.is_empty() && devices[0].length != 0 {
@@ -238,8 +250,9 @@ pub fn parse_passthrough_devices_address(vm_cfg: &mut AxVMConfig, dtb: &[u8]) {
 
         // Traverse all device tree nodes
         for node in fdt.all_nodes() {
-            // Skip root node
-            if node.name() == "/" || node.name().starts_with("memory") {
+            // Skip root node, and devices emulated by the hypervisor
+            if node.name() == "/" || node.name().starts_with("memory") || is_emulated_device(&node)
+            {
                 continue;
             }
 
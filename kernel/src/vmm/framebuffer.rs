@@ -0,0 +1,573 @@
+//! Framebuffers of guests shown by a display-owner VM or on the display of the hypervisor.
+//!
+//! A guest gets a linear framebuffer, outside of its RAM, with a `[framebuffer]` section in its VM
+//! config, and draws into it as into a simple framebuffer:
+//!
+//! ```toml
+//! [framebuffer]
+//! # Guest physical base of the framebuffer, page aligned and outside of guest memory.
+//! base = 0x4000_0000
+//! width = 1280
+//! height = 720
+//! # Bytes per line, `width * 4` by default. Pixels are 32-bit XRGB8888.
+//! stride = 5120
+//! # Buffers the guest flips between, 1 by default and at most 4, each page aligned.
+//! buffers = 2
+//! # Where the frames are shown: "vm" for a display-owner VM, the default, or "host" for the
+//! # display of the hypervisor.
+//! scanout = "vm"
+//! # Host physical address of the display of the hypervisor, for `scanout = "host"`.
+//! host_base = 0x8000_0000
+//! # Interrupt injected to the guest when the buffer it flipped to was shown. Optional.
+//! irq = 0x37
+//! ```
+//!
+//! The guest shows a buffer with the [`HVC_FB_FLIP`] hypercall (`HFbFlip`), `args[0]` being the
+//! index of the buffer, and draws the next frame in another one until it's interrupted.
+//!
+//! With `scanout = "vm"`, the hypervisor allocates the buffers and a manager VM (see
+//! [`crate::vmm::vmdef`]) owning the display shows them with the [`HVC_FB_CONTROL`] hypercall
+//! (`HFbControl`), `args[0]` being the peer handle of the guest and `args[1]` the operation:
+//!
+//! - [`FB_ATTACH`]: the buffers are mapped read-only into the caller, which is notified of the
+//!   flips through the raw IVC channel with key `args[2]` it published. Returns the GPA of the
+//!   buffers in the caller. A framebuffer has one display VM at a time;
+//! - [`FB_DETACH`]: stops showing the framebuffer, the buffers are unmapped from the caller;
+//! - [`FB_RELEASE`]: the buffer flipped to was shown, interrupts the guest.
+//!
+//! The data of the channel is an [`FbHeader`]. A flip updates `front` and `flips`, sets
+//! [`FB_EVENT_FLIP`] in `events` and notifies the display VM with the vector it registered for
+//! the channel. When the guest is destroyed, the buffers are unmapped from the display VM, which
+//! is notified with [`FB_EVENT_GONE`]. The display VM clears the bits it handled. Only the buffers
+//! are shared: the display VM needs no introspection rights beyond reading them, see
+//! [`crate::vmm::security::Introspection`].
+//!
+//! With `scanout = "host"`, the guest has a single buffer, the physical framebuffer of the display
+//! at `host_base` mapped directly into it, which the firmware or the bootloader of the board set
+//! up with the geometry of the config. The guest owns the display: one guest at a time may, and
+//! a flip interrupts it at once. The framebuffer must be in the linear mapping of the hypervisor,
+//! e.g. in an MMIO range of its platform config.
+//!
+//! [`HVC_FB_FLIP`]: crate::vmm::hvc::HVC_FB_FLIP
+//! [`HVC_FB_CONTROL`]: crate::vmm::hvc::HVC_FB_CONTROL
+use alloc::collections::BTreeMap;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::{GuestPhysAddr, HostPhysAddr, MappingFlags};
+use axerrno::{AxResult, ax_err, ax_err_type};
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::vmm::hvc::{self, HVC_FB_CONTROL, HVC_FB_FLIP, HvcService};
+use crate::vmm::irq::IrqLine;
+use crate::vmm::ivc::{self, IVCMapping};
+use crate::vmm::memstat::{self, MemSubsystem};
+use crate::vmm::security::{self, Introspection};
+use crate::vmm::{VMRef, introspect, iommu, reclaim, stage2};
+
+/// Operations of [`HVC_FB_CONTROL`](crate::vmm::hvc::HVC_FB_CONTROL), in `args[1]`.
+pub const FB_ATTACH: u64 = 0;
+pub const FB_DETACH: u64 = 1;
+pub const FB_RELEASE: u64 = 2;
+
+/// Bits of `FbHeader::events`.
+pub const FB_EVENT_FLIP: u32 = 1 << 0;
+pub const FB_EVENT_GONE: u32 = 1 << 1;
+
+/// `magic` of [`FbHeader`], "AXFB".
+pub const FB_MAGIC: u32 = u32::from_le_bytes(*b"AXFB");
+/// Version of the layout of the channel.
+pub const FB_VERSION: u16 = 1;
+/// Pixel formats, in `FbHeader::format`.
+pub const FB_FORMAT_XRGB8888: u32 = 1;
+/// Buffers of a framebuffer, at most.
+pub const MAX_FB_BUFFERS: usize = 4;
+
+/// Bytes per pixel of XRGB8888.
+const BYTES_PER_PIXEL: usize = 4;
+
+/// Header of the data of the channel.
+#[repr(C)]
+pub struct FbHeader {
+    pub magic: u32,
+    pub version: u16,
+    pub buffers: u16,
+    pub width: u32,
+    pub height: u32,
+    /// Bytes per line.
+    pub stride: u32,
+    /// `FB_FORMAT_*`.
+    pub format: u32,
+    /// Offset of buffer `n + 1` from buffer `n`, a multiple of the page size.
+    pub buffer_size: u64,
+    /// Buffer the guest flipped to last.
+    pub front: AtomicU32,
+    /// `FB_EVENT_*` bits, cleared by the display VM.
+    pub events: AtomicU32,
+    /// Flips since the display VM attached.
+    pub flips: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scanout {
+    Vm,
+    Host,
+}
+
+/// The display VM showing a framebuffer.
+struct Display {
+    vm_id: usize,
+    key: usize,
+    /// The buffers in the display VM.
+    window: GuestPhysAddr,
+    window_size: usize,
+    flips: u64,
+}
+
+struct Framebuffer {
+    hpa: HostPhysAddr,
+    width: usize,
+    height: usize,
+    stride: usize,
+    buffers: usize,
+    buffer_size: usize,
+    irq: Option<usize>,
+    scanout: Scanout,
+    display: Option<Display>,
+    flips: u64,
+    releases: u64,
+}
+
+impl Framebuffer {
+    fn size(&self) -> usize {
+        self.buffers * self.buffer_size
+    }
+}
+
+/// The framebuffers, by VM ID.
+static FRAMEBUFFERS: Mutex<BTreeMap<usize, Framebuffer>> = Mutex::new(BTreeMap::new());
+
+/// The framebuffer of a VM, for monitoring.
+#[derive(Debug, Clone, Copy)]
+pub struct FramebufferStats {
+    pub width: usize,
+    pub height: usize,
+    pub buffers: usize,
+    /// Whether the framebuffer is the display of the hypervisor.
+    pub host: bool,
+    /// The display VM, `None` while there's none or for the display of the hypervisor.
+    pub display_vm_id: Option<usize>,
+    pub flips: u64,
+    pub releases: u64,
+}
+
+/// The flip hypercall, for the VMs with a framebuffer.
+pub static FLIP_HVC_SERVICE: HvcService = HvcService {
+    name: "framebuffer flip",
+    codes: HVC_FB_FLIP..HVC_FB_FLIP + 1,
+    permit: hvc::permit_all,
+    deferrable: false,
+    handler: |vm, _, _, args| flip(vm, args[0] as usize).map(|_| 0),
+};
+
+/// The framebuffer hypercall, for manager VMs.
+pub static HVC_SERVICE: HvcService = HvcService {
+    name: "framebuffer",
+    codes: HVC_FB_CONTROL..HVC_FB_CONTROL + 1,
+    permit: hvc::permit_managers,
+    deferrable: false,
+    handler: |vm, _, _, args| handle_control(vm, args),
+};
+
+/// Returns the header of the data of the channel.
+fn header(data: &mut [u8]) -> &mut FbHeader {
+    // SAFETY: the channel was checked to hold the header by `attach`, which is 8-byte aligned at
+    // the start of the data.
+    unsafe { &mut *data.as_mut_ptr().cast::<FbHeader>() }
+}
+
+/// Handles the `HFbFlip` hypercall of `vm`, showing buffer `buffer`.
+fn flip(vm: &VMRef, buffer: usize) -> AxResult {
+    let release = {
+        let mut framebuffers = FRAMEBUFFERS.lock();
+        let Some(fb) = framebuffers.get_mut(&vm.id()) else {
+            return ax_err!(NotFound, format!("VM[{}] has no framebuffer", vm.id()));
+        };
+        if buffer >= fb.buffers {
+            return ax_err!(InvalidInput, format!("no framebuffer buffer {}", buffer));
+        }
+        fb.flips += 1;
+        match (fb.scanout, fb.display.as_mut()) {
+            // The display of the hypervisor shows the buffer as it's drawn.
+            (Scanout::Host, _) => {
+                fb.releases += 1;
+                fb.irq
+            }
+            (Scanout::Vm, Some(display)) => {
+                display.flips += 1;
+                let flips = display.flips;
+                let notified = ivc::write_raw_channel(display.vm_id, display.key, |data| {
+                    let header = header(data);
+                    header.front.store(buffer as u32, Ordering::Relaxed);
+                    header.flips.store(flips, Ordering::Relaxed);
+                    header.events.fetch_or(FB_EVENT_FLIP, Ordering::Release);
+                });
+                if let Err(e) = notified {
+                    warn!(
+                        "VM[{}] framebuffer: display VM[{}] not notified: {:?}",
+                        vm.id(),
+                        display.vm_id,
+                        e
+                    );
+                }
+                None
+            }
+            // Nothing shows the buffer until a display VM attaches.
+            (Scanout::Vm, None) => None,
+        }
+    };
+    if let Some(irq) = release {
+        IrqLine::new(vm.id(), irq).raise();
+    }
+    Ok(())
+}
+
+/// Handles the `HFbControl` hypercall of the manager `vm`.
+fn handle_control(vm: &VMRef, args: [u64; 6]) -> AxResult<usize> {
+    let target = introspect::target_vm(vm, args[0], Introspection::Read)?;
+    if target.id() == vm.id() {
+        return ax_err!(InvalidInput, "a VM cannot show its own framebuffer");
+    }
+    let mut framebuffers = FRAMEBUFFERS.lock();
+    let Some(fb) = framebuffers.get_mut(&target.id()) else {
+        return ax_err!(NotFound, format!("VM[{}] has no framebuffer", target.id()));
+    };
+    if fb.scanout != Scanout::Vm {
+        return ax_err!(
+            Unsupported,
+            format!("VM[{}] owns the display of the hypervisor", target.id())
+        );
+    }
+    if args[1] == FB_ATTACH {
+        return attach(vm, target.id(), fb, args[2] as usize);
+    }
+
+    if fb.display.as_ref().is_none_or(|display| display.vm_id != vm.id()) {
+        return ax_err!(
+            NotFound,
+            format!(
+                "VM[{}] doesn't show the framebuffer of VM[{}]",
+                vm.id(),
+                target.id()
+            )
+        );
+    }
+    match args[1] {
+        FB_DETACH => {
+            let display = fb.display.take().unwrap();
+            unmap_buffers(target.id(), &display);
+            info!(
+                "VM[{}] stopped showing the framebuffer of VM[{}]",
+                vm.id(),
+                target.id()
+            );
+        }
+        FB_RELEASE => {
+            fb.releases += 1;
+            let irq = fb.irq;
+            drop(framebuffers);
+            if let Some(irq) = irq {
+                IrqLine::new(target.id(), irq).raise();
+            }
+        }
+        op => {
+            return ax_err!(InvalidInput, format!("unknown framebuffer operation {}", op));
+        }
+    }
+    Ok(0)
+}
+
+/// Makes `vm` the display VM of the framebuffer `fb` of VM `target_id`, returns the GPA of the
+/// buffers in `vm`.
+fn attach(vm: &VMRef, target_id: usize, fb: &mut Framebuffer, key: usize) -> AxResult<usize> {
+    if let Some(other) = fb.display.as_ref() {
+        return ax_err!(
+            ResourceBusy,
+            format!(
+                "the framebuffer of VM[{}] is shown by VM[{}]",
+                target_id, other.vm_id
+            )
+        );
+    }
+
+    let window_size = fb.size();
+    let (window, _) = vm.alloc_ivc_channel(window_size)?;
+    let fits = ivc::access_raw_channel(vm.id(), key, |data| {
+        if data.len() < size_of::<FbHeader>() {
+            return false;
+        }
+        data[..size_of::<FbHeader>()].fill(0);
+        let header = header(data);
+        header.magic = FB_MAGIC;
+        header.version = FB_VERSION;
+        header.buffers = fb.buffers as u16;
+        header.width = fb.width as u32;
+        header.height = fb.height as u32;
+        header.stride = fb.stride as u32;
+        header.format = FB_FORMAT_XRGB8888;
+        header.buffer_size = fb.buffer_size as u64;
+        true
+    })?;
+    if !fits {
+        return ax_err!(InvalidInput, "IVC channel too small for a framebuffer");
+    }
+    let mapping = IVCMapping {
+        offset: 0,
+        hpa: fb.hpa,
+        size: window_size,
+        flags: MappingFlags::READ,
+    };
+    ivc::map_window(vm, window, window_size, &[mapping])?;
+
+    fb.display = Some(Display {
+        vm_id: vm.id(),
+        key,
+        window,
+        window_size,
+        flips: 0,
+    });
+    info!(
+        "VM[{}] shows the framebuffer of VM[{}], channel {:#x}, buffers at {:#x}",
+        vm.id(),
+        target_id,
+        key,
+        window
+    );
+    Ok(window.as_usize())
+}
+
+/// Unmaps the buffers of the framebuffer of VM `vm_id` from its display VM.
+fn unmap_buffers(vm_id: usize, display: &Display) {
+    let unmapped = crate::vmm::with_vm(display.vm_id, |vm| {
+        iommu::unmap_region(&vm, display.window, display.window_size)
+    });
+    if let Some(Err(e)) = unmapped {
+        warn!(
+            "VM[{}] framebuffer not unmapped from VM[{}]: {:?}",
+            vm_id, display.vm_id, e
+        );
+    }
+}
+
+/// Allocates zeroed, physically contiguous buffers of `size` bytes, whole pages.
+fn alloc_buffers(size: usize) -> Option<HostPhysAddr> {
+    let vaddr = reclaim::alloc_or_reclaim(|| {
+        axalloc::global_allocator()
+            .alloc_pages(size / PAGE_SIZE_4K, PAGE_SIZE_4K)
+            .ok()
+    })?;
+    // SAFETY: the pages were just allocated.
+    unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, size) };
+    Some(axhal::mem::virt_to_phys(vaddr.into()))
+}
+
+/// Scrubs and frees buffers allocated with [`alloc_buffers`] for VM `vm_id`.
+fn dealloc_buffers(vm_id: usize, hpa: HostPhysAddr, size: usize) {
+    let vaddr = axhal::mem::phys_to_virt(hpa);
+    // SAFETY: the buffers are being freed and no VM maps them anymore.
+    let bytes = unsafe { core::slice::from_raw_parts_mut(vaddr.as_mut_ptr(), size) };
+    security::scrub_mode(vm_id).scrub(bytes);
+    axalloc::global_allocator().dealloc_pages(vaddr.as_usize(), size / PAGE_SIZE_4K);
+}
+
+/// Maps the framebuffer described in the `[framebuffer]` section of `raw_cfg` into the VM.
+///
+/// Does nothing if the VM config has no `[framebuffer]` section.
+pub fn setup_vm_framebuffer(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("framebuffer").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let int = |key: &str| {
+        cfg.get(key)
+            .and_then(|v| v.as_integer())
+            .map(|v| v as usize)
+    };
+    let get = |key: &str| {
+        int(key).ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                format!("framebuffer config: missing `{}`", key)
+            )
+        })
+    };
+    let base = get("base")?;
+    let width = get("width")?;
+    let height = get("height")?;
+    let stride = int("stride").unwrap_or(width * BYTES_PER_PIXEL);
+    let buffers = int("buffers").unwrap_or(1);
+    let scanout = match cfg.get("scanout").map(|v| v.as_str()) {
+        None | Some(Some("vm")) => Scanout::Vm,
+        Some(Some("host")) => Scanout::Host,
+        _ => {
+            return ax_err!(
+                InvalidInput,
+                "framebuffer config: `scanout` must be \"vm\" or \"host\""
+            );
+        }
+    };
+    if width == 0 || height == 0 || stride < width * BYTES_PER_PIXEL {
+        return ax_err!(
+            InvalidInput,
+            format!(
+                "framebuffer config: bad geometry {}x{}, stride {}",
+                width, height, stride
+            )
+        );
+    }
+    if buffers == 0 || buffers > MAX_FB_BUFFERS {
+        return ax_err!(
+            InvalidInput,
+            format!("framebuffer config: 1 to {} buffers", MAX_FB_BUFFERS)
+        );
+    }
+    if scanout == Scanout::Host && buffers != 1 {
+        return ax_err!(
+            InvalidInput,
+            "framebuffer config: the display of the hypervisor has a single buffer"
+        );
+    }
+    let buffer_size = (stride * height).next_multiple_of(PAGE_SIZE_4K);
+    let size = buffers * buffer_size;
+    if base % PAGE_SIZE_4K != 0 {
+        return ax_err!(
+            InvalidInput,
+            "framebuffer config: `base` must be page aligned"
+        );
+    }
+    if vm.memory_regions().iter().any(|region| {
+        base < region.gpa.as_usize() + region.size() && region.gpa.as_usize() < base + size
+    }) {
+        return ax_err!(
+            InvalidInput,
+            format!("framebuffer config: {:#x} overlaps guest memory", base)
+        );
+    }
+
+    let mut framebuffers = FRAMEBUFFERS.lock();
+    let (hpa, flags) = match scanout {
+        Scanout::Host => {
+            let host_base = get("host_base")?;
+            if host_base % PAGE_SIZE_4K != 0 {
+                return ax_err!(
+                    InvalidInput,
+                    "framebuffer config: `host_base` must be page aligned"
+                );
+            }
+            if let Some((owner, _)) = framebuffers
+                .iter()
+                .find(|(_, fb)| fb.scanout == Scanout::Host)
+            {
+                return ax_err!(
+                    ResourceBusy,
+                    format!("the display of the hypervisor is owned by VM[{}]", owner)
+                );
+            }
+            let flags = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE;
+            (HostPhysAddr::from(host_base), flags)
+        }
+        Scanout::Vm => {
+            memstat::try_charge(MemSubsystem::Devices, vm.id(), size)?;
+            let hpa = alloc_buffers(size).ok_or_else(|| {
+                memstat::uncharge(MemSubsystem::Devices, Some(vm.id()), size);
+                ax_err_type!(
+                    NoMemory,
+                    format!("failed to allocate a {:#x}-byte framebuffer", size)
+                )
+            })?;
+            (hpa, MappingFlags::READ | MappingFlags::WRITE)
+        }
+    };
+    if let Err(e) = stage2::map_region(vm, GuestPhysAddr::from(base), hpa, size, flags) {
+        if scanout == Scanout::Vm {
+            dealloc_buffers(vm.id(), hpa, size);
+            memstat::uncharge(MemSubsystem::Devices, Some(vm.id()), size);
+        }
+        return Err(e);
+    }
+    framebuffers.insert(
+        vm.id(),
+        Framebuffer {
+            hpa,
+            width,
+            height,
+            stride,
+            buffers,
+            buffer_size,
+            irq: int("irq"),
+            scanout,
+            display: None,
+            flips: 0,
+            releases: 0,
+        },
+    );
+    info!(
+        "VM[{}] {}x{} framebuffer at {:#x}, {} buffers, scanout {:?}",
+        vm.id(),
+        width,
+        height,
+        base,
+        buffers,
+        scanout
+    );
+    Ok(())
+}
+
+/// Frees the framebuffer of a VM and detaches the VM from the framebuffers it shows, called when
+/// the VM is destroyed.
+pub fn teardown_vm_framebuffer(vm_id: usize) {
+    let mut framebuffers = FRAMEBUFFERS.lock();
+    for fb in framebuffers.values_mut() {
+        // The buffers go with the stage-2 of the display VM.
+        if fb.display.as_ref().is_some_and(|display| display.vm_id == vm_id) {
+            fb.display = None;
+        }
+    }
+    let Some(mut fb) = framebuffers.remove(&vm_id) else {
+        return;
+    };
+    drop(framebuffers);
+    if let Some(display) = fb.display.take() {
+        unmap_buffers(vm_id, &display);
+        let gone = ivc::write_raw_channel(display.vm_id, display.key, |data| {
+            header(data)
+                .events
+                .fetch_or(FB_EVENT_GONE, Ordering::Release);
+        });
+        if let Err(e) = gone {
+            warn!(
+                "VM[{}] framebuffer: display VM[{}] not notified: {:?}",
+                vm_id, display.vm_id, e
+            );
+        }
+    }
+    if fb.scanout == Scanout::Vm {
+        dealloc_buffers(vm_id, fb.hpa, fb.size());
+        memstat::uncharge(MemSubsystem::Devices, Some(vm_id), fb.size());
+    }
+}
+
+/// Returns the framebuffer of VM `vm_id`, if it has one.
+pub fn framebuffer_stats(vm_id: usize) -> Option<FramebufferStats> {
+    FRAMEBUFFERS.lock().get(&vm_id).map(|fb| FramebufferStats {
+        width: fb.width,
+        height: fb.height,
+        buffers: fb.buffers,
+        host: fb.scanout == Scanout::Host,
+        display_vm_id: fb.display.as_ref().map(|display| display.vm_id),
+        flips: fb.flips,
+        releases: fb.releases,
+    })
+}
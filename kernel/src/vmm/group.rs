@@ -0,0 +1,364 @@
+//! VM groups: resource pools shared by related VMs.
+//!
+//! The VMs of a tenant may be limited together, so that the set of them can't exhaust the memory
+//! the hypervisor allocates on behalf of guests or the CPUs, whatever their number. A VM joins a
+//! group with a `[group]` section in its VM config:
+//!
+//! ```toml
+//! [group]
+//! # Name of the group, created by the first VM naming it.
+//! name = "tenant-a"
+//! # Memory the hypervisor may allocate on behalf of the VMs of the group, in KiB, no limit by
+//! # default.
+//! memory_limit_kb = 4096
+//! # Percentage of one CPU the shared VMs of the group may use together, 0 (the default) for no
+//! # cap.
+//! cpu_cap = 150
+//! ```
+//!
+//! The other VMs naming the group give the same limits or none.
+//!
+//! The memory limit covers the memory charged to the VMs of the group (see
+//! [`crate::vmm::memstat`]): their IVC channels, consoles, emulated devices and information
+//! pages, but not their guest RAM, which is given by their configs. The allocations the VMs cause
+//! at runtime fail with `NoMemory` once the group reached its limit, e.g. publishing an IVC
+//! channel. The CPU cap applies to the run time of the shared VMs of the group per
+//! [`SHARE_PERIOD`], on top of their own caps, see [`crate::vmm::sched`]; partitioned and gang VMs
+//! can't join a group with a CPU cap.
+//!
+//! Manager VMs (see [`crate::vmm::vmdef`]) create groups and move VMs between them at runtime with
+//! the [`HVC_VM_GROUP`] hypercall (`HVmGroup`), `args[0]` being the operation:
+//!
+//! - [`GROUP_CREATE`]: creates a group with a memory limit of `args[1]` bytes and a CPU cap of
+//!   `args[2]` percent, 0 for none. Returns the ID of the group. At most
+//!   [`MAX_GROUP_SHARES`](sched::MAX_GROUP_SHARES) groups have a CPU cap;
+//! - [`GROUP_MOVE`]: moves the VM of peer handle `args[1]` to group `args[2]`, or out of its group
+//!   with [`GROUP_NONE`]. Fails if the memory already charged to the VM would put the group over
+//!   its limit;
+//! - [`GROUP_DESTROY`]: destroys the empty group `args[1]`.
+//!
+//! A destroyed VM leaves its group, and the memory it leaves behind is no longer charged to the
+//! group. Groups aren't destroyed when they become empty.
+//!
+//! [`SHARE_PERIOD`]: crate::vmm::sched::SHARE_PERIOD
+//! [`HVC_VM_GROUP`]: crate::vmm::hvc::HVC_VM_GROUP
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use spin::Mutex;
+
+use crate::vmm::hvc::{self, HVC_VM_GROUP, HvcService};
+use crate::vmm::sched;
+use crate::vmm::{VMRef, memstat, peers};
+
+/// `args[0]` of [`crate::vmm::hvc::HVC_VM_GROUP`] creating a group.
+pub const GROUP_CREATE: u64 = 0;
+/// `args[0]` of [`crate::vmm::hvc::HVC_VM_GROUP`] moving a VM to a group.
+pub const GROUP_MOVE: u64 = 1;
+/// `args[0]` of [`crate::vmm::hvc::HVC_VM_GROUP`] destroying a group.
+pub const GROUP_DESTROY: u64 = 2;
+/// Group ID of [`GROUP_MOVE`] taking the VM out of its group.
+pub const GROUP_NONE: u64 = 0;
+
+struct VmGroup {
+    /// Name of the groups created from VM configs.
+    name: Option<String>,
+    /// Memory limit in bytes, 0 for none.
+    memory_limit: usize,
+    /// Bytes charged to the VMs of the group.
+    memory_used: usize,
+    /// Refused allocations.
+    refusals: u64,
+    /// Percentage of one CPU, 0 for no cap.
+    cpu_cap: u32,
+    /// Slot of the CPU share of the group, if it has a CPU cap.
+    cpu: Option<usize>,
+    vms: BTreeSet<usize>,
+}
+
+struct Groups {
+    groups: BTreeMap<usize, VmGroup>,
+    /// Group of each VM in one.
+    vm_groups: BTreeMap<usize, usize>,
+    next_id: usize,
+}
+
+static GROUPS: Mutex<Groups> = Mutex::new(Groups {
+    groups: BTreeMap::new(),
+    vm_groups: BTreeMap::new(),
+    next_id: 1,
+});
+
+/// A VM group, see [`groups`].
+#[derive(Debug, Clone)]
+pub struct GroupInfo {
+    pub id: usize,
+    pub name: Option<String>,
+    /// Memory limit in bytes, 0 for none.
+    pub memory_limit: usize,
+    pub memory_used: usize,
+    /// Allocations refused as the group reached its limit.
+    pub refusals: u64,
+    /// Percentage of one CPU, 0 for no cap.
+    pub cpu_cap: u32,
+    /// Run time of the VMs of the group in the current period, if it has a CPU cap.
+    pub cpu_consumed_ns: Option<u64>,
+    pub vms: Vec<usize>,
+}
+
+pub static HVC_SERVICE: HvcService = HvcService {
+    name: "group",
+    codes: HVC_VM_GROUP..HVC_VM_GROUP + 1,
+    permit: hvc::permit_managers,
+    deferrable: false,
+    handler: |vm, _, _, args| handle_group(vm, args),
+};
+
+impl Groups {
+    fn create(
+        &mut self,
+        name: Option<String>,
+        memory_limit: usize,
+        cpu_cap: u32,
+    ) -> AxResult<usize> {
+        let cpu = match cpu_cap {
+            0 => None,
+            cap => Some(sched::alloc_group_share(cap)?),
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        self.groups.insert(
+            id,
+            VmGroup {
+                name,
+                memory_limit,
+                memory_used: 0,
+                refusals: 0,
+                cpu_cap,
+                cpu,
+                vms: BTreeSet::new(),
+            },
+        );
+        Ok(id)
+    }
+
+    /// Takes VM `vm_id` out of its group, with the memory charged to it.
+    fn leave(&mut self, vm_id: usize) {
+        let Some(id) = self.vm_groups.remove(&vm_id) else {
+            return;
+        };
+        if let Some(group) = self.groups.get_mut(&id) {
+            group.vms.remove(&vm_id);
+            group.memory_used = group.memory_used.saturating_sub(memstat::vm_total(vm_id));
+        }
+        sched::set_vm_group_share(vm_id, None);
+    }
+
+    /// Puts VM `vm_id` in group `id`, with the memory charged to it.
+    fn join(&mut self, vm_id: usize, id: usize) -> AxResult {
+        let group = self
+            .groups
+            .get(&id)
+            .ok_or_else(|| ax_err_type!(NotFound, format!("no VM group {}", id)))?;
+        if group.vms.contains(&vm_id) {
+            return Ok(());
+        }
+        let charged = memstat::vm_total(vm_id);
+        let memory_used = group.memory_used + charged;
+        if group.memory_limit != 0 && memory_used > group.memory_limit {
+            return ax_err!(
+                NoMemory,
+                format!(
+                    "VM[{}] uses {} bytes, more than left in VM group {}",
+                    vm_id, charged, id
+                )
+            );
+        }
+        if group.cpu.is_some() && sched::vm_share(vm_id).is_none() {
+            return ax_err!(
+                InvalidInput,
+                format!("VM[{}] isn't shared, it can't join a group with a CPU cap", vm_id)
+            );
+        }
+
+        self.leave(vm_id);
+        let group = self.groups.get_mut(&id).unwrap();
+        group.memory_used = memory_used;
+        group.vms.insert(vm_id);
+        sched::set_vm_group_share(vm_id, group.cpu);
+        self.vm_groups.insert(vm_id, id);
+        Ok(())
+    }
+}
+
+/// Puts the VM in the group named in the `[group]` section of `raw_cfg`, creating the group with
+/// the limits in the section if it doesn't exist. Called when the VM is created, once its
+/// scheduling mode is known.
+pub fn setup_vm_group(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("group").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let name = cfg
+        .get("name")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| ax_err_type!(InvalidInput, "group config: missing `name`"))?;
+    let get = |key: &str| match cfg.get(key) {
+        None => Ok(None),
+        Some(v) => v
+            .as_integer()
+            .filter(|&v| (0..=u32::MAX as i64).contains(&v))
+            .map(|v| Some(v as u32))
+            .ok_or_else(|| {
+                ax_err_type!(
+                    InvalidInput,
+                    format!("group config: `{}` must be a non-negative integer", key)
+                )
+            }),
+    };
+    let memory_limit = get("memory_limit_kb")?.map(|kb| kb as usize * 1024);
+    let cpu_cap = get("cpu_cap")?;
+
+    let mut groups = GROUPS.lock();
+    let existing = groups
+        .groups
+        .iter()
+        .find(|(_, group)| group.name.as_deref() == Some(name))
+        .map(|(&id, group)| (id, group.memory_limit, group.cpu_cap));
+    let id = match existing {
+        Some((id, limit, cap)) => {
+            if memory_limit.is_some_and(|memory_limit| memory_limit != limit)
+                || cpu_cap.is_some_and(|cpu_cap| cpu_cap != cap)
+            {
+                return ax_err!(
+                    InvalidInput,
+                    format!("group config: other limits than VM group `{}`", name)
+                );
+            }
+            id
+        }
+        None => groups.create(
+            Some(name.into()),
+            memory_limit.unwrap_or(0),
+            cpu_cap.unwrap_or(0),
+        )?,
+    };
+    groups.join(vm.id(), id)?;
+    info!("VM[{}] in VM group `{}` ({})", vm.id(), name, id);
+    Ok(())
+}
+
+/// Takes a VM out of its group, called when the VM is destroyed.
+pub fn teardown_vm_group(vm_id: usize) {
+    GROUPS.lock().leave(vm_id);
+}
+
+/// Charges `bytes` allocated on behalf of VM `vm_id` to its group, even over its limit.
+pub fn charge_memory(vm_id: usize, bytes: usize) {
+    let mut groups = GROUPS.lock();
+    if let Some(&id) = groups.vm_groups.get(&vm_id)
+        && let Some(group) = groups.groups.get_mut(&id)
+    {
+        group.memory_used += bytes;
+    }
+}
+
+/// Charges `bytes` about to be allocated on behalf of VM `vm_id` to its group, fails if the group
+/// would go over its limit.
+pub fn try_charge_memory(vm_id: usize, bytes: usize) -> AxResult {
+    let mut groups = GROUPS.lock();
+    let Some(&id) = groups.vm_groups.get(&vm_id) else {
+        return Ok(());
+    };
+    let Some(group) = groups.groups.get_mut(&id) else {
+        return Ok(());
+    };
+    if group.memory_limit != 0 && group.memory_used + bytes > group.memory_limit {
+        group.refusals += 1;
+        return ax_err!(
+            NoMemory,
+            format!(
+                "VM group {} can't allocate {} more bytes for VM[{}]",
+                id, bytes, vm_id
+            )
+        );
+    }
+    group.memory_used += bytes;
+    Ok(())
+}
+
+/// Drops the charge of `bytes` freed on behalf of VM `vm_id` from its group.
+pub fn uncharge_memory(vm_id: usize, bytes: usize) {
+    let mut groups = GROUPS.lock();
+    if let Some(&id) = groups.vm_groups.get(&vm_id)
+        && let Some(group) = groups.groups.get_mut(&id)
+    {
+        group.memory_used = group.memory_used.saturating_sub(bytes);
+    }
+}
+
+/// Handles the [`HVC_VM_GROUP`](crate::vmm::hvc::HVC_VM_GROUP) hypercall of manager VM `vm`.
+fn handle_group(vm: &VMRef, args: [u64; 6]) -> AxResult<usize> {
+    let mut groups = GROUPS.lock();
+    match args[0] {
+        GROUP_CREATE => {
+            if args[2] > u32::MAX as u64 {
+                return ax_err!(InvalidInput, "CPU cap out of range");
+            }
+            let id = groups.create(None, args[1] as usize, args[2] as u32)?;
+            info!("VM[{}] created VM group {}", vm.id(), id);
+            Ok(id)
+        }
+        GROUP_MOVE => {
+            let target_vm_id = peers::resolve(vm.id(), args[1] as usize)?;
+            if args[2] == GROUP_NONE {
+                groups.leave(target_vm_id);
+            } else {
+                groups.join(target_vm_id, args[2] as usize)?;
+            }
+            info!("VM[{}] moved to VM group {}", target_vm_id, args[2]);
+            Ok(0)
+        }
+        GROUP_DESTROY => {
+            let id = args[1] as usize;
+            match groups.groups.get(&id) {
+                None => return ax_err!(NotFound, format!("no VM group {}", id)),
+                Some(group) if !group.vms.is_empty() => {
+                    return ax_err!(ResourceBusy, format!("VM group {} isn't empty", id));
+                }
+                Some(_) => {}
+            }
+            if let Some(slot) = groups.groups.remove(&id).and_then(|group| group.cpu) {
+                sched::free_group_share(slot);
+            }
+            info!("VM[{}] destroyed VM group {}", vm.id(), id);
+            Ok(0)
+        }
+        op => ax_err!(InvalidInput, format!("unknown VM group operation {}", op)),
+    }
+}
+
+/// Returns the ID of the group of VM `vm_id`, if it's in one.
+pub fn vm_group(vm_id: usize) -> Option<usize> {
+    GROUPS.lock().vm_groups.get(&vm_id).copied()
+}
+
+/// Returns the VM groups.
+pub fn groups() -> Vec<GroupInfo> {
+    GROUPS
+        .lock()
+        .groups
+        .iter()
+        .map(|(&id, group)| GroupInfo {
+            id,
+            name: group.name.clone(),
+            memory_limit: group.memory_limit,
+            memory_used: group.memory_used,
+            refusals: group.refusals,
+            cpu_cap: group.cpu_cap,
+            cpu_consumed_ns: group.cpu.map(sched::group_consumed_ns),
+            vms: group.vms.iter().copied().collect(),
+        })
+        .collect()
+}
@@ -0,0 +1,333 @@
+//! Guest time: a per-VM clock and stolen-time reporting.
+//!
+//! Every VM has a clock which starts at zero when its vCPUs start running and stands still while
+//! the VM is paused (`vm suspend`, core dumps), so guest time doesn't jump over the pauses. It can
+//! be set to carry guest time over a snapshot or a migration, see [`set_guest_time_ns`]. The
+//! clock is kept as an offset from host time, [`counter_offset_ticks`] gives it in counter ticks.
+//! The vCPU backends own the hardware offsets (`CNTVOFF_EL2` in the aarch64 vCPU context, the TSC
+//! offset of the x86 one) and expose no interface to set them, so the counter the guest reads
+//! directly isn't offset yet.
+//!
+//! The time a vCPU spends off its CPU while it has work to do, i.e. yielding at the end of a time
+//! slice, over its CPU share or waiting for its gang (see [`crate::vmm::sched`]), is accounted as
+//! stolen time. Halts are idle time, not stolen. Linux guests on aarch64 read it from the Arm
+//! paravirtualized time interface (SMCCC `PV_TIME_FEATURES` and `PV_TIME_ST`) if the VM has
+//! stolen time records:
+//!
+//! ```toml
+//! [time]
+//! # GPA of the stolen time records, one 64-byte record per vCPU, in guest memory the guest
+//! # doesn't use otherwise, e.g. a reserved-memory region of its device tree.
+//! stolen_time_gpa = 0x7fff_f000
+//! ```
+//!
+//! The record of a vCPU is updated after each reschedule, before the vCPU enters the guest again.
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err, ax_err_type};
+use spin::Mutex;
+
+use crate::vmm::VMRef;
+
+/// Size of the stolen time record of a vCPU.
+pub const STOLEN_TIME_RECORD_SIZE: usize = 64;
+/// Offset of the stolen time in nanoseconds in a stolen time record, after the revision and
+/// attributes words which are 0.
+const STOLEN_TIME_OFFSET: usize = 8;
+
+/// `PV_TIME_FEATURES`.
+#[cfg(target_arch = "aarch64")]
+const PV_TIME_FEATURES: u64 = 0xc500_0020;
+/// `PV_TIME_ST`.
+#[cfg(target_arch = "aarch64")]
+const PV_TIME_ST: u64 = 0xc500_0021;
+#[cfg(target_arch = "aarch64")]
+const SMCCC_RET_SUCCESS: isize = 0;
+#[cfg(target_arch = "aarch64")]
+const SMCCC_RET_NOT_SUPPORTED: isize = -1;
+
+struct VmClock {
+    /// Host time at which guest time was 0, pauses included. Negative for a guest time restored
+    /// from a host with a longer uptime.
+    base_ns: AtomicI64,
+    /// Host time at which the VM was paused, 0 while it runs.
+    paused_ns: AtomicU64,
+    stolen_time_gpa: Option<GuestPhysAddr>,
+    /// Stolen time of each vCPU.
+    steal_ns: Vec<AtomicU64>,
+}
+
+impl VmClock {
+    fn guest_time_ns(&self, now: u64) -> u64 {
+        let paused = self.paused_ns.load(Ordering::Acquire);
+        let now = if paused != 0 { paused } else { now };
+        (now as i64 - self.base_ns.load(Ordering::Acquire)).max(0) as u64
+    }
+}
+
+static CLOCKS: Mutex<BTreeMap<usize, Arc<VmClock>>> = Mutex::new(BTreeMap::new());
+
+fn clock(vm_id: usize) -> Option<Arc<VmClock>> {
+    CLOCKS.lock().get(&vm_id).cloned()
+}
+
+/// Creates the clock of the VM, with the stolen time records described in the `[time]` section
+/// of `raw_cfg` if any.
+pub fn setup_vm_guest_time(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let stolen_time_gpa = match raw_cfg
+        .get("time")
+        .and_then(|v| v.as_table())
+        .and_then(|cfg| cfg.get("stolen_time_gpa"))
+    {
+        None => None,
+        Some(v) => {
+            let gpa = v.as_integer().map(|v| v as usize).ok_or_else(|| {
+                ax_err_type!(
+                    InvalidInput,
+                    "time config: `stolen_time_gpa` must be an address"
+                )
+            })?;
+            check_stolen_time_gpa(vm, gpa)?;
+            Some(GuestPhysAddr::from(gpa))
+        }
+    };
+
+    let now = axhal::time::monotonic_time_nanos();
+    CLOCKS.lock().insert(
+        vm.id(),
+        Arc::new(VmClock {
+            base_ns: AtomicI64::new(now as i64),
+            paused_ns: AtomicU64::new(0),
+            stolen_time_gpa,
+            steal_ns: (0..vm.vcpu_num()).map(|_| AtomicU64::new(0)).collect(),
+        }),
+    );
+    if let Some(gpa) = stolen_time_gpa {
+        info!("VM[{}] stolen time records at {:?}", vm.id(), gpa);
+    }
+    Ok(())
+}
+
+fn check_stolen_time_gpa(vm: &VMRef, gpa: usize) -> AxResult {
+    if !cfg!(target_arch = "aarch64") {
+        return ax_err!(
+            Unsupported,
+            "time config: no stolen time interface on this platform"
+        );
+    }
+    let size = vm.vcpu_num() * STOLEN_TIME_RECORD_SIZE;
+    if gpa % STOLEN_TIME_RECORD_SIZE != 0
+        || !vm.memory_regions().iter().any(|region| {
+            region.gpa.as_usize() <= gpa && gpa + size <= region.gpa.as_usize() + region.size()
+        })
+    {
+        return ax_err!(
+            InvalidInput,
+            format!(
+                "time config: stolen time records at {:#x} are not in guest memory",
+                gpa
+            )
+        );
+    }
+    Ok(())
+}
+
+/// Removes the clock of a VM, called when the VM is destroyed.
+pub fn teardown_vm_guest_time(vm_id: usize) {
+    CLOCKS.lock().remove(&vm_id);
+}
+
+/// Starts the clock of VM `vm_id` from zero, called when its vCPUs start running.
+pub fn start(vm_id: usize) {
+    if let Some(clock) = clock(vm_id) {
+        clock.base_ns.store(
+            axhal::time::monotonic_time_nanos() as i64,
+            Ordering::Release,
+        );
+        clock.paused_ns.store(0, Ordering::Release);
+        for steal in &clock.steal_ns {
+            steal.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Stops the clock of VM `vm_id` while the VM is paused.
+pub fn pause(vm_id: usize) {
+    if let Some(clock) = clock(vm_id) {
+        let now = axhal::time::monotonic_time_nanos().max(1);
+        let _ = clock
+            .paused_ns
+            .compare_exchange(0, now, Ordering::AcqRel, Ordering::Acquire);
+    }
+}
+
+/// Restarts the clock of VM `vm_id` where it stopped.
+pub fn resume(vm_id: usize) {
+    if let Some(clock) = clock(vm_id) {
+        let paused = clock.paused_ns.swap(0, Ordering::AcqRel);
+        if paused != 0 {
+            let pause_ns = axhal::time::monotonic_time_nanos() - paused;
+            clock.base_ns.fetch_add(pause_ns as i64, Ordering::AcqRel);
+        }
+    }
+}
+
+/// Returns the guest time of VM `vm_id` in nanoseconds.
+pub fn guest_time_ns(vm_id: usize) -> Option<u64> {
+    clock(vm_id).map(|clock| clock.guest_time_ns(axhal::time::monotonic_time_nanos()))
+}
+
+/// Sets the guest time of VM `vm_id`, e.g. to the time saved with a snapshot of the VM.
+pub fn set_guest_time_ns(vm_id: usize, guest_ns: u64) -> AxResult {
+    let clock = clock(vm_id)
+        .ok_or_else(|| ax_err_type!(NotFound, format!("VM[{}] has no clock", vm_id)))?;
+    let paused = clock.paused_ns.load(Ordering::Acquire);
+    let now = if paused != 0 {
+        paused
+    } else {
+        axhal::time::monotonic_time_nanos()
+    };
+    let guest_ns = i64::try_from(guest_ns)
+        .map_err(|_| ax_err_type!(InvalidInput, format!("invalid guest time {} ns", guest_ns)))?;
+    clock
+        .base_ns
+        .store(now as i64 - guest_ns, Ordering::Release);
+    Ok(())
+}
+
+/// Returns the offset of the guest counter of VM `vm_id` from the host counter, in ticks, to be
+/// subtracted from the host counter.
+pub fn counter_offset_ticks(vm_id: usize) -> Option<i64> {
+    let base_ns = clock(vm_id)?.base_ns.load(Ordering::Acquire);
+    let ticks = axhal::time::nanos_to_ticks(base_ns.unsigned_abs()) as i64;
+    Some(if base_ns < 0 { -ticks } else { ticks })
+}
+
+/// Stolen time accounting of a vCPU.
+pub struct VCpuClock {
+    clock: Arc<VmClock>,
+    vcpu_id: usize,
+    /// Stolen time last written to the record of the vCPU.
+    reported_ns: u64,
+}
+
+impl VCpuClock {
+    /// Returns the stolen time accounting of vCPU `vcpu_id` of VM `vm_id`.
+    pub fn new(vm_id: usize, vcpu_id: usize) -> Option<Self> {
+        Some(Self {
+            clock: clock(vm_id)?,
+            vcpu_id,
+            reported_ns: 0,
+        })
+    }
+
+    /// Accounts the time since `since_ns` as stolen, called by the vCPU task after it got its CPU
+    /// back.
+    #[inline]
+    pub fn steal_since(&self, since_ns: u64) {
+        let stolen = axhal::time::monotonic_time_nanos().saturating_sub(since_ns);
+        if let Some(steal) = self.clock.steal_ns.get(self.vcpu_id) {
+            steal.fetch_add(stolen, Ordering::Relaxed);
+        }
+    }
+
+    /// Writes the stolen time of the vCPU to its record if it changed, called by the vCPU task
+    /// before it enters the guest.
+    #[inline]
+    pub fn update_record(&mut self, vm: &VMRef) {
+        let Some(gpa) = self.clock.stolen_time_gpa else {
+            return;
+        };
+        let Some(steal) = self.clock.steal_ns.get(self.vcpu_id) else {
+            return;
+        };
+        let steal = steal.load(Ordering::Relaxed);
+        if steal == self.reported_ns {
+            return;
+        }
+        let record = gpa + self.vcpu_id * STOLEN_TIME_RECORD_SIZE + STOLEN_TIME_OFFSET;
+        match vm.write_to_guest_of(record, &steal) {
+            Ok(()) => self.reported_ns = steal,
+            Err(e) => warn!(
+                "VM[{}] VCpu[{}] failed to update its stolen time: {:?}",
+                vm.id(),
+                self.vcpu_id,
+                e
+            ),
+        }
+    }
+}
+
+/// Whether `nr` of a hypercall exit is a paravirtualized time call.
+#[cfg(target_arch = "aarch64")]
+pub fn is_pv_time_call(nr: u64) -> bool {
+    nr == PV_TIME_FEATURES || nr == PV_TIME_ST
+}
+
+/// Whether VM `vm_id` implements the paravirtualized time call `fid`, for `SMCCC_ARCH_FEATURES`.
+#[cfg(target_arch = "aarch64")]
+pub fn has_pv_time_call(vm_id: usize, fid: u64) -> bool {
+    is_pv_time_call(fid) && clock(vm_id).is_some_and(|clock| clock.stolen_time_gpa.is_some())
+}
+
+/// Handles the paravirtualized time call `fid` of vCPU `vcpu_id` of `vm`.
+#[cfg(target_arch = "aarch64")]
+pub fn handle_pv_time_call(vm: &VMRef, vcpu_id: usize, fid: u64, args: [u64; 6]) -> isize {
+    let Some(clock) = clock(vm.id()) else {
+        return SMCCC_RET_NOT_SUPPORTED;
+    };
+    let Some(gpa) = clock.stolen_time_gpa else {
+        return SMCCC_RET_NOT_SUPPORTED;
+    };
+    match fid {
+        PV_TIME_FEATURES if is_pv_time_call(args[0]) => SMCCC_RET_SUCCESS,
+        PV_TIME_FEATURES => SMCCC_RET_NOT_SUPPORTED,
+        _ => {
+            // Revision 0 and no attributes in the first word, then the stolen time, which the
+            // vCPU keeps up to date.
+            let mut words = [0u64; STOLEN_TIME_RECORD_SIZE / 8];
+            words[STOLEN_TIME_OFFSET / 8] = clock
+                .steal_ns
+                .get(vcpu_id)
+                .map_or(0, |steal| steal.load(Ordering::Relaxed));
+            let record = gpa + vcpu_id * STOLEN_TIME_RECORD_SIZE;
+            if let Err(e) = vm.write_to_guest_of(record, &words) {
+                warn!(
+                    "VM[{}] VCpu[{}] failed to reset its stolen time record: {:?}",
+                    vm.id(),
+                    vcpu_id,
+                    e
+                );
+                return SMCCC_RET_NOT_SUPPORTED;
+            }
+            record.as_usize() as isize
+        }
+    }
+}
+
+/// Guest time and stolen time of a VM, for monitoring.
+#[derive(Debug, Clone)]
+pub struct GuestTimeStats {
+    pub guest_time_ns: u64,
+    pub paused: bool,
+    pub vcpu_steal_ns: Vec<u64>,
+}
+
+/// Returns the guest time and the stolen time of the vCPUs of VM `vm_id`.
+pub fn stats(vm_id: usize) -> Option<GuestTimeStats> {
+    let clock = clock(vm_id)?;
+    Some(GuestTimeStats {
+        guest_time_ns: clock.guest_time_ns(axhal::time::monotonic_time_nanos()),
+        paused: clock.paused_ns.load(Ordering::Acquire) != 0,
+        vcpu_steal_ns: clock
+            .steal_ns
+            .iter()
+            .map(|steal| steal.load(Ordering::Relaxed))
+            .collect(),
+    })
+}
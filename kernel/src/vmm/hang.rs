@@ -0,0 +1,435 @@
+//! Detection of stuck vCPUs.
+//!
+//! Every vCPU task counts its exits and flags when it blocks on purpose (guest `WFI`/`HLT`, a
+//! suspended VM, a vCPU turned off). A vCPU that is neither blocked nor exiting for `timeout_ms`
+//! is making no forward progress: it's either spinning in the guest without a single exit, not
+//! even for host timer interrupts, or runnable in the hypervisor but never scheduled.
+//!
+//! With `instructions`, the tasks also count the instructions retired by the guest with a
+//! performance counter of the hypervisor (see [`pmu::instruction_counter`], aarch64 only), and a
+//! vCPU that keeps exiting without retiring any instruction, e.g. faulting on the same instruction
+//! again and again, is stuck too:
+//!
+//! ```toml
+//! [hang_detect]
+//! timeout_ms = 5000
+//! instructions = true
+//! ```
+//!
+//! Stuck vCPUs are reported once per stall with the state visible to the hypervisor, and kept for
+//! `vm hangs`. Their registers are requested when they are detected and added to the report once
+//! the vCPU leaves the guest, which it is kicked out of (see [`regs::request`]).
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+use core::time::Duration;
+use std::os::arceos::modules::axhal;
+use std::thread;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use axvcpu::{AxVCpuExitReason, VCpuState};
+use axvm::VMStatus;
+use spin::{Mutex, Once};
+
+use crate::task::AsVCpuTask;
+use crate::vmm::percpu::{self, SCRATCH_WORDS};
+use crate::vmm::regs::{self, RegsSnapshot};
+use crate::vmm::{VMRef, pmu, vcpus, vm_list};
+
+/// Interval between two checks of the detector.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Number of hang events kept for `vm hangs`.
+const MAX_EVENTS: usize = 32;
+
+const EXIT_NAMES: &[&str] = &[
+    "none",
+    "Hypercall",
+    "MmioRead",
+    "MmioWrite",
+    "SysRegRead",
+    "SysRegWrite",
+    "ExternalInterrupt",
+    "Halt",
+    "CpuUp",
+    "CpuDown",
+    "SystemDown",
+    "SendIPI",
+    "FailEntry",
+    "Nothing",
+    "other",
+];
+
+const _: () = assert!(EXIT_NAMES.len() == crate::vmm::stats::STATS_EXIT_REASONS);
+
+/// Code of an exit reason, see [`exit_name`].
+pub(crate) fn exit_code(reason: &AxVCpuExitReason) -> u8 {
+    match reason {
+        AxVCpuExitReason::Hypercall { .. } => 1,
+        AxVCpuExitReason::MmioRead { .. } => 2,
+        AxVCpuExitReason::MmioWrite { .. } => 3,
+        AxVCpuExitReason::SysRegRead { .. } => 4,
+        AxVCpuExitReason::SysRegWrite { .. } => 5,
+        AxVCpuExitReason::ExternalInterrupt { .. } => 6,
+        AxVCpuExitReason::Halt => 7,
+        AxVCpuExitReason::CpuUp { .. } => 8,
+        AxVCpuExitReason::CpuDown { .. } => 9,
+        AxVCpuExitReason::SystemDown => 10,
+        AxVCpuExitReason::SendIPI { .. } => 11,
+        AxVCpuExitReason::FailEntry { .. } => 12,
+        AxVCpuExitReason::Nothing => 13,
+        _ => 14,
+    }
+}
+
+/// Name of an exit reason code.
+pub(crate) fn exit_name(code: u8) -> &'static str {
+    EXIT_NAMES.get(code as usize).copied().unwrap_or("other")
+}
+
+/// Forward progress of a vCPU, updated by its task.
+pub struct VCpuProgress {
+    exits: AtomicU64,
+    last_exit: AtomicU8,
+    /// Details of the last exit, see [`percpu::exit_details`].
+    exit_details: [AtomicU64; SCRATCH_WORDS],
+    /// Instructions retired by the guest, counted if the detector samples them, see
+    /// [`VCpuInstructions`].
+    instructions: AtomicU64,
+    blocked: AtomicBool,
+}
+
+impl VCpuProgress {
+    /// Progress of a vCPU whose task has not started yet, flagged as blocked until it waits for
+    /// the VM to run.
+    pub const fn new() -> Self {
+        Self {
+            exits: AtomicU64::new(0),
+            last_exit: AtomicU8::new(0),
+            exit_details: [const { AtomicU64::new(0) }; SCRATCH_WORDS],
+            instructions: AtomicU64::new(0),
+            blocked: AtomicBool::new(true),
+        }
+    }
+
+    /// Records an exit of the vCPU.
+    #[inline]
+    pub fn record_exit(&self, reason: &AxVCpuExitReason) {
+        self.exits.fetch_add(1, Ordering::Relaxed);
+        self.last_exit.store(exit_code(reason), Ordering::Relaxed);
+        for (word, detail) in self.exit_details.iter().zip(percpu::exit_details(reason)) {
+            word.store(detail, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the number of exits of the vCPU, the code of the last one and its details.
+    pub fn last_exit(&self) -> (u64, u8, [u64; SCRATCH_WORDS]) {
+        (
+            self.exits.load(Ordering::Relaxed),
+            self.last_exit.load(Ordering::Relaxed),
+            core::array::from_fn(|i| self.exit_details[i].load(Ordering::Relaxed)),
+        )
+    }
+
+    /// Flags the vCPU task as blocked on purpose, it's not expected to make progress meanwhile.
+    ///
+    /// Ordered before the wakeup condition the task checks next, see [`vcpus::kick`].
+    #[inline]
+    pub fn set_blocked(&self, blocked: bool) {
+        self.blocked.store(blocked, Ordering::SeqCst);
+    }
+
+    /// Returns whether the vCPU task is blocked on purpose.
+    #[inline]
+    pub fn is_blocked(&self) -> bool {
+        self.blocked.load(Ordering::SeqCst)
+    }
+}
+
+/// Where a stuck vCPU is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HangKind {
+    /// Running in the guest without any exit.
+    InGuest,
+    /// Runnable in the hypervisor but not making progress, e.g. never scheduled.
+    InHypervisor,
+    /// Exiting without retiring any instruction of the guest.
+    ExitLoop,
+}
+
+/// A stuck vCPU, with the state visible to the hypervisor when it was detected.
+#[derive(Debug, Clone)]
+pub struct HangEvent {
+    pub vm_id: usize,
+    pub vcpu_id: usize,
+    pub kind: HangKind,
+    /// Host time of the detection in nanoseconds.
+    pub detected_at_ns: u64,
+    /// Time without progress in milliseconds.
+    pub stalled_ms: u64,
+    /// Exits of the vCPU since it started.
+    pub exits: u64,
+    /// Reason of the last exit.
+    pub last_exit: &'static str,
+    pub vcpu_state: VCpuState,
+    /// Physical CPU of the vCPU task.
+    pub pcpu: usize,
+    /// Instructions retired by the guest since the vCPU started, if the detector samples them.
+    pub instructions: Option<u64>,
+    /// The registers of the vCPU, `None` until it leaves the guest after the detection.
+    pub regs: Option<RegsSnapshot>,
+}
+
+/// Progress of a vCPU at the last check of the detector.
+struct Sample {
+    exits: u64,
+    instructions: u64,
+    since_ns: u64,
+    reported: bool,
+}
+
+struct VmDetector {
+    timeout_ns: u64,
+    /// The counter of the instructions of the guest, if they are sampled.
+    counter: Option<usize>,
+    samples: Vec<Option<Sample>>,
+}
+
+static DETECTORS: Mutex<BTreeMap<usize, VmDetector>> = Mutex::new(BTreeMap::new());
+static EVENTS: Mutex<VecDeque<HangEvent>> = Mutex::new(VecDeque::new());
+static DETECTOR_THREAD: Once = Once::new();
+
+/// Enables the detection of stuck vCPUs for the VM if `raw_cfg` has a `[hang_detect]` section.
+pub fn setup_vm_hang_detect(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("hang_detect").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let timeout_ms = cfg
+        .get("timeout_ms")
+        .and_then(|v| v.as_integer())
+        .ok_or_else(|| ax_err_type!(InvalidInput, "hang_detect config: missing `timeout_ms`"))?;
+    if timeout_ms <= 0 {
+        return ax_err!(
+            InvalidInput,
+            "hang_detect config: `timeout_ms` must be positive"
+        );
+    }
+    let counter = match cfg.get("instructions").map(|v| v.as_bool()) {
+        None | Some(Some(false)) => None,
+        Some(Some(true)) => Some(
+            pmu::instruction_counter()
+                .map_err(|e| ax_err_type!(InvalidInput, format!("hang_detect config: {:?}", e)))?,
+        ),
+        Some(None) => {
+            return ax_err!(
+                InvalidInput,
+                "hang_detect config: `instructions` must be a boolean"
+            );
+        }
+    };
+
+    DETECTORS.lock().insert(
+        vm.id(),
+        VmDetector {
+            timeout_ns: timeout_ms as u64 * 1_000_000,
+            counter,
+            samples: (0..vm.vcpu_num()).map(|_| None).collect(),
+        },
+    );
+    DETECTOR_THREAD.call_once(|| {
+        thread::spawn(|| {
+            loop {
+                thread::sleep(POLL_INTERVAL);
+                check_all();
+            }
+        });
+    });
+    info!(
+        "VM[{}] hang detection: timeout {} ms, instructions {}",
+        vm.id(),
+        timeout_ms,
+        if counter.is_some() {
+            "sampled"
+        } else {
+            "not sampled"
+        }
+    );
+    Ok(())
+}
+
+/// Disables the detection of stuck vCPUs for a VM, called when the VM is destroyed.
+pub fn teardown_vm_hang_detect(vm_id: usize) {
+    DETECTORS.lock().remove(&vm_id);
+}
+
+/// Returns the hang events detected so far, oldest first.
+pub fn events() -> Vec<HangEvent> {
+    EVENTS.lock().iter().cloned().collect()
+}
+
+fn check_all() {
+    let now = axhal::time::monotonic_time_nanos();
+    let mut detected = Vec::new();
+    {
+        let mut detectors = DETECTORS.lock();
+        for (&vm_id, detector) in detectors.iter_mut() {
+            let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+                continue;
+            };
+            check_vm(&vm, detector, now, &mut detected);
+        }
+    }
+
+    for event in detected {
+        error!(
+            "VM[{}] VCpu[{}] stuck {:?} for {} ms: {} exits, {:?} instructions, last exit {}, \
+             state {:?}, pCPU {}",
+            event.vm_id,
+            event.vcpu_id,
+            event.kind,
+            event.stalled_ms,
+            event.exits,
+            event.instructions,
+            event.last_exit,
+            event.vcpu_state,
+            event.pcpu
+        );
+        regs::request(event.vm_id, event.vcpu_id);
+        let mut events = EVENTS.lock();
+        if events.len() == MAX_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    // The registers of the vCPUs detected, once they left the guest.
+    for event in EVENTS
+        .lock()
+        .iter_mut()
+        .filter(|event| event.regs.is_none())
+    {
+        event.regs = regs::last(event.vm_id, event.vcpu_id)
+            .filter(|snapshot| snapshot.taken_ns >= event.detected_at_ns);
+        if let Some(snapshot) = &event.regs {
+            error!(
+                "VM[{}] VCpu[{}] registers when stuck: {:#x?}",
+                event.vm_id, event.vcpu_id, snapshot.regs
+            );
+        }
+    }
+}
+
+fn check_vm(vm: &VMRef, detector: &mut VmDetector, now: u64, detected: &mut Vec<HangEvent>) {
+    if vm.vm_status() != VMStatus::Running {
+        detector
+            .samples
+            .iter_mut()
+            .for_each(|sample| *sample = None);
+        return;
+    }
+    for (vcpu_id, sample) in detector.samples.iter_mut().enumerate() {
+        let progress = vcpus::with_vcpu_task(vm.id(), vcpu_id, |task| {
+            let progress = &task.as_vcpu_task().progress;
+            (
+                progress.exits.load(Ordering::Relaxed),
+                progress.instructions.load(Ordering::Relaxed),
+                progress.last_exit.load(Ordering::Relaxed),
+                progress.blocked.load(Ordering::Relaxed),
+                task.cpu_id() as usize,
+            )
+        });
+        // Not started yet, or blocked on purpose.
+        let Some((exits, instructions, last_exit, false, pcpu)) = progress else {
+            *sample = None;
+            continue;
+        };
+        let sampled = detector.counter.is_some();
+        match sample {
+            Some(sample)
+                if sample.exits == exits || (sampled && sample.instructions == instructions) =>
+            {
+                let stalled = now - sample.since_ns;
+                if stalled >= detector.timeout_ns && !sample.reported {
+                    sample.reported = true;
+                    let vcpu_state = vm
+                        .vcpu(vcpu_id)
+                        .map_or(VCpuState::Invalid, |vcpu| vcpu.state());
+                    detected.push(HangEvent {
+                        vm_id: vm.id(),
+                        vcpu_id,
+                        kind: if sample.exits != exits {
+                            HangKind::ExitLoop
+                        } else if vcpu_state == VCpuState::Running {
+                            HangKind::InGuest
+                        } else {
+                            HangKind::InHypervisor
+                        },
+                        detected_at_ns: now,
+                        stalled_ms: stalled / 1_000_000,
+                        exits,
+                        last_exit: exit_name(last_exit),
+                        vcpu_state,
+                        pcpu,
+                        instructions: sampled.then_some(instructions),
+                        regs: None,
+                    });
+                }
+            }
+            _ => {
+                *sample = Some(Sample {
+                    exits,
+                    instructions,
+                    since_ns: now,
+                    reported: false,
+                })
+            }
+        }
+    }
+}
+
+/// The counting of the instructions retired by the guest of a vCPU, as seen by its task.
+pub struct VCpuInstructions {
+    counter: usize,
+    /// Value of the counter when the vCPU entered the guest.
+    start: u32,
+    /// Keeps the task on this CPU while the counter counts the guest.
+    guard: Option<kernel_guard::NoPreempt>,
+}
+
+impl VCpuInstructions {
+    /// Returns the counting of the instructions of a vCPU of VM `vm_id`, if its detector samples
+    /// them.
+    pub fn new(vm_id: usize) -> Option<Self> {
+        let counter = DETECTORS.lock().get(&vm_id)?.counter?;
+        Some(Self {
+            counter,
+            start: 0,
+            guard: None,
+        })
+    }
+
+    /// Starts counting, called right before the vCPU enters the guest. The task is not
+    /// preempted until [`VCpuInstructions::exit`].
+    pub fn enter(&mut self) {
+        self.guard = Some(kernel_guard::NoPreempt::new());
+        #[cfg(target_arch = "aarch64")]
+        {
+            self.start = crate::hal::arch::pmu::start_guest_instructions(self.counter);
+        }
+    }
+
+    /// Adds the instructions retired by the guest since [`VCpuInstructions::enter`] to
+    /// `progress`, called when the vCPU exits the guest.
+    pub fn exit(&mut self, progress: &VCpuProgress) {
+        #[cfg(target_arch = "aarch64")]
+        {
+            let retired =
+                crate::hal::arch::pmu::read_counter(self.counter).wrapping_sub(self.start);
+            progress
+                .instructions
+                .fetch_add(retired as u64, Ordering::Relaxed);
+        }
+        #[cfg(not(target_arch = "aarch64"))]
+        let _ = progress;
+        self.guard = None;
+    }
+}
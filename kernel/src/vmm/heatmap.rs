@@ -0,0 +1,394 @@
+//! Sampling of the hot guest RAM of a VM, for sizing balloons, swap and cache colors.
+//!
+//! A VM with a `[heatmap]` section has its RAM cut into cells of `granule` bytes, whose write heat
+//! is sampled every `interval_ms`:
+//!
+//! ```toml
+//! [heatmap]
+//! # Bytes of guest RAM per cell, a multiple of 4 KB.
+//! granule = 0x20_0000
+//! interval_ms = 1000
+//! ```
+//!
+//! The stage-2 page tables have no access and dirty bits the hypervisor can rely on on every
+//! architecture, so the sampler protects them itself, as dirty tracking does (see
+//! [`crate::vmm::dirty`]): every interval, one page of each cell is mapped read-only, the page
+//! changing from an interval to the next. The first write of the guest to it faults, marks the
+//! cell written and maps the page writable again. The heat of a cell decays by a quarter each
+//! interval and rises when its sampled page was written, so it follows the share of the cell the
+//! guest keeps writing, with a fault per cell and interval at most. Reads are not sampled: the
+//! hypervisor reads guest memory through the stage-2 too, so pages can't be made inaccessible.
+//!
+//! The sampling splits the block mappings of guest RAM into pages (see [`crate::vmm::blocks`]),
+//! and is refused with passthrough DMA, for the reasons of dirty tracking, with which it can't be
+//! combined. Only the regions allocated at VM creation are sampled, and only while the VM runs.
+//!
+//! A manager VM gets the heatmap of another VM with [`HVC_HEATMAP_QUERY`], as a
+//! [`HeatmapHeader`] followed by a byte of heat per cell, downsampled to fit its buffer.
+//!
+//! [`HVC_HEATMAP_QUERY`]: crate::vmm::hvc::HVC_HEATMAP_QUERY
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::time::Duration;
+use std::os::arceos::modules::axhal;
+use std::thread;
+
+use axaddrspace::{AxMmHal, GuestPhysAddr, HostPhysAddr, MappingFlags};
+use axerrno::{AxResult, ax_err, ax_err_type};
+use axvm::VMStatus;
+use memory_addr::PAGE_SIZE_4K;
+use spin::{Mutex, Once};
+
+use crate::hal::AxMmHalImpl;
+use crate::vmm::blocks::{self, RAM_FLAGS};
+use crate::vmm::dirty::{self, remap};
+use crate::vmm::hvc::{self, HVC_HEATMAP_QUERY, HvcService};
+use crate::vmm::introspect;
+use crate::vmm::security::Introspection;
+use crate::vmm::{VMRef, iommu, stage2, vm_list};
+
+/// Interval between two checks of the sampler.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Cells of a heatmap, at most.
+pub const MAX_HEATMAP_CELLS: usize = 0x10000;
+/// `magic` of [`HeatmapHeader`].
+pub const HEATMAP_MAGIC: u32 = u32::from_le_bytes(*b"AXHM");
+/// Version of the layout of [`HeatmapHeader`].
+pub const HEATMAP_VERSION: u16 = 1;
+/// Heat added to a cell written in an interval.
+const WRITE_HEAT: u8 = 64;
+
+/// Header of a heatmap written by [`HVC_HEATMAP_QUERY`](crate::vmm::hvc::HVC_HEATMAP_QUERY),
+/// followed by `cells` bytes of heat, from 0 (cold) to 255.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HeatmapHeader {
+    pub magic: u32,
+    pub version: u16,
+    pub _reserved: u16,
+    pub cells: u32,
+    /// Intervals sampled so far.
+    pub intervals: u32,
+    /// Bytes of guest RAM per cell, after downsampling.
+    pub granule: u64,
+    /// GPA of the first cell.
+    pub base_gpa: u64,
+}
+
+/// A sampled region of guest RAM.
+struct SampledRegion {
+    gpa: usize,
+    hpa: HostPhysAddr,
+    size: usize,
+}
+
+struct VmHeatmap {
+    granule: usize,
+    interval_ns: u64,
+    next_ns: u64,
+    base: usize,
+    regions: Vec<SampledRegion>,
+    heat: Vec<u8>,
+    /// Page of each cell protected in the current interval, if any.
+    armed: Vec<Option<usize>>,
+    /// Whether the guest wrote the protected page of each cell in the current interval.
+    written: Vec<bool>,
+    intervals: u64,
+    /// Write faults of the guest on protected pages.
+    faults: u64,
+}
+
+impl VmHeatmap {
+    fn hpa_of(&self, page: usize) -> Option<HostPhysAddr> {
+        self.regions
+            .iter()
+            .find(|region| (region.gpa..region.gpa + region.size).contains(&page))
+            .map(|region| region.hpa + (page - region.gpa))
+    }
+}
+
+/// Heatmaps of the VMs, indexed by VM ID.
+static HEATMAPS: Mutex<BTreeMap<usize, VmHeatmap>> = Mutex::new(BTreeMap::new());
+static SAMPLER_THREAD: Once = Once::new();
+
+/// Heatmap of a VM, for monitoring.
+#[derive(Debug, Clone, Copy)]
+pub struct HeatmapStats {
+    pub granule: usize,
+    pub cells: usize,
+    /// Cells with some heat.
+    pub warm: usize,
+    pub intervals: u64,
+    pub faults: u64,
+}
+
+/// The heatmap hypercall, for manager VMs.
+pub static HVC_SERVICE: HvcService = HvcService {
+    name: "heatmap",
+    codes: HVC_HEATMAP_QUERY..HVC_HEATMAP_QUERY + 1,
+    permit: hvc::permit_managers,
+    deferrable: false,
+    handler: |vm, _, _, args| query(vm, args),
+};
+
+/// Starts sampling the heat of the RAM of the VM if its config has a `[heatmap]` section.
+///
+/// Does nothing if the VM config has no `[heatmap]` section. Must run after the passthrough
+/// devices are set up.
+pub fn setup_vm_heatmap(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("heatmap").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let granule = match cfg.get("granule").map(|v| v.as_integer()) {
+        None => stage2::BLOCK_2M,
+        Some(Some(granule)) if granule > 0 && granule as usize % PAGE_SIZE_4K == 0 => {
+            granule as usize
+        }
+        Some(_) => {
+            return ax_err!(
+                InvalidInput,
+                "heatmap config: `granule` must be a multiple of 4 KB"
+            );
+        }
+    };
+    let interval_ms = cfg
+        .get("interval_ms")
+        .map_or(Some(1000), |v| v.as_integer())
+        .filter(|interval_ms| *interval_ms > 0)
+        .ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                "heatmap config: `interval_ms` must be positive"
+            )
+        })?;
+    if iommu::has_domain(vm.id()) {
+        return ax_err!(
+            Unsupported,
+            "heatmap config: the VM has passthrough DMA, its writes can't be sampled"
+        );
+    }
+    if dirty::is_tracking(vm.id()) {
+        return ax_err!(
+            ResourceBusy,
+            "heatmap config: the dirty pages of the VM are tracked"
+        );
+    }
+
+    let mut regions: Vec<_> = vm
+        .memory_regions()
+        .iter()
+        .map(|region| SampledRegion {
+            gpa: region.gpa.as_usize(),
+            hpa: AxMmHalImpl::virt_to_phys(region.hva),
+            size: region.size(),
+        })
+        .collect();
+    regions.sort_by_key(|region| region.gpa);
+    let (Some(first), Some(last)) = (regions.first(), regions.last()) else {
+        return ax_err!(InvalidInput, "heatmap config: the VM has no RAM");
+    };
+    let base = first.gpa / granule * granule;
+    let cells = (last.gpa + last.size - base).div_ceil(granule);
+    if cells > MAX_HEATMAP_CELLS {
+        return ax_err!(
+            InvalidInput,
+            format!(
+                "heatmap config: {} cells, at most {}, the granule is too small",
+                cells, MAX_HEATMAP_CELLS
+            )
+        );
+    }
+    for region in &regions {
+        blocks::split_blocks(vm, GuestPhysAddr::from(region.gpa), region.size)?;
+    }
+
+    info!(
+        "VM[{}] heatmap: {} cells of {:#x} bytes from {:#x}, every {} ms",
+        vm.id(),
+        cells,
+        granule,
+        base,
+        interval_ms
+    );
+    HEATMAPS.lock().insert(
+        vm.id(),
+        VmHeatmap {
+            granule,
+            interval_ns: interval_ms as u64 * 1_000_000,
+            next_ns: 0,
+            base,
+            regions,
+            heat: vec![0; cells],
+            armed: vec![None; cells],
+            written: vec![false; cells],
+            intervals: 0,
+            faults: 0,
+        },
+    );
+    SAMPLER_THREAD.call_once(|| {
+        thread::spawn(|| {
+            loop {
+                thread::sleep(POLL_INTERVAL);
+                sample_all();
+            }
+        });
+    });
+    Ok(())
+}
+
+/// Stops sampling the VM, called when the VM is destroyed.
+pub fn teardown_vm_heatmap(vm_id: usize) {
+    HEATMAPS.lock().remove(&vm_id);
+}
+
+/// Whether the heat of the RAM of VM `vm_id` is sampled.
+pub fn is_sampling(vm_id: usize) -> bool {
+    HEATMAPS.lock().contains_key(&vm_id)
+}
+
+fn sample_all() {
+    let now = axhal::time::monotonic_time_nanos();
+    let mut heatmaps = HEATMAPS.lock();
+    for (&vm_id, heatmap) in heatmaps.iter_mut() {
+        let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+            continue;
+        };
+        if vm.vm_status() != VMStatus::Running || now < heatmap.next_ns {
+            continue;
+        }
+        heatmap.next_ns = now + heatmap.interval_ns;
+        if let Err(err) = sample_vm(&vm, heatmap) {
+            warn!("VM[{}] heatmap sampling failed: {:?}", vm_id, err);
+        }
+    }
+}
+
+/// Ends the current interval of `heatmap`, updating the heat of its cells, and protects the pages
+/// sampled in the next one.
+fn sample_vm(vm: &VMRef, heatmap: &mut VmHeatmap) -> AxResult {
+    let pages_per_cell = heatmap.granule / PAGE_SIZE_4K;
+    let round = heatmap.intervals as usize;
+    for cell in 0..heatmap.heat.len() {
+        if let Some(page) = heatmap.armed[cell].take() {
+            let written = core::mem::take(&mut heatmap.written[cell]);
+            if !written && let Some(hpa) = heatmap.hpa_of(page) {
+                remap(vm, page, hpa, PAGE_SIZE_4K, RAM_FLAGS)?;
+            }
+            let heat = heatmap.heat[cell];
+            let gain = if written { WRITE_HEAT } else { 0 };
+            heatmap.heat[cell] = (heat - heat / 4).saturating_add(gain);
+        }
+
+        // Another page every interval, scattered over the cell.
+        let idx = round.wrapping_mul(0x9e37).wrapping_add(cell) % pages_per_cell;
+        let page = heatmap.base + cell * heatmap.granule + idx * PAGE_SIZE_4K;
+        if let Some(hpa) = heatmap.hpa_of(page) {
+            remap(vm, page, hpa, PAGE_SIZE_4K, RAM_FLAGS - MappingFlags::WRITE)?;
+            heatmap.armed[cell] = Some(page);
+        }
+    }
+    heatmap.intervals += 1;
+    Ok(())
+}
+
+/// Handles a stage-2 fault of the VM at `gpa` with `access`.
+///
+/// Returns false if it's not a write to a sampled page, in which case the fault is for someone
+/// else.
+pub fn handle_fault(vm: &VMRef, gpa: GuestPhysAddr, access: MappingFlags) -> AxResult<bool> {
+    if !access.contains(MappingFlags::WRITE) {
+        return Ok(false);
+    }
+    let mut heatmaps = HEATMAPS.lock();
+    let Some(heatmap) = heatmaps.get_mut(&vm.id()) else {
+        return Ok(false);
+    };
+    let page = gpa.as_usize() & !(PAGE_SIZE_4K - 1);
+    let Some(cell) = page
+        .checked_sub(heatmap.base)
+        .map(|offset| offset / heatmap.granule)
+        .filter(|cell| heatmap.armed.get(*cell).copied().flatten() == Some(page))
+    else {
+        return Ok(false);
+    };
+    let Some(hpa) = heatmap.hpa_of(page) else {
+        return Ok(false);
+    };
+    // Another vCPU may have faulted on the page meanwhile, mapping it again is harmless.
+    remap(vm, page, hpa, PAGE_SIZE_4K, RAM_FLAGS)?;
+    heatmap.written[cell] = true;
+    heatmap.faults += 1;
+    Ok(true)
+}
+
+/// Handles the `HHeatmapQuery` hypercall of the manager `vm`: `args[0]` is the peer handle of the
+/// sampled VM, `args[1]` and `args[2]` the GPA and size of the buffer and `args[3]` the number of
+/// cells merged into one, the hottest winning, 0 to fit the buffer. Returns the cells written.
+fn query(vm: &VMRef, args: [u64; 6]) -> AxResult<usize> {
+    let target = introspect::target_vm(vm, args[0], Introspection::Read)?;
+    let header_size = size_of::<HeatmapHeader>();
+    let size = hvc::arg_count(
+        args[2],
+        header_size + MAX_HEATMAP_CELLS,
+        "heatmap buffer size",
+    )?;
+    if size <= header_size {
+        return ax_err!(
+            InvalidInput,
+            format!("heatmap buffer of {} bytes is too small", size)
+        );
+    }
+    let buf = hvc::guest_range(vm, args[1], size, align_of::<HeatmapHeader>())?;
+    let merge = hvc::arg_count(args[3], MAX_HEATMAP_CELLS, "heatmap merge factor")?;
+
+    let (header, cells) = {
+        let heatmaps = HEATMAPS.lock();
+        let Some(heatmap) = heatmaps.get(&target.id()) else {
+            return ax_err!(
+                NotFound,
+                format!("VM[{}] has no heatmap", target.id())
+            );
+        };
+        let merge = merge
+            .max(heatmap.heat.len().div_ceil(size - header_size))
+            .max(1);
+        let cells: Vec<u8> = heatmap
+            .heat
+            .chunks(merge)
+            .map(|chunk| chunk.iter().copied().max().unwrap_or(0))
+            .collect();
+        let header = HeatmapHeader {
+            magic: HEATMAP_MAGIC,
+            version: HEATMAP_VERSION,
+            _reserved: 0,
+            cells: cells.len() as u32,
+            intervals: heatmap.intervals as u32,
+            granule: (heatmap.granule * merge) as u64,
+            base_gpa: heatmap.base as u64,
+        };
+        (header, cells)
+    };
+    vm.write_to_guest_of(buf, &header)?;
+    for (i, heat) in cells.iter().enumerate() {
+        vm.write_to_guest_of(buf + header_size + i, heat)?;
+    }
+    debug!(
+        "VM[{}] reads the heatmap of VM[{}], {} cells",
+        vm.id(),
+        target.id(),
+        cells.len()
+    );
+    Ok(cells.len())
+}
+
+/// Returns the heatmap of VM `vm_id`, `None` if it's not sampled.
+pub fn heatmap_stats(vm_id: usize) -> Option<HeatmapStats> {
+    HEATMAPS.lock().get(&vm_id).map(|heatmap| HeatmapStats {
+        granule: heatmap.granule,
+        cells: heatmap.heat.len(),
+        warm: heatmap.heat.iter().filter(|heat| **heat > 0).count(),
+        intervals: heatmap.intervals,
+        faults: heatmap.faults,
+    })
+}
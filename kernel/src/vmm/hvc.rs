@@ -1,12 +1,387 @@
-use axaddrspace::{GuestPhysAddr, MappingFlags};
+use alloc::vec::Vec;
+use core::mem::{align_of, size_of};
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+use std::os::arceos::modules::axtask;
+
+use axaddrspace::GuestPhysAddr;
 use axerrno::{AxResult, ax_err, ax_err_type};
 use axhvc::{HyperCallCode, HyperCallResult};
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::task::AsVCpuTask;
+use crate::vmm::ivc::{self, IVCChannel, IVCNotifyMode};
+use crate::vmm::{VCpuRef, VMRef, blocks, iommu, peers, security, stage2, vmdef};
+
+/// Base of the hypercall numbers handled by axvisor itself on a fast path, without going through
+/// [`HyperCallCode`].
+pub const AXVISOR_FAST_HVC_BASE: u64 = 0x1000_0000;
+/// Rings a pre-registered doorbell, `args[0]` is the doorbell ID, see [`crate::vmm::doorbell`].
+/// Like [`HVC_IVC_KICK`], returns [`IRQ_THROTTLED`] past the interrupt rate of the caller, see
+/// [`crate::vmm::irqpolicy`].
+///
+/// [`IRQ_THROTTLED`]: crate::vmm::irqpolicy::IRQ_THROTTLED
+pub const HVC_RT_DOORBELL: u64 = AXVISOR_FAST_HVC_BASE;
+/// Kicks the peer on a ring IVC channel in kick or watermark mode, `args[0]` is the channel
+/// handle of the caller. See [`crate::vmm::ivc`].
+pub const HVC_IVC_KICK: u64 = AXVISOR_FAST_HVC_BASE + 1;
+/// Begins (`args[1] == 0`) or commits (`args[1] == 1`) an update of the broadcast IVC channel
+/// published by the caller, `args[0]` is its channel handle. See [`crate::vmm::ivc`].
+pub const HVC_IVC_BROADCAST: u64 = AXVISOR_FAST_HVC_BASE + 2;
+/// Kicks the watchdog of the caller (`HWatchdogKick`). See [`crate::vmm::watchdog`].
+pub const HVC_WATCHDOG_KICK: u64 = AXVISOR_FAST_HVC_BASE + 3;
+/// Defines a VM at runtime (`HVmDefine`), `args[0]` and `args[1]` are the GPA and size of the
+/// definition and `args[2]` its format. Only allowed to manager VMs, see [`crate::vmm::vmdef`].
+pub const HVC_VM_DEFINE: u64 = AXVISOR_FAST_HVC_BASE + 4;
+/// Sets the physical CPU mask of a vCPU (`HVCpuSetAffinity`), `args[0]` is the peer handle of the
+/// VM, `args[1]` the vCPU ID and `args[2]` the mask. See [`crate::vmm::affinity`].
+pub const HVC_VCPU_SET_AFFINITY: u64 = AXVISOR_FAST_HVC_BASE + 5;
+/// Sets the CPU share of a VM (`HVmSetShares`), `args[0]` is the peer handle of the VM (or
+/// [`AFFINITY_SELF`](crate::vmm::affinity::AFFINITY_SELF)), `args[1]` its weight and `args[2]` its
+/// cap in percent of a CPU. Only allowed to manager VMs, see [`crate::vmm::sched`].
+pub const HVC_VM_SET_SHARES: u64 = AXVISOR_FAST_HVC_BASE + 6;
+/// Sets the trace context of the calling vCPU or takes the one forwarded to its VM, `args[0]` is
+/// the operation. See [`crate::vmm::tracectx`].
+pub const HVC_TRACE_CONTEXT: u64 = AXVISOR_FAST_HVC_BASE + 7;
+/// Exports the hypervisor trace records to the raw IVC channel `args[0]`
+/// published by the caller, `args[1]` is the peer handle of the VM traced and `args[2]` a mask of
+/// event classes. Only allowed to manager VMs, see [`crate::vmm::trace`].
+pub const HVC_TRACE_EXPORT: u64 = AXVISOR_FAST_HVC_BASE + 8;
+/// Queries the hypervisor statistics of a VM (`HStatsQuery`), `args[0]` is the peer handle of the
+/// VM (or [`STATS_SELF`](crate::vmm::stats::STATS_SELF)), `args[1]` and `args[2]` the GPA and
+/// size of the buffer they are written to. Returns the number of bytes written, only manager VMs
+/// may query other VMs. See [`crate::vmm::stats`].
+pub const HVC_STATS_QUERY: u64 = AXVISOR_FAST_HVC_BASE + 9;
+/// Reports the caller ready (`HVmReady`), appending a ready event to the lifecycle log. See
+/// [`crate::vmm::lifecycle`].
+pub const HVC_VM_READY: u64 = AXVISOR_FAST_HVC_BASE + 10;
+/// Shuts down the whole system (`HSystemShutdown`), `args[0]` is the grace period of the guests
+/// in seconds, 0 for the configured one. Only allowed to manager VMs, see
+/// [`crate::vmm::shutdown`].
+pub const HVC_SYSTEM_SHUTDOWN: u64 = AXVISOR_FAST_HVC_BASE + 11;
+/// Plugs a virtio device into a running VM or unplugs one (`HVirtioHotplug`), `args[0]` is the
+/// peer handle of the VM and `args[1]` the operation. Returns the slot of a plugged device, only
+/// allowed to manager VMs. See [`crate::vmm::virtio::handle_hotplug`].
+pub const HVC_VIRTIO_HOTPLUG: u64 = AXVISOR_FAST_HVC_BASE + 12;
+/// Reports the state of the filesystem quiesce agent of the caller (`HFsQuiesce`), `args[0]` is
+/// the operation and `args[1]` its error, if any. See [`crate::vmm::quiesce`].
+pub const HVC_FS_QUIESCE: u64 = AXVISOR_FAST_HVC_BASE + 13;
+/// Blocks the calling vCPU until the ring it produces on a ring IVC channel has room
+/// (`HIVCWaitSpace`), `args[0]` is the channel handle of the caller, `args[1]` the number of
+/// slots and `args[2]` the timeout in nanoseconds. Returns the free slots, 0 on timeout. See
+/// [`crate::vmm::ivc`].
+pub const HVC_IVC_WAIT_SPACE: u64 = AXVISOR_FAST_HVC_BASE + 14;
+/// Completes a hypercall forwarded to the caller (`HForwardComplete`), `args[0]` is its slot in
+/// the request page. Only allowed to hypercall handler VMs, see [`crate::vmm::unknown_hvc`].
+pub const HVC_FORWARD_COMPLETE: u64 = AXVISOR_FAST_HVC_BASE + 15;
+/// Attaches the caller to the consoles of all the other VMs (`HConsoleAttach`), `args[0]` is the
+/// key of the raw IVC channel receiving them, or
+/// [`CONSOLE_DETACH`](crate::vmm::conmux::CONSOLE_DETACH). Only allowed to manager VMs, see
+/// [`crate::vmm::conmux`].
+pub const HVC_CONSOLE_ATTACH: u64 = AXVISOR_FAST_HVC_BASE + 16;
+/// Calls a deferrable hypercall without waiting for it (`HCallAsync`), `args[0]` is its number,
+/// `args[1]` the vector raised when it completes and `args[2..6]` its arguments. Returns the
+/// token of the call, see [`crate::vmm::deferred`].
+pub const HVC_CALL_ASYNC: u64 = AXVISOR_FAST_HVC_BASE + 17;
+/// Gets the result of an asynchronous call (`HGetResult`), `args[0]` is its token and `args[1]`
+/// the GPA of the [`AsyncResult`](crate::vmm::deferred::AsyncResult) written. Returns the state
+/// of the call, see [`crate::vmm::deferred`].
+pub const HVC_GET_RESULT: u64 = AXVISOR_FAST_HVC_BASE + 18;
+/// Copies memory of another VM to the caller (`HVMReadGuestMem`), `args[0]` is the peer handle of
+/// the VM, `args[1]` the GPA in the VM, `args[2]` the GPA in the caller and `args[3]` the size.
+/// Only allowed to manager VMs, see [`crate::vmm::introspect`].
+pub const HVC_VM_READ_GUEST_MEM: u64 = AXVISOR_FAST_HVC_BASE + 19;
+/// Copies memory of the caller to another VM (`HVMWriteGuestMem`), with the arguments of
+/// [`HVC_VM_READ_GUEST_MEM`]. Only allowed to manager VMs, see [`crate::vmm::introspect`].
+pub const HVC_VM_WRITE_GUEST_MEM: u64 = AXVISOR_FAST_HVC_BASE + 20;
+/// Gets the state and registers of a vCPU of another VM (`HVMGetVcpuRegs`), `args[0]` is the peer
+/// handle of the VM, `args[1]` the vCPU and `args[2]` the GPA of the
+/// [`VCpuRegs`](crate::vmm::introspect::VCpuRegs) written. Only allowed to manager VMs, see
+/// [`crate::vmm::introspect`].
+pub const HVC_VM_GET_VCPU_REGS: u64 = AXVISOR_FAST_HVC_BASE + 21;
+/// Gets the heatmap of the RAM of another VM (`HHeatmapQuery`), `args[0]` is the peer handle of
+/// the VM, `args[1]` and `args[2]` the GPA and size of the buffer and `args[3]` the number of
+/// cells merged into one. Returns the cells written. Only allowed to manager VMs, see
+/// [`crate::vmm::heatmap`].
+pub const HVC_HEATMAP_QUERY: u64 = AXVISOR_FAST_HVC_BASE + 22;
+/// Controls the introspection events of another VM (`HVMIControl`), `args[0]` is the peer
+/// handle of the VM and `args[1]` the operation. Only allowed to manager VMs, see
+/// [`crate::vmm::vmi`].
+pub const HVC_VMI_CONTROL: u64 = AXVISOR_FAST_HVC_BASE + 23;
+/// Emulates guest physical ranges of another VM (`HIoreqControl`), `args[0]` is the peer handle of
+/// the VM and `args[1]` the operation. Only allowed to manager VMs, see [`crate::vmm::ioreq`].
+pub const HVC_IOREQ_CONTROL: u64 = AXVISOR_FAST_HVC_BASE + 24;
+/// Serves a virtio device of another VM (`HVhostControl`), `args[0]` is the peer handle of the
+/// VM, `args[1]` the operation and `args[2]` the index of the device. Only allowed to manager VMs,
+/// see [`crate::vmm::virtio`].
+pub const HVC_VHOST_CONTROL: u64 = AXVISOR_FAST_HVC_BASE + 25;
+/// Shows a buffer of the framebuffer of the caller (`HFbFlip`), `args[0]` is the index of the
+/// buffer. See [`crate::vmm::framebuffer`].
+pub const HVC_FB_FLIP: u64 = AXVISOR_FAST_HVC_BASE + 26;
+/// Shows the framebuffer of another VM (`HFbControl`), `args[0]` is the peer handle of the VM and
+/// `args[1]` the operation. Only allowed to manager VMs, see [`crate::vmm::framebuffer`].
+pub const HVC_FB_CONTROL: u64 = AXVISOR_FAST_HVC_BASE + 27;
+/// Hands the shared UART to another VM (`HUartAssign`), `args[0]` is the peer handle of the VM.
+/// Only allowed to manager VMs, see [`crate::vmm::uartshare`].
+pub const HVC_UART_ASSIGN: u64 = AXVISOR_FAST_HVC_BASE + 28;
+/// Requests a performance level for the CPUs of the caller (`HCpufreqRequest`), `args[0]` is the
+/// level in percent, returns the level granted. See [`crate::vmm::cpufreq`].
+pub const HVC_CPUFREQ_REQUEST: u64 = AXVISOR_FAST_HVC_BASE + 29;
+/// Manages the VM groups (`HVmGroup`), `args[0]` is the operation. Only allowed to manager VMs, see
+/// [`crate::vmm::group`].
+pub const HVC_VM_GROUP: u64 = AXVISOR_FAST_HVC_BASE + 30;
+/// Streams a core dump of another VM to the caller (`HVmDump`), `args[0]` is the peer handle of
+/// the VM and `args[1]` the key of the raw IVC channel receiving it. Only allowed to manager VMs,
+/// see [`crate::vmm::coredump`].
+pub const HVC_VM_DUMP: u64 = AXVISOR_FAST_HVC_BASE + 31;
+
+/// Hypercalls of an optional subsystem, registered with [`register_service`] when the hypervisor
+/// starts instead of being dispatched by the vCPU loop itself.
+///
+/// The hypercalls handled by the vCPU loop directly, those blocking the vCPU or changing its
+/// state, and the [`HyperCallCode`]s implemented by [`HyperCall::execute`] take precedence over
+/// the services. The numbers no one handles are unknown hypercalls, see
+/// [`crate::vmm::unknown_hvc`].
+pub struct HvcService {
+    /// Name of the service, for the log.
+    pub name: &'static str,
+    /// Hypercall numbers of the service.
+    pub codes: Range<u64>,
+    /// Checks that the calling VM may use the service, e.g. [`permit_all`] or
+    /// [`permit_managers`].
+    pub permit: fn(&VMRef) -> AxResult,
+    /// Whether the hypercalls of the service may be called asynchronously, their handler then
+    /// runs on a worker task with the arguments 4 and 5 zeroed. See [`crate::vmm::deferred`].
+    pub deferrable: bool,
+    /// Handles hypercall `nr` of vCPU `vcpu_id` of the VM, returns its return value.
+    pub handler: fn(vm: &VMRef, vcpu_id: usize, nr: u64, args: [u64; 6]) -> AxResult<usize>,
+}
+
+/// The registered services.
+static SERVICES: Mutex<Vec<&'static HvcService>> = Mutex::new(Vec::new());
+
+/// Registers the hypercalls of a service, which must not overlap with those of the others.
+pub fn register_service(service: &'static HvcService) -> AxResult {
+    let mut services = SERVICES.lock();
+    if service.codes.is_empty() {
+        return ax_err!(InvalidInput, format!("{} has no hypercalls", service.name));
+    }
+    if let Some(other) = services.iter().find(|other| {
+        other.codes.start < service.codes.end && service.codes.start < other.codes.end
+    }) {
+        return ax_err!(
+            AlreadyExists,
+            format!(
+                "hypercalls {:#x?} of {} overlap with {}",
+                service.codes, service.name, other.name
+            )
+        );
+    }
+    debug!("Hypercall service {}: {:#x?}", service.name, service.codes);
+    services.push(service);
+    Ok(())
+}
+
+/// Returns the registered service handling hypercall `nr`, if any.
+pub fn service_of(nr: u64) -> Option<&'static HvcService> {
+    SERVICES
+        .lock()
+        .iter()
+        .find(|service| service.codes.contains(&nr))
+        .copied()
+}
+
+/// Returns whether a registered service handles hypercall `nr`.
+pub fn has_service(nr: u64) -> bool {
+    service_of(nr).is_some()
+}
+
+/// Handles hypercall `nr` of vCPU `vcpu_id` of `vm` with the service registered for it, if the
+/// service permits the VM. Failures are logged with the name of the service.
+pub fn call_service(vm: &VMRef, vcpu_id: usize, nr: u64, args: [u64; 6]) -> AxResult<usize> {
+    let service = service_of(nr)
+        .ok_or_else(|| ax_err_type!(NotFound, format!("no service for hypercall {:#x}", nr)))?;
+    (service.permit)(vm)
+        .and_then(|_| (service.handler)(vm, vcpu_id, nr, args))
+        .inspect_err(|e| {
+            warn!(
+                "VM[{}] {} hypercall {:#x} failed: {:?}",
+                vm.id(),
+                service.name,
+                nr,
+                e
+            )
+        })
+}
+
+/// Permission policy of a service any VM may use.
+pub fn permit_all(_vm: &VMRef) -> AxResult {
+    Ok(())
+}
+
+/// Permission policy of a service only manager VMs (see [`crate::vmm::vmdef`]) may use.
+pub fn permit_managers(vm: &VMRef) -> AxResult {
+    if !vmdef::is_manager(vm.id()) {
+        return ax_err!(
+            PermissionDenied,
+            format!("VM[{}] is not a manager VM", vm.id())
+        );
+    }
+    Ok(())
+}
+
+/// Handles the [`HVC_IVC_KICK`] hypercall of vCPU `vcpu_id` of VM `vm_id`.
+pub fn ivc_kick(vm_id: usize, vcpu_id: usize, args: [u64; 6]) -> AxResult {
+    ivc::kick(vm_id, vcpu_id, args[0])
+}
+
+/// Handles the [`HVC_IVC_BROADCAST`] hypercall of vCPU `vcpu_id` of VM `vm_id`.
+pub fn ivc_broadcast(vm_id: usize, vcpu_id: usize, args: [u64; 6]) -> AxResult {
+    let commit = match args[1] {
+        0 => false,
+        1 => true,
+        op => return ax_err!(InvalidInput, format!("invalid broadcast operation {}", op)),
+    };
+    ivc::broadcast_update(vm_id, vcpu_id, args[0], commit)
+}
+
+/// Handles the [`HVC_IVC_WAIT_SPACE`] hypercall of VM `vm_id`, `block` blocks the calling vCPU
+/// as for [`ivc::wait_space`].
+pub fn ivc_wait_space(vm_id: usize, args: [u64; 6], block: impl Fn(&AtomicBool)) -> AxResult<u32> {
+    let slots = u32::try_from(args[1])
+        .map_err(|_| ax_err_type!(InvalidInput, format!("invalid slot count {}", args[1])))?;
+    ivc::wait_space(vm_id, args[0], slots, args[2], block)
+}
+
+// Checks of the hypercall arguments, for the handlers which size a buffer with a count argument
+// or access the guest memory an argument points to: a bad argument fails the hypercall with
+// `InvalidInput` instead of reaching memory the VM doesn't own, such as its MMIO regions or IVC
+// windows.
+
+/// Returns the count argument `value` of a hypercall, checked to be at most `max`.
+pub fn arg_count(value: u64, max: usize, what: &str) -> AxResult<usize> {
+    usize::try_from(value)
+        .ok()
+        .filter(|count| *count <= max)
+        .ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                format!("invalid {} {}, at most {}", what, value, max)
+            )
+        })
+}
+
+/// Returns the guest physical address argument `gpa` of a hypercall of `vm`, checked to be
+/// aligned to `align` and followed by `size` bytes of a single RAM region of the VM.
+pub fn guest_range(vm: &VMRef, gpa: u64, size: usize, align: usize) -> AxResult<GuestPhysAddr> {
+    let start = usize::try_from(gpa)
+        .ok()
+        .filter(|start| start % align == 0)
+        .ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                format!("guest address {:#x} not aligned to {}", gpa, align)
+            )
+        })?;
+    let in_ram = start.checked_add(size).is_some_and(|end| {
+        vm.memory_regions().iter().any(|region| {
+            region.gpa.as_usize() <= start && end <= region.gpa.as_usize() + region.size()
+        })
+    });
+    if !in_ram {
+        return ax_err!(
+            InvalidInput,
+            format!(
+                "guest buffer {:#x} of {:#x} bytes outside of guest memory",
+                start, size
+            )
+        );
+    }
+    Ok(GuestPhysAddr::from_usize(start))
+}
+
+/// [`guest_range`] for an argument pointing to a `T`.
+pub fn guest_ptr<T>(vm: &VMRef, gpa: u64) -> AxResult<GuestPhysAddr> {
+    guest_range(vm, gpa, size_of::<T>(), align_of::<T>())
+}
+
+/// Returns whether hypercall `code` is a [`HyperCallCode`] implemented by
+/// [`HyperCall::execute`]. The others are handled as [`crate::vmm::unknown_hvc`] says.
+pub fn is_implemented(code: u64) -> bool {
+    matches!(
+        u32::try_from(code).map(HyperCallCode::try_from),
+        Ok(Ok(HyperCallCode::HIVCPublishChannel
+            | HyperCallCode::HIVCUnPublishChannel
+            | HyperCallCode::HIVCSubscribChannel
+            | HyperCallCode::HIVCUnSubscribChannel))
+    )
+}
+
+/// Hypercalls a vCPU may be in at once.
+///
+/// Hypercalls don't nest: the backends of [`HyperCall::execute`] never call back into it, and a
+/// vCPU is in at most one hypercall. A hypercall completing asynchronously returns from
+/// [`HyperCall::execute`] before it waits, and its completion is delivered by the vCPU loop or
+/// by another task, outside of any hypercall of the vCPU. Hypercalls blocking the vCPU, such as
+/// [`HVC_IVC_WAIT_SPACE`], are handled by the vCPU loop and don't go through
+/// [`HyperCall::execute`] either.
+pub const MAX_HYPERCALL_DEPTH: u8 = 1;
+
+/// Hypercalls refused because their vCPU was already in one, since boot.
+static REENTRIES: AtomicU64 = AtomicU64::new(0);
+
+/// Number of hypercalls refused because their vCPU was already in [`MAX_HYPERCALL_DEPTH`] ones.
+/// Any is a hypervisor bug.
+pub fn reentries() -> u64 {
+    REENTRIES.load(Ordering::Relaxed)
+}
+
+/// Hypercall nesting of a vCPU, kept in its task, see [`MAX_HYPERCALL_DEPTH`].
+pub struct HyperCallNesting {
+    depth: AtomicU8,
+}
+
+impl HyperCallNesting {
+    pub const fn new() -> Self {
+        Self {
+            depth: AtomicU8::new(0),
+        }
+    }
+
+    /// Enters a hypercall, left when the guard is dropped. Re-entering past
+    /// [`MAX_HYPERCALL_DEPTH`] panics in debug builds and fails with `BadState` otherwise.
+    fn enter(
+        &self,
+        vm_id: usize,
+        vcpu_id: usize,
+        code: &HyperCallCode,
+    ) -> AxResult<NestingGuard<'_>> {
+        let depth = self.depth.fetch_add(1, Ordering::Acquire);
+        // Dropped on failure too, restoring the depth.
+        let guard = NestingGuard(&self.depth);
+        if depth >= MAX_HYPERCALL_DEPTH {
+            REENTRIES.fetch_add(1, Ordering::Relaxed);
+            error!(
+                "VM[{vm_id}] VCpu[{vcpu_id}] re-entered the hypercall path with {code:?} at depth {depth}"
+            );
+            debug_assert!(false, "hypercall reentry of VM[{vm_id}] VCpu[{vcpu_id}]");
+            return ax_err!(BadState, "hypercall reentry");
+        }
+        Ok(guard)
+    }
+}
 
-use crate::vmm::ivc::{self, IVCChannel};
-use crate::vmm::{VCpuRef, VMRef};
+struct NestingGuard<'a>(&'a AtomicU8);
+
+impl Drop for NestingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Release);
+    }
+}
 
 pub struct HyperCall {
-    _vcpu: VCpuRef,
+    vcpu: VCpuRef,
     vm: VMRef,
     code: HyperCallCode,
     args: [u64; 6],
@@ -20,70 +395,126 @@ impl HyperCall {
         })?;
 
         Ok(Self {
-            _vcpu: vcpu,
+            vcpu,
             vm,
             code,
             args,
         })
     }
 
+    /// Executes the hypercall on the current vCPU task, at most [`MAX_HYPERCALL_DEPTH`] deep.
     pub fn execute(&self) -> HyperCallResult {
+        let curr = axtask::current();
+        let _nesting = curr.as_vcpu_task().hypercall_nesting.enter(
+            self.vm.id(),
+            self.vcpu.id(),
+            &self.code,
+        )?;
+        self.dispatch()
+    }
+
+    fn dispatch(&self) -> HyperCallResult {
         match self.code {
             HyperCallCode::HIVCPublishChannel => {
                 let key = self.args[0] as usize;
                 let shm_base_gpa_ptr = GuestPhysAddr::from_usize(self.args[1] as usize);
                 let shm_size_ptr = GuestPhysAddr::from_usize(self.args[2] as usize);
+                // 0 for a raw channel, `IVC_CHANNEL_BROADCAST` for a broadcast channel, or the
+                // `IVCNotifyMode` of a ring channel of `args[4]`-byte slots. In watermark mode,
+                // bits 32-47 and 48-63 of `args[4]` are the high and low watermarks in slots.
+                let channel_type = self.args[3];
 
                 info!(
-                    "VM[{}] HyperCall {:?} key {:#x}",
+                    "VM[{}] HyperCall {:?} key {:#x} type {}",
                     self.vm.id(),
                     self.code,
-                    key
+                    key,
+                    channel_type
                 );
+                let notify = match channel_type {
+                    0 | ivc::IVC_CHANNEL_BROADCAST => None,
+                    mode => Some(IVCNotifyMode::try_from(mode).map_err(|_| {
+                        ax_err_type!(InvalidInput, format!("invalid IVC channel type {}", mode))
+                    })?),
+                };
                 // User will pass the size of the shared memory region,
                 // we will allocate the shared memory region based on this size.
                 let shm_region_size = self.vm.read_from_guest_of::<usize>(shm_size_ptr)?;
-                let (shm_base_gpa, shm_region_size) = self.vm.alloc_ivc_channel(shm_region_size)?;
+                // A broadcast channel is one data page followed by its header page.
+                let shm_region_size = if channel_type == ivc::IVC_CHANNEL_BROADCAST {
+                    2 * PAGE_SIZE_4K
+                } else {
+                    shm_region_size
+                };
+                let (window, offset, shm_region_size) =
+                    security::alloc_ivc_channel(&self.vm, shm_region_size)?;
+                let shm_base_gpa = window + offset;
 
-                let ivc_channel =
-                    IVCChannel::alloc(self.vm.id(), key, shm_region_size, shm_base_gpa)?;
+                let mut ivc_channel = match notify {
+                    None if channel_type == ivc::IVC_CHANNEL_BROADCAST => {
+                        IVCChannel::alloc_broadcast(self.vm.id(), key, shm_base_gpa)?
+                    }
+                    None => IVCChannel::alloc(self.vm.id(), key, shm_region_size, shm_base_gpa)?,
+                    Some(notify) => IVCChannel::alloc_ring(
+                        self.vm.id(),
+                        key,
+                        shm_region_size,
+                        shm_base_gpa,
+                        notify,
+                        self.args[4] as u32 as usize,
+                        (
+                            (self.args[4] >> 32) as u16 as u32,
+                            (self.args[4] >> 48) as u16 as u32,
+                        ),
+                    )?,
+                };
+                // Vector the publisher is notified with, 0 for none.
+                ivc_channel.set_notify_vector(self.vm.id(), self.args[5] as usize);
 
                 let actual_size = ivc_channel.size();
 
-                self.vm.map_region(
-                    shm_base_gpa,
-                    ivc_channel.base_hpa(),
-                    actual_size,
-                    MappingFlags::READ | MappingFlags::WRITE,
-                )?;
+                blocks::split_blocks(&self.vm, shm_base_gpa, actual_size)?;
+                for mapping in ivc_channel.mappings(self.vm.id()) {
+                    stage2::map_region(
+                        &self.vm,
+                        shm_base_gpa + mapping.offset,
+                        mapping.hpa,
+                        mapping.size,
+                        mapping.flags,
+                    )?;
+                }
 
                 self.vm
                     .write_to_guest_of(shm_base_gpa_ptr, &shm_base_gpa.as_usize())?;
                 self.vm.write_to_guest_of(shm_size_ptr, &actual_size)?;
 
-                ivc::insert_channel(self.vm.id(), ivc_channel)?;
+                // The channel handle of the publisher.
+                let handle = ivc::insert_channel(self.vm.id(), ivc_channel)?;
 
-                Ok(0)
+                Ok(handle as usize)
             }
             HyperCallCode::HIVCUnPublishChannel => {
-                let key = self.args[0] as usize;
+                let handle = self.args[0];
 
                 info!(
-                    "VM[{}] HyperCall {:?} with key {:#x}",
+                    "VM[{}] HyperCall {:?} with handle {:#x}",
                     self.vm.id(),
                     self.code,
-                    key
+                    handle
                 );
-                let (base_gpa, size) = ivc::unpublish_channel(self.vm.id(), key)?.unwrap();
-                self.vm.unmap_region(base_gpa, size)?;
+                let (base_gpa, size) = ivc::unpublish_channel(self.vm.id(), handle)?.unwrap();
+                iommu::unmap_region(&self.vm, base_gpa, size)?;
 
                 Ok(0)
             }
             HyperCallCode::HIVCSubscribChannel => {
-                let publisher_vm_id = self.args[0] as usize;
+                // Guests name the publisher by its peer handle.
+                let publisher_vm_id = peers::resolve(self.vm.id(), self.args[0] as usize)?;
                 let key = self.args[1] as usize;
                 let shm_base_gpa_ptr = GuestPhysAddr::from_usize(self.args[2] as usize);
                 let shm_size_ptr = GuestPhysAddr::from_usize(self.args[3] as usize);
+                // Vector the subscriber is notified with, 0 for none.
+                let notify_vector = self.args[4] as usize;
 
                 info!(
                     "VM[{}] HyperCall {:?} to VM[{}]",
@@ -93,49 +524,55 @@ impl HyperCall {
                 );
 
                 let shm_size = ivc::get_channel_size(publisher_vm_id, key)?;
-                let (shm_base_gpa, _) = self.vm.alloc_ivc_channel(shm_size)?;
+                let (window, offset, _) = security::alloc_ivc_channel(&self.vm, shm_size)?;
+                let shm_base_gpa = window + offset;
 
-                let (base_hpa, actual_size) = ivc::subscribe_to_channel_of_publisher(
+                let (mappings, actual_size, handle) = ivc::subscribe_to_channel_of_publisher(
                     publisher_vm_id,
                     key,
                     self.vm.id(),
                     shm_base_gpa,
+                    notify_vector,
                 )?;
 
-                // TODO: seperate the mapping flags of metadata and data.
-                self.vm.map_region(
-                    shm_base_gpa,
-                    base_hpa,
-                    actual_size,
-                    MappingFlags::READ | MappingFlags::WRITE,
-                )?;
+                blocks::split_blocks(&self.vm, shm_base_gpa, actual_size)?;
+                for mapping in mappings {
+                    stage2::map_region(
+                        &self.vm,
+                        shm_base_gpa + mapping.offset,
+                        mapping.hpa,
+                        mapping.size,
+                        mapping.flags,
+                    )?;
+                }
 
                 self.vm
                     .write_to_guest_of(shm_base_gpa_ptr, &shm_base_gpa.as_usize())?;
                 self.vm.write_to_guest_of(shm_size_ptr, &actual_size)?;
 
                 info!(
-                    "VM[{}] HyperCall HIVC_REGISTER_SUBSCRIBER success, base GPA: {:#x}, size: {}",
+                    "VM[{}] HyperCall HIVC_REGISTER_SUBSCRIBER success, base GPA: {:#x}, size: {}, \
+                     handle: {:#x}",
                     self.vm.id(),
                     shm_base_gpa,
-                    actual_size
+                    actual_size,
+                    handle
                 );
 
-                Ok(0)
+                // The channel handle of the subscriber.
+                Ok(handle as usize)
             }
             HyperCallCode::HIVCUnSubscribChannel => {
-                let publisher_vm_id = self.args[0] as usize;
-                let key = self.args[1] as usize;
+                let handle = self.args[0];
 
                 info!(
-                    "VM[{}] HyperCall {:?} from VM[{}]",
+                    "VM[{}] HyperCall {:?} with handle {:#x}",
                     self.vm.id(),
                     self.code,
-                    publisher_vm_id
+                    handle
                 );
-                let (base_gpa, size) =
-                    ivc::unsubscribe_from_channel_of_publisher(publisher_vm_id, key, self.vm.id())?;
-                self.vm.unmap_region(base_gpa, size)?;
+                let (base_gpa, size) = ivc::unsubscribe_from_channel(handle, self.vm.id())?;
+                iommu::unmap_region(&self.vm, base_gpa, size)?;
 
                 Ok(0)
             }
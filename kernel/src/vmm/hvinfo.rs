@@ -0,0 +1,298 @@
+//! The read-only hypervisor information pages.
+//!
+//! A VM whose config has an `[hv_info]` section gets three read-only pages describing the
+//! hypervisor, itself and its services, so that its drivers can configure themselves without querying the
+//! hypervisor at boot:
+//!
+//! ```toml
+//! [hv_info]
+//! # Guest physical address of the pages, page aligned and outside of guest memory.
+//! gpa = 0x0900_0000
+//! ```
+//!
+//! The first page, [`HvInfo`], is the same physical page for all guests: the hypervisor version,
+//! the level of the guest ABI, the architecture and platform of the host. The second one,
+//! [`VmInfo`], is the identity of the VM: its ID, name, vCPUs and memory size, and whether it is
+//! the manager VM (see [`crate::vmm::vmdef`]) or a partitioned VM (see [`crate::vmm::sched`]),
+//! which instance of a template it is if it was stamped from one (see [`crate::vmm::template`]) and its UUID (see
+//! [`crate::vmm::identity`]). The third one is the services page
+//! of the VM, see [`crate::vmm::services`].
+//!
+//! All pages are written before the VM boots and never change afterwards. Their layouts are part
+//! of the guest ABI, see [`crate::vmm::abi`].
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::{AxMmHal, GuestPhysAddr, HostPhysAddr, MappingFlags};
+use axerrno::{AxResult, ax_err, ax_err_type};
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::hal::AxMmHalImpl;
+use crate::vmm::hvc::AXVISOR_FAST_HVC_BASE;
+use crate::vmm::memstat::{self, MemSubsystem};
+use crate::vmm::{VMRef, identity, lazymem, reclaim, sched, services, stage2, template, vmdef};
+
+/// `magic` of [`HvInfo`].
+pub const HV_INFO_MAGIC: u32 = u32::from_le_bytes(*b"AXHV");
+/// `magic` of [`VmInfo`].
+pub const VM_INFO_MAGIC: u32 = u32::from_le_bytes(*b"AXVM");
+/// Version of the layouts of the pages.
+pub const HV_INFO_VERSION: u32 = 1;
+/// Level of the guest ABI, bumped whenever a hypercall or a shared page layout is added.
+pub const ABI_LEVEL: u32 = 31;
+
+/// Host architectures, in `HvInfo::arch`.
+pub const HV_ARCH_AARCH64: u32 = 1;
+pub const HV_ARCH_RISCV64: u32 = 2;
+pub const HV_ARCH_X86_64: u32 = 3;
+
+/// The VM is the manager VM, in `VmInfo::flags`.
+pub const VM_INFO_F_MANAGER: u32 = 1 << 0;
+/// The VM is partitioned, in `VmInfo::flags`.
+pub const VM_INFO_F_PARTITIONED: u32 = 1 << 1;
+/// The VM has the virtualization extensions, in `VmInfo::flags`. Never set, as no VM has them
+/// yet (see [`crate::vmm::nested`]).
+pub const VM_INFO_F_NESTED: u32 = 1 << 2;
+/// The VM was stamped from a template, in `VmInfo::flags`.
+pub const VM_INFO_F_STAMPED: u32 = 1 << 3;
+
+/// The page describing the hypervisor, shared by all guests.
+#[repr(C)]
+pub struct HvInfo {
+    pub magic: u32,
+    pub version: u32,
+    pub abi_level: u32,
+    /// One of the `HV_ARCH_*` constants.
+    pub arch: u32,
+    pub hv_major: u16,
+    pub hv_minor: u16,
+    pub hv_patch: u16,
+    pub _reserved0: u16,
+    /// Number of physical CPUs.
+    pub host_cpus: u32,
+    pub _reserved1: u32,
+    /// Number of the first fast hypercall, see [`crate::vmm::hvc`].
+    pub fast_hvc_base: u64,
+    /// Frequency of the host counter in Hz.
+    pub counter_freq_hz: u64,
+    /// Name of the platform, NUL-padded.
+    pub platform: [u8; 32],
+}
+
+/// The page describing a VM, private to it.
+#[repr(C)]
+pub struct VmInfo {
+    pub magic: u32,
+    pub version: u32,
+    pub vm_id: u64,
+    pub vcpus: u32,
+    /// `VM_INFO_F_*` flags.
+    pub flags: u32,
+    /// Size of the guest memory in bytes.
+    pub memory_size: u64,
+    /// Name of the VM, NUL-padded and truncated to 63 bytes.
+    pub name: [u8; 64],
+    /// Number of the instance of the template the VM was stamped from, with `VM_INFO_F_STAMPED`.
+    pub instance: u32,
+    pub _reserved: u32,
+    /// UUID of the VM.
+    pub uuid: [u8; 16],
+}
+
+/// The frame of [`HvInfo`], allocated by the first VM mapping it.
+static HV_INFO_FRAME: spin::Once<HostPhysAddr> = spin::Once::new();
+/// Frames of the [`VmInfo`] and services pages of the VMs, indexed by VM ID.
+static VM_INFO_FRAMES: Mutex<BTreeMap<usize, [HostPhysAddr; 2]>> = Mutex::new(BTreeMap::new());
+
+/// Copies `s` into a NUL-padded buffer, truncated to leave at least one NUL.
+fn padded<const N: usize>(s: &str) -> [u8; N] {
+    let mut buf = [0; N];
+    let len = s.len().min(N - 1);
+    buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+    buf
+}
+
+fn hv_version() -> (u16, u16, u16) {
+    let mut parts = env!("CARGO_PKG_VERSION")
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0));
+    (
+        parts.next().unwrap_or(0),
+        parts.next().unwrap_or(0),
+        parts.next().unwrap_or(0),
+    )
+}
+
+/// Allocates an information page, for VM `vm_id` or for all VMs.
+fn alloc_zeroed_frame(vm_id: Option<usize>) -> AxResult<HostPhysAddr> {
+    let frame = reclaim::alloc_or_reclaim(AxMmHalImpl::alloc_frame)
+        .ok_or_else(|| ax_err_type!(NoMemory, "failed to allocate a hypervisor info page"))?;
+    memstat::charge(MemSubsystem::HvInfo, vm_id, PAGE_SIZE_4K);
+    // SAFETY: the frame was just allocated and is mapped in the linear mapping of the host.
+    unsafe {
+        core::ptr::write_bytes(
+            AxMmHalImpl::phys_to_virt(frame).as_mut_ptr(),
+            0,
+            PAGE_SIZE_4K,
+        )
+    };
+    Ok(frame)
+}
+
+fn hv_info_frame() -> AxResult<HostPhysAddr> {
+    HV_INFO_FRAME
+        .try_call_once(|| {
+            let frame = alloc_zeroed_frame(None)?;
+            let (hv_major, hv_minor, hv_patch) = hv_version();
+            let info = HvInfo {
+                magic: HV_INFO_MAGIC,
+                version: HV_INFO_VERSION,
+                abi_level: ABI_LEVEL,
+                arch: if cfg!(target_arch = "aarch64") {
+                    HV_ARCH_AARCH64
+                } else if cfg!(target_arch = "riscv64") {
+                    HV_ARCH_RISCV64
+                } else {
+                    HV_ARCH_X86_64
+                },
+                hv_major,
+                hv_minor,
+                hv_patch,
+                _reserved0: 0,
+                host_cpus: axruntime::cpu_count() as u32,
+                _reserved1: 0,
+                fast_hvc_base: AXVISOR_FAST_HVC_BASE,
+                counter_freq_hz: axhal::time::nanos_to_ticks(axhal::time::NANOS_PER_SEC),
+                platform: padded(option_env!("AX_PLATFORM").unwrap_or("")),
+            };
+            // SAFETY: the frame is ours and large enough.
+            unsafe {
+                AxMmHalImpl::phys_to_virt(frame)
+                    .as_mut_ptr_of::<HvInfo>()
+                    .write(info)
+            };
+            Ok(frame)
+        })
+        .copied()
+}
+
+/// Maps the hypervisor information pages described in the `[hv_info]` section of `raw_cfg` into
+/// the VM.
+///
+/// Does nothing if the VM config has no `[hv_info]` section. Must run after
+/// [`vmdef::setup_vm_manager`], [`sched::setup_vm_scheduling`] and
+/// [`services::setup_vm_services`].
+pub fn setup_vm_hv_info(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("hv_info").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let Some(gpa) = cfg
+        .get("gpa")
+        .and_then(|v| v.as_integer())
+        .map(|v| v as usize)
+    else {
+        return ax_err!(InvalidInput, "hv_info config: missing `gpa`");
+    };
+    let size = 3 * PAGE_SIZE_4K;
+    if gpa % PAGE_SIZE_4K != 0 {
+        return ax_err!(InvalidInput, "hv_info config: `gpa` must be page aligned");
+    }
+    let regions = vm.memory_regions();
+    if regions.iter().any(|region| {
+        gpa < region.gpa.as_usize() + region.size() && region.gpa.as_usize() < gpa + size
+    }) {
+        return ax_err!(
+            InvalidInput,
+            format!("hv_info config: {:#x} overlaps guest memory", gpa)
+        );
+    }
+
+    let vm_id = vm.id();
+    let mut flags = 0;
+    if vmdef::is_manager(vm_id) {
+        flags |= VM_INFO_F_MANAGER;
+    }
+    if sched::is_partitioned(vm_id) {
+        flags |= VM_INFO_F_PARTITIONED;
+    }
+    let instance = template::instance_of(vm_id);
+    if instance.is_some() {
+        flags |= VM_INFO_F_STAMPED;
+    }
+    let name: String = vm.with_config(|cfg| cfg.name());
+    let info = VmInfo {
+        magic: VM_INFO_MAGIC,
+        version: HV_INFO_VERSION,
+        vm_id: vm_id as u64,
+        vcpus: vm.vcpu_num() as u32,
+        flags,
+        memory_size: regions
+            .iter()
+            .map(|region| region.size() as u64)
+            .sum::<u64>()
+            + lazymem::lazy_memory_stats(vm_id).map_or(0, |lazy| lazy.size as u64),
+        name: padded(&name),
+        instance: instance.unwrap_or(0),
+        _reserved: 0,
+        uuid: identity::identity(vm_id).map_or([0; 16], |identity| identity.uuid),
+    };
+
+    let hv_frame = hv_info_frame()?;
+    let vm_frame = alloc_zeroed_frame(Some(vm_id))?;
+    // SAFETY: the frame is ours and large enough.
+    unsafe {
+        AxMmHalImpl::phys_to_virt(vm_frame)
+            .as_mut_ptr_of::<VmInfo>()
+            .write(info)
+    };
+    let services_frame = match alloc_zeroed_frame(Some(vm_id)) {
+        Ok(frame) => frame,
+        Err(err) => {
+            AxMmHalImpl::dealloc_frame(vm_frame);
+            memstat::uncharge(MemSubsystem::HvInfo, Some(vm_id), PAGE_SIZE_4K);
+            return Err(err);
+        }
+    };
+    // SAFETY: the frame is ours, zeroed and page aligned.
+    unsafe {
+        services::write_page(
+            vm_id,
+            AxMmHalImpl::phys_to_virt(services_frame).as_mut_ptr(),
+        )
+    };
+    VM_INFO_FRAMES
+        .lock()
+        .insert(vm_id, [vm_frame, services_frame]);
+
+    let gpa = GuestPhysAddr::from(gpa);
+    stage2::map_region(vm, gpa, hv_frame, PAGE_SIZE_4K, MappingFlags::READ)?;
+    stage2::map_region(
+        vm,
+        gpa + PAGE_SIZE_4K,
+        vm_frame,
+        PAGE_SIZE_4K,
+        MappingFlags::READ,
+    )?;
+    stage2::map_region(
+        vm,
+        gpa + 2 * PAGE_SIZE_4K,
+        services_frame,
+        PAGE_SIZE_4K,
+        MappingFlags::READ,
+    )?;
+    info!("VM[{}] hypervisor info pages at {:#x}", vm_id, gpa);
+    Ok(())
+}
+
+/// Frees the [`VmInfo`] and services pages of a VM, called when the VM is destroyed.
+pub fn teardown_vm_hv_info(vm_id: usize) {
+    if let Some(frames) = VM_INFO_FRAMES.lock().remove(&vm_id) {
+        for frame in frames {
+            AxMmHalImpl::dealloc_frame(frame);
+            memstat::uncharge(MemSubsystem::HvInfo, Some(vm_id), PAGE_SIZE_4K);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+//! Identity of the VMs, unique to each clone.
+//!
+//! VMs created from the same config, i.e. the instances of a config with a `[scale]` section (see
+//! [`crate::vmm::config`]) and the VMs stamped from a template (see [`crate::vmm::template`]),
+//! would otherwise share everything their config sets: MAC addresses, hostname, and, for a
+//! template, the state of their RNG. Each VM gets an identity when it's created, which an
+//! `[identity]` section of its config can set:
+//!
+//! ```toml
+//! [identity]
+//! # UUID of the VM, derived from its name and ID by default.
+//! uuid = "1b4e28ba-2fa1-11d2-883f-0016d3cca427"
+//! # Hostname of the VM, its name by default.
+//! hostname = "worker"
+//! # Bytes of fresh seed for the RNG of the guest, 32 for clones and 0 (none) for the others by
+//! # default, at most 64.
+//! rng_seed = 32
+//! ```
+//!
+//! A clone, any VM but the first instance of a config or the golden VM of a template, gets a
+//! rewritten identity instead: its UUID is derived from the configured one (or its name) and its
+//! ID, its hostname is the configured one followed by `-` and the number of the clone, and the MAC
+//! addresses set in its config keep their first three bytes followed by the VM ID and the index of
+//! the device, as the default ones (see [`crate::vmm::virtio`]). Derived UUIDs are version 8 and
+//! stable across restarts of the VM.
+//!
+//! The guest finds its identity in the `/chosen` node of its DTB: `axvisor,uuid` (16 bytes),
+//! `axvisor,hostname` and the standard `rng-seed`, regenerated every time the VM is created. The
+//! UUID is also in its [`VmInfo`](crate::vmm::hvinfo::VmInfo) page. The seed mixes the host clocks
+//! with a counter through AES: it differs from one VM to another but is no substitute for a
+//! hardware entropy source, which guests should mix in when they have one.
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use std::os::arceos::api::time::ax_wall_time;
+use std::os::arceos::modules::axhal;
+
+use aes_gcm::aes::Aes256;
+use aes_gcm::aes::cipher::{BlockEncrypt, KeyInit, generic_array::GenericArray};
+use axerrno::{AxResult, ax_err, ax_err_type};
+use spin::{Mutex, Once};
+
+use crate::vmm::{VMRef, template};
+
+/// Largest RNG seed.
+pub const MAX_RNG_SEED: usize = 64;
+/// RNG seed of a clone without an `rng_seed` key.
+const DEFAULT_CLONE_RNG_SEED: usize = 32;
+
+/// The identity of a VM.
+#[derive(Debug, Clone)]
+pub struct VmIdentity {
+    pub uuid: [u8; 16],
+    pub hostname: String,
+    /// Fresh bytes for the RNG of the guest, empty for none.
+    pub rng_seed: Vec<u8>,
+    /// Number of the clone, `None` for the first instance of its config.
+    pub clone: Option<u64>,
+}
+
+/// Identities of the VMs, indexed by VM ID.
+static IDENTITIES: Mutex<BTreeMap<usize, VmIdentity>> = Mutex::new(BTreeMap::new());
+/// Key of the seed generator, from the host clocks at its first use.
+static SEED_KEY: Once<[u8; 32]> = Once::new();
+/// Blocks generated by the seed generator.
+static SEED_BLOCKS: AtomicU64 = AtomicU64::new(0);
+
+fn parse_uuid(s: &str) -> Option<[u8; 16]> {
+    let hex: Vec<u8> = s.bytes().filter(|b| *b != b'-').collect();
+    if hex.len() != 32 || s.len() != 36 {
+        return None;
+    }
+    let mut uuid = [0; 16];
+    for (i, byte) in uuid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(core::str::from_utf8(&hex[2 * i..2 * i + 2]).ok()?, 16).ok()?;
+    }
+    Some(uuid)
+}
+
+/// Formats `uuid` in the usual 8-4-4-4-12 form.
+pub fn format_uuid(uuid: &[u8; 16]) -> String {
+    let mut s = String::with_capacity(36);
+    for (i, byte) in uuid.iter().enumerate() {
+        if matches!(i, 4 | 6 | 8 | 10) {
+            s.push('-');
+        }
+        s.push_str(&format!("{:02x}", byte));
+    }
+    s
+}
+
+/// FNV-1a hash of `bytes` from `basis`.
+fn fnv1a(basis: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(basis, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+/// Derives a version 8 UUID from `seed` and `vm_id`.
+fn derive_uuid(seed: &[u8], vm_id: usize) -> [u8; 16] {
+    let mut input = seed.to_vec();
+    input.extend_from_slice(&(vm_id as u64).to_le_bytes());
+    let mut uuid = [0; 16];
+    uuid[..8].copy_from_slice(&fnv1a(0xcbf2_9ce4_8422_2325, &input).to_be_bytes());
+    uuid[8..].copy_from_slice(&fnv1a(0x6c62_272e_07bb_0142, &input).to_be_bytes());
+    uuid[6] = (uuid[6] & 0x0f) | 0x80;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    uuid
+}
+
+/// Returns `len` fresh seed bytes for VM `vm_id`.
+fn rng_seed(vm_id: usize, len: usize) -> Vec<u8> {
+    let key = SEED_KEY.call_once(|| {
+        let mut key = [0; 32];
+        key[..16].copy_from_slice(&ax_wall_time().as_nanos().to_le_bytes());
+        key[16..24].copy_from_slice(&axhal::time::monotonic_time_nanos().to_le_bytes());
+        key[24..].copy_from_slice(&axhal::time::current_ticks().to_le_bytes());
+        key
+    });
+    let cipher = Aes256::new(GenericArray::from_slice(key));
+    let mut seed = Vec::with_capacity(len.next_multiple_of(16));
+    while seed.len() < len {
+        let mut block = [0; 16];
+        block[..8].copy_from_slice(&SEED_BLOCKS.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+        block[8..12].copy_from_slice(&(vm_id as u32).to_le_bytes());
+        block[12..].copy_from_slice(&(axhal::time::current_ticks() as u32).to_le_bytes());
+        let mut block = GenericArray::from(block);
+        cipher.encrypt_block(&mut block);
+        seed.extend_from_slice(&block);
+    }
+    seed.truncate(len);
+    seed
+}
+
+/// Returns the number of the clone of `vm` among the VMs created from `raw_cfg`, `None` if it's
+/// the first one, see the [module documentation](self).
+fn clone_number(vm: &VMRef, raw_cfg: &toml::Table) -> Option<u64> {
+    if let Some(instance) = template::instance_of(vm.id()) {
+        return Some(instance as u64);
+    }
+    let base_id = raw_cfg
+        .get("base")
+        .and_then(|v| v.get("id"))
+        .and_then(|v| v.as_integer())? as usize;
+    vm.id()
+        .checked_sub(base_id)
+        .filter(|idx| *idx > 0)
+        .map(|idx| idx as u64)
+}
+
+/// Gives the VM its identity, from the `[identity]` section of `raw_cfg` if any. Must run after
+/// [`template::setup_vm_stamp`] and before the DTB of the VM is written.
+pub fn setup_vm_identity(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let empty = toml::Table::new();
+    let cfg = match raw_cfg.get("identity") {
+        None => &empty,
+        Some(v) => v
+            .as_table()
+            .ok_or_else(|| ax_err_type!(InvalidInput, "identity config: not a table"))?,
+    };
+    let string = |key: &str| {
+        cfg.get(key)
+            .map(|v| {
+                v.as_str().ok_or_else(|| {
+                    ax_err_type!(
+                        InvalidInput,
+                        format!("identity config: `{}` must be a string", key)
+                    )
+                })
+            })
+            .transpose()
+    };
+    let uuid = string("uuid")?
+        .map(|s| {
+            parse_uuid(s)
+                .ok_or_else(|| ax_err_type!(InvalidInput, "identity config: invalid `uuid`"))
+        })
+        .transpose()?;
+    let hostname = string("hostname")?;
+    let rng_seed_len = match cfg.get("rng_seed") {
+        None => None,
+        Some(len) => Some(
+            len.as_integer()
+                .filter(|len| (0..=MAX_RNG_SEED as i64).contains(len))
+                .ok_or_else(|| ax_err_type!(InvalidInput, "identity config: invalid `rng_seed`"))?
+                as usize,
+        ),
+    };
+    if hostname.is_some_and(|hostname| hostname.is_empty()) {
+        return ax_err!(InvalidInput, "identity config: empty `hostname`");
+    }
+
+    let vm_id = vm.id();
+    let name: String = vm.with_config(|cfg| cfg.name());
+    let clone = clone_number(vm, raw_cfg);
+    let uuid = match (uuid, clone) {
+        (Some(uuid), None) => uuid,
+        (Some(uuid), Some(_)) => derive_uuid(&uuid, vm_id),
+        (None, _) => derive_uuid(name.as_bytes(), vm_id),
+    };
+    let hostname = match (hostname, clone) {
+        (Some(hostname), None) => hostname.to_string(),
+        (Some(hostname), Some(clone)) => format!("{}-{}", hostname, clone),
+        (None, _) => name,
+    };
+    let rng_seed_len = rng_seed_len.unwrap_or(if clone.is_some() {
+        DEFAULT_CLONE_RNG_SEED
+    } else {
+        0
+    });
+    let identity = VmIdentity {
+        uuid,
+        rng_seed: rng_seed(vm_id, rng_seed_len),
+        hostname,
+        clone,
+    };
+
+    info!(
+        "VM[{}] identity: uuid {}, hostname {:?}, clone {:?}",
+        vm_id,
+        format_uuid(&identity.uuid),
+        identity.hostname,
+        identity.clone
+    );
+    IDENTITIES.lock().insert(vm_id, identity);
+    Ok(())
+}
+
+/// Forgets the identity of a VM, called when the VM is destroyed.
+pub fn teardown_vm_identity(vm_id: usize) {
+    if let Some(mut identity) = IDENTITIES.lock().remove(&vm_id) {
+        identity.rng_seed.fill(0);
+    }
+}
+
+/// Returns the identity of VM `vm_id`.
+pub fn identity(vm_id: usize) -> Option<VmIdentity> {
+    IDENTITIES.lock().get(&vm_id).cloned()
+}
+
+/// Returns the MAC address of device `idx` of VM `vm_id` whose config sets `mac`: `mac` itself,
+/// or for a clone its first three bytes followed by the VM ID and `idx`.
+pub fn instance_mac(vm_id: usize, mac: [u8; 6], idx: usize) -> [u8; 6] {
+    let is_clone = IDENTITIES
+        .lock()
+        .get(&vm_id)
+        .is_some_and(|identity| identity.clone.is_some());
+    if !is_clone {
+        return mac;
+    }
+    [
+        mac[0],
+        mac[1],
+        mac[2],
+        (vm_id >> 8) as u8,
+        vm_id as u8,
+        idx as u8,
+    ]
+}
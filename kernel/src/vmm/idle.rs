@@ -0,0 +1,156 @@
+//! Blocking of halted vCPUs.
+//!
+//! A vCPU executing WFI or HLT has nothing to do until its next interrupt, so its task blocks on
+//! the wait queue of its VM instead of polling, and an idle guest leaves its CPUs to the other
+//! vCPUs or to the idle task. The vCPU is woken up when:
+//!
+//! - an interrupt is injected to the VM: the interrupts posted to the vCPU (see
+//!   [`crate::vmm::posted`]) and the emulated devices wake up the vCPUs of the VM, see
+//!   [`vcpus::notify_all_vcpus`];
+//! - a doorbell targeting it is rung, see [`vcpus::kick`];
+//! - its next timer is due, see below.
+//!
+//! A vCPU doesn't block if vectors were posted to it while it was in the guest, and doesn't miss a
+//! wakeup between its exit and blocking: it only blocks if its VM wasn't notified since it entered
+//! the guest, see [`vcpus::wait_halted`].
+//!
+//! A guest without a periodic tick halts its vCPUs until its next timer. The timers of a halted
+//! vCPU are:
+//!
+//! - on aarch64, the virtual timer of the guest, which the vCPU leaves in place when it exits: its
+//!   deadline is read right after the exit, see [`guest_vtimer_deadline`];
+//! - the emulated timers, the local APIC timer on x86_64, the CLINT on riscv64 and the EL1 physical
+//!   timer on aarch64, which are host timers of the CPU, see [`timer::next_deadline`].
+//!
+//! The vCPU sleeps until the earliest of them and the timer of the CPU is programmed to fire right
+//! then (see [`axruntime::set_next_event`]) instead of on the next tick of the hypervisor, which
+//! may be 10 ms later. The CPU idles longer between two deadlines and the guest timer interrupts
+//! arrive on time. A vCPU whose deadline already passed enters the guest again right away.
+//!
+//! The vCPUs of partitioned VMs block too, their dedicated CPUs then idle, see
+//! [`crate::vmm::sched`].
+//!
+//! [`guest_vtimer_deadline`]: crate::hal::arch::guest_vtimer_deadline
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use std::os::arceos::modules::axhal;
+
+use spin::Mutex;
+
+use crate::vmm::{timer, vcpus};
+
+/// How much later than the deadline the timer of the CPU is programmed, as the wait queue arms its
+/// own timeout a little after the deadline is computed and must not miss it.
+const WAKE_SLACK_NS: u64 = 2_000;
+
+/// Counters of the halts of a vCPU, see [`idle_stats`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdleStats {
+    /// Halts of the vCPU.
+    pub halts: u64,
+    /// Halts with an interrupt already pending, which didn't block.
+    pub pending: u64,
+    /// Halts whose timer deadline had already passed, which didn't block.
+    pub expired: u64,
+    /// Halts which ended on an interrupt or another notification of the VM.
+    pub interrupt_wakeups: u64,
+    /// Halts which ended at the timer deadline.
+    pub deadline_wakeups: u64,
+    /// Time spent blocked in nanoseconds.
+    pub idle_ns: u64,
+    /// Total and maximum lateness of the deadline wakeups in nanoseconds.
+    pub total_late_ns: u64,
+    pub max_late_ns: u64,
+}
+
+/// Halt counters by VM and vCPU.
+static STATS: Mutex<BTreeMap<(usize, usize), IdleStats>> = Mutex::new(BTreeMap::new());
+
+/// Returns the deadline in nanoseconds of the guest timer of the vCPU which just exited on this
+/// CPU, to be called right after the exit.
+#[inline]
+pub fn guest_deadline() -> Option<u64> {
+    #[cfg(target_arch = "aarch64")]
+    {
+        crate::hal::arch::guest_vtimer_deadline().map(axhal::time::ticks_to_nanos)
+    }
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        None
+    }
+}
+
+/// Blocks halted vCPU `vcpu_id` of VM `vm_id` until an interrupt is injected or its next timer
+/// deadline.
+///
+/// - `notified`: the notifications of the VM when the vCPU entered the guest, from
+///   [`vcpus::notifications`];
+/// - `pending`: whether vectors were posted to the vCPU since it entered the guest;
+/// - `guest_deadline`: the deadline of its guest timer, from [`guest_deadline`].
+pub fn halt(
+    vm_id: usize,
+    vcpu_id: usize,
+    notified: usize,
+    pending: bool,
+    guest_deadline: Option<u64>,
+) {
+    let deadline = [guest_deadline, timer::next_deadline()]
+        .into_iter()
+        .flatten()
+        .min();
+    let start = axhal::time::monotonic_time_nanos();
+    if pending || deadline.is_some_and(|deadline| deadline <= start) {
+        {
+            let mut stats = STATS.lock();
+            let stats = stats.entry((vm_id, vcpu_id)).or_default();
+            stats.halts += 1;
+            if pending {
+                stats.pending += 1;
+            } else {
+                stats.expired += 1;
+            }
+        }
+        timer::check_events();
+        return;
+    }
+
+    if let Some(deadline) = deadline {
+        axruntime::set_next_event(deadline + WAKE_SLACK_NS);
+    }
+    let timed_out = vcpus::wait_halted(vm_id, vcpu_id, notified, deadline);
+    let now = axhal::time::monotonic_time_nanos();
+    {
+        let mut stats = STATS.lock();
+        let stats = stats.entry((vm_id, vcpu_id)).or_default();
+        stats.halts += 1;
+        stats.idle_ns += now - start;
+        match deadline {
+            Some(deadline) if timed_out => {
+                let late = now.saturating_sub(deadline);
+                stats.deadline_wakeups += 1;
+                stats.total_late_ns += late;
+                stats.max_late_ns = stats.max_late_ns.max(late);
+            }
+            _ => stats.interrupt_wakeups += 1,
+        }
+    }
+    // The emulated timers only fire on the exits of the vCPUs of the CPU.
+    timer::check_events();
+}
+
+/// Returns the halt counters of the vCPUs of VM `vm_id` which halted, by vCPU ID.
+pub fn idle_stats(vm_id: usize) -> Vec<(usize, IdleStats)> {
+    STATS
+        .lock()
+        .range((vm_id, 0)..=(vm_id, usize::MAX))
+        .map(|(&(_, vcpu_id), stats)| (vcpu_id, *stats))
+        .collect()
+}
+
+/// Drops the halt counters of a VM, called when the VM is destroyed.
+pub fn teardown_vm_idle(vm_id: usize) {
+    STATS
+        .lock()
+        .retain(|&(stats_vm_id, _), _| stats_vm_id != vm_id);
+}
@@ -0,0 +1,85 @@
+//! Direct Linux boot parameters of a VM.
+//!
+//! A VM booting Linux directly gets its kernel command line and initramfs through the `/chosen`
+//! node of its device tree. Both can be set per VM, without rebuilding the DTB, with a `[linux]`
+//! section:
+//!
+//! ```toml
+//! [linux]
+//! # Kernel command line, replacing the `bootargs` of the device tree.
+//! bootargs = "console=ttyAMA0 earlycon root=/dev/vda rw"
+//! # Appended to the command line, of the device tree or set above.
+//! bootargs_append = "loglevel=4"
+//! ```
+//!
+//! The initramfs is the ramdisk image of the VM (`ramdisk_path` of `[kernel]`, or the built-in
+//! ramdisk). It's loaded at `ramdisk_load_addr`, or, if not set, at the first 2 MiB boundary past
+//! the kernel, and its range is given to the kernel as `linux,initrd-start` and
+//! `linux,initrd-end`. Without a ramdisk, initrd properties of the device tree are dropped, they
+//! would describe memory holding nothing. A `/chosen` node is added to device trees without one.
+//!
+//! The device tree of the guest is only rebuilt on aarch64: other architectures don't support the
+//! `[linux]` section, the x86 boot parameters being set up by the BIOS image.
+use alloc::string::{String, ToString};
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err, ax_err_type};
+
+/// Kernel command line and initramfs of a VM booting Linux directly.
+#[derive(Debug, Clone, Default)]
+pub struct LinuxBoot {
+    /// Replaces the command line of the device tree.
+    pub bootargs: Option<String>,
+    /// Appended to the command line.
+    pub bootargs_append: Option<String>,
+    /// Guest physical range of the initramfs, set once it's loaded.
+    pub initrd: Option<(GuestPhysAddr, usize)>,
+}
+
+impl LinuxBoot {
+    /// Parses the `[linux]` section of `raw_cfg`, the default parameters if there is none.
+    pub fn from_raw_config(raw_cfg: &toml::Table) -> AxResult<Self> {
+        let Some(cfg) = raw_cfg.get("linux").and_then(|v| v.as_table()) else {
+            return Ok(Self::default());
+        };
+        if !cfg!(target_arch = "aarch64") {
+            return ax_err!(
+                Unsupported,
+                "linux config: boot parameters are passed through the device tree, aarch64 only"
+            );
+        }
+        let get = |key: &str| {
+            cfg.get(key)
+                .map(|v| {
+                    v.as_str().map(ToString::to_string).ok_or_else(|| {
+                        ax_err_type!(
+                            InvalidInput,
+                            format!("linux config: `{}` must be a string", key)
+                        )
+                    })
+                })
+                .transpose()
+        };
+        Ok(Self {
+            bootargs: get("bootargs")?,
+            bootargs_append: get("bootargs_append")?,
+            initrd: None,
+        })
+    }
+
+    /// Returns the command line of the guest, given the `bootargs` of its device tree.
+    ///
+    /// Without a `bootargs` override, the root filesystem of the device tree is mounted
+    /// read-write.
+    pub fn cmdline(&self, dt_bootargs: Option<&str>) -> Option<String> {
+        let base = match &self.bootargs {
+            Some(bootargs) => Some(bootargs.clone()),
+            None => dt_bootargs.map(|s| s.replace(" ro ", " rw ")),
+        };
+        match (base, &self.bootargs_append) {
+            (Some(base), Some(append)) if !base.is_empty() => Some(format!("{base} {append}")),
+            (_, Some(append)) => Some(append.clone()),
+            (base, None) => base,
+        }
+    }
+}
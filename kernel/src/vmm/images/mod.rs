@@ -1,16 +1,23 @@
 use axaddrspace::GuestPhysAddr;
-use axerrno::AxResult;
+use axerrno::{AxResult, ax_err};
 
 use axvm::VMMemoryRegion;
 use axvm::config::AxVMCrateConfig;
 use byte_unit::Byte;
+use memory_addr::MemoryAddr;
 
 use crate::hal::CacheOp;
 use crate::vmm::VMRef;
 use crate::vmm::config::{config, get_vm_dtb_arc};
 
+mod boot;
 mod linux;
 
+pub use boot::LinuxBoot;
+
+/// Alignment of an initramfs placed past the kernel.
+const INITRD_ALIGN: usize = 0x20_0000;
+
 pub fn get_image_header(config: &AxVMCrateConfig) -> Option<linux::Header> {
     match config.kernel.image_location.as_deref() {
         Some("memory") => with_memory_image(config, linux::Header::parse),
@@ -26,6 +33,37 @@ pub fn get_image_header(config: &AxVMCrateConfig) -> Option<linux::Header> {
     }
 }
 
+/// Checks that the kernel image of a VM config can be found, without loading it.
+pub fn check_kernel_image(config: &AxVMCrateConfig) -> AxResult {
+    match config.kernel.image_location.as_deref() {
+        Some("memory") => {
+            if config::get_memory_images()
+                .iter()
+                .any(|v| v.id == config.base.id)
+            {
+                Ok(())
+            } else {
+                ax_err!(
+                    NotFound,
+                    format!("no built-in image for VM[{}]", config.base.id)
+                )
+            }
+        }
+        #[cfg(feature = "fs")]
+        Some("fs") => match std::fs::metadata(config.kernel.kernel_path.as_str()) {
+            Ok(_) => Ok(()),
+            Err(e) => ax_err!(
+                NotFound,
+                format!("kernel image {}: {:?}", config.kernel.kernel_path, e)
+            ),
+        },
+        location => ax_err!(
+            Unsupported,
+            format!("unsupported image location {:?}", location)
+        ),
+    }
+}
+
 fn with_memory_image<F, R>(config: &AxVMCrateConfig, func: F) -> R
 where
     F: FnOnce(&[u8]) -> R,
@@ -46,10 +84,16 @@ pub struct ImageLoader {
     bios_load_gpa: Option<GuestPhysAddr>,
     dtb_load_gpa: Option<GuestPhysAddr>,
     ramdisk_load_gpa: Option<GuestPhysAddr>,
+    linux: LinuxBoot,
 }
 
 impl ImageLoader {
-    pub fn new(main_memory: VMMemoryRegion, config: AxVMCrateConfig, vm: VMRef) -> Self {
+    pub fn new(
+        main_memory: VMMemoryRegion,
+        config: AxVMCrateConfig,
+        vm: VMRef,
+        linux: LinuxBoot,
+    ) -> Self {
         Self {
             main_memory,
             vm,
@@ -58,6 +102,7 @@ impl ImageLoader {
             bios_load_gpa: None,
             dtb_load_gpa: None,
             ramdisk_load_gpa: None,
+            linux,
         }
     }
 
@@ -87,9 +132,29 @@ impl ImageLoader {
         }
     }
 
+    /// Returns where the ramdisk is loaded: at `ramdisk_load_addr`, or at the first 2 MiB boundary
+    /// past the kernel, which takes the larger of `kernel_size` and the size in its image header
+    /// (covering its BSS).
+    fn ramdisk_load_gpa(&mut self, kernel_size: usize) -> GuestPhysAddr {
+        if let Some(gpa) = self.ramdisk_load_gpa {
+            return gpa;
+        }
+        let image_size = get_image_header(&self.config).map_or(0, |hdr| hdr.image_size as usize);
+        let gpa = (self.kernel_load_gpa + kernel_size.max(image_size)).align_up(INITRD_ALIGN);
+        info!(
+            "VM[{}] ramdisk load addr not set, placed at {:#x}",
+            self.vm.id(),
+            gpa
+        );
+        self.vm
+            .with_config(|config| config.image_config.ramdisk_load_gpa = Some(gpa));
+        self.ramdisk_load_gpa = Some(gpa);
+        gpa
+    }
+
     /// Load VM images from memory
     /// into the guest VM's memory space based on the VM configuration.
-    fn load_vm_images_from_memory(&self) -> AxResult {
+    fn load_vm_images_from_memory(&mut self) -> AxResult {
         info!("Loading VM[{}] images from memory", self.config.base.id);
 
         let vm_imags = config::get_memory_images()
@@ -97,8 +162,21 @@ impl ImageLoader {
             .find(|&v| v.id == self.config.base.id)
             .expect("VM images is missed, Perhaps add `VM_CONFIGS=PATH/CONFIGS/FILE` command.");
 
-        load_vm_image_from_memory(vm_imags.kernel, self.kernel_load_gpa, self.vm.clone())
-            .expect("Failed to load VM images");
+        if crate::vmm::uefi::is_uefi_boot(self.vm.id()) {
+            info!("VM[{}] boots UEFI, kernel image not loaded", self.vm.id());
+        } else {
+            load_vm_image_from_memory(vm_imags.kernel, self.kernel_load_gpa, self.vm.clone())
+                .expect("Failed to load VM images");
+        }
+
+        // Load Ramdisk image, before the DTB telling the kernel where it is.
+        if let Some(buffer) = vm_imags.ramdisk {
+            let ramdisk_load_gpa = self.ramdisk_load_gpa(vm_imags.kernel.len());
+            load_vm_image_from_memory(buffer, ramdisk_load_gpa, self.vm.clone())
+                .expect("Failed to load Ramdisk images");
+            self.linux.initrd = Some((ramdisk_load_gpa, buffer.len()));
+        };
+
         // Load DTB image
         let vm_config = axvm::config::AxVMConfig::from(self.config.clone());
 
@@ -109,6 +187,7 @@ impl ImageLoader {
                 core::ptr::NonNull::new(_dtb_slice.as_ptr() as *mut u8).unwrap(),
                 _dtb_slice.len(),
                 self.vm.clone(),
+                &self.linux,
             );
         } else {
             info!("dtb_load_gpa not provided");
@@ -120,12 +199,6 @@ impl ImageLoader {
                 .expect("Failed to load BIOS images");
         }
 
-        // Load Ramdisk image
-        if let Some(buffer) = vm_imags.ramdisk {
-            load_vm_image_from_memory(buffer, self.ramdisk_load_gpa.unwrap(), self.vm.clone())
-                .expect("Failed to load Ramdisk images");
-        };
-
         Ok(())
     }
 }
@@ -145,6 +218,16 @@ pub fn load_vm_image_from_memory(
         image_buffer.len()
     );
 
+    let mut shared_pos = 0;
+    if crate::vmm::lazymem::load_shared_image(&vm, load_addr, image_size, |buf| {
+        buf.copy_from_slice(&image_buffer[shared_pos..shared_pos + buf.len()]);
+        shared_pos += buf.len();
+        Ok(())
+    })? {
+        return Ok(());
+    }
+
+    crate::vmm::lazymem::populate(&vm, load_addr, image_size)?;
     let image_load_regions = vm.get_image_load_region(load_addr, image_size)?;
 
     for region in image_load_regions {
@@ -218,14 +301,18 @@ pub mod fs {
 
     /// Loads the VM image files from the filesystem
     /// into the guest VM's memory space based on the VM configuration.
-    pub(crate) fn load_vm_images_from_filesystem(loader: &ImageLoader) -> AxResult {
+    pub(crate) fn load_vm_images_from_filesystem(loader: &mut ImageLoader) -> AxResult {
         info!("Loading VM images from filesystem");
-        // Load kernel image.
-        load_vm_image(
-            &loader.config.kernel.kernel_path,
-            loader.kernel_load_gpa,
-            loader.vm.clone(),
-        )?;
+        // Load kernel image, unless the firmware boots the guest.
+        if crate::vmm::uefi::is_uefi_boot(loader.vm.id()) {
+            info!("VM[{}] boots UEFI, kernel image not loaded", loader.vm.id());
+        } else {
+            load_vm_image(
+                &loader.config.kernel.kernel_path,
+                loader.kernel_load_gpa,
+                loader.vm.clone(),
+            )?;
+        }
         // Load BIOS image if needed.
         if let Some(bios_path) = &loader.config.kernel.bios_path {
             if let Some(bios_load_addr) = loader.bios_load_gpa {
@@ -235,12 +322,19 @@ pub mod fs {
             }
         };
         // Load Ramdisk image if needed.
-        if let Some(ramdisk_path) = &loader.config.kernel.ramdisk_path {
-            if let Some(ramdisk_load_addr) = loader.ramdisk_load_gpa {
-                load_vm_image(ramdisk_path, ramdisk_load_addr, loader.vm.clone())?;
-            } else {
-                return ax_err!(NotFound, "Ramdisk load addr is missed");
-            }
+        if let Some(ramdisk_path) = loader.config.kernel.ramdisk_path.clone() {
+            let ramdisk_load_addr = match loader.ramdisk_load_gpa {
+                Some(gpa) => gpa,
+                None if crate::vmm::uefi::is_uefi_boot(loader.vm.id()) => {
+                    return ax_err!(NotFound, "Ramdisk load addr is missed");
+                }
+                None => {
+                    let (_, kernel_size) = open_image_file(&loader.config.kernel.kernel_path)?;
+                    loader.ramdisk_load_gpa(kernel_size)
+                }
+            };
+            let ramdisk_size = load_vm_image(&ramdisk_path, ramdisk_load_addr, loader.vm.clone())?;
+            loader.linux.initrd = Some((ramdisk_load_addr, ramdisk_size));
         };
         // Load DTB image if needed.
         let vm_config = axvm::config::AxVMConfig::from(loader.config.clone());
@@ -251,18 +345,36 @@ pub mod fs {
                 core::ptr::NonNull::new(_dtb_slice.as_ptr() as *mut u8).unwrap(),
                 _dtb_slice.len(),
                 loader.vm.clone(),
+                &loader.linux,
             );
         }
 
         Ok(())
     }
 
-    fn load_vm_image(image_path: &str, image_load_gpa: GuestPhysAddr, vm: VMRef) -> AxResult {
+    /// Loads the image file at `image_path` at `image_load_gpa`, returning its size.
+    fn load_vm_image(
+        image_path: &str,
+        image_load_gpa: GuestPhysAddr,
+        vm: VMRef,
+    ) -> AxResult<usize> {
         use std::io::{BufReader, Read};
         let (image_file, image_size) = open_image_file(image_path)?;
+        let mut file = BufReader::new(image_file);
+
+        if crate::vmm::lazymem::load_shared_image(&vm, image_load_gpa, image_size, |buf| {
+            file.read_exact(buf).map_err(|err| {
+                ax_err_type!(
+                    Io,
+                    format!("Failed in reading from file {}, err {:?}", image_path, err)
+                )
+            })
+        })? {
+            return Ok(image_size);
+        }
 
+        crate::vmm::lazymem::populate(&vm, image_load_gpa, image_size)?;
         let image_load_regions = vm.get_image_load_region(image_load_gpa, image_size)?;
-        let mut file = BufReader::new(image_file);
 
         for buffer in image_load_regions {
             file.read_exact(buffer).map_err(|err| {
@@ -279,7 +391,7 @@ pub mod fs {
             );
         }
 
-        Ok(())
+        Ok(image_size)
     }
 
     pub fn open_image_file(file_name: &str) -> AxResult<(File, usize)> {
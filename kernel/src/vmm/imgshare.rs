@@ -0,0 +1,135 @@
+//! Pages of boot images shared between VMs.
+//!
+//! When several VMs boot the same kernel or initrd, the pages of the images are identical from
+//! one VM to the other. VMs that share their images (see [`crate::vmm::lazymem`]) map such pages
+//! read-only to a single frame of this pool instead of a copy of their own, and get a private
+//! copy when they first write to it.
+//!
+//! Frames are looked up by the content of the page, so images are shared whatever their source
+//! (built in or from the filesystem) as long as they are loaded at the same offset within a page.
+//! A frame is freed when the last VM mapping it drops it.
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use axaddrspace::{AxMmHal, HostPhysAddr};
+use axerrno::{AxResult, ax_err_type};
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::hal::{AxMmHalImpl, CacheOp};
+use crate::vmm::memstat::{self, MemSubsystem};
+use crate::vmm::reclaim;
+
+struct SharedFrame {
+    frame: HostPhysAddr,
+    refs: usize,
+}
+
+/// The shared frames, indexed by the hash of their content.
+static POOL: Mutex<BTreeMap<u64, Vec<SharedFrame>>> = Mutex::new(BTreeMap::new());
+
+/// Shared image pages.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SharedImageStats {
+    /// Frames in the pool.
+    pub frames: usize,
+    /// Mappings of the frames by VMs, each saving a page beyond the first of a frame.
+    pub mappings: usize,
+}
+
+/// FNV-1a hash of a page.
+fn hash(page: &[u8]) -> u64 {
+    page.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+fn frame_bytes(frame: HostPhysAddr) -> &'static mut [u8] {
+    // SAFETY: the frames of the pool are mapped in the linear mapping of the host and only
+    // written before they are shared.
+    unsafe {
+        core::slice::from_raw_parts_mut(AxMmHalImpl::phys_to_virt(frame).as_mut_ptr(), PAGE_SIZE_4K)
+    }
+}
+
+/// Returns a shared frame holding `page`, allocating it if no VM shares such a page yet. The
+/// caller holds a reference to the frame, dropped with [`release`].
+pub fn get(page: &[u8]) -> AxResult<HostPhysAddr> {
+    debug_assert_eq!(page.len(), PAGE_SIZE_4K);
+    let key = hash(page);
+    let mut pool = POOL.lock();
+    if let Some(shared) = pool
+        .get_mut(&key)
+        .and_then(|frames| frames.iter_mut().find(|f| frame_bytes(f.frame) == page))
+    {
+        shared.refs += 1;
+        return Ok(shared.frame);
+    }
+
+    let frame = reclaim::alloc_or_reclaim(AxMmHalImpl::alloc_frame)
+        .ok_or_else(|| ax_err_type!(NoMemory, "failed to allocate a shared image page"))?;
+    let bytes = frame_bytes(frame);
+    bytes.copy_from_slice(page);
+    crate::hal::arch::cache::dcache_range(
+        CacheOp::Clean,
+        (bytes.as_ptr() as usize).into(),
+        PAGE_SIZE_4K,
+    );
+    memstat::charge(MemSubsystem::Images, None, PAGE_SIZE_4K);
+    pool.entry(key)
+        .or_default()
+        .push(SharedFrame { frame, refs: 1 });
+    Ok(frame)
+}
+
+/// Takes another reference to shared `frame`, dropped with [`release`].
+pub fn acquire(frame: HostPhysAddr) {
+    let mut pool = POOL.lock();
+    let key = hash(frame_bytes(frame));
+    match pool
+        .get_mut(&key)
+        .and_then(|frames| frames.iter_mut().find(|f| f.frame == frame))
+    {
+        Some(shared) => shared.refs += 1,
+        None => warn!("shared image page {:#x} not in the pool", frame),
+    }
+}
+
+/// Copies the content of shared `frame` to `dst`, a page.
+pub fn copy_to(frame: HostPhysAddr, dst: &mut [u8]) {
+    dst.copy_from_slice(frame_bytes(frame));
+}
+
+/// Drops a reference to shared `frame`, freeing it if it was the last one.
+pub fn release(frame: HostPhysAddr) {
+    let mut pool = POOL.lock();
+    let key = hash(frame_bytes(frame));
+    let Some(frames) = pool.get_mut(&key) else {
+        warn!("shared image page {:#x} not in the pool", frame);
+        return;
+    };
+    let Some(idx) = frames.iter().position(|f| f.frame == frame) else {
+        warn!("shared image page {:#x} not in the pool", frame);
+        return;
+    };
+    frames[idx].refs -= 1;
+    if frames[idx].refs == 0 {
+        frames.swap_remove(idx);
+        if frames.is_empty() {
+            pool.remove(&key);
+        }
+        AxMmHalImpl::dealloc_frame(frame);
+        memstat::uncharge(MemSubsystem::Images, None, PAGE_SIZE_4K);
+    }
+}
+
+/// Returns the pages in the pool.
+pub fn shared_image_stats() -> SharedImageStats {
+    let pool = POOL.lock();
+    pool.values()
+        .flatten()
+        .fold(SharedImageStats::default(), |stats, f| SharedImageStats {
+            frames: stats.frames + 1,
+            mappings: stats.mappings + f.refs,
+        })
+}
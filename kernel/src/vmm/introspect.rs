@@ -0,0 +1,207 @@
+//! Introspection of guests by a manager VM.
+//!
+//! A manager VM (see [`crate::vmm::vmdef`]) can read and write the memory of the other VMs and
+//! read the state of their vCPUs, so that crash analyzers, memory scanners or live patching tools
+//! run in the manager instead of the hypervisor:
+//!
+//! - [`HVC_VM_READ_GUEST_MEM`] copies `args[3]` bytes at GPA `args[1]` of the VM with the peer
+//!   handle `args[0]` to GPA `args[2]` of the caller;
+//! - [`HVC_VM_WRITE_GUEST_MEM`] copies `args[3]` bytes at GPA `args[2]` of the caller to GPA
+//!   `args[1]` of the VM `args[0]`;
+//! - [`HVC_VM_GET_VCPU_REGS`] writes the state and registers of vCPU `args[1]` of the VM
+//!   `args[0]` as a [`VCpuRegs`] at GPA `args[2]` of the caller.
+//!
+//! A copy is at most [`MAX_INTROSPECT_SIZE`] bytes and both ranges must be in the RAM of their VM.
+//! The target VM is not paused, tools wanting a consistent view suspend it first. [`VCpuRegs`]
+//! holds the state the hypervisor sees, the last exit of the vCPU and its details (e.g. the number
+//! and first argument of a hypercall or the faulting address of a stage-2 fault) and its
+//! registers. A running vCPU is kicked out of the guest for them and the caller waits up to
+//! [`REGS_TIMEOUT`], see [`crate::vmm::regs`].
+//!
+//! What managers may do to a VM is part of its security policy, see
+//! [`crate::vmm::security::Introspection`]; every access is logged at debug level.
+//!
+//! [`HVC_VM_READ_GUEST_MEM`]: crate::vmm::hvc::HVC_VM_READ_GUEST_MEM
+//! [`HVC_VM_WRITE_GUEST_MEM`]: crate::vmm::hvc::HVC_VM_WRITE_GUEST_MEM
+//! [`HVC_VM_GET_VCPU_REGS`]: crate::vmm::hvc::HVC_VM_GET_VCPU_REGS
+use core::time::Duration;
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+
+use crate::task::AsVCpuTask;
+use crate::vmm::coredump::vcpu_state_code;
+use crate::vmm::hvc::{
+    self, HVC_VM_GET_VCPU_REGS, HVC_VM_READ_GUEST_MEM, HVC_VM_WRITE_GUEST_MEM, HvcService,
+};
+use crate::vmm::percpu::SCRATCH_WORDS;
+use crate::vmm::regs::{self, MAX_GUEST_REGS};
+use crate::vmm::security::{self, Introspection};
+use crate::vmm::{VMRef, peers, vcpus, vm_list};
+
+/// Bytes copied by a hypercall, at most.
+pub const MAX_INTROSPECT_SIZE: usize = 0x10000;
+
+/// How long [`HVC_VM_GET_VCPU_REGS`](crate::vmm::hvc::HVC_VM_GET_VCPU_REGS) waits for a running
+/// vCPU to exit.
+pub const REGS_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// State and registers of a vCPU, written by [`HVC_VM_GET_VCPU_REGS`](crate::vmm::hvc::HVC_VM_GET_VCPU_REGS).
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct VCpuRegs {
+    pub vcpu_id: u32,
+    /// Scheduling state, as in the vCPU notes of core dumps (see
+    /// [`crate::vmm::coredump::vcpu_state_code`]).
+    pub state: u32,
+    /// Physical CPU the vCPU runs or ran last on.
+    pub pcpu: u32,
+    /// Reason of the last exit, indexed like the exit counters of the statistics (see
+    /// [`crate::vmm::stats`]).
+    pub last_exit: u32,
+    /// Exits since the vCPU started.
+    pub exits: u64,
+    /// Details of the last exit: the hypercall number and first argument, the faulting or
+    /// accessed address, or the interrupt vector.
+    pub exit_details: [u64; SCRATCH_WORDS],
+    /// Registers in `regs`, 0 if they couldn't be read: the vCPU backend doesn't expose them or
+    /// the vCPU didn't exit in time.
+    pub reg_count: u32,
+    pub _reserved: u32,
+    /// When the registers were read, in nanoseconds of monotonic time.
+    pub regs_taken_ns: u64,
+    /// The registers, in the order of the `user_regs_struct` of the ELF core files of the
+    /// architecture (see [`crate::hal::arch::GuestRegs`]).
+    pub regs: [u64; MAX_GUEST_REGS],
+}
+
+/// The introspection hypercalls, for manager VMs.
+pub static HVC_SERVICE: HvcService = HvcService {
+    name: "introspection",
+    codes: HVC_VM_READ_GUEST_MEM..HVC_VM_GET_VCPU_REGS + 1,
+    permit: hvc::permit_managers,
+    deferrable: false,
+    handler: |vm, _, nr, args| match nr {
+        HVC_VM_READ_GUEST_MEM => read_guest_mem(vm, args),
+        HVC_VM_WRITE_GUEST_MEM => write_guest_mem(vm, args),
+        _ => get_vcpu_regs(vm, args).map(|_| 0),
+    },
+};
+
+/// Returns the VM with peer handle `handle` for the manager `vm`, if its policy allows `access`.
+pub(crate) fn target_vm(vm: &VMRef, handle: u64, access: Introspection) -> AxResult<VMRef> {
+    let target_id = peers::resolve(vm.id(), handle as usize)?;
+    let Some(target) = vm_list::get_vm_by_id(target_id) else {
+        return ax_err!(NotFound, format!("VM[{}] not found", target_id));
+    };
+    let allowed = security::introspection(target_id);
+    if allowed == Introspection::Off || (access == Introspection::Full && allowed != access) {
+        return ax_err!(
+            PermissionDenied,
+            format!("VM[{}] doesn't allow {:?} introspection", target_id, access)
+        );
+    }
+    Ok(target)
+}
+
+/// Copies `size` bytes at `src_gpa` of `src` to `dst_gpa` of `dst`.
+fn copy_guest(
+    src: &VMRef,
+    src_gpa: GuestPhysAddr,
+    dst: &VMRef,
+    dst_gpa: GuestPhysAddr,
+    size: usize,
+) -> AxResult {
+    let mut offset = 0;
+    while offset + 8 <= size {
+        let word: u64 = src.read_from_guest_of(src_gpa + offset)?;
+        dst.write_to_guest_of(dst_gpa + offset, &word)?;
+        offset += 8;
+    }
+    while offset < size {
+        let byte: u8 = src.read_from_guest_of(src_gpa + offset)?;
+        dst.write_to_guest_of(dst_gpa + offset, &byte)?;
+        offset += 1;
+    }
+    Ok(())
+}
+
+/// Handles the `HVMReadGuestMem` hypercall of the manager `vm`, returns the bytes copied.
+fn read_guest_mem(vm: &VMRef, args: [u64; 6]) -> AxResult<usize> {
+    let target = target_vm(vm, args[0], Introspection::Read)?;
+    let size = hvc::arg_count(args[3], MAX_INTROSPECT_SIZE, "introspection size")?;
+    let src = hvc::guest_range(&target, args[1], size, 1)?;
+    let dst = hvc::guest_range(vm, args[2], size, 1)?;
+    debug!(
+        "VM[{}] reads {:#x} bytes at {:?} of VM[{}]",
+        vm.id(),
+        size,
+        src,
+        target.id()
+    );
+    copy_guest(&target, src, vm, dst, size)?;
+    Ok(size)
+}
+
+/// Handles the `HVMWriteGuestMem` hypercall of the manager `vm`, returns the bytes copied.
+fn write_guest_mem(vm: &VMRef, args: [u64; 6]) -> AxResult<usize> {
+    let target = target_vm(vm, args[0], Introspection::Full)?;
+    let size = hvc::arg_count(args[3], MAX_INTROSPECT_SIZE, "introspection size")?;
+    let dst = hvc::guest_range(&target, args[1], size, 1)?;
+    let src = hvc::guest_range(vm, args[2], size, 1)?;
+    debug!(
+        "VM[{}] writes {:#x} bytes at {:?} of VM[{}]",
+        vm.id(),
+        size,
+        dst,
+        target.id()
+    );
+    copy_guest(vm, src, &target, dst, size)?;
+    Ok(size)
+}
+
+/// Handles the `HVMGetVcpuRegs` hypercall of the manager `vm`.
+fn get_vcpu_regs(vm: &VMRef, args: [u64; 6]) -> AxResult {
+    let target = target_vm(vm, args[0], Introspection::Read)?;
+    let vcpu_id = args[1] as usize;
+    let Some(vcpu) = target.vcpu_list().get(vcpu_id).cloned() else {
+        return ax_err!(
+            InvalidInput,
+            format!("VM[{}] has no vCPU {}", target.id(), vcpu_id)
+        );
+    };
+    let buf = hvc::guest_ptr::<VCpuRegs>(vm, args[2])?;
+    let (pcpu, (exits, last_exit, exit_details)) =
+        vcpus::with_vcpu_task(target.id(), vcpu_id, |task| {
+            (
+                task.cpu_id() as u32,
+                task.as_vcpu_task().progress.last_exit(),
+            )
+        })
+        .unwrap_or((0, (0, 0, [0; SCRATCH_WORDS])));
+    let snapshot = regs::capture(&target, vcpu_id, REGS_TIMEOUT);
+    let mut state = VCpuRegs {
+        vcpu_id: vcpu_id as u32,
+        state: vcpu_state_code(vcpu.state()),
+        pcpu,
+        last_exit: last_exit as u32,
+        exits,
+        exit_details,
+        reg_count: 0,
+        _reserved: 0,
+        regs_taken_ns: 0,
+        regs: [0; MAX_GUEST_REGS],
+    };
+    if let Some(snapshot) = snapshot {
+        state.reg_count = snapshot.regs.len() as u32;
+        state.regs_taken_ns = snapshot.taken_ns;
+        state.regs[..snapshot.regs.len()].copy_from_slice(&snapshot.regs);
+    }
+    debug!(
+        "VM[{}] reads the state of VM[{}] VCpu[{}]",
+        vm.id(),
+        target.id(),
+        vcpu_id
+    );
+    vm.write_to_guest_of(buf, &state)
+}
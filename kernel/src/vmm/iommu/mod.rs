@@ -0,0 +1,236 @@
+//! IOMMU programming for DMA isolation of passthrough devices.
+//!
+//! Every VM owning passthrough devices gets an IOMMU domain sharing the stage-2 (EPT) page table
+//! of the VM, so DMA issued by its devices is translated the same way as guest accesses and
+//! confined to guest memory. The platform IOMMU is described in the `[pci.iommu]` section of the
+//! VM config, the first VM describing it initializes the driver:
+//!
+//! ```toml
+//! [pci.iommu]
+//! # "smmuv3" on aarch64, "vtd" on x86_64.
+//! type = "smmuv3"
+//! # Physical base of the IOMMU registers.
+//! base = 0x0905_0000
+//! ```
+//!
+//! Streams not attached to any domain keep bypassing translation, so that DMA of devices owned by
+//! the hypervisor is not affected.
+#[cfg(target_arch = "aarch64")]
+mod smmuv3;
+#[cfg(target_arch = "x86_64")]
+mod vtd;
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+
+use std::os::arceos::modules::{axalloc, axhal};
+
+use axaddrspace::{GuestPhysAddr, HostPhysAddr};
+use axerrno::{AxResult, ax_err, ax_err_type};
+use memory_addr::{PAGE_SIZE_4K, VirtAddr};
+use spin::{Mutex, Once};
+
+use crate::vmm::{VMRef, stage2};
+
+/// An IOMMU translation domain, one per VM with passthrough devices.
+#[derive(Debug)]
+pub struct Domain {
+    /// Domain ID, the ID of the owner VM.
+    pub id: usize,
+    /// Root of the stage-2 page table shared with the VM.
+    pub root: HostPhysAddr,
+    /// IDs of the streams (requester IDs) attached to the domain.
+    pub streams: BTreeSet<u32>,
+}
+
+/// Operations of a platform IOMMU driver.
+pub trait IommuDriver: Send + Sync {
+    /// Name of the IOMMU, for logs.
+    fn name(&self) -> &'static str;
+
+    /// Makes `stream_id` translate through the page table of `domain`.
+    fn attach(&self, stream_id: u32, domain: &Domain) -> AxResult;
+
+    /// Restores `stream_id` to bypass.
+    fn detach(&self, stream_id: u32, domain: &Domain) -> AxResult;
+
+    /// Invalidates all cached translations of `domain`.
+    fn flush_domain(&self, domain: &Domain);
+}
+
+static IOMMU: Once<Box<dyn IommuDriver>> = Once::new();
+
+/// IOMMU domains, indexed by VM ID.
+static DOMAINS: Mutex<BTreeMap<usize, Domain>> = Mutex::new(BTreeMap::new());
+
+/// Owner VM IDs of the attached streams, indexed by stream ID.
+static STREAM_OWNERS: Mutex<BTreeMap<u32, usize>> = Mutex::new(BTreeMap::new());
+
+/// Initializes the platform IOMMU described in `cfg` (the `[pci.iommu]` section), if it's not
+/// initialized yet.
+pub fn init(cfg: &toml::Table) -> AxResult {
+    if IOMMU.is_completed() {
+        return Ok(());
+    }
+    let kind = cfg
+        .get("type")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| ax_err_type!(InvalidInput, "iommu config: missing `type`"))?;
+    let base = cfg
+        .get("base")
+        .and_then(|v| v.as_integer())
+        .map(|v| HostPhysAddr::from(v as usize))
+        .ok_or_else(|| ax_err_type!(InvalidInput, "iommu config: missing `base`"))?;
+
+    let driver: Box<dyn IommuDriver> = match kind {
+        #[cfg(target_arch = "aarch64")]
+        "smmuv3" => Box::new(smmuv3::SmmuV3::new(base)?),
+        #[cfg(target_arch = "x86_64")]
+        "vtd" => Box::new(vtd::VtdUnit::new(base)?),
+        _ => {
+            return ax_err!(
+                Unsupported,
+                format!("IOMMU type {:?} is not supported on this platform", kind)
+            );
+        }
+    };
+    info!("IOMMU {} initialized at {:#x}", driver.name(), base);
+    IOMMU.call_once(|| driver);
+    Ok(())
+}
+
+/// Attaches the device issuing DMA with `stream_id` to the IOMMU domain of the VM.
+pub fn attach_device(vm: &VMRef, stream_id: u32) -> AxResult {
+    let iommu = IOMMU
+        .get()
+        .ok_or_else(|| ax_err_type!(BadState, "IOMMU not initialized"))?;
+    let mut domains = DOMAINS.lock();
+    let mut owners = STREAM_OWNERS.lock();
+    if owners
+        .get(&stream_id)
+        .is_some_and(|owner| *owner != vm.id())
+    {
+        return ax_err!(
+            AlreadyExists,
+            format!("IOMMU stream {:#x} attached to another VM", stream_id)
+        );
+    }
+
+    let domain = domains.entry(vm.id()).or_insert_with(|| Domain {
+        id: vm.id(),
+        root: vm.ept_root(),
+        streams: BTreeSet::new(),
+    });
+    iommu.attach(stream_id, domain)?;
+    domain.streams.insert(stream_id);
+    owners.insert(stream_id, vm.id());
+    debug!(
+        "VM[{}] IOMMU stream {:#x} attached, root {:#x}",
+        vm.id(),
+        stream_id,
+        domain.root
+    );
+    Ok(())
+}
+
+/// Detaches all devices from the IOMMU domain of a VM and destroys the domain, called when the
+/// passthrough devices of the VM are released.
+pub fn detach_vm(vm_id: usize) {
+    let (Some(iommu), Some(domain)) = (IOMMU.get(), DOMAINS.lock().remove(&vm_id)) else {
+        return;
+    };
+    for &stream_id in &domain.streams {
+        STREAM_OWNERS.lock().remove(&stream_id);
+        if let Err(e) = iommu.detach(stream_id, &domain) {
+            warn!(
+                "VM[{}] failed to detach IOMMU stream {:#x}: {:?}",
+                vm_id, stream_id, e
+            );
+        }
+    }
+    iommu.flush_domain(&domain);
+}
+
+/// Invalidates the IOMMU translations of a VM, must be called after any stage-2 unmapping of a
+/// VM with passthrough devices.
+pub fn flush_vm(vm_id: usize) {
+    if let Some(iommu) = IOMMU.get()
+        && let Some(domain) = DOMAINS.lock().get(&vm_id)
+    {
+        iommu.flush_domain(domain);
+    }
+}
+
+/// Whether the VM has an IOMMU domain, i.e., passthrough devices issuing DMA through its stage-2.
+pub fn has_domain(vm_id: usize) -> bool {
+    DOMAINS.lock().contains_key(&vm_id)
+}
+
+/// Unmaps a guest physical region from the stage-2 of the VM and invalidates the stale IOMMU
+/// translations of the region.
+pub fn unmap_region(vm: &VMRef, gpa: GuestPhysAddr, size: usize) -> AxResult {
+    stage2::unmap_region(vm, gpa, size)?;
+    flush_vm(vm.id());
+    Ok(())
+}
+
+/// Allocates zeroed, physically contiguous and naturally aligned pages for IOMMU tables.
+fn alloc_table(num_pages: usize) -> AxResult<(HostPhysAddr, VirtAddr)> {
+    let size = num_pages * PAGE_SIZE_4K;
+    let vaddr = axalloc::global_allocator()
+        .alloc_pages(num_pages, size.next_power_of_two())
+        .map_err(|_| ax_err_type!(NoMemory, "Failed to allocate IOMMU table"))?;
+    // SAFETY: the pages are freshly allocated and owned by the IOMMU driver.
+    unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, size) };
+    let vaddr = VirtAddr::from(vaddr);
+    Ok((axhal::mem::virt_to_phys(vaddr), vaddr))
+}
+
+/// MMIO register accessor of an IOMMU.
+struct Regs {
+    base: VirtAddr,
+}
+
+impl Regs {
+    fn new(base: HostPhysAddr) -> Self {
+        Self {
+            base: axhal::mem::phys_to_virt(base),
+        }
+    }
+
+    fn read32(&self, offset: usize) -> u32 {
+        // SAFETY: `offset` is within the register frame of the IOMMU, which is mapped as MMIO.
+        unsafe { ((self.base + offset).as_ptr() as *const u32).read_volatile() }
+    }
+
+    fn write32(&self, offset: usize, val: u32) {
+        // SAFETY: `offset` is within the register frame of the IOMMU, which is mapped as MMIO.
+        unsafe { ((self.base + offset).as_mut_ptr() as *mut u32).write_volatile(val) }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn read64(&self, offset: usize) -> u64 {
+        // SAFETY: `offset` is within the register frame of the IOMMU, which is mapped as MMIO.
+        unsafe { ((self.base + offset).as_ptr() as *const u64).read_volatile() }
+    }
+
+    fn write64(&self, offset: usize, val: u64) {
+        // SAFETY: `offset` is within the register frame of the IOMMU, which is mapped as MMIO.
+        unsafe { ((self.base + offset).as_mut_ptr() as *mut u64).write_volatile(val) }
+    }
+
+    /// Polls `cond` on the 32-bit register at `offset`, returns a `TimedOut` error after too many
+    /// attempts.
+    fn poll32(&self, offset: usize, cond: impl Fn(u32) -> bool) -> AxResult {
+        for _ in 0..1_000_000 {
+            if cond(self.read32(offset)) {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        ax_err!(
+            TimedOut,
+            format!("IOMMU register {:#x} poll timed out", offset)
+        )
+    }
+}
@@ -0,0 +1,263 @@
+//! Arm SMMUv3 driver.
+//!
+//! Uses a linear stream table and the command queue only. Attached streams are configured for
+//! stage-2 translation with the VM ID as VMID, all other streams bypass.
+use axaddrspace::HostPhysAddr;
+use axerrno::{AxResult, ax_err};
+use memory_addr::{PAGE_SIZE_4K, VirtAddr};
+use spin::Mutex;
+
+use super::{Domain, IommuDriver, Regs, alloc_table};
+
+const SMMU_IDR0: usize = 0x00;
+const SMMU_IDR1: usize = 0x04;
+const SMMU_IDR5: usize = 0x14;
+const SMMU_CR0: usize = 0x20;
+const SMMU_CR0ACK: usize = 0x24;
+const SMMU_CR1: usize = 0x28;
+const SMMU_STRTAB_BASE: usize = 0x80;
+const SMMU_STRTAB_BASE_CFG: usize = 0x88;
+const SMMU_CMDQ_BASE: usize = 0x90;
+const SMMU_CMDQ_PROD: usize = 0x98;
+const SMMU_CMDQ_CONS: usize = 0x9c;
+
+const IDR0_S2P: u32 = 1 << 0;
+const CR0_SMMUEN: u32 = 1 << 0;
+const CR0_CMDQEN: u32 = 1 << 3;
+/// Inner/outer write-back cacheable, inner shareable table and queue walks.
+const CR1_WB_ISH: u32 = 0b11_01_01_11_01_01;
+const BASE_RA: u64 = 1 << 62;
+const CMDQ_CONS_ERR_MASK: u32 = 0x7f << 24;
+
+/// Log2 of the number of stream table entries, limits stream IDs to 16 bits.
+const MAX_STRTAB_LOG2: u32 = 16;
+/// Log2 of the number of command queue entries.
+const CMDQ_LOG2: u32 = 8;
+const STE_SIZE: usize = 64;
+const CMD_SIZE: usize = 16;
+
+const CMD_CFGI_STE: u64 = 0x03;
+const CMD_CFGI_ALL: u64 = 0x04;
+const CMD_TLBI_S12_VMALL: u64 = 0x28;
+const CMD_TLBI_NSNH_ALL: u64 = 0x30;
+const CMD_SYNC: u64 = 0x46;
+
+const STE_V: u64 = 1 << 0;
+const STE_CFG_BYPASS: u64 = 0b100 << 1;
+const STE_CFG_S2_TRANS: u64 = 0b110 << 1;
+/// Use the incoming shareability attribute.
+const STE_SHCFG_INCOMING: u64 = 1 << 44;
+/// Stage-2 configuration fields of the third double word.
+const STE_S2IR0_WB: u64 = 1 << 40;
+const STE_S2OR0_WB: u64 = 1 << 42;
+const STE_S2SH0_ISH: u64 = 0b11 << 44;
+const STE_S2AA64: u64 = 1 << 51;
+const STE_S2PTW: u64 = 1 << 54;
+const STE_S2R: u64 = 1 << 58;
+
+struct CmdQueue {
+    base: VirtAddr,
+    prod: u32,
+}
+
+/// An SMMUv3 instance.
+pub struct SmmuV3 {
+    regs: Regs,
+    strtab: VirtAddr,
+    strtab_log2: u32,
+    /// Output address size of the SMMU, the `S2PS` encoding.
+    oas: u64,
+    cmdq: Mutex<CmdQueue>,
+}
+
+impl SmmuV3 {
+    /// Resets the SMMU at `base` and enables it with all streams bypassing.
+    pub fn new(base: HostPhysAddr) -> AxResult<Self> {
+        let regs = Regs::new(base);
+        if regs.read32(SMMU_IDR0) & IDR0_S2P == 0 {
+            return ax_err!(Unsupported, "SMMUv3 does not support stage-2 translation");
+        }
+        let strtab_log2 = (regs.read32(SMMU_IDR1) & 0x3f).min(MAX_STRTAB_LOG2);
+        let oas = (regs.read32(SMMU_IDR5) & 0x7) as u64;
+
+        // Disable the SMMU before reprogramming its tables.
+        regs.write32(SMMU_CR0, 0);
+        regs.poll32(SMMU_CR0ACK, |v| v == 0)?;
+
+        let strtab_size = (1usize << strtab_log2) * STE_SIZE;
+        let (strtab_pa, strtab) = alloc_table(strtab_size.div_ceil(PAGE_SIZE_4K))?;
+        let cmdq_size = (1usize << CMDQ_LOG2) * CMD_SIZE;
+        let (cmdq_pa, cmdq) = alloc_table(cmdq_size.div_ceil(PAGE_SIZE_4K))?;
+
+        let smmu = Self {
+            regs,
+            strtab,
+            strtab_log2,
+            oas,
+            cmdq: Mutex::new(CmdQueue {
+                base: cmdq,
+                prod: 0,
+            }),
+        };
+        for sid in 0..(1u32 << strtab_log2) {
+            smmu.write_bypass_ste(sid);
+        }
+
+        let regs = &smmu.regs;
+        regs.write32(SMMU_CR1, CR1_WB_ISH);
+        regs.write64(
+            SMMU_STRTAB_BASE,
+            BASE_RA | (strtab_pa.as_usize() as u64 & 0xffff_ffff_ffc0),
+        );
+        // Linear format.
+        regs.write32(SMMU_STRTAB_BASE_CFG, strtab_log2);
+        regs.write64(
+            SMMU_CMDQ_BASE,
+            BASE_RA | (cmdq_pa.as_usize() as u64 & 0xffff_ffff_ffe0) | CMDQ_LOG2 as u64,
+        );
+        regs.write32(SMMU_CMDQ_PROD, 0);
+        regs.write32(SMMU_CMDQ_CONS, 0);
+
+        regs.write32(SMMU_CR0, CR0_CMDQEN);
+        regs.poll32(SMMU_CR0ACK, |v| v == CR0_CMDQEN)?;
+        smmu.submit(&[[CMD_CFGI_ALL, 31], [CMD_TLBI_NSNH_ALL, 0], [CMD_SYNC, 0]])?;
+
+        regs.write32(SMMU_CR0, CR0_CMDQEN | CR0_SMMUEN);
+        regs.poll32(SMMU_CR0ACK, |v| v == CR0_CMDQEN | CR0_SMMUEN)?;
+        Ok(smmu)
+    }
+
+    fn ste(&self, sid: u32) -> *mut u64 {
+        (self.strtab + sid as usize * STE_SIZE).as_mut_ptr() as *mut u64
+    }
+
+    /// Writes the 8 double words of STE `sid`. The first word holding the valid bit is written
+    /// last, so the SMMU never observes a half-written valid entry.
+    fn write_ste(&self, sid: u32, words: [u64; 8]) {
+        let ste = self.ste(sid);
+        // SAFETY: `sid` is checked against the stream table size by the callers.
+        unsafe {
+            for (i, word) in words.iter().enumerate().skip(1) {
+                ste.add(i).write_volatile(*word);
+            }
+            ste.write_volatile(words[0]);
+        }
+    }
+
+    fn write_bypass_ste(&self, sid: u32) {
+        self.write_ste(
+            sid,
+            [STE_V | STE_CFG_BYPASS, STE_SHCFG_INCOMING, 0, 0, 0, 0, 0, 0],
+        );
+    }
+
+    fn check_sid(&self, sid: u32) -> AxResult {
+        if sid >> self.strtab_log2 != 0 {
+            return ax_err!(
+                InvalidInput,
+                format!("SMMUv3 stream ID {:#x} out of range", sid)
+            );
+        }
+        Ok(())
+    }
+
+    /// Submits commands to the command queue and waits for their consumption.
+    fn submit(&self, cmds: &[[u64; 2]]) -> AxResult {
+        let mut cmdq = self.cmdq.lock();
+        let wrap_mask = (2u32 << CMDQ_LOG2) - 1;
+        let idx_mask = (1u32 << CMDQ_LOG2) - 1;
+
+        for cmd in cmds {
+            // Wait for a free slot: full when the indexes are equal but the wrap bits differ.
+            self.regs.poll32(SMMU_CMDQ_CONS, |cons| {
+                (cmdq.prod ^ cons) & wrap_mask != 1 << CMDQ_LOG2
+            })?;
+            let slot =
+                (cmdq.base + (cmdq.prod & idx_mask) as usize * CMD_SIZE).as_mut_ptr() as *mut u64;
+            // SAFETY: the slot is within the command queue, which is owned by this driver.
+            unsafe {
+                slot.write_volatile(cmd[0]);
+                slot.add(1).write_volatile(cmd[1]);
+            }
+            cmdq.prod = (cmdq.prod + 1) & wrap_mask;
+            self.regs.write32(SMMU_CMDQ_PROD, cmdq.prod);
+        }
+
+        let prod = cmdq.prod;
+        self.regs.poll32(SMMU_CMDQ_CONS, |cons| {
+            cons & wrap_mask == prod || cons & CMDQ_CONS_ERR_MASK != 0
+        })?;
+        let err = self.regs.read32(SMMU_CMDQ_CONS) & CMDQ_CONS_ERR_MASK;
+        if err != 0 {
+            return ax_err!(Io, format!("SMMUv3 command error {:#x}", err >> 24));
+        }
+        Ok(())
+    }
+
+    fn sync_ste(&self, sid: u32) -> AxResult {
+        // CFGI_STE with `Leaf` set, then SYNC.
+        self.submit(&[[CMD_CFGI_STE | (sid as u64) << 32, 1], [CMD_SYNC, 0]])
+    }
+}
+
+impl IommuDriver for SmmuV3 {
+    fn name(&self) -> &'static str {
+        "SMMUv3"
+    }
+
+    fn attach(&self, stream_id: u32, domain: &Domain) -> AxResult {
+        self.check_sid(stream_id)?;
+        // The stage-2 page table of the VM starts at level 0 with a 48-bit IPA space when it has
+        // 4 levels, at level 1 with a 39-bit IPA space otherwise.
+        let (t0sz, sl0) = if cfg!(feature = "ept-level-4") {
+            (16, 2)
+        } else {
+            (25, 1)
+        };
+        let s2cfg = domain.id as u64 & 0xffff
+            | t0sz << 32
+            | sl0 << 38
+            | STE_S2IR0_WB
+            | STE_S2OR0_WB
+            | STE_S2SH0_ISH
+            | self.oas << 48
+            | STE_S2AA64
+            | STE_S2PTW
+            | STE_S2R;
+
+        self.write_ste(
+            stream_id,
+            [
+                STE_V | STE_CFG_S2_TRANS,
+                STE_SHCFG_INCOMING,
+                s2cfg,
+                domain.root.as_usize() as u64 & 0xf_ffff_ffff_fff0,
+                0,
+                0,
+                0,
+                0,
+            ],
+        );
+        self.sync_ste(stream_id)?;
+        self.flush_domain(domain);
+        Ok(())
+    }
+
+    fn detach(&self, stream_id: u32, _domain: &Domain) -> AxResult {
+        self.check_sid(stream_id)?;
+        self.write_bypass_ste(stream_id);
+        self.sync_ste(stream_id)
+    }
+
+    fn flush_domain(&self, domain: &Domain) {
+        if let Err(e) = self.submit(&[
+            [CMD_TLBI_S12_VMALL | (domain.id as u64 & 0xffff) << 32, 0],
+            [CMD_SYNC, 0],
+        ]) {
+            warn!(
+                "SMMUv3 TLB invalidation of VMID {} failed: {:?}",
+                domain.id, e
+            );
+        }
+    }
+}
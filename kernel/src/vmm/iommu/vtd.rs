@@ -0,0 +1,223 @@
+//! Intel VT-d remapping unit driver.
+//!
+//! Uses legacy-mode root/context tables and register-based invalidation. All buses initially
+//! point to a shared context table passing DMA through untranslated; attaching a device gives its
+//! bus a private context table where the device translates through the second-level page table of
+//! the domain, which is the EPT of the VM.
+use alloc::collections::BTreeMap;
+
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::HostPhysAddr;
+use axerrno::{AxResult, ax_err};
+use memory_addr::{PAGE_SIZE_4K, VirtAddr};
+use spin::Mutex;
+
+use super::{Domain, IommuDriver, Regs, alloc_table};
+
+const VTD_CAP: usize = 0x08;
+const VTD_ECAP: usize = 0x10;
+const VTD_GCMD: usize = 0x18;
+const VTD_GSTS: usize = 0x1c;
+const VTD_RTADDR: usize = 0x20;
+const VTD_CCMD: usize = 0x28;
+
+/// Supports 4-level (48-bit) second-level page tables.
+const CAP_SAGAW_4LEVEL: u64 = 1 << 10;
+/// Coherent accesses to the translation structures.
+const ECAP_C: u64 = 1 << 0;
+/// Pass-through translation type.
+const ECAP_PT: u64 = 1 << 6;
+
+const GCMD_TE: u32 = 1 << 31;
+const GCMD_SRTP: u32 = 1 << 30;
+/// Bits of `GSTS` to be preserved when writing `GCMD`, excludes the one-shot commands.
+const GSTS_PERSISTENT_MASK: u32 = 0x96ff_ffff;
+
+const CCMD_ICC: u64 = 1 << 63;
+const CCMD_GLOBAL: u64 = 1 << 61;
+const IOTLB_IVT: u64 = 1 << 63;
+const IOTLB_GLOBAL: u64 = 1 << 60;
+const IOTLB_DOMAIN: u64 = 2 << 60;
+const IOTLB_DRAIN: u64 = 0b11 << 48;
+
+const ENTRY_PRESENT: u64 = 1 << 0;
+const CTX_TT_UNTRANSLATED: u64 = 0b00 << 2;
+const CTX_TT_PASSTHROUGH: u64 = 0b10 << 2;
+/// 48-bit address width, 4-level page table.
+const CTX_AW_48BIT: u64 = 0b010;
+/// Domain ID used by passthrough context entries.
+const PASSTHROUGH_DID: u64 = 0;
+
+const ENTRY_SIZE: usize = 16;
+const ENTRIES_PER_TABLE: usize = PAGE_SIZE_4K / ENTRY_SIZE;
+
+/// A DMA remapping unit.
+pub struct VtdUnit {
+    regs: Regs,
+    /// Offset of the IOTLB invalidate register.
+    iotlb_reg: usize,
+    root_table: VirtAddr,
+    /// Host physical address of the shared passthrough context table.
+    passthrough_ctx: HostPhysAddr,
+    /// Private context tables of the buses having attached devices.
+    bus_ctx: Mutex<BTreeMap<u8, VirtAddr>>,
+}
+
+/// Writes a 128-bit root or context entry, the low word holding the present bit last.
+///
+/// # Safety
+///
+/// `table` must be a root or context table owned by the driver and `idx` below 256.
+unsafe fn write_entry(table: VirtAddr, idx: usize, lo: u64, hi: u64) {
+    let entry = (table + idx * ENTRY_SIZE).as_mut_ptr() as *mut u64;
+    unsafe {
+        entry.add(1).write_volatile(hi);
+        entry.write_volatile(lo);
+    }
+}
+
+impl VtdUnit {
+    /// Enables the remapping unit at `base` with DMA of all devices passed through.
+    pub fn new(base: HostPhysAddr) -> AxResult<Self> {
+        let regs = Regs::new(base);
+        let cap = regs.read64(VTD_CAP);
+        let ecap = regs.read64(VTD_ECAP);
+        if cap & CAP_SAGAW_4LEVEL == 0 {
+            return ax_err!(Unsupported, "VT-d does not support 4-level page tables");
+        }
+        if ecap & ECAP_PT == 0 || ecap & ECAP_C == 0 {
+            return ax_err!(
+                Unsupported,
+                "VT-d without pass-through or coherent page walks is not supported"
+            );
+        }
+
+        let (root_pa, root_table) = alloc_table(1)?;
+        let (ctx_pa, ctx_table) = alloc_table(1)?;
+        for idx in 0..ENTRIES_PER_TABLE {
+            // SAFETY: both tables are freshly allocated and `idx` is in range.
+            unsafe {
+                write_entry(
+                    ctx_table,
+                    idx,
+                    ENTRY_PRESENT | CTX_TT_PASSTHROUGH,
+                    CTX_AW_48BIT | PASSTHROUGH_DID << 8,
+                );
+                write_entry(root_table, idx, ENTRY_PRESENT | ctx_pa.as_usize() as u64, 0);
+            }
+        }
+
+        let unit = Self {
+            iotlb_reg: (((ecap >> 8) & 0x3ff) as usize) * 16 + 8,
+            regs,
+            root_table,
+            passthrough_ctx: ctx_pa,
+            bus_ctx: Mutex::new(BTreeMap::new()),
+        };
+        unit.regs.write64(VTD_RTADDR, root_pa.as_usize() as u64);
+        unit.global_command(GCMD_SRTP)?;
+        unit.invalidate_context();
+        unit.invalidate_iotlb(IOTLB_GLOBAL);
+        unit.global_command(GCMD_TE)?;
+        Ok(unit)
+    }
+
+    /// Issues a global command and waits for the corresponding status bit.
+    fn global_command(&self, cmd: u32) -> AxResult {
+        let sts = self.regs.read32(VTD_GSTS) & GSTS_PERSISTENT_MASK;
+        self.regs.write32(VTD_GCMD, sts | cmd);
+        self.regs.poll32(VTD_GSTS, |v| v & cmd != 0)
+    }
+
+    fn poll64(&self, offset: usize, busy: u64) {
+        while self.regs.read64(offset) & busy != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn invalidate_context(&self) {
+        self.regs.write64(VTD_CCMD, CCMD_ICC | CCMD_GLOBAL);
+        self.poll64(VTD_CCMD, CCMD_ICC);
+    }
+
+    fn invalidate_iotlb(&self, granularity: u64) {
+        self.regs
+            .write64(self.iotlb_reg, IOTLB_IVT | IOTLB_DRAIN | granularity);
+        self.poll64(self.iotlb_reg, IOTLB_IVT);
+    }
+
+    /// Returns the private context table of `bus`, allocates one copying the passthrough entries
+    /// if the bus has none yet.
+    fn bus_context(&self, bus: u8) -> AxResult<VirtAddr> {
+        let mut bus_ctx = self.bus_ctx.lock();
+        if let Some(table) = bus_ctx.get(&bus) {
+            return Ok(*table);
+        }
+        let (ctx_pa, ctx_table) = alloc_table(1)?;
+        let shared = axhal::mem::phys_to_virt(self.passthrough_ctx);
+        // SAFETY: both tables are context tables of a page owned by the driver.
+        unsafe {
+            core::ptr::copy_nonoverlapping(shared.as_ptr(), ctx_table.as_mut_ptr(), PAGE_SIZE_4K);
+            write_entry(
+                self.root_table,
+                bus as usize,
+                ENTRY_PRESENT | ctx_pa.as_usize() as u64,
+                0,
+            );
+        }
+        bus_ctx.insert(bus, ctx_table);
+        Ok(ctx_table)
+    }
+}
+
+impl IommuDriver for VtdUnit {
+    fn name(&self) -> &'static str {
+        "VT-d"
+    }
+
+    fn attach(&self, stream_id: u32, domain: &Domain) -> AxResult {
+        if stream_id > u16::MAX as u32 {
+            return ax_err!(
+                InvalidInput,
+                format!("VT-d source ID {:#x} out of range", stream_id)
+            );
+        }
+        let table = self.bus_context((stream_id >> 8) as u8)?;
+        // SAFETY: `table` is a context table owned by the driver and the devfn is below 256.
+        unsafe {
+            write_entry(
+                table,
+                (stream_id & 0xff) as usize,
+                ENTRY_PRESENT
+                    | CTX_TT_UNTRANSLATED
+                    | (domain.root.as_usize() as u64 & !(PAGE_SIZE_4K as u64 - 1)),
+                CTX_AW_48BIT | (domain.id as u64 + 1) << 8,
+            );
+        }
+        self.invalidate_context();
+        self.flush_domain(domain);
+        Ok(())
+    }
+
+    fn detach(&self, stream_id: u32, _domain: &Domain) -> AxResult {
+        let Some(&table) = self.bus_ctx.lock().get(&((stream_id >> 8) as u8)) else {
+            return Ok(());
+        };
+        // SAFETY: `table` is a context table owned by the driver and the devfn is below 256.
+        unsafe {
+            write_entry(
+                table,
+                (stream_id & 0xff) as usize,
+                ENTRY_PRESENT | CTX_TT_PASSTHROUGH,
+                CTX_AW_48BIT | PASSTHROUGH_DID << 8,
+            );
+        }
+        self.invalidate_context();
+        Ok(())
+    }
+
+    fn flush_domain(&self, domain: &Domain) {
+        self.invalidate_iotlb(IOTLB_DOMAIN | (domain.id as u64 + 1) << 32);
+    }
+}
@@ -0,0 +1,450 @@
+//! Guest physical ranges emulated by a driver VM.
+//!
+//! A manager VM (see [`crate::vmm::vmdef`]) emulating devices for another VM in its userspace, like
+//! an ioreq server, becomes its I/O request server with the [`HVC_IOREQ_CONTROL`] hypercall,
+//! `args[0]` being the peer handle of the emulated VM and `args[1]` the operation:
+//!
+//! - [`IOREQ_ATTACH`]: the requests go to the raw IVC channel with key `args[2]` published by the
+//!   caller, which must hold a slot per vCPU of the VM. A VM has one server at a time;
+//! - [`IOREQ_DETACH`]: stops serving the VM, its ranges are released;
+//! - [`IOREQ_MAP`]: traps the accesses to the `args[3]` bytes at GPA `args[2]`, both page aligned,
+//!   outside of guest memory and of the regions already emulated by the hypervisor;
+//! - [`IOREQ_UNMAP`]: releases the range at GPA `args[2]`;
+//! - [`IOREQ_COMPLETE`]: completes the request of vCPU `args[2]`.
+//!
+//! The data of the channel is an [`IoreqHeader`] followed, from offset [`IOREQ_SLOTS_OFFSET`], by
+//! an [`IoreqSlot`] per vCPU of the VM, slot `n` being the one of vCPU `n`. An access of a vCPU to
+//! a trapped range fills its slot, sets its state to [`IOREQ_PENDING`], bumps `requests` and
+//! notifies the server with the vector it registered for the channel. The server writes the value
+//! read to `data` for a read, sets the state to [`IOREQ_DONE`] and completes the request. The
+//! hypervisor frees the slot when the vCPU resumes.
+//!
+//! The vCPU is blocked until the request is completed. If the server detaches or goes away
+//! first, the read returns the value of the register before the access and the write is dropped,
+//! and the VM shows the failure in the log. The server usually emulates DMA with the
+//! introspection hypercalls, so the emulated VM must allow full introspection, see
+//! [`crate::vmm::security::Introspection`].
+//!
+//! [`HVC_IOREQ_CONTROL`]: crate::vmm::hvc::HVC_IOREQ_CONTROL
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::{offset_of, size_of};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::os::arceos::modules::axtask;
+
+use axaddrspace::GuestPhysAddr;
+use axaddrspace::device::AccessWidth;
+use axerrno::{AxResult, ax_err, ax_err_type};
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::task::AsVCpuTask;
+use crate::vmm::hvc::{self, HVC_IOREQ_CONTROL, HvcService};
+use crate::vmm::mmio::{self, MmioTrapHandler};
+use crate::vmm::security::Introspection;
+use crate::vmm::{VMRef, introspect, ivc, vcpus};
+
+/// Operations of [`HVC_IOREQ_CONTROL`](crate::vmm::hvc::HVC_IOREQ_CONTROL), in `args[1]`.
+pub const IOREQ_ATTACH: u64 = 0;
+pub const IOREQ_DETACH: u64 = 1;
+pub const IOREQ_MAP: u64 = 2;
+pub const IOREQ_UNMAP: u64 = 3;
+pub const IOREQ_COMPLETE: u64 = 4;
+
+/// States of a slot, in `IoreqSlot::state`.
+pub const IOREQ_FREE: u32 = 0;
+pub const IOREQ_PENDING: u32 = 1;
+pub const IOREQ_DONE: u32 = 2;
+
+/// `magic` of [`IoreqHeader`], "AXIO".
+pub const IOREQ_MAGIC: u32 = u32::from_le_bytes(*b"AXIO");
+/// Version of the layouts of the channel.
+pub const IOREQ_VERSION: u16 = 1;
+/// Offset of the first slot in the data of the channel.
+pub const IOREQ_SLOTS_OFFSET: usize = 64;
+/// Ranges trapped in a VM, at most.
+pub const MAX_IOREQ_RANGES: usize = 64;
+
+/// Header of the data of the channel.
+#[repr(C)]
+pub struct IoreqHeader {
+    pub magic: u32,
+    pub version: u16,
+    /// Size of an [`IoreqSlot`].
+    pub slot_size: u16,
+    /// Slots in the channel, the vCPUs of the VM.
+    pub slots: u32,
+    pub _reserved: u32,
+    /// Requests sent since the server attached.
+    pub requests: AtomicU64,
+}
+
+/// The request of a vCPU.
+#[repr(C)]
+pub struct IoreqSlot {
+    /// [`IOREQ_FREE`], [`IOREQ_PENDING`] or [`IOREQ_DONE`].
+    pub state: AtomicU32,
+    /// 1 for a write, 0 for a read.
+    pub is_write: u8,
+    /// Bytes accessed: 1, 2, 4 or 8.
+    pub size: u8,
+    pub _reserved: u16,
+    /// GPA accessed.
+    pub addr: u64,
+    /// Value written, or the value read written by the server.
+    pub data: u64,
+}
+
+struct Server {
+    vm_id: usize,
+    key: usize,
+    /// Sizes of the trapped ranges, indexed by GPA.
+    ranges: BTreeMap<usize, usize>,
+    /// The flags the vCPUs waiting for their request are woken with, indexed by vCPU.
+    waiters: Vec<Option<Arc<AtomicBool>>>,
+    requests: u64,
+}
+
+/// Servers, indexed by the ID of the emulated VM.
+static SERVERS: Mutex<BTreeMap<usize, Server>> = Mutex::new(BTreeMap::new());
+
+/// The requests of a VM, for monitoring.
+#[derive(Debug, Clone, Copy)]
+pub struct IoreqStats {
+    pub server_vm_id: usize,
+    pub ranges: usize,
+    pub requests: u64,
+}
+
+/// The I/O request hypercall, for manager VMs.
+pub static HVC_SERVICE: HvcService = HvcService {
+    name: "ioreq",
+    codes: HVC_IOREQ_CONTROL..HVC_IOREQ_CONTROL + 1,
+    permit: hvc::permit_managers,
+    deferrable: false,
+    handler: |vm, _, _, args| handle_control(vm, args).map(|_| 0),
+};
+
+/// The trap of the ranges of a VM, forwarding the accesses to its server.
+struct IoreqTrap;
+
+impl MmioTrapHandler for IoreqTrap {
+    fn handle_read(&self, vm: &VMRef, addr: GuestPhysAddr, width: AccessWidth) -> AxResult<usize> {
+        forward(vm, addr, width, None)
+    }
+
+    fn handle_write(
+        &self,
+        vm: &VMRef,
+        addr: GuestPhysAddr,
+        width: AccessWidth,
+        val: usize,
+    ) -> AxResult {
+        forward(vm, addr, width, Some(val)).map(|_| ())
+    }
+}
+
+/// Returns the slot of vCPU `vcpu_id` in the data of the channel.
+fn slot(data: &mut [u8], vcpu_id: usize) -> *mut IoreqSlot {
+    let offset = IOREQ_SLOTS_OFFSET + vcpu_id * size_of::<IoreqSlot>();
+    data[offset..offset + size_of::<IoreqSlot>()]
+        .as_mut_ptr()
+        .cast()
+}
+
+/// Handles the `HIoreqControl` hypercall of the manager `vm`.
+fn handle_control(vm: &VMRef, args: [u64; 6]) -> AxResult {
+    let target = introspect::target_vm(vm, args[0], Introspection::Full)?;
+    if target.id() == vm.id() {
+        return ax_err!(InvalidInput, "a VM cannot serve its own I/O requests");
+    }
+    match args[1] {
+        IOREQ_ATTACH => attach(vm.id(), &target, args[2] as usize),
+        IOREQ_DETACH => detach(vm.id(), target.id()),
+        IOREQ_MAP => map(vm.id(), &target, args),
+        IOREQ_UNMAP => unmap(vm.id(), target.id(), args[2] as usize),
+        IOREQ_COMPLETE => complete(vm.id(), target.id(), args[2] as usize),
+        op => ax_err!(
+            InvalidInput,
+            format!("unknown I/O request operation {}", op)
+        ),
+    }
+}
+
+fn attach(server_vm_id: usize, target: &VMRef, key: usize) -> AxResult {
+    let mut servers = SERVERS.lock();
+    if let Some(other) = servers.get(&target.id()) {
+        return ax_err!(
+            ResourceBusy,
+            format!("VM[{}] is served by VM[{}]", target.id(), other.vm_id)
+        );
+    }
+    if servers
+        .values()
+        .any(|server| server.vm_id == server_vm_id && server.key == key)
+    {
+        return ax_err!(
+            ResourceBusy,
+            format!("IVC channel {:#x} already receives I/O requests", key)
+        );
+    }
+    let vcpus = target.vcpu_num();
+    let fits = ivc::access_raw_channel(server_vm_id, key, |data| {
+        if data.len() < IOREQ_SLOTS_OFFSET + vcpus * size_of::<IoreqSlot>() {
+            return false;
+        }
+        data.fill(0);
+        let header = IoreqHeader {
+            magic: IOREQ_MAGIC,
+            version: IOREQ_VERSION,
+            slot_size: size_of::<IoreqSlot>() as u16,
+            slots: vcpus as u32,
+            _reserved: 0,
+            requests: AtomicU64::new(0),
+        };
+        // SAFETY: the header fits in `data`, which is longer than `IOREQ_SLOTS_OFFSET`.
+        unsafe { data.as_mut_ptr().cast::<IoreqHeader>().write_unaligned(header) };
+        true
+    })?;
+    if !fits {
+        return ax_err!(
+            InvalidInput,
+            format!("IVC channel too small for {} I/O request slots", vcpus)
+        );
+    }
+    servers.insert(
+        target.id(),
+        Server {
+            vm_id: server_vm_id,
+            key,
+            ranges: BTreeMap::new(),
+            waiters: (0..vcpus).map(|_| None).collect(),
+            requests: 0,
+        },
+    );
+    info!(
+        "VM[{}] serves the I/O requests of VM[{}], channel {:#x}",
+        server_vm_id,
+        target.id(),
+        key
+    );
+    Ok(())
+}
+
+/// Returns the server `server_vm_id` of VM `target_id`.
+fn server_of(
+    servers: &mut BTreeMap<usize, Server>,
+    server_vm_id: usize,
+    target_id: usize,
+) -> AxResult<&mut Server> {
+    match servers.get_mut(&target_id) {
+        Some(server) if server.vm_id == server_vm_id => Ok(server),
+        _ => ax_err!(
+            NotFound,
+            format!(
+                "VM[{}] doesn't serve the I/O requests of VM[{}]",
+                server_vm_id, target_id
+            )
+        ),
+    }
+}
+
+/// Releases the ranges of `server` and wakes the vCPUs waiting for it, which find it gone.
+fn remove(target_id: usize, server: Server) {
+    for base in server.ranges.keys() {
+        if let Err(e) = mmio::unregister_trap(target_id, (*base).into()) {
+            warn!(
+                "VM[{}] failed to release I/O range {:#x}: {:?}",
+                target_id, base, e
+            );
+        }
+    }
+    let mut waiting = false;
+    for woken in server.waiters.into_iter().flatten() {
+        woken.store(true, Ordering::Release);
+        waiting = true;
+    }
+    if waiting {
+        vcpus::notify_all_vcpus(target_id);
+    }
+}
+
+fn detach(server_vm_id: usize, target_id: usize) -> AxResult {
+    let server = {
+        let mut servers = SERVERS.lock();
+        server_of(&mut servers, server_vm_id, target_id)?;
+        servers.remove(&target_id).unwrap()
+    };
+    info!(
+        "VM[{}] stopped serving the I/O requests of VM[{}]",
+        server_vm_id, target_id
+    );
+    remove(target_id, server);
+    Ok(())
+}
+
+fn map(server_vm_id: usize, target: &VMRef, args: [u64; 6]) -> AxResult {
+    let (gpa, size) = (args[2] as usize, args[3] as usize);
+    if gpa % PAGE_SIZE_4K != 0 || size % PAGE_SIZE_4K != 0 || size == 0 {
+        return ax_err!(InvalidInput, "I/O range not page aligned");
+    }
+    let Some(end) = gpa.checked_add(size) else {
+        return ax_err!(InvalidInput, "I/O range out of the address space");
+    };
+    if target.memory_regions().iter().any(|region| {
+        gpa < region.gpa.as_usize() + region.size() && region.gpa.as_usize() < end
+    }) {
+        return ax_err!(
+            InvalidInput,
+            format!("I/O range {:#x} overlaps guest memory", gpa)
+        );
+    }
+
+    let mut servers = SERVERS.lock();
+    let server = server_of(&mut servers, server_vm_id, target.id())?;
+    if server.ranges.len() >= MAX_IOREQ_RANGES {
+        return ax_err!(
+            NoMemory,
+            format!("at most {} I/O ranges", MAX_IOREQ_RANGES)
+        );
+    }
+    mmio::register_trap(target.id(), gpa.into(), size, Arc::new(IoreqTrap))?;
+    server.ranges.insert(gpa, size);
+    debug!(
+        "VM[{}] emulates [{:#x}, {:#x}) of VM[{}]",
+        server_vm_id,
+        gpa,
+        end,
+        target.id()
+    );
+    Ok(())
+}
+
+fn unmap(server_vm_id: usize, target_id: usize, gpa: usize) -> AxResult {
+    let mut servers = SERVERS.lock();
+    let server = server_of(&mut servers, server_vm_id, target_id)?;
+    if server.ranges.remove(&gpa).is_none() {
+        return ax_err!(NotFound, format!("no I/O range at {:#x}", gpa));
+    }
+    mmio::unregister_trap(target_id, gpa.into())
+}
+
+fn complete(server_vm_id: usize, target_id: usize, vcpu_id: usize) -> AxResult {
+    {
+        let mut servers = SERVERS.lock();
+        let server = server_of(&mut servers, server_vm_id, target_id)?;
+        let Some(Some(woken)) = server.waiters.get(vcpu_id) else {
+            return ax_err!(
+                InvalidInput,
+                format!("VCpu[{}] has no pending I/O request", vcpu_id)
+            );
+        };
+        woken.store(true, Ordering::Release);
+    }
+    vcpus::notify_all_vcpus(target_id);
+    Ok(())
+}
+
+/// Sends the access of the current vCPU of `vm` to its server and waits for the answer, returns
+/// the value read.
+fn forward(
+    vm: &VMRef,
+    addr: GuestPhysAddr,
+    width: AccessWidth,
+    write: Option<usize>,
+) -> AxResult<usize> {
+    let vm_id = vm.id();
+    let vcpu_id = axtask::current().as_vcpu_task().vcpu.id();
+    let woken = Arc::new(AtomicBool::new(false));
+    {
+        let mut servers = SERVERS.lock();
+        let server = servers.get_mut(&vm_id).ok_or_else(|| {
+            ax_err_type!(NotFound, format!("VM[{}] has no I/O request server", vm_id))
+        })?;
+        let requests = server.requests + 1;
+        let request = IoreqSlot {
+            state: AtomicU32::new(IOREQ_FREE),
+            is_write: write.is_some() as u8,
+            size: width.size() as u8,
+            _reserved: 0,
+            addr: addr.as_usize() as u64,
+            data: write.unwrap_or(0) as u64,
+        };
+        ivc::write_raw_channel(server.vm_id, server.key, |data| {
+            let slot = slot(data, vcpu_id);
+            let count = data[offset_of!(IoreqHeader, requests)..].as_mut_ptr();
+            // SAFETY: the slot was sized for the vCPU by `attach`, the server only writes it once
+            // it is pending. `requests` is 8-byte aligned in the page of the channel.
+            unsafe {
+                slot.write_volatile(request);
+                (*slot).state.store(IOREQ_PENDING, Ordering::Release);
+                (*count.cast::<AtomicU64>()).store(requests, Ordering::Release);
+            }
+        })?;
+        server.requests = requests;
+        server.waiters[vcpu_id] = Some(woken.clone());
+    }
+
+    // Woken by the server completing the request, the server going away or the VM stopping.
+    vcpus::wait_for(vm_id, || vm.stopping() || woken.load(Ordering::Acquire));
+
+    let mut servers = SERVERS.lock();
+    let Some(server) = servers.get_mut(&vm_id).filter(|server| {
+        server.waiters[vcpu_id]
+            .as_ref()
+            .is_some_and(|waiter| Arc::ptr_eq(waiter, &woken))
+    }) else {
+        return ax_err!(
+            BadState,
+            format!("the I/O request server of VM[{}] is gone", vm_id)
+        );
+    };
+    server.waiters[vcpu_id] = None;
+    let (state, data) = ivc::access_raw_channel(server.vm_id, server.key, |data| {
+        let slot = slot(data, vcpu_id);
+        // SAFETY: the slot is within the channel, the server may still write it.
+        unsafe {
+            let state = (*slot).state.swap(IOREQ_FREE, Ordering::AcqRel);
+            (state, core::ptr::read_volatile(&raw const (*slot).data))
+        }
+    })?;
+    if state != IOREQ_DONE {
+        return ax_err!(
+            BadState,
+            format!("I/O request of VM[{}] VCpu[{}] not answered", vm_id, vcpu_id)
+        );
+    }
+    Ok(data as usize & (usize::MAX >> (usize::BITS as usize - width.size() * 8)))
+}
+
+/// Returns the I/O requests of VM `vm_id`, `None` if no server emulates ranges of it.
+pub fn ioreq_stats(vm_id: usize) -> Option<IoreqStats> {
+    SERVERS.lock().get(&vm_id).map(|server| IoreqStats {
+        server_vm_id: server.vm_id,
+        ranges: server.ranges.len(),
+        requests: server.requests,
+    })
+}
+
+/// Removes the server of and the servers of other VMs run by the VM, called when the VM is
+/// destroyed.
+pub fn teardown_vm_ioreq(vm_id: usize) {
+    let removed: Vec<_> = {
+        let mut servers = SERVERS.lock();
+        // The traps of the VM itself are dropped with the VM.
+        servers.remove(&vm_id);
+        let targets: Vec<_> = servers
+            .iter()
+            .filter(|(_, server)| server.vm_id == vm_id)
+            .map(|(target_id, _)| *target_id)
+            .collect();
+        targets
+            .into_iter()
+            .filter_map(|target_id| Some((target_id, servers.remove(&target_id)?)))
+            .collect()
+    };
+    for (target_id, server) in removed {
+        remove(target_id, server);
+    }
+}
@@ -0,0 +1,36 @@
+//! Interrupt lines of the devices emulated by the hypervisor.
+//!
+//! Emulated devices raise their interrupts from any context: the vCPU of a peer VM, a worker task
+//! or a host timer. An [`IrqLine`] routes the interrupt to the emulated interrupt controller of the
+//! VM if it has one (the virtual PLIC on riscv64, see [`crate::vmm::vintc`]). Otherwise the
+//! interrupt is posted to vCPU 0 of the VM, see [`crate::vmm::posted`].
+use crate::vmm::posted;
+
+/// An interrupt line of an emulated device to its VM.
+#[derive(Debug, Clone, Copy)]
+pub struct IrqLine {
+    vm_id: usize,
+    irq: usize,
+}
+
+impl IrqLine {
+    pub const fn new(vm_id: usize, irq: usize) -> Self {
+        Self { vm_id, irq }
+    }
+
+    /// The interrupt number of the line.
+    pub fn irq(&self) -> usize {
+        self.irq
+    }
+
+    /// Raises the interrupt and notifies vCPU 0 of the VM.
+    pub fn raise(&self) {
+        #[cfg(target_arch = "riscv64")]
+        if crate::vmm::vintc::raise(self.vm_id, self.irq) {
+            return;
+        }
+        if let Err(e) = posted::post(self.vm_id, 0, self.irq) {
+            trace!("VM[{}] irq {} dropped: {:?}", self.vm_id, self.irq, e);
+        }
+    }
+}
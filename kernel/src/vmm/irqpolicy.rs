@@ -0,0 +1,206 @@
+//! Rate limits and priorities of the interrupts VMs send to each other.
+//!
+//! A guest interrupts its peers with IVC kicks ([`HVC_IVC_KICK`]) and doorbells
+//! ([`HVC_RT_DOORBELL`], see [`crate::vmm::doorbell`]), each costing the peer an exit and an
+//! interrupt. A misbehaving guest kicking in a loop would keep its peers from running, so the rate
+//! at which a VM sends them can be capped in the `[irq]` section of its config:
+//!
+//! ```toml
+//! [irq]
+//! # Interrupts per second the VM may send to other VMs, 0 (the default) for no limit.
+//! send_rate = 10000
+//! # Interrupts the VM may send at once above the rate, `send_rate / 10` by default.
+//! send_burst = 1000
+//! # "high" for real-time VMs, "normal" otherwise. Partitioned VMs are "high" by default.
+//! priority = "normal"
+//! ```
+//!
+//! Every kick and doorbell ring counts, and the limit is enforced with the generic cell rate
+//! algorithm on a single timestamp per VM, so that the doorbell path stays lock-free. An interrupt
+//! over the limit is refused, not queued: both hypercalls return [`IRQ_THROTTLED`] and the guest
+//! tries again later. Whatever it put in a channel stays there for the peer to find on its next
+//! poll or kick.
+//!
+//! The interrupts of a high-priority VM are never throttled, so that a real-time guest signals its
+//! peers in bounded time whatever its limit, and a kick notifying several peers raises the
+//! interrupts of the high-priority ones first. The IPIs between the vCPUs of a VM aren't limited.
+//!
+//! [`HVC_IVC_KICK`]: crate::vmm::hvc::HVC_IVC_KICK
+//! [`HVC_RT_DOORBELL`]: crate::vmm::hvc::HVC_RT_DOORBELL
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use std::os::arceos::modules::axhal;
+
+use axerrno::{AxResult, ax_err};
+
+use crate::vmm::{VMRef, sched};
+
+/// Maximum number of VMs with an interrupt policy, VM IDs must be below this.
+pub const MAX_POLICY_VMS: usize = 128;
+/// Return value of a kick or doorbell hypercall refused by the rate limit of the caller.
+pub const IRQ_THROTTLED: isize = -2;
+
+/// The interrupt policy of a VM.
+struct VmPolicy {
+    /// Nanoseconds between two interrupts at the limit, 0 for no limit.
+    interval_ns: AtomicU64,
+    /// How far ahead of the current time `tat` may get, the burst.
+    tolerance_ns: AtomicU64,
+    /// Theoretical arrival time of the next interrupt at the limit.
+    tat: AtomicU64,
+    high_priority: AtomicBool,
+    sent: AtomicU64,
+    throttled: AtomicU64,
+}
+
+impl VmPolicy {
+    const fn new() -> Self {
+        Self {
+            interval_ns: AtomicU64::new(0),
+            tolerance_ns: AtomicU64::new(0),
+            tat: AtomicU64::new(0),
+            high_priority: AtomicBool::new(false),
+            sent: AtomicU64::new(0),
+            throttled: AtomicU64::new(0),
+        }
+    }
+}
+
+static POLICIES: [VmPolicy; MAX_POLICY_VMS] = [const { VmPolicy::new() }; MAX_POLICY_VMS];
+
+/// The interrupt policy of a VM, for monitoring.
+#[derive(Debug, Clone, Copy)]
+pub struct IrqPolicyStats {
+    /// Interrupts per second the VM may send, 0 for no limit.
+    pub send_rate: u64,
+    pub high_priority: bool,
+    /// Interrupts sent to other VMs.
+    pub sent: u64,
+    /// Interrupts refused by the limit.
+    pub throttled: u64,
+}
+
+/// Charges an interrupt of VM `vm_id` to another VM to its limit, returns whether the VM may send
+/// it. Lock-free, called on the doorbell fast path.
+#[inline]
+pub fn admit(vm_id: usize) -> bool {
+    let Some(policy) = POLICIES.get(vm_id) else {
+        return true;
+    };
+    let interval = policy.interval_ns.load(Ordering::Relaxed);
+    if interval == 0 || policy.high_priority.load(Ordering::Relaxed) {
+        policy.sent.fetch_add(1, Ordering::Relaxed);
+        return true;
+    }
+    let tolerance = policy.tolerance_ns.load(Ordering::Relaxed);
+    let now = axhal::time::monotonic_time_nanos();
+    let mut tat = policy.tat.load(Ordering::Relaxed);
+    loop {
+        let next = tat.max(now) + interval;
+        if next - now > tolerance + interval {
+            policy.throttled.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        match policy
+            .tat
+            .compare_exchange_weak(tat, next, Ordering::Relaxed, Ordering::Relaxed)
+        {
+            Ok(_) => break,
+            Err(current) => tat = current,
+        }
+    }
+    policy.sent.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// Whether VM `vm_id` is a high-priority VM, whose interrupts are never throttled.
+pub fn is_high_priority(vm_id: usize) -> bool {
+    POLICIES
+        .get(vm_id)
+        .is_some_and(|policy| policy.high_priority.load(Ordering::Relaxed))
+}
+
+/// Sets the interrupt policy of the VM from the `[irq]` section of `raw_cfg`, called once its
+/// scheduling mode is known.
+pub fn setup_vm_irq_policy(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let cfg = raw_cfg.get("irq").and_then(|v| v.as_table());
+    let int = |key: &str| {
+        cfg.and_then(|cfg| cfg.get(key))
+            .and_then(|v| v.as_integer())
+            .filter(|v| *v >= 0)
+            .map(|v| v as u64)
+    };
+    let send_rate = int("send_rate").unwrap_or(0);
+    let high_priority = match cfg.and_then(|cfg| cfg.get("priority")).map(|v| v.as_str()) {
+        None => sched::is_partitioned(vm.id()),
+        Some(Some("high")) => true,
+        Some(Some("normal")) => false,
+        Some(_) => {
+            return ax_err!(
+                InvalidInput,
+                "irq config: `priority` must be \"high\" or \"normal\""
+            );
+        }
+    };
+    let Some(policy) = POLICIES.get(vm.id()) else {
+        if send_rate > 0 || high_priority {
+            return ax_err!(
+                InvalidInput,
+                format!("VM[{}] ID too large for an interrupt policy", vm.id())
+            );
+        }
+        return Ok(());
+    };
+
+    let interval_ns = if send_rate > 0 {
+        (1_000_000_000 / send_rate).max(1)
+    } else {
+        0
+    };
+    let burst = int("send_burst").unwrap_or(send_rate / 10).max(1);
+    policy.interval_ns.store(interval_ns, Ordering::Relaxed);
+    policy
+        .tolerance_ns
+        .store(interval_ns.saturating_mul(burst - 1), Ordering::Relaxed);
+    policy.tat.store(0, Ordering::Relaxed);
+    policy.high_priority.store(high_priority, Ordering::Relaxed);
+    policy.sent.store(0, Ordering::Relaxed);
+    policy.throttled.store(0, Ordering::Relaxed);
+    if send_rate > 0 || high_priority {
+        info!(
+            "VM[{}] interrupt policy: {} priority, {}",
+            vm.id(),
+            if high_priority { "high" } else { "normal" },
+            if send_rate > 0 {
+                format!("{} per second, bursts of {}", send_rate, burst)
+            } else {
+                "no limit".into()
+            }
+        );
+    }
+    Ok(())
+}
+
+/// Clears the interrupt policy of a VM, called when the VM is destroyed.
+pub fn teardown_vm_irq_policy(vm_id: usize) {
+    if let Some(policy) = POLICIES.get(vm_id) {
+        policy.interval_ns.store(0, Ordering::Relaxed);
+        policy.high_priority.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Returns the interrupt policy of VM `vm_id`.
+pub fn irq_policy_stats(vm_id: usize) -> Option<IrqPolicyStats> {
+    let policy = POLICIES.get(vm_id)?;
+    let interval = policy.interval_ns.load(Ordering::Relaxed);
+    Some(IrqPolicyStats {
+        send_rate: if interval > 0 {
+            1_000_000_000 / interval
+        } else {
+            0
+        },
+        high_priority: policy.high_priority.load(Ordering::Relaxed),
+        sent: policy.sent.load(Ordering::Relaxed),
+        throttled: policy.throttled.load(Ordering::Relaxed),
+    })
+}
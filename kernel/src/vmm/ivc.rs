@@ -1,47 +1,302 @@
 //! Inter-VM communication (IVC) module.
+//!
+//! A channel is a shared page published by one VM and mapped by its subscribers. It starts with an
+//! [`IVCChannelHeader`], the rest of the page is left to the guests ("raw" channels), unless the
+//! publisher asks for the ring layout when it publishes the channel.
+//!
+//! # Channel handles
+//!
+//! Guests name a channel by its publisher (a peer handle, see [`crate::vmm::peers`]) and key only
+//! to subscribe to it. Publishing and subscribing return a channel handle instead, an opaque
+//! nonzero number valid for the calling VM only, which the other IVC hypercalls (unpublish,
+//! unsubscribe, [`HVC_IVC_KICK`], [`HVC_IVC_BROADCAST`]) take. Each VM has its own handle table:
+//! a handle is dropped when the VM detaches from its channel (or the channel is torn down with
+//! its publisher) and is never reused, so a stale handle fails instead of naming another channel,
+//! and a VM cannot name a channel it is not attached to.
+//!
+//! # Ring channels
+//!
+//! A ring channel has exactly one subscriber and carries two lock-free single-producer
+//! single-consumer rings of fixed-size slots: `rings[0]` from the publisher to the subscriber and
+//! `rings[1]` back. Their layout is described by an [`IVCRingHeader`] following the channel
+//! header, written and validated by the hypervisor on publish:
+//!
+//! - `head` is only written by the producer, `tail` only by the consumer, both are free-running
+//!   and the slot of index `i` is `i % slot_count`. The ring is empty if `head == tail` and full if
+//!   `head - tail == slot_count`.
+//! - The producer publishes a slot by writing it, then `head` with release semantics. The
+//!   consumer reads `head` with acquire semantics, then the slot, then writes `tail`.
+//!
+//! The hypervisor also keeps credit counters in each ring, refreshed on every [`HVC_IVC_KICK`] and
+//! [`HVC_IVC_WAIT_SPACE`] of either end: `producer_credits` is the number of free slots and
+//! `consumer_credits` the number of slots to consume, as of that update. They are hints, the
+//! indices are authoritative.
+//!
+//! The notification mode is chosen per channel on publish:
+//!
+//! - [`IVCNotifyMode::Poll`]: guests poll the indices, the hypervisor is not involved after setup
+//!   unless a producer waits for room (see below).
+//! - [`IVCNotifyMode::Kick`]: after updating an index, a guest issues the [`HVC_IVC_KICK`]
+//!   hypercall and the hypervisor raises the notification vector of the peer. A consumer
+//!   which is busy polling sets [`IVC_RING_F_NO_KICK`] in the `flags` of the ring it consumes, and
+//!   the producer skips the hypercall while it is set. The hypervisor drops the kicks racing with
+//!   the flag, so the consumer must check the indices again after clearing it.
+//! - [`IVCNotifyMode::Watermark`]: for bulk transfers, e.g. console or log output, the peers are
+//!   notified by fill level rather than per slot. The publisher picks a high and a low watermark,
+//!   in slots, which the hypervisor writes to the `watermarks` of both rings. The consumer of a
+//!   ring is notified when it fills up to the high watermark, and its producer when it drains
+//!   back to the low watermark, once per cycle. Guests issue the [`HVC_IVC_KICK`] hypercall when
+//!   their update may cross a watermark (the producer filling the ring up to the high one, the
+//!   consumer draining a ring it was notified of down to the low one), and the hypervisor checks
+//!   the indices of both rings to decide whom to notify.
+//!
+//! # Backpressure
+//!
+//! A producer finding its ring full doesn't have to poll it: the [`HVC_IVC_WAIT_SPACE`] hypercall
+//! blocks the calling vCPU until the ring has room for a number of slots, the peer is gone or a
+//! timeout elapses. The hypervisor counts the blocked vCPUs in the `space_waiters` of the ring,
+//! and a consumer which frees slots while it is nonzero issues the [`HVC_IVC_KICK`] hypercall,
+//! which wakes them up. This works in every notification mode, on a polling channel the kick
+//! only wakes the waiting producers.
+//!
+//! # Peer loss
+//!
+//! Publishers and subscribers may register a notification vector when they attach to a channel.
+//! It is an interrupt number of the VM as in its config, e.g. a PLIC source or an IMSIC identity
+//! on riscv64, see [`crate::vmm::abi`].
+//! When a VM stops, for whatever reason, [`teardown_vm_channels`] detaches it from the IVC
+//! registry:
+//!
+//! - the channels it published are unmapped from their subscribers, which are notified, and
+//!   freed;
+//! - its subscriptions are dropped and their publishers notified. On a ring channel,
+//!   [`IVC_RING_F_PEER_GONE`] is set in the `flags` of the ring the VM consumed, so the publisher
+//!   can tell the loss of its peer from a kick, and a new subscriber may attach.
+//!
+//! # Broadcast channels
+//!
+//! A broadcast channel is published with [`IVC_CHANNEL_BROADCAST`]. Its data page is writable by
+//! the publisher only and mapped read-only into any number of subscribers. It is followed by a
+//! page holding an [`IVCBroadcastHeader`], read-only for everyone and only written by the
+//! hypervisor on the [`HVC_IVC_BROADCAST`] hypercalls of the publisher, which bracket each update
+//! of the data:
+//!
+//! - "begin" makes `version` odd, the publisher then writes the data;
+//! - "commit" makes `version` even again and records the time of the update.
+//!
+//! Subscribers read `version`, the data, then `version` again, and retry if the two differ or are
+//! odd. A subscriber mapping the channel late detects whether the data was ever published or has
+//! changed since it last looked by comparing `version` with the last one it has seen.
+//!
+//! # Remote channels
+//!
+//! Channels may be exported to, and imported from, another axvisor node through the bridge (see
+//! [`crate::vmm::bridge`]). The VMs of the peer node are represented by proxy VMs, which publish
+//! the imported channels and subscribe to the exported ones. The kicks, subscriptions and
+//! unsubscriptions of a local VM whose peer is a proxy VM are forwarded to the peer node.
+//!
+//! [`HVC_IVC_KICK`]: crate::vmm::hvc::HVC_IVC_KICK
+//! [`HVC_IVC_WAIT_SPACE`]: crate::vmm::hvc::HVC_IVC_WAIT_SPACE
+//! [`HVC_IVC_BROADCAST`]: crate::vmm::hvc::HVC_IVC_BROADCAST
 use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 
+use std::os::arceos::modules::axhal;
 use std::os::arceos::modules::axhal::paging::PagingHandlerImpl;
 use std::sync::Mutex;
 
-use axaddrspace::{GuestPhysAddr, HostPhysAddr};
-use axerrno::AxResult;
+use axaddrspace::{GuestPhysAddr, HostPhysAddr, MappingFlags};
+use axerrno::{AxResult, ax_err, ax_err_type};
+use memory_addr::PAGE_SIZE_4K;
 use page_table_multiarch::PagingHandler;
 
+use crate::vmm::irq::IrqLine;
+use crate::vmm::memstat::{self, MemSubsystem::Ivc};
+use crate::vmm::security::{self, ScrubMode};
+use crate::vmm::trace::{
+    self, TRACE_CLASS_IVC, TRACE_IVC_BROADCAST_BEGIN, TRACE_IVC_BROADCAST_COMMIT, TRACE_IVC_KICK,
+};
+use crate::vmm::{
+    VMRef, blocks, bridge, iommu, irqpolicy, reclaim, stage2, stats, timer, tracectx, vcpus,
+};
+
+/// Channel type of the publish hypercall selecting a broadcast channel, see the
+/// [module docs](self).
+pub const IVC_CHANNEL_BROADCAST: u64 = 3;
+
+/// `magic` of an [`IVCRingHeader`], "IVCR".
+pub const IVC_RING_MAGIC: u32 = 0x5243_5649;
+/// Version of the ring layout.
+pub const IVC_RING_VERSION: u16 = 2;
+/// Set by the consumer of a ring in its `flags` when it does not need to be kicked.
+pub const IVC_RING_F_NO_KICK: u32 = 1 << 0;
+/// Set by the hypervisor in the `flags` of a ring whose consumer is gone.
+pub const IVC_RING_F_PEER_GONE: u32 = 1 << 31;
+/// Smallest slot size of a ring channel.
+const IVC_RING_MIN_SLOT_SIZE: usize = 16;
+/// Alignment of the slot arrays in the shared region.
+const IVC_RING_ALIGN: usize = 64;
+
 /// A global btree map to store IVC channels,
 /// indexed by (publisher_vm_id, channel_key).
 static IVC_CHANNELS: Mutex<BTreeMap<(usize, usize), IVCChannel<PagingHandlerImpl>>> =
     Mutex::new(BTreeMap::new());
+/// The channel handle tables of the VMs, by VM ID. Always locked after [`IVC_CHANNELS`].
+static IVC_HANDLES: Mutex<BTreeMap<usize, IVCHandleTable>> = Mutex::new(BTreeMap::new());
+/// The vCPUs blocked in [`wait_space`]. Always locked after [`IVC_CHANNELS`].
+static SPACE_WAITERS: Mutex<Vec<SpaceWaiter>> = Mutex::new(Vec::new());
+
+/// A vCPU waiting for room in a ring it produces.
+struct SpaceWaiter {
+    vm_id: usize,
+    /// `(publisher_vm_id, key)` of the channel.
+    channel: (usize, usize),
+    /// Index of the ring in the channel.
+    ring: usize,
+    /// Set when the vCPU should check the ring again.
+    woken: Arc<AtomicBool>,
+}
+
+/// The channels a VM is attached to, by handle.
+#[derive(Default)]
+struct IVCHandleTable {
+    /// The last handle given out, handles start at 1 and are never reused.
+    last: u64,
+    /// `(publisher_vm_id, key)` of the channel of each handle.
+    channels: BTreeMap<u64, (usize, usize)>,
+}
+
+/// Returns the handle of VM `vm_id` to `channel`, given out if the VM has none yet.
+fn attach_handle(vm_id: usize, channel: (usize, usize)) -> u64 {
+    let mut handles = IVC_HANDLES.lock();
+    let table = handles.entry(vm_id).or_default();
+    if let Some((handle, _)) = table.channels.iter().find(|(_, c)| **c == channel) {
+        return *handle;
+    }
+    table.last += 1;
+    table.channels.insert(table.last, channel);
+    table.last
+}
+
+/// Returns `(publisher_vm_id, key)` of the channel of `handle` of VM `vm_id`.
+fn resolve_handle(vm_id: usize, handle: u64) -> AxResult<(usize, usize)> {
+    IVC_HANDLES
+        .lock()
+        .get(&vm_id)
+        .and_then(|table| table.channels.get(&handle).copied())
+        .ok_or_else(|| {
+            ax_err_type!(
+                NotFound,
+                format!("VM[{}] has no IVC channel handle {:#x}", vm_id, handle)
+            )
+        })
+}
+
+/// Drops `handle` of VM `vm_id`, returns the channel it named.
+fn detach_handle(vm_id: usize, handle: u64) -> AxResult<(usize, usize)> {
+    IVC_HANDLES
+        .lock()
+        .get_mut(&vm_id)
+        .and_then(|table| table.channels.remove(&handle))
+        .ok_or_else(|| {
+            ax_err_type!(
+                NotFound,
+                format!("VM[{}] has no IVC channel handle {:#x}", vm_id, handle)
+            )
+        })
+}
 
+/// Drops the handles of VM `vm_id` to `channel`.
+fn detach_channel(vm_id: usize, channel: (usize, usize)) {
+    if let Some(table) = IVC_HANDLES.lock().get_mut(&vm_id) {
+        table.channels.retain(|_, c| *c != channel);
+    }
+}
+
+/// Registers a channel published by VM `publisher_vm_id`, returns the handle of the publisher to
+/// it.
 pub fn insert_channel(
     publisher_vm_id: usize,
     channel: IVCChannel<PagingHandlerImpl>,
-) -> AxResult<()> {
+) -> AxResult<u64> {
     let mut channels = IVC_CHANNELS.lock();
-    if channels
-        .insert((publisher_vm_id, channel.key), channel)
-        .is_some()
-    {
-        Err(axerrno::ax_err_type!(
+    let key = channel.key;
+    if channels.contains_key(&(publisher_vm_id, key)) {
+        return Err(axerrno::ax_err_type!(
             AlreadyExists,
             "IVC channel already exists"
-        ))
-    } else {
-        Ok(())
+        ));
+    }
+    if channel.origin == RegionOrigin::Exported {
+        bridge::export(
+            publisher_vm_id,
+            key,
+            channel.shared_region_base,
+            channel.shared_region_size,
+        )?;
     }
+    channels.insert((publisher_vm_id, key), channel);
+    Ok(attach_handle(publisher_vm_id, (publisher_vm_id, key)))
 }
 
-/// Try to remove a channel according to the publisher VM ID and key.
+/// Registers the channel `key` exported by the VM of the peer node standing behind the proxy VM
+/// `proxy_vm_id`, whose region of `size` bytes starts at `base`. Returns the handle of the proxy.
+pub fn import_channel(
+    proxy_vm_id: usize,
+    key: usize,
+    base: HostPhysAddr,
+    size: usize,
+) -> AxResult<u64> {
+    insert_channel(proxy_vm_id, IVCChannel::import(proxy_vm_id, key, base, size))
+}
+
+/// Removes the channel `key` of the proxy VM `proxy_vm_id`, withdrawn by the peer node, and
+/// detaches its subscribers as if the publisher had stopped.
+pub fn withdraw_channel(proxy_vm_id: usize, key: usize) {
+    let mut notifications = Vec::new();
+    let channel = {
+        let mut channels = IVC_CHANNELS.lock();
+        let Some(channel) = channels.remove(&(proxy_vm_id, key)) else {
+            return;
+        };
+        detach_channel(proxy_vm_id, (proxy_vm_id, key));
+        for (subscriber, _) in channel.subscribers() {
+            detach_channel(subscriber, (proxy_vm_id, key));
+            if let Some(vector) = channel.notify_vectors.get(&subscriber) {
+                notifications.push((subscriber, *vector));
+            }
+        }
+        channel
+    };
+    wake_space_waiters((proxy_vm_id, key), None);
+    release_published(proxy_vm_id, alloc::vec![channel]);
+    for (peer, vector) in notifications {
+        IrqLine::new(peer, vector).raise();
+    }
+}
+
+/// Try to remove the channel `handle` of the publisher VM.
 /// If the channel still has subscribers, it will just mark it as unpublished
 /// (by setting its base GPA to None).
 /// If the channel is successfully unpublished, it will return the base GPA and size of the channel.
-/// If the channel does not exist, it will return an error.
+/// If the channel does not exist or is not published by the VM, it will return an error.
 pub fn unpublish_channel(
     publisher_vm_id: usize,
-    key: usize,
+    handle: u64,
 ) -> AxResult<Option<(GuestPhysAddr, usize)>> {
     let mut channels = IVC_CHANNELS.lock();
+    let (owner, key) = resolve_handle(publisher_vm_id, handle)?;
+    if owner != publisher_vm_id {
+        return ax_err!(
+            PermissionDenied,
+            format!(
+                "VM[{}] does not publish the IVC channel of handle {:#x}",
+                publisher_vm_id, handle
+            )
+        );
+    }
     if let Some(mut channel) = channels.remove(&(publisher_vm_id, key)) {
         let base_gpa = channel.base_gpa_in_publisher().ok_or_else(|| {
             axerrno::ax_err_type!(
@@ -53,6 +308,7 @@ pub fn unpublish_channel(
             )
         })?;
         let size = channel.size();
+        detach_handle(publisher_vm_id, handle)?;
         if !channel.subscribers().is_empty() {
             channel.base_gpa = None; // Mark the channel as removed.
             // If there are still subscribers, just return None.
@@ -70,6 +326,443 @@ pub fn unpublish_channel(
     }
 }
 
+/// Maps `mappings` into `vm` at `base`, the window of `size` bytes allocated for them with
+/// `alloc_ivc_channel`. Nothing is left mapped on failure.
+///
+/// Used for the memory of a VM shared with the backend of its devices, see
+/// [`crate::vmm::virtio`], and for shared framebuffers.
+pub fn map_window(
+    vm: &VMRef,
+    base: GuestPhysAddr,
+    size: usize,
+    mappings: &[IVCMapping],
+) -> AxResult {
+    blocks::split_blocks(vm, base, size)?;
+    for (idx, mapping) in mappings.iter().enumerate() {
+        let mapped = stage2::map_region(
+            vm,
+            base + mapping.offset,
+            mapping.hpa,
+            mapping.size,
+            mapping.flags,
+        );
+        if let Err(e) = mapped {
+            for mapping in &mappings[..idx] {
+                iommu::unmap_region(vm, base + mapping.offset, mapping.size)?;
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Kicks the peer of vCPU `vcpu_id` of VM `vm_id` on the ring channel of its handle `handle`.
+///
+/// The ring produced by the caller is checked for consistency before the kick vector of the peer
+/// is raised, along with the trace context of the caller (see [`crate::vmm::tracectx`]). On a
+/// channel in watermark mode, the peers whose watermark was crossed are notified instead. The
+/// producers waiting for room in the ring consumed by the caller are woken up, which is all a kick
+/// does on a polling channel. The high-priority peers are notified first, see
+/// [`crate::vmm::irqpolicy`].
+pub fn kick(vm_id: usize, vcpu_id: usize, handle: u64) -> AxResult {
+    let (key, mut targets) = {
+        let mut channels = IVC_CHANNELS.lock();
+        let (publisher_vm_id, key) = resolve_handle(vm_id, handle)?;
+        let channel = channels.get_mut(&(publisher_vm_id, key)).ok_or_else(|| {
+            ax_err_type!(
+                NotFound,
+                format!(
+                    "IVC channel for publisher VM [{}] key {:#x} not found",
+                    publisher_vm_id, key
+                )
+            )
+        })?;
+        let Some(ring) = channel.ring.as_ref() else {
+            return ax_err!(InvalidInput, "kick on a raw IVC channel");
+        };
+        let notify = ring.notify;
+        let produced = channel.produced_ring(vm_id)?;
+        // The producer of the ring consumed by the caller may wait for room.
+        channel.check_ring(1 - produced)?;
+        channel.update_credits(1 - produced);
+        wake_space_waiters((publisher_vm_id, key), Some(1 - produced));
+        let peer = if produced == 0 {
+            match channel.subscriber_vms.keys().next() {
+                Some(subscriber) => *subscriber,
+                // Nobody to kick yet.
+                None => return Ok(()),
+            }
+        } else {
+            publisher_vm_id
+        };
+        channel.check_ring(produced)?;
+        channel.update_credits(produced);
+        stats::count_ivc_bytes(vm_id, channel.take_produced(produced));
+        let targets = if bridge::is_remote(peer) {
+            // The peer node replays the kick, whatever the notification mode.
+            bridge::forward_kick(vm_id, publisher_vm_id, key);
+            Vec::new()
+        } else if notify == IVCNotifyMode::Poll {
+            // Only the waiting producers are woken up.
+            return Ok(());
+        } else if notify == IVCNotifyMode::Watermark {
+            channel.watermark_targets()
+        } else {
+            match channel.notify_vectors.get(&peer) {
+                Some(vector) => alloc::vec![(peer, *vector)],
+                None => return Ok(()),
+            }
+        };
+        (key, targets)
+    };
+
+    trace::record(TRACE_CLASS_IVC, vm_id, vcpu_id, TRACE_IVC_KICK, key as u64);
+    targets.sort_by_key(|&(peer, _)| !irqpolicy::is_high_priority(peer));
+    for (peer, vector) in targets {
+        tracectx::forward(vm_id, vcpu_id, peer);
+        IrqLine::new(peer, vector).raise();
+    }
+    Ok(())
+}
+
+/// Wakes up the vCPUs waiting for room in ring `ring` of `channel`, or in any of its rings.
+fn wake_space_waiters(channel: (usize, usize), ring: Option<usize>) {
+    let mut vms = Vec::new();
+    for waiter in SPACE_WAITERS.lock().iter() {
+        if waiter.channel == channel && ring.is_none_or(|ring| ring == waiter.ring) {
+            waiter.woken.store(true, Ordering::Release);
+            if !vms.contains(&waiter.vm_id) {
+                vms.push(waiter.vm_id);
+            }
+        }
+    }
+    for vm_id in vms {
+        vcpus::notify_all_vcpus(vm_id);
+    }
+}
+
+/// Blocks the caller, a vCPU of VM `vm_id`, until the ring it produces on the ring channel of its
+/// handle `handle` has room for `slots` slots, or `timeout_ns` nanoseconds elapse. See the
+/// [module docs](self).
+///
+/// `block` blocks the vCPU until the flag it is given is set or its VM stops. Returns the free
+/// slots of the ring, 0 if the timeout elapsed or the VM stopped first. A timeout of 0 only
+/// refreshes the credits of the ring.
+pub fn wait_space(
+    vm_id: usize,
+    handle: u64,
+    slots: u32,
+    timeout_ns: u64,
+    block: impl Fn(&AtomicBool),
+) -> AxResult<u32> {
+    let deadline = axhal::time::monotonic_time_nanos().saturating_add(timeout_ns);
+    let woken = Arc::new(AtomicBool::new(false));
+    let mut waiting = None;
+    let ret = loop {
+        let status = {
+            let mut channels = IVC_CHANNELS.lock();
+            space_status(&mut channels, vm_id, handle, slots)
+        };
+        let (channel, ring, free) = match status {
+            Ok(status) => status,
+            Err(e) => break Err(e),
+        };
+        if free >= slots {
+            break Ok(free);
+        }
+        if axhal::time::monotonic_time_nanos() >= deadline {
+            break Ok(0);
+        }
+        if waiting.is_none() {
+            waiting = Some((channel, ring));
+            add_space_waiter(vm_id, channel, ring, &woken, deadline);
+        }
+        block(&woken);
+        if !woken.swap(false, Ordering::AcqRel) {
+            // The VM is stopping.
+            break Ok(0);
+        }
+    };
+    if let Some((channel, ring)) = waiting {
+        remove_space_waiter(channel, ring, &woken);
+    }
+    ret
+}
+
+/// Returns the channel of `handle` of VM `vm_id`, the index of the ring the VM produces and its
+/// free slots, after refreshing its credits.
+fn space_status(
+    channels: &mut BTreeMap<(usize, usize), IVCChannel<PagingHandlerImpl>>,
+    vm_id: usize,
+    handle: u64,
+    slots: u32,
+) -> AxResult<((usize, usize), usize, u32)> {
+    let key = resolve_handle(vm_id, handle)?;
+    let channel = channels
+        .get_mut(&key)
+        .ok_or_else(|| ax_err_type!(NotFound, format!("IVC channel {:?} not found", key)))?;
+    let Some(config) = channel.ring.as_ref() else {
+        return ax_err!(InvalidInput, "space wait on a raw IVC channel");
+    };
+    if slots == 0 || slots > config.slot_count {
+        return ax_err!(
+            InvalidInput,
+            format!("cannot wait for {} slots of an IVC ring", slots)
+        );
+    }
+    let idx = channel.produced_ring(vm_id)?;
+    channel.check_ring(idx)?;
+    if channel.ring_header().rings[idx]
+        .flags
+        .load(Ordering::Acquire)
+        & IVC_RING_F_PEER_GONE
+        != 0
+    {
+        return ax_err!(BadState, "the consumer of the IVC ring is gone");
+    }
+    Ok((key, idx, channel.update_credits(idx)))
+}
+
+fn add_space_waiter(
+    vm_id: usize,
+    channel: (usize, usize),
+    ring: usize,
+    woken: &Arc<AtomicBool>,
+    deadline: u64,
+) {
+    {
+        let channels = IVC_CHANNELS.lock();
+        if let Some(ch) = channels.get(&channel) {
+            ch.ring_header().rings[ring]
+                .space_waiters
+                .fetch_add(1, Ordering::AcqRel);
+        }
+        SPACE_WAITERS.lock().push(SpaceWaiter {
+            vm_id,
+            channel,
+            ring,
+            woken: woken.clone(),
+        });
+    }
+    // The consumer may have freed slots before it could see the waiter.
+    woken.store(true, Ordering::Release);
+
+    let woken: Weak<AtomicBool> = Arc::downgrade(woken);
+    timer::register_timer(deadline, move |_| {
+        if let Some(woken) = woken.upgrade() {
+            woken.store(true, Ordering::Release);
+            vcpus::notify_all_vcpus(vm_id);
+        }
+    });
+}
+
+fn remove_space_waiter(channel: (usize, usize), ring: usize, woken: &Arc<AtomicBool>) {
+    let channels = IVC_CHANNELS.lock();
+    if let Some(ch) = channels.get(&channel) {
+        ch.ring_header().rings[ring]
+            .space_waiters
+            .fetch_sub(1, Ordering::AcqRel);
+    }
+    SPACE_WAITERS
+        .lock()
+        .retain(|waiter| !Arc::ptr_eq(&waiter.woken, woken));
+}
+
+/// Detaches a stopped VM from all IVC channels, called from the VM exit path. See the
+/// [module docs](self).
+pub fn teardown_vm_channels(vm_id: usize) {
+    let mut published = Vec::new();
+    let mut notifications = Vec::new();
+    let mut touched = Vec::new();
+    {
+        let mut channels = IVC_CHANNELS.lock();
+        let keys: Vec<_> = channels
+            .range((vm_id, 0)..=(vm_id, usize::MAX))
+            .map(|(key, _)| *key)
+            .collect();
+        for key in keys {
+            touched.push(key);
+            let channel = channels.remove(&key).unwrap();
+            for (subscriber, _) in channel.subscribers() {
+                detach_channel(subscriber, key);
+                if let Some(vector) = channel.notify_vectors.get(&subscriber) {
+                    notifications.push((subscriber, *vector));
+                }
+            }
+            published.push(channel);
+        }
+        IVC_HANDLES.lock().remove(&vm_id);
+
+        channels.retain(|_, channel| {
+            if channel.remove_subscriber(vm_id).is_some() {
+                touched.push((channel.publisher_vm_id, channel.key));
+                if bridge::is_remote(channel.publisher_vm_id) {
+                    bridge::forward_detach(vm_id, channel.publisher_vm_id, channel.key);
+                }
+                channel.notify_vectors.remove(&vm_id);
+                if channel.base_gpa.is_some() {
+                    if channel.ring.is_some() {
+                        channel.ring_header().rings[0]
+                            .flags
+                            .fetch_or(IVC_RING_F_PEER_GONE, Ordering::Release);
+                    }
+                    if let Some(vector) = channel.notify_vectors.get(&channel.publisher_vm_id) {
+                        notifications.push((channel.publisher_vm_id, *vector));
+                    }
+                }
+            }
+            !(channel.subscriber_vms.is_empty() && channel.base_gpa.is_none())
+        });
+        SPACE_WAITERS.lock().retain(|waiter| waiter.vm_id != vm_id);
+    }
+    // The producers waiting on the channels of the VM find them gone or without a consumer.
+    for channel in touched {
+        wake_space_waiters(channel, None);
+    }
+
+    release_published(vm_id, published);
+    for (peer, vector) in notifications {
+        IrqLine::new(peer, vector).raise();
+    }
+}
+
+/// Unmaps the channels removed from the registry, published by VM `vm_id`, from their
+/// subscribers and drops them.
+fn release_published(vm_id: usize, published: Vec<IVCChannel<PagingHandlerImpl>>) {
+    // Subscribers must lose access to the shared pages before they are freed.
+    for channel in published {
+        for (subscriber, gpa) in channel.subscribers() {
+            if bridge::is_remote(subscriber) {
+                continue;
+            }
+            let unmapped = crate::vmm::with_vm(subscriber, |vm| {
+                iommu::unmap_region(&vm, gpa, channel.size())
+            });
+            if let Some(Err(e)) = unmapped {
+                // Leak the pages rather than leave them mapped into a guest once reused.
+                warn!(
+                    "VM[{}] failed to unmap IVC channel {:#x} of VM[{}]: {:?}",
+                    subscriber, channel.key, vm_id, e
+                );
+                core::mem::forget(channel);
+                break;
+            }
+        }
+    }
+}
+
+/// Begins (`commit == false`) or commits an update of the broadcast channel of handle `handle`
+/// published by vCPU `vcpu_id` of VM `vm_id`.
+///
+/// A commit forwards the trace context of the caller to the subscribers, see
+/// [`crate::vmm::tracectx`].
+pub fn broadcast_update(vm_id: usize, vcpu_id: usize, handle: u64, commit: bool) -> AxResult {
+    let channels = IVC_CHANNELS.lock();
+    let (publisher_vm_id, key) = resolve_handle(vm_id, handle)?;
+    let (channel, header) = channels
+        .get(&(publisher_vm_id, key))
+        .filter(|channel| channel.publisher_vm_id == vm_id && channel.base_gpa.is_some())
+        .and_then(|channel| Some((channel, channel.broadcast_header()?)))
+        .ok_or_else(|| {
+            ax_err_type!(
+                NotFound,
+                format!("VM[{}] publishes no broadcast channel {:#x}", vm_id, key)
+            )
+        })?;
+    let version = header.version.load(Ordering::Relaxed);
+    if (version % 2 == 1) != commit {
+        return ax_err!(
+            BadState,
+            format!(
+                "VM[{}] broadcast channel {:#x}: unbalanced update at version {}",
+                vm_id, key, version
+            )
+        );
+    }
+    if commit {
+        header
+            .updated_at_ns
+            .store(axhal::time::monotonic_time_nanos(), Ordering::Relaxed);
+        stats::count_ivc_bytes(vm_id, PAGE_SIZE_4K as u64);
+        for subscriber in channel.subscriber_vms.keys() {
+            tracectx::forward(vm_id, vcpu_id, *subscriber);
+        }
+    }
+    let op = if commit {
+        TRACE_IVC_BROADCAST_COMMIT
+    } else {
+        TRACE_IVC_BROADCAST_BEGIN
+    };
+    trace::record(TRACE_CLASS_IVC, vm_id, vcpu_id, op, key as u64);
+    // Release orders the data of a commit before the new version, begin is ordered before the
+    // writes of the publisher by the hypercall exit itself.
+    header.version.store(version + 1, Ordering::Release);
+    Ok(())
+}
+
+/// Lets `f` fill the data of the raw channel `key` published by VM `publisher_vm_id`, the part of
+/// the channel after its header, and notifies the publisher if it registered a vector.
+///
+/// Used by the hypervisor to hand data to a guest, see [`crate::vmm::crash`].
+pub fn write_raw_channel<R>(
+    publisher_vm_id: usize,
+    key: usize,
+    f: impl FnOnce(&mut [u8]) -> R,
+) -> AxResult<R> {
+    let (ret, vector) = with_raw_channel(publisher_vm_id, key, f)?;
+    if let Some(vector) = vector {
+        IrqLine::new(publisher_vm_id, vector).raise();
+    }
+    Ok(ret)
+}
+
+/// Lets `f` access the data of the raw channel `key` published by VM `publisher_vm_id`, like
+/// [`write_raw_channel`] but without notifying the publisher.
+///
+/// Used by the hypervisor to take the answers of a guest, see [`crate::vmm::ioreq`].
+pub fn access_raw_channel<R>(
+    publisher_vm_id: usize,
+    key: usize,
+    f: impl FnOnce(&mut [u8]) -> R,
+) -> AxResult<R> {
+    with_raw_channel(publisher_vm_id, key, f).map(|(ret, _)| ret)
+}
+
+/// Runs `f` on the data of a raw channel, returns its result and the notify vector of the
+/// publisher.
+fn with_raw_channel<R>(
+    publisher_vm_id: usize,
+    key: usize,
+    f: impl FnOnce(&mut [u8]) -> R,
+) -> AxResult<(R, Option<usize>)> {
+    let channels = IVC_CHANNELS.lock();
+    let channel = channels
+        .get(&(publisher_vm_id, key))
+        .filter(|channel| channel.base_gpa.is_some())
+        .ok_or_else(|| {
+            ax_err_type!(
+                NotFound,
+                format!(
+                    "VM[{}] publishes no IVC channel {:#x}",
+                    publisher_vm_id, key
+                )
+            )
+        })?;
+    if channel.ring.is_some() || channel.broadcast_frame.is_some() {
+        return ax_err!(InvalidInput, "IVC channel is not a raw channel");
+    }
+    let len = channel
+        .shared_region_size
+        .saturating_sub(core::mem::size_of::<IVCChannelHeader>());
+    // SAFETY: the data follows the header in the frame of the channel, which lives as long as
+    // the channel. Guests may write it concurrently, they get what they asked for.
+    let data = unsafe { core::slice::from_raw_parts_mut(channel.data_region() as *mut u8, len) };
+    Ok((
+        f(data),
+        channel.notify_vectors.get(&publisher_vm_id).copied(),
+    ))
+}
+
 pub fn get_channel_size(publisher_vm_id: usize, key: usize) -> AxResult<usize> {
     let channels = IVC_CHANNELS.lock();
     if let Some(channel) = channels.get(&(publisher_vm_id, key)) {
@@ -86,18 +779,42 @@ pub fn get_channel_size(publisher_vm_id: usize, key: usize) -> AxResult<usize> {
 }
 
 /// Subcribe to a channel of a publisher VM with the given key,
-/// return the shared region base address and size.
+/// return the parts of the channel to map, its size and the handle of the subscriber to it.
+///
+/// `notify_vector` is the vector the subscriber is notified with, 0 for none.
 pub fn subscribe_to_channel_of_publisher(
     publisher_vm_id: usize,
     key: usize,
     subscriber_vm_id: usize,
     subscriber_gpa: GuestPhysAddr,
-) -> AxResult<(HostPhysAddr, usize)> {
+    notify_vector: usize,
+) -> AxResult<(Vec<IVCMapping>, usize, u64)> {
     let mut channels = IVC_CHANNELS.lock();
     if let Some(channel) = channels.get_mut(&(publisher_vm_id, key)) {
+        if channel.ring.is_some() {
+            // Rings are single-producer single-consumer.
+            if channel
+                .subscriber_vms
+                .keys()
+                .any(|vm_id| *vm_id != subscriber_vm_id)
+            {
+                return ax_err!(
+                    ResourceBusy,
+                    format!(
+                        "IVC ring channel of VM [{}] key {:#x} already has a subscriber",
+                        publisher_vm_id, key
+                    )
+                );
+            }
+        }
         // Add the subscriber VM ID to the channel.
         channel.add_subscriber(subscriber_vm_id, subscriber_gpa);
-        Ok((channel.base_hpa(), channel.size()))
+        channel.set_notify_vector(subscriber_vm_id, notify_vector);
+        if bridge::is_remote(publisher_vm_id) {
+            bridge::forward_attach(subscriber_vm_id, publisher_vm_id, key);
+        }
+        let handle = attach_handle(subscriber_vm_id, (publisher_vm_id, key));
+        Ok((channel.mappings(subscriber_vm_id), channel.size(), handle))
     } else {
         Err(axerrno::ax_err_type!(
             NotFound,
@@ -109,18 +826,33 @@ pub fn subscribe_to_channel_of_publisher(
     }
 }
 
-/// Unsubscribe from a channel of a publisher VM with the given key,
+/// Unsubscribe from the channel of handle `handle` of the subscriber VM,
 /// if the channel has been unpublished (i.e., the base GPA is None) and has no subscribers,
 /// it will remove the channel from the global map.
-pub fn unsubscribe_from_channel_of_publisher(
-    publisher_vm_id: usize,
-    key: usize,
+pub fn unsubscribe_from_channel(
+    handle: u64,
     subscriber_vm_id: usize,
 ) -> AxResult<(GuestPhysAddr, usize)> {
     let mut channels = IVC_CHANNELS.lock();
+    let (publisher_vm_id, key) = resolve_handle(subscriber_vm_id, handle)?;
+    if publisher_vm_id == subscriber_vm_id {
+        return ax_err!(
+            InvalidInput,
+            format!(
+                "VM[{}] IVC channel handle {:#x} names a channel it publishes",
+                subscriber_vm_id, handle
+            )
+        );
+    }
+    // The handle goes even if the channel went with its publisher.
+    detach_handle(subscriber_vm_id, handle)?;
     let (base_gpa, size) = if let Some(channel) = channels.get_mut(&(publisher_vm_id, key)) {
         // Remove the subscriber VM ID from the channel.
         if let Some(subscriber_gpa) = channel.remove_subscriber(subscriber_vm_id) {
+            channel.notify_vectors.remove(&subscriber_vm_id);
+            if bridge::is_remote(publisher_vm_id) {
+                bridge::forward_detach(subscriber_vm_id, publisher_vm_id, key);
+            }
             Ok((subscriber_gpa, channel.size()))
         } else {
             Err(axerrno::ax_err_type!(
@@ -157,20 +889,148 @@ pub struct IVCChannel<H: PagingHandler> {
     /// The key is the subscriber VM ID, and the value is the base address of the shared region in
     /// guest physical address of the subscriber VM.
     subscriber_vms: BTreeMap<usize, GuestPhysAddr>,
+    /// The physically contiguous region of the channel, whole pages.
     shared_region_base: HostPhysAddr,
     shared_region_size: usize,
     /// The base address of the shared memory region in guest physical address of the publisher VM.
     /// `None` if the channel has been unpublished (but still has subscribers).
     base_gpa: Option<GuestPhysAddr>,
+    /// The ring layout of the channel, `None` for a raw channel.
+    ring: Option<IVCRingConfig>,
+    /// The frame holding the [`IVCBroadcastHeader`] of a broadcast channel.
+    broadcast_frame: Option<HostPhysAddr>,
+    /// How the pages are scrubbed when freed, from the policy of the publisher.
+    scrub: ScrubMode,
+    /// Where the region comes from.
+    origin: RegionOrigin,
+    /// Notification vectors of the VMs attached to the channel, by VM ID.
+    notify_vectors: BTreeMap<usize, usize>,
     _phatom: core::marker::PhantomData<H>,
 }
 
+/// Where the region of a channel comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RegionOrigin {
+    /// The host memory.
+    Host,
+    /// The half of the bridge window owned by this node, the channel is exported.
+    Exported,
+    /// The half of the bridge window owned by the peer node, the channel is imported.
+    Imported,
+}
+
 #[repr(C)]
 pub struct IVCChannelHeader {
     pub publisher_id: u64,
     pub key: u64,
 }
 
+/// How the two ends of a ring channel notify each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum IVCNotifyMode {
+    /// Guests poll the ring indices.
+    Poll = 1,
+    /// Guests kick each other through the hypervisor.
+    Kick = 2,
+    /// Guests are notified when a ring crosses its watermarks. 3 is the broadcast channel type.
+    Watermark = 4,
+}
+
+impl TryFrom<u64> for IVCNotifyMode {
+    type Error = axerrno::AxError;
+
+    fn try_from(mode: u64) -> Result<Self, Self::Error> {
+        match mode {
+            1 => Ok(Self::Poll),
+            2 => Ok(Self::Kick),
+            4 => Ok(Self::Watermark),
+            _ => Err(ax_err_type!(InvalidInput)),
+        }
+    }
+}
+
+/// The indices of one direction of a ring channel.
+#[repr(C)]
+pub struct IVCRing {
+    /// Number of slots produced, only written by the producer.
+    pub head: AtomicU32,
+    /// Number of slots consumed, only written by the consumer.
+    pub tail: AtomicU32,
+    /// `IVC_RING_F_*` flags, only written by the consumer.
+    pub flags: AtomicU32,
+    /// High (bits 0-15) and low (bits 16-31) watermarks in slots in watermark mode, 0 otherwise.
+    /// Only written by the hypervisor.
+    pub watermarks: u32,
+    /// Free slots as of the last update of the hypervisor.
+    pub producer_credits: AtomicU32,
+    /// Slots to consume as of the last update of the hypervisor.
+    pub consumer_credits: AtomicU32,
+    /// Number of producer vCPUs blocked until the ring has room, the consumer kicks when it frees
+    /// slots while it is nonzero. Only written by the hypervisor.
+    pub space_waiters: AtomicU32,
+    pub _reserved: u32,
+}
+
+/// The ring layout of a channel, right after its [`IVCChannelHeader`].
+#[repr(C)]
+pub struct IVCRingHeader {
+    /// [`IVC_RING_MAGIC`].
+    pub magic: u32,
+    /// [`IVC_RING_VERSION`].
+    pub version: u16,
+    /// [`IVCNotifyMode`] of the channel.
+    pub notify: u16,
+    /// Size of a slot in bytes, a power of two.
+    pub slot_size: u32,
+    /// Number of slots of each ring, a power of two.
+    pub slot_count: u32,
+    /// Offsets of the slot arrays of `rings` from the start of the shared region.
+    pub slot_offsets: [u32; 2],
+    /// `rings[0]` is produced by the publisher, `rings[1]` by the subscriber.
+    pub rings: [IVCRing; 2],
+}
+
+/// The header of a broadcast channel, in the page following its data.
+#[repr(C)]
+pub struct IVCBroadcastHeader {
+    /// Number of updates begun or committed, odd while the publisher updates the data.
+    pub version: AtomicU64,
+    /// Host monotonic time of the last committed update in nanoseconds.
+    pub updated_at_ns: AtomicU64,
+}
+
+/// A part of a channel mapped contiguously into a guest.
+#[derive(Debug, Clone, Copy)]
+pub struct IVCMapping {
+    /// Offset from the base of the channel in the guest.
+    pub offset: usize,
+    pub hpa: HostPhysAddr,
+    pub size: usize,
+    pub flags: MappingFlags,
+}
+
+/// The ring layout of a channel, as validated on publish.
+#[derive(Debug)]
+struct IVCRingConfig {
+    notify: IVCNotifyMode,
+    slot_size: u32,
+    slot_count: u32,
+    /// `head` of each ring at its last kick, for the statistics of [`crate::vmm::stats`].
+    kicked_heads: [u32; 2],
+    /// The watermarks of a channel in watermark mode.
+    watermarks: Option<IVCWatermarks>,
+}
+
+/// The watermarks of the rings of a channel in watermark mode, in slots.
+#[derive(Debug)]
+struct IVCWatermarks {
+    high: u32,
+    low: u32,
+    /// Whether each ring reached the high watermark since it last drained to the low one.
+    above: [bool; 2],
+}
+
 impl<H: PagingHandler> IVCChannel<H> {
     #[allow(unused)]
     pub fn header(&self) -> &IVCChannelHeader {
@@ -187,7 +1047,220 @@ impl<H: PagingHandler> IVCChannel<H> {
         }
     }
 
-    #[allow(unused)]
+    fn ring_header(&self) -> &IVCRingHeader {
+        unsafe {
+            // The ring header follows the channel header.
+            &*H::phys_to_virt(self.shared_region_base)
+                .as_mut_ptr()
+                .add(core::mem::size_of::<IVCChannelHeader>())
+                .cast::<IVCRingHeader>()
+        }
+    }
+
+    fn ring_header_mut(&mut self) -> &mut IVCRingHeader {
+        unsafe {
+            &mut *H::phys_to_virt(self.shared_region_base)
+                .as_mut_ptr()
+                .add(core::mem::size_of::<IVCChannelHeader>())
+                .cast::<IVCRingHeader>()
+        }
+    }
+
+    /// Lays the shared region out as a pair of rings of `slot_size`-byte slots.
+    ///
+    /// `watermarks` are the high and low watermarks of a channel in watermark mode, `(0, 0)` for
+    /// three quarters and a quarter of the slots.
+    fn init_ring(
+        &mut self,
+        notify: IVCNotifyMode,
+        slot_size: usize,
+        watermarks: (u32, u32),
+    ) -> AxResult {
+        if !slot_size.is_power_of_two() || slot_size < IVC_RING_MIN_SLOT_SIZE {
+            return ax_err!(
+                InvalidInput,
+                format!("invalid IVC ring slot size {:#x}", slot_size)
+            );
+        }
+        let slots_base = (core::mem::size_of::<IVCChannelHeader>()
+            + core::mem::size_of::<IVCRingHeader>())
+        .next_multiple_of(IVC_RING_ALIGN);
+        let ring_size =
+            (self.shared_region_size.saturating_sub(slots_base) / 2) & !(IVC_RING_ALIGN - 1);
+        let slot_count = ring_size / slot_size;
+        if slot_count < 2 {
+            return ax_err!(
+                InvalidInput,
+                format!(
+                    "IVC region of {:#x} bytes too small for {:#x}-byte ring slots",
+                    self.shared_region_size, slot_size
+                )
+            );
+        }
+        // Keep the slot count a power of two so that indices can wrap around.
+        let slot_count = 1 << slot_count.ilog2();
+        let ring_size = (slot_count * slot_size).next_multiple_of(IVC_RING_ALIGN);
+        let watermarks = match (notify, watermarks) {
+            (IVCNotifyMode::Watermark, (0, 0)) => {
+                Some((slot_count as u32 * 3 / 4, slot_count as u32 / 4))
+            }
+            (IVCNotifyMode::Watermark, (high, low)) if low < high && high <= slot_count as u32 => {
+                Some((high, low))
+            }
+            (IVCNotifyMode::Watermark, (high, low)) => {
+                return ax_err!(
+                    InvalidInput,
+                    format!(
+                        "invalid IVC ring watermarks {}/{} for {} slots",
+                        high, low, slot_count
+                    )
+                );
+            }
+            _ => None,
+        };
+
+        let header = self.ring_header_mut();
+        header.magic = IVC_RING_MAGIC;
+        header.version = IVC_RING_VERSION;
+        header.notify = notify as u16;
+        header.slot_size = slot_size as u32;
+        header.slot_count = slot_count as u32;
+        header.slot_offsets = [slots_base as u32, (slots_base + ring_size) as u32];
+        for ring in &mut header.rings {
+            ring.head.store(0, Ordering::Relaxed);
+            ring.tail.store(0, Ordering::Relaxed);
+            ring.flags.store(0, Ordering::Relaxed);
+            ring.watermarks = watermarks.map_or(0, |(high, low)| high | low << 16);
+            ring.producer_credits
+                .store(slot_count as u32, Ordering::Relaxed);
+            ring.consumer_credits.store(0, Ordering::Relaxed);
+            ring.space_waiters.store(0, Ordering::Relaxed);
+            ring._reserved = 0;
+        }
+
+        self.ring = Some(IVCRingConfig {
+            notify,
+            slot_size: slot_size as u32,
+            slot_count: slot_count as u32,
+            kicked_heads: [0; 2],
+            watermarks: watermarks.map(|(high, low)| IVCWatermarks {
+                high,
+                low,
+                above: [false; 2],
+            }),
+        });
+        Ok(())
+    }
+
+    /// Checks that the layout and the indices of ring `idx` have not been corrupted by a guest.
+    fn check_ring(&self, idx: usize) -> AxResult {
+        let Some(config) = self.ring.as_ref() else {
+            return Ok(());
+        };
+        let header = self.ring_header();
+        let ring = &header.rings[idx];
+        let used = ring
+            .head
+            .load(Ordering::Acquire)
+            .wrapping_sub(ring.tail.load(Ordering::Acquire));
+        if header.magic != IVC_RING_MAGIC
+            || header.slot_count != config.slot_count
+            || used > config.slot_count
+        {
+            return ax_err!(
+                BadState,
+                format!(
+                    "IVC ring {} of VM [{}] key {:#x} is corrupted",
+                    idx, self.publisher_vm_id, self.key
+                )
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the index of the ring VM `vm_id` produces, 0 for the publisher and 1 for the
+    /// subscriber.
+    fn produced_ring(&self, vm_id: usize) -> AxResult<usize> {
+        if vm_id == self.publisher_vm_id {
+            Ok(0)
+        } else if self.subscriber_vms.contains_key(&vm_id) && self.base_gpa.is_some() {
+            Ok(1)
+        } else {
+            ax_err!(
+                PermissionDenied,
+                format!("VM[{}] is not attached to the IVC channel", vm_id)
+            )
+        }
+    }
+
+    /// Refreshes the credits of ring `idx`, checked by [`Self::check_ring`], returns its free
+    /// slots.
+    fn update_credits(&self, idx: usize) -> u32 {
+        let Some(config) = self.ring.as_ref() else {
+            return 0;
+        };
+        let ring = &self.ring_header().rings[idx];
+        let used = ring
+            .head
+            .load(Ordering::Acquire)
+            .wrapping_sub(ring.tail.load(Ordering::Acquire));
+        let free = config.slot_count - used;
+        ring.producer_credits.store(free, Ordering::Release);
+        ring.consumer_credits.store(used, Ordering::Release);
+        free
+    }
+
+    /// Returns the bytes produced into ring `idx` since the last call.
+    fn take_produced(&mut self, idx: usize) -> u64 {
+        let head = self.ring_header().rings[idx].head.load(Ordering::Acquire);
+        let Some(config) = self.ring.as_mut() else {
+            return 0;
+        };
+        let produced = head.wrapping_sub(config.kicked_heads[idx]);
+        config.kicked_heads[idx] = head;
+        produced as u64 * config.slot_size as u64
+    }
+
+    /// Updates the watermark state of the rings of a channel in watermark mode, returns the VMs
+    /// whose watermark was crossed and their notification vectors.
+    fn watermark_targets(&mut self) -> Vec<(usize, usize)> {
+        let Some(subscriber) = self.subscriber_vms.keys().next().copied() else {
+            return Vec::new();
+        };
+        // The producer and the consumer of each ring.
+        let ends = [
+            (self.publisher_vm_id, subscriber),
+            (subscriber, self.publisher_vm_id),
+        ];
+        let used = self.ring_header().rings.each_ref().map(|ring| {
+            ring.head
+                .load(Ordering::Acquire)
+                .wrapping_sub(ring.tail.load(Ordering::Acquire))
+        });
+        let Some(watermarks) = self.ring.as_mut().and_then(|ring| ring.watermarks.as_mut()) else {
+            return Vec::new();
+        };
+
+        let mut targets = Vec::new();
+        for (idx, (producer, consumer)) in ends.into_iter().enumerate() {
+            let target = if !watermarks.above[idx] && used[idx] >= watermarks.high {
+                watermarks.above[idx] = true;
+                consumer
+            } else if watermarks.above[idx] && used[idx] <= watermarks.low {
+                watermarks.above[idx] = false;
+                producer
+            } else {
+                continue;
+            };
+            if let Some(vector) = self.notify_vectors.get(&target)
+                && !targets.contains(&(target, *vector))
+            {
+                targets.push((target, *vector));
+            }
+        }
+        targets
+    }
+
     pub fn data_region(&self) -> *const u8 {
         unsafe {
             // Return a pointer to the data region, which starts after the header.
@@ -202,12 +1275,13 @@ impl<H: PagingHandler> core::fmt::Debug for IVCChannel<H> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
-            "IVCChannel(publisher[{}], subscribers {:?}, base: {:?}, size: {:#x}, gpa: {:?})",
+            "IVCChannel(publisher[{}], subscribers {:?}, base: {:?}, size: {:#x}, gpa: {:?}, ring: {:?})",
             self.publisher_vm_id,
             self.subscriber_vms,
             self.shared_region_base,
             self.shared_region_size,
-            self.base_gpa
+            self.base_gpa,
+            self.ring
         )
     }
 }
@@ -219,7 +1293,30 @@ impl<H: PagingHandler> Drop for IVCChannel<H> {
             "Dropping IVCChannel for VM[{}], shared region base: {:?}",
             self.publisher_vm_id, self.shared_region_base
         );
-        H::dealloc_frame(self.shared_region_base);
+        let scrub = |frame: HostPhysAddr| {
+            // SAFETY: the frame is owned by the channel, which is unmapped from all VMs.
+            self.scrub.scrub(unsafe {
+                core::slice::from_raw_parts_mut(H::phys_to_virt(frame).as_mut_ptr(), PAGE_SIZE_4K)
+            })
+        };
+        match self.origin {
+            RegionOrigin::Host => {
+                scrub(self.shared_region_base);
+                H::dealloc_frame(self.shared_region_base);
+                memstat::uncharge(Ivc, Some(self.publisher_vm_id), PAGE_SIZE_4K);
+            }
+            RegionOrigin::Exported => {
+                scrub(self.shared_region_base);
+                bridge::release_region(self.shared_region_base);
+            }
+            // Owned by the peer node.
+            RegionOrigin::Imported => {}
+        }
+        if let Some(frame) = self.broadcast_frame {
+            scrub(frame);
+            H::dealloc_frame(frame);
+            memstat::uncharge(Ivc, Some(self.publisher_vm_id), PAGE_SIZE_4K);
+        }
     }
 }
 
@@ -232,9 +1329,20 @@ impl<H: PagingHandler> IVCChannel<H> {
     ) -> AxResult<Self> {
         // TODO: support larger shared region sizes with alloc_frames API.
         let shared_region_size = shared_region_size.min(4096);
-        let shared_region_base = H::alloc_frame().ok_or_else(|| {
-            axerrno::ax_err_type!(NoMemory, "Failed to allocate shared region frame")
-        })?;
+        let origin = if bridge::is_exported(publisher_vm_id, key) {
+            RegionOrigin::Exported
+        } else {
+            RegionOrigin::Host
+        };
+        let shared_region_base = if origin == RegionOrigin::Exported {
+            bridge::alloc_region(PAGE_SIZE_4K)?
+        } else {
+            memstat::try_charge(Ivc, publisher_vm_id, PAGE_SIZE_4K)?;
+            reclaim::alloc_or_reclaim(H::alloc_frame).ok_or_else(|| {
+                memstat::uncharge(Ivc, Some(publisher_vm_id), PAGE_SIZE_4K);
+                ax_err_type!(NoMemory, "Failed to allocate shared region frame")
+            })?
+        };
 
         let mut channel = IVCChannel {
             publisher_vm_id,
@@ -243,6 +1351,11 @@ impl<H: PagingHandler> IVCChannel<H> {
             shared_region_base,
             shared_region_size,
             base_gpa: Some(base_gpa),
+            ring: None,
+            broadcast_frame: None,
+            scrub: security::scrub_mode(publisher_vm_id),
+            origin,
+            notify_vectors: BTreeMap::new(),
             _phatom: core::marker::PhantomData,
         };
 
@@ -254,16 +1367,143 @@ impl<H: PagingHandler> IVCChannel<H> {
         Ok(channel)
     }
 
-    pub fn base_hpa(&self) -> HostPhysAddr {
-        self.shared_region_base
+    /// Allocates a ring channel of `slot_size`-byte slots, see the [module docs](self).
+    ///
+    /// `watermarks` are the high and low watermarks in watermark mode, `(0, 0)` for the defaults.
+    pub fn alloc_ring(
+        publisher_vm_id: usize,
+        key: usize,
+        shared_region_size: usize,
+        base_gpa: GuestPhysAddr,
+        notify: IVCNotifyMode,
+        slot_size: usize,
+        watermarks: (u32, u32),
+    ) -> AxResult<Self> {
+        let mut channel = Self::alloc(publisher_vm_id, key, shared_region_size, base_gpa)?;
+        channel.init_ring(notify, slot_size, watermarks)?;
+        Ok(channel)
+    }
+
+    /// Allocates a broadcast channel, see the [module docs](self).
+    pub fn alloc_broadcast(
+        publisher_vm_id: usize,
+        key: usize,
+        base_gpa: GuestPhysAddr,
+    ) -> AxResult<Self> {
+        if bridge::is_exported(publisher_vm_id, key) {
+            return ax_err!(Unsupported, "broadcast IVC channels cannot be exported");
+        }
+        let mut channel = Self::alloc(publisher_vm_id, key, PAGE_SIZE_4K, base_gpa)?;
+        memstat::try_charge(Ivc, publisher_vm_id, PAGE_SIZE_4K)?;
+        let frame = reclaim::alloc_or_reclaim(H::alloc_frame).ok_or_else(|| {
+            memstat::uncharge(Ivc, Some(publisher_vm_id), PAGE_SIZE_4K);
+            ax_err_type!(NoMemory, "Failed to allocate broadcast header frame")
+        })?;
+        channel.broadcast_frame = Some(frame);
+        let header = channel.broadcast_header().unwrap();
+        header.version.store(0, Ordering::Relaxed);
+        header.updated_at_ns.store(0, Ordering::Relaxed);
+        Ok(channel)
     }
 
+    /// Wraps the region of `size` bytes at `base` of a channel of the peer node, published by the
+    /// proxy VM `proxy_vm_id`. A region laid out as rings by the peer is a ring channel.
+    fn import(proxy_vm_id: usize, key: usize, base: HostPhysAddr, size: usize) -> Self {
+        let mut channel = IVCChannel {
+            publisher_vm_id: proxy_vm_id,
+            key,
+            subscriber_vms: BTreeMap::new(),
+            shared_region_base: base,
+            shared_region_size: size,
+            // The publisher maps the region on the peer node.
+            base_gpa: Some(GuestPhysAddr::from(0)),
+            ring: None,
+            broadcast_frame: None,
+            scrub: ScrubMode::Off,
+            origin: RegionOrigin::Imported,
+            notify_vectors: BTreeMap::new(),
+            _phatom: core::marker::PhantomData,
+        };
+        if size >= core::mem::size_of::<IVCChannelHeader>() + core::mem::size_of::<IVCRingHeader>()
+        {
+            let header = channel.ring_header();
+            let ring_end = |idx: usize| {
+                header.slot_offsets[idx] as usize
+                    + header.slot_count as usize * header.slot_size as usize
+            };
+            if header.magic == IVC_RING_MAGIC
+                && header.version == IVC_RING_VERSION
+                && header.slot_count.is_power_of_two()
+                && header.slot_size.is_power_of_two()
+                && ring_end(0) <= size
+                && ring_end(1) <= size
+                && let Ok(notify) = IVCNotifyMode::try_from(header.notify as u64)
+            {
+                let watermarks = header.rings[0].watermarks;
+                channel.ring = Some(IVCRingConfig {
+                    notify,
+                    slot_size: header.slot_size,
+                    slot_count: header.slot_count,
+                    kicked_heads: [0; 2],
+                    watermarks: (notify == IVCNotifyMode::Watermark).then_some(IVCWatermarks {
+                        high: watermarks & 0xffff,
+                        low: watermarks >> 16,
+                        above: [false; 2],
+                    }),
+                });
+            }
+        }
+        channel
+    }
+
+    fn broadcast_header(&self) -> Option<&IVCBroadcastHeader> {
+        self.broadcast_frame
+            .map(|frame| unsafe { &*H::phys_to_virt(frame).as_mut_ptr_of::<IVCBroadcastHeader>() })
+    }
+
+    /// The parts of the channel to map into VM `vm_id`, which is attached to it.
+    pub fn mappings(&self, vm_id: usize) -> Vec<IVCMapping> {
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let Some(frame) = self.broadcast_frame else {
+            return vec![IVCMapping {
+                offset: 0,
+                hpa: self.shared_region_base,
+                size: self.shared_region_size,
+                flags: rw,
+            }];
+        };
+        let data_flags = if vm_id == self.publisher_vm_id {
+            rw
+        } else {
+            MappingFlags::READ
+        };
+        vec![
+            IVCMapping {
+                offset: 0,
+                hpa: self.shared_region_base,
+                size: self.shared_region_size,
+                flags: data_flags,
+            },
+            IVCMapping {
+                offset: self.shared_region_size,
+                hpa: frame,
+                size: PAGE_SIZE_4K,
+                flags: MappingFlags::READ,
+            },
+        ]
+    }
+
+
     pub fn base_gpa_in_publisher(&self) -> Option<GuestPhysAddr> {
         self.base_gpa
     }
 
+    /// Size of the channel in the guests, including the header page of a broadcast channel.
     pub fn size(&self) -> usize {
-        self.shared_region_size
+        match self.broadcast_frame {
+            Some(_) => self.shared_region_size + PAGE_SIZE_4K,
+            None => self.shared_region_size,
+        }
     }
 
     pub fn add_subscriber(&mut self, subscriber_vm_id: usize, subscriber_gpa: GuestPhysAddr) {
@@ -276,6 +1516,15 @@ impl<H: PagingHandler> IVCChannel<H> {
         self.subscriber_vms.remove(&subscriber_vm_id)
     }
 
+    /// Sets the vector VM `vm_id` is notified with, 0 for none.
+    pub fn set_notify_vector(&mut self, vm_id: usize, vector: usize) {
+        if vector != 0 {
+            self.notify_vectors.insert(vm_id, vector);
+        } else {
+            self.notify_vectors.remove(&vm_id);
+        }
+    }
+
     pub fn subscribers(&self) -> Vec<(usize, GuestPhysAddr)> {
         self.subscriber_vms
             .iter()
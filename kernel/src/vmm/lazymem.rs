@@ -0,0 +1,470 @@
+//! On-demand population of guest memory.
+//!
+//! By default the memory regions of a VM are allocated and mapped when the VM is created. With a
+//! `[lazy_memory]` section, the allocated regions (`MapAlloc`) after the first one are only
+//! reserved in the guest physical address space: a frame is allocated, zeroed and mapped when
+//! the guest first accesses a page, on the stage-2 fault. This lets the memory of the VMs add up
+//! to more than the host has, as long as the guests don't touch all of it, and VMs with a lot of
+//! memory start faster:
+//!
+//! ```toml
+//! [lazy_memory]
+//! # Hard limit of the memory populated on demand in bytes, none by default.
+//! limit = 0x2000_0000
+//! # Share the pages of the boot images with the other VMs booting the same images, false by
+//! # default.
+//! share_images = true
+//! ```
+//!
+//! The first region holds the images of the VM and is always populated at creation, so a VM
+//! benefits from lazy population by putting the bulk of its memory in further regions. A fault
+//! beyond the limit, or when the host is out of memory even after reclaim (see
+//! [`crate::vmm::reclaim`]), is a crash of the vCPU (see [`crate::vmm::crash`]).
+//!
+//! The hypervisor populates the pages it accesses itself on behalf of the guest, see
+//! [`populate`]. Populated pages are only freed when the VM is destroyed.
+//!
+//! With `share_images`, the images loaded entirely in regions populated on demand are not
+//! copied: their pages are mapped read-only to the frames shared by all VMs booting the same
+//! images (see [`crate::vmm::imgshare`]). The first write to a shared page, by the guest or by
+//! the hypervisor, faults and gives the VM a private copy of the page (copy-on-write). Shared
+//! pages don't count in the limit, private copies do. Images loaded in the first region are
+//! always copied, so the images of a VM sharing them are loaded in a further region, e.g., with
+//! a small first region for the firmware and the DTB.
+//!
+//! The regions populated on demand are also what a template captures and its instances share
+//! copy-on-write, see [`crate::vmm::template`].
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use axaddrspace::{AxMmHal, GuestPhysAddr, HostPhysAddr, MappingFlags};
+use axerrno::{AxResult, ax_err, ax_err_type};
+use axvm::config::{VmMemConfig, VmMemMappingType};
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::hal::AxMmHalImpl;
+use crate::vmm::{VMRef, imgshare, iommu, reclaim, security, stage2};
+
+/// A region populated on demand.
+#[derive(Debug, Clone, Copy)]
+struct LazyRegion {
+    gpa: usize,
+    size: usize,
+    flags: MappingFlags,
+}
+
+struct VmLazyMemory {
+    regions: Vec<LazyRegion>,
+    limit: Option<usize>,
+    share_images: bool,
+    /// Frames populated, indexed by guest physical page.
+    frames: BTreeMap<usize, HostPhysAddr>,
+    /// Shared image frames mapped read-only, indexed by guest physical page.
+    shared: BTreeMap<usize, HostPhysAddr>,
+    faults: u64,
+    /// Shared pages copied on the first write.
+    cow_breaks: u64,
+    /// Whether the VM is stamped from a template.
+    stamped: bool,
+}
+
+/// Lazily populated memory of the VMs with a `[lazy_memory]` section, indexed by VM ID.
+static LAZY: Mutex<BTreeMap<usize, VmLazyMemory>> = Mutex::new(BTreeMap::new());
+
+/// Lazily populated memory of a VM.
+#[derive(Debug, Clone, Copy)]
+pub struct LazyMemoryStats {
+    /// Size of the regions populated on demand.
+    pub size: usize,
+    /// Bytes populated so far.
+    pub populated: usize,
+    pub limit: Option<usize>,
+    /// Stage-2 faults that populated a page.
+    pub faults: u64,
+    /// Bytes mapped to shared image pages.
+    pub shared: usize,
+    /// Shared pages copied on the first write.
+    pub cow_breaks: u64,
+}
+
+/// Whether memory region `idx` of a VM whose raw config is `raw_cfg` is populated on demand.
+pub fn is_lazy(raw_cfg: &toml::Table, idx: usize, region: &VmMemConfig) -> bool {
+    raw_cfg.contains_key("lazy_memory")
+        && idx > 0
+        && matches!(region.map_type, VmMemMappingType::MapAlloc)
+}
+
+/// Records the regions of the VM populated on demand, as described in the `[lazy_memory]`
+/// section of `raw_cfg`.
+///
+/// Does nothing if the VM config has no `[lazy_memory]` section. Must run before the regions
+/// that are not lazy are allocated, which skips the lazy ones.
+pub fn setup_vm_lazy_memory(
+    vm: &VMRef,
+    raw_cfg: &toml::Table,
+    regions: &[VmMemConfig],
+) -> AxResult {
+    let Some(cfg) = raw_cfg.get("lazy_memory").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let limit = match cfg.get("limit") {
+        None => None,
+        Some(limit) => Some(
+            limit
+                .as_integer()
+                .filter(|limit| *limit > 0)
+                .ok_or_else(|| ax_err_type!(InvalidInput, "lazy_memory config: invalid `limit`"))?
+                as usize,
+        ),
+    };
+    let share_images = match cfg.get("share_images") {
+        None => false,
+        Some(share) => share.as_bool().ok_or_else(|| {
+            ax_err_type!(InvalidInput, "lazy_memory config: invalid `share_images`")
+        })?,
+    };
+    let regions: Vec<_> = regions
+        .iter()
+        .enumerate()
+        .filter(|(idx, region)| is_lazy(raw_cfg, *idx, region))
+        .map(|(_, region)| LazyRegion {
+            gpa: region.gpa,
+            size: region.size,
+            flags: MappingFlags::from_bits_truncate(region.flags),
+        })
+        .collect();
+    if let Some(region) = regions
+        .iter()
+        .find(|region| region.gpa % PAGE_SIZE_4K != 0 || region.size % PAGE_SIZE_4K != 0)
+    {
+        return ax_err!(
+            InvalidInput,
+            format!(
+                "lazy_memory config: region at {:#x} is not page aligned",
+                region.gpa
+            )
+        );
+    }
+
+    info!(
+        "VM[{}] {} memory region(s) populated on demand, limit {:?}",
+        vm.id(),
+        regions.len(),
+        limit
+    );
+    LAZY.lock().insert(
+        vm.id(),
+        VmLazyMemory {
+            regions,
+            limit,
+            share_images,
+            frames: BTreeMap::new(),
+            shared: BTreeMap::new(),
+            faults: 0,
+            cow_breaks: 0,
+            stamped: false,
+        },
+    );
+    Ok(())
+}
+
+/// Frees the populated memory of a VM, called when the VM is destroyed.
+pub fn teardown_vm_lazy_memory(vm_id: usize) {
+    if let Some(lazy) = LAZY.lock().remove(&vm_id) {
+        let scrub = security::scrub_mode(vm_id);
+        for frame in lazy.frames.into_values() {
+            scrub.scrub_frame(frame);
+            AxMmHalImpl::dealloc_frame(frame);
+        }
+        for frame in lazy.shared.into_values() {
+            imgshare::release(frame);
+        }
+    }
+}
+
+impl VmLazyMemory {
+    fn region_of(&self, page: usize) -> Option<LazyRegion> {
+        self.regions
+            .iter()
+            .find(|region| (region.gpa..region.gpa + region.size).contains(&page))
+            .copied()
+    }
+
+    /// Populates the page of `gpa` with a private frame, copying the shared image page mapped
+    /// there if any. Returns false if it's not in a lazy region.
+    fn populate_page(&mut self, vm: &VMRef, gpa: usize) -> AxResult<bool> {
+        let page = gpa & !(PAGE_SIZE_4K - 1);
+        let Some(region) = self.region_of(page) else {
+            return Ok(false);
+        };
+        if self.frames.contains_key(&page) {
+            // Populated by another vCPU meanwhile.
+            return Ok(true);
+        }
+        if self
+            .limit
+            .is_some_and(|limit| (self.frames.len() + 1) * PAGE_SIZE_4K > limit)
+        {
+            return ax_err!(
+                NoMemory,
+                format!("VM[{}] lazy memory limit reached", vm.id())
+            );
+        }
+
+        let frame = reclaim::alloc_or_reclaim(AxMmHalImpl::alloc_frame)
+            .ok_or_else(|| ax_err_type!(NoMemory, "failed to populate guest memory"))?;
+        // SAFETY: the frame was just allocated and is mapped in the linear mapping of the host.
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(
+                AxMmHalImpl::phys_to_virt(frame).as_mut_ptr(),
+                PAGE_SIZE_4K,
+            )
+        };
+        let shared = self.shared.get(&page).copied();
+        match shared {
+            Some(shared) => imgshare::copy_to(shared, bytes),
+            None => bytes.fill(0),
+        }
+        let mapped = match shared {
+            Some(_) => iommu::unmap_region(vm, GuestPhysAddr::from(page), PAGE_SIZE_4K),
+            None => Ok(()),
+        }
+        .and_then(|_| {
+            stage2::map_region(
+                vm,
+                GuestPhysAddr::from(page),
+                frame,
+                PAGE_SIZE_4K,
+                region.flags,
+            )
+        });
+        if let Err(e) = mapped {
+            AxMmHalImpl::dealloc_frame(frame);
+            return Err(e);
+        }
+        if let Some(shared) = shared {
+            self.shared.remove(&page);
+            imgshare::release(shared);
+            self.cow_breaks += 1;
+        }
+        self.frames.insert(page, frame);
+        Ok(true)
+    }
+}
+
+/// Handles a stage-2 fault of the VM at `gpa`.
+///
+/// Returns false if `gpa` is not in a region populated on demand, an error if the page can't be
+/// populated.
+pub fn handle_fault(vm: &VMRef, gpa: GuestPhysAddr) -> AxResult<bool> {
+    let mut lazy = LAZY.lock();
+    let Some(vm_lazy) = lazy.get_mut(&vm.id()) else {
+        return Ok(false);
+    };
+    let populated = vm_lazy.populate_page(vm, gpa.as_usize())?;
+    if populated {
+        vm_lazy.faults += 1;
+    }
+    Ok(populated)
+}
+
+/// Populates the pages of `[gpa, gpa + size)` in the regions of the VM populated on demand,
+/// before the hypervisor accesses them.
+pub fn populate(vm: &VMRef, gpa: GuestPhysAddr, size: usize) -> AxResult {
+    let mut lazy = LAZY.lock();
+    let Some(vm_lazy) = lazy.get_mut(&vm.id()) else {
+        return Ok(());
+    };
+    let start = gpa.as_usize() & !(PAGE_SIZE_4K - 1);
+    for page in (start..gpa.as_usize() + size).step_by(PAGE_SIZE_4K) {
+        vm_lazy.populate_page(vm, page)?;
+    }
+    Ok(())
+}
+
+/// Loads an image of `size` bytes at `gpa` with shared pages, if the VM shares its images and
+/// the image is entirely in regions populated on demand. `read` fills its buffer with the next
+/// bytes of the image.
+///
+/// Returns false, without reading the image, if it's not loaded with shared pages, in which case
+/// the caller copies it as usual.
+pub fn load_shared_image(
+    vm: &VMRef,
+    gpa: GuestPhysAddr,
+    size: usize,
+    mut read: impl FnMut(&mut [u8]) -> AxResult,
+) -> AxResult<bool> {
+    let mut lazy = LAZY.lock();
+    let Some(vm_lazy) = lazy.get_mut(&vm.id()) else {
+        return Ok(false);
+    };
+    let start = gpa.as_usize() & !(PAGE_SIZE_4K - 1);
+    let end = gpa.as_usize() + size;
+    let pages: Vec<_> = (start..end).step_by(PAGE_SIZE_4K).collect();
+    // The images of a stamped VM were loaded, and possibly modified, by the VM of the template.
+    if vm_lazy.stamped && size > 0 && pages.iter().all(|page| vm_lazy.shared.contains_key(page)) {
+        return Ok(true);
+    }
+    if !vm_lazy.share_images
+        || size == 0
+        || !pages.iter().all(|page| {
+            vm_lazy.region_of(*page).is_some()
+                && !vm_lazy.frames.contains_key(page)
+                && !vm_lazy.shared.contains_key(page)
+        })
+    {
+        return Ok(false);
+    }
+
+    let mut buf = alloc::vec![0u8; PAGE_SIZE_4K];
+    for page in pages {
+        // The parts of the first and last pages outside of the image are zero, as in a page
+        // populated on demand.
+        buf.fill(0);
+        let from = gpa.as_usize().max(page) - page;
+        let to = end.min(page + PAGE_SIZE_4K) - page;
+        read(&mut buf[from..to])?;
+
+        let frame = imgshare::get(&buf)?;
+        let flags = vm_lazy.region_of(page).unwrap().flags - MappingFlags::WRITE;
+        if let Err(e) =
+            stage2::map_region(vm, GuestPhysAddr::from(page), frame, PAGE_SIZE_4K, flags)
+        {
+            imgshare::release(frame);
+            return Err(e);
+        }
+        vm_lazy.shared.insert(page, frame);
+    }
+    debug!(
+        "VM[{}] image at {:#x} loaded with shared pages, {:#x} bytes",
+        vm.id(),
+        gpa,
+        size
+    );
+    Ok(true)
+}
+
+/// Returns the pages of the regions of `vm` populated on demand as shared frames, indexed by guest
+/// physical page: the shared image pages the VM maps and copies of its private pages. The caller
+/// holds a reference to each frame, dropped with [`imgshare::release`].
+///
+/// The VM must be paused, so that its pages don't change meanwhile.
+pub fn share_pages(vm: &VMRef) -> AxResult<BTreeMap<usize, HostPhysAddr>> {
+    let lazy = LAZY.lock();
+    let Some(vm_lazy) = lazy.get(&vm.id()) else {
+        return ax_err!(
+            InvalidInput,
+            format!("VM[{}] has no memory populated on demand", vm.id())
+        );
+    };
+    let mut pages = BTreeMap::new();
+    for (&page, &frame) in &vm_lazy.frames {
+        // SAFETY: the frame is populated and mapped in the linear mapping of the host.
+        let bytes = unsafe {
+            core::slice::from_raw_parts(AxMmHalImpl::phys_to_virt(frame).as_ptr(), PAGE_SIZE_4K)
+        };
+        match imgshare::get(bytes) {
+            Ok(shared) => {
+                pages.insert(page, shared);
+            }
+            Err(e) => {
+                pages.into_values().for_each(imgshare::release);
+                return Err(e);
+            }
+        }
+    }
+    for (&page, &frame) in &vm_lazy.shared {
+        imgshare::acquire(frame);
+        pages.insert(page, frame);
+    }
+    Ok(pages)
+}
+
+/// Maps the shared frames of a template, indexed by guest physical page, read-only into the
+/// regions of `vm` populated on demand. Must run before the images of the VM are loaded: those
+/// the template holds aren't loaded again.
+///
+/// The pages are copied on the first write, like shared image pages.
+pub fn map_template(vm: &VMRef, pages: &BTreeMap<usize, HostPhysAddr>) -> AxResult {
+    let mut lazy = LAZY.lock();
+    let Some(vm_lazy) = lazy.get_mut(&vm.id()) else {
+        return ax_err!(
+            InvalidInput,
+            format!("VM[{}] has no memory populated on demand", vm.id())
+        );
+    };
+    vm_lazy.stamped = true;
+    for (&page, &frame) in pages {
+        let Some(region) = vm_lazy.region_of(page) else {
+            return ax_err!(
+                InvalidInput,
+                format!(
+                    "VM[{}] has no memory populated on demand at {:#x}",
+                    vm.id(),
+                    page
+                )
+            );
+        };
+        imgshare::acquire(frame);
+        let flags = region.flags - MappingFlags::WRITE;
+        if let Err(e) =
+            stage2::map_region(vm, GuestPhysAddr::from(page), frame, PAGE_SIZE_4K, flags)
+        {
+            imgshare::release(frame);
+            return Err(e);
+        }
+        vm_lazy.shared.insert(page, frame);
+    }
+    Ok(())
+}
+
+/// Frees the populated pages of the VM holding only zeros, which are populated again, zeroed, on
+/// the next access. Returns the number of pages freed.
+///
+/// The VM must not run meanwhile, e.g. while it's suspended to RAM (see [`crate::vmm::sleep`]).
+pub fn release_zero_pages(vm: &VMRef) -> usize {
+    let mut lazy = LAZY.lock();
+    let Some(vm_lazy) = lazy.get_mut(&vm.id()) else {
+        return 0;
+    };
+    let zero_pages: Vec<usize> = vm_lazy
+        .frames
+        .iter()
+        .filter(|(_, frame)| {
+            // SAFETY: populated frames are mapped in the linear mapping of the host.
+            let bytes = unsafe {
+                core::slice::from_raw_parts(
+                    AxMmHalImpl::phys_to_virt(**frame).as_ptr(),
+                    PAGE_SIZE_4K,
+                )
+            };
+            bytes.iter().all(|b| *b == 0)
+        })
+        .map(|(page, _)| *page)
+        .collect();
+    let mut freed = 0;
+    for page in zero_pages {
+        if let Err(e) = iommu::unmap_region(vm, GuestPhysAddr::from(page), PAGE_SIZE_4K) {
+            warn!("VM[{}] failed to unmap zero page {:#x}: {:?}", vm.id(), page, e);
+            continue;
+        }
+        if let Some(frame) = vm_lazy.frames.remove(&page) {
+            AxMmHalImpl::dealloc_frame(frame);
+            freed += 1;
+        }
+    }
+    freed
+}
+
+/// Returns the lazily populated memory of VM `vm_id`, `None` without a `[lazy_memory]` section.
+pub fn lazy_memory_stats(vm_id: usize) -> Option<LazyMemoryStats> {
+    LAZY.lock().get(&vm_id).map(|lazy| LazyMemoryStats {
+        size: lazy.regions.iter().map(|region| region.size).sum(),
+        populated: lazy.frames.len() * PAGE_SIZE_4K,
+        limit: lazy.limit,
+        faults: lazy.faults,
+        shared: lazy.shared.len() * PAGE_SIZE_4K,
+        cow_breaks: lazy.cow_breaks,
+    })
+}
@@ -0,0 +1,245 @@
+//! The log of the lifecycle events of the VMs, shared with the manager VMs.
+//!
+//! The hypervisor appends an event to the log when a VM is created, boots, reports itself ready,
+//! crashes or is destroyed. A manager VM (see [`crate::vmm::vmdef`]) subscribes to the log in its
+//! config, so that its services react to peers appearing and disappearing without polling:
+//!
+//! ```toml
+//! [lifecycle]
+//! # Guest physical address of the log page, page aligned and outside of guest memory.
+//! gpa = 0x0900_4000
+//! # Interrupt raised in the VM when events are appended, none by default.
+//! irq = 0x33
+//! ```
+//!
+//! The log is a single read-only page shared by all subscribers: a [`LifecycleLogHeader`]
+//! followed, from offset [`LIFECYCLE_RECORDS_OFFSET`], by a ring of [`LifecycleRecord`]s. Events
+//! are numbered from 1, event `n` is in slot `(n - 1) % capacity` and `head` is the last one
+//! appended, so the log holds the events since the hypervisor started until it wraps. A record
+//! is being written while its `sequence` is 0; a guest reads `sequence`, the record and
+//! `sequence` again and retries if they differ or are not the event it expects.
+//!
+//! A VM reports itself ready with the [`HVC_VM_READY`](crate::vmm::hvc::HVC_VM_READY) hypercall,
+//! once its services are up; what ready means is up to the guests.
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::{offset_of, size_of};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::{AxMmHal, GuestPhysAddr, HostPhysAddr, MappingFlags};
+use axerrno::{AxResult, ax_err, ax_err_type};
+use memory_addr::PAGE_SIZE_4K;
+use spin::{Mutex, Once};
+
+use crate::hal::AxMmHalImpl;
+use crate::vmm::irq::IrqLine;
+use crate::vmm::memstat::{self, MemSubsystem};
+use crate::vmm::{VMRef, reclaim, stage2, vmdef};
+
+/// `magic` of [`LifecycleLogHeader`], "AXLC".
+pub const LIFECYCLE_MAGIC: u32 = u32::from_le_bytes(*b"AXLC");
+/// Version of the layouts of the log.
+pub const LIFECYCLE_VERSION: u16 = 1;
+/// Offset of the first record in the log page.
+pub const LIFECYCLE_RECORDS_OFFSET: usize = 64;
+/// Records in the log.
+pub const LIFECYCLE_CAPACITY: usize =
+    (PAGE_SIZE_4K - LIFECYCLE_RECORDS_OFFSET) / size_of::<LifecycleRecord>();
+
+/// Lifecycle events, in `LifecycleRecord::event`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum LifecycleEvent {
+    Created = 1,
+    Booted = 2,
+    Ready = 3,
+    /// `detail` is the [`CrashReason`](crate::vmm::crash::CrashReason).
+    Crashed = 4,
+    Destroyed = 5,
+    /// The guest asked to power off or reboot, `detail` is the
+    /// [`PowerRequest`](crate::vmm::reboot::PowerRequest).
+    PowerRequest = 6,
+}
+
+/// Header of the log page.
+#[repr(C)]
+pub struct LifecycleLogHeader {
+    pub magic: u32,
+    pub version: u16,
+    /// Size of a [`LifecycleRecord`].
+    pub record_size: u16,
+    /// Records in the ring.
+    pub capacity: u32,
+    pub _reserved: u32,
+    /// Number of the last event appended, 0 before the first one.
+    pub head: AtomicU64,
+}
+
+/// An event of the log.
+#[repr(C)]
+pub struct LifecycleRecord {
+    /// Number of the event, 0 while the record is being written.
+    pub sequence: AtomicU64,
+    /// Host time of the event in nanoseconds.
+    pub time_ns: u64,
+    pub vm_id: u32,
+    /// [`LifecycleEvent`].
+    pub event: u32,
+    /// Depends on the event, 0 if unused.
+    pub detail: u32,
+    pub _reserved: u32,
+    /// Name of the VM, NUL-padded.
+    pub name: [u8; 32],
+}
+
+struct Log {
+    frame: HostPhysAddr,
+    head: u64,
+    /// Names of the VMs, kept until they are destroyed.
+    names: BTreeMap<usize, String>,
+}
+
+/// The log, allocated by the first event.
+static LOG: Once<Mutex<Log>> = Once::new();
+/// Interrupts of the subscribed VMs, indexed by VM ID.
+static SUBSCRIBERS: Mutex<BTreeMap<usize, Option<usize>>> = Mutex::new(BTreeMap::new());
+
+fn log() -> AxResult<&'static Mutex<Log>> {
+    LOG.try_call_once(|| {
+        let frame = reclaim::alloc_or_reclaim(AxMmHalImpl::alloc_frame)
+            .ok_or_else(|| ax_err_type!(NoMemory, "failed to allocate the lifecycle log"))?;
+        memstat::charge(MemSubsystem::HvInfo, None, PAGE_SIZE_4K);
+        let page = AxMmHalImpl::phys_to_virt(frame).as_mut_ptr();
+        // SAFETY: the frame was just allocated and is mapped in the linear mapping of the host.
+        unsafe {
+            core::ptr::write_bytes(page, 0, PAGE_SIZE_4K);
+            page.cast::<LifecycleLogHeader>().write(LifecycleLogHeader {
+                magic: LIFECYCLE_MAGIC,
+                version: LIFECYCLE_VERSION,
+                record_size: size_of::<LifecycleRecord>() as u16,
+                capacity: LIFECYCLE_CAPACITY as u32,
+                _reserved: 0,
+                head: AtomicU64::new(0),
+            })
+        };
+        Ok(Mutex::new(Log {
+            frame,
+            head: 0,
+            names: BTreeMap::new(),
+        }))
+    })
+}
+
+/// Appends `event` of VM `vm_id` to the log and interrupts the subscribers.
+pub fn emit(vm_id: usize, event: LifecycleEvent, detail: u32) {
+    let log = match log() {
+        Ok(log) => log,
+        Err(e) => {
+            warn!("VM[{}] lifecycle event {:?} lost: {:?}", vm_id, event, e);
+            return;
+        }
+    };
+    {
+        let mut log = log.lock();
+        if event == LifecycleEvent::Created
+            && let Some(vm) = crate::vmm::vm_list::get_vm_by_id(vm_id)
+        {
+            log.names.insert(vm_id, vm.with_config(|cfg| cfg.name()));
+        }
+        let mut name = [0u8; 32];
+        if let Some(vm_name) = log.names.get(&vm_id) {
+            let len = vm_name.len().min(name.len() - 1);
+            name[..len].copy_from_slice(&vm_name.as_bytes()[..len]);
+        }
+        if event == LifecycleEvent::Destroyed {
+            log.names.remove(&vm_id);
+        }
+
+        log.head += 1;
+        let sequence = log.head;
+        let page = AxMmHalImpl::phys_to_virt(log.frame).as_mut_ptr();
+        let slot = (sequence - 1) as usize % LIFECYCLE_CAPACITY;
+        // SAFETY: the page is the log, the slot is within it and only written under the lock.
+        unsafe {
+            let record = &mut *page
+                .add(LIFECYCLE_RECORDS_OFFSET + slot * size_of::<LifecycleRecord>())
+                .cast::<LifecycleRecord>();
+            record.sequence.store(0, Ordering::Release);
+            record.time_ns = axhal::time::monotonic_time_nanos();
+            record.vm_id = vm_id as u32;
+            record.event = event as u32;
+            record.detail = detail;
+            record.name = name;
+            record.sequence.store(sequence, Ordering::Release);
+            (*page
+                .add(offset_of!(LifecycleLogHeader, head))
+                .cast::<AtomicU64>())
+            .store(sequence, Ordering::Release);
+        }
+    }
+    debug!("VM[{}] lifecycle event {:?}", vm_id, event);
+
+    let subscribers: Vec<_> = SUBSCRIBERS
+        .lock()
+        .iter()
+        .filter_map(|(vm_id, irq)| irq.map(|irq| (*vm_id, irq)))
+        .collect();
+    for (vm_id, irq) in subscribers {
+        IrqLine::new(vm_id, irq).raise();
+    }
+}
+
+/// Maps the log into the VM as described in the `[lifecycle]` section of `raw_cfg`.
+///
+/// Does nothing if the VM config has no `[lifecycle]` section. Must run after
+/// [`vmdef::setup_vm_manager`].
+pub fn setup_vm_lifecycle(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("lifecycle").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let get = |key: &str| {
+        cfg.get(key)
+            .and_then(|v| v.as_integer())
+            .map(|v| v as usize)
+    };
+    if !vmdef::is_manager(vm.id()) {
+        return ax_err!(
+            PermissionDenied,
+            "lifecycle config: only manager VMs may subscribe"
+        );
+    }
+    let Some(gpa) = get("gpa") else {
+        return ax_err!(InvalidInput, "lifecycle config: missing `gpa`");
+    };
+    if gpa % PAGE_SIZE_4K != 0 {
+        return ax_err!(InvalidInput, "lifecycle config: `gpa` must be page aligned");
+    }
+    if vm.memory_regions().iter().any(|region| {
+        gpa < region.gpa.as_usize() + region.size() && region.gpa.as_usize() < gpa + PAGE_SIZE_4K
+    }) {
+        return ax_err!(
+            InvalidInput,
+            format!("lifecycle config: {:#x} overlaps guest memory", gpa)
+        );
+    }
+
+    let frame = log()?.lock().frame;
+    stage2::map_region(
+        vm,
+        GuestPhysAddr::from(gpa),
+        frame,
+        PAGE_SIZE_4K,
+        MappingFlags::READ,
+    )?;
+    SUBSCRIBERS.lock().insert(vm.id(), get("irq"));
+    info!("VM[{}] lifecycle log at {:#x}", vm.id(), gpa);
+    Ok(())
+}
+
+/// Unsubscribes a VM from the log, called when the VM is destroyed.
+pub fn teardown_vm_lifecycle(vm_id: usize) {
+    SUBSCRIBERS.lock().remove(&vm_id);
+}
@@ -0,0 +1,333 @@
+//! Lockup detection of the physical CPUs of the hypervisor.
+//!
+//! Every physical CPU touches a heartbeat whenever it runs a vCPU loop iteration, and from a
+//! heartbeat thread pinned to it when it has nothing else to run, so a CPU busy with guests beats
+//! as well as an idle one. A CPU whose heartbeat is older than [`LOCKUP_TIMEOUT`] is stuck in the
+//! hypervisor, e.g. spinning on a lock with interrupts disabled.
+//!
+//! The CPUs monitor each other: each one checks the heartbeats of the others at most once per
+//! [`HEARTBEAT_PERIOD`]. A silent CPU is reported once, with the last events of its trace ring
+//! (vCPU entries and exits, heartbeats), and the [`LockupAction`] is taken. There is no hardware
+//! watchdog driver, so a lockup of every CPU, or of the only one, goes unnoticed.
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use std::os::arceos::api::task::{AxCpuMask, ax_set_current_affinity};
+use std::os::arceos::modules::axhal;
+use std::thread;
+
+use spin::Once;
+
+use crate::vmm::memstat::{self, MemSubsystem};
+use crate::vmm::{hang, vcpus, vm_list};
+
+/// Interval between two heartbeats of an idle CPU, and between two checks of a CPU.
+pub const HEARTBEAT_PERIOD: Duration = Duration::from_secs(1);
+/// Age of the heartbeat of a stuck CPU.
+pub const LOCKUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Events kept in the trace ring of each CPU.
+const TRACE_LEN: usize = 16;
+
+/// What to do when a CPU is stuck.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LockupAction {
+    /// Only report the CPU.
+    Log = 0,
+    /// Stop the VM whose vCPU last ran on the CPU, so that its other vCPUs don't pile up behind
+    /// it, and panic if the CPU is still stuck after another timeout.
+    Recover = 1,
+    /// Panic, after the report.
+    Panic = 2,
+}
+
+impl LockupAction {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Log,
+            1 => Self::Recover,
+            _ => Self::Panic,
+        }
+    }
+}
+
+/// Kind of an event of a trace ring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceKind {
+    Heartbeat,
+    VCpuEntry,
+    VCpuExit,
+}
+
+/// An event of the trace ring of a CPU.
+#[derive(Debug, Clone, Copy)]
+pub struct TraceEvent {
+    pub time_ns: u64,
+    pub kind: TraceKind,
+    pub vm_id: usize,
+    pub vcpu_id: usize,
+    /// Exit reason of a [`TraceKind::VCpuExit`].
+    pub exit: &'static str,
+}
+
+/// A trace event packed in a word: kind (8 bits), exit code (8 bits), vCPU ID (16 bits) and VM
+/// ID (32 bits).
+fn pack(kind: TraceKind, vm_id: usize, vcpu_id: usize, exit: u8) -> u64 {
+    (kind as u64) | (exit as u64) << 8 | (vcpu_id as u64 & 0xffff) << 16 | (vm_id as u64) << 32
+}
+
+fn unpack(time_ns: u64, word: u64) -> TraceEvent {
+    TraceEvent {
+        time_ns,
+        kind: match word & 0xff {
+            0 => TraceKind::Heartbeat,
+            1 => TraceKind::VCpuEntry,
+            _ => TraceKind::VCpuExit,
+        },
+        vm_id: (word >> 32) as usize,
+        vcpu_id: (word >> 16 & 0xffff) as usize,
+        exit: hang::exit_name((word >> 8 & 0xff) as u8),
+    }
+}
+
+/// Heartbeat and trace ring of a physical CPU, written by the CPU itself only.
+struct CpuState {
+    heartbeat_ns: AtomicU64,
+    last_check_ns: AtomicU64,
+    /// Set while the CPU is reported as stuck.
+    reported: AtomicBool,
+    head: AtomicUsize,
+    times: [AtomicU64; TRACE_LEN],
+    words: [AtomicU64; TRACE_LEN],
+}
+
+impl CpuState {
+    fn new(now: u64) -> Self {
+        Self {
+            heartbeat_ns: AtomicU64::new(now),
+            last_check_ns: AtomicU64::new(now),
+            reported: AtomicBool::new(false),
+            head: AtomicUsize::new(0),
+            times: [const { AtomicU64::new(0) }; TRACE_LEN],
+            words: [const { AtomicU64::new(0) }; TRACE_LEN],
+        }
+    }
+
+    fn record(&self, now: u64, word: u64) {
+        let slot = self.head.fetch_add(1, Ordering::Relaxed) % TRACE_LEN;
+        self.times[slot].store(now, Ordering::Relaxed);
+        self.words[slot].store(word, Ordering::Relaxed);
+    }
+
+    /// Returns the last `count` events of the trace ring, oldest first.
+    fn events(&self, count: usize) -> impl Iterator<Item = TraceEvent> + '_ {
+        let head = self.head.load(Ordering::Relaxed);
+        (head.saturating_sub(count.min(TRACE_LEN))..head).map(|i| {
+            let slot = i % TRACE_LEN;
+            unpack(
+                self.times[slot].load(Ordering::Relaxed),
+                self.words[slot].load(Ordering::Relaxed),
+            )
+        })
+    }
+
+    /// Returns the events of the trace ring, oldest first.
+    fn trace(&self) -> Vec<TraceEvent> {
+        self.events(TRACE_LEN).collect()
+    }
+
+    /// Returns the VM whose vCPU last entered the guest on this CPU.
+    fn last_vm(&self) -> Option<usize> {
+        self.trace()
+            .iter()
+            .rev()
+            .find(|event| event.kind == TraceKind::VCpuEntry)
+            .map(|event| event.vm_id)
+    }
+}
+
+static CPUS: Once<Vec<CpuState>> = Once::new();
+static ACTION: AtomicU8 = AtomicU8::new(LockupAction::Panic as u8);
+
+/// Starts the lockup detection, with a heartbeat thread pinned to every physical CPU.
+pub fn init() {
+    let cpu_count = axruntime::cpu_count();
+    let now = axhal::time::monotonic_time_nanos();
+    CPUS.call_once(|| {
+        memstat::charge(MemSubsystem::Trace, None, cpu_count * size_of::<CpuState>());
+        (0..cpu_count).map(|_| CpuState::new(now)).collect()
+    });
+    for cpu_id in 0..cpu_count {
+        thread::spawn(move || {
+            if let Err(e) = ax_set_current_affinity(AxCpuMask::one_shot(cpu_id)) {
+                warn!("CPU {} heartbeat thread not pinned: {:?}", cpu_id, e);
+                return;
+            }
+            loop {
+                heartbeat();
+                thread::sleep(HEARTBEAT_PERIOD);
+            }
+        });
+    }
+    info!(
+        "Lockup detection of {} CPUs: timeout {:?}, action {:?}",
+        cpu_count,
+        LOCKUP_TIMEOUT,
+        action()
+    );
+}
+
+/// Returns the action taken when a CPU is stuck.
+pub fn action() -> LockupAction {
+    LockupAction::from_u8(ACTION.load(Ordering::Relaxed))
+}
+
+/// Sets the action taken when a CPU is stuck.
+pub fn set_action(action: LockupAction) {
+    ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+/// Touches the heartbeat of the current CPU and records an event in its trace ring. Checks the
+/// other CPUs if they have not been checked for a [`HEARTBEAT_PERIOD`].
+#[inline]
+pub fn tick(kind: TraceKind, vm_id: usize, vcpu_id: usize, exit: u8) {
+    let Some(cpus) = CPUS.get() else {
+        return;
+    };
+    let _guard = kernel_guard::NoPreempt::new();
+    let Some(cpu) = cpus.get(axhal::percpu::this_cpu_id()) else {
+        return;
+    };
+    let now = axhal::time::monotonic_time_nanos();
+    cpu.heartbeat_ns.store(now, Ordering::Release);
+    cpu.record(now, pack(kind, vm_id, vcpu_id, exit));
+
+    if now - cpu.last_check_ns.load(Ordering::Relaxed) >= HEARTBEAT_PERIOD.as_nanos() as u64 {
+        cpu.last_check_ns.store(now, Ordering::Relaxed);
+        check_cpus(cpus, now);
+    }
+}
+
+/// Touches the heartbeat of the current CPU, for code holding the CPU for long outside of the
+/// vCPU loop.
+#[inline]
+pub fn heartbeat() {
+    tick(TraceKind::Heartbeat, 0, 0, 0);
+}
+
+/// Records a vCPU entry in the trace ring of the current CPU.
+#[inline]
+pub fn trace_entry(vm_id: usize, vcpu_id: usize) {
+    tick(TraceKind::VCpuEntry, vm_id, vcpu_id, 0);
+}
+
+/// Records a vCPU exit in the trace ring of the current CPU.
+#[inline]
+pub fn trace_exit(vm_id: usize, vcpu_id: usize, exit_code: u8) {
+    tick(TraceKind::VCpuExit, vm_id, vcpu_id, exit_code);
+}
+
+fn check_cpus(cpus: &[CpuState], now: u64) {
+    let timeout = LOCKUP_TIMEOUT.as_nanos() as u64;
+    for (cpu_id, cpu) in cpus.iter().enumerate() {
+        let heartbeat = cpu.heartbeat_ns.load(Ordering::Acquire);
+        let silent_ns = now.saturating_sub(heartbeat);
+        if silent_ns < timeout {
+            if cpu.reported.swap(false, Ordering::Relaxed) {
+                warn!("CPU {} is beating again", cpu_id);
+            }
+            continue;
+        }
+        if silent_ns >= 2 * timeout && cpu.reported.load(Ordering::Relaxed) {
+            if action() == LockupAction::Recover {
+                panic!(
+                    "CPU {} still stuck after recovery, silent for {} ms",
+                    cpu_id,
+                    silent_ns / 1_000_000
+                );
+            }
+            continue;
+        }
+        // Only the first CPU to notice reports it.
+        if cpu.reported.swap(true, Ordering::Relaxed) {
+            continue;
+        }
+        report(cpu_id, cpu, silent_ns);
+    }
+}
+
+fn report(cpu_id: usize, cpu: &CpuState, silent_ns: u64) {
+    error!(
+        "CPU {} lockup: no heartbeat for {} ms, reported by CPU {}",
+        cpu_id,
+        silent_ns / 1_000_000,
+        axhal::percpu::this_cpu_id()
+    );
+    for event in cpu.trace() {
+        error!(
+            "  CPU {} [{}.{:09}] {:?} VM[{}] VCpu[{}] {}",
+            cpu_id,
+            event.time_ns / 1_000_000_000,
+            event.time_ns % 1_000_000_000,
+            event.kind,
+            event.vm_id,
+            event.vcpu_id,
+            if event.kind == TraceKind::VCpuExit {
+                event.exit
+            } else {
+                ""
+            }
+        );
+    }
+
+    match action() {
+        LockupAction::Log => {}
+        LockupAction::Recover => {
+            if let Some(vm) = cpu.last_vm().and_then(vm_list::get_vm_by_id) {
+                warn!("CPU {} lockup: stopping VM[{}]", cpu_id, vm.id());
+                if let Err(e) = vm.shutdown() {
+                    error!("VM[{}] shutdown failed: {:?}", vm.id(), e);
+                }
+                vcpus::notify_all_vcpus(vm.id());
+            }
+        }
+        LockupAction::Panic => panic!("CPU {} lockup", cpu_id),
+    }
+}
+
+/// Heartbeat age and recent events of a physical CPU.
+#[derive(Debug, Clone)]
+pub struct CpuLockupStatus {
+    pub cpu_id: usize,
+    pub heartbeat_age_ns: u64,
+    pub stuck: bool,
+    pub trace: Vec<TraceEvent>,
+}
+
+/// Returns the heartbeat ages and trace rings of all physical CPUs.
+pub fn status() -> Vec<CpuLockupStatus> {
+    let now = axhal::time::monotonic_time_nanos();
+    CPUS.get()
+        .map(|cpus| {
+            cpus.iter()
+                .enumerate()
+                .map(|(cpu_id, cpu)| CpuLockupStatus {
+                    cpu_id,
+                    heartbeat_age_ns: now.saturating_sub(cpu.heartbeat_ns.load(Ordering::Acquire)),
+                    stuck: cpu.reported.load(Ordering::Relaxed),
+                    trace: cpu.trace(),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Runs `f` on the last `count` events of the trace ring of CPU `cpu_id`, oldest first, for the
+/// panic report (see [`crate::vmm::panic_report`]).
+///
+/// Doesn't allocate, the panic may come from the allocator.
+pub(crate) fn for_each_event(cpu_id: usize, count: usize, f: impl FnMut(TraceEvent)) {
+    if let Some(cpu) = CPUS.get().and_then(|cpus| cpus.get(cpu_id)) {
+        cpu.events(count).for_each(f);
+    }
+}
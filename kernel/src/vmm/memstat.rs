@@ -0,0 +1,153 @@
+//! Memory usage of the hypervisor, by subsystem and by VM.
+//!
+//! The allocations the hypervisor makes for itself and on behalf of guests are charged to the
+//! [`MemSubsystem`] making them and, when they serve a single VM, to that VM, so that the memory
+//! not given to guests as RAM can be accounted for at runtime (see the `mem` shell command and
+//! `vm show --stats`):
+//!
+//! - [`MemSubsystem::Ivc`]: the pages of IVC channels, charged to their publisher;
+//! - [`MemSubsystem::PageTables`]: the second-stage page tables of all VMs, allocated by `axvm`
+//!   without telling for which VM, so they are only counted in the totals;
+//! - [`MemSubsystem::Devices`]: the memory of emulated devices, e.g. virtio-blk ramdisks;
+//! - [`MemSubsystem::Trace`]: the trace rings of [`crate::vmm::lockup`] and [`crate::vmm::trace`];
+//! - [`MemSubsystem::HvInfo`]: the hypervisor and VM information pages, see
+//!   [`crate::vmm::hvinfo`];
+//! - [`MemSubsystem::Images`]: the pages of boot images shared between VMs, see
+//!   [`crate::vmm::imgshare`]. They serve several VMs, so they are only counted in the totals;
+//! - [`MemSubsystem::Console`]: console buffers, i.e. the line buffers of the serial ports of
+//!   [`crate::vmm::uefi`] and of the virtio-console devices. Other guest consoles are passed
+//!   through.
+//!
+//! Small heap allocations (configs, bookkeeping maps) are not charged. The charges of a VM are
+//! dropped as the memory is freed, which may be after the VM is destroyed (e.g., a virtio-blk
+//! ramdisk is freed when its worker exits); charges left behind by a destroyed VM are leaks.
+//!
+//! The charges of the VMs of a VM group count against the memory limit of the group, see
+//! [`crate::vmm::group`]. The allocations the VMs cause at runtime (IVC channels, consoles,
+//! ramdisks, framebuffers) are charged with [`try_charge`] before the memory is allocated and
+//! fail once the group reached its limit.
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use axerrno::AxResult;
+use spin::Mutex;
+
+use crate::vmm::group;
+
+/// A subsystem memory is charged to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemSubsystem {
+    Ivc,
+    Console,
+    PageTables,
+    Devices,
+    Trace,
+    HvInfo,
+    Images,
+}
+
+impl MemSubsystem {
+    pub const ALL: [Self; SUBSYSTEMS] = [
+        Self::Ivc,
+        Self::Console,
+        Self::PageTables,
+        Self::Devices,
+        Self::Trace,
+        Self::HvInfo,
+        Self::Images,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ivc => "ivc",
+            Self::Console => "console",
+            Self::PageTables => "page tables",
+            Self::Devices => "devices",
+            Self::Trace => "trace",
+            Self::HvInfo => "info pages",
+            Self::Images => "shared images",
+        }
+    }
+}
+
+const SUBSYSTEMS: usize = 7;
+
+/// Bytes charged to each subsystem.
+static TOTALS: [AtomicUsize; SUBSYSTEMS] = [const { AtomicUsize::new(0) }; SUBSYSTEMS];
+/// Bytes charged to each subsystem on behalf of each VM, for the VMs with charges.
+static VMS: Mutex<BTreeMap<usize, [usize; SUBSYSTEMS]>> = Mutex::new(BTreeMap::new());
+
+fn account(subsystem: MemSubsystem, vm_id: Option<usize>, bytes: usize) {
+    TOTALS[subsystem as usize].fetch_add(bytes, Ordering::Relaxed);
+    if let Some(vm_id) = vm_id {
+        VMS.lock().entry(vm_id).or_default()[subsystem as usize] += bytes;
+    }
+}
+
+/// Charges `bytes` allocated by `subsystem`, on behalf of VM `vm_id` if any, even if the group of
+/// the VM goes over its memory limit.
+pub fn charge(subsystem: MemSubsystem, vm_id: Option<usize>, bytes: usize) {
+    if let Some(vm_id) = vm_id {
+        group::charge_memory(vm_id, bytes);
+    }
+    account(subsystem, vm_id, bytes);
+}
+
+/// Charges `bytes` about to be allocated by `subsystem` on behalf of VM `vm_id`, fails if the
+/// group of the VM would go over its memory limit. The charge is dropped with [`uncharge`],
+/// including when the allocation fails.
+pub fn try_charge(subsystem: MemSubsystem, vm_id: usize, bytes: usize) -> AxResult {
+    group::try_charge_memory(vm_id, bytes)?;
+    account(subsystem, Some(vm_id), bytes);
+    Ok(())
+}
+
+/// Drops the charge of `bytes` freed by `subsystem`, made with [`charge`].
+pub fn uncharge(subsystem: MemSubsystem, vm_id: Option<usize>, bytes: usize) {
+    TOTALS[subsystem as usize].fetch_sub(bytes, Ordering::Relaxed);
+    if let Some(vm_id) = vm_id {
+        group::uncharge_memory(vm_id, bytes);
+        let mut vms = VMS.lock();
+        if let Some(usage) = vms.get_mut(&vm_id) {
+            usage[subsystem as usize] = usage[subsystem as usize].saturating_sub(bytes);
+            if usage.iter().all(|bytes| *bytes == 0) {
+                vms.remove(&vm_id);
+            }
+        }
+    }
+}
+
+/// Returns the bytes charged to each subsystem.
+pub fn totals() -> Vec<(MemSubsystem, usize)> {
+    MemSubsystem::ALL
+        .iter()
+        .map(|subsystem| {
+            (
+                *subsystem,
+                TOTALS[*subsystem as usize].load(Ordering::Relaxed),
+            )
+        })
+        .collect()
+}
+
+/// Returns the bytes charged to each subsystem on behalf of VM `vm_id`.
+pub fn vm_usage(vm_id: usize) -> Vec<(MemSubsystem, usize)> {
+    let usage = VMS.lock().get(&vm_id).copied().unwrap_or_default();
+    MemSubsystem::ALL
+        .iter()
+        .map(|subsystem| (*subsystem, usage[*subsystem as usize]))
+        .collect()
+}
+
+/// Returns the bytes charged on behalf of VM `vm_id`, in all the subsystems.
+pub fn vm_total(vm_id: usize) -> usize {
+    VMS.lock()
+        .get(&vm_id)
+        .map_or(0, |usage| usage.iter().sum())
+}
+
+/// Returns the IDs of the VMs with charges, including destroyed VMs whose memory was not freed.
+pub fn charged_vms() -> Vec<usize> {
+    VMS.lock().keys().copied().collect()
+}
@@ -0,0 +1,135 @@
+//! MMIO trap handlers implemented by the hypervisor itself.
+//!
+//! MMIO accesses that are not claimed by any device emulated inside `axvm` are reported back to
+//! the vCPU loop as `MmioRead`/`MmioWrite` exits. Subsystems of the hypervisor that need to
+//! emulate guest physical regions on their own (e.g., the virtual ECAM window of PCI passthrough)
+//! register a [`MmioTrapHandler`] for that region here.
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+use axaddrspace::GuestPhysAddr;
+use axaddrspace::device::AccessWidth;
+use axerrno::{AxResult, ax_err, ax_err_type};
+use spin::Mutex;
+
+use crate::vmm::VMRef;
+
+/// A handler of trapped MMIO accesses to a guest physical region.
+pub trait MmioTrapHandler: Send + Sync {
+    /// Handles a read of `width` at `addr`, returning the value to be loaded by the guest.
+    fn handle_read(&self, vm: &VMRef, addr: GuestPhysAddr, width: AccessWidth) -> AxResult<usize>;
+
+    /// Handles a write of `val` with `width` at `addr`.
+    fn handle_write(
+        &self,
+        vm: &VMRef,
+        addr: GuestPhysAddr,
+        width: AccessWidth,
+        val: usize,
+    ) -> AxResult;
+}
+
+struct MmioTrap {
+    size: usize,
+    handler: Arc<dyn MmioTrapHandler>,
+}
+
+/// Registered MMIO traps, indexed by (vm_id, region base GPA).
+static MMIO_TRAPS: Mutex<BTreeMap<(usize, usize), MmioTrap>> = Mutex::new(BTreeMap::new());
+
+/// Looks up the trap covering `addr` in the given VM.
+pub(super) fn find_trap(vm_id: usize, addr: GuestPhysAddr) -> Option<Arc<dyn MmioTrapHandler>> {
+    let addr = addr.as_usize();
+    let traps = MMIO_TRAPS.lock();
+    traps
+        .range((vm_id, 0)..=(vm_id, addr))
+        .next_back()
+        .filter(|((_, base), trap)| addr < base + trap.size)
+        .map(|(_, trap)| trap.handler.clone())
+}
+
+/// Registers a trap handler for the guest physical region `[base, base + size)` of a VM.
+///
+/// Returns an `AlreadyExists` error if the region overlaps with an already registered one.
+pub fn register_trap(
+    vm_id: usize,
+    base: GuestPhysAddr,
+    size: usize,
+    handler: Arc<dyn MmioTrapHandler>,
+) -> AxResult {
+    let base = base.as_usize();
+    let mut traps = MMIO_TRAPS.lock();
+
+    let overlaps_prev = traps
+        .range((vm_id, 0)..=(vm_id, base))
+        .next_back()
+        .is_some_and(|((_, b), t)| base < b + t.size);
+    let overlaps_next = traps
+        .range((vm_id, base)..(vm_id, base + size))
+        .next()
+        .is_some();
+    if overlaps_prev || overlaps_next {
+        return ax_err!(
+            AlreadyExists,
+            format!(
+                "VM[{}] MMIO trap [{:#x}, {:#x}) overlaps with an existing one",
+                vm_id,
+                base,
+                base + size
+            )
+        );
+    }
+
+    debug!(
+        "VM[{}] register MMIO trap [{:#x}, {:#x})",
+        vm_id,
+        base,
+        base + size
+    );
+    traps.insert((vm_id, base), MmioTrap { size, handler });
+    Ok(())
+}
+
+/// Unregisters the trap whose region starts at `base`.
+pub fn unregister_trap(vm_id: usize, base: GuestPhysAddr) -> AxResult {
+    MMIO_TRAPS
+        .lock()
+        .remove(&(vm_id, base.as_usize()))
+        .map(|_| ())
+        .ok_or_else(|| {
+            ax_err_type!(
+                NotFound,
+                format!("VM[{}] MMIO trap at {:#x} not found", vm_id, base)
+            )
+        })
+}
+
+/// Unregisters all traps of a VM, called when the VM is destroyed.
+pub fn unregister_vm_traps(vm_id: usize) {
+    let mut traps = MMIO_TRAPS.lock();
+    // Only walk the key range of the VM instead of the traps of all VMs.
+    let bases: alloc::vec::Vec<_> = traps
+        .range((vm_id, 0)..=(vm_id, usize::MAX))
+        .map(|(key, _)| *key)
+        .collect();
+    for key in bases {
+        traps.remove(&key);
+    }
+}
+
+/// Dispatches a trapped MMIO read to the registered handler.
+pub fn handle_mmio_read(vm: &VMRef, addr: GuestPhysAddr, width: AccessWidth) -> AxResult<usize> {
+    let handler = find_trap(vm.id(), addr).ok_or_else(|| ax_err_type!(NotFound))?;
+    handler.handle_read(vm, addr, width)
+}
+
+/// Dispatches a trapped MMIO write to the registered handler.
+pub fn handle_mmio_write(
+    vm: &VMRef,
+    addr: GuestPhysAddr,
+    width: AccessWidth,
+    val: usize,
+) -> AxResult {
+    let handler = find_trap(vm.id(), addr).ok_or_else(|| ax_err_type!(NotFound))?;
+    handler.handle_write(vm, addr, width, val)
+}
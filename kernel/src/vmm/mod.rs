@@ -1,14 +1,101 @@
 mod hvc;
 mod ivc;
 
+pub mod abi;
+pub mod affinity;
+pub mod bench;
+pub mod blocks;
+pub mod bridge;
 pub mod config;
+pub mod conmux;
+pub mod coredump;
+pub mod cpufreq;
+pub mod crash;
+pub mod deferred;
+pub mod direct_irq;
+pub mod dirty;
+pub mod doorbell;
+pub mod exit_budget;
+pub mod framebuffer;
+pub mod group;
+pub mod guest_time;
+pub mod hang;
+pub mod heatmap;
+pub mod hvinfo;
+pub mod identity;
+pub mod idle;
 pub mod images;
+pub mod imgshare;
+pub mod introspect;
+pub mod iommu;
+pub mod ioreq;
+pub mod irq;
+pub mod irqpolicy;
+pub mod lazymem;
+pub mod lifecycle;
+pub mod lockup;
+pub mod memstat;
+pub mod mmio;
+pub mod msi;
+pub mod nested;
+pub mod panic_report;
+pub mod pci;
+pub mod peers;
+pub mod percpu;
+pub mod pmu;
+pub mod posted;
+pub mod power;
+pub mod quiesce;
+pub mod reboot;
+pub mod reclaim;
+pub mod regs;
+pub mod sched;
+pub mod seal;
+pub mod security;
+pub mod services;
+pub mod shutdown;
+pub mod sleep;
+pub mod stage2;
+pub mod stats;
+pub mod template;
 pub mod timer;
+pub mod trace;
+pub mod tracectx;
+pub mod traps;
+pub mod uartshare;
+pub mod uefi;
+pub mod unknown_hvc;
 pub mod vcpus;
+pub mod virtio;
 pub mod vm_list;
+pub mod vmdef;
+pub mod vmi;
+pub mod watchdog;
 
+#[cfg(target_arch = "x86_64")]
+pub mod acpi;
+#[cfg(target_arch = "aarch64")]
+pub mod dtb_builder;
 #[cfg(target_arch = "aarch64")]
 pub mod fdt;
+#[cfg(target_arch = "aarch64")]
+pub mod psci;
+#[cfg(target_arch = "x86_64")]
+pub mod pvcompat;
+#[cfg(target_arch = "aarch64")]
+pub mod smc;
+#[cfg(target_arch = "aarch64")]
+pub mod spe;
+#[cfg(target_arch = "riscv64")]
+pub mod vintc;
+#[cfg(target_arch = "aarch64")]
+pub mod vits;
+#[cfg(target_arch = "aarch64")]
+pub mod vsgi;
+#[cfg(target_arch = "aarch64")]
+pub mod vtimer;
+#[cfg(target_arch = "x86_64")]
+pub mod x2apic;
 
 use core::sync::atomic::{AtomicUsize, Ordering};
 use std::os::arceos::{
@@ -22,6 +109,7 @@ use crate::{
     hal::{AxVCpuHalImpl, AxVMHalImpl},
     task::AsVCpuTask,
 };
+pub use hvc::{HyperCallNesting, reentries as hypercall_reentries};
 pub use timer::init_percpu as init_timer_percpu;
 
 /// The instantiated VM type.
@@ -41,6 +129,13 @@ static RUNNING_VM_COUNT: AtomicUsize = AtomicUsize::new(0);
 /// This function creates the VM structures and sets up the primary VCpu for each VM.
 pub fn init() {
     info!("Initializing VMM...");
+    percpu::init();
+    panic_report::init();
+    lockup::init();
+    #[cfg(target_arch = "aarch64")]
+    vsgi::init();
+    register_hypercall_services();
+
     // Initialize guest VM according to config file.
     config::init_guest_vms();
 
@@ -51,6 +146,33 @@ pub fn init() {
     }
 }
 
+/// Registers the hypercalls of the subsystems with the dispatcher, see [`hvc::HvcService`].
+fn register_hypercall_services() {
+    for service in [
+        &stats::HVC_SERVICE,
+        &trace::HVC_SERVICE,
+        &vmdef::HVC_SERVICE,
+        &unknown_hvc::HVC_SERVICE,
+        &conmux::HVC_SERVICE,
+        &deferred::HVC_SERVICE,
+        &introspect::HVC_SERVICE,
+        &heatmap::HVC_SERVICE,
+        &vmi::HVC_SERVICE,
+        &ioreq::HVC_SERVICE,
+        &virtio::VHOST_HVC_SERVICE,
+        &framebuffer::FLIP_HVC_SERVICE,
+        &framebuffer::HVC_SERVICE,
+        &uartshare::HVC_SERVICE,
+        &cpufreq::HVC_SERVICE,
+        &group::HVC_SERVICE,
+        &coredump::HVC_SERVICE,
+    ] {
+        if let Err(e) = hvc::register_service(service) {
+            error!("Hypercall service {} not registered: {:?}", service.name, e);
+        }
+    }
+}
+
 /// Start the VMM.
 pub fn start() {
     info!("VMM starting, booting VMs...");
@@ -132,6 +254,77 @@ pub fn with_vm_and_vcpu_on_pcpu(
     // }))
 }
 
+/// Releases the PCI devices, the hypervisor-emulated devices and the other per-VM state of a VM,
+/// called when the VM is destroyed, after its vCPUs have been cleaned up.
+pub fn teardown_vm_resources(vm: &VMRef) {
+    let vm_id = vm.id();
+    pci::teardown_vm_passthrough(vm_id);
+    power::remove_vm_power_device(vm_id);
+    doorbell::teardown_vm_doorbells(vm_id);
+    irqpolicy::teardown_vm_irq_policy(vm_id);
+    cpufreq::teardown_vm_cpufreq(vm_id);
+    virtio::teardown_vm_virtio_devices(vm_id);
+    framebuffer::teardown_vm_framebuffer(vm_id);
+    uartshare::teardown_vm_shared_uart(vm_id);
+    idle::teardown_vm_idle(vm_id);
+    sleep::teardown_vm_sleep(vm_id);
+    posted::teardown_vm_posted(vm_id);
+    blocks::teardown_vm_blocks(vm_id);
+    coredump::teardown_vm_checkpoints(vm_id);
+    seal::teardown_vm_seal(vm_id);
+    dirty::teardown_vm_dirty(vm_id);
+    heatmap::teardown_vm_heatmap(vm_id);
+    vmi::teardown_vm_vmi(vm_id);
+    ioreq::teardown_vm_ioreq(vm_id);
+    lazymem::teardown_vm_lazy_memory(vm_id);
+    template::teardown_vm_template(vm_id);
+    identity::teardown_vm_identity(vm_id);
+    stats::teardown_vm_stats(vm_id);
+    peers::teardown_vm_peers(vm_id);
+    bridge::teardown_vm_bridge(vm_id);
+    #[cfg(target_arch = "aarch64")]
+    vtimer::teardown_vm_timers(vm_id);
+    #[cfg(target_arch = "aarch64")]
+    vits::teardown_vm_its(vm_id);
+    #[cfg(target_arch = "aarch64")]
+    smc::teardown_vm_smc(vm_id);
+    #[cfg(target_arch = "riscv64")]
+    vintc::teardown_vm_intc(vm_id);
+    #[cfg(target_arch = "x86_64")]
+    x2apic::teardown_vm_x2apic(vm_id);
+    #[cfg(target_arch = "x86_64")]
+    pvcompat::teardown_vm_pv_compat(vm_id);
+    watchdog::teardown_vm_watchdog(vm_id);
+    crash::teardown_vm_crash(vm_id);
+    reboot::teardown_vm_reboot(vm_id);
+    reclaim::teardown_vm_reclaim(vm_id);
+    hang::teardown_vm_hang_detect(vm_id);
+    exit_budget::teardown_vm_exit_budget(vm_id);
+    guest_time::teardown_vm_guest_time(vm_id);
+    vmdef::teardown_vm_manager(vm_id);
+    affinity::teardown_vm_affinity(vm_id);
+    group::teardown_vm_group(vm_id);
+    sched::teardown_vm_scheduling(vm_id);
+    direct_irq::teardown_vm_direct_irqs(vm_id);
+    traps::teardown_vm_traps(vm_id);
+    pmu::teardown_vm_pmu(vm_id);
+    #[cfg(target_arch = "aarch64")]
+    spe::teardown_vm_spe(vm_id);
+    tracectx::teardown_vm_trace_ctx(vm_id);
+    lifecycle::teardown_vm_lifecycle(vm_id);
+    unknown_hvc::teardown_vm_unknown_hvc(vm_id);
+    conmux::teardown_vm_conmux(vm_id);
+    deferred::teardown_vm_deferred(vm_id);
+    shutdown::teardown_vm_shutdown(vm_id);
+    quiesce::teardown_vm_quiesce(vm_id);
+    services::teardown_vm_services(vm_id);
+    hvinfo::teardown_vm_hv_info(vm_id);
+    uefi::teardown_vm_uefi_boot(vm_id);
+    mmio::unregister_vm_traps(vm_id);
+    security::teardown_vm_security(vm);
+    lifecycle::emit(vm_id, lifecycle::LifecycleEvent::Destroyed, 0);
+}
+
 pub fn add_running_vm_count(count: usize) {
     RUNNING_VM_COUNT.fetch_add(count, Ordering::Release);
 }
@@ -0,0 +1,113 @@
+//! Delivery of message-signaled interrupts to guests.
+//!
+//! The MSI/MSI-X messages a guest programs into its passthrough PCI devices are shadowed by
+//! [`crate::vmm::pci`], which takes the physical interrupts and hands the guest message to
+//! [`deliver`]. The message is decoded the way the guest's interrupt controller would:
+//!
+//! - aarch64: a write to the `GITS_TRANSLATER` of the VM's ITS (see [`crate::vmm::vits`]) is
+//!   translated to an LPI. Any other doorbell is a GICv2m-style frame, the data being the SPI.
+//! - x86_64: with emulated local APICs (see [`crate::vmm::x2apic`]), the address selects the
+//!   destination APIC and the data the vector and delivery mode. Without, the destination is still
+//!   decoded from the address (see [`target_vcpus`]) and the vector injected to those vCPUs.
+//! - riscv64: the data is the interrupt injected to vCPU 0; with AIA, MSIs are written to IMSIC
+//!   guest files by the hardware instead (see [`crate::vmm::vintc`]).
+use alloc::vec::Vec;
+
+use cpumask::CpuMask;
+
+use crate::vmm::sleep::{self, WakeReason};
+use crate::vmm::{stats, vm_list};
+
+/// An MSI message, as programmed by the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestMsi {
+    pub addr: u64,
+    pub data: u32,
+}
+
+/// Extracts the interrupt vector from the data of an MSI message, for guests without a message
+/// decoder.
+fn data_to_vector(data: u32) -> usize {
+    if cfg!(target_arch = "x86_64") {
+        // The vector is in the low byte, the rest is the delivery mode and trigger mode.
+        (data & 0xff) as usize
+    } else {
+        // Data is the interrupt ID with GICv2m-style MSI frames.
+        data as usize
+    }
+}
+
+/// Shift of the destination ID in the address of an x86 MSI message.
+#[cfg(target_arch = "x86_64")]
+const MSI_ADDR_DEST_SHIFT: u64 = 12;
+/// Destination mode bit of the address of an x86 MSI message, set for logical destinations.
+#[cfg(target_arch = "x86_64")]
+const MSI_ADDR_DEST_LOGICAL: u64 = 1 << 2;
+/// Lowest priority delivery mode in the data of an x86 MSI message.
+#[cfg(target_arch = "x86_64")]
+const MSI_DATA_DELIVERY_LOWEST: u32 = 1;
+
+/// Returns the vCPUs out of `vcpu_num` targeted by `msi`, for guests without a message decoder.
+///
+/// On x86_64, the destination is in bits 19:12 of the address: an APIC ID, which is the vCPU ID
+/// like with the emulated local APICs, `0xff` to broadcast, or with a logical destination a mask
+/// of vCPUs in the flat model. A lowest priority message goes to the first of them. The messages
+/// of GICv2m-style frames and of riscv64 carry no destination, the interrupt goes to vCPU 0.
+fn target_vcpus(vcpu_num: usize, msi: GuestMsi) -> Vec<usize> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        let dest = ((msi.addr >> MSI_ADDR_DEST_SHIFT) & 0xff) as usize;
+        let mut targets: Vec<usize> = if msi.addr & MSI_ADDR_DEST_LOGICAL != 0 {
+            (0..vcpu_num.min(8))
+                .filter(|vcpu_id| dest & (1 << vcpu_id) != 0)
+                .collect()
+        } else if dest == 0xff {
+            (0..vcpu_num).collect()
+        } else {
+            (dest < vcpu_num).then_some(dest).into_iter().collect()
+        };
+        if (msi.data >> 8) & 0b111 == MSI_DATA_DELIVERY_LOWEST {
+            targets.truncate(1);
+        }
+        targets
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        let _ = msi;
+        (vcpu_num > 0).then_some(0).into_iter().collect()
+    }
+}
+
+/// Delivers `msi`, sent by the device with guest requester ID `device_id`, to VM `vm_id`.
+pub fn deliver(vm_id: usize, device_id: u32, msi: GuestMsi) {
+    sleep::wake(vm_id, WakeReason::Device);
+    #[cfg(target_arch = "aarch64")]
+    if super::vits::deliver_msi(vm_id, device_id, msi.addr, msi.data) {
+        return;
+    }
+    #[cfg(target_arch = "x86_64")]
+    if super::x2apic::deliver_msi(vm_id, msi.addr, msi.data) {
+        return;
+    }
+
+    let vector = data_to_vector(msi.data);
+    let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+        return;
+    };
+    let targets = target_vcpus(vm.vcpu_num(), msi);
+    if targets.is_empty() {
+        warn!(
+            "VM[{}] MSI {:x?} of device {:#x} targets no vCPU, dropped",
+            vm_id, msi, device_id
+        );
+    }
+    for vcpu_id in targets {
+        match vm.inject_interrupt_to_vcpu(CpuMask::one_shot(vcpu_id), vector) {
+            Ok(()) => stats::count_irq(vm_id, vcpu_id),
+            Err(e) => warn!(
+                "VM[{}] failed to inject MSI vector {} of device {:#x} to VCpu[{}]: {:?}",
+                vm_id, vector, device_id, vcpu_id, e
+            ),
+        }
+    }
+}
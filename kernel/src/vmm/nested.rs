@@ -0,0 +1,96 @@
+//! Nested virtualization.
+//!
+//! A VM whose config has a `[nested]` section is to be given the virtualization extensions, so that
+//! a hypervisor (e.g., KVM) can run in it, typically to develop and test guest stacks in CI:
+//!
+//! ```toml
+//! [nested]
+//! # Shadow second-stage tables kept for the guests of the guest hypervisor, 4 by default.
+//! shadow_tables = 4
+//! ```
+//!
+//! The guest hypervisor runs at virtual EL2 on aarch64 and in VMX non-root operation with
+//! virtual VMX on x86_64. Its second-stage tables (stage-2, EPT) translate the addresses of its
+//! own guests to the addresses of the VM, so the tables the hardware walks are shadows combining
+//! them with the second stage of the VM, up to `shadow_tables` of them being kept at a time.
+//! riscv64 has no hardware support for nested virtualization and is not supported.
+//!
+//! The accesses of the guest hypervisor to its EL2 registers or VMCS are emulated, which needs
+//! FEAT_NV on aarch64 (and is much cheaper with FEAT_NV2, which turns most of them into memory
+//! accesses) and is cheaper with VMCS shadowing on x86_64. They must trap, so nested
+//! virtualization conflicts with minimal system register trapping (see [`crate::vmm::traps`]).
+//! The guest learns that it may use the extensions from its information page (see
+//! [`crate::vmm::hvinfo`]).
+//!
+//! axvisor validates the nested configuration of the VMs. The virtual EL2 and VMX state, the shadow
+//! tables and the reflection of the exits of the nested guests to the guest hypervisor are up to
+//! the vCPU backends, which don't provide them yet: until they do, a VM with a `[nested]` section
+//! fails to be created rather than booting without the extensions, and its information page never
+//! sets [`VM_INFO_F_NESTED`](crate::vmm::hvinfo::VM_INFO_F_NESTED).
+use axerrno::{AxResult, ax_err};
+
+use crate::vmm::VMRef;
+use crate::vmm::traps::{self, SysregTraps};
+
+const MAX_SHADOW_TABLES: usize = 64;
+
+/// Whether the CPU can run a guest hypervisor, and whether it accelerates it.
+fn hw_support() -> AxResult<bool> {
+    #[cfg(target_arch = "aarch64")]
+    {
+        match crate::hal::arch::nested_virt_level() {
+            0 => ax_err!(Unsupported, "nested config: the CPU has no FEAT_NV"),
+            level => Ok(level >= 2),
+        }
+    }
+    #[cfg(target_arch = "x86_64")]
+    {
+        Ok(crate::hal::arch::has_vmcs_shadowing())
+    }
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "x86_64")))]
+    {
+        ax_err!(
+            Unsupported,
+            "nested config: nested virtualization is not supported on this architecture"
+        )
+    }
+}
+
+/// Validates the `[nested]` section of `raw_cfg` for the VM, and fails: the vCPU backends don't
+/// provide the virtualization extensions yet.
+///
+/// Does nothing if the VM config has no `[nested]` section. Must run after
+/// [`traps::setup_vm_traps`].
+pub fn setup_vm_nested(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("nested").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    if let Some(v) = cfg.get("shadow_tables")
+        && !v
+            .as_integer()
+            .is_some_and(|n| (1..=MAX_SHADOW_TABLES as i64).contains(&n))
+    {
+        return ax_err!(
+            InvalidInput,
+            format!(
+                "nested config: `shadow_tables` must be between 1 and {}",
+                MAX_SHADOW_TABLES
+            )
+        );
+    }
+    if traps::trap_config(vm.id()).sysreg == SysregTraps::Minimal {
+        return ax_err!(
+            InvalidInput,
+            "nested config: nested virtualization conflicts with minimal system register trapping"
+        );
+    }
+    hw_support()?;
+    ax_err!(
+        Unsupported,
+        format!(
+            "VM[{}] nested config: the vCPU backends don't provide the virtualization extensions \
+             yet",
+            vm.id()
+        )
+    )
+}
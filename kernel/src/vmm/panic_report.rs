@@ -0,0 +1,404 @@
+//! The final report of a hypervisor panic.
+//!
+//! When the hypervisor panics, after the panic message is logged, the report is written to the
+//! console: a colored screen for whoever is watching, with the panic, the VM and vCPU each
+//! physical CPU runs or ran last (see [`crate::vmm::percpu`]) and the last
+//! [`PANIC_TRACE_EVENTS`] events of the trace ring of each CPU (see [`crate::vmm::lockup`]),
+//! followed by the same state as a blob to copy from the serial log:
+//!
+//! ```text
+//! -----BEGIN AXVISOR PANIC REPORT-----
+//! <base64 of the CBOR report, 76 characters per line>
+//! -----END AXVISOR PANIC REPORT-----
+//! ```
+//!
+//! The blob is at most [`MAX_REPORT_SIZE`] bytes before encoding, so that it fits a single QR
+//! code as well, and `cargo xtask panic-decode <log>` decodes it on the host. The report is a CBOR
+//! map with integer keys:
+//!
+//! - 0: the version of the report, [`PANIC_REPORT_VERSION`];
+//! - 1: the panic message, at most [`MAX_MESSAGE_SIZE`] bytes;
+//! - 2: `[file, line]` of the panic, or null;
+//! - 3: the uptime in nanoseconds;
+//! - 4: the CPU that panicked;
+//! - 5: the CPUs, each `[cpu, vm, vcpu, in guest, last exit, scratch 0, scratch 1, entries,
+//!   exits, inconsistencies]`, the VM and vCPU null if the CPU has run none;
+//! - 6: the trace rings, each `[cpu, [[age in µs, kind, vm, vcpu, exit], ...]]`, the kind being
+//!   0 for a heartbeat, 1 for a vCPU entry and 2 for an exit;
+//! - 7: true if the state didn't fit and was truncated, absent otherwise.
+//!
+//! The arrays of keys 5 and 6 have an indefinite length, the last CPUs and events being dropped
+//! when the report is full. The report doesn't allocate, the panic may come from the allocator.
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use std::os::arceos::modules::axhal;
+
+use crate::vmm::lockup::{self, TraceKind};
+use crate::vmm::percpu;
+
+/// Version of the layout of the report.
+pub const PANIC_REPORT_VERSION: u64 = 1;
+/// Size of the CBOR report, at most.
+pub const MAX_REPORT_SIZE: usize = 2048;
+/// Bytes of the panic message in the report, at most.
+pub const MAX_MESSAGE_SIZE: usize = 128;
+/// Events of the trace ring of each CPU in the report.
+pub const PANIC_TRACE_EVENTS: usize = 8;
+
+/// Bytes kept free for the end of the report while the CPUs and trace rings are added.
+const RESERVED_SIZE: usize = 8;
+/// Base64 characters per line of the blob.
+const BLOB_LINE: usize = 76;
+
+const RESET: &str = "\x1b[0m";
+const BANNER: &str = "\x1b[1;97;41m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const GREEN: &str = "\x1b[32m";
+const DIM: &str = "\x1b[2m";
+
+/// Registers the report with the panic handler.
+pub fn init() {
+    axruntime::set_panic_hook(report);
+}
+
+/// Writes to the console directly.
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        axhal::console::write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// A string in a fixed buffer, truncated to the buffer.
+struct FixedStr<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> FixedStr<N> {
+    const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // SAFETY: only whole characters are written.
+        unsafe { core::str::from_utf8_unchecked(&self.buf[..self.len]) }
+    }
+}
+
+impl<const N: usize> Write for FixedStr<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let len = c.len_utf8();
+            if self.len + len > N {
+                break;
+            }
+            c.encode_utf8(&mut self.buf[self.len..]);
+            self.len += len;
+        }
+        Ok(())
+    }
+}
+
+/// A CBOR encoder into a fixed buffer.
+///
+/// The values are dropped once the report is full, except for the bytes ending the arrays and
+/// the map, which have [`RESERVED_SIZE`] bytes kept for them.
+struct Cbor {
+    buf: [u8; MAX_REPORT_SIZE],
+    len: usize,
+    overflow: bool,
+    /// Set when an element didn't fit, no more are added.
+    full: bool,
+}
+
+impl Cbor {
+    const fn new() -> Self {
+        Self {
+            buf: [0; MAX_REPORT_SIZE],
+            len: 0,
+            overflow: false,
+            full: false,
+        }
+    }
+
+    fn put_within(&mut self, bytes: &[u8], limit: usize) {
+        if self.overflow || self.len + bytes.len() > limit {
+            self.overflow = true;
+            return;
+        }
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn put(&mut self, bytes: &[u8]) {
+        self.put_within(bytes, MAX_REPORT_SIZE - RESERVED_SIZE);
+    }
+
+    /// Writes bytes of the structure of the report, in the reserved bytes if needed.
+    fn put_reserved(&mut self, bytes: &[u8]) {
+        self.put_within(bytes, MAX_REPORT_SIZE);
+    }
+
+    fn head(&mut self, major: u8, value: u64) {
+        let major = major << 5;
+        match value {
+            0..24 => self.put(&[major | value as u8]),
+            24..0x100 => self.put(&[major | 24, value as u8]),
+            0x100..0x1_0000 => {
+                self.put(&[major | 25]);
+                self.put(&(value as u16).to_be_bytes());
+            }
+            0x1_0000..0x1_0000_0000 => {
+                self.put(&[major | 26]);
+                self.put(&(value as u32).to_be_bytes());
+            }
+            _ => {
+                self.put(&[major | 27]);
+                self.put(&value.to_be_bytes());
+            }
+        }
+    }
+
+    fn uint(&mut self, value: u64) {
+        self.head(0, value);
+    }
+
+    fn text(&mut self, text: &str) {
+        self.head(3, text.len() as u64);
+        self.put(text.as_bytes());
+    }
+
+    fn array(&mut self, len: u64) {
+        self.head(4, len);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.put(&[if value { 0xf5 } else { 0xf4 }]);
+    }
+
+    fn null(&mut self) {
+        self.put(&[0xf6]);
+    }
+
+    fn opt_uint(&mut self, value: Option<usize>) {
+        match value {
+            Some(value) => self.uint(value as u64),
+            None => self.null(),
+        }
+    }
+
+    /// Starts an array of indefinite length, or a map with `map`.
+    fn indefinite(&mut self, map: bool) {
+        self.put_reserved(&[if map { 0xbf } else { 0x9f }]);
+    }
+
+    /// Ends an array or a map of indefinite length.
+    fn end(&mut self) {
+        self.put_reserved(&[0xff]);
+    }
+
+    /// Runs `f` to add an element, which is dropped if it doesn't fit. Returns whether it fit.
+    fn element(&mut self, f: impl FnOnce(&mut Self)) -> bool {
+        if self.full {
+            return false;
+        }
+        let mark = self.len;
+        f(self);
+        if self.overflow {
+            self.len = mark;
+            self.overflow = false;
+            self.full = true;
+        }
+        !self.full
+    }
+}
+
+/// Name of a trace event for the report.
+fn event_exit(kind: TraceKind, exit: &'static str) -> &'static str {
+    if kind == TraceKind::VCpuExit { exit } else { "" }
+}
+
+fn report(info: &PanicInfo) {
+    let now = axhal::time::monotonic_time_nanos();
+    let this_cpu = axhal::percpu::this_cpu_id();
+    let mut message = FixedStr::<MAX_MESSAGE_SIZE>::new();
+    let _ = write!(message, "{}", info.message());
+
+    let _ = print_screen(info, message.as_str(), this_cpu, now);
+    let mut cbor = Cbor::new();
+    encode(&mut cbor, info, message.as_str(), this_cpu, now);
+    let _ = print_blob(&cbor.buf[..cbor.len]);
+}
+
+fn print_screen(info: &PanicInfo, message: &str, this_cpu: usize, now: u64) -> fmt::Result {
+    let mut out = Console;
+    writeln!(out)?;
+    writeln!(out, "{BANNER}  AXVISOR PANIC  {RESET}")?;
+    write!(out, "{RED}panicked")?;
+    if let Some(location) = info.location() {
+        write!(out, " at {}:{}", location.file(), location.line())?;
+    }
+    writeln!(out, ": {message}{RESET}")?;
+    writeln!(
+        out,
+        "on CPU {}, uptime {}.{:09} s",
+        this_cpu,
+        now / 1_000_000_000,
+        now % 1_000_000_000
+    )?;
+
+    writeln!(out, "{YELLOW}CPUs:{RESET}")?;
+    let mut result = Ok(());
+    percpu::for_each_block(|block| {
+        result = result.and_then(|_| match block.current {
+            Some((vm_id, vcpu_id)) => writeln!(
+                out,
+                "  CPU {:<3} {}{} VM[{}] VCpu[{}]{}, last exit {} [{:#x}, {:#x}], {} entries, {} exits, {} inconsistencies",
+                block.cpu_id,
+                if block.in_guest { GREEN } else { DIM },
+                if block.in_guest { "in" } else { "out of" },
+                vm_id,
+                vcpu_id,
+                RESET,
+                block.last_exit,
+                block.scratch[0],
+                block.scratch[1],
+                block.entries,
+                block.exits,
+                block.inconsistencies
+            ),
+            None => writeln!(out, "  CPU {:<3} {DIM}no vCPU run{RESET}", block.cpu_id),
+        });
+    });
+    result?;
+
+    writeln!(out, "{YELLOW}Last events:{RESET}")?;
+    for cpu_id in 0..axruntime::cpu_count() {
+        lockup::for_each_event(cpu_id, PANIC_TRACE_EVENTS, |event| {
+            result = result.and_then(|_| {
+                let age_ns = now.saturating_sub(event.time_ns);
+                writeln!(
+                    out,
+                    "  CPU {:<3} -{}.{:06} ms {:?} VM[{}] VCpu[{}] {}",
+                    cpu_id,
+                    age_ns / 1_000_000,
+                    age_ns % 1_000_000,
+                    event.kind,
+                    event.vm_id,
+                    event.vcpu_id,
+                    event_exit(event.kind, event.exit)
+                )
+            });
+        });
+    }
+    result
+}
+
+fn encode(cbor: &mut Cbor, info: &PanicInfo, message: &str, this_cpu: usize, now: u64) {
+    cbor.indefinite(true);
+    cbor.uint(0);
+    cbor.uint(PANIC_REPORT_VERSION);
+    cbor.uint(1);
+    cbor.text(message);
+    cbor.uint(2);
+    match info.location() {
+        Some(location) => {
+            cbor.array(2);
+            cbor.text(location.file());
+            cbor.uint(location.line() as u64);
+        }
+        None => cbor.null(),
+    }
+    cbor.uint(3);
+    cbor.uint(now);
+    cbor.uint(4);
+    cbor.uint(this_cpu as u64);
+
+    cbor.uint(5);
+    cbor.indefinite(false);
+    percpu::for_each_block(|block| {
+        cbor.element(|cbor| {
+            cbor.array(10);
+            cbor.uint(block.cpu_id as u64);
+            cbor.opt_uint(block.current.map(|(vm_id, _)| vm_id));
+            cbor.opt_uint(block.current.map(|(_, vcpu_id)| vcpu_id));
+            cbor.bool(block.in_guest);
+            cbor.text(block.last_exit);
+            cbor.uint(block.scratch[0]);
+            cbor.uint(block.scratch[1]);
+            cbor.uint(block.entries);
+            cbor.uint(block.exits);
+            cbor.uint(block.inconsistencies);
+        });
+    });
+    cbor.end();
+
+    cbor.put_reserved(&[6]);
+    cbor.indefinite(false);
+    for cpu_id in 0..axruntime::cpu_count() {
+        let started = cbor.element(|cbor| {
+            cbor.array(2);
+            cbor.uint(cpu_id as u64);
+            cbor.put(&[0x9f]);
+        });
+        if !started {
+            break;
+        }
+        lockup::for_each_event(cpu_id, PANIC_TRACE_EVENTS, |event| {
+            cbor.element(|cbor| {
+                cbor.array(5);
+                cbor.uint(now.saturating_sub(event.time_ns) / 1000);
+                cbor.uint(event.kind as u64);
+                cbor.uint(event.vm_id as u64);
+                cbor.uint(event.vcpu_id as u64);
+                cbor.text(event_exit(event.kind, event.exit));
+            });
+        });
+        cbor.end();
+    }
+    cbor.end();
+
+    if cbor.full {
+        cbor.put_reserved(&[7, 0xf5]);
+    }
+    cbor.end();
+}
+
+fn print_blob(report: &[u8]) -> fmt::Result {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = Console;
+    writeln!(out, "-----BEGIN AXVISOR PANIC REPORT-----")?;
+    let mut line = [0u8; BLOB_LINE];
+    let mut len = 0;
+    for chunk in report.chunks(3) {
+        let word = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |word, (i, byte)| word | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            line[len + i] = if i <= chunk.len() {
+                ALPHABET[(word >> (18 - 6 * i) & 0x3f) as usize]
+            } else {
+                b'='
+            };
+        }
+        len += 4;
+        if len == BLOB_LINE {
+            axhal::console::write_bytes(&line);
+            writeln!(out)?;
+            len = 0;
+        }
+    }
+    if len > 0 {
+        axhal::console::write_bytes(&line[..len]);
+        writeln!(out)?;
+    }
+    writeln!(out, "-----END AXVISOR PANIC REPORT-----")
+}
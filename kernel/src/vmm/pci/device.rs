@@ -0,0 +1,681 @@
+//! Config space mediation of a passthrough PCI function.
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::device::AccessWidth;
+use axaddrspace::{GuestPhysAddr, HostPhysAddr, MappingFlags};
+use axerrno::{AxResult, ax_err};
+use memory_addr::{PAGE_SIZE_4K, VirtAddr};
+use spin::Mutex;
+
+use super::msix::{MsixTable, MsixTrap};
+use super::{Bdf, set_irq_route};
+use crate::vmm::msi::GuestMsi;
+use crate::vmm::{VMRef, iommu, mmio, stage2};
+
+const REG_COMMAND: usize = 0x04;
+const REG_HEADER: usize = 0x0c;
+const REG_BAR0: usize = 0x10;
+const REG_BAR5: usize = 0x24;
+const REG_ROM: usize = 0x30;
+const REG_CAP_PTR: usize = 0x34;
+const REG_INTERRUPT: usize = 0x3c;
+const EXT_CONFIG_START: usize = 0x100;
+
+const CMD_IO: u16 = 1 << 0;
+const CMD_MEM: u16 = 1 << 1;
+const CMD_BUS_MASTER: u16 = 1 << 2;
+const CMD_PARITY: u16 = 1 << 6;
+const CMD_SERR: u16 = 1 << 8;
+const CMD_INTX_DISABLE: u16 = 1 << 10;
+/// Command bits the guest is allowed to change on the physical device.
+const CMD_GUEST_MASK: u16 =
+    CMD_IO | CMD_MEM | CMD_BUS_MASTER | CMD_PARITY | CMD_SERR | CMD_INTX_DISABLE;
+/// Status register bit indicating the presence of a capability list.
+const STATUS_CAP_LIST: u32 = 1 << 20;
+
+const CAP_ID_MSI: u8 = 0x05;
+const CAP_ID_MSIX: u8 = 0x11;
+
+const MSI_CTRL_ENABLE: u32 = 1 << 16;
+const MSI_CTRL_MMC_MME: u32 = 0x7e << 16;
+const MSI_CTRL_64BIT: u32 = 1 << 23;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_MEM_64: u32 = 0b10 << 1;
+const BAR_MEM_TYPE_MASK: u32 = 0b11 << 1;
+
+/// MMIO accessor of the physical config space of a PCI function.
+pub(super) struct HostConfigSpace {
+    base: VirtAddr,
+}
+
+impl HostConfigSpace {
+    fn new(base: HostPhysAddr) -> Self {
+        Self {
+            base: axhal::mem::phys_to_virt(base),
+        }
+    }
+
+    pub(super) fn read(&self, reg: usize, width: AccessWidth) -> u32 {
+        let ptr = (self.base + reg).as_ptr();
+        // SAFETY: `reg` is within the 4K config space of the function, which is mapped as MMIO.
+        unsafe {
+            match width {
+                AccessWidth::Byte => ptr.read_volatile() as u32,
+                AccessWidth::Word => (ptr as *const u16).read_volatile() as u32,
+                _ => (ptr as *const u32).read_volatile(),
+            }
+        }
+    }
+
+    pub(super) fn write(&self, reg: usize, width: AccessWidth, val: u32) {
+        let ptr = (self.base + reg).as_mut_ptr();
+        // SAFETY: `reg` is within the 4K config space of the function, which is mapped as MMIO.
+        unsafe {
+            match width {
+                AccessWidth::Byte => ptr.write_volatile(val as u8),
+                AccessWidth::Word => (ptr as *mut u16).write_volatile(val as u16),
+                _ => (ptr as *mut u32).write_volatile(val),
+            }
+        }
+    }
+
+    pub(super) fn read32(&self, reg: usize) -> u32 {
+        self.read(reg, AccessWidth::Dword)
+    }
+
+    pub(super) fn write32(&self, reg: usize, val: u32) {
+        self.write(reg, AccessWidth::Dword, val)
+    }
+}
+
+/// A memory or I/O BAR of the physical function.
+#[derive(Debug, Clone, Copy)]
+struct Bar {
+    host_addr: u64,
+    size: u64,
+    /// The low flag bits of the BAR register (I/O space, memory type and prefetchable).
+    flags: u32,
+    /// The address programmed by the guest.
+    guest_addr: u64,
+    /// The guest physical address the BAR is currently mapped at.
+    mapped_at: Option<u64>,
+}
+
+impl Bar {
+    fn is_io(&self) -> bool {
+        self.flags & BAR_IO != 0
+    }
+
+    fn is_64(&self) -> bool {
+        !self.is_io() && self.flags & BAR_MEM_TYPE_MASK == BAR_MEM_64
+    }
+
+    fn addr_mask(&self) -> u64 {
+        !(self.size - 1)
+    }
+}
+
+/// Shadow of the MSI capability.
+#[derive(Debug, Default)]
+struct MsiCap {
+    offset: usize,
+    ctrl: u32,
+    addr: u64,
+    data: u32,
+}
+
+impl MsiCap {
+    fn is_64(&self) -> bool {
+        self.ctrl & MSI_CTRL_64BIT != 0
+    }
+
+    fn data_offset(&self) -> usize {
+        if self.is_64() { 0xc } else { 0x8 }
+    }
+
+    /// Length of the mediated part of the capability, mask and pending bits are passed through.
+    fn len(&self) -> usize {
+        self.data_offset() + 4
+    }
+}
+
+struct DeviceState {
+    /// BAR slots, the slot following a 64-bit BAR is `None`.
+    bars: [Option<Bar>; 6],
+    interrupt_line: u8,
+    msi: Option<MsiCap>,
+}
+
+/// A physical PCI function assigned to a guest.
+pub struct PassthroughDevice {
+    bdf: Bdf,
+    cfg: HostConfigSpace,
+    host_msi_addr: u64,
+    host_irqs: Vec<usize>,
+    /// Offset of the MSI-X capability.
+    msix_cap: Option<usize>,
+    msix: Option<Arc<MsixTable>>,
+    state: Mutex<DeviceState>,
+}
+
+impl PassthroughDevice {
+    /// Probes the physical function at `cfg_base`, sizing its BARs and locating its MSI/MSI-X
+    /// capabilities.
+    pub(super) fn probe(
+        bdf: Bdf,
+        cfg_base: HostPhysAddr,
+        host_msi_addr: u64,
+        host_irqs: Vec<usize>,
+    ) -> AxResult<Self> {
+        let cfg = HostConfigSpace::new(cfg_base);
+        let id = cfg.read32(0);
+        if id & 0xffff == 0xffff {
+            return ax_err!(NotFound, format!("PCI {} not present", bdf));
+        }
+        if (cfg.read32(REG_HEADER) >> 16) & 0x7f != 0 {
+            return ax_err!(Unsupported, format!("PCI {} is not a type 0 function", bdf));
+        }
+
+        // Disable decoding while sizing the BARs.
+        let cmd = cfg.read(REG_COMMAND, AccessWidth::Word) as u16;
+        cfg.write(
+            REG_COMMAND,
+            AccessWidth::Word,
+            (cmd & !(CMD_IO | CMD_MEM)) as u32,
+        );
+        let bars = Self::size_bars(&cfg);
+        cfg.write(REG_COMMAND, AccessWidth::Word, cmd as u32);
+
+        let (mut msi, mut msix_cap) = (None, None);
+        if cfg.read32(REG_COMMAND) & STATUS_CAP_LIST != 0 {
+            let mut ptr = cfg.read(REG_CAP_PTR, AccessWidth::Byte) as usize & 0xfc;
+            // Bound the walk in case of a malformed (looping) capability list.
+            for _ in 0..48 {
+                if ptr < 0x40 {
+                    break;
+                }
+                let header = cfg.read32(ptr);
+                match header as u8 {
+                    CAP_ID_MSI => {
+                        msi = Some(MsiCap {
+                            offset: ptr,
+                            ctrl: header & 0xffff_0000 & !(MSI_CTRL_ENABLE | MSI_CTRL_MMC_MME),
+                            ..Default::default()
+                        })
+                    }
+                    CAP_ID_MSIX => msix_cap = Some(ptr),
+                    _ => {}
+                }
+                ptr = (header >> 8) as usize & 0xfc;
+            }
+        }
+
+        let msix = match msix_cap {
+            Some(cap) => {
+                let table = cfg.read32(cap + 4);
+                let bir = (table & 0x7) as usize;
+                let bar = bars[bir].ok_or_else(|| {
+                    axerrno::ax_err_type!(InvalidData, "MSI-X table in a non-existent BAR")
+                })?;
+                let entries = ((cfg.read32(cap) >> 16) & 0x7ff) as usize + 1;
+                Some(Arc::new(MsixTable::new(
+                    bir,
+                    (table & !0x7) as usize,
+                    entries,
+                    HostPhysAddr::from(bar.host_addr as usize),
+                    host_msi_addr,
+                    host_irqs.clone(),
+                )))
+            }
+            None => None,
+        };
+
+        let interrupt_line = cfg.read(REG_INTERRUPT, AccessWidth::Byte) as u8;
+        debug!(
+            "PCI {} id {:#x} bars {:#x?} msi {:?} msix {:?}",
+            bdf, id, bars, msi, msix_cap
+        );
+
+        Ok(Self {
+            bdf,
+            cfg,
+            host_msi_addr,
+            host_irqs,
+            msix_cap,
+            msix,
+            state: Mutex::new(DeviceState {
+                bars,
+                interrupt_line,
+                msi,
+            }),
+        })
+    }
+
+    fn size_bars(cfg: &HostConfigSpace) -> [Option<Bar>; 6] {
+        let mut bars = [None; 6];
+        let mut idx = 0;
+        while idx < 6 {
+            let reg = REG_BAR0 + idx * 4;
+            let orig = cfg.read32(reg);
+            cfg.write32(reg, u32::MAX);
+            let probed = cfg.read32(reg);
+            cfg.write32(reg, orig);
+
+            let flags = orig & if orig & BAR_IO != 0 { 0x3 } else { 0xf };
+            let mut addr = (orig & !flags) as u64;
+            let mut mask = (probed & !flags) as u64;
+            let is_64 = flags & (BAR_IO | BAR_MEM_TYPE_MASK) == BAR_MEM_64;
+            if is_64 && idx < 5 {
+                let orig_hi = cfg.read32(reg + 4);
+                cfg.write32(reg + 4, u32::MAX);
+                let probed_hi = cfg.read32(reg + 4);
+                cfg.write32(reg + 4, orig_hi);
+                addr |= (orig_hi as u64) << 32;
+                mask |= (probed_hi as u64) << 32;
+            } else if flags & BAR_IO != 0 {
+                mask |= 0xffff_0000;
+            } else {
+                mask |= !0xffff_ffff;
+            }
+
+            if mask & !0xf != 0 {
+                let size = !mask + 1;
+                bars[idx] = Some(Bar {
+                    host_addr: addr,
+                    size,
+                    flags,
+                    guest_addr: addr,
+                    mapped_at: None,
+                });
+            }
+            idx += if is_64 { 2 } else { 1 };
+        }
+        bars
+    }
+
+    /// The host BDF of this device.
+    pub fn bdf(&self) -> Bdf {
+        self.bdf
+    }
+
+    /// Host interrupts backing the MSI/MSI-X vectors of this device.
+    pub fn host_irqs(&self) -> &[usize] {
+        &self.host_irqs
+    }
+
+    fn memory_decode_enabled(&self) -> bool {
+        self.cfg.read(REG_COMMAND, AccessWidth::Word) as u16 & CMD_MEM != 0
+    }
+
+    /// Maps the memory BARs into the guest stage-2 if memory decoding is enabled.
+    pub(super) fn map_bars(&self, vm: &VMRef) -> AxResult {
+        if !self.memory_decode_enabled() {
+            return Ok(());
+        }
+        let mut state = self.state.lock();
+        for idx in 0..6 {
+            self.map_bar(vm, &mut state, idx)?;
+        }
+        Ok(())
+    }
+
+    /// Unmaps the memory BARs mapped into the guest stage-2, if any.
+    pub(super) fn unmap_all_bars(&self, vm: &VMRef) -> AxResult {
+        self.unmap_bars(vm, &mut self.state.lock())
+    }
+
+    fn unmap_bars(&self, vm: &VMRef, state: &mut DeviceState) -> AxResult {
+        for idx in 0..6 {
+            self.unmap_bar(vm, state, idx)?;
+        }
+        Ok(())
+    }
+
+    fn map_bar(&self, vm: &VMRef, state: &mut DeviceState, idx: usize) -> AxResult {
+        let Some(bar) = state.bars[idx].as_mut() else {
+            return Ok(());
+        };
+        if bar.is_io() || bar.mapped_at == Some(bar.guest_addr) {
+            return Ok(());
+        }
+        if bar.size < PAGE_SIZE_4K as u64 {
+            warn!(
+                "PCI {} BAR{} size {:#x} is smaller than a page, mapped with its neighbours",
+                self.bdf, idx, bar.size
+            );
+        }
+
+        let gpa = bar.guest_addr as usize;
+        let hpa = bar.host_addr as usize;
+        let size = (bar.size as usize).max(PAGE_SIZE_4K);
+        let flags = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE;
+
+        // The pages holding the MSI-X table are trapped instead of mapped.
+        let hole = self
+            .msix
+            .as_ref()
+            .filter(|t| t.bir() == idx)
+            .map(|t| t.trap_range());
+        match hole {
+            Some((start, len)) => {
+                if start > 0 {
+                    stage2::map_region(vm, gpa.into(), hpa.into(), start, flags)?;
+                }
+                if start + len < size {
+                    stage2::map_region(
+                        vm,
+                        (gpa + start + len).into(),
+                        (hpa + start + len).into(),
+                        size - start - len,
+                        flags,
+                    )?;
+                }
+                mmio::register_trap(
+                    vm.id(),
+                    (gpa + start).into(),
+                    len,
+                    Arc::new(MsixTrap::new(
+                        self.msix.clone().unwrap(),
+                        GuestPhysAddr::from(gpa + start),
+                    )),
+                )?;
+            }
+            None => stage2::map_region(vm, gpa.into(), hpa.into(), size, flags)?,
+        }
+
+        debug!(
+            "VM[{}] PCI {} BAR{} mapped GPA {:#x} -> HPA {:#x} size {:#x}",
+            vm.id(),
+            self.bdf,
+            idx,
+            gpa,
+            hpa,
+            size
+        );
+        bar.mapped_at = Some(bar.guest_addr);
+        Ok(())
+    }
+
+    fn unmap_bar(&self, vm: &VMRef, state: &mut DeviceState, idx: usize) -> AxResult {
+        let Some(bar) = state.bars[idx].as_mut() else {
+            return Ok(());
+        };
+        let Some(gpa) = bar.mapped_at.take() else {
+            return Ok(());
+        };
+        let gpa = gpa as usize;
+        let size = (bar.size as usize).max(PAGE_SIZE_4K);
+
+        match self
+            .msix
+            .as_ref()
+            .filter(|t| t.bir() == idx)
+            .map(|t| t.trap_range())
+        {
+            Some((start, len)) => {
+                if start > 0 {
+                    stage2::unmap_region(vm, gpa.into(), start)?;
+                }
+                if start + len < size {
+                    stage2::unmap_region(vm, (gpa + start + len).into(), size - start - len)?;
+                }
+                mmio::unregister_trap(vm.id(), (gpa + start).into())?;
+            }
+            None => stage2::unmap_region(vm, gpa.into(), size)?,
+        }
+        iommu::flush_vm(vm.id());
+        Ok(())
+    }
+
+    /// Disables bus mastering and decoding of the physical device, and masks its interrupts.
+    pub(super) fn quiesce(&self) {
+        let cmd = self.cfg.read(REG_COMMAND, AccessWidth::Word) as u16;
+        self.cfg.write(
+            REG_COMMAND,
+            AccessWidth::Word,
+            (cmd & !(CMD_IO | CMD_MEM | CMD_BUS_MASTER) | CMD_INTX_DISABLE) as u32,
+        );
+        if let Some(msi) = &self.state.lock().msi {
+            let ctrl = self.cfg.read32(msi.offset);
+            self.cfg.write32(msi.offset, ctrl & !MSI_CTRL_ENABLE);
+        }
+        if let Some(cap) = self.msix_cap {
+            let ctrl = self.cfg.read32(cap);
+            self.cfg.write32(cap, ctrl & !(1 << 31));
+        }
+    }
+
+    /// Handles a guest read of the config space register at `reg`.
+    pub(super) fn config_read(
+        &self,
+        _vm: &VMRef,
+        reg: usize,
+        width: AccessWidth,
+    ) -> AxResult<usize> {
+        let aligned = reg & !0x3;
+        let shift = (reg & 0x3) * 8;
+        let val = match aligned {
+            REG_HEADER => self.cfg.read32(REG_HEADER) & !(1 << 23), // Hide multi-function.
+            REG_BAR0..=REG_BAR5 => self.read_bar((aligned - REG_BAR0) / 4),
+            REG_ROM => 0, // Expansion ROMs are not exposed to the guest.
+            REG_INTERRUPT => {
+                // INTx is not routed to the guest, report no interrupt pin.
+                (self.cfg.read32(REG_INTERRUPT) & 0xffff_0000)
+                    | self.state.lock().interrupt_line as u32
+            }
+            _ => match self.msi_read(aligned) {
+                Some(val) => val,
+                None => self.cfg.read32(aligned),
+            },
+        };
+        let mask = match width.size() {
+            1 => 0xff,
+            2 => 0xffff,
+            _ => u32::MAX,
+        };
+        Ok(((val >> shift) & mask) as usize)
+    }
+
+    /// Handles a guest write of `val` to the config space register at `reg`.
+    pub(super) fn config_write(
+        &self,
+        vm: &VMRef,
+        reg: usize,
+        width: AccessWidth,
+        val: usize,
+    ) -> AxResult {
+        let aligned = reg & !0x3;
+        let shift = (reg & 0x3) * 8;
+        let mask = match width.size() {
+            1 => 0xffu32,
+            2 => 0xffff,
+            _ => u32::MAX,
+        } << shift;
+        let val = (val as u32) << shift;
+
+        match aligned {
+            REG_COMMAND => {
+                if mask & 0xffff != 0 {
+                    self.write_command(vm, (val & mask) as u16)?;
+                }
+                if mask & 0xffff_0000 != 0 {
+                    // Status bits are RW1C, only forward the bytes written by the guest.
+                    self.cfg
+                        .write(REG_COMMAND + 2, AccessWidth::Word, (val & mask) >> 16);
+                }
+            }
+            REG_HEADER => self.cfg.write(reg, width, val >> shift),
+            REG_BAR0..=REG_BAR5 => {
+                let idx = (aligned - REG_BAR0) / 4;
+                let merged = (self.read_bar(idx) & !mask) | (val & mask);
+                self.write_bar(vm, idx, merged)?;
+            }
+            REG_INTERRUPT => {
+                if mask & 0xff != 0 {
+                    self.state.lock().interrupt_line = val as u8;
+                }
+            }
+            0..REG_INTERRUPT => {
+                trace!("PCI {} ignored write to read-only {:#x}", self.bdf, reg)
+            }
+            EXT_CONFIG_START.. => {
+                trace!("PCI {} ignored write to extended {:#x}", self.bdf, reg)
+            }
+            _ => {
+                if !self.msi_write(aligned, val, mask) {
+                    self.cfg.write(reg, width, val >> shift);
+                    if self.msix_cap.is_some_and(|cap| aligned == cap) {
+                        self.update_msix_routes();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_command(&self, vm: &VMRef, cmd: u16) -> AxResult {
+        let old = self.cfg.read(REG_COMMAND, AccessWidth::Word) as u16;
+        let new = (old & !CMD_GUEST_MASK) | (cmd & CMD_GUEST_MASK);
+        self.cfg.write(REG_COMMAND, AccessWidth::Word, new as u32);
+
+        if (old ^ new) & CMD_MEM != 0 {
+            if new & CMD_MEM != 0 {
+                self.map_bars(vm)?;
+            } else {
+                self.unmap_bars(vm, &mut self.state.lock())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_bar(&self, idx: usize) -> u32 {
+        let state = self.state.lock();
+        if let Some(bar) = &state.bars[idx] {
+            ((bar.guest_addr & bar.addr_mask()) as u32) | bar.flags
+        } else if let Some(bar) = idx.checked_sub(1).and_then(|i| state.bars[i]) {
+            // Upper half of a 64-bit BAR.
+            if bar.is_64() {
+                ((bar.guest_addr & bar.addr_mask()) >> 32) as u32
+            } else {
+                0
+            }
+        } else {
+            0
+        }
+    }
+
+    fn write_bar(&self, vm: &VMRef, idx: usize, val: u32) -> AxResult {
+        let mut state = self.state.lock();
+        let (bar_idx, addr) = match (
+            state.bars[idx],
+            idx.checked_sub(1).and_then(|i| state.bars[i]),
+        ) {
+            (Some(bar), _) => (
+                idx,
+                (bar.guest_addr & !0xffff_ffff) | (val & !bar.flags) as u64,
+            ),
+            (None, Some(bar)) if bar.is_64() => (
+                idx - 1,
+                (bar.guest_addr & 0xffff_ffff) | ((val as u64) << 32),
+            ),
+            _ => return Ok(()),
+        };
+
+        let remap = state.bars[bar_idx].is_some_and(|b| b.mapped_at.is_some());
+        if remap {
+            self.unmap_bar(vm, &mut state, bar_idx)?;
+        }
+        let bar = state.bars[bar_idx].as_mut().unwrap();
+        bar.guest_addr = addr & bar.addr_mask();
+        // Sizing writes leave the BAR at an address the guest never enables decoding with, so
+        // the BAR is only mapped back once it's no longer all ones.
+        let all_ones = if bar.is_64() {
+            u64::MAX
+        } else {
+            u32::MAX as u64
+        };
+        if remap && bar.guest_addr | (bar.size - 1) != all_ones {
+            self.map_bar(vm, &mut state, bar_idx)?;
+        }
+        Ok(())
+    }
+
+    fn msi_read(&self, reg: usize) -> Option<u32> {
+        let state = self.state.lock();
+        let msi = state.msi.as_ref()?;
+        if !(msi.offset..msi.offset + msi.len()).contains(&reg) {
+            return None;
+        }
+        Some(match reg - msi.offset {
+            0 => (self.cfg.read32(reg) & 0xffff) | msi.ctrl,
+            0x4 => msi.addr as u32,
+            0x8 if msi.is_64() => (msi.addr >> 32) as u32,
+            _ => msi.data,
+        })
+    }
+
+    /// Handles a write to the MSI capability, returns `false` if `reg` is outside of it.
+    fn msi_write(&self, reg: usize, val: u32, mask: u32) -> bool {
+        let mut state = self.state.lock();
+        let Some(msi) = state.msi.as_mut() else {
+            return false;
+        };
+        if !(msi.offset..msi.offset + msi.len()).contains(&reg) {
+            return false;
+        }
+
+        let merge = |old: u32| (old & !mask) | (val & mask);
+        match reg - msi.offset {
+            // Only a single vector is supported, only the enable bit is writable.
+            0 => msi.ctrl = (msi.ctrl & !MSI_CTRL_ENABLE) | (val & mask & MSI_CTRL_ENABLE),
+            0x4 => msi.addr = (msi.addr & !0xffff_ffff) | merge(msi.addr as u32) as u64,
+            0x8 if msi.is_64() => {
+                msi.addr = (msi.addr & 0xffff_ffff) | (merge((msi.addr >> 32) as u32) as u64) << 32
+            }
+            _ => msi.data = merge(msi.data),
+        }
+
+        let Some(&host_irq) = self.host_irqs.first() else {
+            warn!("PCI {} enables MSI without host irqs", self.bdf);
+            return true;
+        };
+        // Program the physical capability with the host doorbell, the guest doorbell is only
+        // kept in the shadow.
+        let offset = msi.offset;
+        self.cfg.write32(offset + 0x4, self.host_msi_addr as u32);
+        if msi.is_64() {
+            self.cfg
+                .write32(offset + 0x8, (self.host_msi_addr >> 32) as u32);
+        }
+        self.cfg.write(
+            offset + msi.data_offset(),
+            AccessWidth::Word,
+            host_irq as u32,
+        );
+        let ctrl = self.cfg.read32(offset);
+        self.cfg.write32(
+            offset,
+            (ctrl & !(MSI_CTRL_ENABLE | MSI_CTRL_MMC_MME)) | (msi.ctrl & MSI_CTRL_ENABLE),
+        );
+
+        let enabled = msi.ctrl & MSI_CTRL_ENABLE != 0;
+        set_irq_route(
+            host_irq,
+            enabled.then_some(GuestMsi {
+                addr: msi.addr,
+                data: msi.data,
+            }),
+        );
+        true
+    }
+
+    fn update_msix_routes(&self) {
+        if let Some(msix) = &self.msix {
+            msix.update_routes();
+        }
+    }
+}
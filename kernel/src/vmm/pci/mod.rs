@@ -0,0 +1,582 @@
+//! PCI device passthrough.
+//!
+//! A physical PCI function can be assigned to a guest with a `[pci]` section in its VM config:
+//!
+//! ```toml
+//! [pci]
+//! # Physical base of the host ECAM window.
+//! host_ecam_base = 0x40_1000_0000
+//! # Guest physical base of the virtual ECAM window, the guest sees a single bus 0.
+//! ecam_base = 0x40_1000_0000
+//! # Doorbell address programmed into the physical MSI/MSI-X capabilities, defaults to the
+//! # doorbell of the GICv2m frame on GICv2 hosts.
+//! host_msi_addr = 0x0808_0040
+//!
+//! [[pci.devices]]
+//! # Host `bus:device.function`.
+//! bdf = "01:00.0"
+//! # Device number on guest bus 0, defaults to the index in `pci.devices`.
+//! guest_slot = 1
+//! # Host interrupts backing the MSI/MSI-X vectors of the device, in vector order.
+//! host_irqs = [0x60, 0x61]
+//! # IOMMU stream ID of the device, defaults to the requester ID derived from `bdf`.
+//! stream_id = 0x100
+//! ```
+//!
+//! The BARs of an assigned device are mapped into the guest stage-2 at the addresses programmed by
+//! the guest, config space accesses are trapped through the virtual ECAM window and mediated by
+//! [`PassthroughDevice`], and the MSI/MSI-X vectors are routed from host interrupts to the guest
+//! messages the guest driver programmed, delivered by [`crate::vmm::msi`]. A handler is registered
+//! for each of the host interrupts, so that they are forwarded whether a vCPU or the hypervisor
+//! runs when they fire. When the config has a `[pci.iommu]` section (see [`crate::vmm::iommu`]),
+//! the devices are attached to the IOMMU domain of the VM so that their DMA is confined to guest
+//! memory.
+mod device;
+mod msix;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::GuestPhysAddr;
+use axaddrspace::device::AccessWidth;
+use axerrno::{AxResult, ax_err, ax_err_type};
+use kspin::SpinNoIrq;
+use spin::Mutex;
+
+use crate::vmm::mmio::{self, MmioTrapHandler};
+use crate::vmm::msi::{self, GuestMsi};
+use crate::vmm::{VM, VMRef};
+use crate::vmm::{direct_irq, iommu};
+
+pub use device::PassthroughDevice;
+
+/// Size of the config space of a single function in ECAM.
+const ECAM_FUNC_SIZE: usize = 0x1000;
+/// Size of the virtual ECAM window, which covers a single bus (32 devices, 8 functions each).
+const ECAM_BUS_SIZE: usize = 32 * 8 * ECAM_FUNC_SIZE;
+
+/// A PCI `bus:device.function` address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Bdf {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Bdf {
+    /// Parses a BDF in the form of `[segment:]bus:device.function`, all fields in hex.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = match s.matches(':').count() {
+            1 => s,
+            2 => s.split_once(':')?.1,
+            _ => return None,
+        };
+        let (bus, rest) = s.split_once(':')?;
+        let (device, function) = rest.split_once('.')?;
+        let bdf = Self {
+            bus: u8::from_str_radix(bus, 16).ok()?,
+            device: u8::from_str_radix(device, 16).ok()?,
+            function: u8::from_str_radix(function, 16).ok()?,
+        };
+        (bdf.device < 32 && bdf.function < 8).then_some(bdf)
+    }
+
+    /// Offset of the config space of this function in an ECAM window.
+    pub fn ecam_offset(&self) -> usize {
+        ((self.bus as usize) << 20)
+            | ((self.device as usize) << 15)
+            | ((self.function as usize) << 12)
+    }
+
+    /// The requester ID of this function, as seen by the IOMMU.
+    pub fn requester_id(&self) -> u16 {
+        (self.bus as u16) << 8 | (self.device as u16) << 3 | self.function as u16
+    }
+}
+
+impl core::fmt::Display for Bdf {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{:x}",
+            self.bus, self.device, self.function
+        )
+    }
+}
+
+/// The `[pci]` section of a VM config.
+struct PciConfig {
+    host_ecam_base: usize,
+    ecam_base: usize,
+    host_msi_addr: u64,
+    /// The `[pci.iommu]` section, devices are not DMA-isolated without it.
+    iommu: Option<toml::Table>,
+    devices: Vec<PciDeviceConfig>,
+}
+
+/// An entry of `pci.devices` in a VM config.
+struct PciDeviceConfig {
+    bdf: Bdf,
+    guest_slot: u8,
+    host_irqs: Vec<usize>,
+    stream_id: u32,
+}
+
+fn get_usize(table: &toml::Table, key: &str) -> AxResult<usize> {
+    table
+        .get(key)
+        .and_then(|v| v.as_integer())
+        .map(|v| v as usize)
+        .ok_or_else(|| ax_err_type!(InvalidInput, format!("PCI config: missing `{}`", key)))
+}
+
+impl PciConfig {
+    fn from_table(pci: &toml::Table) -> AxResult<Self> {
+        let mut devices = Vec::new();
+        let entries = pci
+            .get("devices")
+            .and_then(|v| v.as_array())
+            .map(|a| a.as_slice())
+            .unwrap_or_default();
+
+        for (idx, entry) in entries.iter().enumerate() {
+            let entry = entry
+                .as_table()
+                .ok_or_else(|| ax_err_type!(InvalidInput, "PCI config: invalid device entry"))?;
+            let bdf = entry
+                .get("bdf")
+                .and_then(|v| v.as_str())
+                .and_then(Bdf::parse)
+                .ok_or_else(|| ax_err_type!(InvalidInput, "PCI config: invalid `bdf`"))?;
+            let guest_slot = entry
+                .get("guest_slot")
+                .and_then(|v| v.as_integer())
+                .unwrap_or(idx as i64);
+            if !(0..32).contains(&guest_slot) {
+                return ax_err!(InvalidInput, "PCI config: `guest_slot` out of range");
+            }
+            let host_irqs = entry
+                .get("host_irqs")
+                .and_then(|v| v.as_array())
+                .map(|irqs| {
+                    irqs.iter()
+                        .filter_map(|v| v.as_integer())
+                        .map(|v| v as usize)
+                        .collect()
+                })
+                .unwrap_or_default();
+            let stream_id = entry
+                .get("stream_id")
+                .and_then(|v| v.as_integer())
+                .map(|v| v as u32)
+                .unwrap_or(bdf.requester_id() as u32);
+
+            devices.push(PciDeviceConfig {
+                bdf,
+                guest_slot: guest_slot as u8,
+                host_irqs,
+                stream_id,
+            });
+        }
+
+        let config = Self {
+            host_ecam_base: get_usize(pci, "host_ecam_base")?,
+            ecam_base: get_usize(pci, "ecam_base")?,
+            host_msi_addr: get_usize(pci, "host_msi_addr")
+                .map(|addr| addr as u64)
+                .unwrap_or_else(|_| Self::default_msi_addr()),
+            iommu: pci.get("iommu").and_then(|v| v.as_table()).cloned(),
+            devices,
+        };
+        config.check_msi_irqs()?;
+        Ok(config)
+    }
+
+    /// The doorbell of the GICv2m frame on GICv2 hosts, none otherwise.
+    fn default_msi_addr() -> u64 {
+        #[cfg(target_arch = "aarch64")]
+        if let Some(frame) = crate::hal::arch::gicv2::v2m_frame() {
+            return frame.doorbell();
+        }
+        0
+    }
+
+    /// Checks that the GICv2m frame the MSIs target can raise the host irqs of the devices.
+    fn check_msi_irqs(&self) -> AxResult {
+        #[cfg(target_arch = "aarch64")]
+        if let Some(frame) = crate::hal::arch::gicv2::v2m_frame()
+            && self.host_msi_addr == frame.doorbell()
+            && let Some(irq) = self
+                .devices
+                .iter()
+                .flat_map(|dev| &dev.host_irqs)
+                .find(|irq| !frame.contains(**irq))
+        {
+            return ax_err!(
+                InvalidInput,
+                format!(
+                    "PCI config: host irq {:#x} is not an SPI of the GICv2m frame",
+                    irq
+                )
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The passthrough state of a VM, which emulates the virtual ECAM window of the guest.
+struct VmPassthrough {
+    vm_id: usize,
+    ecam_base: GuestPhysAddr,
+    /// Assigned devices, indexed by their device number on guest bus 0.
+    devices: BTreeMap<u8, Arc<PassthroughDevice>>,
+}
+
+impl MmioTrapHandler for VmPassthrough {
+    fn handle_read(&self, vm: &VMRef, addr: GuestPhysAddr, width: AccessWidth) -> AxResult<usize> {
+        let offset = addr.as_usize() - self.ecam_base.as_usize();
+        let (slot, function, reg) = (
+            (offset >> 15) as u8 & 0x1f,
+            (offset >> 12) as u8 & 0x7,
+            offset & 0xfff,
+        );
+        match self.devices.get(&slot) {
+            Some(dev) if function == 0 => dev.config_read(vm, reg, width),
+            // Nothing behind this function, reads of a non-existent function return all ones.
+            _ => Ok(usize::MAX >> (usize::BITS as usize - width.size() * 8)),
+        }
+    }
+
+    fn handle_write(
+        &self,
+        vm: &VMRef,
+        addr: GuestPhysAddr,
+        width: AccessWidth,
+        val: usize,
+    ) -> AxResult {
+        let offset = addr.as_usize() - self.ecam_base.as_usize();
+        let (slot, function, reg) = (
+            (offset >> 15) as u8 & 0x1f,
+            (offset >> 12) as u8 & 0x7,
+            offset & 0xfff,
+        );
+        match self.devices.get(&slot) {
+            Some(dev) if function == 0 => dev.config_write(vm, reg, width, val),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Passthrough states of all VMs, indexed by VM ID.
+static VM_PASSTHROUGH: Mutex<BTreeMap<usize, Arc<VmPassthrough>>> = Mutex::new(BTreeMap::new());
+
+/// The route of a host interrupt to a guest.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct IrqRoute {
+    /// The owner VM ID.
+    pub(super) vm_id: usize,
+    /// Requester ID of the device on the guest bus 0, the device ID of its MSIs.
+    pub(super) device_id: u32,
+    /// The MSI message most recently programmed by the guest, `None` if the guest has not enabled
+    /// it yet.
+    pub(super) msi: Option<GuestMsi>,
+}
+
+/// Routes of host interrupts to guests, indexed by host interrupt number.
+static IRQ_ROUTES: SpinNoIrq<BTreeMap<usize, IrqRoute>> = SpinNoIrq::new(BTreeMap::new());
+
+/// Host interrupts routed to guests at the same time, at most.
+const MAX_ROUTED_IRQS: usize = 32;
+/// Value of a free slot of [`HANDLER_IRQS`].
+const NO_IRQ: usize = usize::MAX;
+
+/// Host interrupt forwarded by each handler of [`IRQ_HANDLERS`].
+static HANDLER_IRQS: [AtomicUsize; MAX_ROUTED_IRQS] =
+    [const { AtomicUsize::new(NO_IRQ) }; MAX_ROUTED_IRQS];
+
+/// Host interrupt handler of slot `SLOT` of [`HANDLER_IRQS`], the host handlers don't get the
+/// interrupt number.
+fn forward_irq<const SLOT: usize>() {
+    handle_host_irq(HANDLER_IRQS[SLOT].load(Ordering::Acquire));
+}
+
+macro_rules! irq_handlers {
+    ($($slot:literal)*) => {
+        [$(forward_irq::<$slot> as fn()),*]
+    };
+}
+
+/// The handlers of the slots of [`HANDLER_IRQS`].
+static IRQ_HANDLERS: [fn(); MAX_ROUTED_IRQS] = irq_handlers!(
+    0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+);
+
+/// Registers a host handler forwarding `host_irq` to its route, which enables the interrupt.
+fn register_irq(host_irq: usize) -> AxResult {
+    let Some((slot, handler_irq)) = HANDLER_IRQS.iter().enumerate().find(|(_, irq)| {
+        irq.compare_exchange(NO_IRQ, host_irq, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    }) else {
+        return ax_err!(
+            NoMemory,
+            format!(
+                "host irq {}: at most {} host irqs are routed to guests",
+                host_irq, MAX_ROUTED_IRQS
+            )
+        );
+    };
+    if !axhal::irq::register(host_irq, IRQ_HANDLERS[slot]) {
+        handler_irq.store(NO_IRQ, Ordering::Release);
+        return ax_err!(
+            ResourceBusy,
+            format!("host irq {} already has a handler", host_irq)
+        );
+    }
+    Ok(())
+}
+
+/// Unregisters the host handler of `host_irq`, which disables the interrupt.
+fn unregister_irq(host_irq: usize) {
+    axhal::irq::unregister(host_irq);
+    if let Some(handler_irq) = HANDLER_IRQS
+        .iter()
+        .find(|irq| irq.load(Ordering::Acquire) == host_irq)
+    {
+        handler_irq.store(NO_IRQ, Ordering::Release);
+    }
+}
+
+/// Removes the routes of `host_irqs` and unregisters their handlers.
+fn unroute_irqs<'a>(host_irqs: impl Iterator<Item = &'a usize>) {
+    for irq in host_irqs {
+        let routed = IRQ_ROUTES.lock().remove(irq).is_some();
+        if routed {
+            unregister_irq(*irq);
+        }
+    }
+}
+
+/// Sets the guest MSI message a host interrupt is routed to, `None` to mask it.
+pub(super) fn set_irq_route(host_irq: usize, guest_msi: Option<GuestMsi>) {
+    if let Some(route) = IRQ_ROUTES.lock().get_mut(&host_irq) {
+        trace!(
+            "VM[{}] route host irq {} to guest MSI {:x?}",
+            route.vm_id, host_irq, guest_msi
+        );
+        route.msi = guest_msi;
+    }
+}
+
+/// Returns the route of a routed host interrupt.
+pub(super) fn irq_route(host_irq: usize) -> Option<IrqRoute> {
+    IRQ_ROUTES.lock().get(&host_irq).copied()
+}
+
+/// Forwards a host interrupt to the guest owning it, returns `false` if it's not routed.
+fn handle_host_irq(host_irq: usize) -> bool {
+    let Some(route) = irq_route(host_irq) else {
+        return false;
+    };
+    match route.msi {
+        Some(msi) => msi::deliver(route.vm_id, route.device_id, msi),
+        None => trace!("VM[{}] host irq {} masked, dropped", route.vm_id, host_irq),
+    }
+    true
+}
+
+/// Checks that the PCI devices described in the `[pci]` section of `raw_cfg` are free, i.e. not
+/// assigned to a VM other than those in `releasing`, without assigning them.
+pub fn check_vm_passthrough(raw_cfg: &toml::Table, releasing: &[usize]) -> AxResult {
+    let Some(pci) = raw_cfg.get("pci").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let config = PciConfig::from_table(pci)?;
+    let assigned = VM_PASSTHROUGH.lock();
+    for dev_cfg in &config.devices {
+        let owner = assigned
+            .values()
+            .filter(|p| !releasing.contains(&p.vm_id))
+            .find(|p| p.devices.values().any(|dev| dev.bdf() == dev_cfg.bdf));
+        if let Some(owner) = owner {
+            return ax_err!(
+                ResourceBusy,
+                format!("PCI {} is assigned to VM[{}]", dev_cfg.bdf, owner.vm_id)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Assigns the PCI devices described in the `[pci]` section of `raw_cfg` to the VM.
+///
+/// Does nothing if the VM config has no `[pci]` section.
+pub fn setup_vm_passthrough(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(pci) = raw_cfg.get("pci").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let config = PciConfig::from_table(pci)?;
+    let vm_id = vm.id();
+    match &config.iommu {
+        Some(iommu_cfg) => iommu::init(iommu_cfg)?,
+        None if !config.devices.is_empty() => warn!(
+            "VM[{}] has no IOMMU configured, DMA of its PCI devices is not isolated",
+            vm_id
+        ),
+        None => {}
+    }
+
+    let mut devices = BTreeMap::new();
+    for dev_cfg in &config.devices {
+        if let Err(e) = assign_device(vm, &config, dev_cfg, &mut devices) {
+            release_devices(vm, &devices);
+            return Err(e);
+        }
+    }
+
+    let passthrough = Arc::new(VmPassthrough {
+        vm_id,
+        ecam_base: config.ecam_base.into(),
+        devices,
+    });
+    if let Err(e) = mmio::register_trap(
+        vm_id,
+        passthrough.ecam_base,
+        ECAM_BUS_SIZE,
+        passthrough.clone(),
+    ) {
+        release_devices(vm, &passthrough.devices);
+        return Err(e);
+    }
+    VM_PASSTHROUGH.lock().insert(vm_id, passthrough);
+
+    Ok(())
+}
+
+/// Assigns the device of `dev_cfg` to the VM and adds it to `devices`.
+///
+/// The device is added as soon as it's probed, so that [`release_devices`] undoes what was done
+/// for it if the assignment fails partway.
+fn assign_device(
+    vm: &VMRef,
+    config: &PciConfig,
+    dev_cfg: &PciDeviceConfig,
+    devices: &mut BTreeMap<u8, Arc<PassthroughDevice>>,
+) -> AxResult {
+    let vm_id = vm.id();
+    if devices.contains_key(&dev_cfg.guest_slot) {
+        return ax_err!(
+            AlreadyExists,
+            format!(
+                "VM[{}] PCI guest slot {} assigned twice",
+                vm_id, dev_cfg.guest_slot
+            )
+        );
+    }
+    if dev_cfg
+        .host_irqs
+        .iter()
+        .any(|&irq| direct_irq::owner(irq).is_some())
+        || {
+            let routes = IRQ_ROUTES.lock();
+            dev_cfg.host_irqs.iter().any(|irq| routes.contains_key(irq))
+        }
+    {
+        return ax_err!(
+            AlreadyExists,
+            format!("VM[{}] PCI {} host irqs already routed", vm_id, dev_cfg.bdf)
+        );
+    }
+
+    let host_cfg_base = config.host_ecam_base + dev_cfg.bdf.ecam_offset();
+    let dev = Arc::new(PassthroughDevice::probe(
+        dev_cfg.bdf,
+        host_cfg_base.into(),
+        config.host_msi_addr,
+        dev_cfg.host_irqs.clone(),
+    )?);
+    devices.insert(dev_cfg.guest_slot, dev.clone());
+    if config.iommu.is_some() {
+        iommu::attach_device(vm, dev_cfg.stream_id)?;
+    }
+    dev.map_bars(vm)?;
+
+    for &irq in &dev_cfg.host_irqs {
+        IRQ_ROUTES.lock().insert(
+            irq,
+            IrqRoute {
+                vm_id,
+                device_id: (dev_cfg.guest_slot as u32) << 3,
+                msi: None,
+            },
+        );
+        if let Err(e) = register_irq(irq) {
+            IRQ_ROUTES.lock().remove(&irq);
+            return Err(e);
+        }
+    }
+
+    info!(
+        "VM[{}] PCI {} assigned to guest 00:{:02x}.0",
+        vm_id, dev_cfg.bdf, dev_cfg.guest_slot
+    );
+    Ok(())
+}
+
+/// Undoes the assignment of `devices` to the VM after a failed setup: the devices are quiesced,
+/// their BARs unmapped from the guest, they are detached from the IOMMU and their interrupts are
+/// unrouted.
+fn release_devices(vm: &VMRef, devices: &BTreeMap<u8, Arc<PassthroughDevice>>) {
+    for dev in devices.values() {
+        dev.quiesce();
+        if let Err(e) = dev.unmap_all_bars(vm) {
+            warn!(
+                "VM[{}] failed to unmap the BARs of PCI {}: {:?}",
+                vm.id(),
+                dev.bdf(),
+                e
+            );
+        }
+    }
+    iommu::detach_vm(vm.id());
+    unroute_irqs(devices.values().flat_map(|dev| dev.host_irqs()));
+}
+
+/// Releases the PCI devices assigned to a VM, called when the VM is destroyed.
+///
+/// The devices are quiesced (bus mastering and decoding disabled) before they are detached from
+/// the IOMMU and their interrupts are unrouted, so that a later owner does not observe DMA or
+/// interrupts of the previous guest.
+pub fn teardown_vm_passthrough(vm_id: usize) {
+    let Some(passthrough) = VM_PASSTHROUGH.lock().remove(&vm_id) else {
+        return;
+    };
+    for dev in passthrough.devices.values() {
+        dev.quiesce();
+    }
+    iommu::detach_vm(vm_id);
+    unroute_irqs(passthrough.devices.values().flat_map(|dev| dev.host_irqs()));
+    info!(
+        "VM[{}] released {} PCI passthrough devices",
+        vm_id,
+        passthrough.devices.len()
+    );
+}
+
+/// Returns the PCI devices assigned to a VM as (host BDF, guest slot) pairs.
+#[allow(unused)]
+pub fn assigned_devices(vm: &VM) -> Vec<(Bdf, u8)> {
+    VM_PASSTHROUGH
+        .lock()
+        .get(&vm.id())
+        .map(|p| {
+            p.devices
+                .iter()
+                .map(|(slot, dev)| (dev.bdf(), *slot))
+                .collect()
+        })
+        .unwrap_or_default()
+}
@@ -0,0 +1,239 @@
+//! Mediation of the MSI-X table of a passthrough PCI function.
+//!
+//! The pages of the BAR holding the MSI-X table are not mapped into the guest but trapped, the
+//! guest programs a shadow table, while the physical table is programmed with the host doorbell
+//! and the host interrupts backing each vector.
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::device::AccessWidth;
+use axaddrspace::{GuestPhysAddr, HostPhysAddr};
+use axerrno::AxResult;
+use memory_addr::{PAGE_SIZE_4K, VirtAddr, align_down_4k, align_up_4k};
+use spin::Mutex;
+
+use super::set_irq_route;
+use crate::vmm::VMRef;
+use crate::vmm::mmio::MmioTrapHandler;
+use crate::vmm::msi::GuestMsi;
+
+const ENTRY_SIZE: usize = 16;
+const ENTRY_ADDR_LO: usize = 0x0;
+const ENTRY_ADDR_HI: usize = 0x4;
+const ENTRY_DATA: usize = 0x8;
+const ENTRY_CTRL: usize = 0xc;
+const ENTRY_CTRL_MASKED: u32 = 1 << 0;
+
+/// An MSI-X table entry as programmed by the guest.
+#[derive(Debug, Clone, Copy)]
+struct MsixEntry {
+    addr: u64,
+    data: u32,
+    ctrl: u32,
+}
+
+impl MsixEntry {
+    fn masked(&self) -> bool {
+        self.ctrl & ENTRY_CTRL_MASKED != 0
+    }
+}
+
+/// The shadow MSI-X table of a passthrough device.
+pub(super) struct MsixTable {
+    /// Index of the BAR holding the table.
+    bir: usize,
+    /// Offset of the table in the BAR.
+    offset: usize,
+    /// Host virtual address of the BAR.
+    host_bar: VirtAddr,
+    host_msi_addr: u64,
+    host_irqs: Vec<usize>,
+    entries: Mutex<Vec<MsixEntry>>,
+}
+
+impl MsixTable {
+    pub(super) fn new(
+        bir: usize,
+        offset: usize,
+        num_entries: usize,
+        host_bar: HostPhysAddr,
+        host_msi_addr: u64,
+        host_irqs: Vec<usize>,
+    ) -> Self {
+        if host_irqs.len() < num_entries {
+            warn!(
+                "MSI-X table has {} entries but only {} host irqs, the rest stay masked",
+                num_entries,
+                host_irqs.len()
+            );
+        }
+        let table = Self {
+            bir,
+            offset,
+            host_bar: axhal::mem::phys_to_virt(host_bar),
+            host_msi_addr,
+            host_irqs,
+            entries: Mutex::new(vec![
+                MsixEntry {
+                    addr: 0,
+                    data: 0,
+                    ctrl: ENTRY_CTRL_MASKED,
+                };
+                num_entries
+            ]),
+        };
+        for idx in 0..num_entries {
+            table.write_host(table.entry_offset(idx) + ENTRY_CTRL, ENTRY_CTRL_MASKED);
+        }
+        table
+    }
+
+    /// Index of the BAR holding the table.
+    pub(super) fn bir(&self) -> usize {
+        self.bir
+    }
+
+    /// The page-aligned range of the BAR to be trapped, as (offset, size).
+    pub(super) fn trap_range(&self) -> (usize, usize) {
+        let len = self.entries.lock().len() * ENTRY_SIZE;
+        let start = align_down_4k(self.offset);
+        let end = align_up_4k(self.offset + len).max(start + PAGE_SIZE_4K);
+        (start, end - start)
+    }
+
+    fn entry_offset(&self, idx: usize) -> usize {
+        self.offset + idx * ENTRY_SIZE
+    }
+
+    fn read_host(&self, offset: usize) -> u32 {
+        // SAFETY: `offset` is within the BAR, which is mapped as MMIO.
+        unsafe { ((self.host_bar + offset).as_ptr() as *const u32).read_volatile() }
+    }
+
+    fn write_host(&self, offset: usize, val: u32) {
+        // SAFETY: `offset` is within the BAR, which is mapped as MMIO.
+        unsafe { ((self.host_bar + offset).as_mut_ptr() as *mut u32).write_volatile(val) }
+    }
+
+    /// Programs the physical entry `idx` from its shadow and updates the route of its host irq.
+    fn sync_entry(&self, idx: usize, entry: &MsixEntry) {
+        let base = self.entry_offset(idx);
+        let Some(&host_irq) = self.host_irqs.get(idx) else {
+            return;
+        };
+
+        // Mask the entry while it's being reprogrammed.
+        self.write_host(base + ENTRY_CTRL, ENTRY_CTRL_MASKED);
+        self.write_host(base + ENTRY_ADDR_LO, self.host_msi_addr as u32);
+        self.write_host(base + ENTRY_ADDR_HI, (self.host_msi_addr >> 32) as u32);
+        self.write_host(base + ENTRY_DATA, host_irq as u32);
+        self.write_host(base + ENTRY_CTRL, entry.ctrl);
+
+        set_irq_route(
+            host_irq,
+            (!entry.masked()).then_some(GuestMsi {
+                addr: entry.addr,
+                data: entry.data,
+            }),
+        );
+    }
+
+    /// Reprograms all physical entries from the shadow table, called when the guest changes the
+    /// MSI-X message control.
+    pub(super) fn update_routes(&self) {
+        let entries = self.entries.lock();
+        for (idx, entry) in entries.iter().enumerate() {
+            self.sync_entry(idx, entry);
+        }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        let entries = self.entries.lock();
+        let rel = offset.wrapping_sub(self.offset);
+        match entries.get(rel / ENTRY_SIZE) {
+            Some(entry) if offset >= self.offset => match rel % ENTRY_SIZE {
+                ENTRY_ADDR_LO => entry.addr as u32,
+                ENTRY_ADDR_HI => (entry.addr >> 32) as u32,
+                ENTRY_DATA => entry.data,
+                _ => entry.ctrl,
+            },
+            // Outside of the table (e.g., the PBA sharing the page), pass through.
+            _ => self.read_host(offset),
+        }
+    }
+
+    fn write(&self, offset: usize, val: u32) {
+        let mut entries = self.entries.lock();
+        let rel = offset.wrapping_sub(self.offset);
+        let idx = rel / ENTRY_SIZE;
+        match entries.get_mut(idx) {
+            Some(entry) if offset >= self.offset => {
+                match rel % ENTRY_SIZE {
+                    ENTRY_ADDR_LO => entry.addr = (entry.addr & !0xffff_ffff) | val as u64,
+                    ENTRY_ADDR_HI => entry.addr = (entry.addr & 0xffff_ffff) | (val as u64) << 32,
+                    ENTRY_DATA => entry.data = val,
+                    _ => entry.ctrl = val & ENTRY_CTRL_MASKED,
+                }
+                let entry = *entry;
+                self.sync_entry(idx, &entry);
+            }
+            _ => self.write_host(offset, val),
+        }
+    }
+}
+
+/// The MMIO trap of the MSI-X table pages at their current guest physical address.
+pub(super) struct MsixTrap {
+    table: Arc<MsixTable>,
+    base: GuestPhysAddr,
+}
+
+impl MsixTrap {
+    pub(super) fn new(table: Arc<MsixTable>, base: GuestPhysAddr) -> Self {
+        Self { table, base }
+    }
+
+    /// Offset of `addr` in the BAR holding the table.
+    fn bar_offset(&self, addr: GuestPhysAddr) -> usize {
+        self.table.trap_range().0 + (addr.as_usize() - self.base.as_usize())
+    }
+}
+
+impl MmioTrapHandler for MsixTrap {
+    fn handle_read(&self, _vm: &VMRef, addr: GuestPhysAddr, width: AccessWidth) -> AxResult<usize> {
+        let offset = self.bar_offset(addr);
+        Ok(match width {
+            AccessWidth::Qword => {
+                self.table.read(offset) as usize | (self.table.read(offset + 4) as usize) << 32
+            }
+            _ => {
+                let val = self.table.read(offset & !0x3) >> ((offset & 0x3) * 8);
+                (val as usize) & (usize::MAX >> (usize::BITS as usize - width.size() * 8))
+            }
+        })
+    }
+
+    fn handle_write(
+        &self,
+        _vm: &VMRef,
+        addr: GuestPhysAddr,
+        width: AccessWidth,
+        val: usize,
+    ) -> AxResult {
+        let offset = self.bar_offset(addr);
+        match width {
+            AccessWidth::Qword => {
+                self.table.write(offset, val as u32);
+                self.table.write(offset + 4, (val >> 32) as u32);
+            }
+            AccessWidth::Dword => self.table.write(offset, val as u32),
+            _ => warn!(
+                "Unsupported {:?} access to MSI-X table at {:#x}, ignored",
+                width, offset
+            ),
+        }
+        Ok(())
+    }
+}
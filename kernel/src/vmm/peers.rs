@@ -0,0 +1,116 @@
+//! Per-VM namespaces of the VM IDs visible to guests through hypercalls.
+//!
+//! Guests refer to other VMs by local peer handles instead of global VM IDs, so guest images do
+//! not depend on the order the VMs are created in. The handles of a VM are declared in its config,
+//! `peer<N>` being handle `N`, and resolve either to a VM ID or to a VM name:
+//!
+//! ```toml
+//! [peers]
+//! peer0 = 2
+//! peer1 = "linux-rtos"
+//! ```
+//!
+//! Names are resolved when a hypercall uses the handle, so the peer may be created after the VM.
+//! A name of the form `name@node` refers to a VM of another axvisor node reached through the
+//! bridge, see [`crate::vmm::bridge`].
+//! A VM without a `[peers]` section keeps seeing global VM IDs.
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use spin::Mutex;
+
+use crate::vmm::{VMRef, bridge, vm_list};
+
+const HANDLE_PREFIX: &str = "peer";
+
+/// The VM a peer handle refers to.
+#[derive(Debug, Clone)]
+pub enum PeerTarget {
+    Id(usize),
+    Name(String),
+}
+
+/// Peer handles of all VMs that have a namespace, indexed by VM ID.
+static NAMESPACES: Mutex<BTreeMap<usize, BTreeMap<usize, PeerTarget>>> =
+    Mutex::new(BTreeMap::new());
+
+/// Creates the namespace described in the `[peers]` section of `raw_cfg` for the VM.
+pub fn setup_vm_peers(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(table) = raw_cfg.get("peers").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+
+    let mut peers = BTreeMap::new();
+    for (key, value) in table {
+        let handle = key
+            .strip_prefix(HANDLE_PREFIX)
+            .and_then(|n| n.parse::<usize>().ok())
+            .ok_or_else(|| {
+                ax_err_type!(
+                    InvalidInput,
+                    format!("peers config: invalid handle `{}`", key)
+                )
+            })?;
+        let target = match value {
+            toml::Value::Integer(id) if *id >= 0 => PeerTarget::Id(*id as usize),
+            toml::Value::String(name) => PeerTarget::Name(name.clone()),
+            _ => {
+                return ax_err!(
+                    InvalidInput,
+                    format!("peers config: `{}` must be a VM ID or name", key)
+                );
+            }
+        };
+        info!("VM[{}] {} -> {:?}", vm.id(), key, target);
+        peers.insert(handle, target);
+    }
+    NAMESPACES.lock().insert(vm.id(), peers);
+    Ok(())
+}
+
+/// Removes the namespace of a VM, called when the VM is destroyed.
+pub fn teardown_vm_peers(vm_id: usize) {
+    NAMESPACES.lock().remove(&vm_id);
+}
+
+/// Translates a VM ID passed by the guest of VM `vm_id` to a global VM ID.
+///
+/// Without a namespace the ID is global already.
+pub fn resolve(vm_id: usize, handle: usize) -> AxResult<usize> {
+    let target = match NAMESPACES.lock().get(&vm_id) {
+        Some(peers) => peers.get(&handle).cloned().ok_or_else(|| {
+            ax_err_type!(
+                NotFound,
+                format!("VM[{}] has no peer handle {}", vm_id, handle)
+            )
+        })?,
+        None => return Ok(handle),
+    };
+    match target {
+        PeerTarget::Id(id) => Ok(id),
+        // A VM of another node, see `crate::vmm::bridge`.
+        PeerTarget::Name(name) if name.contains('@') => {
+            let (name, node) = name.rsplit_once('@').unwrap();
+            let node = node.parse::<usize>().map_err(|_| {
+                ax_err_type!(InvalidInput, format!("invalid node in peer {:?}", name))
+            })?;
+            bridge::resolve_remote(name, node)
+        }
+        PeerTarget::Name(name) => vm_list::get_vm_list()
+            .iter()
+            .find(|vm| vm.with_config(|cfg| cfg.name() == name))
+            .map(|vm| vm.id())
+            .ok_or_else(|| ax_err_type!(NotFound, format!("peer VM {:?} not found", name))),
+    }
+}
+
+/// Returns the peer handles of a VM and their targets.
+pub fn vm_peers(vm_id: usize) -> Vec<(usize, PeerTarget)> {
+    NAMESPACES
+        .lock()
+        .get(&vm_id)
+        .map(|peers| peers.iter().map(|(h, t)| (*h, t.clone())).collect())
+        .unwrap_or_default()
+}
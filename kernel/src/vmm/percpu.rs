@@ -0,0 +1,224 @@
+//! Per-CPU hypervisor state.
+//!
+//! Every physical CPU has a block recording the vCPU it runs, whether it is in the guest, the
+//! last exit and its details in scratch words, and entry and exit counts. The block is written by
+//! its CPU only, on the world switch in the vCPU loop, and read by anyone: the shell and, when the
+//! hypervisor panics, the panic report (see [`crate::vmm::panic_report`]), so that the VM and
+//! vCPU each CPU was running is known after a crash.
+//!
+//! The world switch is checked against the block: a CPU entering a guest while it is already in
+//! one, or exiting a vCPU it didn't enter (e.g. because the vCPU task migrated while the vCPU was
+//! bound to the CPU), is a hypervisor bug. It is logged and counted in the block, and the block is
+//! resynchronized so that one bug is reported once.
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+
+use std::os::arceos::modules::axhal;
+
+use axvcpu::AxVCpuExitReason;
+use spin::Once;
+
+use crate::vmm::hang;
+use crate::vmm::memstat::{self, MemSubsystem};
+
+/// Number of scratch words of a block.
+pub const SCRATCH_WORDS: usize = 2;
+
+/// Value of [`HvCpu::current`] when the CPU has not run a vCPU yet.
+const NO_VCPU: u64 = u64::MAX;
+
+fn pack(vm_id: usize, vcpu_id: usize) -> u64 {
+    (vm_id as u64) << 32 | (vcpu_id as u64 & 0xffff_ffff)
+}
+
+fn unpack(word: u64) -> Option<(usize, usize)> {
+    (word != NO_VCPU).then_some(((word >> 32) as usize, (word & 0xffff_ffff) as usize))
+}
+
+/// Hypervisor state of a physical CPU, written by the CPU itself only.
+struct HvCpu {
+    /// VM and vCPU run last on the CPU, packed by [`pack`].
+    current: AtomicU64,
+    in_guest: AtomicBool,
+    entry_ns: AtomicU64,
+    entries: AtomicU64,
+    exits: AtomicU64,
+    last_exit: AtomicU8,
+    /// Details of the last exit, see [`exit_details`].
+    scratch: [AtomicU64; SCRATCH_WORDS],
+    inconsistencies: AtomicU64,
+}
+
+impl HvCpu {
+    const fn new() -> Self {
+        Self {
+            current: AtomicU64::new(NO_VCPU),
+            in_guest: AtomicBool::new(false),
+            entry_ns: AtomicU64::new(0),
+            entries: AtomicU64::new(0),
+            exits: AtomicU64::new(0),
+            last_exit: AtomicU8::new(0),
+            scratch: [const { AtomicU64::new(0) }; SCRATCH_WORDS],
+            inconsistencies: AtomicU64::new(0),
+        }
+    }
+
+    fn snapshot(&self, cpu_id: usize) -> CpuBlock {
+        CpuBlock {
+            cpu_id,
+            current: unpack(self.current.load(Ordering::Acquire)),
+            in_guest: self.in_guest.load(Ordering::Acquire),
+            entry_ns: self.entry_ns.load(Ordering::Relaxed),
+            entries: self.entries.load(Ordering::Relaxed),
+            exits: self.exits.load(Ordering::Relaxed),
+            last_exit: hang::exit_name(self.last_exit.load(Ordering::Relaxed)),
+            scratch: core::array::from_fn(|i| self.scratch[i].load(Ordering::Relaxed)),
+            inconsistencies: self.inconsistencies.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Snapshot of the hypervisor state of a physical CPU.
+#[derive(Debug, Clone, Copy)]
+pub struct CpuBlock {
+    pub cpu_id: usize,
+    /// VM and vCPU in the guest on the CPU, or run last if not [`in_guest`](Self::in_guest).
+    pub current: Option<(usize, usize)>,
+    pub in_guest: bool,
+    /// Time of the last guest entry.
+    pub entry_ns: u64,
+    pub entries: u64,
+    pub exits: u64,
+    /// Reason of the last exit.
+    pub last_exit: &'static str,
+    /// Details of the last exit: the hypercall number and first argument, the faulting or
+    /// accessed address, or the interrupt vector.
+    pub scratch: [u64; SCRATCH_WORDS],
+    /// World switches inconsistent with the block.
+    pub inconsistencies: u64,
+}
+
+static CPUS: Once<Vec<HvCpu>> = Once::new();
+
+/// Allocates the blocks of the physical CPUs.
+pub fn init() {
+    let cpu_count = axruntime::cpu_count();
+    CPUS.call_once(|| {
+        memstat::charge(MemSubsystem::Trace, None, cpu_count * size_of::<HvCpu>());
+        (0..cpu_count).map(|_| HvCpu::new()).collect()
+    });
+}
+
+/// Runs `f` on the block of the current CPU, with preemption disabled.
+fn with_this_cpu(f: impl FnOnce(usize, &HvCpu)) {
+    let Some(cpus) = CPUS.get() else {
+        return;
+    };
+    let _guard = kernel_guard::NoPreempt::new();
+    let cpu_id = axhal::percpu::this_cpu_id();
+    if let Some(cpu) = cpus.get(cpu_id) {
+        f(cpu_id, cpu);
+    }
+}
+
+/// Records the entry of the current CPU into the guest of vCPU `vcpu_id` of VM `vm_id`.
+pub fn enter(vm_id: usize, vcpu_id: usize) {
+    with_this_cpu(|cpu_id, cpu| {
+        if cpu.in_guest.load(Ordering::Relaxed) {
+            error!(
+                "CPU {}: VM[{}] VCpu[{}] entered while {:?} is in the guest",
+                cpu_id,
+                vm_id,
+                vcpu_id,
+                unpack(cpu.current.load(Ordering::Relaxed))
+            );
+            cpu.inconsistencies.fetch_add(1, Ordering::Relaxed);
+        }
+        cpu.current.store(pack(vm_id, vcpu_id), Ordering::Release);
+        cpu.entry_ns
+            .store(axhal::time::monotonic_time_nanos(), Ordering::Relaxed);
+        cpu.entries.fetch_add(1, Ordering::Relaxed);
+        // Ordered before the checks of the events of the vCPU which follow, see
+        // [`crate::vmm::vcpus::kick`].
+        cpu.in_guest.store(true, Ordering::SeqCst);
+    });
+}
+
+/// Records the exit of the current CPU from the guest of vCPU `vcpu_id` of VM `vm_id`, for
+/// `reason` if the vCPU ran without error.
+pub fn exit(vm_id: usize, vcpu_id: usize, reason: Option<&AxVCpuExitReason>) {
+    with_this_cpu(|cpu_id, cpu| {
+        let current = unpack(cpu.current.load(Ordering::Relaxed));
+        if !cpu.in_guest.load(Ordering::Relaxed) || current != Some((vm_id, vcpu_id)) {
+            error!(
+                "CPU {}: VM[{}] VCpu[{}] exited, but the CPU entered {:?} (in guest: {})",
+                cpu_id,
+                vm_id,
+                vcpu_id,
+                current,
+                cpu.in_guest.load(Ordering::Relaxed)
+            );
+            cpu.inconsistencies.fetch_add(1, Ordering::Relaxed);
+            cpu.current.store(pack(vm_id, vcpu_id), Ordering::Release);
+        }
+        cpu.in_guest.store(false, Ordering::Release);
+        cpu.exits.fetch_add(1, Ordering::Relaxed);
+        if let Some(reason) = reason {
+            cpu.last_exit
+                .store(hang::exit_code(reason), Ordering::Relaxed);
+            for (word, value) in cpu.scratch.iter().zip(exit_details(reason)) {
+                word.store(value, Ordering::Relaxed);
+            }
+        }
+    });
+}
+
+/// Returns the CPU on which vCPU `vcpu_id` of VM `vm_id` is in the guest, if it is.
+pub fn guest_cpu(vm_id: usize, vcpu_id: usize) -> Option<usize> {
+    let current = pack(vm_id, vcpu_id);
+    CPUS.get()?.iter().position(|cpu| {
+        cpu.in_guest.load(Ordering::SeqCst) && cpu.current.load(Ordering::Acquire) == current
+    })
+}
+
+/// Scratch words recorded for an exit.
+pub(crate) fn exit_details(reason: &AxVCpuExitReason) -> [u64; SCRATCH_WORDS] {
+    match reason {
+        AxVCpuExitReason::Hypercall { nr, args } => [*nr, args[0]],
+        AxVCpuExitReason::MmioRead { addr, .. } | AxVCpuExitReason::MmioWrite { addr, .. } => {
+            [addr.as_usize() as u64, 0]
+        }
+        AxVCpuExitReason::SysRegRead { addr, .. } | AxVCpuExitReason::SysRegWrite { addr, .. } => {
+            [addr.addr() as u64, 0]
+        }
+        AxVCpuExitReason::NestedPageFault { addr, .. } => [addr.as_usize() as u64, 0],
+        AxVCpuExitReason::ExternalInterrupt { vector } => [*vector, 0],
+        AxVCpuExitReason::FailEntry {
+            hardware_entry_failure_reason,
+        } => [*hardware_entry_failure_reason, 0],
+        _ => [0; SCRATCH_WORDS],
+    }
+}
+
+/// Returns the blocks of the physical CPUs.
+pub fn blocks() -> Vec<CpuBlock> {
+    CPUS.get().map_or_else(Vec::new, |cpus| {
+        cpus.iter()
+            .enumerate()
+            .map(|(cpu_id, cpu)| cpu.snapshot(cpu_id))
+            .collect()
+    })
+}
+
+/// Runs `f` on the blocks of the physical CPUs, for the panic report (see
+/// [`crate::vmm::panic_report`]).
+///
+/// Doesn't allocate, the panic may come from the allocator.
+pub(crate) fn for_each_block(mut f: impl FnMut(CpuBlock)) {
+    let Some(cpus) = CPUS.get() else {
+        return;
+    };
+    for (cpu_id, cpu) in cpus.iter().enumerate() {
+        f(cpu.snapshot(cpu_id));
+    }
+}
@@ -0,0 +1,406 @@
+//! Performance counter virtualization (vPMU).
+//!
+//! A VM whose config has a `[pmu]` section is given some of the performance counters of the CPU,
+//! so that `perf` and similar tools can be used in the guest:
+//!
+//! ```toml
+//! [pmu]
+//! # General-purpose counters given to the guest, defaults to the counters of the CPU not
+//! # reserved by the hypervisor.
+//! counters = 4
+//! # Events the guest may count (the `evtCount` of PMEVTYPER<n>_EL0 on aarch64, the event select
+//! # and unit mask of IA32_PERFEVTSELx on x86), all by default.
+//! events = [0x08, 0x11]
+//! ```
+//!
+//! The counters are split between the guests and the hypervisor: a guest gets the first
+//! `counters` general-purpose counters (and the cycle counter on aarch64), the hypervisor keeps
+//! the last [`reserved`] ones for its own profiling (see the `pmu reserve` shell command). The
+//! state of the guest counters is saved when a vCPU exits and restored before it enters the guest
+//! again, by [`VCpuPmu`], so the counts of a vCPU are its own. On aarch64 the guest accesses its
+//! counters directly, `MDCR_EL2.HPMN` hiding the others; on x86 its accesses to the PMU MSRs are
+//! emulated by [`VCpuPmu::handle_msr_read`] and [`VCpuPmu::handle_msr_write`], so they must trap
+//! and the vPMU conflicts with minimal system register trapping (see [`crate::vmm::traps`]).
+//!
+//! Events not in `events` are refused: on x86 a counter programmed with one is not enabled, on
+//! aarch64, where the guest programs its counters directly, it is disabled at the next exit of
+//! the vCPU and counts until then. Counter overflow interrupts are not forwarded to guests yet,
+//! so guests can count events (`perf stat`) but not sample them (`perf record`). The vCPU
+//! backends must leave `MDCR_EL2` to axvisor on aarch64 and don't adjust the counters reported by
+//! CPUID leaf 0xA on x86, where the counters beyond the ones given to the guest read as zero.
+//! riscv64 is not supported.
+//!
+//! On aarch64, the last counter counts the instructions retired by the guests once the detection
+//! of stuck vCPUs samples them (see [`instruction_counter`]), and stays reserved for the
+//! hypervisor from then on.
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use axerrno::{AxResult, ax_err};
+use spin::Mutex;
+
+#[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+use crate::hal::arch::pmu as arch;
+use crate::vmm::VMRef;
+#[cfg(target_arch = "x86_64")]
+use crate::vmm::traps::{self, SysregTraps};
+
+/// The vPMU settings of a VM.
+#[derive(Debug, Clone)]
+pub struct PmuConfig {
+    /// General-purpose counters given to the guest.
+    pub counters: usize,
+    /// Events the guest may count, all if `None`.
+    pub events: Option<Vec<u16>>,
+}
+
+impl PmuConfig {
+    /// Whether the guest may count `event`.
+    pub fn allows(&self, event: u16) -> bool {
+        self.events
+            .as_ref()
+            .is_none_or(|events| events.contains(&event))
+    }
+}
+
+/// vPMU settings of the VMs with a `[pmu]` section.
+static PMU: Mutex<BTreeMap<usize, PmuConfig>> = Mutex::new(BTreeMap::new());
+/// General-purpose counters kept by the hypervisor.
+static RESERVED: AtomicUsize = AtomicUsize::new(0);
+/// Whether the last counter counts the instructions of the guests, see [`instruction_counter`].
+static INSTRUCTIONS: AtomicBool = AtomicBool::new(false);
+
+/// Number of general-purpose counters of the CPU that can be given to guests, 0 if the
+/// architecture or the CPU has no supported PMU.
+pub fn host_counters() -> usize {
+    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+    {
+        arch::counters().min(arch::MAX_COUNTERS)
+    }
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "x86_64")))]
+    {
+        0
+    }
+}
+
+/// Number of general-purpose counters the hypervisor keeps for its own profiling.
+pub fn reserved() -> usize {
+    RESERVED.load(Ordering::Relaxed)
+}
+
+/// Reserves the last `count` general-purpose counters for the hypervisor.
+///
+/// Fails if the CPU has fewer counters or if a VM was given counters that would be reserved.
+pub fn set_reserved(count: usize) -> AxResult {
+    let host = host_counters();
+    if count > host {
+        return ax_err!(
+            InvalidInput,
+            format!("the CPU has {} performance counters", host)
+        );
+    }
+    if count == 0 && INSTRUCTIONS.load(Ordering::Relaxed) {
+        return ax_err!(
+            ResourceBusy,
+            "the last counter counts the instructions of the guests"
+        );
+    }
+    let pmu = PMU.lock();
+    if let Some((vm_id, _)) = pmu.iter().find(|(_, cfg)| cfg.counters > host - count) {
+        return ax_err!(
+            ResourceBusy,
+            format!("VM[{}] uses counters that would be reserved", vm_id)
+        );
+    }
+    RESERVED.store(count, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Returns the counter counting the instructions retired by the guests for the detection of
+/// stuck vCPUs (see [`crate::vmm::hang`]), the last one, reserving it for the hypervisor.
+///
+/// Only supported on aarch64, where the counters of the hypervisor can leave EL2 out. On x86 the
+/// counters run in VMX root operation too, unless the VM entries and exits switch
+/// `IA32_PERF_GLOBAL_CTRL`, which the vCPU backend doesn't.
+pub fn instruction_counter() -> AxResult<usize> {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let host = host_counters();
+        if host == 0 {
+            return ax_err!(Unsupported, "the CPU has no supported performance counters");
+        }
+        if reserved() == 0 {
+            set_reserved(1)?;
+        }
+        INSTRUCTIONS.store(true, Ordering::Relaxed);
+        Ok(host - 1)
+    }
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        ax_err!(
+            Unsupported,
+            "counting the instructions of the guests is only supported on aarch64"
+        )
+    }
+}
+
+/// Records the vPMU settings described in the `[pmu]` section of `raw_cfg` for the VM.
+///
+/// Does nothing if the VM config has no `[pmu]` section. Must run after
+/// [`crate::vmm::traps::setup_vm_traps`].
+pub fn setup_vm_pmu(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("pmu").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let host = host_counters();
+    if host == 0 {
+        return ax_err!(
+            Unsupported,
+            "pmu config: the CPU has no supported performance counters"
+        );
+    }
+    // Checked under the lock so that a concurrent `set_reserved` sees the VM.
+    let mut pmu = PMU.lock();
+    let available = host - reserved();
+    let counters = match cfg.get("counters") {
+        None => available,
+        Some(v) => match v.as_integer() {
+            Some(n) if (1..=available as i64).contains(&n) => n as usize,
+            _ => {
+                return ax_err!(
+                    InvalidInput,
+                    format!(
+                        "pmu config: `counters` must be between 1 and {} ({} reserved by the \
+                         hypervisor)",
+                        available,
+                        reserved()
+                    )
+                );
+            }
+        },
+    };
+    if counters == 0 {
+        return ax_err!(
+            ResourceBusy,
+            "pmu config: all the performance counters are reserved by the hypervisor"
+        );
+    }
+    let events = match cfg.get("events") {
+        None => None,
+        Some(v) => {
+            let Some(array) = v.as_array() else {
+                return ax_err!(InvalidInput, "pmu config: `events` must be an array");
+            };
+            let mut events = Vec::with_capacity(array.len());
+            for event in array {
+                match event.as_integer() {
+                    Some(n) if (0..=u16::MAX as i64).contains(&n) => events.push(n as u16),
+                    _ => {
+                        return ax_err!(
+                            InvalidInput,
+                            "pmu config: `events` must hold event numbers between 0 and 0xffff"
+                        );
+                    }
+                }
+            }
+            Some(events)
+        }
+    };
+    #[cfg(target_arch = "x86_64")]
+    if traps::trap_config(vm.id()).sysreg == SysregTraps::Minimal {
+        return ax_err!(
+            InvalidInput,
+            "pmu config: the vPMU conflicts with minimal system register trapping"
+        );
+    }
+
+    let config = PmuConfig { counters, events };
+    info!("VM[{}] vPMU: {:?}", vm.id(), config);
+    pmu.insert(vm.id(), config);
+    Ok(())
+}
+
+/// Forgets the vPMU settings of a VM, called when the VM is destroyed.
+pub fn teardown_vm_pmu(vm_id: usize) {
+    PMU.lock().remove(&vm_id);
+}
+
+/// Returns the vPMU settings of VM `vm_id`, if it has a vPMU.
+pub fn pmu_config(vm_id: usize) -> Option<PmuConfig> {
+    PMU.lock().get(&vm_id).cloned()
+}
+
+/// The vPMU of a vCPU, as seen by its task.
+#[cfg_attr(target_arch = "riscv64", allow(dead_code))]
+pub struct VCpuPmu {
+    vm_id: usize,
+    vcpu_id: usize,
+    config: PmuConfig,
+    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+    state: arch::PmuState,
+    #[cfg(target_arch = "x86_64")]
+    width_mask: u64,
+    /// Keeps the task on this CPU while the guest counters are loaded.
+    guard: Option<kernel_guard::NoPreempt>,
+    /// An event was refused, only the first one is reported.
+    denied: bool,
+}
+
+impl VCpuPmu {
+    pub fn new(vm_id: usize, vcpu_id: usize) -> Option<Self> {
+        let config = pmu_config(vm_id)?;
+        Some(Self {
+            vm_id,
+            vcpu_id,
+            config,
+            #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+            state: arch::PmuState::new(),
+            #[cfg(target_arch = "x86_64")]
+            width_mask: match arch::counter_width() {
+                width @ 1..64 => (1 << width) - 1,
+                _ => u64::MAX,
+            },
+            guard: None,
+            denied: false,
+        })
+    }
+
+    /// Loads the guest counters into the PMU of this CPU, called right before the vCPU enters
+    /// the guest. The task is not preempted until [`VCpuPmu::exit`].
+    pub fn enter(&mut self) {
+        self.guard = Some(kernel_guard::NoPreempt::new());
+        #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+        arch::load(&self.state, self.config.counters);
+    }
+
+    /// Saves the guest counters and hands the PMU back to the hypervisor, called when the vCPU
+    /// exits the guest.
+    pub fn exit(&mut self) {
+        #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+        arch::save(&mut self.state, self.config.counters);
+        #[cfg(target_arch = "aarch64")]
+        self.filter_events();
+        self.guard = None;
+    }
+
+    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+    fn deny(&mut self, event: u16) {
+        if !self.denied {
+            warn!(
+                "VM[{}] VCpu[{}] counting event {:#x} is not allowed, counter disabled",
+                self.vm_id, self.vcpu_id, event
+            );
+            self.denied = true;
+        }
+    }
+
+    /// Disables the enabled counters counting events the guest may not count.
+    #[cfg(target_arch = "aarch64")]
+    fn filter_events(&mut self) {
+        if self.config.events.is_none() {
+            return;
+        }
+        for idx in 0..self.config.counters {
+            if self.state.cntenset & (1 << idx) == 0 {
+                continue;
+            }
+            // PMEVTYPER<n>_EL0.evtCount
+            let event = self.state.evtypers[idx] as u16;
+            if !self.config.allows(event) {
+                self.state.cntenset &= !(1 << idx);
+                self.state.evtypers[idx] &= !0xffff;
+                self.deny(event);
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl VCpuPmu {
+    /// Returns the guest counter `msr` is the `IA32_PERFEVTSELx` (`base` `MSR_IA32_PERFEVTSEL0`)
+    /// or `IA32_PMCx` of, if any.
+    fn counter_of(msr: usize, base: u32) -> Option<usize> {
+        let idx = msr.checked_sub(base as usize)?;
+        (idx < arch::MAX_COUNTERS).then_some(idx)
+    }
+
+    fn is_fixed_msr(msr: usize) -> bool {
+        let fixed = arch::MSR_IA32_FIXED_CTR0 as usize;
+        (fixed..fixed + 3).contains(&msr) || msr == arch::MSR_IA32_FIXED_CTR_CTRL as usize
+    }
+
+    fn global_mask(&self) -> u64 {
+        (1 << self.config.counters) - 1
+    }
+
+    /// Handles a read of `msr` by the vCPU, returns `None` if the MSR is not emulated here.
+    ///
+    /// Counters not given to the guest, the fixed counters and the overflow status read as zero.
+    pub fn handle_msr_read(&self, msr: usize) -> Option<AxResult<u64>> {
+        let counters = self.config.counters;
+        let val = if let Some(idx) = Self::counter_of(msr, arch::MSR_IA32_PERFEVTSEL0) {
+            if idx < counters {
+                self.state.evtsels[idx]
+            } else {
+                0
+            }
+        } else if let Some(idx) = Self::counter_of(msr, arch::MSR_IA32_PMC0)
+            .or_else(|| Self::counter_of(msr, arch::MSR_IA32_A_PMC0))
+        {
+            if idx < counters {
+                self.state.pmcs[idx]
+            } else {
+                0
+            }
+        } else if msr == arch::MSR_IA32_PERF_GLOBAL_CTRL as usize {
+            self.state.global_ctrl
+        } else if msr == arch::MSR_IA32_PERF_GLOBAL_STATUS as usize
+            || msr == arch::MSR_IA32_PERF_GLOBAL_OVF_CTRL as usize
+            || Self::is_fixed_msr(msr)
+        {
+            0
+        } else {
+            return None;
+        };
+        Some(Ok(val))
+    }
+
+    /// Handles a write of `val` to `msr` by the vCPU, returns `None` if the MSR is not emulated
+    /// here.
+    ///
+    /// The counters take effect when the vCPU enters the guest again. Writes to counters not
+    /// given to the guest, to the fixed counters and to the overflow controls are ignored.
+    pub fn handle_msr_write(&mut self, msr: usize, val: u64) -> Option<AxResult> {
+        let counters = self.config.counters;
+        if let Some(idx) = Self::counter_of(msr, arch::MSR_IA32_PERFEVTSEL0) {
+            if idx < counters {
+                // No overflow interrupts are forwarded.
+                let mut evtsel = val & !arch::PERFEVTSEL_INT;
+                let event = evtsel as u16;
+                if evtsel & arch::PERFEVTSEL_EN != 0 && !self.config.allows(event) {
+                    evtsel &= !arch::PERFEVTSEL_EN;
+                    self.deny(event);
+                }
+                self.state.evtsels[idx] = evtsel;
+            }
+        } else if let Some(idx) = Self::counter_of(msr, arch::MSR_IA32_PMC0) {
+            // Writes to the legacy aliases are sign-extended from bit 31.
+            if idx < counters {
+                self.state.pmcs[idx] = val as u32 as i32 as i64 as u64 & self.width_mask;
+            }
+        } else if let Some(idx) = Self::counter_of(msr, arch::MSR_IA32_A_PMC0) {
+            if idx < counters {
+                self.state.pmcs[idx] = val & self.width_mask;
+            }
+        } else if msr == arch::MSR_IA32_PERF_GLOBAL_CTRL as usize {
+            self.state.global_ctrl = val & self.global_mask();
+        } else if !(msr == arch::MSR_IA32_PERF_GLOBAL_STATUS as usize
+            || msr == arch::MSR_IA32_PERF_GLOBAL_OVF_CTRL as usize
+            || Self::is_fixed_msr(msr))
+        {
+            return None;
+        }
+        Some(Ok(()))
+    }
+}
@@ -0,0 +1,243 @@
+//! Posted interrupt delivery to vCPUs.
+//!
+//! Interrupts for a vCPU other than the current one (IPIs between vCPUs, IVC kicks, the
+//! interrupts of emulated virtio devices, MSIs taken on another CPU) are posted to a descriptor
+//! of the target vCPU rather than injected in place, which only the CPU running the vCPU can do.
+//! The descriptor follows the VT-x posted-interrupt descriptor:
+//!
+//! - `PIR`, a bitmap of the posted vectors, 256 of them on x86_64 and 1024 elsewhere;
+//! - `ON` (outstanding notification), set by the first poster since the vCPU last took its
+//!   vectors;
+//! - `SN` (suppress notification), set while the vCPU is not in the guest;
+//! - `NDST`, the physical CPU the vCPU last entered the guest on.
+//!
+//! Posting sets the vector in `PIR` without a lock and, if `ON` was clear, notifies the vCPU: a
+//! vCPU in the guest on another CPU is interrupted with an IPI so that it exits and takes its
+//! vectors right away, instead of at its next exit; a vCPU out of the guest is woken up in case it
+//! is halted. The vCPU task moves the posted vectors into the vCPU before each guest entry, see
+//! [`VCpuPosted`]. Vectors beyond `PIR` (aarch64 LPIs) are queued under a lock.
+//!
+//! The posting is done in software only. Hardware delivery to a vCPU in the guest, without an exit
+//! (VT-x posted-interrupt processing, GICv4.1 direct vLPI injection), isn't implemented: it needs
+//! the vCPU backend to take the descriptor (its address and the notification vector in the VMCS,
+//! or the vPE tables of the ITS), which it doesn't. A vector posted to a vCPU in the guest thus
+//! costs an IPI and an exit, and is injected through the list registers or the VMCS as any other;
+//! what posting spares is the IPIs of the vectors posted while a notification is outstanding.
+//!
+//! On riscv64 the vectors are injected through the IMSIC guest file of the vCPU or `hvip`, see
+//! [`crate::vmm::vintc`].
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use std::os::arceos::modules::axhal;
+
+use axerrno::{AxResult, ax_err};
+use spin::Mutex;
+
+use crate::vmm::sleep::{self, WakeReason};
+use crate::vmm::trace::{self, TRACE_CLASS_IRQ};
+use crate::vmm::{VCpuRef, VMRef, stats, vcpus};
+
+/// Words of the posted-interrupt requests bitmap.
+#[cfg(target_arch = "x86_64")]
+const PIR_WORDS: usize = 4;
+#[cfg(not(target_arch = "x86_64"))]
+const PIR_WORDS: usize = 16;
+const PIR_VECTORS: usize = PIR_WORDS * 64;
+
+/// Outstanding notification.
+const CTRL_ON: u64 = 1 << 0;
+/// Suppress notification.
+const CTRL_SN: u64 = 1 << 1;
+const CTRL_NDST_SHIFT: u64 = 32;
+
+/// The posted-interrupt descriptor of a vCPU.
+#[repr(C, align(64))]
+struct PostedDesc {
+    pir: [AtomicU64; PIR_WORDS],
+    control: AtomicU64,
+    /// Vectors beyond `pir`.
+    overflow: Mutex<Vec<usize>>,
+}
+
+impl PostedDesc {
+    fn new() -> Self {
+        Self {
+            pir: [const { AtomicU64::new(0) }; PIR_WORDS],
+            control: AtomicU64::new(CTRL_SN),
+            overflow: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+/// Posted-interrupt descriptors and counters of a VM.
+struct VmPosted {
+    descs: Vec<PostedDesc>,
+    posted: AtomicU64,
+    ipis: AtomicU64,
+}
+
+static POSTED: Mutex<BTreeMap<usize, Arc<VmPosted>>> = Mutex::new(BTreeMap::new());
+
+/// Creates the posted-interrupt descriptors of the vCPUs of a VM.
+pub fn setup_vm_posted(vm: &VMRef) {
+    let posted = VmPosted {
+        descs: (0..vm.vcpu_num()).map(|_| PostedDesc::new()).collect(),
+        posted: AtomicU64::new(0),
+        ipis: AtomicU64::new(0),
+    };
+    POSTED.lock().insert(vm.id(), Arc::new(posted));
+}
+
+/// Removes the posted-interrupt descriptors of a VM, called when the VM is destroyed.
+pub fn teardown_vm_posted(vm_id: usize) {
+    POSTED.lock().remove(&vm_id);
+}
+
+fn vm_posted(vm_id: usize) -> Option<Arc<VmPosted>> {
+    POSTED.lock().get(&vm_id).cloned()
+}
+
+/// Interrupts the vCPU of `control` if it runs in the guest on another CPU, wakes it up otherwise.
+fn notify(vm_id: usize, posted: &VmPosted, control: u64) {
+    let ndst = (control >> CTRL_NDST_SHIFT) as usize;
+    if control & CTRL_SN == 0 && ndst != axhal::percpu::this_cpu_id() {
+        posted.ipis.fetch_add(1, Ordering::Relaxed);
+        // The interrupt itself makes the vCPU exit, there's nothing to run on the target CPU.
+        axipi::send_ipi_event_to_one(ndst, || {});
+    } else {
+        vcpus::notify_all_vcpus(vm_id);
+    }
+}
+
+/// Posts `vector` to vCPU `vcpu_id` of VM `vm_id` and notifies the vCPU.
+pub fn post(vm_id: usize, vcpu_id: usize, vector: usize) -> AxResult {
+    let Some(posted) = vm_posted(vm_id) else {
+        return ax_err!(NotFound, format!("VM[{}] has no posted interrupts", vm_id));
+    };
+    let Some(desc) = posted.descs.get(vcpu_id) else {
+        return ax_err!(
+            InvalidInput,
+            format!("VM[{}] has no VCpu[{}]", vm_id, vcpu_id)
+        );
+    };
+    trace::record(TRACE_CLASS_IRQ, vm_id, vcpu_id, vector as u64, 0);
+    sleep::wake(vm_id, WakeReason::Interrupt);
+    // The emulated local APIC latches the vector itself, so that the guest EOIs it there.
+    #[cfg(target_arch = "x86_64")]
+    if crate::vmm::x2apic::raise(vm_id, vcpu_id, vector) {
+        posted.posted.fetch_add(1, Ordering::Relaxed);
+        return Ok(());
+    }
+    if vector < PIR_VECTORS {
+        desc.pir[vector / 64].fetch_or(1 << (vector % 64), Ordering::SeqCst);
+    } else {
+        desc.overflow.lock().push(vector);
+    }
+    posted.posted.fetch_add(1, Ordering::Relaxed);
+    let control = desc.control.fetch_or(CTRL_ON, Ordering::SeqCst);
+    if control & CTRL_ON == 0 {
+        notify(vm_id, &posted, control);
+    }
+    Ok(())
+}
+
+/// Notifies vCPU `vcpu_id` of VM `vm_id` of an interrupt latched elsewhere (e.g., in its emulated
+/// local APIC), so that it enters the guest again promptly.
+pub fn kick(vm_id: usize, vcpu_id: usize) {
+    let Some(posted) = vm_posted(vm_id) else {
+        return;
+    };
+    if let Some(desc) = posted.descs.get(vcpu_id) {
+        notify(vm_id, &posted, desc.control.load(Ordering::SeqCst));
+    }
+}
+
+/// The posted interrupts of a vCPU, as seen by its task.
+pub struct VCpuPosted {
+    posted: Arc<VmPosted>,
+    vm_id: usize,
+    vcpu_id: usize,
+}
+
+impl VCpuPosted {
+    pub fn new(vm_id: usize, vcpu_id: usize) -> Option<Self> {
+        let posted = vm_posted(vm_id)?;
+        (vcpu_id < posted.descs.len()).then_some(Self {
+            posted,
+            vm_id,
+            vcpu_id,
+        })
+    }
+
+    fn desc(&self) -> &PostedDesc {
+        &self.posted.descs[self.vcpu_id]
+    }
+
+    /// Allows notifications on this CPU and injects the posted vectors, called right before the
+    /// vCPU enters the guest.
+    ///
+    /// The posted vectors are taken after the notifications are allowed, so a vector posted
+    /// meanwhile is either injected here or notifies this CPU.
+    pub fn enter(&self, vcpu: &VCpuRef) {
+        let desc = self.desc();
+        let ndst = (axhal::percpu::this_cpu_id() as u64) << CTRL_NDST_SHIFT;
+        desc.control.store(ndst, Ordering::SeqCst);
+
+        for (word, pir) in desc.pir.iter().enumerate() {
+            let mut bits = pir.swap(0, Ordering::SeqCst);
+            while bits != 0 {
+                let bit = bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                self.inject(vcpu, word * 64 + bit);
+            }
+        }
+        let overflow = core::mem::take(&mut *desc.overflow.lock());
+        for vector in overflow {
+            self.inject(vcpu, vector);
+        }
+    }
+
+    /// Whether vectors were posted since the vCPU last entered the guest.
+    pub fn pending(&self) -> bool {
+        self.desc().control.load(Ordering::SeqCst) & CTRL_ON != 0
+    }
+
+    /// Suppresses notifications, called when the vCPU exits the guest.
+    pub fn exit(&self) {
+        self.desc().control.fetch_or(CTRL_SN, Ordering::SeqCst);
+    }
+
+    fn inject(&self, vcpu: &VCpuRef, vector: usize) {
+        #[cfg(target_arch = "riscv64")]
+        let result = crate::vmm::vintc::inject(self.vm_id, vcpu, vector);
+        #[cfg(not(target_arch = "riscv64"))]
+        let result = vcpu.inject_interrupt(vector);
+        match result {
+            Ok(()) => stats::count_irq(self.vm_id, self.vcpu_id),
+            Err(e) => warn!(
+                "VCpu[{}] failed to inject posted vector {}: {:?}",
+                self.vcpu_id, vector, e
+            ),
+        }
+    }
+}
+
+/// Counters of the posted interrupts of a VM.
+#[derive(Debug, Clone, Copy)]
+pub struct PostedStats {
+    /// Vectors posted.
+    pub posted: u64,
+    /// IPIs sent to vCPUs running in the guest.
+    pub ipis: u64,
+}
+
+/// Returns the counters of the posted interrupts of VM `vm_id`.
+pub fn posted_stats(vm_id: usize) -> Option<PostedStats> {
+    vm_posted(vm_id).map(|posted| PostedStats {
+        posted: posted.posted.load(Ordering::Relaxed),
+        ipis: posted.ipis.load(Ordering::Relaxed),
+    })
+}
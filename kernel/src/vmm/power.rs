@@ -0,0 +1,256 @@
+//! Paravirtual power-state device.
+//!
+//! Exposes the host power supply (AC adapter and battery) and power requests coordinated by the
+//! hypervisor (suspend/shutdown) to guests, so that the power manager in guest userspace can
+//! react to platform power events. A guest gets the device with a `[power]` section in its VM
+//! config:
+//!
+//! ```toml
+//! [power]
+//! # Guest physical base of the register page.
+//! base = 0x0a00_4000
+//! # Interrupt injected to the guest when an event is pending.
+//! irq = 0x2f
+//! ```
+//!
+//! All registers are 32-bit:
+//!
+//! | Offset | Name             | Access | Description                                        |
+//! |--------|------------------|--------|----------------------------------------------------|
+//! | 0x00   | `MAGIC`          | RO     | `"AXPW"`                                           |
+//! | 0x04   | `VERSION`        | RO     | Device version, currently 2                        |
+//! | 0x08   | `AC_ONLINE`      | RO     | 1 if the host runs on AC power                     |
+//! | 0x0c   | `BAT_PRESENT`    | RO     | 1 if the host has a battery                        |
+//! | 0x10   | `BAT_CAPACITY`   | RO     | Battery capacity in percent                        |
+//! | 0x14   | `BAT_STATUS`     | RO     | [`BatteryStatus`]                                  |
+//! | 0x18   | `EVENT`          | RW1C   | Pending [`PowerEvent`]s                            |
+//! | 0x1c   | `EVENT_ENABLE`   | RW     | Events raising the interrupt, all disabled at reset|
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+use axaddrspace::GuestPhysAddr;
+use axaddrspace::device::AccessWidth;
+use axerrno::{AxResult, ax_err, ax_err_type};
+use bitflags::bitflags;
+use cpumask::CpuMask;
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::vmm::mmio::{self, MmioTrapHandler};
+use crate::vmm::{VMRef, stats, vm_list};
+
+const MAGIC: u32 = u32::from_le_bytes(*b"AXPW");
+const VERSION: u32 = 2;
+
+const REG_MAGIC: usize = 0x00;
+const REG_VERSION: usize = 0x04;
+const REG_AC_ONLINE: usize = 0x08;
+const REG_BAT_PRESENT: usize = 0x0c;
+const REG_BAT_CAPACITY: usize = 0x10;
+const REG_BAT_STATUS: usize = 0x14;
+const REG_EVENT: usize = 0x18;
+const REG_EVENT_ENABLE: usize = 0x1c;
+
+bitflags! {
+    /// Events reported through the `EVENT` register.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PowerEvent: u32 {
+        /// The host power supply state changed.
+        const SUPPLY_CHANGED = 1 << 0;
+        /// The hypervisor requests the guest to suspend.
+        const SUSPEND_REQUEST = 1 << 1;
+        /// The hypervisor requests the guest to shut down.
+        const SHUTDOWN_REQUEST = 1 << 2;
+        /// The hypervisor requests the guest to freeze its filesystems, see
+        /// [`crate::vmm::quiesce`].
+        const FREEZE_REQUEST = 1 << 3;
+        /// The hypervisor requests the guest to thaw its filesystems.
+        const THAW_REQUEST = 1 << 4;
+    }
+}
+
+/// Charging state of the host battery.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatteryStatus {
+    Unknown = 0,
+    Charging = 1,
+    Discharging = 2,
+    Full = 3,
+}
+
+/// The host power supply state shared with guests.
+#[derive(Debug, Clone, Copy)]
+pub struct PowerSupplyState {
+    pub ac_online: bool,
+    pub battery_present: bool,
+    /// Battery capacity in percent.
+    pub capacity: u8,
+    pub status: BatteryStatus,
+}
+
+impl PowerSupplyState {
+    /// A host on AC power without a battery, used until a platform driver reports otherwise.
+    const fn ac_only() -> Self {
+        Self {
+            ac_online: true,
+            battery_present: false,
+            capacity: 100,
+            status: BatteryStatus::Unknown,
+        }
+    }
+}
+
+static HOST_POWER_SUPPLY: Mutex<PowerSupplyState> = Mutex::new(PowerSupplyState::ac_only());
+
+struct PowerDeviceState {
+    event: PowerEvent,
+    enable: PowerEvent,
+}
+
+/// The power-state device of a VM.
+struct PowerDevice {
+    vm_id: usize,
+    irq: usize,
+    state: Mutex<PowerDeviceState>,
+}
+
+impl PowerDevice {
+    /// Latches `event` and injects the interrupt if it's enabled by the guest.
+    fn raise(&self, event: PowerEvent) {
+        let fire = {
+            let mut state = self.state.lock();
+            state.event |= event;
+            state.enable.intersects(event)
+        };
+        if !fire {
+            return;
+        }
+        if let Some(vm) = vm_list::get_vm_by_id(self.vm_id) {
+            match vm.inject_interrupt_to_vcpu(CpuMask::one_shot(0), self.irq) {
+                Ok(()) => stats::count_irq(self.vm_id, 0),
+                Err(e) => warn!("VM[{}] failed to inject power event: {:?}", self.vm_id, e),
+            }
+        }
+    }
+}
+
+impl MmioTrapHandler for PowerDevice {
+    fn handle_read(&self, _vm: &VMRef, addr: GuestPhysAddr, width: AccessWidth) -> AxResult<usize> {
+        if !matches!(width, AccessWidth::Dword) {
+            return ax_err!(InvalidInput, "power device registers are 32-bit");
+        }
+        let supply = *HOST_POWER_SUPPLY.lock();
+        let state = self.state.lock();
+        let val = match addr.as_usize() & (PAGE_SIZE_4K - 1) {
+            REG_MAGIC => MAGIC,
+            REG_VERSION => VERSION,
+            REG_AC_ONLINE => supply.ac_online as u32,
+            REG_BAT_PRESENT => supply.battery_present as u32,
+            REG_BAT_CAPACITY => supply.capacity as u32,
+            REG_BAT_STATUS => supply.status as u32,
+            REG_EVENT => state.event.bits(),
+            REG_EVENT_ENABLE => state.enable.bits(),
+            _ => 0,
+        };
+        Ok(val as usize)
+    }
+
+    fn handle_write(
+        &self,
+        _vm: &VMRef,
+        addr: GuestPhysAddr,
+        width: AccessWidth,
+        val: usize,
+    ) -> AxResult {
+        if !matches!(width, AccessWidth::Dword) {
+            return ax_err!(InvalidInput, "power device registers are 32-bit");
+        }
+        let val = PowerEvent::from_bits_truncate(val as u32);
+        let mut state = self.state.lock();
+        match addr.as_usize() & (PAGE_SIZE_4K - 1) {
+            REG_EVENT => state.event.remove(val),
+            REG_EVENT_ENABLE => state.enable = val,
+            reg => trace!(
+                "VM[{}] power device write to {:#x} ignored",
+                self.vm_id, reg
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// Power-state devices of all VMs, indexed by VM ID.
+static POWER_DEVICES: Mutex<BTreeMap<usize, Arc<PowerDevice>>> = Mutex::new(BTreeMap::new());
+
+/// Creates the power-state device described in the `[power]` section of `raw_cfg` for the VM.
+///
+/// Does nothing if the VM config has no `[power]` section.
+pub fn setup_vm_power_device(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(power) = raw_cfg.get("power").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let get = |key: &str| {
+        power
+            .get(key)
+            .and_then(|v| v.as_integer())
+            .map(|v| v as usize)
+            .ok_or_else(|| ax_err_type!(InvalidInput, format!("power config: missing `{}`", key)))
+    };
+    let base = get("base")?;
+    let irq = get("irq")?;
+    if base % PAGE_SIZE_4K != 0 {
+        return ax_err!(InvalidInput, "power config: `base` must be page-aligned");
+    }
+
+    let device = Arc::new(PowerDevice {
+        vm_id: vm.id(),
+        irq,
+        state: Mutex::new(PowerDeviceState {
+            event: PowerEvent::empty(),
+            enable: PowerEvent::empty(),
+        }),
+    });
+    mmio::register_trap(vm.id(), base.into(), PAGE_SIZE_4K, device.clone())?;
+    POWER_DEVICES.lock().insert(vm.id(), device);
+
+    info!("VM[{}] power device at {:#x}, irq {}", vm.id(), base, irq);
+    Ok(())
+}
+
+/// Removes the power-state device of a VM, called when the VM is destroyed.
+pub fn remove_vm_power_device(vm_id: usize) {
+    POWER_DEVICES.lock().remove(&vm_id);
+}
+
+/// Returns the host power supply state shared with guests.
+pub fn host_power_supply() -> PowerSupplyState {
+    *HOST_POWER_SUPPLY.lock()
+}
+
+/// Updates the host power supply state and notifies all guests with a power-state device.
+pub fn update_host_power_supply(supply: PowerSupplyState) {
+    *HOST_POWER_SUPPLY.lock() = supply;
+    debug!("Host power supply changed: {:?}", supply);
+
+    let devices: alloc::vec::Vec<_> = POWER_DEVICES.lock().values().cloned().collect();
+    for device in devices {
+        device.raise(PowerEvent::SUPPLY_CHANGED);
+    }
+}
+
+/// Requests the guest of a VM to suspend, shut down, freeze or thaw its filesystems through its
+/// power-state device.
+///
+/// The request is only a notification, it's up to the guest to act on it. Returns a `NotFound`
+/// error if the VM has no power-state device.
+pub fn request_vm_power_event(vm_id: usize, event: PowerEvent) -> AxResult {
+    let device = POWER_DEVICES
+        .lock()
+        .get(&vm_id)
+        .cloned()
+        .ok_or_else(|| ax_err_type!(NotFound, format!("VM[{}] has no power device", vm_id)))?;
+    info!("VM[{}] power request {:?}", vm_id, event);
+    device.raise(event);
+    Ok(())
+}
@@ -0,0 +1,291 @@
+//! PSCI emulation for aarch64 guests.
+//!
+//! Guests boot their secondary cores, idle and power off through PSCI calls. The vCPU backend
+//! either decodes `CPU_ON`, `CPU_OFF` and `SYSTEM_OFF` itself and reports them as `CpuUp`,
+//! `CpuDown` and `SystemDown` exits, or reports the raw call as a hypercall exit with the function
+//! ID in `nr`. Both end up here, so the power state of the vCPUs of a VM is tracked in one place:
+//!
+//! - `CPU_ON` starts the task of a vCPU that was never on, or resumes a vCPU parked by `CPU_OFF`,
+//!   at the given entry point with the context ID in `x0`.
+//! - `CPU_OFF` parks the task of the calling vCPU until it's turned on again or the VM stops.
+//! - `CPU_SUSPEND` is handled as a standby state: the vCPU waits for an interrupt and the call
+//!   returns success, which PSCI allows for power-down states too.
+//! - `SYSTEM_SUSPEND` suspends the VM to RAM if its config lets it, once its other vCPUs are off.
+//!   The calling vCPU resumes at the given entry point with the context ID in `x0` when the VM
+//!   wakes up, see [`crate::vmm::sleep`].
+//! - `SYSTEM_OFF` stops the VM, it then goes to the `Stopped` state once all vCPUs have exited.
+//! - `SYSTEM_RESET` stops the VM as well, what follows is up to its reboot policy, see
+//!   [`crate::vmm::reboot`].
+//!
+//! The SMCCC `SMCCC_VERSION` (1.1) and `SMCCC_ARCH_FEATURES` calls are handled here too, the
+//! latter reports the paravirtualized time calls of [`crate::vmm::guest_time`].
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use axaddrspace::GuestPhysAddr;
+use spin::Mutex;
+
+use crate::vmm::{VMRef, guest_time, sleep, vcpus};
+
+/// Bit of the function ID selecting the SMC64 calling convention.
+const PSCI_SMC64: u64 = 0x4000_0000;
+
+const PSCI_VERSION: u64 = 0x8400_0000;
+const PSCI_CPU_SUSPEND: u64 = 0x8400_0001;
+const PSCI_CPU_OFF: u64 = 0x8400_0002;
+const PSCI_CPU_ON: u64 = 0x8400_0003;
+const PSCI_AFFINITY_INFO: u64 = 0x8400_0004;
+const PSCI_MIGRATE_INFO_TYPE: u64 = 0x8400_0006;
+const PSCI_SYSTEM_OFF: u64 = 0x8400_0008;
+const PSCI_SYSTEM_RESET: u64 = 0x8400_0009;
+const PSCI_FEATURES: u64 = 0x8400_000a;
+const PSCI_SYSTEM_SUSPEND: u64 = 0x8400_000e;
+const SMCCC_VERSION: u64 = 0x8000_0000;
+const SMCCC_ARCH_FEATURES: u64 = 0x8000_0001;
+
+const SMCCC_VERSION_1_1: isize = 0x0001_0001;
+
+/// PSCI 1.0.
+const PSCI_VERSION_1_0: isize = 0x0001_0000;
+/// `MIGRATE_INFO_TYPE`: no Trusted OS, migration is not required.
+const PSCI_TOS_NOT_PRESENT: isize = 2;
+
+const PSCI_SUCCESS: isize = 0;
+const PSCI_NOT_SUPPORTED: isize = -1;
+const PSCI_INVALID_PARAMETERS: isize = -2;
+const PSCI_DENIED: isize = -3;
+const PSCI_ALREADY_ON: isize = -4;
+const PSCI_ON_PENDING: isize = -5;
+
+/// `AFFINITY_INFO` return values.
+const AFFINITY_ON: isize = 0;
+const AFFINITY_OFF: isize = 1;
+const AFFINITY_ON_PENDING: isize = 2;
+
+/// What the vCPU loop has to do after a PSCI call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsciAction {
+    /// Return the value to the guest in `x0`.
+    Return(isize),
+    /// Park the calling vCPU until it's turned on again, see [`resume_pending`].
+    CpuOff,
+    /// Wait for an interrupt, then return success.
+    Suspend,
+    /// Suspend the VM to RAM, then resume the calling vCPU at `entry` with `context_id` in `x0`.
+    SystemSuspend {
+        entry: GuestPhysAddr,
+        context_id: usize,
+    },
+    /// Stop the VM.
+    SystemOff,
+    /// Reboot the VM.
+    SystemReset,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PowerState {
+    /// Never turned on, the vCPU has no task yet.
+    Off,
+    /// Turned off by `CPU_OFF`, the task of the vCPU is parked.
+    Parked,
+    /// Turned on by `CPU_ON` while parked, the task has not resumed yet.
+    OnPending {
+        entry: GuestPhysAddr,
+        context_id: usize,
+    },
+    On,
+}
+
+/// Power states of the vCPUs of all VMs, indexed by VM ID and then by vCPU ID.
+static POWER_STATES: Mutex<BTreeMap<usize, Vec<PowerState>>> = Mutex::new(BTreeMap::new());
+
+/// Runs `f` on the power states of the vCPUs of `vm`. The states are created on first use, with
+/// the primary vCPU on and the others off.
+fn with_states<T>(vm: &VMRef, f: impl FnOnce(&mut Vec<PowerState>) -> T) -> T {
+    let mut states = POWER_STATES.lock();
+    let states = states.entry(vm.id()).or_insert_with(|| {
+        (0..vm.vcpu_num())
+            .map(|id| {
+                if id == 0 {
+                    PowerState::On
+                } else {
+                    PowerState::Off
+                }
+            })
+            .collect()
+    });
+    f(states)
+}
+
+/// Whether `nr` of a hypercall exit is a PSCI or SMCCC architecture function ID.
+pub fn is_psci_call(nr: u64) -> bool {
+    (PSCI_VERSION..=PSCI_VERSION + 0x1f).contains(&(nr & !PSCI_SMC64))
+        || nr == SMCCC_VERSION
+        || nr == SMCCC_ARCH_FEATURES
+}
+
+/// Handles the PSCI call `fid` of the vCPU `vcpu_id` of `vm`.
+pub fn handle_call(vm: &VMRef, vcpu_id: usize, fid: u64, args: [u64; 6]) -> PsciAction {
+    let ret = match fid & !PSCI_SMC64 {
+        SMCCC_VERSION => SMCCC_VERSION_1_1,
+        SMCCC_ARCH_FEATURES if guest_time::has_pv_time_call(vm.id(), args[0]) => PSCI_SUCCESS,
+        SMCCC_ARCH_FEATURES => PSCI_NOT_SUPPORTED,
+        PSCI_VERSION => PSCI_VERSION_1_0,
+        PSCI_CPU_SUSPEND => return PsciAction::Suspend,
+        PSCI_CPU_OFF => return cpu_off(vm, vcpu_id),
+        PSCI_CPU_ON => cpu_on(
+            vm,
+            args[0],
+            GuestPhysAddr::from(args[1] as usize),
+            args[2] as _,
+        ),
+        PSCI_AFFINITY_INFO => affinity_info(vm, args[0], args[1]),
+        PSCI_MIGRATE_INFO_TYPE => PSCI_TOS_NOT_PRESENT,
+        PSCI_SYSTEM_OFF => return PsciAction::SystemOff,
+        PSCI_SYSTEM_RESET => return PsciAction::SystemReset,
+        PSCI_SYSTEM_SUSPEND if sleep::is_enabled(vm.id()) => {
+            match system_suspend(vm, vcpu_id, args[0], args[1]) {
+                Ok(action) => return action,
+                Err(ret) => ret,
+            }
+        }
+        PSCI_FEATURES => match args[0] & !PSCI_SMC64 {
+            PSCI_VERSION
+            | PSCI_CPU_SUSPEND
+            | PSCI_CPU_OFF
+            | PSCI_CPU_ON
+            | PSCI_AFFINITY_INFO
+            | PSCI_MIGRATE_INFO_TYPE
+            | PSCI_SYSTEM_OFF
+            | PSCI_SYSTEM_RESET
+            | PSCI_FEATURES
+            | SMCCC_VERSION => PSCI_SUCCESS,
+            PSCI_SYSTEM_SUSPEND if sleep::is_enabled(vm.id()) => PSCI_SUCCESS,
+            _ => PSCI_NOT_SUPPORTED,
+        },
+        _ => {
+            debug!("VM[{}] unsupported PSCI call {:#x}", vm.id(), fid);
+            PSCI_NOT_SUPPORTED
+        }
+    };
+    PsciAction::Return(ret)
+}
+
+/// Finds the vCPU of `vm` with the affinity `mpidr`.
+fn vcpu_of_mpidr(vm: &VMRef, mpidr: u64) -> Option<usize> {
+    vm.get_vcpu_affinities_pcpu_ids()
+        .iter()
+        .find(|(_, _, phys_id)| *phys_id == mpidr as usize)
+        .map(|(vcpu_id, _, _)| *vcpu_id)
+}
+
+/// Turns on the vCPU with the affinity `mpidr`, returns the PSCI status.
+pub fn cpu_on(vm: &VMRef, mpidr: u64, entry: GuestPhysAddr, context_id: usize) -> isize {
+    let Some(vcpu_id) = vcpu_of_mpidr(vm, mpidr) else {
+        warn!("VM[{}] CPU_ON: no vCPU with affinity {:#x}", vm.id(), mpidr);
+        return PSCI_INVALID_PARAMETERS;
+    };
+    let prev = with_states(vm, |states| {
+        let prev = states[vcpu_id];
+        match prev {
+            PowerState::Off => states[vcpu_id] = PowerState::On,
+            PowerState::Parked => states[vcpu_id] = PowerState::OnPending { entry, context_id },
+            PowerState::OnPending { .. } | PowerState::On => {}
+        }
+        prev
+    });
+    match prev {
+        PowerState::Off => {
+            info!(
+                "VM[{}] CPU_ON vCPU[{}] entry={:?} context={:#x}",
+                vm.id(),
+                vcpu_id,
+                entry,
+                context_id
+            );
+            vcpus::vcpu_on(vm.clone(), vcpu_id, entry, context_id);
+            PSCI_SUCCESS
+        }
+        PowerState::Parked => {
+            info!(
+                "VM[{}] CPU_ON resuming vCPU[{}] entry={:?}",
+                vm.id(),
+                vcpu_id,
+                entry
+            );
+            vcpus::notify_all_vcpus(vm.id());
+            PSCI_SUCCESS
+        }
+        PowerState::OnPending { .. } => PSCI_ON_PENDING,
+        PowerState::On => PSCI_ALREADY_ON,
+    }
+}
+
+/// Turns off the vCPU `vcpu_id` of `vm`, its task has to park until [`resume_pending`].
+pub fn cpu_off(vm: &VMRef, vcpu_id: usize) -> PsciAction {
+    info!("VM[{}] CPU_OFF vCPU[{}]", vm.id(), vcpu_id);
+    with_states(vm, |states| states[vcpu_id] = PowerState::Parked);
+    PsciAction::CpuOff
+}
+
+/// Checks that the vCPU `vcpu_id` of `vm` is the only one on, so that the VM can suspend to RAM.
+fn system_suspend(
+    vm: &VMRef,
+    vcpu_id: usize,
+    entry: u64,
+    context_id: u64,
+) -> Result<PsciAction, isize> {
+    let others_off = with_states(vm, |states| {
+        states.iter().enumerate().all(|(id, state)| {
+            id == vcpu_id || matches!(state, PowerState::Off | PowerState::Parked)
+        })
+    });
+    if !others_off {
+        info!("VM[{}] SYSTEM_SUSPEND denied, other vCPUs are on", vm.id());
+        return Err(PSCI_DENIED);
+    }
+    Ok(PsciAction::SystemSuspend {
+        entry: GuestPhysAddr::from(entry as usize),
+        context_id: context_id as usize,
+    })
+}
+
+fn affinity_info(vm: &VMRef, mpidr: u64, lowest_level: u64) -> isize {
+    if lowest_level != 0 {
+        return PSCI_INVALID_PARAMETERS;
+    }
+    let Some(vcpu_id) = vcpu_of_mpidr(vm, mpidr) else {
+        return PSCI_INVALID_PARAMETERS;
+    };
+    match with_states(vm, |states| states[vcpu_id]) {
+        PowerState::On => AFFINITY_ON,
+        PowerState::OnPending { .. } => AFFINITY_ON_PENDING,
+        PowerState::Off | PowerState::Parked => AFFINITY_OFF,
+    }
+}
+
+/// Whether the parked vCPU `vcpu_id` of the VM `vm_id` has been turned on again.
+pub fn resume_pending(vm_id: usize, vcpu_id: usize) -> bool {
+    POWER_STATES
+        .lock()
+        .get(&vm_id)
+        .is_some_and(|states| matches!(states[vcpu_id], PowerState::OnPending { .. }))
+}
+
+/// Marks the parked vCPU `vcpu_id` of the VM `vm_id` as on, returns its entry point and context ID.
+pub fn resume(vm_id: usize, vcpu_id: usize) -> Option<(GuestPhysAddr, usize)> {
+    let mut states = POWER_STATES.lock();
+    let state = states.get_mut(&vm_id)?.get_mut(vcpu_id)?;
+    match *state {
+        PowerState::OnPending { entry, context_id } => {
+            *state = PowerState::On;
+            Some((entry, context_id))
+        }
+        _ => None,
+    }
+}
+
+/// Forgets the power states of the vCPUs of a VM, called when the VM stops.
+pub fn teardown_vm_psci(vm_id: usize) {
+    POWER_STATES.lock().remove(&vm_id);
+}
@@ -0,0 +1,204 @@
+//! Compatibility with the paravirtualized interfaces of KVM for x86 guests.
+//!
+//! Guest kernels built for KVM make a few hypercalls with `vmcall`, the number in `rax` and the
+//! arguments in `rbx`, `rcx`, `rdx` and `rsi`, which a VM with a `[pv_compat]` section gets
+//! translated onto the subsystems of the hypervisor:
+//!
+//! ```toml
+//! [pv_compat]
+//! # Paravirtualized interface the guest uses, only "kvm" for now.
+//! abi = "kvm"
+//! ```
+//!
+//! - `KVM_HC_SEND_IPI` raises the interrupt in the emulated local APICs of the destinations (see
+//!   [`crate::vmm::x2apic`]), the x2APIC ID being the vCPU ID;
+//! - `KVM_HC_KICK_CPU` wakes the halted vCPUs of the VM;
+//! - `KVM_HC_SCHED_YIELD` yields the CPU of the calling vCPU;
+//! - `KVM_HC_CLOCK_PAIRING` returns the guest time of the VM (see [`crate::vmm::guest_time`])
+//!   with the TSC read at the same time, the TSC of the guest being the one of the host;
+//! - `KVM_HC_VAPIC_POLL_IRQ` does nothing, as on KVM.
+//!
+//! The other KVM hypercalls fail with `-KVM_ENOSYS`. The numbers of the KVM hypercalls shadow the
+//! native hypercalls with the same numbers, the IVC [`HyperCallCode`]s, for the VM; it uses the
+//! fast hypercalls only. The VM doesn't get the KVM signature in its CPUID leaves, which come from
+//! the vCPU backend, so its kernel has to assume KVM (e.g. Linux built with `CONFIG_KVM_GUEST`
+//! and the paravirtualized features forced). The Arm counterpart, the stolen time interface, is
+//! native, see [`crate::vmm::guest_time`].
+//!
+//! [`HyperCallCode`]: axhvc::HyperCallCode
+use alloc::collections::BTreeMap;
+use core::arch::x86_64::_rdtsc;
+use std::os::arceos::modules::axtask;
+
+use axerrno::{AxResult, ax_err};
+use spin::Mutex;
+
+use crate::vmm::{VMRef, guest_time, hvc, vcpus, x2apic};
+
+const KVM_HC_VAPIC_POLL_IRQ: u64 = 1;
+const KVM_HC_KICK_CPU: u64 = 5;
+const KVM_HC_CLOCK_PAIRING: u64 = 9;
+const KVM_HC_SEND_IPI: u64 = 10;
+const KVM_HC_SCHED_YIELD: u64 = 11;
+/// Last hypercall number of KVM.
+const KVM_HC_MAX: u64 = 12;
+
+const KVM_ENOSYS: isize = 1000;
+const KVM_EFAULT: isize = 14;
+const KVM_EOPNOTSUPP: isize = 95;
+
+/// `KVM_CLOCK_PAIRING_WALLCLOCK`, the only clock of `KVM_HC_CLOCK_PAIRING`.
+const KVM_CLOCK_PAIRING_WALLCLOCK: u64 = 0;
+/// Destinations of `KVM_HC_SEND_IPI`, bits of two 64-bit bitmaps.
+const KVM_IPI_DESTS: usize = 128;
+/// Delivery mode bits of the ICR value of `KVM_HC_SEND_IPI`.
+const ICR_DELIVERY_MODE_MASK: u64 = 0x700;
+
+/// `struct kvm_clock_pairing`, written by `KVM_HC_CLOCK_PAIRING`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct KvmClockPairing {
+    sec: i64,
+    nsec: i64,
+    tsc: u64,
+    flags: u32,
+    pad: [u32; 9],
+}
+
+/// Paravirtualized interfaces, in the `abi` key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PvAbi {
+    Kvm,
+}
+
+/// Paravirtualized hypercalls of a VM.
+#[derive(Debug, Clone, Copy)]
+pub struct PvCompatStats {
+    pub abi: PvAbi,
+    /// Hypercalls translated.
+    pub translated: u64,
+    /// Hypercalls the hypervisor doesn't translate.
+    pub unsupported: u64,
+}
+
+/// Paravirtualized interfaces of the VMs with a `[pv_compat]` section, indexed by VM ID.
+static VMS: Mutex<BTreeMap<usize, PvCompatStats>> = Mutex::new(BTreeMap::new());
+
+/// Records the paravirtualized interface described in the `[pv_compat]` section of `raw_cfg` for
+/// the VM.
+///
+/// Does nothing if the VM config has no `[pv_compat]` section.
+pub fn setup_vm_pv_compat(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("pv_compat").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let abi = match cfg.get("abi").and_then(|v| v.as_str()) {
+        Some("kvm") => PvAbi::Kvm,
+        Some(abi) => {
+            return ax_err!(
+                InvalidInput,
+                format!("pv_compat config: unknown ABI `{}`", abi)
+            );
+        }
+        None => return ax_err!(InvalidInput, "pv_compat config: `abi` is required"),
+    };
+    info!("VM[{}] makes {:?} paravirtualized hypercalls", vm.id(), abi);
+    VMS.lock().insert(
+        vm.id(),
+        PvCompatStats {
+            abi,
+            translated: 0,
+            unsupported: 0,
+        },
+    );
+    Ok(())
+}
+
+/// Forgets the paravirtualized interface of the VM, called when the VM is destroyed.
+pub fn teardown_vm_pv_compat(vm_id: usize) {
+    VMS.lock().remove(&vm_id);
+}
+
+/// Whether `nr` of a hypercall exit of VM `vm_id` is a paravirtualized hypercall.
+pub fn is_pv_call(vm_id: usize, nr: u64) -> bool {
+    (1..=KVM_HC_MAX).contains(&nr)
+        && VMS
+            .lock()
+            .get(&vm_id)
+            .is_some_and(|stats| stats.abi == PvAbi::Kvm)
+}
+
+/// Handles the paravirtualized hypercall `nr` of vCPU `vcpu_id` of `vm`, returns the value of
+/// `rax`.
+pub fn handle_call(vm: &VMRef, vcpu_id: usize, nr: u64, args: [u64; 6]) -> isize {
+    let vm_id = vm.id();
+    let ret = match nr {
+        KVM_HC_VAPIC_POLL_IRQ => Ok(0),
+        KVM_HC_KICK_CPU => {
+            vcpus::notify_all_vcpus(vm_id);
+            Ok(0)
+        }
+        KVM_HC_SCHED_YIELD => {
+            axtask::yield_now();
+            Ok(0)
+        }
+        KVM_HC_SEND_IPI => send_ipi(vm_id, args),
+        KVM_HC_CLOCK_PAIRING => clock_pairing(vm, args),
+        _ => Err(-KVM_ENOSYS),
+    };
+    if let Some(stats) = VMS.lock().get_mut(&vm_id) {
+        match ret {
+            Err(err) if err == -KVM_ENOSYS => stats.unsupported += 1,
+            _ => stats.translated += 1,
+        }
+    }
+    ret.unwrap_or_else(|err| {
+        debug!(
+            "VM[{}] VCpu[{}] KVM hypercall {} failed: {}",
+            vm_id, vcpu_id, nr, err
+        );
+        err
+    })
+}
+
+/// `KVM_HC_SEND_IPI`: `args[0..2]` are the bitmap of the destinations from x2APIC ID `args[2]`
+/// and `args[3]` the ICR value. Returns the interrupts delivered.
+fn send_ipi(vm_id: usize, args: [u64; 6]) -> Result<isize, isize> {
+    if args[3] & ICR_DELIVERY_MODE_MASK != 0 {
+        // Only fixed interrupts are sent this way by Linux.
+        return Err(-KVM_EOPNOTSUPP);
+    }
+    let vector = (args[3] & 0xff) as usize;
+    let min = args[2] as usize;
+    let delivered = (0..KVM_IPI_DESTS)
+        .filter(|bit| args[bit / 64] & (1 << (bit % 64)) != 0)
+        .filter(|bit| x2apic::raise(vm_id, min + bit, vector))
+        .count();
+    Ok(delivered as isize)
+}
+
+/// `KVM_HC_CLOCK_PAIRING`: `args[0]` is the GPA of the `struct kvm_clock_pairing` written and
+/// `args[1]` the clock.
+fn clock_pairing(vm: &VMRef, args: [u64; 6]) -> Result<isize, isize> {
+    if args[1] != KVM_CLOCK_PAIRING_WALLCLOCK {
+        return Err(-KVM_EOPNOTSUPP);
+    }
+    let gpa = hvc::guest_ptr::<KvmClockPairing>(vm, args[0]).map_err(|_| -KVM_EFAULT)?;
+    let guest_ns = guest_time::guest_time_ns(vm.id()).ok_or(-KVM_EOPNOTSUPP)?;
+    // SAFETY: reading the TSC has no side effects.
+    let tsc = unsafe { _rdtsc() };
+    let pairing = KvmClockPairing {
+        sec: (guest_ns / 1_000_000_000) as i64,
+        nsec: (guest_ns % 1_000_000_000) as i64,
+        tsc,
+        flags: 0,
+        pad: [0; 9],
+    };
+    vm.write_to_guest_of(gpa, &pairing).map_err(|_| -KVM_EFAULT)?;
+    Ok(0)
+}
+
+/// Paravirtualized hypercalls of VM `vm_id`, if it has a `[pv_compat]` section.
+pub fn stats(vm_id: usize) -> Option<PvCompatStats> {
+    VMS.lock().get(&vm_id).copied()
+}
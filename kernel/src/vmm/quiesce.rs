@@ -0,0 +1,213 @@
+//! Filesystem quiesce of guests, for application-consistent snapshots.
+//!
+//! A snapshot of a running guest (e.g. a core dump, see [`crate::vmm::coredump`]) is only
+//! crash-consistent: what the guest applications wrote may still sit in the guest page cache or
+//! be half-written to the disk. A guest running a quiesce agent can be asked to flush and freeze
+//! its filesystems first, and to thaw them once the snapshot is taken.
+//!
+//! The agent contract:
+//!
+//! 1. The agent announces itself with the [`HVC_FS_QUIESCE`] hypercall, operation
+//!    [`QUIESCE_AGENT_READY`], and enables the freeze and thaw events of the power-state device of
+//!    the VM (see [`crate::vmm::power`]), which the VM must have.
+//! 2. On [`PowerEvent::FREEZE_REQUEST`], the agent flushes and freezes the filesystems (e.g. with
+//!    `FIFREEZE`), then reports [`QUIESCE_FROZEN`] with `args[1]` 0, or the error it got if it
+//!    failed, in which case it thaws whatever it froze.
+//! 3. On [`PowerEvent::THAW_REQUEST`], the agent thaws the filesystems and reports
+//!    [`QUIESCE_THAWED`]. It also thaws them on its own, and reports it, if no thaw request comes
+//!    within a minute, so that the guest isn't stuck if the hypervisor side gives up.
+//! 4. An agent stopping reports [`QUIESCE_AGENT_GONE`].
+//!
+//! The hypervisor side is [`freeze`] and [`thaw`]: freezing a guest without an agent fails with
+//! `Unsupported`, so that the caller can fall back to a crash-consistent snapshot.
+use alloc::collections::BTreeMap;
+use core::time::Duration;
+
+use std::os::arceos::modules::axhal;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use spin::Mutex;
+
+use crate::vmm::VMRef;
+use crate::vmm::power::{self, PowerEvent};
+
+/// The agent of the caller is ready to serve freeze and thaw requests.
+pub const QUIESCE_AGENT_READY: u64 = 0;
+/// The filesystems of the caller are frozen, or failed to, with the error in `args[1]`.
+pub const QUIESCE_FROZEN: u64 = 1;
+/// The filesystems of the caller are thawed.
+pub const QUIESCE_THAWED: u64 = 2;
+/// The agent of the caller stopped.
+pub const QUIESCE_AGENT_GONE: u64 = 3;
+
+/// Default time the agent has to answer a request.
+pub const DEFAULT_QUIESCE_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// State of the filesystems of a guest with an agent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuiesceState {
+    Thawed,
+    /// A freeze request is pending.
+    Freezing,
+    Frozen,
+    /// The last freeze request failed with this error of the agent.
+    Failed(u64),
+    /// A thaw request is pending.
+    Thawing,
+}
+
+/// States of the guests with an agent, indexed by VM ID.
+static AGENTS: Mutex<BTreeMap<usize, QuiesceState>> = Mutex::new(BTreeMap::new());
+
+/// Handles the [`HVC_FS_QUIESCE`] hypercall of a VM.
+///
+/// [`HVC_FS_QUIESCE`]: crate::vmm::hvc::HVC_FS_QUIESCE
+pub fn handle_hypercall(vm: &VMRef, args: [u64; 6]) -> AxResult {
+    let vm_id = vm.id();
+    let mut agents = AGENTS.lock();
+    match args[0] {
+        QUIESCE_AGENT_READY => {
+            if agents.insert(vm_id, QuiesceState::Thawed).is_none() {
+                info!("VM[{}] quiesce agent ready", vm_id);
+            }
+            Ok(())
+        }
+        QUIESCE_AGENT_GONE => {
+            agents.remove(&vm_id);
+            info!("VM[{}] quiesce agent gone", vm_id);
+            Ok(())
+        }
+        op @ (QUIESCE_FROZEN | QUIESCE_THAWED) => {
+            let Some(agent) = agents.get_mut(&vm_id) else {
+                return ax_err!(BadState, "no quiesce agent announced");
+            };
+            let state = match (op, args[1]) {
+                (QUIESCE_FROZEN, 0) => QuiesceState::Frozen,
+                (QUIESCE_FROZEN, err) => QuiesceState::Failed(err),
+                _ => QuiesceState::Thawed,
+            };
+            if op == QUIESCE_THAWED && *agent == QuiesceState::Frozen {
+                warn!("VM[{}] filesystems thawed by the guest", vm_id);
+            }
+            *agent = state;
+            Ok(())
+        }
+        op => ax_err!(InvalidInput, format!("invalid quiesce operation {}", op)),
+    }
+}
+
+/// Returns the quiesce state of VM `vm_id`, `None` if it has no agent.
+pub fn state(vm_id: usize) -> Option<QuiesceState> {
+    AGENTS.lock().get(&vm_id).copied()
+}
+
+/// Sets the state of the agent of VM `vm_id` to `to` if `from` accepts it, returns the state
+/// found.
+fn transition(
+    vm_id: usize,
+    from: impl Fn(QuiesceState) -> bool,
+    to: QuiesceState,
+) -> AxResult<QuiesceState> {
+    let mut agents = AGENTS.lock();
+    let agent = agents
+        .get_mut(&vm_id)
+        .ok_or_else(|| ax_err_type!(Unsupported, format!("VM[{}] has no quiesce agent", vm_id)))?;
+    let found = *agent;
+    if from(found) {
+        *agent = to;
+    }
+    Ok(found)
+}
+
+/// Waits up to `timeout` for the agent of VM `vm_id` to leave `pending`, returns its new state.
+fn wait_answer(vm_id: usize, pending: QuiesceState, timeout: Duration) -> Option<QuiesceState> {
+    let deadline = axhal::time::monotonic_time_nanos() + timeout.as_nanos() as u64;
+    loop {
+        // A gone agent won't answer.
+        let state = state(vm_id).unwrap_or(QuiesceState::Thawed);
+        if state != pending {
+            return Some(state);
+        }
+        if axhal::time::monotonic_time_nanos() >= deadline {
+            return None;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Asks the guest of VM `vm_id` to flush and freeze its filesystems, and waits up to `timeout`
+/// for them to be frozen.
+///
+/// On success, the caller must [`thaw`] them once done. Fails with `Unsupported` if the guest has
+/// no agent, `ResourceBusy` if a request is pending or the filesystems are already frozen,
+/// `TimedOut` if the agent doesn't answer in time (the filesystems are thawed again) and `Io` if
+/// the agent failed to freeze them.
+pub fn freeze(vm_id: usize, timeout: Duration) -> AxResult {
+    let idle = |state| matches!(state, QuiesceState::Thawed | QuiesceState::Failed(_));
+    let found = transition(vm_id, idle, QuiesceState::Freezing)?;
+    if !idle(found) {
+        return ax_err!(
+            ResourceBusy,
+            format!("VM[{}] filesystems are {:?}", vm_id, found)
+        );
+    }
+
+    if let Err(e) = power::request_vm_power_event(vm_id, PowerEvent::FREEZE_REQUEST) {
+        transition(
+            vm_id,
+            |state| state == QuiesceState::Freezing,
+            QuiesceState::Thawed,
+        )?;
+        return Err(e);
+    }
+    match wait_answer(vm_id, QuiesceState::Freezing, timeout) {
+        Some(QuiesceState::Frozen) => {
+            info!("VM[{}] filesystems frozen", vm_id);
+            Ok(())
+        }
+        Some(QuiesceState::Failed(err)) => ax_err!(
+            Io,
+            format!("VM[{}] failed to freeze its filesystems: {}", vm_id, err)
+        ),
+        Some(state) => ax_err!(
+            BadState,
+            format!("VM[{}] filesystems {:?} while freezing", vm_id, state)
+        ),
+        None => {
+            warn!("VM[{}] quiesce agent didn't freeze in {:?}", vm_id, timeout);
+            // It may still freeze them, undo it.
+            let _ = thaw(vm_id, timeout);
+            ax_err!(
+                TimedOut,
+                format!("VM[{}] quiesce agent didn't answer", vm_id)
+            )
+        }
+    }
+}
+
+/// Asks the guest of VM `vm_id` to thaw its filesystems, and waits up to `timeout` for them to be
+/// thawed.
+pub fn thaw(vm_id: usize, timeout: Duration) -> AxResult {
+    let frozen = |state| matches!(state, QuiesceState::Frozen | QuiesceState::Freezing);
+    let found = transition(vm_id, frozen, QuiesceState::Thawing)?;
+    if !frozen(found) {
+        return Ok(());
+    }
+    power::request_vm_power_event(vm_id, PowerEvent::THAW_REQUEST)?;
+    match wait_answer(vm_id, QuiesceState::Thawing, timeout) {
+        Some(_) => {
+            info!("VM[{}] filesystems thawed", vm_id);
+            Ok(())
+        }
+        None => ax_err!(
+            TimedOut,
+            format!("VM[{}] quiesce agent didn't thaw in {:?}", vm_id, timeout)
+        ),
+    }
+}
+
+/// Forgets the agent of a VM, called when the VM is destroyed.
+pub fn teardown_vm_quiesce(vm_id: usize) {
+    AGENTS.lock().remove(&vm_id);
+}
@@ -0,0 +1,244 @@
+//! Guest-initiated power-off and reboot.
+//!
+//! A guest powers itself off or reboots through PSCI `SYSTEM_OFF`/`SYSTEM_RESET` on aarch64, the
+//! ACPI sleep and reset registers on x86 (see [`crate::vmm::acpi`]), or whatever the vCPU backend
+//! reports as a system-down exit. On x86, a failed VM entry of a VM without a crash policy (see
+//! [`crate::vmm::crash`]), which is how the vCPU backend reports a triple fault, is a reboot too,
+//! as on real hardware. What happens then is the policy of the VM:
+//!
+//! ```toml
+//! [reboot]
+//! # What a guest reboot does: "restart" (the default), "halt", "destroy" or "notify-manager".
+//! on_reboot = "restart"
+//! # What a guest power-off does, "halt" by default.
+//! on_poweroff = "destroy"
+//! # Peer handle of the manager VM (see `crate::vmm::peers`) and the interrupt raised in it, for
+//! # "notify-manager".
+//! manager = 0
+//! manager_irq = 0x34
+//! ```
+//!
+//! - "restart" stops the VM, destroys it and creates it again from its config before booting it,
+//!   so that it starts over with fresh memory, devices and IVC channels, as a cold boot would.
+//! - "halt" stops the VM, which can be started again with `vm start`.
+//! - "destroy" stops and destroys the VM, like `vm delete`.
+//! - "notify-manager" stops the VM and interrupts the manager VM once it's stopped, which decides
+//!   what's next, e.g. redefining the VM to boot it again (see [`crate::vmm::vmdef`]).
+//!
+//! Without a `[reboot]` section both halt the VM. Every request is also appended to the lifecycle
+//! log as a [`LifecycleEvent::PowerRequest`], whose detail is the [`PowerRequest`]. VMs are not
+//! restarted during a system shutdown (see [`crate::vmm::shutdown`]).
+use alloc::collections::BTreeMap;
+use std::os::arceos::api::task::ax_wait_queue_wake;
+use std::thread;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use axvm::config::AxVMCrateConfig;
+use spin::Mutex;
+
+use crate::vmm::irq::IrqLine;
+use crate::vmm::lifecycle::{self, LifecycleEvent};
+use crate::vmm::{VMRef, config, peers, sub_running_vm_count, vcpus, vm_list, vmdef};
+
+/// A power request of a guest, the detail of [`LifecycleEvent::PowerRequest`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum PowerRequest {
+    PowerOff = 1,
+    Reboot = 2,
+}
+
+/// What a power request of a guest does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebootAction {
+    Restart,
+    Halt,
+    Destroy,
+    NotifyManager,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RebootPolicy {
+    on_reboot: RebootAction,
+    on_poweroff: RebootAction,
+    /// Peer handle of the manager VM and its interrupt.
+    manager: Option<(usize, usize)>,
+}
+
+struct VmReboot {
+    policy: RebootPolicy,
+    /// The config the VM was created from, to create it again on restart.
+    config: AxVMCrateConfig,
+    raw_cfg: toml::Table,
+}
+
+/// Reboot policies of the VMs with a `[reboot]` section, indexed by VM ID.
+static POLICIES: Mutex<BTreeMap<usize, VmReboot>> = Mutex::new(BTreeMap::new());
+/// Actions to carry out once the VMs are stopped, indexed by VM ID.
+static PENDING: Mutex<BTreeMap<usize, RebootAction>> = Mutex::new(BTreeMap::new());
+
+fn parse_action(cfg: &toml::Table, key: &str, default: RebootAction) -> AxResult<RebootAction> {
+    match cfg.get(key).map(|v| v.as_str()) {
+        None => Ok(default),
+        Some(Some("restart")) => Ok(RebootAction::Restart),
+        Some(Some("halt")) => Ok(RebootAction::Halt),
+        Some(Some("destroy")) => Ok(RebootAction::Destroy),
+        Some(Some("notify-manager")) => Ok(RebootAction::NotifyManager),
+        Some(_) => ax_err!(
+            InvalidInput,
+            format!("reboot config: unsupported `{}` action", key)
+        ),
+    }
+}
+
+/// Records the reboot policy described in the `[reboot]` section of `raw_cfg` for the VM, created
+/// from `config`.
+///
+/// Does nothing if the VM config has no `[reboot]` section.
+pub fn setup_vm_reboot(vm: &VMRef, config: &AxVMCrateConfig, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("reboot").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let get = |key: &str| {
+        cfg.get(key)
+            .and_then(|v| v.as_integer())
+            .map(|v| v as usize)
+    };
+    let manager = match (get("manager"), get("manager_irq")) {
+        (Some(manager), Some(irq)) => Some((manager, irq)),
+        (None, None) => None,
+        _ => {
+            return ax_err!(
+                InvalidInput,
+                "reboot config: `manager` and `manager_irq` go together"
+            );
+        }
+    };
+    let policy = RebootPolicy {
+        on_reboot: parse_action(cfg, "on_reboot", RebootAction::Restart)?,
+        on_poweroff: parse_action(cfg, "on_poweroff", RebootAction::Halt)?,
+        manager,
+    };
+    if manager.is_none()
+        && (policy.on_reboot == RebootAction::NotifyManager
+            || policy.on_poweroff == RebootAction::NotifyManager)
+    {
+        return ax_err!(
+            InvalidInput,
+            "reboot config: \"notify-manager\" needs a `manager`"
+        );
+    }
+
+    info!("VM[{}] reboot policy: {:?}", vm.id(), policy);
+    POLICIES.lock().insert(
+        vm.id(),
+        VmReboot {
+            policy,
+            config: config.clone(),
+            raw_cfg: raw_cfg.clone(),
+        },
+    );
+    Ok(())
+}
+
+/// Forgets the reboot policy of a VM, called when the VM is destroyed.
+pub fn teardown_vm_reboot(vm_id: usize) {
+    POLICIES.lock().remove(&vm_id);
+    PENDING.lock().remove(&vm_id);
+}
+
+/// Returns the action of VM `vm_id` for `request`.
+pub fn action(vm_id: usize, request: PowerRequest) -> RebootAction {
+    POLICIES
+        .lock()
+        .get(&vm_id)
+        .map_or(RebootAction::Halt, |reboot| match request {
+            PowerRequest::PowerOff => reboot.policy.on_poweroff,
+            PowerRequest::Reboot => reboot.policy.on_reboot,
+        })
+}
+
+/// Handles the power request of `vm`: stops the VM, the rest of the action is carried out once
+/// it's stopped, see [`on_vm_stopped`].
+pub fn on_guest_request(vm: &VMRef, request: PowerRequest) {
+    let vm_id = vm.id();
+    lifecycle::emit(vm_id, LifecycleEvent::PowerRequest, request as u32);
+    let action = action(vm_id, request);
+    warn!("VM[{}] guest {:?}, action {:?}", vm_id, request, action);
+    if action != RebootAction::Halt {
+        PENDING.lock().insert(vm_id, action);
+    }
+    if let Err(e) = vm.shutdown() {
+        PENDING.lock().remove(&vm_id);
+        error!("VM[{}] failed to stop: {:?}", vm_id, e);
+    }
+    vcpus::notify_all_vcpus(vm_id);
+}
+
+/// Carries out the pending action of the VM, called by its last vCPU task when the VM reaches the
+/// `Stopped` state. Returns true if the VM is restarting, in which case it still counts as
+/// running.
+pub fn on_vm_stopped(vm: &VMRef) -> bool {
+    let Some(action) = PENDING.lock().remove(&vm.id()) else {
+        return false;
+    };
+    if super::shutdown::in_progress() {
+        return false;
+    }
+    let vm = vm.clone();
+    match action {
+        RebootAction::Restart => {
+            thread::spawn(move || {
+                if let Err(e) = restart_vm(vm) {
+                    error!("VM restart failed: {:?}", e);
+                    sub_running_vm_count(1);
+                    ax_wait_queue_wake(&super::VMM, 1);
+                }
+            });
+            return true;
+        }
+        RebootAction::Destroy => {
+            thread::spawn(move || {
+                info!("VM[{}] destroyed after a guest request", vm.id());
+                vmdef::destroy_vm(vm);
+            });
+        }
+        RebootAction::NotifyManager => notify_manager(&vm),
+        RebootAction::Halt => {}
+    }
+    false
+}
+
+fn notify_manager(vm: &VMRef) {
+    let Some((manager, irq)) = POLICIES
+        .lock()
+        .get(&vm.id())
+        .and_then(|reboot| reboot.policy.manager)
+    else {
+        return;
+    };
+    match peers::resolve(vm.id(), manager) {
+        Ok(manager_vm_id) => IrqLine::new(manager_vm_id, irq).raise(),
+        Err(e) => warn!("VM[{}] reboot: manager not found: {:?}", vm.id(), e),
+    }
+}
+
+/// Destroys the stopped `vm` and creates it again from its config, then boots it.
+fn restart_vm(vm: VMRef) -> AxResult {
+    let vm_id = vm.id();
+    let (config, raw_cfg) = POLICIES
+        .lock()
+        .get(&vm_id)
+        .map(|reboot| (reboot.config.clone(), reboot.raw_cfg.clone()))
+        .ok_or_else(|| ax_err_type!(NotFound, format!("VM[{}] has no reboot policy", vm_id)))?;
+    info!("VM[{}] restarting from its config", vm_id);
+
+    vmdef::destroy_vm(vm);
+    config::init_guest_vm_instance(config, &raw_cfg)?;
+    let vm = vm_list::get_vm_by_id(vm_id)
+        .ok_or_else(|| ax_err_type!(NotFound, format!("VM[{}] was not created", vm_id)))?;
+    vcpus::setup_vm_primary_vcpu(vm.clone());
+    vm.boot()?;
+    vcpus::notify_primary_vcpu(vm_id);
+    Ok(())
+}
@@ -0,0 +1,243 @@
+//! Memory reclaim when the hypervisor runs low on memory.
+//!
+//! Allocations made on behalf of guests (e.g., the pages of IVC channels) don't fail right away
+//! when the host is out of memory: [`alloc_or_reclaim`] runs the reclaim steps in the configured
+//! order, stopping as soon as one frees something, and retries the allocation once. The steps
+//! are:
+//!
+//! - [`ReclaimStep::Compress`]: compress the idle pages of VMs;
+//! - [`ReclaimStep::Caches`]: shrink the caches of the hypervisor, e.g. the DTBs generated for
+//!   VMs whose images are loaded already;
+//! - [`ReclaimStep::Balloon`]: take pages back from the balloons of VMs.
+//!
+//! The order is `compress,caches,balloon` by default and can be changed with the `reclaim` shell
+//! command. Steps taking memory from VMs go through them by increasing priority, set in their
+//! config:
+//!
+//! ```toml
+//! [reclaim]
+//! # 0 to 255, VMs with the lowest priority are reclaimed from first. 128 by default.
+//! priority = 32
+//! ```
+//!
+//! Every action taken is logged and kept in a ring, see [`actions`].
+//!
+//! Guest memory is mapped to the VMs as whole regions and there is no balloon device yet, so the
+//! steps taking memory from VMs find nothing to reclaim for now. Caches live in the heap, so what
+//! they free serves the next heap allocations of the hypervisor rather than page allocations.
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use std::os::arceos::modules::axhal;
+
+use axerrno::{AxResult, ax_err};
+use spin::Mutex;
+
+use crate::vmm::VMRef;
+
+const DEFAULT_PRIORITY: u8 = 128;
+const MAX_ACTIONS: usize = 32;
+
+/// A reclaim step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReclaimStep {
+    Compress,
+    Caches,
+    Balloon,
+}
+
+impl ReclaimStep {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Compress => "compress",
+            Self::Caches => "caches",
+            Self::Balloon => "balloon",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "compress" => Some(Self::Compress),
+            "caches" => Some(Self::Caches),
+            "balloon" => Some(Self::Balloon),
+            _ => None,
+        }
+    }
+}
+
+/// A reclaim action taken.
+#[derive(Debug, Clone, Copy)]
+pub struct ReclaimAction {
+    pub time_ns: u64,
+    pub step: ReclaimStep,
+    /// The VM memory was taken from, `None` for the hypervisor's own memory.
+    pub vm_id: Option<usize>,
+    pub bytes: usize,
+}
+
+static ORDER: Mutex<Vec<ReclaimStep>> = Mutex::new(Vec::new());
+/// Reclaim priorities of the VMs with a `[reclaim]` section.
+static PRIORITIES: Mutex<BTreeMap<usize, u8>> = Mutex::new(BTreeMap::new());
+static ACTIONS: Mutex<VecDeque<ReclaimAction>> = Mutex::new(VecDeque::new());
+/// Times the reclaim ran, and ran without freeing anything.
+static RUNS: AtomicU64 = AtomicU64::new(0);
+static FAILED_RUNS: AtomicU64 = AtomicU64::new(0);
+
+/// Records the reclaim priority described in the `[reclaim]` section of `raw_cfg` for the VM.
+///
+/// Does nothing if the VM config has no `[reclaim]` section.
+pub fn setup_vm_reclaim(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("reclaim").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let priority = match cfg.get("priority") {
+        None => DEFAULT_PRIORITY,
+        Some(v) => match v.as_integer().and_then(|v| u8::try_from(v).ok()) {
+            Some(priority) => priority,
+            None => {
+                return ax_err!(
+                    InvalidInput,
+                    "reclaim config: `priority` must be between 0 and 255"
+                );
+            }
+        },
+    };
+    PRIORITIES.lock().insert(vm.id(), priority);
+    Ok(())
+}
+
+/// Forgets the reclaim priority of a VM, called when the VM is destroyed.
+pub fn teardown_vm_reclaim(vm_id: usize) {
+    PRIORITIES.lock().remove(&vm_id);
+}
+
+/// Returns the reclaim priority of VM `vm_id`.
+pub fn priority(vm_id: usize) -> u8 {
+    PRIORITIES
+        .lock()
+        .get(&vm_id)
+        .copied()
+        .unwrap_or(DEFAULT_PRIORITY)
+}
+
+/// Returns the order of the reclaim steps.
+pub fn order() -> Vec<ReclaimStep> {
+    let order = ORDER.lock();
+    if order.is_empty() {
+        alloc::vec![
+            ReclaimStep::Compress,
+            ReclaimStep::Caches,
+            ReclaimStep::Balloon
+        ]
+    } else {
+        order.clone()
+    }
+}
+
+/// Sets the order of the reclaim steps from a comma-separated list of step names. Steps left out
+/// are not run.
+pub fn set_order(names: &str) -> AxResult {
+    let mut order = Vec::new();
+    for name in names.split(',').map(str::trim) {
+        let Some(step) = ReclaimStep::from_name(name) else {
+            return ax_err!(InvalidInput, format!("unknown reclaim step `{}`", name));
+        };
+        if order.contains(&step) {
+            return ax_err!(InvalidInput, format!("reclaim step `{}` given twice", name));
+        }
+        order.push(step);
+    }
+    if order.is_empty() {
+        return ax_err!(InvalidInput, "no reclaim step given");
+    }
+    *ORDER.lock() = order;
+    Ok(())
+}
+
+fn log_action(step: ReclaimStep, vm_id: Option<usize>, bytes: usize) {
+    match vm_id {
+        Some(vm_id) => info!(
+            "reclaim: {} freed {} bytes of VM[{}]",
+            step.name(),
+            bytes,
+            vm_id
+        ),
+        None => info!("reclaim: {} freed {} bytes", step.name(), bytes),
+    }
+    let mut actions = ACTIONS.lock();
+    if actions.len() == MAX_ACTIONS {
+        actions.pop_front();
+    }
+    actions.push_back(ReclaimAction {
+        time_ns: axhal::time::monotonic_time_nanos(),
+        step,
+        vm_id,
+        bytes,
+    });
+}
+
+/// VM IDs by increasing reclaim priority.
+fn vms_by_priority() -> Vec<usize> {
+    let mut vms: Vec<_> = super::vm_list::get_vm_list()
+        .iter()
+        .map(|vm| (priority(vm.id()), vm.id()))
+        .collect();
+    vms.sort_unstable();
+    vms.into_iter().map(|(_, vm_id)| vm_id).collect()
+}
+
+/// Runs `step`, returns the bytes freed.
+fn run_step(step: ReclaimStep) -> usize {
+    match step {
+        ReclaimStep::Caches => {
+            #[cfg(target_arch = "aarch64")]
+            let freed = super::fdt::shrink_dtb_cache();
+            #[cfg(not(target_arch = "aarch64"))]
+            let freed = 0;
+            if freed > 0 {
+                log_action(step, None, freed);
+            }
+            freed
+        }
+        ReclaimStep::Compress | ReclaimStep::Balloon => {
+            // Guest memory can neither be compressed nor ballooned yet, see the module docs.
+            let vms = vms_by_priority();
+            debug!(
+                "reclaim: {} has nothing to take from VMs {:?}",
+                step.name(),
+                vms
+            );
+            0
+        }
+    }
+}
+
+/// Runs the reclaim steps in order until one frees memory, returns the bytes freed.
+pub fn reclaim() -> usize {
+    RUNS.fetch_add(1, Ordering::Relaxed);
+    for step in order() {
+        let freed = run_step(step);
+        if freed > 0 {
+            return freed;
+        }
+    }
+    FAILED_RUNS.fetch_add(1, Ordering::Relaxed);
+    warn!("reclaim: no memory could be freed");
+    0
+}
+
+/// Calls `alloc`, and again once after reclaiming memory if it fails.
+pub fn alloc_or_reclaim<T>(mut alloc: impl FnMut() -> Option<T>) -> Option<T> {
+    alloc().or_else(|| if reclaim() > 0 { alloc() } else { None })
+}
+
+/// Returns the reclaim actions taken, oldest first, and the number of reclaim runs and of runs
+/// which freed nothing.
+pub fn actions() -> (Vec<ReclaimAction>, u64, u64) {
+    (
+        ACTIONS.lock().iter().copied().collect(),
+        RUNS.load(Ordering::Relaxed),
+        FAILED_RUNS.load(Ordering::Relaxed),
+    )
+}
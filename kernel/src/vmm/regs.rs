@@ -0,0 +1,162 @@
+//! Register snapshots of the vCPUs.
+//!
+//! The registers of a vCPU can only be read by its own task, right after it exits: on x86_64 part
+//! of them live in the VMCS, which is loaded on one CPU at a time, and on aarch64 the stack pointer
+//! of EL1 stays in the CPU until another vCPU enters. So the vCPU task takes a snapshot of its
+//! registers (see [`crate::hal::arch::guest_regs`]) right after an exit:
+//!
+//! - when one was requested, with [`request`] or [`capture`], which kick the vCPU out of the guest
+//!   (see [`vcpus::kick`]);
+//! - when it halts or its VM is suspending, so that the halted vCPUs and the vCPUs of a suspended
+//!   VM have the registers they stopped with;
+//! - when its run failed, for the crash report, see [`crate::vmm::crash`].
+//!
+//! The last snapshot of a vCPU is kept in its task, until the VM is destroyed. It is current,
+//! i.e. holds the registers the vCPU has now, until the vCPU exits again without taking one, see
+//! [`current`]. The registers are
+//! in the order of the `user_regs_struct` of the ELF core files of the architecture, which the
+//! core dumps (see [`crate::vmm::coredump`]) and the introspection hypercalls (see
+//! [`crate::vmm::introspect`]) pass on as is. The riscv64 backend doesn't expose them.
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use std::os::arceos::modules::{axhal, axtask};
+use std::thread;
+
+use axvm::VMStatus;
+use spin::Mutex;
+
+use crate::task::AsVCpuTask;
+use crate::vmm::{VCpuRef, VMRef, vcpus};
+
+pub use crate::hal::arch::{GUEST_REGS, GuestRegs};
+
+/// Registers of the largest register file of the supported architectures, the size of the
+/// register files of the guest ABI.
+pub const MAX_GUEST_REGS: usize = 34;
+
+/// Whether the vCPU backend exposes the registers of the guest.
+const SUPPORTED: bool = !cfg!(target_arch = "riscv64");
+
+/// How often [`capture`] kicks a vCPU which didn't exit yet.
+const CAPTURE_POLL: Duration = Duration::from_micros(100);
+
+/// The registers of a vCPU at some point.
+#[derive(Debug, Clone, Copy)]
+pub struct RegsSnapshot {
+    pub regs: GuestRegs,
+    /// When the snapshot was taken, in nanoseconds of monotonic time.
+    pub taken_ns: u64,
+}
+
+/// The register snapshots of a vCPU, in its task.
+pub struct VCpuRegs {
+    requested: AtomicBool,
+    /// Whether `last` was taken at the last exit of the vCPU.
+    current: AtomicBool,
+    last: Mutex<Option<RegsSnapshot>>,
+}
+
+impl VCpuRegs {
+    pub const fn new() -> Self {
+        Self {
+            requested: AtomicBool::new(false),
+            current: AtomicBool::new(false),
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Takes a snapshot of the registers of `vcpu`, which just exited on this CPU, if one was
+    /// requested or `force`.
+    pub fn on_exit(&self, vcpu: &VCpuRef, force: bool) {
+        let requested = self.requested.swap(false, Ordering::Relaxed);
+        if force || requested {
+            self.take(vcpu);
+        } else {
+            self.current.store(false, Ordering::Relaxed);
+        }
+    }
+
+    fn take(&self, vcpu: &VCpuRef) -> Option<RegsSnapshot> {
+        let snapshot = RegsSnapshot {
+            regs: crate::hal::arch::guest_regs(vcpu)?,
+            taken_ns: axhal::time::monotonic_time_nanos(),
+        };
+        *self.last.lock() = Some(snapshot);
+        self.current.store(true, Ordering::Relaxed);
+        Some(snapshot)
+    }
+}
+
+fn with_regs<T>(vm_id: usize, vcpu_id: usize, f: impl FnOnce(&VCpuRegs) -> T) -> Option<T> {
+    vcpus::with_vcpu_task(vm_id, vcpu_id, |task| f(&task.as_vcpu_task().regs))
+}
+
+/// Returns the last snapshot of the registers of vCPU `vcpu_id` of VM `vm_id`.
+pub fn last(vm_id: usize, vcpu_id: usize) -> Option<RegsSnapshot> {
+    with_regs(vm_id, vcpu_id, |regs| *regs.last.lock()).flatten()
+}
+
+/// Returns the snapshot of the registers of vCPU `vcpu_id` of VM `vm_id` taken at its last exit,
+/// `None` if it took none then. Only meaningful for a vCPU out of the guest, e.g. halted or of a
+/// suspended VM.
+pub fn current(vm_id: usize, vcpu_id: usize) -> Option<RegsSnapshot> {
+    with_regs(vm_id, vcpu_id, |regs| {
+        regs.current
+            .load(Ordering::Relaxed)
+            .then(|| *regs.last.lock())
+            .flatten()
+    })
+    .flatten()
+}
+
+/// Requests a snapshot of the registers of vCPU `vcpu_id` of VM `vm_id`, taken on its next exit,
+/// and kicks it out of the guest. Doesn't wait, see [`last`].
+pub fn request(vm_id: usize, vcpu_id: usize) {
+    if SUPPORTED
+        && with_regs(vm_id, vcpu_id, |regs| {
+            regs.requested.store(true, Ordering::Relaxed)
+        })
+        .is_some()
+    {
+        vcpus::kick(vm_id, vcpu_id);
+    }
+}
+
+/// Takes a snapshot of the registers of vCPU `vcpu_id` of `vm`, `None` if the backend doesn't
+/// expose them or the vCPU didn't exit within `timeout`.
+///
+/// The vCPU is kicked out of the guest until it exits. The vCPUs of a suspended VM don't run, their
+/// snapshot is the one they took when they stopped (see [`current`]), and a vCPU which took none
+/// then only takes one if it exits again. A vCPU reading its own registers in a hypercall takes
+/// the snapshot right away.
+pub fn capture(vm: &VMRef, vcpu_id: usize, timeout: Duration) -> Option<RegsSnapshot> {
+    if !SUPPORTED {
+        return None;
+    }
+    let vm_id = vm.id();
+    let current = axtask::current().id().as_u64();
+    if vcpus::with_vcpu_task(vm_id, vcpu_id, |task| task.id().as_u64() == current)? {
+        let vcpu = vm.vcpu_list().get(vcpu_id)?.clone();
+        return with_regs(vm_id, vcpu_id, |regs| regs.take(&vcpu)).flatten();
+    }
+
+    let since = axhal::time::monotonic_time_nanos();
+    with_regs(vm_id, vcpu_id, |regs| {
+        regs.requested.store(true, Ordering::Relaxed)
+    })?;
+    loop {
+        if vm.vm_status() == VMStatus::Suspended
+            && let Some(snapshot) = current(vm_id, vcpu_id)
+        {
+            return Some(snapshot);
+        }
+        if let Some(snapshot) = last(vm_id, vcpu_id).filter(|snapshot| snapshot.taken_ns >= since) {
+            return Some(snapshot);
+        }
+        if axhal::time::monotonic_time_nanos() - since >= timeout.as_nanos() as u64 {
+            return None;
+        }
+        vcpus::kick(vm_id, vcpu_id);
+        thread::sleep(CAPTURE_POLL);
+    }
+}
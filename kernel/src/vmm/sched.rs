@@ -0,0 +1,679 @@
+//! Scheduling modes of VMs.
+//!
+//! The vCPUs of a VM share the physical CPUs with the other vCPUs and host tasks by default: a
+//! halted vCPU blocks and its CPU runs other tasks until the vCPU is woken up. Hard real-time
+//! guests can't tolerate that jitter and are partitioned instead:
+//!
+//! ```toml
+//! [scheduling]
+//! # "shared" (the default), "partitioned" or "gang".
+//! mode = "partitioned"
+//! ```
+//!
+//! Each vCPU of a partitioned VM is pinned to its own physical CPU, given by a single-CPU mask in
+//! the `[affinity]` section or by `phys_cpu_ids`, and the CPUs are dedicated to the VM (see
+//! [`crate::vmm::affinity`]). A halted vCPU blocks as in a shared VM (see [`crate::vmm::idle`]),
+//! but its CPU then idles: the hypervisor never schedules anything else in its place, and the vCPU
+//! enters the guest again as soon as it's woken up.
+//!
+//! The host timer interrupt of a partitioned CPU still exits to the hypervisor, and host interrupts
+//! of other VMs' passthrough devices are not steered away from it: the platform layer doesn't
+//! expose interrupt affinity.
+//!
+//! Guests whose internal synchronization collapses when only some of their vCPUs run, e.g. with
+//! spinning barriers between vCPUs, are gang scheduled instead. Each vCPU of a gang VM is pinned to
+//! its own physical CPU like a partitioned one, but the CPUs are not dedicated to the VM: the gang
+//! gives them up together at the end of each `slice_ms` of host time (10 ms by default), aligned
+//! so that all its vCPUs reach the same boundary, and none of its vCPUs re-enters the guest until
+//! all those that gave up their CPU got it back. Halted vCPUs block as in a shared VM and don't
+//! hold the gang back. Two gangs can't share a CPU, they would keep each other from assembling.
+//!
+//! The scheduler isn't preemptive, so a vCPU running in the guest at a slice boundary only leaves
+//! on its next exit, at the latest on the next host timer interrupt, and the gang is dispatched
+//! within that skew.
+//!
+//! A shared VM with more vCPUs than physical CPUs to run them on is overcommitted. Its vCPUs are
+//! time-sliced, so that a vCPU spinning in the guest doesn't keep its siblings, e.g. the holder of
+//! the lock it spins on, off the CPU. The slices are aligned on multiples of `slice_ms` of host
+//! time, so the vCPUs of the VM running on different CPUs yield together and their queued
+//! siblings are scheduled together, as a gang:
+//!
+//! ```toml
+//! [scheduling]
+//! # Time slice of an overcommitted VM, 0 disables the slicing.
+//! slice_ms = 10
+//! ```
+//!
+//! Shared VMs get proportional shares of the CPUs they compete for. Their run time is accounted
+//! per [`SHARE_PERIOD`]: a VM that used more than its weight's share of the CPUs in the current
+//! period yields after each exit while other VMs are active, and a capped VM is kept off the CPUs
+//! for the rest of the period once it used its cap. The VMs active in a period are those charged
+//! with run time in it, their weights are summed as they first run in the period:
+//!
+//! ```toml
+//! [scheduling]
+//! # Relative weight, 256 by default.
+//! weight = 512
+//! # Percentage of one CPU the VM may use per period, 0 (the default) for no cap.
+//! cap = 70
+//! ```
+//!
+//! Manager VMs (see [`crate::vmm::vmdef`]) change them at runtime with the
+//! [`HVC_VM_SET_SHARES`](crate::vmm::hvc::HVC_VM_SET_SHARES) hypercall (`HVmSetShares`): `args[0]`
+//! is the peer handle of the VM, or [`AFFINITY_SELF`](affinity::AFFINITY_SELF) for the caller,
+//! `args[1]` its weight and `args[2]` its cap. The run time of every vCPU is accounted for
+//! monitoring, see `vm sched`.
+//!
+//! The shared VMs of a VM group with a CPU cap (see [`crate::vmm::group`]) are also capped
+//! together: their run time is accounted to the share of the group as well, and once the group
+//! used its cap in the current period, each of its VMs is kept off the CPUs for the rest of the
+//! period on the next exit of its vCPUs. The shares of the groups are slots of a fixed table, at
+//! most [`MAX_GROUP_SHARES`] groups have a CPU cap.
+//!
+//! The accounting and the enforcement of the shares after each exit take no lock, they only touch
+//! the atomics of the VM, of its group and of the active weights.
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use std::os::arceos::modules::{axhal, axtask};
+use std::thread;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use spin::Mutex;
+
+use crate::task::AsVCpuTask;
+use crate::vmm::{VMRef, affinity, lockup, peers, vmdef};
+
+/// Default time slice of the vCPUs of an overcommitted VM.
+const DEFAULT_SLICE_MS: u64 = 10;
+/// Accounting period of the CPU shares.
+pub const SHARE_PERIOD: Duration = Duration::from_millis(30);
+/// Default weight of a VM.
+pub const DEFAULT_WEIGHT: u32 = 256;
+/// Maximum weight of a VM.
+pub const MAX_WEIGHT: u32 = 65535;
+/// How long a vCPU waiting for its gang spins before it yields its CPU.
+const GANG_SPIN_NS: u64 = 50_000;
+
+/// Partitioned VMs.
+static PARTITIONED: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+/// Time slices of the overcommitted VMs in nanoseconds.
+static SLICES: Mutex<BTreeMap<usize, u64>> = Mutex::new(BTreeMap::new());
+/// Gang scheduled VMs.
+static GANGS: Mutex<BTreeMap<usize, Arc<Gang>>> = Mutex::new(BTreeMap::new());
+/// CPU shares of the shared VMs.
+static SHARES: Mutex<BTreeMap<usize, Arc<VmShare>>> = Mutex::new(BTreeMap::new());
+/// Sums of the weights of the VMs that ran in the current and in the previous period, in the slot
+/// of the index of the period modulo 2: the low 32 bits of the index in the upper half, the sum in
+/// the lower half.
+static ACTIVE_WEIGHTS: [AtomicU64; 2] = [const { AtomicU64::new(0) }; 2];
+/// Maximum number of groups with a CPU cap.
+pub const MAX_GROUP_SHARES: usize = 64;
+/// Shares of the groups with a CPU cap, a slot with a zero cap is free.
+static GROUP_SHARES: [GroupShare; MAX_GROUP_SHARES] =
+    [const { GroupShare::new() }; MAX_GROUP_SHARES];
+
+const PERIOD_NS: u64 = SHARE_PERIOD.as_nanos() as u64;
+
+/// CPU share and run time accounting of a VM.
+pub struct VmShare {
+    vm_id: usize,
+    weight: AtomicU32,
+    /// Percentage of one CPU, 0 for no cap.
+    cap: AtomicU32,
+    /// Index of the period `consumed_ns` is accounted for.
+    period: AtomicU64,
+    consumed_ns: AtomicU64,
+    /// Run time in the previous period.
+    last_consumed_ns: AtomicU64,
+    /// Total run time of each vCPU.
+    vcpu_runtime_ns: Vec<AtomicU64>,
+    /// Slot of the share of the group of the VM plus one, 0 if it's in no group with a CPU cap.
+    group: AtomicUsize,
+    /// Index of the last period the VM's weight was added to the active weights for.
+    active_period: AtomicU64,
+}
+
+/// Aggregate CPU cap of the shared VMs of a VM group, see [`crate::vmm::group`].
+struct GroupShare {
+    /// Percentage of one CPU, 0 if the slot is free.
+    cap: AtomicU32,
+    /// Index of the period `consumed_ns` is accounted for.
+    period: AtomicU64,
+    consumed_ns: AtomicU64,
+}
+
+impl GroupShare {
+    const fn new() -> Self {
+        Self {
+            cap: AtomicU32::new(0),
+            period: AtomicU64::new(0),
+            consumed_ns: AtomicU64::new(0),
+        }
+    }
+
+    fn roll(&self, period: u64) {
+        let current = self.period.load(Ordering::Acquire);
+        if current != period
+            && self
+                .period
+                .compare_exchange(current, period, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            self.consumed_ns.store(0, Ordering::Release);
+        }
+    }
+
+    /// Whether the group used its cap in `period`.
+    fn exhausted(&self, period: u64) -> bool {
+        self.roll(period);
+        let cap = self.cap.load(Ordering::Relaxed) as u64;
+        cap != 0 && self.consumed_ns.load(Ordering::Relaxed) >= PERIOD_NS * cap / 100
+    }
+}
+
+/// Keeps the current vCPU off its CPU until period `period + 1`.
+fn sleep_until_next_period(period: u64, now: u64) {
+    let progress = &axtask::current().as_vcpu_task().progress;
+    progress.set_blocked(true);
+    thread::sleep(Duration::from_nanos((period + 1) * PERIOD_NS - now));
+    progress.set_blocked(false);
+}
+
+impl VmShare {
+    fn roll(&self, period: u64) {
+        let current = self.period.load(Ordering::Acquire);
+        if current != period
+            && self
+                .period
+                .compare_exchange(current, period, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            let consumed = self.consumed_ns.swap(0, Ordering::AcqRel);
+            // A VM idle for more than a period has no recent run time.
+            let last = if current + 1 == period { consumed } else { 0 };
+            self.last_consumed_ns.store(last, Ordering::Release);
+        }
+    }
+
+    /// Charges `ns` of run time to vCPU `vcpu_id`.
+    #[inline]
+    pub fn charge(&self, vcpu_id: usize, ns: u64) {
+        let period = axhal::time::monotonic_time_nanos() / PERIOD_NS;
+        self.roll(period);
+        self.consumed_ns.fetch_add(ns, Ordering::Relaxed);
+        if let Some(runtime) = self.vcpu_runtime_ns.get(vcpu_id) {
+            runtime.fetch_add(ns, Ordering::Relaxed);
+        }
+        if let Some(group) = self.group() {
+            group.roll(period);
+            group.consumed_ns.fetch_add(ns, Ordering::Relaxed);
+        }
+        if self.active_period.swap(period, Ordering::AcqRel) != period {
+            add_active_weight(period, self.weight.load(Ordering::Relaxed));
+        }
+    }
+
+    /// Returns the share of the group of the VM, if it's in a group with a CPU cap.
+    #[inline]
+    fn group(&self) -> Option<&'static GroupShare> {
+        let slot = self.group.load(Ordering::Acquire);
+        (slot != 0).then(|| &GROUP_SHARES[slot - 1])
+    }
+
+    /// Enforces the share of the VM after an exit of one of its vCPUs: yields the CPU if the VM
+    /// used more than its share while other VMs are active, sleeps until the next period if it
+    /// or its group used its cap.
+    #[inline]
+    pub fn enforce(&self) {
+        let now = axhal::time::monotonic_time_nanos();
+        let period = now / PERIOD_NS;
+        self.roll(period);
+        let consumed = self.consumed_ns.load(Ordering::Relaxed);
+
+        let cap = self.cap.load(Ordering::Relaxed) as u64;
+        let group_exhausted = self.group().is_some_and(|group| group.exhausted(period));
+        if (cap != 0 && consumed >= PERIOD_NS * cap / 100) || group_exhausted {
+            sleep_until_next_period(period, now);
+            return;
+        }
+
+        let weight = self.weight.load(Ordering::Relaxed) as u64;
+        let active_weight = active_weight(period).max(weight);
+        if active_weight > weight {
+            let entitled = PERIOD_NS * axruntime::cpu_count() as u64 * weight / active_weight;
+            if consumed > entitled {
+                axtask::yield_now();
+            }
+        }
+    }
+}
+
+/// Adds `weight` to the sum of the weights of the VMs that ran in `period`, on the first run of a
+/// VM in the period.
+fn add_active_weight(period: u64, weight: u32) {
+    let tag = period & u32::MAX as u64;
+    let _ = ACTIVE_WEIGHTS[period as usize % 2].fetch_update(
+        Ordering::AcqRel,
+        Ordering::Acquire,
+        |sum| {
+            // The slot may still hold the sum of two periods ago.
+            let sum = if sum >> 32 == tag { sum } else { tag << 32 };
+            Some(sum + weight as u64)
+        },
+    );
+}
+
+/// Returns the sum of the weights of the VMs that ran in the previous period.
+fn active_weight(period: u64) -> u64 {
+    let Some(last) = period.checked_sub(1) else {
+        return 0;
+    };
+    let sum = ACTIVE_WEIGHTS[last as usize % 2].load(Ordering::Acquire);
+    if sum >> 32 == last & u32::MAX as u64 {
+        sum & u32::MAX as u64
+    } else {
+        0
+    }
+}
+
+fn check_share(weight: u64, cap: u64) -> AxResult {
+    if weight == 0 || weight > MAX_WEIGHT as u64 {
+        return ax_err!(InvalidInput, format!("invalid weight {}", weight));
+    }
+    if cap > 100 * axruntime::cpu_count() as u64 {
+        return ax_err!(InvalidInput, format!("invalid cap {}%", cap));
+    }
+    Ok(())
+}
+
+/// Sets the scheduling mode described in the `[scheduling]` section of `raw_cfg` for the VM,
+/// after its `[affinity]` section. A VM without this section is shared.
+pub fn setup_vm_scheduling(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let cfg = raw_cfg.get("scheduling").and_then(|v| v.as_table());
+    match cfg
+        .and_then(|cfg| cfg.get("mode"))
+        .and_then(|v| v.as_str())
+        .unwrap_or("shared")
+    {
+        "shared" => setup_shared(vm, cfg),
+        "partitioned" => setup_partitioned(vm),
+        "gang" => setup_gang(vm, cfg),
+        mode => ax_err!(
+            InvalidInput,
+            format!("scheduling config: unsupported mode `{}`", mode)
+        ),
+    }
+}
+
+fn setup_shared(vm: &VMRef, cfg: Option<&toml::Table>) -> AxResult {
+    let get = |key: &str, default: u64| match cfg.and_then(|cfg| cfg.get(key)) {
+        None => Ok(default),
+        Some(v) => v
+            .as_integer()
+            .filter(|&v| v >= 0)
+            .map(|v| v as u64)
+            .ok_or_else(|| {
+                ax_err_type!(
+                    InvalidInput,
+                    format!(
+                        "scheduling config: `{}` must be a non-negative integer",
+                        key
+                    )
+                )
+            }),
+    };
+    let slice_ms = get("slice_ms", DEFAULT_SLICE_MS)?;
+    let weight = get("weight", DEFAULT_WEIGHT as u64)?;
+    let cap = get("cap", 0)?;
+    check_share(weight, cap)?;
+    SHARES.lock().insert(
+        vm.id(),
+        Arc::new(VmShare {
+            vm_id: vm.id(),
+            weight: AtomicU32::new(weight as u32),
+            cap: AtomicU32::new(cap as u32),
+            period: AtomicU64::new(0),
+            consumed_ns: AtomicU64::new(0),
+            last_consumed_ns: AtomicU64::new(0),
+            vcpu_runtime_ns: (0..vm.vcpu_num()).map(|_| AtomicU64::new(0)).collect(),
+            group: AtomicUsize::new(0),
+            active_period: AtomicU64::new(u64::MAX),
+        }),
+    );
+
+    let cpus = vm
+        .vcpu_list()
+        .iter()
+        .fold(0, |cpus, vcpu| cpus | affinity::vcpu_mask(vm, vcpu));
+    if slice_ms == 0 || vm.vcpu_num() <= cpus.count_ones() as usize {
+        return Ok(());
+    }
+    SLICES.lock().insert(vm.id(), slice_ms * 1_000_000);
+    info!(
+        "VM[{}] overcommitted: {} vCPUs on {} CPUs, {} ms time slices",
+        vm.id(),
+        vm.vcpu_num(),
+        cpus.count_ones(),
+        slice_ms
+    );
+    Ok(())
+}
+
+/// Returns the single-CPU masks of the vCPUs of a VM in `mode`, one distinct CPU per vCPU.
+fn single_cpu_masks(vm: &VMRef, mode: &str) -> AxResult<Vec<usize>> {
+    let mut masks = Vec::with_capacity(vm.vcpu_num());
+    for vcpu in vm.vcpu_list() {
+        let mask = affinity::configured_mask(vm.id(), vcpu.id())
+            .or_else(|| vcpu.phys_cpu_set())
+            .filter(|mask| mask.count_ones() == 1)
+            .ok_or_else(|| {
+                ax_err_type!(
+                    InvalidInput,
+                    format!(
+                        "scheduling config: VCpu[{}] of a {} VM needs a single physical CPU",
+                        vcpu.id(),
+                        mode
+                    )
+                )
+            })?;
+        if masks.contains(&mask) {
+            return ax_err!(
+                InvalidInput,
+                format!(
+                    "scheduling config: VCpu[{}] shares physical CPU {} with another vCPU",
+                    vcpu.id(),
+                    mask.trailing_zeros()
+                )
+            );
+        }
+        masks.push(mask);
+    }
+    Ok(masks)
+}
+
+fn setup_partitioned(vm: &VMRef) -> AxResult {
+    let masks = single_cpu_masks(vm, "partitioned")?;
+    affinity::pin(vm, masks, true)?;
+
+    PARTITIONED.lock().insert(vm.id());
+    info!("VM[{}] partitioned", vm.id());
+    Ok(())
+}
+
+fn setup_gang(vm: &VMRef, cfg: Option<&toml::Table>) -> AxResult {
+    let slice_ms = match cfg.and_then(|cfg| cfg.get("slice_ms")) {
+        None => DEFAULT_SLICE_MS,
+        Some(v) => v.as_integer().filter(|&ms| ms > 0).ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                "scheduling config: `slice_ms` of a gang VM must be a positive integer"
+            )
+        })? as u64,
+    };
+    let masks = single_cpu_masks(vm, "gang")?;
+    let cpus = masks.iter().fold(0, |cpus, mask| cpus | mask);
+
+    let mut gangs = GANGS.lock();
+    if let Some((&other, _)) = gangs
+        .iter()
+        .find(|&(&id, gang)| id != vm.id() && gang.cpus & cpus != 0)
+    {
+        return ax_err!(
+            ResourceBusy,
+            format!(
+                "scheduling config: CPUs {:#x} are already used by gang VM[{}]",
+                cpus & gangs[&other].cpus,
+                other
+            )
+        );
+    }
+    affinity::pin(vm, masks, false)?;
+    gangs.insert(
+        vm.id(),
+        Arc::new(Gang {
+            cpus,
+            slice_ns: slice_ms * 1_000_000,
+            away: AtomicUsize::new(0),
+        }),
+    );
+    info!(
+        "VM[{}] gang scheduled on CPUs {:#x}, {} ms time slices",
+        vm.id(),
+        cpus,
+        slice_ms
+    );
+    Ok(())
+}
+
+/// Forgets the scheduling mode of a VM, called when the VM is destroyed.
+pub fn teardown_vm_scheduling(vm_id: usize) {
+    PARTITIONED.lock().remove(&vm_id);
+    GANGS.lock().remove(&vm_id);
+    SLICES.lock().remove(&vm_id);
+    SHARES.lock().remove(&vm_id);
+}
+
+/// Returns the CPU share of VM `vm_id`, `None` if it's not shared.
+pub fn vm_share(vm_id: usize) -> Option<Arc<VmShare>> {
+    SHARES.lock().get(&vm_id).cloned()
+}
+
+/// Sets the weight and cap of a shared VM.
+pub fn set_vm_share(vm_id: usize, weight: u32, cap: u32) -> AxResult {
+    check_share(weight as u64, cap as u64)?;
+    let share = vm_share(vm_id).ok_or_else(|| {
+        ax_err_type!(
+            NotFound,
+            format!("VM[{}] has no CPU share, it's not shared or gone", vm_id)
+        )
+    })?;
+    share.weight.store(weight, Ordering::Relaxed);
+    share.cap.store(cap, Ordering::Relaxed);
+    info!("VM[{}] CPU share: weight {}, cap {}%", vm_id, weight, cap);
+    Ok(())
+}
+
+/// Allocates the share of a group with a CPU cap of `cap` percent of one CPU, returns its slot.
+pub fn alloc_group_share(cap: u32) -> AxResult<usize> {
+    if cap == 0 {
+        return ax_err!(InvalidInput, "a group share needs a CPU cap");
+    }
+    let slot = GROUP_SHARES
+        .iter()
+        .position(|group| {
+            group
+                .cap
+                .compare_exchange(0, cap, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        })
+        .ok_or_else(|| {
+            ax_err_type!(
+                NoMemory,
+                format!("no more than {} groups with a CPU cap", MAX_GROUP_SHARES)
+            )
+        })?;
+    let group = &GROUP_SHARES[slot];
+    group.period.store(0, Ordering::Release);
+    group.consumed_ns.store(0, Ordering::Release);
+    Ok(slot)
+}
+
+/// Frees the share of a group in `slot`, once no VM is capped with it.
+pub fn free_group_share(slot: usize) {
+    GROUP_SHARES[slot].cap.store(0, Ordering::Release);
+}
+
+/// Returns the run time of the VMs of the group whose share is in `slot` in the current period.
+pub fn group_consumed_ns(slot: usize) -> u64 {
+    let group = &GROUP_SHARES[slot];
+    group.roll(axhal::time::monotonic_time_nanos() / PERIOD_NS);
+    group.consumed_ns.load(Ordering::Relaxed)
+}
+
+/// Caps VM `vm_id` with the other VMs of its group, with `group` the slot of the share of the
+/// group or `None` when it leaves the group. Returns `false` if the VM isn't shared.
+pub fn set_vm_group_share(vm_id: usize, group: Option<usize>) -> bool {
+    let Some(share) = vm_share(vm_id) else {
+        return false;
+    };
+    share
+        .group
+        .store(group.map_or(0, |slot| slot + 1), Ordering::Release);
+    true
+}
+
+/// Handles the [`HVC_VM_SET_SHARES`](crate::vmm::hvc::HVC_VM_SET_SHARES) hypercall of VM
+/// `vm_id`.
+pub fn handle_set_shares(vm_id: usize, args: [u64; 6]) -> AxResult {
+    if !vmdef::is_manager(vm_id) {
+        return ax_err!(
+            PermissionDenied,
+            format!("VM[{}] is not allowed to set CPU shares", vm_id)
+        );
+    }
+    let target_vm_id = if args[0] == affinity::AFFINITY_SELF {
+        vm_id
+    } else {
+        peers::resolve(vm_id, args[0] as usize)?
+    };
+    if args[1] > MAX_WEIGHT as u64 || args[2] > u32::MAX as u64 {
+        return ax_err!(InvalidInput, "CPU share out of range");
+    }
+    set_vm_share(target_vm_id, args[1] as u32, args[2] as u32)
+}
+
+/// CPU share and run time of a VM, for monitoring.
+#[derive(Debug, Clone)]
+pub struct VmShareStats {
+    pub vm_id: usize,
+    pub weight: u32,
+    pub cap: u32,
+    /// Run time in the previous period.
+    pub last_period_ns: u64,
+    /// Total run time of each vCPU.
+    pub vcpu_runtime_ns: Vec<u64>,
+}
+
+/// Returns the CPU shares and run times of the shared VMs.
+pub fn share_stats() -> Vec<VmShareStats> {
+    let period = axhal::time::monotonic_time_nanos() / PERIOD_NS;
+    SHARES
+        .lock()
+        .values()
+        .map(|share| {
+            share.roll(period);
+            VmShareStats {
+                vm_id: share.vm_id,
+                weight: share.weight.load(Ordering::Relaxed),
+                cap: share.cap.load(Ordering::Relaxed),
+                last_period_ns: share.last_consumed_ns.load(Ordering::Acquire),
+                vcpu_runtime_ns: share
+                    .vcpu_runtime_ns
+                    .iter()
+                    .map(|runtime| runtime.load(Ordering::Relaxed))
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+/// Returns whether VM `vm_id` is partitioned.
+pub fn is_partitioned(vm_id: usize) -> bool {
+    PARTITIONED.lock().contains(&vm_id)
+}
+
+/// Gang-aligned time slicing of a vCPU of an overcommitted VM.
+pub struct VCpuSlice {
+    slice_ns: u64,
+    /// Index of the current slice since boot.
+    epoch: u64,
+}
+
+impl VCpuSlice {
+    /// Returns the time slicing of the vCPUs of VM `vm_id`, `None` if it's not overcommitted.
+    pub fn new(vm_id: usize) -> Option<Self> {
+        let slice_ns = *SLICES.lock().get(&vm_id)?;
+        Some(Self {
+            slice_ns,
+            epoch: axhal::time::monotonic_time_nanos() / slice_ns,
+        })
+    }
+
+    /// Yields the CPU if the current slice has ended. Called by the vCPU task after each exit.
+    #[inline]
+    pub fn yield_if_expired(&mut self) {
+        if axhal::time::monotonic_time_nanos() / self.slice_ns == self.epoch {
+            return;
+        }
+        axtask::yield_now();
+        self.epoch = axhal::time::monotonic_time_nanos() / self.slice_ns;
+    }
+}
+
+/// The CPUs and slices of a gang scheduled VM.
+struct Gang {
+    cpus: usize,
+    slice_ns: u64,
+    /// vCPUs that gave up their CPU at the end of a slice and didn't get it back yet.
+    away: AtomicUsize,
+}
+
+/// Gang scheduling of a vCPU.
+pub struct VCpuGang {
+    gang: Arc<Gang>,
+    bit: usize,
+    /// Index of the current slice since boot.
+    epoch: u64,
+}
+
+impl VCpuGang {
+    /// Returns the gang scheduling of vCPU `vcpu_id` of VM `vm_id`, `None` if the VM isn't gang
+    /// scheduled.
+    pub fn new(vm_id: usize, vcpu_id: usize) -> Option<Self> {
+        let gang = GANGS.lock().get(&vm_id)?.clone();
+        let epoch = axhal::time::monotonic_time_nanos() / gang.slice_ns;
+        Some(Self {
+            gang,
+            bit: 1 << vcpu_id,
+            epoch,
+        })
+    }
+
+    /// Waits until no vCPU of the gang is away from its CPU, or until `give_up` returns true.
+    /// Called by the vCPU task before each entry into the guest.
+    #[inline]
+    pub fn assemble(&mut self, give_up: impl Fn() -> bool) {
+        if self.gang.away.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        let mut spin_start = axhal::time::monotonic_time_nanos();
+        while self.gang.away.load(Ordering::Acquire) != 0 && !give_up() {
+            let now = axhal::time::monotonic_time_nanos();
+            if now - spin_start < GANG_SPIN_NS {
+                core::hint::spin_loop();
+                continue;
+            }
+            // Let the tasks queued on this CPU, maybe a sibling, run.
+            lockup::heartbeat();
+            axtask::yield_now();
+            spin_start = axhal::time::monotonic_time_nanos();
+        }
+        self.epoch = axhal::time::monotonic_time_nanos() / self.gang.slice_ns;
+    }
+
+    /// Gives up the CPU if the current slice has ended, the gang re-assembles before the vCPU
+    /// enters the guest again. Called by the vCPU task after each exit.
+    #[inline]
+    pub fn yield_if_expired(&mut self) {
+        if axhal::time::monotonic_time_nanos() / self.gang.slice_ns == self.epoch {
+            return;
+        }
+        self.gang.away.fetch_or(self.bit, Ordering::AcqRel);
+        axtask::yield_now();
+        self.gang.away.fetch_and(!self.bit, Ordering::AcqRel);
+        self.epoch = axhal::time::monotonic_time_nanos() / self.gang.slice_ns;
+    }
+}
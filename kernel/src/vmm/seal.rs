@@ -0,0 +1,272 @@
+//! Encryption and authentication of the snapshots of guests.
+//!
+//! Core dumps and checkpoints (see [`crate::vmm::coredump`]) hold the whole memory of a guest. A
+//! VM with a snapshot key has them sealed with AES-256-GCM, so that they can be stored or moved
+//! to another axvisor instance without exposing or letting anyone alter its memory:
+//!
+//! ```toml
+//! [snapshot]
+//! # AES-256 key of the deployment, 64 hex digits.
+//! key = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+//! # Or a file holding it, e.g. on storage only the hypervisor can read (needs the `fs` feature).
+//! key_file = "/keys/snapshot.key"
+//! ```
+//!
+//! Layout of a sealed stream, all integers little-endian:
+//!
+//! - A [`SEAL_HEADER_LEN`]-byte header: [`SEAL_MAGIC`], `u32` [`SEAL_VERSION`], `u32` size of the
+//!   records, the key check value (the first 8 bytes of the zero block encrypted with the key),
+//!   which tells a wrong key from a corrupted stream, then a 16-byte salt unique to the stream.
+//! - Records, each a `u32` plaintext size, up to the record size, then the ciphertext and the
+//!   16-byte GCM tag. The nonce of record `n` is `n` as a `u64` then a `u32`, 1 for the last
+//!   record and 0 for the others, and the additional data is the header then the `u32` size, so
+//!   records can't be reordered, dropped, truncated or moved to another stream.
+//!
+//! Records are encrypted with a key of the stream, the two blocks of the salt and of the salt with
+//! its last bit flipped encrypted with the snapshot key. The salt is the host wall time, a counter
+//! and the VM ID encrypted with the snapshot key, so it's unique as long as the wall clock of the
+//! host doesn't go back. Crash dumps sent to a manager VM over IVC (see [`crate::vmm::crash`])
+//! stay in memory and aren't sealed.
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use std::io::{self, Write};
+use std::os::arceos::api::time::ax_wall_time;
+
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::aes::Aes256;
+use aes_gcm::aes::cipher::{BlockEncrypt, generic_array::GenericArray};
+use aes_gcm::{Aes256Gcm, Nonce};
+use axerrno::{AxResult, ax_err, ax_err_type};
+use spin::Mutex;
+
+use crate::vmm::VMRef;
+
+/// Magic of a sealed stream, "AXSEALED".
+pub const SEAL_MAGIC: [u8; 8] = *b"AXSEALED";
+/// Version of the layout of sealed streams.
+pub const SEAL_VERSION: u32 = 1;
+/// Size of the header of a sealed stream.
+pub const SEAL_HEADER_LEN: usize = 40;
+/// Plaintext size of the records.
+pub const SEAL_RECORD_SIZE: usize = 0x1_0000;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+/// Snapshot keys of the VMs with a `[snapshot]` section, indexed by VM ID.
+static KEYS: Mutex<BTreeMap<usize, [u8; KEY_LEN]>> = Mutex::new(BTreeMap::new());
+/// Streams sealed since boot, part of the salts.
+static STREAMS: AtomicU32 = AtomicU32::new(0);
+
+fn parse_key(hex: &str) -> AxResult<[u8; KEY_LEN]> {
+    let hex = hex.trim();
+    let mut key = [0; KEY_LEN];
+    if hex.len() != 2 * KEY_LEN {
+        return ax_err!(
+            InvalidInput,
+            "snapshot config: the key must be 64 hex digits"
+        );
+    }
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = hex
+            .get(2 * i..2 * i + 2)
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| ax_err_type!(InvalidInput, "snapshot config: invalid key"))?;
+    }
+    Ok(key)
+}
+
+#[cfg(feature = "fs")]
+fn read_key_file(path: &str) -> AxResult<[u8; KEY_LEN]> {
+    let hex = std::fs::read_to_string(path).map_err(|e| {
+        ax_err_type!(
+            NotFound,
+            format!("snapshot config: failed to read {}: {:?}", path, e)
+        )
+    })?;
+    parse_key(&hex)
+}
+
+#[cfg(not(feature = "fs"))]
+fn read_key_file(_path: &str) -> AxResult<[u8; KEY_LEN]> {
+    ax_err!(
+        Unsupported,
+        "snapshot config: `key_file` needs the `fs` feature"
+    )
+}
+
+/// Records the snapshot key described in the `[snapshot]` section of `raw_cfg` for the VM.
+///
+/// Does nothing if the VM config has no `[snapshot]` section.
+pub fn setup_vm_seal(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("snapshot").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let string = |key: &str| {
+        cfg.get(key)
+            .map(|v| {
+                v.as_str().ok_or_else(|| {
+                    ax_err_type!(
+                        InvalidInput,
+                        format!("snapshot config: `{}` must be a string", key)
+                    )
+                })
+            })
+            .transpose()
+    };
+    let key = match (string("key")?, string("key_file")?) {
+        (Some(hex), None) => parse_key(hex)?,
+        (None, Some(path)) => read_key_file(path)?,
+        _ => {
+            return ax_err!(
+                InvalidInput,
+                "snapshot config: exactly one of `key` and `key_file` is needed"
+            );
+        }
+    };
+
+    info!(
+        "VM[{}] snapshots sealed, key check value {:02x?}",
+        vm.id(),
+        key_check_value(&key)
+    );
+    KEYS.lock().insert(vm.id(), key);
+    Ok(())
+}
+
+/// Forgets the snapshot key of a VM, called when the VM is destroyed.
+pub fn teardown_vm_seal(vm_id: usize) {
+    if let Some(mut key) = KEYS.lock().remove(&vm_id) {
+        key.fill(0);
+    }
+}
+
+/// Whether the snapshots of VM `vm_id` are sealed.
+pub fn is_sealed(vm_id: usize) -> bool {
+    KEYS.lock().contains_key(&vm_id)
+}
+
+fn encrypt_block(key: &[u8; KEY_LEN], block: [u8; 16]) -> [u8; 16] {
+    let mut block = GenericArray::from(block);
+    Aes256::new(GenericArray::from_slice(key)).encrypt_block(&mut block);
+    block.into()
+}
+
+fn key_check_value(key: &[u8; KEY_LEN]) -> [u8; 8] {
+    let mut kcv = [0; 8];
+    kcv.copy_from_slice(&encrypt_block(key, [0; 16])[..8]);
+    kcv
+}
+
+/// A writer sealing what's written to it before passing it on, see the
+/// [module documentation](self).
+pub struct SealedWriter<W: Write> {
+    inner: W,
+    cipher: Aes256Gcm,
+    header: [u8; SEAL_HEADER_LEN],
+    /// Plaintext of the record being filled.
+    record: Vec<u8>,
+    /// Index of the next record.
+    index: u64,
+    /// Bytes written to `inner`.
+    written: usize,
+}
+
+impl<W: Write> SealedWriter<W> {
+    fn new(mut inner: W, vm_id: usize, key: &[u8; KEY_LEN]) -> io::Result<Self> {
+        let mut seed = [0; 16];
+        seed[..8].copy_from_slice(&(ax_wall_time().as_nanos() as u64).to_le_bytes());
+        seed[8..12].copy_from_slice(&STREAMS.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+        seed[12..].copy_from_slice(&(vm_id as u32).to_le_bytes());
+        let salt = encrypt_block(key, seed);
+
+        let mut header = [0; SEAL_HEADER_LEN];
+        header[..8].copy_from_slice(&SEAL_MAGIC);
+        header[8..12].copy_from_slice(&SEAL_VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&(SEAL_RECORD_SIZE as u32).to_le_bytes());
+        header[16..24].copy_from_slice(&key_check_value(key));
+        header[24..40].copy_from_slice(&salt);
+
+        let mut flipped = salt;
+        flipped[15] ^= 1;
+        let mut stream_key = [0; KEY_LEN];
+        stream_key[..16].copy_from_slice(&encrypt_block(key, salt));
+        stream_key[16..].copy_from_slice(&encrypt_block(key, flipped));
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(&stream_key));
+        stream_key.fill(0);
+
+        inner.write_all(&header)?;
+        Ok(Self {
+            inner,
+            cipher,
+            header,
+            record: Vec::with_capacity(SEAL_RECORD_SIZE + TAG_LEN),
+            index: 0,
+            written: SEAL_HEADER_LEN,
+        })
+    }
+
+    /// Seals and writes the record being filled.
+    fn seal_record(&mut self, last: bool) -> io::Result<()> {
+        let size = (self.record.len() as u32).to_le_bytes();
+        let mut nonce = [0; 12];
+        nonce[..8].copy_from_slice(&self.index.to_le_bytes());
+        nonce[8..].copy_from_slice(&(last as u32).to_le_bytes());
+        let mut aad = [0; SEAL_HEADER_LEN + 4];
+        aad[..SEAL_HEADER_LEN].copy_from_slice(&self.header);
+        aad[SEAL_HEADER_LEN..].copy_from_slice(&size);
+
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), &aad, &mut self.record)
+            .expect("a record is within the size limit of AES-GCM");
+        self.inner.write_all(&size)?;
+        self.inner.write_all(&self.record)?;
+        self.inner.write_all(&tag)?;
+        self.written += size.len() + self.record.len() + TAG_LEN;
+        self.record.clear();
+        self.index += 1;
+        Ok(())
+    }
+
+    /// Seals the last record and flushes the stream, returns the size of the sealed stream.
+    ///
+    /// A stream that isn't finished fails to open.
+    pub fn finish(mut self) -> io::Result<usize> {
+        self.seal_record(true)?;
+        self.inner.flush()?;
+        Ok(self.written)
+    }
+}
+
+impl<W: Write> Write for SealedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(SEAL_RECORD_SIZE - self.record.len());
+        self.record.extend_from_slice(&buf[..len]);
+        if self.record.len() == SEAL_RECORD_SIZE {
+            self.seal_record(false)?;
+        }
+        Ok(len)
+    }
+
+    /// Flushes the records sealed so far, the record being filled is only sealed when full or by
+    /// [`SealedWriter::finish`].
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for SealedWriter<W> {
+    fn drop(&mut self) {
+        self.record.fill(0);
+    }
+}
+
+/// Returns a writer sealing the snapshot of VM `vm_id` to `out`, with its header written, or
+/// `None` if the snapshots of the VM aren't sealed.
+pub fn sealed_writer<W: Write>(vm_id: usize, out: W) -> io::Result<Option<SealedWriter<W>>> {
+    let Some(key) = KEYS.lock().get(&vm_id).copied() else {
+        return Ok(None);
+    };
+    SealedWriter::new(out, vm_id, &key).map(Some)
+}
@@ -0,0 +1,233 @@
+//! Per-VM security policy against data leaking to the next tenant of the memory of a VM, or to
+//! the manager VMs introspecting it.
+//!
+//! ```toml
+//! [security]
+//! # Scrub the memory of the VM when it is freed: "off" (default), "zero", or "pattern" to fill
+//! # it with `SCRUB_PATTERN`, which tells a stale read from a zeroed page.
+//! scrub = "zero"
+//! # Place the IVC channels of the VM at random guest physical addresses, false by default.
+//! randomize_ivc = true
+//! # What the manager VMs may do to the memory and vCPUs of the VM (see
+//! # [`crate::vmm::introspect`]): "off", "read" or "full" (default).
+//! introspection = "read"
+//! ```
+//!
+//! The memory scrubbed is the guest RAM and the pages populated on demand (see
+//! [`crate::vmm::lazymem`]) when the VM is destroyed, the pages of the IVC channels published by
+//! the VM when they are unpublished or torn down, and the virtio-blk ramdisks of the VM when they
+//! are freed. Guest consoles are passed through and have no buffers in the hypervisor. Shared
+//! image pages (see [`crate::vmm::imgshare`]) only hold the images and are not scrubbed, the
+//! private copies a VM makes of them are.
+//!
+//! With `randomize_ivc`, each channel the VM publishes or subscribes to is placed at a random
+//! page offset within its window, up to [`IVC_MAX_OFFSET_PAGES`] pages, so that the guest
+//! physical addresses of channels don't tell what the VM or its predecessors did. This costs the
+//! offset in IVC address space per channel, the size granted to the channel is the same. The placement is randomized with a generator seeded
+//! from the host clock, it is not cryptographically strong.
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::{AxMmHal, GuestPhysAddr, HostPhysAddr};
+use axerrno::{AxResult, ax_err_type};
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::hal::AxMmHalImpl;
+use crate::vmm::VMRef;
+
+/// Byte written by the "pattern" scrub mode.
+pub const SCRUB_PATTERN: u8 = 0xa5;
+/// Largest random offset of an IVC channel in pages, exclusive.
+pub const IVC_MAX_OFFSET_PAGES: usize = 16;
+
+/// How freed memory is scrubbed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ScrubMode {
+    #[default]
+    Off,
+    Zero,
+    Pattern,
+}
+
+impl ScrubMode {
+    /// Scrubs `bytes`.
+    pub fn scrub(self, bytes: &mut [u8]) {
+        match self {
+            Self::Off => {}
+            Self::Zero => bytes.fill(0),
+            Self::Pattern => bytes.fill(SCRUB_PATTERN),
+        }
+    }
+
+    /// Scrubs the page at `frame`.
+    pub fn scrub_frame(self, frame: HostPhysAddr) {
+        if self == Self::Off {
+            return;
+        }
+        // SAFETY: the frame is being freed by its owner and is mapped in the linear mapping of
+        // the host.
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(
+                AxMmHalImpl::phys_to_virt(frame).as_mut_ptr(),
+                PAGE_SIZE_4K,
+            )
+        };
+        self.scrub(bytes);
+    }
+}
+
+/// What the manager VMs may do to a VM.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Introspection {
+    Off,
+    /// Read its memory and the state of its vCPUs.
+    Read,
+    /// Write its memory as well.
+    #[default]
+    Full,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct SecurityPolicy {
+    scrub: ScrubMode,
+    randomize_ivc: bool,
+    introspection: Introspection,
+}
+
+/// Policies of the VMs with a `[security]` section, indexed by VM ID.
+static POLICIES: Mutex<BTreeMap<usize, SecurityPolicy>> = Mutex::new(BTreeMap::new());
+/// State of the placement generator, 0 until seeded.
+static RNG: AtomicU64 = AtomicU64::new(0);
+
+fn policy(vm_id: usize) -> SecurityPolicy {
+    POLICIES.lock().get(&vm_id).copied().unwrap_or_default()
+}
+
+/// Returns how the memory of VM `vm_id` is scrubbed when freed.
+pub fn scrub_mode(vm_id: usize) -> ScrubMode {
+    policy(vm_id).scrub
+}
+
+/// Returns what the manager VMs may do to VM `vm_id`.
+pub fn introspection(vm_id: usize) -> Introspection {
+    policy(vm_id).introspection
+}
+
+/// Applies the `[security]` section of `raw_cfg` to the VM.
+///
+/// Does nothing if the VM config has no `[security]` section.
+pub fn setup_vm_security(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("security").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let scrub = match cfg.get("scrub").map(|v| v.as_str()) {
+        None | Some(Some("off")) => ScrubMode::Off,
+        Some(Some("zero")) => ScrubMode::Zero,
+        Some(Some("pattern")) => ScrubMode::Pattern,
+        _ => {
+            return Err(ax_err_type!(
+                InvalidInput,
+                "security config: `scrub` must be \"off\", \"zero\" or \"pattern\""
+            ));
+        }
+    };
+    let randomize_ivc = match cfg.get("randomize_ivc") {
+        None => false,
+        Some(v) => v.as_bool().ok_or_else(|| {
+            ax_err_type!(InvalidInput, "security config: invalid `randomize_ivc`")
+        })?,
+    };
+    let introspection = match cfg.get("introspection").map(|v| v.as_str()) {
+        None | Some(Some("full")) => Introspection::Full,
+        Some(Some("read")) => Introspection::Read,
+        Some(Some("off")) => Introspection::Off,
+        _ => {
+            return Err(ax_err_type!(
+                InvalidInput,
+                "security config: `introspection` must be \"off\", \"read\" or \"full\""
+            ));
+        }
+    };
+
+    info!(
+        "VM[{}] security policy: scrub {:?}, randomized IVC placement {}, introspection {:?}",
+        vm.id(),
+        scrub,
+        randomize_ivc,
+        introspection
+    );
+    POLICIES.lock().insert(
+        vm.id(),
+        SecurityPolicy {
+            scrub,
+            randomize_ivc,
+            introspection,
+        },
+    );
+    Ok(())
+}
+
+/// Scrubs the guest RAM of the VM and drops its policy, called when the VM is destroyed, after
+/// the other per-VM state is torn down.
+pub fn teardown_vm_security(vm: &VMRef) {
+    let Some(policy) = POLICIES.lock().remove(&vm.id()) else {
+        return;
+    };
+    if policy.scrub == ScrubMode::Off {
+        return;
+    }
+    let mut scrubbed = 0;
+    for region in vm.memory_regions() {
+        // SAFETY: the VM is destroyed, nothing accesses its memory anymore.
+        let bytes =
+            unsafe { core::slice::from_raw_parts_mut(region.hva.as_mut_ptr(), region.size()) };
+        policy.scrub.scrub(bytes);
+        scrubbed += region.size();
+    }
+    info!(
+        "VM[{}] {:#x} bytes of guest RAM scrubbed",
+        vm.id(),
+        scrubbed
+    );
+}
+
+/// xorshift64* step of the placement generator.
+fn next_random() -> u64 {
+    let mut state = RNG.load(Ordering::Relaxed);
+    loop {
+        let mut x = if state == 0 {
+            axhal::time::monotonic_time_nanos() | 1
+        } else {
+            state
+        };
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        match RNG.compare_exchange_weak(state, x, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return x.wrapping_mul(0x2545_f491_4f6c_dd1d),
+            Err(current) => state = current,
+        }
+    }
+}
+
+/// Returns the offset in bytes of the next IVC channel of VM `vm_id` in its window, 0 unless
+/// the VM randomizes the placement of its channels.
+fn ivc_offset(vm_id: usize) -> usize {
+    if !policy(vm_id).randomize_ivc {
+        return 0;
+    }
+    (next_random() % IVC_MAX_OFFSET_PAGES as u64) as usize * PAGE_SIZE_4K
+}
+
+/// Allocates the window of an IVC channel of `size` bytes in the IVC address space of `vm`, with
+/// room for its [`ivc_offset`]. Returns the base GPA of the window, the offset of the channel in
+/// the window, and the size of the channel, `size` rounded up to whole pages.
+pub fn alloc_ivc_channel(vm: &VMRef, size: usize) -> AxResult<(GuestPhysAddr, usize, usize)> {
+    let size = size.next_multiple_of(PAGE_SIZE_4K);
+    let offset = ivc_offset(vm.id());
+    let (window, _) = vm.alloc_ivc_channel(size + offset)?;
+    Ok((window, offset, size))
+}
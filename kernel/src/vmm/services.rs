@@ -0,0 +1,361 @@
+//! The services page, a description of the communication graph of a VM for its guest.
+//!
+//! A VM with an `[hv_info]` section (see [`crate::vmm::hvinfo`]) gets a third read-only page
+//! after the hypervisor and VM information pages, describing what its guest may talk to, so that
+//! guest middleware can configure itself from it instead of from a copy of the VM config:
+//!
+//! - the peer handles of the VM (see [`crate::vmm::peers`]) and the VMs they refer to;
+//! - the IVC channels the VM is expected to publish or subscribe to, declared in its config;
+//! - the virtual devices of the VM: the virtio devices (see [`crate::vmm::virtio`]), the power
+//!   device (see [`crate::vmm::power`]) and the doorbells it can ring (see
+//!   [`crate::vmm::doorbell`]).
+//!
+//! IVC channels are not pre-authorized: any VM may subscribe to any published channel, so the
+//! declared channels are only a description of the intended graph, checked against the peer
+//! handles of the VM but not enforced:
+//!
+//! ```toml
+//! [[services]]
+//! # Name of the service, up to 31 bytes.
+//! name = "sensors"
+//! # Key of the channel.
+//! key = 0x10
+//! # Peer handle of the publisher, absent for channels the VM publishes itself.
+//! peer = 0
+//! ```
+//!
+//! The page is a [`ServicesHeader`] followed by its [`ServicePeer`], [`ServiceChannel`] and
+//! [`ServiceDevice`] entries, in that order. It is written before the VM boots and never changes
+//! afterwards, so peers given by name are resolved only if their VM already exists. Its layout is
+//! part of the guest ABI, see [`crate::vmm::abi`].
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::vmm::peers::{self, PeerTarget};
+use crate::vmm::{VMRef, virtio, vm_list};
+
+/// `magic` of [`ServicesHeader`], "AXSV".
+pub const SERVICES_MAGIC: u32 = u32::from_le_bytes(*b"AXSV");
+/// Version of the layouts of the services page.
+pub const SERVICES_VERSION: u16 = 1;
+/// `vm_id` of a [`ServicePeer`] whose VM is not known yet.
+pub const SERVICE_VM_UNRESOLVED: u32 = u32::MAX;
+/// `peer` of a [`ServiceChannel`] published by the VM itself.
+pub const SERVICE_PEER_SELF: u32 = u32::MAX;
+
+/// Kinds of devices, in `ServiceDevice::kind`.
+pub const SERVICE_DEV_VIRTIO_NET: u32 = 1;
+pub const SERVICE_DEV_VIRTIO_BLK: u32 = 2;
+pub const SERVICE_DEV_VIRTIO_VSOCK: u32 = 3;
+pub const SERVICE_DEV_POWER: u32 = 4;
+pub const SERVICE_DEV_DOORBELL: u32 = 5;
+pub const SERVICE_DEV_VIRTIO_CONSOLE: u32 = 6;
+/// The virtio hot-plug window, `arg` is its number of slots.
+pub const SERVICE_DEV_VIRTIO_HOTPLUG: u32 = 7;
+/// A virtio device served by a driver VM, `arg` is its virtio device ID.
+pub const SERVICE_DEV_VIRTIO_VHOST: u32 = 8;
+pub const SERVICE_DEV_VIRTIO_RNG: u32 = 9;
+/// A framebuffer, `arg` is its width in the low 32 bits and its height in the high ones.
+pub const SERVICE_DEV_FRAMEBUFFER: u32 = 10;
+
+/// Header of the services page.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ServicesHeader {
+    pub magic: u32,
+    pub version: u16,
+    /// Number of [`ServicePeer`] entries.
+    pub peers: u16,
+    /// Number of [`ServiceChannel`] entries.
+    pub channels: u16,
+    /// Number of [`ServiceDevice`] entries.
+    pub devices: u16,
+    pub _reserved: u32,
+}
+
+/// A peer handle of the VM.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ServicePeer {
+    pub handle: u32,
+    /// VM ID the handle refers to, or [`SERVICE_VM_UNRESOLVED`].
+    pub vm_id: u32,
+    /// Name of the VM, NUL-padded, empty if unknown.
+    pub name: [u8; 32],
+}
+
+/// An IVC channel declared in the config of the VM.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ServiceChannel {
+    /// Peer handle of the publisher, or [`SERVICE_PEER_SELF`].
+    pub peer: u32,
+    pub _reserved: u32,
+    pub key: u64,
+    /// Name of the service, NUL-padded.
+    pub name: [u8; 32],
+}
+
+/// A virtual device of the VM.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ServiceDevice {
+    /// One of the `SERVICE_DEV_*` constants.
+    pub kind: u32,
+    /// Interrupt raised by the device, the vector injected into the target for doorbells.
+    pub irq: u32,
+    /// Guest physical address of the MMIO window, the doorbell ID for doorbells.
+    pub base: u64,
+    /// The CID for virtio-vsock, the target VM for doorbells, 0 otherwise.
+    pub arg: u64,
+    /// The host bridge for virtio-net, NUL-padded, empty otherwise.
+    pub name: [u8; 32],
+}
+
+/// The services of a VM.
+#[derive(Debug, Clone)]
+pub struct Services {
+    pub peers: Vec<ServicePeer>,
+    pub channels: Vec<ServiceChannel>,
+    pub devices: Vec<ServiceDevice>,
+}
+
+impl Services {
+    fn page_size(&self) -> usize {
+        size_of::<ServicesHeader>()
+            + self.peers.len() * size_of::<ServicePeer>()
+            + self.channels.len() * size_of::<ServiceChannel>()
+            + self.devices.len() * size_of::<ServiceDevice>()
+    }
+}
+
+/// Services of all VMs, indexed by VM ID.
+static SERVICES: Mutex<BTreeMap<usize, Services>> = Mutex::new(BTreeMap::new());
+
+/// Copies `s` into a NUL-padded buffer, truncated to leave at least one NUL.
+fn padded<const N: usize>(s: &str) -> [u8; N] {
+    let mut buf = [0; N];
+    let len = s.len().min(N - 1);
+    buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+    buf
+}
+
+/// Returns the NUL-padded string `buf` as a `&str`.
+pub fn unpadded(buf: &[u8]) -> &str {
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    core::str::from_utf8(&buf[..len]).unwrap_or("")
+}
+
+fn vm_name(vm_id: usize) -> Option<String> {
+    vm_list::get_vm_by_id(vm_id).map(|vm| vm.with_config(|cfg| cfg.name()))
+}
+
+fn resolve_peer(handle: usize, target: PeerTarget) -> ServicePeer {
+    let (vm_id, name) = match target {
+        PeerTarget::Id(id) => (id as u32, vm_name(id).unwrap_or_default()),
+        PeerTarget::Name(name) => {
+            let vm_id = vm_list::get_vm_list()
+                .iter()
+                .find(|vm| vm.with_config(|cfg| cfg.name() == name))
+                .map_or(SERVICE_VM_UNRESOLVED, |vm| vm.id() as u32);
+            (vm_id, name)
+        }
+    };
+    ServicePeer {
+        handle: handle as u32,
+        vm_id,
+        name: padded(&name),
+    }
+}
+
+fn int(entry: &toml::Value, key: &str) -> Option<u64> {
+    entry
+        .get(key)
+        .and_then(|v| v.as_integer())
+        .map(|v| v as u64)
+}
+
+/// Collects the devices declared in `raw_cfg`, already validated by their own setup.
+fn config_devices(vm_id: usize, raw_cfg: &toml::Table) -> Vec<ServiceDevice> {
+    let device = |kind, entry: &toml::Value, arg, name: &str| ServiceDevice {
+        kind,
+        irq: int(entry, "irq").unwrap_or(0) as u32,
+        base: int(entry, "base").unwrap_or(0),
+        arg,
+        name: padded(name),
+    };
+    let array = |key: &str| {
+        raw_cfg
+            .get(key)
+            .and_then(|v| v.as_array())
+            .map(|entries| entries.as_slice())
+            .unwrap_or_default()
+    };
+
+    let mut devices = Vec::new();
+    for entry in array("virtio_net") {
+        let bridge = entry.get("bridge").and_then(|v| v.as_str()).unwrap_or("");
+        devices.push(device(SERVICE_DEV_VIRTIO_NET, entry, 0, bridge));
+    }
+    for entry in array("virtio_blk") {
+        devices.push(device(SERVICE_DEV_VIRTIO_BLK, entry, 0, ""));
+    }
+    if let Some(entry) = raw_cfg.get("virtio_vsock") {
+        let cid = int(entry, "cid").unwrap_or_else(|| virtio::default_cid(vm_id));
+        devices.push(device(SERVICE_DEV_VIRTIO_VSOCK, entry, cid, ""));
+    }
+    if let Some(entry) = raw_cfg.get("power") {
+        devices.push(device(SERVICE_DEV_POWER, entry, 0, ""));
+    }
+    for entry in array("virtio_console") {
+        devices.push(device(SERVICE_DEV_VIRTIO_CONSOLE, entry, 0, ""));
+    }
+    for entry in array("virtio_rng") {
+        devices.push(device(SERVICE_DEV_VIRTIO_RNG, entry, 0, ""));
+    }
+    if let Some(entry) = raw_cfg.get("virtio_hotplug") {
+        let slots = int(entry, "slots").unwrap_or(virtio::DEFAULT_HOTPLUG_SLOTS as u64);
+        devices.push(device(SERVICE_DEV_VIRTIO_HOTPLUG, entry, slots, ""));
+    }
+    for entry in array("virtio_vhost") {
+        let device_id = int(entry, "device_id").unwrap_or(0);
+        devices.push(device(SERVICE_DEV_VIRTIO_VHOST, entry, device_id, ""));
+    }
+    if let Some(entry) = raw_cfg.get("framebuffer") {
+        let width = int(entry, "width").unwrap_or(0);
+        let height = int(entry, "height").unwrap_or(0);
+        devices.push(device(SERVICE_DEV_FRAMEBUFFER, entry, width | height << 32, ""));
+    }
+    for entry in array("doorbells") {
+        devices.push(ServiceDevice {
+            kind: SERVICE_DEV_DOORBELL,
+            irq: int(entry, "vector").unwrap_or(0) as u32,
+            base: int(entry, "id").unwrap_or(0),
+            arg: int(entry, "target_vm").unwrap_or(0),
+            name: [0; 32],
+        });
+    }
+    devices
+}
+
+/// Builds the services description of the VM from `raw_cfg`.
+///
+/// Must run after the setup of the peers and of the devices of the VM.
+pub fn setup_vm_services(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let vm_id = vm.id();
+    let vm_peers = peers::vm_peers(vm_id);
+
+    let mut channels = Vec::new();
+    let declared = raw_cfg
+        .get("services")
+        .map(|v| {
+            v.as_array()
+                .ok_or_else(|| ax_err_type!(InvalidInput, "services config: must be an array"))
+        })
+        .transpose()?;
+    for entry in declared.into_iter().flatten() {
+        let Some(name) = entry.get("name").and_then(|v| v.as_str()) else {
+            return ax_err!(InvalidInput, "services config: missing `name`");
+        };
+        if name.len() >= 32 {
+            return ax_err!(
+                InvalidInput,
+                format!("services config: name {:?} longer than 31 bytes", name)
+            );
+        }
+        let Some(key) = int(entry, "key") else {
+            return ax_err!(
+                InvalidInput,
+                format!("services config: `{}` has no `key`", name)
+            );
+        };
+        let peer = match int(entry, "peer") {
+            None => SERVICE_PEER_SELF,
+            Some(handle) if vm_peers.iter().any(|(h, _)| *h as u64 == handle) => handle as u32,
+            Some(handle) => {
+                return ax_err!(
+                    InvalidInput,
+                    format!("services config: `{}` uses unknown peer{}", name, handle)
+                );
+            }
+        };
+        channels.push(ServiceChannel {
+            peer,
+            _reserved: 0,
+            key,
+            name: padded(name),
+        });
+    }
+
+    let services = Services {
+        peers: vm_peers
+            .into_iter()
+            .map(|(handle, target)| resolve_peer(handle, target))
+            .collect(),
+        channels,
+        devices: config_devices(vm_id, raw_cfg),
+    };
+    if services.page_size() > PAGE_SIZE_4K {
+        return ax_err!(
+            InvalidInput,
+            format!("VM[{}] services do not fit in a page", vm_id)
+        );
+    }
+    SERVICES.lock().insert(vm_id, services);
+    Ok(())
+}
+
+/// Drops the services description of a VM, called when the VM is destroyed.
+pub fn teardown_vm_services(vm_id: usize) {
+    SERVICES.lock().remove(&vm_id);
+}
+
+/// Returns the services of VM `vm_id`.
+pub fn vm_services(vm_id: usize) -> Option<Services> {
+    SERVICES.lock().get(&vm_id).cloned()
+}
+
+/// Writes the services page of VM `vm_id` to `page`, which must be zeroed.
+///
+/// # Safety
+///
+/// `page` must be valid for writes of a page and aligned to 8 bytes.
+pub unsafe fn write_page(vm_id: usize, page: *mut u8) {
+    let services = SERVICES.lock();
+    let Some(services) = services.get(&vm_id) else {
+        return;
+    };
+    let header = ServicesHeader {
+        magic: SERVICES_MAGIC,
+        version: SERVICES_VERSION,
+        peers: services.peers.len() as u16,
+        channels: services.channels.len() as u16,
+        devices: services.devices.len() as u16,
+        _reserved: 0,
+    };
+    // SAFETY: the entries are 8-byte aligned multiples of 8 bytes and fit in the page, as
+    // checked by `setup_vm_services`.
+    unsafe {
+        let mut ptr = page;
+        ptr.cast::<ServicesHeader>().write(header);
+        ptr = ptr.add(size_of::<ServicesHeader>());
+        for peer in &services.peers {
+            ptr.cast::<ServicePeer>().write(*peer);
+            ptr = ptr.add(size_of::<ServicePeer>());
+        }
+        for channel in &services.channels {
+            ptr.cast::<ServiceChannel>().write(*channel);
+            ptr = ptr.add(size_of::<ServiceChannel>());
+        }
+        for device in &services.devices {
+            ptr.cast::<ServiceDevice>().write(*device);
+            ptr = ptr.add(size_of::<ServiceDevice>());
+        }
+    }
+}
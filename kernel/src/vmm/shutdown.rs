@@ -0,0 +1,262 @@
+//! Orderly shutdown of the whole system.
+//!
+//! A shutdown is requested from the shell (`shutdown`), by a manager VM (see
+//! [`crate::vmm::vmdef`]) with the [`HVC_SYSTEM_SHUTDOWN`](crate::vmm::hvc::HVC_SYSTEM_SHUTDOWN)
+//! hypercall, or by the platform power button, whose host interrupt is given in the config of a
+//! manager VM:
+//!
+//! ```toml
+//! [shutdown]
+//! # Host interrupt of the power button, none by default.
+//! power_button_irq = 0x29
+//! # Time the guests have to shut down before they are stopped, in seconds, 10 by default.
+//! grace_secs = 30
+//! ```
+//!
+//! The sequence runs once, on a thread of its own:
+//!
+//! 1. the guest VMs are sent a shutdown request on their power-state device (see
+//!    [`crate::vmm::power`]), those without one are stopped right away;
+//! 2. the hypervisor waits for them to stop, up to the grace period, then stops the stragglers;
+//! 3. the manager VMs go through the same two steps, so that they see the other VMs go down;
+//! 4. the core dumps being written are completed and the platform is powered off.
+//!
+//! A guest acknowledges the request by powering itself off.
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use std::os::arceos::modules::axhal;
+use std::thread;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use axvm::VMStatus;
+use spin::Mutex;
+
+use crate::vmm::power::{self, PowerEvent};
+use crate::vmm::{VMRef, coredump, vcpus, vm_list, vmdef};
+
+/// Grace period when none is configured or requested.
+const DEFAULT_GRACE: Duration = Duration::from_secs(10);
+/// Time the VMs stopped by force have to reach the `Stopped` state.
+const FORCE_GRACE: Duration = Duration::from_secs(1);
+/// Time the core dumps being written have to complete.
+const DUMP_GRACE: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What requested a shutdown.
+#[derive(Debug, Clone, Copy)]
+pub enum ShutdownSource {
+    Shell,
+    /// The manager VM of this ID.
+    Manager(usize),
+    PowerButton,
+}
+
+/// The `[shutdown]` section of a manager VM.
+#[derive(Debug, Clone, Copy)]
+struct ShutdownConfig {
+    vm_id: usize,
+    power_button_irq: Option<usize>,
+    grace: Option<Duration>,
+}
+
+/// Set once a shutdown is requested.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+/// The shutdown config, from the manager VM that has one.
+static CONFIG: Mutex<Option<ShutdownConfig>> = Mutex::new(None);
+
+/// Whether a shutdown is in progress.
+pub fn in_progress() -> bool {
+    SHUTTING_DOWN.load(Ordering::Acquire)
+}
+
+/// Starts the shutdown sequence, with the configured grace period unless `grace` is given.
+///
+/// Returns a `ResourceBusy` error if a shutdown is already in progress.
+pub fn request(source: ShutdownSource, grace: Option<Duration>) -> AxResult {
+    if SHUTTING_DOWN.swap(true, Ordering::AcqRel) {
+        return ax_err!(ResourceBusy, "shutdown already in progress");
+    }
+    let grace = grace
+        .or_else(|| CONFIG.lock().and_then(|cfg| cfg.grace))
+        .unwrap_or(DEFAULT_GRACE);
+    info!(
+        "System shutdown requested by {:?}, grace period {:?}",
+        source, grace
+    );
+    thread::spawn(move || run(grace));
+    Ok(())
+}
+
+fn run(grace: Duration) -> ! {
+    let (managers, guests): (Vec<_>, Vec<_>) = vm_list::get_vm_list()
+        .into_iter()
+        .partition(|vm| vmdef::is_manager(vm.id()));
+
+    let mut forced = shut_down_vms(&guests, grace);
+    forced += shut_down_vms(&managers, grace);
+    info!("All VMs stopped, {} of them by force", forced);
+
+    flush_state();
+    info!("Powering off");
+    std::process::exit(0)
+}
+
+fn is_active(vm: &VMRef) -> bool {
+    !matches!(
+        vm.vm_status(),
+        VMStatus::Stopped | VMStatus::Loading | VMStatus::Loaded
+    )
+}
+
+fn stop(vm: &VMRef) {
+    if let Err(e) = vm.shutdown() {
+        warn!("VM[{}] failed to stop: {:?}", vm.id(), e);
+    }
+    // Wake up the vCPUs of a suspended VM so that they see it stopping.
+    vcpus::notify_all_vcpus(vm.id());
+}
+
+/// Waits for `vms` to stop until `deadline`, returns those still active.
+fn wait_stopped(vms: Vec<VMRef>, deadline: u64) -> Vec<VMRef> {
+    let mut active = vms;
+    loop {
+        active.retain(is_active);
+        if active.is_empty() || axhal::time::monotonic_time_nanos() >= deadline {
+            return active;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Shuts down `vms`, returns how many had to be stopped by force.
+fn shut_down_vms(vms: &[VMRef], grace: Duration) -> usize {
+    let mut notified = Vec::new();
+    for vm in vms.iter().filter(|vm| is_active(vm)) {
+        match power::request_vm_power_event(vm.id(), PowerEvent::SHUTDOWN_REQUEST) {
+            Ok(()) => notified.push(vm.clone()),
+            Err(_) => {
+                info!("VM[{}] has no power device, stopping it", vm.id());
+                stop(vm);
+            }
+        }
+    }
+
+    let deadline = axhal::time::monotonic_time_nanos() + grace.as_nanos() as u64;
+    let stragglers = wait_stopped(notified, deadline);
+    for vm in &stragglers {
+        warn!(
+            "VM[{}] did not shut down within {:?}, stopping it",
+            vm.id(),
+            grace
+        );
+        stop(vm);
+    }
+
+    // The VMs without a power device were stopped too.
+    let deadline = axhal::time::monotonic_time_nanos() + FORCE_GRACE.as_nanos() as u64;
+    for vm in wait_stopped(vms.to_vec(), deadline) {
+        warn!("VM[{}] still {:?}", vm.id(), vm.vm_status());
+    }
+    stragglers.len()
+}
+
+/// Flushes the state the hypervisor keeps outside of memory.
+///
+/// The only such state is the core dumps (see [`coredump`]), whose files are flushed once
+/// written, so this waits for the dumps being written, up to [`DUMP_GRACE`].
+fn flush_state() {
+    let deadline = axhal::time::monotonic_time_nanos() + DUMP_GRACE.as_nanos() as u64;
+    while coredump::dumps_in_progress() > 0 {
+        if axhal::time::monotonic_time_nanos() >= deadline {
+            warn!(
+                "{} core dump(s) still being written, powering off anyway",
+                coredump::dumps_in_progress()
+            );
+            return;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Applies the `[shutdown]` section of `raw_cfg`.
+///
+/// Does nothing if the VM config has no `[shutdown]` section. Must run after
+/// [`vmdef::setup_vm_manager`].
+pub fn setup_vm_shutdown(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("shutdown").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    if !vmdef::is_manager(vm.id()) {
+        return ax_err!(
+            PermissionDenied,
+            "shutdown config: only manager VMs may configure the shutdown"
+        );
+    }
+    let get = |key: &str| -> AxResult<Option<usize>> {
+        cfg.get(key)
+            .map(|v| {
+                v.as_integer()
+                    .filter(|v| *v >= 0)
+                    .map(|v| v as usize)
+                    .ok_or_else(|| {
+                        ax_err_type!(InvalidInput, format!("shutdown config: invalid `{}`", key))
+                    })
+            })
+            .transpose()
+    };
+    let config = ShutdownConfig {
+        vm_id: vm.id(),
+        power_button_irq: get("power_button_irq")?,
+        grace: get("grace_secs")?.map(|secs| Duration::from_secs(secs as u64)),
+    };
+
+    let mut current = CONFIG.lock();
+    if let Some(other) = *current {
+        return ax_err!(
+            AlreadyExists,
+            format!("shutdown config: already given by VM[{}]", other.vm_id)
+        );
+    }
+    if let Some(irq) = config.power_button_irq {
+        info!("VM[{}] power button on host irq {}", vm.id(), irq);
+    }
+    *current = Some(config);
+    Ok(())
+}
+
+/// Drops the shutdown config of a VM, called when the VM is destroyed.
+pub fn teardown_vm_shutdown(vm_id: usize) {
+    let mut current = CONFIG.lock();
+    if current.is_some_and(|cfg| cfg.vm_id == vm_id) {
+        *current = None;
+    }
+}
+
+/// Handles host interrupt `host_irq` if it's the power button, returns false otherwise.
+pub fn handle_host_irq(host_irq: usize) -> bool {
+    if CONFIG
+        .lock()
+        .is_none_or(|cfg| cfg.power_button_irq != Some(host_irq))
+    {
+        return false;
+    }
+    if !in_progress() {
+        let _ = request(ShutdownSource::PowerButton, None);
+    }
+    true
+}
+
+/// Handles the [`HVC_SYSTEM_SHUTDOWN`](crate::vmm::hvc::HVC_SYSTEM_SHUTDOWN) hypercall of VM
+/// `vm_id`.
+pub fn handle_hypercall(vm_id: usize, args: [u64; 6]) -> AxResult {
+    if !vmdef::is_manager(vm_id) {
+        return ax_err!(
+            PermissionDenied,
+            "only manager VMs may shut down the system"
+        );
+    }
+    let grace = (args[0] != 0).then(|| Duration::from_secs(args[0]));
+    request(ShutdownSource::Manager(vm_id), grace)
+}
@@ -0,0 +1,289 @@
+//! Suspend-to-RAM of guests.
+//!
+//! A guest whose config lets it suspends itself to RAM as on a real board, e.g. a power-managed
+//! guest with nothing to do until its next event:
+//!
+//! ```toml
+//! [suspend]
+//! # Let the guest suspend to RAM, false by default.
+//! enabled = true
+//! # Wake the VM up after this many milliseconds asleep, never by default.
+//! wake_after_ms = 60000
+//! # Free the pages of the memory populated on demand holding only zeros while the VM sleeps,
+//! # false by default. Needs a `[lazy_memory]` section.
+//! free_zero_pages = true
+//! ```
+//!
+//! - aarch64 guests call PSCI `SYSTEM_SUSPEND` once their other vCPUs are off, see
+//!   [`crate::vmm::psci`]. The calling vCPU resumes at the entry point of the call.
+//! - x86_64 guests enter S3 through the ACPI sleep control register, the DSDT describes `_S3_`,
+//!   see [`crate::vmm::acpi`]. The write to the register returns when the VM wakes up and the
+//!   sleep status register then reports `WAK_STS`, so the guest takes its resume path as if the
+//!   firmware returned from the sleep state.
+//!
+//! riscv64 guests can't suspend, SBI system suspend isn't handled.
+//!
+//! The VM is `Suspended` while asleep. Its vCPUs stay in the hypervisor, with their state and the
+//! state of the emulated devices in place, so there is nothing to save or restore. Its memory
+//! stays allocated, but with `free_zero_pages` the pages of the memory populated on demand holding
+//! only zeros are freed, and populated again, zeroed, when the guest accesses them after waking up
+//! (see [`lazymem::release_zero_pages`]). Guest memory isn't compressed.
+//!
+//! The VM wakes up on:
+//!
+//! - an interrupt posted to it, from a peer VM (IVC kick, doorbell) or one of its emulated devices;
+//! - an MSI of one of its passthrough devices;
+//! - its wake-up timer, `wake_after_ms` after it fell asleep;
+//! - `vm resume` in the shell.
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use std::os::arceos::modules::axhal;
+
+use axerrno::{AxResult, ax_err};
+use axvm::VMStatus;
+use spin::Mutex;
+
+use crate::vmm::{VMRef, lazymem, vcpus, vm_list};
+
+/// What woke a VM up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeReason {
+    /// An interrupt posted to the VM by a peer VM or an emulated device.
+    Interrupt,
+    /// An MSI of a passthrough device.
+    Device,
+    /// The wake-up timer.
+    Timer,
+    /// `vm resume` in the shell.
+    Shell,
+}
+
+struct VmSleep {
+    wake_after_ns: Option<u64>,
+    free_zero_pages: bool,
+    asleep: bool,
+    /// `WAK_STS` of the ACPI sleep status register, set on wake-up until the guest clears it.
+    wake_status: bool,
+    sleeps: u64,
+    slept_ns: u64,
+    freed_pages: u64,
+    last_wake: Option<WakeReason>,
+}
+
+/// Suspend-to-RAM state of the VMs allowed to, indexed by VM ID.
+static SLEEP: Mutex<BTreeMap<usize, VmSleep>> = Mutex::new(BTreeMap::new());
+
+/// Maximum number of VMs which may suspend to RAM, VM IDs must be below this.
+const MAX_SLEEP_VMS: usize = 128;
+
+/// Wake state of a VM: awake, asleep, or woken up by [`wake`] with a reason but not yet resumed.
+const AWAKE: u8 = 0;
+const ASLEEP: u8 = 1;
+const WOKEN: u8 = 2;
+
+/// Wake states of the VMs indexed by VM ID, checked and set without a lock on every posted
+/// interrupt, so that waking a VM up doesn't serialize with the VMs sleeping or waking up.
+static WAKE: [AtomicU8; MAX_SLEEP_VMS] = [const { AtomicU8::new(AWAKE) }; MAX_SLEEP_VMS];
+
+impl WakeReason {
+    const ALL: [Self; 4] = [Self::Interrupt, Self::Device, Self::Timer, Self::Shell];
+
+    /// Returns the wake state of a VM woken up for this reason.
+    fn woken(self) -> u8 {
+        WOKEN + Self::ALL.iter().position(|r| *r == self).unwrap() as u8
+    }
+
+    /// Returns the reason of the wake state `state`, `None` if the VM wasn't woken up.
+    fn of(state: u8) -> Option<Self> {
+        Self::ALL.get(state.checked_sub(WOKEN)? as usize).copied()
+    }
+}
+
+/// Suspend-to-RAM counters of a VM, see [`sleep_stats`].
+#[derive(Debug, Clone, Copy)]
+pub struct SleepStats {
+    pub asleep: bool,
+    /// Times the VM suspended to RAM.
+    pub sleeps: u64,
+    /// Time spent asleep in nanoseconds.
+    pub slept_ns: u64,
+    /// Zero pages freed while asleep.
+    pub freed_pages: u64,
+    pub last_wake: Option<WakeReason>,
+}
+
+/// Reads the `[suspend]` section of `raw_cfg`, called when the VM is created.
+pub fn setup_vm_sleep(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("suspend").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    if !cfg.get("enabled").and_then(|v| v.as_bool()).unwrap_or(false) {
+        return Ok(());
+    }
+    let wake_after_ns = match cfg.get("wake_after_ms") {
+        None => None,
+        Some(v) => match v.as_integer() {
+            Some(ms) if ms > 0 => Some(ms as u64 * 1_000_000),
+            _ => {
+                return ax_err!(
+                    InvalidInput,
+                    "suspend config: `wake_after_ms` must be positive"
+                );
+            }
+        },
+    };
+    if vm.id() >= MAX_SLEEP_VMS {
+        return ax_err!(
+            InvalidInput,
+            format!("VM[{}] ID too large to suspend to RAM", vm.id())
+        );
+    }
+    let free_zero_pages = cfg
+        .get("free_zero_pages")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    if free_zero_pages && !raw_cfg.contains_key("lazy_memory") {
+        return ax_err!(
+            InvalidInput,
+            "suspend config: `free_zero_pages` needs a `[lazy_memory]` section"
+        );
+    }
+
+    SLEEP.lock().insert(
+        vm.id(),
+        VmSleep {
+            wake_after_ns,
+            free_zero_pages,
+            asleep: false,
+            wake_status: false,
+            sleeps: 0,
+            slept_ns: 0,
+            freed_pages: 0,
+            last_wake: None,
+        },
+    );
+    info!(
+        "VM[{}] may suspend to RAM{}",
+        vm.id(),
+        match wake_after_ns {
+            Some(ns) => format!(", wakes up after {} ms", ns / 1_000_000),
+            None => "".into(),
+        }
+    );
+    Ok(())
+}
+
+/// Forgets the suspend-to-RAM state of a VM, called when the VM is destroyed.
+pub fn teardown_vm_sleep(vm_id: usize) {
+    if SLEEP.lock().remove(&vm_id).is_some() {
+        WAKE[vm_id].store(AWAKE, Ordering::Release);
+    }
+}
+
+/// Whether VM `vm_id` may suspend to RAM.
+pub fn is_enabled(vm_id: usize) -> bool {
+    SLEEP.lock().contains_key(&vm_id)
+}
+
+/// Suspends `vm` to RAM, called by the vCPU suspending it, and blocks it until the VM wakes up or
+/// stops. The other vCPUs stay in the hypervisor as the VM is `Suspended`.
+pub fn suspend(vm: &VMRef) {
+    let vm_id = vm.id();
+    if !is_enabled(vm_id) {
+        return;
+    }
+    vm.set_vm_status(VMStatus::Suspended);
+    let (wake_after_ns, free_zero_pages) = {
+        let mut sleep = SLEEP.lock();
+        let Some(vm_sleep) = sleep.get_mut(&vm_id) else {
+            return;
+        };
+        vm_sleep.asleep = true;
+        vm_sleep.wake_status = false;
+        WAKE[vm_id].store(ASLEEP, Ordering::Release);
+        (vm_sleep.wake_after_ns, vm_sleep.free_zero_pages)
+    };
+    let start = axhal::time::monotonic_time_nanos();
+    let freed = if free_zero_pages {
+        lazymem::release_zero_pages(vm)
+    } else {
+        0
+    };
+    info!("VM[{}] suspended to RAM, {} zero pages freed", vm_id, freed);
+
+    let deadline = wake_after_ns.map(|ns| start + ns);
+    let timed_out = vcpus::wait_for_deadline(vm_id, deadline, || vm.stopping() || !vm.suspending());
+    if timed_out {
+        wake(vm_id, WakeReason::Timer);
+    }
+
+    let reason = {
+        let mut sleep = SLEEP.lock();
+        let Some(vm_sleep) = sleep.get_mut(&vm_id) else {
+            return;
+        };
+        vm_sleep.asleep = false;
+        vm_sleep.wake_status = true;
+        vm_sleep.sleeps += 1;
+        vm_sleep.slept_ns += axhal::time::monotonic_time_nanos() - start;
+        vm_sleep.freed_pages += freed as u64;
+        // Resumed from the shell if nothing else woke the VM up.
+        let reason =
+            WakeReason::of(WAKE[vm_id].swap(AWAKE, Ordering::AcqRel)).unwrap_or(WakeReason::Shell);
+        vm_sleep.last_wake = Some(reason);
+        reason
+    };
+    if !vm.stopping() {
+        info!("VM[{}] woken up from suspend to RAM: {:?}", vm_id, reason);
+    }
+}
+
+/// Wakes up VM `vm_id` if it's suspended to RAM. Called on the events which wake a VM up, e.g. a
+/// doorbell ring: it takes no lock unless VM `vm_id` is asleep, the first event waking it up then
+/// looks the VM up and notifies its vCPUs.
+pub fn wake(vm_id: usize, reason: WakeReason) {
+    let Some(state) = WAKE.get(vm_id) else {
+        return;
+    };
+    if state.load(Ordering::Acquire) != ASLEEP
+        || state
+            .compare_exchange(ASLEEP, reason.woken(), Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+    {
+        return;
+    }
+    if let Some(vm) = vm_list::get_vm_by_id(vm_id)
+        && vm.suspending()
+    {
+        vm.set_vm_status(VMStatus::Running);
+    }
+    vcpus::notify_all_vcpus(vm_id);
+}
+
+/// `WAK_STS` of the ACPI sleep status register of VM `vm_id`: whether it woke up since the guest
+/// last cleared it.
+pub fn wake_status(vm_id: usize) -> bool {
+    SLEEP
+        .lock()
+        .get(&vm_id)
+        .is_some_and(|vm_sleep| vm_sleep.wake_status)
+}
+
+/// Clears `WAK_STS` of the ACPI sleep status register of VM `vm_id`.
+pub fn clear_wake_status(vm_id: usize) {
+    if let Some(vm_sleep) = SLEEP.lock().get_mut(&vm_id) {
+        vm_sleep.wake_status = false;
+    }
+}
+
+/// Returns the suspend-to-RAM counters of VM `vm_id`, `None` if it may not suspend.
+pub fn sleep_stats(vm_id: usize) -> Option<SleepStats> {
+    SLEEP.lock().get(&vm_id).map(|vm_sleep| SleepStats {
+        asleep: vm_sleep.asleep,
+        sleeps: vm_sleep.sleeps,
+        slept_ns: vm_sleep.slept_ns,
+        freed_pages: vm_sleep.freed_pages,
+        last_wake: vm_sleep.last_wake,
+    })
+}
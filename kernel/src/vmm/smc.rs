@@ -0,0 +1,285 @@
+//! SMC filtering and proxying for aarch64 guests.
+//!
+//! The SMC calls of a guest trap to the hypervisor, which handles the PSCI, SMCCC architecture
+//! and paravirtualized time calls itself (see [`crate::vmm::psci`]). A VM with an `[smc]` section
+//! can also reach the secure firmware, e.g. OP-TEE or platform power firmware, through a per-VM
+//! allowlist:
+//!
+//! ```toml
+//! [smc]
+//! # Calls forwarded to the firmware: whole services ("sip", "oem", "trusted_app" or
+//! # "trusted_os"), function IDs, or inclusive [first, last] function ID ranges.
+//! forward = ["sip", 0xb200_0010, [0xbf00_0000, 0xbf00_00ff]]
+//! # Calls answered by the hypervisor with fixed values of x0-x3, without reaching the firmware.
+//! emulate = [{ fid = 0x8200_0001, ret = [0, 1] }]
+//! ```
+//!
+//! The other secure service calls of the VM (function ID owners 1 to 4 and 48 to 63, see the SMC
+//! Calling Convention) are denied with `NOT_SUPPORTED`. VMs without an `[smc]` section are not
+//! filtered.
+//!
+//! A forwarded call reaches the firmware with the arguments of the guest unchanged, and the client
+//! ID of the VM, its ID plus one (0 is the hypervisor itself), in `w7`. The firmware sees
+//! addresses as physical ones, so calls passing memory only work for guests whose RAM is
+//! identity-mapped; a warning is printed for the others. A trusted OS usually serves a single
+//! client, so only one VM at a time may forward its calls.
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use spin::Mutex;
+
+use crate::hal::arch::smc_call;
+use crate::vmm::VMRef;
+
+const SMCCC_OWNER_SHIFT: u64 = 24;
+const SMCCC_OWNER_MASK: u64 = 0x3f;
+
+const OWNER_SIP: u64 = 2;
+const OWNER_OEM: u64 = 3;
+const OWNERS_TRUSTED_APP: RangeInclusive<u64> = 48..=49;
+const OWNERS_TRUSTED_OS: RangeInclusive<u64> = 50..=63;
+
+/// Owners of the calls filtered: CPU, SiP, OEM and standard secure services, trusted
+/// applications and OSes.
+fn is_secure_owner(owner: u64) -> bool {
+    (1..=4).contains(&owner) || (48..=63).contains(&owner)
+}
+
+const SMCCC_RET_NOT_SUPPORTED: u64 = -1i64 as u64;
+
+fn owner_of(fid: u64) -> u64 {
+    (fid >> SMCCC_OWNER_SHIFT) & SMCCC_OWNER_MASK
+}
+
+/// The calls forwarded by a VM.
+#[derive(Debug, Clone)]
+enum ForwardRule {
+    /// All the calls of the services whose owners are in the range.
+    Owners(RangeInclusive<u64>),
+    /// Function IDs.
+    Fids(RangeInclusive<u64>),
+}
+
+impl ForwardRule {
+    fn matches(&self, fid: u64) -> bool {
+        match self {
+            Self::Owners(owners) => owners.contains(&owner_of(fid)),
+            Self::Fids(fids) => fids.contains(&fid),
+        }
+    }
+}
+
+/// SMC calls of a VM.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SmcStats {
+    pub forwarded: u64,
+    pub emulated: u64,
+    pub denied: u64,
+}
+
+struct VmSmc {
+    forward: Vec<ForwardRule>,
+    /// Values of `x0`-`x3` returned for the emulated calls, indexed by function ID.
+    emulate: BTreeMap<u64, [u64; 4]>,
+    /// Whether the VM forwards trusted OS calls.
+    trusted_os: bool,
+    stats: SmcStats,
+}
+
+/// SMC filters of the VMs with an `[smc]` section, indexed by VM ID.
+static FILTERS: Mutex<BTreeMap<usize, VmSmc>> = Mutex::new(BTreeMap::new());
+
+/// Parses a function ID of the `what` list, which must be of a filtered call.
+fn parse_fid(value: &toml::Value, what: &str) -> AxResult<u64> {
+    value
+        .as_integer()
+        .filter(|fid| (0..=u32::MAX as i64).contains(fid))
+        .map(|fid| fid as u64)
+        .filter(|fid| is_secure_owner(owner_of(*fid)))
+        .ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                format!("smc config: invalid function ID in `{}`", what)
+            )
+        })
+}
+
+fn parse_forward(value: &toml::Value) -> AxResult<ForwardRule> {
+    if let Some(service) = value.as_str() {
+        return Ok(ForwardRule::Owners(match service {
+            "sip" => OWNER_SIP..=OWNER_SIP,
+            "oem" => OWNER_OEM..=OWNER_OEM,
+            "trusted_app" => OWNERS_TRUSTED_APP,
+            "trusted_os" => OWNERS_TRUSTED_OS,
+            _ => {
+                return ax_err!(
+                    InvalidInput,
+                    format!("smc config: unknown service `{}`", service)
+                );
+            }
+        }));
+    }
+    if let Some(range) = value.as_array() {
+        let [first, last] = range.as_slice() else {
+            return ax_err!(
+                InvalidInput,
+                "smc config: a `forward` range must be [first, last]"
+            );
+        };
+        let (first, last) = (parse_fid(first, "forward")?, parse_fid(last, "forward")?);
+        if first > last {
+            return ax_err!(InvalidInput, "smc config: empty `forward` range");
+        }
+        return Ok(ForwardRule::Fids(first..=last));
+    }
+    let fid = parse_fid(value, "forward")?;
+    Ok(ForwardRule::Fids(fid..=fid))
+}
+
+fn parse_emulate(value: &toml::Value) -> AxResult<(u64, [u64; 4])> {
+    let entry = value.as_table().ok_or_else(|| {
+        ax_err_type!(InvalidInput, "smc config: `emulate` entries must be tables")
+    })?;
+    let fid = parse_fid(
+        entry.get("fid").ok_or_else(|| {
+            ax_err_type!(InvalidInput, "smc config: `emulate` entry without `fid`")
+        })?,
+        "emulate",
+    )?;
+    let mut ret = [0; 4];
+    if let Some(values) = entry.get("ret") {
+        let values = values
+            .as_array()
+            .filter(|values| values.len() <= ret.len())
+            .ok_or_else(|| {
+                ax_err_type!(
+                    InvalidInput,
+                    "smc config: `ret` must be an array of up to 4 integers"
+                )
+            })?;
+        for (slot, value) in ret.iter_mut().zip(values) {
+            *slot = value
+                .as_integer()
+                .ok_or_else(|| ax_err_type!(InvalidInput, "smc config: `ret` must hold integers"))?
+                as u64;
+        }
+    }
+    Ok((fid, ret))
+}
+
+/// Records the SMC filter described in the `[smc]` section of `raw_cfg` for the VM.
+///
+/// Does nothing if the VM config has no `[smc]` section.
+pub fn setup_vm_smc(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("smc").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let list = |key: &str| match cfg.get(key) {
+        None => Ok(&[][..]),
+        Some(v) => v.as_array().map(|list| list.as_slice()).ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                format!("smc config: `{}` must be an array", key)
+            )
+        }),
+    };
+    let forward = list("forward")?
+        .iter()
+        .map(parse_forward)
+        .collect::<AxResult<Vec<_>>>()?;
+    let emulate = list("emulate")?
+        .iter()
+        .map(parse_emulate)
+        .collect::<AxResult<BTreeMap<_, _>>>()?;
+    let trusted_os = forward.iter().any(|rule| match rule {
+        ForwardRule::Owners(owners) => owners.contains(OWNERS_TRUSTED_OS.start()),
+        ForwardRule::Fids(fids) => {
+            OWNERS_TRUSTED_OS.contains(&owner_of(*fids.start()))
+                || OWNERS_TRUSTED_OS.contains(&owner_of(*fids.end()))
+        }
+    });
+
+    let mut filters = FILTERS.lock();
+    if trusted_os && let Some((other, _)) = filters.iter().find(|(_, filter)| filter.trusted_os) {
+        return ax_err!(
+            ResourceBusy,
+            format!(
+                "smc config: VM[{}] already forwards trusted OS calls",
+                other
+            )
+        );
+    }
+    if !forward.is_empty()
+        && !vm
+            .memory_regions()
+            .iter()
+            .all(|region| region.is_identical())
+    {
+        warn!(
+            "VM[{}] forwards SMCs but its RAM is not identity-mapped, calls passing memory won't work",
+            vm.id()
+        );
+    }
+
+    info!(
+        "VM[{}] SMC filter: {} forward rule(s), {} emulated call(s)",
+        vm.id(),
+        forward.len(),
+        emulate.len()
+    );
+    filters.insert(
+        vm.id(),
+        VmSmc {
+            forward,
+            emulate,
+            trusted_os,
+            stats: SmcStats::default(),
+        },
+    );
+    Ok(())
+}
+
+/// Forgets the SMC filter of a VM, called when the VM is destroyed.
+pub fn teardown_vm_smc(vm_id: usize) {
+    FILTERS.lock().remove(&vm_id);
+}
+
+/// Whether the call `fid` of VM `vm_id` goes through its SMC filter, see [`handle_call`].
+pub fn is_filtered(vm_id: usize, fid: u64) -> bool {
+    is_secure_owner(owner_of(fid)) && FILTERS.lock().contains_key(&vm_id)
+}
+
+/// Handles the filtered SMC `fid` of `vm`: forwards it to the firmware, emulates or denies it.
+/// Returns the values of `x0`-`x3`.
+pub fn handle_call(vm: &VMRef, fid: u64, args: [u64; 6]) -> [u64; 4] {
+    let forward = {
+        let mut filters = FILTERS.lock();
+        let Some(filter) = filters.get_mut(&vm.id()) else {
+            return [SMCCC_RET_NOT_SUPPORTED, 0, 0, 0];
+        };
+        if let Some(ret) = filter.emulate.get(&fid).copied() {
+            filter.stats.emulated += 1;
+            return ret;
+        }
+        let forward = filter.forward.iter().any(|rule| rule.matches(fid));
+        if forward {
+            filter.stats.forwarded += 1;
+        } else {
+            filter.stats.denied += 1;
+        }
+        forward
+    };
+    if !forward {
+        debug!("VM[{}] SMC {:#x} denied", vm.id(), fid);
+        return [SMCCC_RET_NOT_SUPPORTED, 0, 0, 0];
+    }
+    trace!("VM[{}] SMC {:#x} forwarded", vm.id(), fid);
+    smc_call(fid, args, (vm.id() + 1) as u16)
+}
+
+/// Returns the SMC calls of VM `vm_id`, `None` if it has no SMC filter.
+pub fn smc_stats(vm_id: usize) -> Option<SmcStats> {
+    FILTERS.lock().get(&vm_id).map(|filter| filter.stats)
+}
@@ -0,0 +1,190 @@
+//! Statistical Profiling Extension passthrough for aarch64 guests.
+//!
+//! One VM at a time can use the SPE of the CPUs, so that `perf record` with the `arm_spe` event
+//! works in the guest, e.g. to profile a vendor workload on the target hardware:
+//!
+//! ```toml
+//! [spe]
+//! # Interrupt of the profiling buffer (a PPI), as in the device tree of the host.
+//! irq = 21
+//! ```
+//!
+//! The VM must be partitioned (see [`crate::vmm::sched`]), so that its vCPUs run on dedicated
+//! CPUs and no other guest runs where it samples. The guest programs the sampling controls and
+//! the profiling buffer directly while its vCPUs run: [`VCpuSpe`] gives them to the guest right
+//! before a vCPU enters it and, when the vCPU exits, stops the sampling, drains the records into
+//! the buffer and takes the buffer back, so the hypervisor is never sampled and never writes into
+//! guest memory behind the guest's back. The other guests trap on the profiling registers.
+//!
+//! The buffer management interrupt the hypervisor takes on a CPU of the VM is injected to the
+//! vCPU running there, with the buffer status the vCPU saw. A buffer write faulting at stage 2,
+//! i.e. a buffer outside the RAM of the VM, stops the profiling and is reported. The vCPU backend
+//! must leave `MDCR_EL2` to axvisor, as for the vPMU (see [`crate::vmm::pmu`]).
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use axerrno::{AxResult, ax_err};
+use cpumask::CpuMask;
+use spin::Mutex;
+
+use crate::hal::arch::spe as arch;
+use crate::vmm::{VMRef, sched};
+
+/// The SPE passthrough of the profiling VM.
+struct SpeOwner {
+    vm_id: usize,
+    irq: usize,
+    /// Buffer management interrupts injected.
+    interrupts: AtomicU64,
+    /// Buffer writes faulting at stage 2.
+    stage2_aborts: AtomicU64,
+}
+
+/// The VM using SPE, if any.
+static OWNER: Mutex<Option<SpeOwner>> = Mutex::new(None);
+
+/// SPE passthrough of a VM, for monitoring.
+#[derive(Debug, Clone, Copy)]
+pub struct SpeStats {
+    pub irq: usize,
+    pub interrupts: u64,
+    pub stage2_aborts: u64,
+}
+
+/// Gives SPE to the VM if its config has an `[spe]` section.
+///
+/// Does nothing if the VM config has no `[spe]` section. Must run after
+/// [`sched::setup_vm_scheduling`].
+pub fn setup_vm_spe(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("spe").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let irq = match cfg.get("irq").and_then(|v| v.as_integer()) {
+        Some(irq @ 16..32) => irq as usize,
+        _ => {
+            return ax_err!(
+                InvalidInput,
+                "spe config: `irq` must be the PPI of the profiling buffer"
+            );
+        }
+    };
+    if !arch::is_supported() {
+        return ax_err!(
+            Unsupported,
+            "spe config: the CPU has no profiling buffer usable by guests"
+        );
+    }
+    let vm_id = vm.id();
+    if !sched::is_partitioned(vm_id) {
+        return ax_err!(InvalidInput, "spe config: SPE needs a partitioned VM");
+    }
+    let mut owner = OWNER.lock();
+    if let Some(other) = owner.as_ref() {
+        return ax_err!(
+            ResourceBusy,
+            format!("spe config: SPE is used by VM[{}]", other.vm_id)
+        );
+    }
+    *owner = Some(SpeOwner {
+        vm_id,
+        irq,
+        interrupts: AtomicU64::new(0),
+        stage2_aborts: AtomicU64::new(0),
+    });
+    info!("VM[{}] uses SPE, buffer interrupt {}", vm_id, irq);
+    Ok(())
+}
+
+/// Takes SPE back from the VM, called when the VM is destroyed.
+pub fn teardown_vm_spe(vm_id: usize) {
+    let mut owner = OWNER.lock();
+    if owner.as_ref().is_some_and(|owner| owner.vm_id == vm_id) {
+        *owner = None;
+    }
+}
+
+/// Returns whether VM `vm_id` uses SPE.
+pub fn owns_spe(vm_id: usize) -> bool {
+    OWNER.lock().as_ref().is_some_and(|owner| owner.vm_id == vm_id)
+}
+
+/// Injects the buffer management interrupt the hypervisor took to vCPU `vcpu_id` of `vm`,
+/// running on this CPU. Returns `false` if `irq` is not the SPE interrupt of the VM.
+pub fn handle_host_irq(vm: &VMRef, vcpu_id: usize, irq: usize) -> bool {
+    {
+        let owner = OWNER.lock();
+        let Some(owner) = owner.as_ref().filter(|owner| owner.vm_id == vm.id()) else {
+            return false;
+        };
+        if owner.irq != irq {
+            return false;
+        }
+        owner.interrupts.fetch_add(1, Ordering::Relaxed);
+    }
+    if let Err(e) = vm.inject_interrupt_to_vcpu(CpuMask::one_shot(vcpu_id), irq) {
+        warn!(
+            "VM[{}] VCpu[{}] failed to inject the SPE interrupt: {:?}",
+            vm.id(),
+            vcpu_id,
+            e
+        );
+    }
+    true
+}
+
+/// Returns the SPE passthrough of VM `vm_id`, if it uses SPE.
+pub fn spe_stats(vm_id: usize) -> Option<SpeStats> {
+    OWNER
+        .lock()
+        .as_ref()
+        .filter(|owner| owner.vm_id == vm_id)
+        .map(|owner| SpeStats {
+            irq: owner.irq,
+            interrupts: owner.interrupts.load(Ordering::Relaxed),
+            stage2_aborts: owner.stage2_aborts.load(Ordering::Relaxed),
+        })
+}
+
+/// The profiling state of a vCPU of the profiling VM, as seen by its task.
+pub struct VCpuSpe {
+    vm_id: usize,
+    vcpu_id: usize,
+    state: arch::SpeState,
+    /// Keeps the task on this CPU while the guest owns the profiling buffer.
+    guard: Option<kernel_guard::NoPreempt>,
+}
+
+impl VCpuSpe {
+    pub fn new(vm_id: usize, vcpu_id: usize) -> Option<Self> {
+        owns_spe(vm_id).then(|| Self {
+            vm_id,
+            vcpu_id,
+            state: arch::SpeState::new(),
+            guard: None,
+        })
+    }
+
+    /// Gives the profiling buffer to the guest, called right before the vCPU enters the guest.
+    /// The task is not preempted until [`VCpuSpe::exit`].
+    pub fn enter(&mut self) {
+        self.guard = Some(kernel_guard::NoPreempt::new());
+        arch::load(&self.state);
+    }
+
+    /// Stops the profiling of the guest and takes the buffer back, called when the vCPU exits
+    /// the guest.
+    pub fn exit(&mut self) {
+        let faulted = self.state.stage2_abort();
+        arch::save(&mut self.state);
+        if self.state.stage2_abort() && !faulted {
+            warn!(
+                "VM[{}] VCpu[{}] SPE buffer write at {:#x} faulted at stage 2, profiling stopped",
+                self.vm_id, self.vcpu_id, self.state.pmbptr
+            );
+            if let Some(owner) = OWNER.lock().as_ref() {
+                owner.stage2_aborts.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.guard = None;
+    }
+}
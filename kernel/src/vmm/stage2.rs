@@ -0,0 +1,189 @@
+//! The stage-2 address space of the VMs, on top of the one of `axvm`.
+//!
+//! `axvm` maps and unmaps the regions of the stage-2 of a VM, and uses a block descriptor for each
+//! aligned block of a region. Its address space isn't reachable from here, so every change axvisor
+//! makes to the stage-2 of a VM goes through this module instead of the VM: [`map_region`] and
+//! [`unmap_region`], and what `axvm` lacks:
+//!
+//! - [`map_region_block`], which maps a region with blocks up to a given size: each block is mapped
+//!   with a call of its own, aligned in both address spaces, and the rest page by page;
+//! - [`split_block`], which turns a block into a table of the mappings one level down, in place,
+//!   with the same translation and permissions, so that a part of it can then be mapped with other
+//!   permissions while the rest stays mapped.
+//!
+//! They all hold the stage-2 lock of the VM, so a block is never split while its tables are
+//! changed, and the block mappings of a region are mapped at once. The stage-2 faults are handled
+//! by axvisor too, `axvm` doesn't change the tables on its own.
+//!
+//! The tables are those of `axvm`, from the root of [`VM::ept_root`], with the descriptors of the
+//! architecture (see [`crate::hal::arch::stage2`]). The table of a split block is allocated with
+//! the paging handler of the tables of `axvm` (see [`VmPagingHandler`]), which frees it with the
+//! others when the VM is destroyed. On aarch64 the block is briefly invalid while it's split
+//! (break-before-make): a vCPU accessing it meanwhile faults and enters the guest again.
+use alloc::vec::Vec;
+
+use axaddrspace::{GuestPhysAddr, HostPhysAddr, MappingFlags};
+use axerrno::{AxResult, ax_err, ax_err_type};
+use memory_addr::PAGE_SIZE_4K;
+use page_table_multiarch::PagingHandler;
+use spin::{Mutex, MutexGuard};
+
+use crate::hal::VmPagingHandler;
+use crate::hal::arch::stage2 as arch;
+use crate::vmm::{VM, iommu};
+
+/// Size of a level-2 block.
+pub const BLOCK_2M: usize = 0x20_0000;
+/// Size of a level-1 block.
+pub const BLOCK_1G: usize = 0x4000_0000;
+
+/// Entries of a table below the root.
+const ENTRIES: usize = 512;
+
+/// Number of stage-2 locks, the VMs whose IDs are equal modulo this number share one.
+const STAGE2_LOCKS: usize = 64;
+
+static LOCKS: [Mutex<()>; STAGE2_LOCKS] = [const { Mutex::new(()) }; STAGE2_LOCKS];
+
+/// Takes the stage-2 lock of VM `vm_id`.
+fn lock(vm_id: usize) -> MutexGuard<'static, ()> {
+    LOCKS[vm_id % STAGE2_LOCKS].lock()
+}
+
+/// Maps `size` bytes of host memory at `hpa` to `gpa` in the stage-2 of `vm`.
+pub fn map_region(
+    vm: &VM,
+    gpa: GuestPhysAddr,
+    hpa: HostPhysAddr,
+    size: usize,
+    flags: MappingFlags,
+) -> AxResult {
+    let _guard = lock(vm.id());
+    vm.map_region(gpa, hpa, size, flags)
+}
+
+/// Unmaps `size` bytes at `gpa` from the stage-2 of `vm`.
+pub fn unmap_region(vm: &VM, gpa: GuestPhysAddr, size: usize) -> AxResult {
+    let _guard = lock(vm.id());
+    vm.unmap_region(gpa, size)
+}
+
+/// Maps `[gpa, gpa + size)` of `vm` again, to `hpa` with `flags`, at once.
+pub fn remap_region(
+    vm: &VM,
+    gpa: GuestPhysAddr,
+    hpa: HostPhysAddr,
+    size: usize,
+    flags: MappingFlags,
+) -> AxResult {
+    let _guard = lock(vm.id());
+    vm.unmap_region(gpa, size)?;
+    vm.map_region(gpa, hpa, size, flags)
+}
+
+/// Maps `size` bytes of host memory at `hpa` to `gpa` in the stage-2 of `vm`, with blocks up to
+/// `max_block` wherever both are aligned and with pages elsewhere. Returns the blocks mapped, as
+/// GPAs and sizes.
+pub fn map_region_block(
+    vm: &VM,
+    gpa: GuestPhysAddr,
+    hpa: HostPhysAddr,
+    size: usize,
+    flags: MappingFlags,
+    max_block: usize,
+) -> AxResult<Vec<(usize, usize)>> {
+    if gpa.as_usize() % PAGE_SIZE_4K != 0
+        || hpa.as_usize() % PAGE_SIZE_4K != 0
+        || size % PAGE_SIZE_4K != 0
+    {
+        return ax_err!(
+            InvalidInput,
+            format!("block mapping of {:#x} not page aligned", gpa)
+        );
+    }
+    let _guard = lock(vm.id());
+    let mut blocks = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let (g, h) = (gpa.as_usize() + offset, hpa.as_usize() + offset);
+        let block = [BLOCK_1G, BLOCK_2M]
+            .into_iter()
+            .find(|&bs| bs <= max_block && g % bs == 0 && h % bs == 0 && size - offset >= bs);
+        let len = match block {
+            Some(bs) => {
+                blocks.push((g, bs));
+                bs
+            }
+            // Pages up to the next 2 MB boundary.
+            None => (BLOCK_2M - g % BLOCK_2M).min(size - offset),
+        };
+        vm.map_region(g.into(), h.into(), len, flags)?;
+        offset += len;
+    }
+    Ok(blocks)
+}
+
+/// Returns the entry of `gpa` in `table`, at `level` of the `levels` of the stage-2.
+fn entry(table: HostPhysAddr, gpa: usize, level: usize, levels: usize) -> *mut u64 {
+    let shift = PAGE_SIZE_4K.trailing_zeros() as usize + 9 * (levels - 1 - level);
+    // The root table may be several concatenated tables.
+    let idx = if level == 0 {
+        gpa >> shift
+    } else {
+        (gpa >> shift) % ENTRIES
+    };
+    // SAFETY: `table` is a table of the stage-2 of a VM, mapped in the hypervisor.
+    unsafe {
+        VmPagingHandler::phys_to_virt(table)
+            .as_mut_ptr()
+            .cast::<u64>()
+            .add(idx)
+    }
+}
+
+/// Splits the block of `size` bytes at `gpa` in the stage-2 of `vm` into the blocks or pages one
+/// level down, i.e., a 1 GB block into 2 MB blocks and a 2 MB block into pages.
+pub fn split_block(vm: &VM, gpa: GuestPhysAddr, size: usize) -> AxResult {
+    let levels = arch::levels();
+    let level = match size {
+        BLOCK_2M => levels.checked_sub(2),
+        BLOCK_1G => levels.checked_sub(3),
+        _ => None,
+    }
+    .ok_or_else(|| ax_err_type!(InvalidInput, format!("no block of {:#x} bytes", size)))?;
+    let gpa = gpa.as_usize();
+    let _guard = lock(vm.id());
+    let mut table = vm.ept_root();
+    for l in 0..level {
+        // SAFETY: the entries of the tables of the VM are valid.
+        let pte = unsafe { entry(table, gpa, l, levels).read_volatile() };
+        table = arch::table_of(pte, l).ok_or_else(|| {
+            ax_err_type!(BadState, format!("{:#x} not mapped in the stage-2", gpa))
+        })?;
+    }
+    let slot = entry(table, gpa, level, levels);
+    // SAFETY: as above.
+    let pte = unsafe { slot.read_volatile() };
+    if !arch::is_block(pte, level) {
+        return ax_err!(
+            BadState,
+            format!("no block of {:#x} bytes at {:#x}", size, gpa)
+        );
+    }
+
+    let split = VmPagingHandler::alloc_frame()
+        .ok_or_else(|| ax_err_type!(NoMemory, "no frame for a stage-2 table"))?;
+    let entries = VmPagingHandler::phys_to_virt(split)
+        .as_mut_ptr()
+        .cast::<u64>();
+    for i in 0..ENTRIES {
+        let pte = arch::split_entry(pte, level, i * size / ENTRIES);
+        // SAFETY: the frame is a new table, not visible to the MMU yet.
+        unsafe { entries.add(i).write(pte) };
+    }
+    // SAFETY: `slot` is the block descriptor, the table maps the same.
+    unsafe { arch::replace_block(slot, arch::table_entry(split)) };
+    // The IOMMUs sharing the tables may have cached the block.
+    iommu::flush_vm(vm.id());
+    Ok(())
+}
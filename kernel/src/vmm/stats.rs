@@ -0,0 +1,338 @@
+//! Hypervisor statistics of the VMs.
+//!
+//! Every VM gets counters, updated by the hypervisor as it runs the VM:
+//!
+//! - per vCPU: the exits by reason (the codes of [`crate::vmm::hang`]), the time spent running in
+//!   the guest and the interrupts injected into the vCPU;
+//! - per VM: the bytes the VM moved through IVC channels. The hypervisor does not see the accesses
+//!   of the guests to their channels, so only the ring slots published by a kick and the updates
+//!   of broadcast channels (a data page each) are counted.
+//!
+//! The stolen time of the vCPUs is taken from [`crate::vmm::guest_time`] when the VM has a
+//! `[guest_time]` section. A background thread aggregates the counters every
+//! [`AGGREGATION_PERIOD`] into the figures of the last period (exits, run time and interrupts of
+//! the whole VM), for rates that don't need two queries.
+//!
+//! A guest reads the statistics of its own VM, and a manager VM (see [`crate::vmm::vmdef`]) those
+//! of any VM, with the [`HVC_STATS_QUERY`](crate::vmm::hvc::HVC_STATS_QUERY) hypercall: a
+//! [`StatsHeader`] followed by a [`VCpuStatsRecord`] per vCPU, as many as fit in the buffer. The
+//! shell shows them with `vm show --stats`.
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use std::os::arceos::modules::axhal;
+use std::thread;
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err, ax_err_type};
+use spin::{Mutex, Once};
+
+use crate::vmm::hvc::{self, HVC_STATS_QUERY, HvcService};
+use crate::vmm::{VMRef, guest_time, peers, vm_list, vmdef};
+
+/// `magic` of [`StatsHeader`], "AXST".
+pub const STATS_MAGIC: u32 = u32::from_le_bytes(*b"AXST");
+/// Version of the layouts of the statistics.
+pub const STATS_VERSION: u32 = 1;
+/// Exit reasons counted, indexed by the codes of [`crate::vmm::hang::exit_name`].
+pub const STATS_EXIT_REASONS: usize = 15;
+/// Passed as the VM of the [`HVC_STATS_QUERY`](crate::vmm::hvc::HVC_STATS_QUERY) hypercall to
+/// query the calling VM.
+pub const STATS_SELF: u64 = u64::MAX;
+/// Interval between two aggregations of the counters.
+pub const AGGREGATION_PERIOD: Duration = Duration::from_secs(1);
+
+/// Header of the statistics of a VM, as written to the guest buffer.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct StatsHeader {
+    pub magic: u32,
+    pub version: u32,
+    pub vm_id: u64,
+    /// vCPUs of the VM, the records written may be fewer.
+    pub vcpus: u32,
+    /// Size of a [`VCpuStatsRecord`].
+    pub vcpu_record_size: u32,
+    /// Host time of the query in nanoseconds.
+    pub sampled_at_ns: u64,
+    /// Bytes moved by the VM through IVC channels.
+    pub ivc_bytes: u64,
+    /// Length of the last aggregation period in nanoseconds, 0 before the first one.
+    pub period_ns: u64,
+    /// Exits of all the vCPUs during the last period.
+    pub period_exits: u64,
+    /// Time all the vCPUs ran in the guest during the last period.
+    pub period_run_ns: u64,
+    /// Interrupts injected into the VM during the last period.
+    pub period_irqs: u64,
+}
+
+/// Counters of a vCPU, as written to the guest buffer.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct VCpuStatsRecord {
+    /// Exits by reason.
+    pub exits: [u64; STATS_EXIT_REASONS],
+    /// Time run in the guest in nanoseconds.
+    pub run_ns: u64,
+    /// Time the vCPU was runnable but not running, 0 without `[guest_time]`.
+    pub steal_ns: u64,
+    /// Interrupts injected.
+    pub injected_irqs: u64,
+}
+
+struct VCpuCounters {
+    exits: [AtomicU64; STATS_EXIT_REASONS],
+    run_ns: AtomicU64,
+    irqs: AtomicU64,
+}
+
+/// Totals of a VM at the last aggregation and over the last period.
+#[derive(Default, Clone, Copy)]
+struct Period {
+    at_ns: u64,
+    exits: u64,
+    run_ns: u64,
+    irqs: u64,
+}
+
+struct VmStats {
+    vcpus: Vec<VCpuCounters>,
+    ivc_bytes: AtomicU64,
+    /// The totals at the last aggregation, and the figures of the period before it.
+    periods: Mutex<(Period, Period)>,
+}
+
+impl VmStats {
+    fn totals(&self, now_ns: u64) -> Period {
+        let mut totals = Period {
+            at_ns: now_ns,
+            ..Default::default()
+        };
+        for vcpu in &self.vcpus {
+            totals.exits += vcpu
+                .exits
+                .iter()
+                .map(|exits| exits.load(Ordering::Relaxed))
+                .sum::<u64>();
+            totals.run_ns += vcpu.run_ns.load(Ordering::Relaxed);
+            totals.irqs += vcpu.irqs.load(Ordering::Relaxed);
+        }
+        totals
+    }
+}
+
+static STATS: Mutex<BTreeMap<usize, Arc<VmStats>>> = Mutex::new(BTreeMap::new());
+static AGGREGATOR_THREAD: Once = Once::new();
+
+fn vm_stats(vm_id: usize) -> Option<Arc<VmStats>> {
+    STATS.lock().get(&vm_id).cloned()
+}
+
+/// Sets up the counters of the VM.
+pub fn setup_vm_stats(vm: &VMRef) {
+    let stats = VmStats {
+        vcpus: (0..vm.vcpu_num())
+            .map(|_| VCpuCounters {
+                exits: [const { AtomicU64::new(0) }; STATS_EXIT_REASONS],
+                run_ns: AtomicU64::new(0),
+                irqs: AtomicU64::new(0),
+            })
+            .collect(),
+        ivc_bytes: AtomicU64::new(0),
+        periods: Mutex::new((
+            Period {
+                at_ns: axhal::time::monotonic_time_nanos(),
+                ..Default::default()
+            },
+            Period::default(),
+        )),
+    };
+    STATS.lock().insert(vm.id(), Arc::new(stats));
+    AGGREGATOR_THREAD.call_once(|| {
+        thread::spawn(|| {
+            loop {
+                thread::sleep(AGGREGATION_PERIOD);
+                aggregate_all();
+            }
+        });
+    });
+}
+
+/// Drops the counters of a VM, called when the VM is destroyed.
+pub fn teardown_vm_stats(vm_id: usize) {
+    STATS.lock().remove(&vm_id);
+}
+
+fn aggregate_all() {
+    let now = axhal::time::monotonic_time_nanos();
+    let vms: Vec<_> = STATS.lock().values().cloned().collect();
+    for stats in vms {
+        let totals = stats.totals(now);
+        let mut periods = stats.periods.lock();
+        let (last, period) = &mut *periods;
+        *period = Period {
+            at_ns: totals.at_ns - last.at_ns,
+            exits: totals.exits - last.exits,
+            run_ns: totals.run_ns - last.run_ns,
+            irqs: totals.irqs - last.irqs,
+        };
+        *last = totals;
+    }
+}
+
+/// Counts an interrupt injected into vCPU `vcpu_id` of VM `vm_id`.
+#[inline]
+pub fn count_irq(vm_id: usize, vcpu_id: usize) {
+    if let Some(stats) = vm_stats(vm_id)
+        && let Some(vcpu) = stats.vcpus.get(vcpu_id)
+    {
+        vcpu.irqs.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts `bytes` moved by VM `vm_id` through an IVC channel.
+#[inline]
+pub fn count_ivc_bytes(vm_id: usize, bytes: u64) {
+    if let Some(stats) = vm_stats(vm_id) {
+        stats.ivc_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// The counters of a vCPU, as seen by its task.
+pub struct VCpuStats {
+    stats: Arc<VmStats>,
+    vcpu_id: usize,
+}
+
+impl VCpuStats {
+    pub fn new(vm_id: usize, vcpu_id: usize) -> Option<Self> {
+        let stats = vm_stats(vm_id)?;
+        (vcpu_id < stats.vcpus.len()).then_some(Self { stats, vcpu_id })
+    }
+
+    /// Counts `count` interrupts injected into the vCPU by its task.
+    #[inline]
+    pub fn count_irqs(&self, count: usize) {
+        if count > 0 {
+            self.stats.vcpus[self.vcpu_id]
+                .irqs
+                .fetch_add(count as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Records an exit of reason `exit_code` after `run_ns` in the guest.
+    #[inline]
+    pub fn record_exit(&self, exit_code: u8, run_ns: u64) {
+        let vcpu = &self.stats.vcpus[self.vcpu_id];
+        if let Some(exits) = vcpu.exits.get(exit_code as usize) {
+            exits.fetch_add(1, Ordering::Relaxed);
+        }
+        vcpu.run_ns.fetch_add(run_ns, Ordering::Relaxed);
+    }
+}
+
+/// The statistics of a VM.
+#[derive(Debug, Clone)]
+pub struct VmStatsSnapshot {
+    pub header: StatsHeader,
+    pub vcpus: Vec<VCpuStatsRecord>,
+}
+
+/// Returns the statistics of VM `vm_id`.
+pub fn snapshot(vm_id: usize) -> Option<VmStatsSnapshot> {
+    let stats = vm_stats(vm_id)?;
+    let steal = guest_time::stats(vm_id).map(|stats| stats.vcpu_steal_ns);
+    let vcpus: Vec<_> = stats
+        .vcpus
+        .iter()
+        .enumerate()
+        .map(|(vcpu_id, vcpu)| VCpuStatsRecord {
+            exits: vcpu
+                .exits
+                .each_ref()
+                .map(|exits| exits.load(Ordering::Relaxed)),
+            run_ns: vcpu.run_ns.load(Ordering::Relaxed),
+            steal_ns: steal
+                .as_ref()
+                .and_then(|steal| steal.get(vcpu_id).copied())
+                .unwrap_or(0),
+            injected_irqs: vcpu.irqs.load(Ordering::Relaxed),
+        })
+        .collect();
+    let (_, period) = *stats.periods.lock();
+    Some(VmStatsSnapshot {
+        header: StatsHeader {
+            magic: STATS_MAGIC,
+            version: STATS_VERSION,
+            vm_id: vm_id as u64,
+            vcpus: vcpus.len() as u32,
+            vcpu_record_size: size_of::<VCpuStatsRecord>() as u32,
+            sampled_at_ns: axhal::time::monotonic_time_nanos(),
+            ivc_bytes: stats.ivc_bytes.load(Ordering::Relaxed),
+            period_ns: period.at_ns,
+            period_exits: period.exits,
+            period_run_ns: period.run_ns,
+            period_irqs: period.irqs,
+        },
+        vcpus,
+    })
+}
+
+/// The [`HVC_STATS_QUERY`](crate::vmm::hvc::HVC_STATS_QUERY) hypercall, only manager VMs may
+/// query other VMs.
+pub static HVC_SERVICE: HvcService = HvcService {
+    name: "statistics query",
+    codes: HVC_STATS_QUERY..HVC_STATS_QUERY + 1,
+    permit: hvc::permit_all,
+    deferrable: false,
+    handler: |vm, _, _, args| handle_query(vm, args),
+};
+
+/// Handles the [`HVC_STATS_QUERY`](crate::vmm::hvc::HVC_STATS_QUERY) hypercall of VM `vm_id`,
+/// returns the number of bytes written.
+fn handle_query(vm: &VMRef, args: [u64; 6]) -> AxResult<usize> {
+    let target = match args[0] {
+        STATS_SELF => vm.id(),
+        handle => {
+            if !vmdef::is_manager(vm.id()) {
+                return ax_err!(
+                    PermissionDenied,
+                    format!("VM[{}] is not a manager VM", vm.id())
+                );
+            }
+            peers::resolve(vm.id(), handle as usize)?
+        }
+    };
+    let gpa = GuestPhysAddr::from_usize(args[1] as usize);
+    let size = args[2] as usize;
+    if size < size_of::<StatsHeader>() {
+        return ax_err!(
+            InvalidInput,
+            format!(
+                "statistics buffer of {} bytes, at least {} needed",
+                size,
+                size_of::<StatsHeader>()
+            )
+        );
+    }
+    if vm_list::get_vm_by_id(target).is_none() {
+        return ax_err!(NotFound, format!("VM[{}] not found", target));
+    }
+    let snapshot =
+        snapshot(target).ok_or_else(|| ax_err_type!(NotFound, "VM has no statistics"))?;
+
+    vm.write_to_guest_of(gpa, &snapshot.header)?;
+    let mut written = size_of::<StatsHeader>();
+    for record in &snapshot.vcpus {
+        if written + size_of::<VCpuStatsRecord>() > size {
+            break;
+        }
+        vm.write_to_guest_of(gpa + written, record)?;
+        written += size_of::<VCpuStatsRecord>();
+    }
+    Ok(written)
+}
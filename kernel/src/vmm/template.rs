@@ -0,0 +1,406 @@
+//! Templates of guests, stamped into many identical instances.
+//!
+//! Scaling out identical worker guests by booting each of them is slow: every instance loads its
+//! images and goes through the same initialization. Instead, a VM whose config has a `[template]`
+//! section is the golden VM of a template. Once the guest is prepared, its memory populated on
+//! demand (see [`crate::vmm::lazymem`], which the VM needs) is captured with [`capture`], and the
+//! template is stamped into instances with [`stamp`]:
+//!
+//! ```toml
+//! [template]
+//! # Name of the template.
+//! name = "worker"
+//! # The instances get the first free IDs from this one, the ID of the VM + 1 by default.
+//! first_id = 100
+//! ```
+//!
+//! The captured pages are shared frames (see [`crate::vmm::imgshare`]) that the instances map
+//! read-only and copy on their first write, so an instance costs its first memory region and the
+//! pages it writes, and starts without loading the images the template holds. The first region,
+//! with the firmware and the DTB, isn't part of the template and is loaded as for any VM.
+//!
+//! An instance is created from the config of the golden VM with the ID and the name
+//! `name-instance` of the instance and a `[stamp]` section naming its template. A VM config may
+//! also have such a section itself, as long as its memory regions hold the pages of the template:
+//!
+//! ```toml
+//! [stamp]
+//! template = "worker"
+//! # Number of the instance, the next one of the template by default.
+//! instance = 7
+//! ```
+//!
+//! Instances boot at the entry point of the config, as the register file of the vCPUs is owned
+//! by the vCPU backend and isn't captured. A guest built for templates saves its context in its
+//! memory before it's captured and, when it finds itself stamped, restores it instead of
+//! initializing again. An instance tells it's stamped, and which instance it is, from its
+//! [`VmInfo`](crate::vmm::hvinfo::VmInfo) page (`VM_INFO_F_STAMPED` and `instance`) or from the
+//! `axvisor,instance` property of the `/chosen` node of its DTB. It then derives what must differ
+//! between instances, e.g. addresses or keys, from its identity.
+//!
+//! A template outlives its golden VM, until it's removed. Capturing again replaces the pages of
+//! the template; the instances already stamped keep the pages they were stamped from.
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::HostPhysAddr;
+use axerrno::{AxResult, ax_err, ax_err_type};
+use axvm::VMStatus;
+use axvm::config::AxVMCrateConfig;
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::vmm::{VMRef, config, coredump, imgshare, lazymem, vm_list, vmdef};
+
+struct Template {
+    /// ID of the golden VM, `None` once it's destroyed.
+    source: Option<usize>,
+    /// The config of the golden VM and the raw table it was parsed from, without its
+    /// `[template]` section.
+    config: AxVMCrateConfig,
+    raw_cfg: toml::Table,
+    first_id: usize,
+    /// Captured pages, indexed by guest physical page, `None` before the first capture.
+    pages: Option<BTreeMap<usize, HostPhysAddr>>,
+    /// Host time of the last capture in nanoseconds.
+    captured_ns: u64,
+    /// Number of the next instance.
+    next_instance: u32,
+}
+
+/// A VM stamped from a template.
+#[derive(Debug, Clone)]
+pub struct Stamp {
+    pub template: String,
+    pub instance: u32,
+}
+
+/// A template, as listed by [`templates`].
+#[derive(Debug, Clone)]
+pub struct TemplateInfo {
+    pub name: String,
+    /// ID of the golden VM, `None` once it's destroyed.
+    pub source: Option<usize>,
+    /// Bytes of memory captured, `None` before the first capture.
+    pub captured: Option<usize>,
+    /// Host time of the last capture in nanoseconds.
+    pub captured_ns: u64,
+    /// Instances alive.
+    pub instances: usize,
+}
+
+/// The templates, indexed by name.
+static TEMPLATES: Mutex<BTreeMap<String, Template>> = Mutex::new(BTreeMap::new());
+/// The VMs stamped from a template, indexed by VM ID.
+static STAMPS: Mutex<BTreeMap<usize, Stamp>> = Mutex::new(BTreeMap::new());
+/// Serializes the stamping of instances, which picks free IDs.
+static STAMP_LOCK: Mutex<()> = Mutex::new(());
+
+/// Registers the VM, created from `config`, as the golden VM of the template described in the
+/// `[template]` section of `raw_cfg`.
+///
+/// Does nothing if the VM config has no `[template]` section.
+pub fn setup_vm_template(vm: &VMRef, config: &AxVMCrateConfig, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("template").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let Some(name) = cfg.get("name").and_then(|v| v.as_str()) else {
+        return ax_err!(InvalidInput, "template config: missing `name`");
+    };
+    let first_id = match cfg.get("first_id") {
+        None => vm.id() + 1,
+        Some(id) => id
+            .as_integer()
+            .filter(|id| *id >= 0)
+            .ok_or_else(|| ax_err_type!(InvalidInput, "template config: invalid `first_id`"))?
+            as usize,
+    };
+    if !raw_cfg.contains_key("lazy_memory") {
+        return ax_err!(
+            InvalidInput,
+            "template config: the VM needs a `[lazy_memory]` section"
+        );
+    }
+    if raw_cfg.contains_key("stamp") {
+        return ax_err!(
+            InvalidInput,
+            "template config: a VM stamped from a template can't be a template"
+        );
+    }
+    let mut raw_cfg = raw_cfg.clone();
+    raw_cfg.remove("template");
+
+    let mut templates = TEMPLATES.lock();
+    if let Some(template) = templates.get_mut(name) {
+        if let Some(source) = template.source
+            && source != vm.id()
+        {
+            return ax_err!(
+                AlreadyExists,
+                format!("template config: VM[{}] is already `{}`", source, name)
+            );
+        }
+        // A new golden VM for an existing template, whose pages are kept until it's captured.
+        template.source = Some(vm.id());
+        template.config = config.clone();
+        template.raw_cfg = raw_cfg;
+        template.first_id = first_id;
+    } else {
+        templates.insert(
+            name.to_string(),
+            Template {
+                source: Some(vm.id()),
+                config: config.clone(),
+                raw_cfg,
+                first_id,
+                pages: None,
+                captured_ns: 0,
+                next_instance: 0,
+            },
+        );
+    }
+    info!("VM[{}] is the golden VM of template {:?}", vm.id(), name);
+    Ok(())
+}
+
+/// Maps the pages of the template named in the `[stamp]` section of `raw_cfg` into the VM.
+///
+/// Does nothing if the VM config has no `[stamp]` section. Must run after
+/// [`lazymem::setup_vm_lazy_memory`] and before the images of the VM are loaded.
+pub fn setup_vm_stamp(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("stamp").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let Some(name) = cfg.get("template").and_then(|v| v.as_str()) else {
+        return ax_err!(InvalidInput, "stamp config: missing `template`");
+    };
+    let instance = match cfg.get("instance") {
+        None => None,
+        Some(instance) => Some(
+            instance
+                .as_integer()
+                .and_then(|instance| u32::try_from(instance).ok())
+                .ok_or_else(|| ax_err_type!(InvalidInput, "stamp config: invalid `instance`"))?,
+        ),
+    };
+
+    let mut templates = TEMPLATES.lock();
+    let Some(template) = templates.get_mut(name) else {
+        return ax_err!(NotFound, format!("stamp config: no template {:?}", name));
+    };
+    let Some(pages) = &template.pages else {
+        return ax_err!(
+            BadState,
+            format!("stamp config: template {:?} was not captured", name)
+        );
+    };
+    lazymem::map_template(vm, pages)?;
+    let instance = instance.unwrap_or_else(|| {
+        template.next_instance += 1;
+        template.next_instance - 1
+    });
+    drop(templates);
+
+    info!(
+        "VM[{}] stamped from template {:?}, instance {}",
+        vm.id(),
+        name,
+        instance
+    );
+    STAMPS.lock().insert(
+        vm.id(),
+        Stamp {
+            template: name.to_string(),
+            instance,
+        },
+    );
+    Ok(())
+}
+
+/// Forgets the template role of a VM, called when the VM is destroyed. The template of a golden
+/// VM stays if it was captured.
+pub fn teardown_vm_template(vm_id: usize) {
+    STAMPS.lock().remove(&vm_id);
+    TEMPLATES.lock().retain(|_, template| {
+        if template.source == Some(vm_id) {
+            template.source = None;
+            return template.pages.is_some();
+        }
+        true
+    });
+}
+
+/// Returns the template VM `vm_id` was stamped from and its instance number.
+pub fn stamp_of(vm_id: usize) -> Option<Stamp> {
+    STAMPS.lock().get(&vm_id).cloned()
+}
+
+/// Returns the instance number of VM `vm_id`, if it was stamped from a template.
+pub fn instance_of(vm_id: usize) -> Option<u32> {
+    STAMPS.lock().get(&vm_id).map(|stamp| stamp.instance)
+}
+
+/// Captures the memory of the golden VM `vm` into its template, replacing what the template held.
+/// Returns the bytes captured.
+///
+/// A running VM is paused during the capture, like for a core dump, and resumed afterwards; a
+/// suspended VM stays suspended.
+pub fn capture(vm: &VMRef) -> AxResult<usize> {
+    let vm_id = vm.id();
+    let Some(name) = TEMPLATES
+        .lock()
+        .iter()
+        .find(|(_, template)| template.source == Some(vm_id))
+        .map(|(name, _)| name.clone())
+    else {
+        return ax_err!(
+            InvalidInput,
+            format!("VM[{}] has no `[template]` section", vm_id)
+        );
+    };
+    let status = vm.vm_status();
+    if !matches!(status, VMStatus::Running | VMStatus::Suspended) {
+        return ax_err!(
+            BadState,
+            format!("VM[{}] is {:?}, not running", vm_id, status)
+        );
+    }
+
+    if status == VMStatus::Running {
+        coredump::suspend(vm);
+        if !coredump::wait_quiesced(vm) {
+            coredump::resume(vm);
+            return ax_err!(
+                BadState,
+                format!("VM[{}] vCPUs did not pause, not captured", vm_id)
+            );
+        }
+    }
+    let pages = lazymem::share_pages(vm);
+    if status == VMStatus::Running {
+        coredump::resume(vm);
+    }
+    let pages = pages?;
+    let captured = pages.len() * PAGE_SIZE_4K;
+
+    let old = {
+        let mut templates = TEMPLATES.lock();
+        match templates.get_mut(&name) {
+            Some(template) => {
+                template.captured_ns = axhal::time::monotonic_time_nanos();
+                template.pages.replace(pages)
+            }
+            // Removed meanwhile.
+            None => Some(pages),
+        }
+    };
+    if let Some(old) = old {
+        old.into_values().for_each(imgshare::release);
+    }
+    info!(
+        "VM[{}] captured into template {:?}, {:#x} bytes",
+        vm_id, name, captured
+    );
+    Ok(captured)
+}
+
+/// Creates `count` instances of template `name`, booting them if `boot` is set. Returns the IDs
+/// of the instances.
+///
+/// Each instance is validated against the remaining capacity of the host like a VM defined at
+/// runtime (see [`crate::vmm::vmdef`]). The instances created before a failure are kept.
+pub fn stamp(name: &str, count: usize, boot: bool) -> AxResult<Vec<usize>> {
+    let _guard = STAMP_LOCK.lock();
+    let (base_config, base_raw_cfg, first_id) = {
+        let templates = TEMPLATES.lock();
+        let Some(template) = templates.get(name) else {
+            return ax_err!(NotFound, format!("no template {:?}", name));
+        };
+        if template.pages.is_none() {
+            return ax_err!(BadState, format!("template {:?} was not captured", name));
+        }
+        (
+            template.config.clone(),
+            template.raw_cfg.clone(),
+            template.first_id,
+        )
+    };
+
+    let mut ids = Vec::with_capacity(count);
+    let mut next_id = first_id;
+    for _ in 0..count {
+        let started_ns = axhal::time::monotonic_time_nanos();
+        while vm_list::get_vm_by_id(next_id).is_some() {
+            next_id += 1;
+        }
+        let instance = {
+            let mut templates = TEMPLATES.lock();
+            let template = templates
+                .get_mut(name)
+                .ok_or_else(|| ax_err_type!(NotFound, format!("no template {:?}", name)))?;
+            template.next_instance += 1;
+            template.next_instance - 1
+        };
+
+        let mut config = base_config.clone();
+        config.base.id = next_id;
+        config.base.name = format!("{}-{}", name, instance);
+        let mut raw_cfg = base_raw_cfg.clone();
+        let mut stamp = toml::Table::new();
+        stamp.insert("template".into(), toml::Value::String(name.into()));
+        stamp.insert("instance".into(), toml::Value::Integer(instance as i64));
+        raw_cfg.insert("stamp".into(), toml::Value::Table(stamp));
+
+        vmdef::validate(&config, &raw_cfg, 1)?;
+        let vm_id = config::init_guest_vm_instance(config, &raw_cfg)?;
+        if boot {
+            let vm = vm_list::get_vm_by_id(vm_id)
+                .ok_or_else(|| ax_err_type!(NotFound, format!("VM[{}] was not created", vm_id)))?;
+            vmdef::boot_vm(vm)?;
+        }
+        info!(
+            "VM[{}] stamped from template {:?} in {} us",
+            vm_id,
+            name,
+            (axhal::time::monotonic_time_nanos() - started_ns) / 1000
+        );
+        ids.push(vm_id);
+    }
+    Ok(ids)
+}
+
+/// Removes template `name`, freeing the pages no instance maps anymore.
+pub fn remove(name: &str) -> AxResult {
+    let template = TEMPLATES
+        .lock()
+        .remove(name)
+        .ok_or_else(|| ax_err_type!(NotFound, format!("no template {:?}", name)))?;
+    if let Some(pages) = template.pages {
+        pages.into_values().for_each(imgshare::release);
+    }
+    Ok(())
+}
+
+/// Returns the templates.
+pub fn templates() -> Vec<TemplateInfo> {
+    let stamps = STAMPS.lock();
+    TEMPLATES
+        .lock()
+        .iter()
+        .map(|(name, template)| TemplateInfo {
+            name: name.clone(),
+            source: template.source,
+            captured: template
+                .pages
+                .as_ref()
+                .map(|pages| pages.len() * PAGE_SIZE_4K),
+            captured_ns: template.captured_ns,
+            instances: stamps
+                .values()
+                .filter(|stamp| &stamp.template == name)
+                .count(),
+        })
+        .collect()
+}
@@ -96,6 +96,15 @@ pub fn check_events() {
     }
 }
 
+/// Returns the deadline in nanoseconds of the earliest timer of the current CPU, if any.
+pub fn next_deadline() -> Option<u64> {
+    let timer_list = unsafe { TIMER_LIST.current_ref_mut_raw() };
+    timer_list
+        .lock()
+        .next_deadline()
+        .map(|deadline| deadline.as_nanos() as u64)
+}
+
 // /// Schedule the next timer event based on the periodic interval
 // pub fn scheduler_next_event() {
 //     trace!("Scheduling next event...");
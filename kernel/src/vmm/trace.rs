@@ -0,0 +1,313 @@
+//! Event tracing of the hypervisor.
+//!
+//! Tracing records timestamped events into a ring of [`RING_LEN`] records per physical CPU,
+//! written by the CPU itself without locks, so that latency spikes can be traced back to what the
+//! hypervisor was doing. It is off by default and is turned on with the `trace` shell command,
+//! for some classes of events and optionally a single VM:
+//!
+//! - [`TRACE_CLASS_EXIT`]: VM exits, `arg0` is the exit reason;
+//! - [`TRACE_CLASS_HYPERCALL`]: hypercalls, `arg0` is the hypercall number and `arg1` its first
+//!   argument;
+//! - [`TRACE_CLASS_IRQ`]: interrupts posted to vCPUs, `arg0` is the vector;
+//! - [`TRACE_CLASS_IVC`]: IVC operations, `arg0` is the `TRACE_IVC_*` operation and `arg1` the
+//!   key of the channel.
+//!
+//! A manager VM (see [`crate::vmm::vmdef`]) exports the records with the [`HVC_TRACE_EXPORT`]
+//! hypercall into one of its raw IVC channels: `args[0]` is the key of the channel, `args[1]` the
+//! peer handle of the VM whose records are exported or [`TRACE_ALL_VMS`], and `args[2]` a mask of
+//! `1 << class`, 0 for all classes. The data of the channel receives a [`TraceExportHeader`]
+//! followed by as many [`TraceRecord`]s as fit, oldest first, and the hypercall returns their
+//! number. The records are exported once: the next export starts after the newest record of
+//! each CPU exported or skipped by this one.
+//!
+//! [`HVC_TRACE_EXPORT`]: crate::vmm::hvc::HVC_TRACE_EXPORT
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use std::os::arceos::modules::axhal;
+
+use axerrno::{AxResult, ax_err};
+use spin::Once;
+
+use crate::vmm::hvc::{self, HVC_TRACE_EXPORT, HvcService};
+use crate::vmm::memstat::{self, MemSubsystem};
+use crate::vmm::{ivc, peers};
+
+/// Records kept in the ring of each CPU.
+pub const RING_LEN: usize = 1024;
+
+/// VM exits.
+pub const TRACE_CLASS_EXIT: u8 = 0;
+/// Hypercalls.
+pub const TRACE_CLASS_HYPERCALL: u8 = 1;
+/// Interrupts posted to vCPUs.
+pub const TRACE_CLASS_IRQ: u8 = 2;
+/// IVC operations.
+pub const TRACE_CLASS_IVC: u8 = 3;
+const TRACE_CLASSES: u8 = 4;
+
+/// IVC operations of [`TRACE_CLASS_IVC`] records.
+pub const TRACE_IVC_KICK: u64 = 0;
+pub const TRACE_IVC_BROADCAST_BEGIN: u64 = 1;
+pub const TRACE_IVC_BROADCAST_COMMIT: u64 = 2;
+
+/// `args[1]` of [`crate::vmm::hvc::HVC_TRACE_EXPORT`] exporting the records of all VMs.
+pub const TRACE_ALL_VMS: u64 = u64::MAX;
+/// `magic` of [`TraceExportHeader`].
+pub const TRACE_EXPORT_MAGIC: u32 = u32::from_le_bytes(*b"AXTR");
+/// Version of the layouts of [`TraceExportHeader`] and [`TraceRecord`].
+pub const TRACE_EXPORT_VERSION: u16 = 1;
+
+/// A trace record, as exported to guests.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TraceRecord {
+    pub time_ns: u64,
+    pub vm_id: u32,
+    pub vcpu_id: u16,
+    /// Physical CPU which recorded the event.
+    pub cpu: u8,
+    /// `TRACE_CLASS_*`.
+    pub class: u8,
+    pub arg0: u64,
+    pub arg1: u64,
+}
+
+/// Header of the trace records exported to an IVC channel.
+#[repr(C)]
+pub struct TraceExportHeader {
+    pub magic: u32,
+    pub version: u16,
+    /// Size of a [`TraceRecord`].
+    pub record_size: u16,
+    /// Number of records following the header.
+    pub count: u32,
+    /// Records overwritten in the rings before they could be exported.
+    pub lost: u32,
+}
+
+/// The trace ring of a physical CPU, written by the CPU itself only.
+struct CpuRing {
+    head: AtomicUsize,
+    /// Index of the first record the next export may take.
+    exported: AtomicUsize,
+    times: [AtomicU64; RING_LEN],
+    /// Class (8 bits), vCPU ID (16 bits) and VM ID (32 bits).
+    words: [AtomicU64; RING_LEN],
+    args: [[AtomicU64; 2]; RING_LEN],
+}
+
+impl CpuRing {
+    fn new() -> Self {
+        Self {
+            head: AtomicUsize::new(0),
+            exported: AtomicUsize::new(0),
+            times: [const { AtomicU64::new(0) }; RING_LEN],
+            words: [const { AtomicU64::new(0) }; RING_LEN],
+            args: [const { [const { AtomicU64::new(0) }; 2] }; RING_LEN],
+        }
+    }
+
+    fn read(&self, cpu: usize, i: usize) -> TraceRecord {
+        let slot = i % RING_LEN;
+        let word = self.words[slot].load(Ordering::Relaxed);
+        TraceRecord {
+            time_ns: self.times[slot].load(Ordering::Relaxed),
+            vm_id: (word >> 32) as u32,
+            vcpu_id: (word >> 8) as u16,
+            cpu: cpu as u8,
+            class: word as u8,
+            arg0: self.args[slot][0].load(Ordering::Relaxed),
+            arg1: self.args[slot][1].load(Ordering::Relaxed),
+        }
+    }
+}
+
+static RINGS: Once<Vec<CpuRing>> = Once::new();
+/// Mask of `1 << class` of the classes traced, 0 if tracing is off.
+static CLASS_MASK: AtomicU32 = AtomicU32::new(0);
+/// VM traced, `usize::MAX` for all.
+static VM_FILTER: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Name of a `TRACE_CLASS_*`.
+pub fn class_name(class: u8) -> &'static str {
+    match class {
+        TRACE_CLASS_EXIT => "exit",
+        TRACE_CLASS_HYPERCALL => "hypercall",
+        TRACE_CLASS_IRQ => "irq",
+        TRACE_CLASS_IVC => "ivc",
+        _ => "unknown",
+    }
+}
+
+/// Parses a comma-separated list of class names into a class mask.
+pub fn parse_classes(names: &str) -> AxResult<u32> {
+    let mut mask = 0;
+    for name in names.split(',').map(str::trim) {
+        match (0..TRACE_CLASSES).find(|class| class_name(*class) == name) {
+            Some(class) => mask |= 1 << class,
+            None => return ax_err!(InvalidInput, format!("unknown trace class `{}`", name)),
+        }
+    }
+    Ok(mask)
+}
+
+/// Starts tracing the classes of `class_mask` (0 for all) of VM `vm_id`, or of all VMs.
+pub fn enable(class_mask: u32, vm_id: Option<usize>) {
+    RINGS.call_once(|| {
+        let cpu_count = axruntime::cpu_count();
+        memstat::charge(MemSubsystem::Trace, None, cpu_count * size_of::<CpuRing>());
+        (0..cpu_count).map(|_| CpuRing::new()).collect()
+    });
+    let all = (1 << TRACE_CLASSES) - 1;
+    let class_mask = if class_mask == 0 {
+        all
+    } else {
+        class_mask & all
+    };
+    VM_FILTER.store(vm_id.unwrap_or(usize::MAX), Ordering::Relaxed);
+    CLASS_MASK.store(class_mask, Ordering::Release);
+    info!(
+        "Tracing classes {:#x} of {}",
+        class_mask,
+        match vm_id {
+            Some(vm_id) => format!("VM[{}]", vm_id),
+            None => "all VMs".into(),
+        }
+    );
+}
+
+/// Stops tracing. The records are kept.
+pub fn disable() {
+    CLASS_MASK.store(0, Ordering::Release);
+}
+
+/// Returns the mask of the classes traced and the VM traced, `None` for all.
+pub fn filter() -> (u32, Option<usize>) {
+    let vm_id = VM_FILTER.load(Ordering::Relaxed);
+    (
+        CLASS_MASK.load(Ordering::Relaxed),
+        (vm_id != usize::MAX).then_some(vm_id),
+    )
+}
+
+/// Records an event of `class` in the ring of the current CPU, if it is traced.
+#[inline]
+pub fn record(class: u8, vm_id: usize, vcpu_id: usize, arg0: u64, arg1: u64) {
+    if CLASS_MASK.load(Ordering::Relaxed) & (1 << class) == 0 {
+        return;
+    }
+    let filter = VM_FILTER.load(Ordering::Relaxed);
+    if filter != usize::MAX && filter != vm_id {
+        return;
+    }
+    let Some(rings) = RINGS.get() else {
+        return;
+    };
+    // Interrupt handlers record events too, keep them from writing the same slot.
+    let _guard = kernel_guard::NoPreemptIrqSave::new();
+    let Some(ring) = rings.get(axhal::percpu::this_cpu_id()) else {
+        return;
+    };
+    let slot = ring.head.load(Ordering::Relaxed) % RING_LEN;
+    ring.times[slot].store(axhal::time::monotonic_time_nanos(), Ordering::Relaxed);
+    ring.words[slot].store(
+        class as u64 | (vcpu_id as u64 & 0xffff) << 8 | (vm_id as u64) << 32,
+        Ordering::Relaxed,
+    );
+    ring.args[slot][0].store(arg0, Ordering::Relaxed);
+    ring.args[slot][1].store(arg1, Ordering::Relaxed);
+    ring.head.fetch_add(1, Ordering::Release);
+}
+
+/// Returns the records of all CPUs in the rings, oldest first.
+pub fn records() -> Vec<TraceRecord> {
+    let Some(rings) = RINGS.get() else {
+        return Vec::new();
+    };
+    let mut records: Vec<_> = rings
+        .iter()
+        .enumerate()
+        .flat_map(|(cpu, ring)| {
+            let head = ring.head.load(Ordering::Acquire);
+            (head.saturating_sub(RING_LEN)..head).map(move |i| ring.read(cpu, i))
+        })
+        .collect();
+    records.sort_unstable_by_key(|record| record.time_ns);
+    records
+}
+
+/// Takes the records not exported yet matching `vm_id` and `class_mask`, oldest first, and the
+/// number of records lost since the last export.
+fn take_unexported(vm_id: Option<usize>, class_mask: u32) -> (Vec<TraceRecord>, usize) {
+    let Some(rings) = RINGS.get() else {
+        return (Vec::new(), 0);
+    };
+    let mut lost = 0;
+    let mut records = Vec::new();
+    for (cpu, ring) in rings.iter().enumerate() {
+        let head = ring.head.load(Ordering::Acquire);
+        let exported = ring.exported.swap(head, Ordering::Relaxed);
+        let start = exported.max(head.saturating_sub(RING_LEN));
+        lost += start - exported;
+        records.extend(
+            (start..head)
+                .map(|i| ring.read(cpu, i))
+                .filter(|record| class_mask & (1 << record.class) != 0)
+                .filter(|record| vm_id.is_none_or(|vm_id| record.vm_id as usize == vm_id)),
+        );
+    }
+    records.sort_unstable_by_key(|record| record.time_ns);
+    (records, lost)
+}
+
+/// The [`HVC_TRACE_EXPORT`](crate::vmm::hvc::HVC_TRACE_EXPORT) hypercall, for manager VMs.
+pub static HVC_SERVICE: HvcService = HvcService {
+    name: "trace export",
+    codes: HVC_TRACE_EXPORT..HVC_TRACE_EXPORT + 1,
+    permit: hvc::permit_managers,
+    deferrable: false,
+    handler: |vm, _, _, args| handle_export(vm.id(), args),
+};
+
+/// Handles the [`HVC_TRACE_EXPORT`](crate::vmm::hvc::HVC_TRACE_EXPORT) hypercall of VM `vm_id`,
+/// returns the number of records exported.
+fn handle_export(vm_id: usize, args: [u64; 6]) -> AxResult<usize> {
+    let key = args[0] as usize;
+    let traced_vm = match args[1] {
+        TRACE_ALL_VMS => None,
+        handle => Some(peers::resolve(vm_id, handle as usize)?),
+    };
+    let class_mask = match args[2] as u32 {
+        0 => u32::MAX,
+        mask => mask,
+    };
+
+    let (records, lost) = take_unexported(traced_vm, class_mask);
+    ivc::write_raw_channel(vm_id, key, |data| {
+        let header_size = size_of::<TraceExportHeader>();
+        let record_size = size_of::<TraceRecord>();
+        if data.len() < header_size {
+            return 0;
+        }
+        let count = records.len().min((data.len() - header_size) / record_size);
+        let header = TraceExportHeader {
+            magic: TRACE_EXPORT_MAGIC,
+            version: TRACE_EXPORT_VERSION,
+            record_size: record_size as u16,
+            count: count as u32,
+            lost: (lost + records.len() - count) as u32,
+        };
+        let base = data.as_mut_ptr();
+        // SAFETY: the header and the records fit in `data`, and neither has padding.
+        unsafe {
+            base.cast::<TraceExportHeader>().write_unaligned(header);
+            for (i, record) in records.iter().take(count).enumerate() {
+                base.add(header_size + i * record_size)
+                    .cast::<TraceRecord>()
+                    .write_unaligned(*record);
+            }
+        }
+        count
+    })
+}
@@ -0,0 +1,226 @@
+//! Trace context propagation across VMs.
+//!
+//! A request served by several VMs (e.g., a front-end VM handing it over IVC to a driver VM) can
+//! be followed end to end if each hop tags its work with the same trace context, typically the
+//! 16-byte trace ID of a distributed tracing system. A VM whose config enables it:
+//!
+//! ```toml
+//! [tracing]
+//! # Accept trace contexts from the guest and forward them to and from its peers.
+//! context = true
+//! ```
+//!
+//! sets the trace context of the calling vCPU with the [`HVC_TRACE_CONTEXT`] hypercall:
+//!
+//! - [`TRACE_CTX_SET`]: `args[1]` and `args[2]` are the low and high halves of the context, in
+//!   little endian; an all-zero context clears it.
+//! - [`TRACE_CTX_TAKE`]: `args[1]` is the GPA of a 16-byte buffer receiving the context last
+//!   forwarded to the VM, which is cleared. Returns 1, or 0 if no context was forwarded.
+//!
+//! While a vCPU has a context, the hypervisor stamps it into its trace events: every hypercall of
+//! the vCPU, and the IVC kicks and broadcast commits, which also forward the context to the peer
+//! VMs that enabled `[tracing]` too. A peer taking the forwarded context is stamped as well, so
+//! the time between the events of two VMs is the latency of the hop. The events are kept in a ring
+//! of [`MAX_EVENTS`] records, see [`events`].
+//!
+//! Doorbells (see [`crate::vmm::doorbell`]) are lock-free and carry no context.
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err, ax_err_type};
+use spin::Mutex;
+
+use crate::vmm::VMRef;
+use crate::vmm::hvc::HVC_TRACE_CONTEXT;
+
+/// Operation of [`HVC_TRACE_CONTEXT`] setting the context of the calling vCPU.
+pub const TRACE_CTX_SET: u64 = 0;
+/// Operation of [`HVC_TRACE_CONTEXT`] taking the context forwarded to the VM.
+pub const TRACE_CTX_TAKE: u64 = 1;
+
+const MAX_EVENTS: usize = 256;
+
+/// A 16-byte trace context, opaque to the hypervisor.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext(pub [u8; 16]);
+
+impl TraceContext {
+    fn from_halves(low: u64, high: u64) -> Option<Self> {
+        if low == 0 && high == 0 {
+            return None;
+        }
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&low.to_le_bytes());
+        bytes[8..].copy_from_slice(&high.to_le_bytes());
+        Some(Self(bytes))
+    }
+}
+
+impl core::fmt::Debug for TraceContext {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a trace event records.
+#[derive(Debug, Clone, Copy)]
+pub enum TraceCtxEvent {
+    /// The vCPU made hypercall `nr`.
+    Hypercall { nr: u64 },
+    /// The context was forwarded to VM `to_vm`.
+    Forward { to_vm: usize },
+    /// The vCPU took the context forwarded by VM `from_vm`.
+    Take { from_vm: usize },
+}
+
+/// A trace event stamped with a trace context.
+#[derive(Debug, Clone, Copy)]
+pub struct TraceCtxRecord {
+    pub time_ns: u64,
+    pub vm_id: usize,
+    pub vcpu_id: usize,
+    pub ctx: TraceContext,
+    pub event: TraceCtxEvent,
+}
+
+/// Trace contexts of a VM.
+struct VmTraceCtx {
+    /// Current context of each vCPU.
+    vcpus: Vec<Option<TraceContext>>,
+    /// Context last forwarded to the VM, and the VM forwarding it.
+    inbound: Option<(usize, TraceContext)>,
+}
+
+static TRACE_CTX: Mutex<BTreeMap<usize, VmTraceCtx>> = Mutex::new(BTreeMap::new());
+/// Number of VMs with `[tracing]`, checked before taking any lock on the exit path.
+static TRACING_VMS: AtomicUsize = AtomicUsize::new(0);
+static EVENTS: Mutex<VecDeque<TraceCtxRecord>> = Mutex::new(VecDeque::new());
+
+fn record(vm_id: usize, vcpu_id: usize, ctx: TraceContext, event: TraceCtxEvent) {
+    let record = TraceCtxRecord {
+        time_ns: axhal::time::monotonic_time_nanos(),
+        vm_id,
+        vcpu_id,
+        ctx,
+        event,
+    };
+    let mut events = EVENTS.lock();
+    if events.len() == MAX_EVENTS {
+        events.pop_front();
+    }
+    events.push_back(record);
+}
+
+/// Enables trace contexts for the VM if the `[tracing]` section of `raw_cfg` asks for it.
+pub fn setup_vm_trace_ctx(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("tracing").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let enabled = match cfg.get("context") {
+        None => false,
+        Some(v) => v.as_bool().ok_or_else(|| {
+            ax_err_type!(InvalidInput, "tracing config: `context` must be a boolean")
+        })?,
+    };
+    if !enabled {
+        return Ok(());
+    }
+    let ctx = VmTraceCtx {
+        vcpus: alloc::vec![None; vm.vcpu_num()],
+        inbound: None,
+    };
+    if TRACE_CTX.lock().insert(vm.id(), ctx).is_none() {
+        TRACING_VMS.fetch_add(1, Ordering::Relaxed);
+    }
+    info!("VM[{}] trace contexts enabled", vm.id());
+    Ok(())
+}
+
+/// Forgets the trace contexts of a VM, called when the VM is destroyed.
+pub fn teardown_vm_trace_ctx(vm_id: usize) {
+    if TRACE_CTX.lock().remove(&vm_id).is_some() {
+        TRACING_VMS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Handles the [`HVC_TRACE_CONTEXT`] hypercall of vCPU `vcpu_id` of `vm`.
+pub fn handle_call(vm: &VMRef, vcpu_id: usize, args: [u64; 6]) -> AxResult<usize> {
+    let vm_id = vm.id();
+    let mut contexts = TRACE_CTX.lock();
+    let Some(vm_ctx) = contexts.get_mut(&vm_id) else {
+        return ax_err!(
+            PermissionDenied,
+            format!("VM[{}] has no trace contexts", vm_id)
+        );
+    };
+    match args[0] {
+        TRACE_CTX_SET => {
+            let Some(slot) = vm_ctx.vcpus.get_mut(vcpu_id) else {
+                return ax_err!(InvalidInput, format!("invalid vCPU ID {}", vcpu_id));
+            };
+            *slot = TraceContext::from_halves(args[1], args[2]);
+            Ok(0)
+        }
+        TRACE_CTX_TAKE => {
+            let Some((from_vm, ctx)) = vm_ctx.inbound.take() else {
+                return Ok(0);
+            };
+            drop(contexts);
+            vm.write_to_guest_of(GuestPhysAddr::from(args[1] as usize), &ctx.0)?;
+            record(vm_id, vcpu_id, ctx, TraceCtxEvent::Take { from_vm });
+            Ok(1)
+        }
+        op => ax_err!(
+            InvalidInput,
+            format!("invalid trace context operation {}", op)
+        ),
+    }
+}
+
+fn current(vm_id: usize, vcpu_id: usize) -> Option<TraceContext> {
+    if TRACING_VMS.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    TRACE_CTX
+        .lock()
+        .get(&vm_id)
+        .and_then(|vm_ctx| vm_ctx.vcpus.get(vcpu_id).copied().flatten())
+}
+
+/// Stamps hypercall `nr` of vCPU `vcpu_id` of VM `vm_id` with the context of the vCPU, if any.
+pub fn stamp_hypercall(vm_id: usize, vcpu_id: usize, nr: u64) {
+    if nr == HVC_TRACE_CONTEXT {
+        return;
+    }
+    if let Some(ctx) = current(vm_id, vcpu_id) {
+        record(vm_id, vcpu_id, ctx, TraceCtxEvent::Hypercall { nr });
+    }
+}
+
+/// Forwards the context of vCPU `vcpu_id` of VM `vm_id`, if any, to VM `to_vm`, if it takes
+/// contexts.
+///
+/// Called before `to_vm` is notified, so that the context is there when it handles the
+/// notification.
+pub fn forward(vm_id: usize, vcpu_id: usize, to_vm: usize) {
+    let Some(ctx) = current(vm_id, vcpu_id) else {
+        return;
+    };
+    match TRACE_CTX.lock().get_mut(&to_vm) {
+        Some(peer) => peer.inbound = Some((vm_id, ctx)),
+        None => return,
+    }
+    record(vm_id, vcpu_id, ctx, TraceCtxEvent::Forward { to_vm });
+}
+
+/// Returns the trace events stamped with a trace context, oldest first.
+pub fn events() -> Vec<TraceCtxRecord> {
+    EVENTS.lock().iter().copied().collect()
+}
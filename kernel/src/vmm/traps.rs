@@ -0,0 +1,132 @@
+//! Trap filters of trusted VMs.
+//!
+//! A trusted partitioned VM (see [`crate::vmm::sched`]) can run with fewer traps, trading the
+//! hypervisor's view of the guest for fewer exits:
+//!
+//! ```toml
+//! [traps]
+//! # Trap the idle instruction (WFI, HLT on x86), true by default.
+//! wfi = false
+//! # Trap the spin-wait hint (WFE, PAUSE on x86), true by default.
+//! wfe = false
+//! # "full" (the default) or "minimal": only the system registers and MSRs the hypervisor
+//! # emulates trap, e.g. the EL1 physical timer and the x2APIC.
+//! sysreg = "minimal"
+//! ```
+//!
+//! An untrapped idle or spin-wait keeps the physical CPU busy with the guest, so both need a
+//! partitioned VM, whose CPUs are its own anyway. Minimal system register trapping hides the
+//! guest's configuration changes from the hypervisor, so it's rejected for VMs whose stuck vCPUs
+//! are being diagnosed (`[hang_detect]`).
+//!
+//! axvisor validates and records the filters. The trap controls themselves (`HCR_EL2.TWI`, `TWE`
+//! and `TID*` on aarch64, HLT/PAUSE exiting and the MSR bitmap on x86, `hstatus.VTW` on riscv64)
+//! are programmed by the vCPU backends, which don't take them from axvisor yet: until they do, a
+//! VM whose `[traps]` section lifts any trap fails to be created rather than silently keeping the
+//! default traps.
+use alloc::collections::BTreeMap;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use spin::Mutex;
+
+use crate::vmm::{VMRef, sched};
+
+/// System register trapping of a VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SysregTraps {
+    /// Every system register access the vCPU backend traps by default.
+    Full,
+    /// Only the accesses the hypervisor emulates.
+    Minimal,
+}
+
+/// The trap filters of a VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrapConfig {
+    pub wfi: bool,
+    pub wfe: bool,
+    pub sysreg: SysregTraps,
+}
+
+impl Default for TrapConfig {
+    fn default() -> Self {
+        Self {
+            wfi: true,
+            wfe: true,
+            sysreg: SysregTraps::Full,
+        }
+    }
+}
+
+/// Trap filters of the VMs with a `[traps]` section.
+static TRAPS: Mutex<BTreeMap<usize, TrapConfig>> = Mutex::new(BTreeMap::new());
+
+/// Records the trap filters described in the `[traps]` section of `raw_cfg` for the VM.
+///
+/// Does nothing if the VM config has no `[traps]` section. Must run after
+/// [`sched::setup_vm_scheduling`].
+pub fn setup_vm_traps(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("traps").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let get_bool = |key: &str| match cfg.get(key) {
+        None => Ok(true),
+        Some(v) => v.as_bool().ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                format!("traps config: `{}` must be a boolean", key)
+            )
+        }),
+    };
+    let traps = TrapConfig {
+        wfi: get_bool("wfi")?,
+        wfe: get_bool("wfe")?,
+        sysreg: match cfg.get("sysreg").and_then(|v| v.as_str()).unwrap_or("full") {
+            "full" => SysregTraps::Full,
+            "minimal" => SysregTraps::Minimal,
+            sysreg => {
+                return ax_err!(
+                    InvalidInput,
+                    format!("traps config: unsupported `sysreg` mode `{}`", sysreg)
+                );
+            }
+        },
+    };
+
+    if (!traps.wfi || !traps.wfe) && !sched::is_partitioned(vm.id()) {
+        return ax_err!(
+            InvalidInput,
+            "traps config: untrapped WFI or WFE needs a partitioned VM"
+        );
+    }
+    if traps.sysreg == SysregTraps::Minimal && raw_cfg.contains_key("hang_detect") {
+        return ax_err!(
+            InvalidInput,
+            "traps config: minimal system register trapping conflicts with `[hang_detect]`"
+        );
+    }
+
+    if traps != TrapConfig::default() {
+        return ax_err!(
+            Unsupported,
+            format!(
+                "VM[{}] traps config: the vCPU backends don't take trap filters yet",
+                vm.id()
+            )
+        );
+    }
+
+    info!("VM[{}] trap filters: {:?}", vm.id(), traps);
+    TRAPS.lock().insert(vm.id(), traps);
+    Ok(())
+}
+
+/// Forgets the trap filters of a VM, called when the VM is destroyed.
+pub fn teardown_vm_traps(vm_id: usize) {
+    TRAPS.lock().remove(&vm_id);
+}
+
+/// Returns the trap filters of VM `vm_id`.
+pub fn trap_config(vm_id: usize) -> TrapConfig {
+    TRAPS.lock().get(&vm_id).copied().unwrap_or_default()
+}
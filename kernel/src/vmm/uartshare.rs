@@ -0,0 +1,610 @@
+//! The UART of the hypervisor console lent to one VM at a time.
+//!
+//! Boards with a single UART can't give each guest a serial port of its own, and the emulated
+//! consoles (see [`crate::vmm::virtio`]) need a driver the guest may not have, e.g. in its
+//! bootloader. A VM sharing the UART with the hypervisor declares it with a `[shared_uart]`
+//! section in its VM config:
+//!
+//! ```toml
+//! [shared_uart]
+//! # Host physical base of the UART of the hypervisor console, the same for all the VMs.
+//! hpa = 0x0900_0000
+//! # "pl011" or "16550", the same for all the VMs.
+//! kind = "pl011"
+//! # For a 16550, register `n` is at `n << reg_shift`, accessed as 32-bit from 2. 0 by default.
+//! reg_shift = 0
+//! # Guest physical base of the UART in the VM, page aligned and outside of guest memory.
+//! gpa = 0x0900_0000
+//! # Interrupt injected to the VM for the UART while it owns it.
+//! irq = 0x21
+//! ```
+//!
+//! The UART belongs to the hypervisor until it's handed to a VM, by pressing [`ESCAPE_KEY`]
+//! (`Ctrl-]`) on the console or with the [`HVC_UART_ASSIGN`] hypercall of a manager VM (see
+//! [`crate::vmm::vmdef`]), `args[0]` being the peer handle of the VM or [`UART_TO_HYPERVISOR`].
+//! The escape key hands the UART over in turn to each VM sharing it, by VM ID, then back to the
+//! hypervisor; the guest reads a NUL in its place.
+//!
+//! The accesses of the VMs to the UART are trapped. Those of the owner go to the UART, so its
+//! driver works as on bare metal, while the others see an idle UART, which never receives and
+//! whose transmissions are dropped; the identification registers of a PL011 always read through.
+//! The interrupt of the UART is taken by the hypervisor and injected to the owner, with the UART
+//! interrupt masked until the owner accesses the UART again, which is how it drains the UART. The
+//! line settings and interrupt enables of each owner, the hypervisor included, are saved when it
+//! loses the UART and restored when it gets it back, so that a guest and the hypervisor console
+//! may use different baud rates.
+//!
+//! The messages of the hypervisor are still written to the UART while a VM owns it, interleaved
+//! with the output of the guest. Only memory-mapped UARTs can be shared, not the I/O port UART of
+//! x86 PCs.
+//!
+//! [`HVC_UART_ASSIGN`]: crate::vmm::hvc::HVC_UART_ASSIGN
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::device::AccessWidth;
+use axaddrspace::{GuestPhysAddr, HostPhysAddr};
+use axerrno::{AxResult, ax_err, ax_err_type};
+use kspin::SpinNoIrq;
+use memory_addr::PAGE_SIZE_4K;
+
+use crate::vmm::hvc::{self, HVC_UART_ASSIGN, HvcService};
+use crate::vmm::irq::IrqLine;
+use crate::vmm::mmio::{self, MmioTrapHandler};
+use crate::vmm::{VMRef, peers};
+
+/// `args[0]` of [`crate::vmm::hvc::HVC_UART_ASSIGN`] giving the UART back to the hypervisor.
+pub const UART_TO_HYPERVISOR: u64 = u64::MAX;
+/// Console key handing the UART to the next owner, `Ctrl-]`.
+pub const ESCAPE_KEY: u8 = 0x1d;
+
+const PL011_DR: usize = 0x00;
+const PL011_FR: usize = 0x18;
+const PL011_IBRD: usize = 0x24;
+const PL011_FBRD: usize = 0x28;
+const PL011_LCR_H: usize = 0x2c;
+const PL011_CR: usize = 0x30;
+const PL011_IFLS: usize = 0x34;
+const PL011_IMSC: usize = 0x38;
+/// First of the peripheral and PrimeCell identification registers.
+const PL011_ID: usize = 0xfe0;
+/// `FR` of an idle PL011: receive FIFO empty, transmit FIFO empty.
+const PL011_FR_IDLE: usize = (1 << 4) | (1 << 7);
+
+const UART_RBR: usize = 0;
+const UART_DLM: usize = 1;
+const UART_IER: usize = 1;
+const UART_IIR: usize = 2;
+const UART_FCR: usize = 2;
+const UART_LCR: usize = 3;
+const UART_MCR: usize = 4;
+const UART_LSR: usize = 5;
+const UART_SCR: usize = 7;
+const UART_LCR_DLAB: u32 = 1 << 7;
+/// `IIR` of an idle 16550: no interrupt pending.
+const UART_IIR_IDLE: usize = 0x01;
+/// `LSR` of an idle 16550: transmitter empty.
+const UART_LSR_IDLE: usize = 0x60;
+/// `FCR` restored for the hypervisor, which can't read it back: FIFOs enabled.
+const UART_FCR_HYPERVISOR: u32 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UartKind {
+    Pl011,
+    Ns16550,
+}
+
+/// The registers of the UART in the linear mapping of the hypervisor.
+#[derive(Clone, Copy)]
+struct Regs {
+    kind: UartKind,
+    base: usize,
+    reg_shift: usize,
+}
+
+/// The settings of an owner while it doesn't own the UART.
+#[derive(Debug, Clone, Copy, Default)]
+struct Saved([u32; 6]);
+
+impl Regs {
+    /// Returns the offset of 16550 register `reg`.
+    fn offset(&self, reg: usize) -> usize {
+        reg << self.reg_shift
+    }
+
+    fn read(&self, offset: usize, width: AccessWidth) -> usize {
+        let addr = self.base + offset;
+        // SAFETY: the UART is mapped in the linear mapping of the hypervisor, the offset is within
+        // its page and accesses to device memory are volatile.
+        unsafe {
+            match width {
+                AccessWidth::Byte => (addr as *const u8).read_volatile() as usize,
+                AccessWidth::Word => (addr as *const u16).read_volatile() as usize,
+                AccessWidth::Dword => (addr as *const u32).read_volatile() as usize,
+                AccessWidth::Qword => (addr as *const u64).read_volatile() as usize,
+            }
+        }
+    }
+
+    fn write(&self, offset: usize, width: AccessWidth, val: usize) {
+        let addr = self.base + offset;
+        // SAFETY: as for `read`.
+        unsafe {
+            match width {
+                AccessWidth::Byte => (addr as *mut u8).write_volatile(val as u8),
+                AccessWidth::Word => (addr as *mut u16).write_volatile(val as u16),
+                AccessWidth::Dword => (addr as *mut u32).write_volatile(val as u32),
+                AccessWidth::Qword => (addr as *mut u64).write_volatile(val as u64),
+            }
+        }
+    }
+
+    /// Width of the accesses of the hypervisor to the registers.
+    fn width(&self) -> AccessWidth {
+        match self.kind {
+            UartKind::Pl011 => AccessWidth::Dword,
+            UartKind::Ns16550 if self.reg_shift >= 2 => AccessWidth::Dword,
+            UartKind::Ns16550 => AccessWidth::Byte,
+        }
+    }
+
+    fn get(&self, reg: usize) -> u32 {
+        self.read(reg, self.width()) as u32
+    }
+
+    fn set(&self, reg: usize, val: u32) {
+        self.write(reg, self.width(), val as usize);
+    }
+
+    fn save(&self) -> Saved {
+        match self.kind {
+            UartKind::Pl011 => Saved([
+                self.get(PL011_IBRD),
+                self.get(PL011_FBRD),
+                self.get(PL011_LCR_H),
+                self.get(PL011_IFLS),
+                self.get(PL011_IMSC),
+                self.get(PL011_CR),
+            ]),
+            UartKind::Ns16550 => {
+                let lcr = self.get(self.offset(UART_LCR));
+                self.set(self.offset(UART_LCR), lcr | UART_LCR_DLAB);
+                let dll = self.get(self.offset(UART_RBR));
+                let dlm = self.get(self.offset(UART_DLM));
+                self.set(self.offset(UART_LCR), lcr & !UART_LCR_DLAB);
+                Saved([
+                    lcr,
+                    dll,
+                    dlm,
+                    self.get(self.offset(UART_IER)),
+                    self.get(self.offset(UART_MCR)),
+                    self.get(self.offset(UART_SCR)),
+                ])
+            }
+        }
+    }
+
+    /// Restores `saved`, with `fcr` the FIFO control of the owner for a 16550.
+    fn restore(&self, saved: &Saved, fcr: u32) {
+        let [a, b, c, d, e, f] = saved.0;
+        match self.kind {
+            UartKind::Pl011 => {
+                // The UART is disabled while its line settings change.
+                self.set(PL011_CR, 0);
+                self.set(PL011_IBRD, a);
+                self.set(PL011_FBRD, b);
+                self.set(PL011_LCR_H, c);
+                self.set(PL011_IFLS, d);
+                self.set(PL011_IMSC, e);
+                self.set(PL011_CR, f);
+            }
+            UartKind::Ns16550 => {
+                self.set(self.offset(UART_LCR), a | UART_LCR_DLAB);
+                self.set(self.offset(UART_RBR), b);
+                self.set(self.offset(UART_DLM), c);
+                self.set(self.offset(UART_LCR), a & !UART_LCR_DLAB);
+                self.set(self.offset(UART_FCR), fcr);
+                self.set(self.offset(UART_IER), d);
+                self.set(self.offset(UART_MCR), e);
+                self.set(self.offset(UART_SCR), f);
+            }
+        }
+    }
+}
+
+/// A VM sharing the UART.
+struct Sharer {
+    gpa: GuestPhysAddr,
+    irq: usize,
+    /// Its settings while it doesn't own the UART, `None` until it owned it.
+    saved: Option<Saved>,
+    /// The last `FCR` it wrote, write-only on a 16550.
+    fcr: u32,
+    /// Whether its last `LCR` selects the divisor latch, which shadows the receive buffer.
+    dlab: bool,
+    handovers: u64,
+    accesses: u64,
+}
+
+struct SharedUart {
+    regs: Regs,
+    hpa: HostPhysAddr,
+    /// Interrupt of the UART in the hypervisor, `None` if the console input is polled.
+    host_irq: Option<usize>,
+    /// The VM owning the UART, `None` for the hypervisor.
+    owner: Option<usize>,
+    /// The settings of the hypervisor while a VM owns the UART.
+    host_saved: Option<Saved>,
+    /// Whether the UART interrupt is masked until the owner accesses the UART.
+    masked: bool,
+    sharers: BTreeMap<usize, Sharer>,
+}
+
+/// The shared UART, while VMs share it. Taken in the console interrupt handler.
+static UART: SpinNoIrq<Option<SharedUart>> = SpinNoIrq::new(None);
+
+/// The sharing of the UART by a VM, for monitoring.
+#[derive(Debug, Clone, Copy)]
+pub struct SharedUartStats {
+    pub owner: bool,
+    /// Times the UART was handed to the VM.
+    pub handovers: u64,
+    /// Accesses of the VM to the UART.
+    pub accesses: u64,
+}
+
+/// The UART hypercall, for manager VMs.
+pub static HVC_SERVICE: HvcService = HvcService {
+    name: "uart",
+    codes: HVC_UART_ASSIGN..HVC_UART_ASSIGN + 1,
+    permit: hvc::permit_managers,
+    deferrable: false,
+    handler: |vm, _, _, args| handle_assign(vm, args[0]).map(|_| 0),
+};
+
+impl SharedUart {
+    /// Hands the UART to `owner`, returns the interrupt to raise in the new owner.
+    fn assign(&mut self, owner: Option<usize>) -> Option<IrqLine> {
+        if owner == self.owner {
+            return None;
+        }
+        let saved = self.regs.save();
+        match self.owner {
+            Some(vm_id) => {
+                if let Some(sharer) = self.sharers.get_mut(&vm_id) {
+                    sharer.saved = Some(saved);
+                }
+            }
+            None => self.host_saved = Some(saved),
+        }
+        if self.masked {
+            self.masked = false;
+            if let Some(irq) = self.host_irq {
+                axhal::irq::set_enable(irq, true);
+            }
+        }
+        self.owner = owner;
+        match owner {
+            Some(vm_id) => {
+                let sharer = self.sharers.get_mut(&vm_id)?;
+                if let Some(saved) = sharer.saved {
+                    self.regs.restore(&saved, sharer.fcr);
+                }
+                sharer.handovers += 1;
+                Some(IrqLine::new(vm_id, sharer.irq))
+            }
+            None => {
+                if let Some(saved) = self.host_saved.take() {
+                    self.regs.restore(&saved, UART_FCR_HYPERVISOR);
+                }
+                None
+            }
+        }
+    }
+
+    /// The owner after the current one in the escape key order.
+    fn next_owner(&self) -> Option<usize> {
+        match self.owner {
+            None => self.sharers.keys().next().copied(),
+            Some(vm_id) => self
+                .sharers
+                .range(vm_id + 1..)
+                .next()
+                .map(|(vm_id, _)| *vm_id),
+        }
+    }
+}
+
+/// Hands the UART to `owner` and tells the console, raising the interrupt of the new owner.
+fn hand_over(uart: &mut SharedUart, owner: Option<usize>) {
+    // With the settings of the hypervisor.
+    if owner.is_some() {
+        announce(owner);
+    }
+    let line = uart.assign(owner);
+    if owner.is_none() {
+        announce(owner);
+    }
+    if let Some(line) = line {
+        line.raise();
+    }
+}
+
+fn announce(owner: Option<usize>) {
+    let line = match owner {
+        Some(vm_id) => format!("\r\n[axvisor] UART handed to VM[{}], Ctrl-] to switch\r\n", vm_id),
+        None => "\r\n[axvisor] UART back to the hypervisor\r\n".into(),
+    };
+    axhal::console::write_bytes(line.as_bytes());
+}
+
+/// Handles [`ESCAPE_KEY`] received by the hypervisor console: hands the UART to the first VM
+/// sharing it. Returns whether the key was taken, `false` if no VM shares the UART.
+pub fn escape() -> bool {
+    let mut uart = UART.lock();
+    let Some(uart) = uart.as_mut() else {
+        return false;
+    };
+    let owner = uart.next_owner();
+    hand_over(uart, owner);
+    true
+}
+
+/// Whether a VM owns the UART, which the hypervisor console must not read then.
+pub fn is_lent() -> bool {
+    UART.lock().as_ref().is_some_and(|uart| uart.owner.is_some())
+}
+
+/// Handles the UART interrupt for the VM owning the UART, returns whether a VM owns it. The
+/// interrupt is masked until the VM accesses the UART.
+pub fn forward_irq() -> bool {
+    let mut uart = UART.lock();
+    let Some(uart) = uart.as_mut() else {
+        return false;
+    };
+    let Some(vm_id) = uart.owner else {
+        return false;
+    };
+    if let Some(irq) = uart.host_irq {
+        axhal::irq::set_enable(irq, false);
+        uart.masked = true;
+    }
+    if let Some(sharer) = uart.sharers.get(&vm_id) {
+        IrqLine::new(vm_id, sharer.irq).raise();
+    }
+    true
+}
+
+/// Handles the `HUartAssign` hypercall of the manager `vm`.
+fn handle_assign(vm: &VMRef, handle: u64) -> AxResult {
+    let owner = match handle {
+        UART_TO_HYPERVISOR => None,
+        handle => Some(peers::resolve(vm.id(), handle as usize)?),
+    };
+    let mut uart = UART.lock();
+    let Some(uart) = uart.as_mut() else {
+        return ax_err!(NotFound, "no VM shares the UART");
+    };
+    if let Some(vm_id) = owner
+        && !uart.sharers.contains_key(&vm_id)
+    {
+        return ax_err!(NotFound, format!("VM[{}] doesn't share the UART", vm_id));
+    }
+    hand_over(uart, owner);
+    Ok(())
+}
+
+/// The trapped UART of a VM.
+struct UartTrap {
+    vm_id: usize,
+}
+
+impl MmioTrapHandler for UartTrap {
+    fn handle_read(&self, _vm: &VMRef, addr: GuestPhysAddr, width: AccessWidth) -> AxResult<usize> {
+        let mut uart = UART.lock();
+        let uart = uart.as_mut().ok_or_else(|| ax_err_type!(BadState))?;
+        let regs = uart.regs;
+        let sharer = uart
+            .sharers
+            .get_mut(&self.vm_id)
+            .ok_or_else(|| ax_err_type!(BadState))?;
+        let offset = addr.as_usize() - sharer.gpa.as_usize();
+        sharer.accesses += 1;
+        if uart.owner != Some(self.vm_id) {
+            return Ok(match regs.kind {
+                UartKind::Pl011 if offset >= PL011_ID => regs.read(offset, width),
+                UartKind::Pl011 if offset == PL011_FR => PL011_FR_IDLE,
+                UartKind::Ns16550 if offset == regs.offset(UART_IIR) => UART_IIR_IDLE,
+                UartKind::Ns16550 if offset == regs.offset(UART_LSR) => UART_LSR_IDLE,
+                _ => 0,
+            });
+        }
+
+        let is_data = match regs.kind {
+            UartKind::Pl011 => offset == PL011_DR,
+            UartKind::Ns16550 => offset == regs.offset(UART_RBR) && !sharer.dlab,
+        };
+        if uart.masked {
+            uart.masked = false;
+            if let Some(irq) = uart.host_irq {
+                axhal::irq::set_enable(irq, true);
+            }
+        }
+        let val = regs.read(offset, width);
+        if is_data && val & 0xff == ESCAPE_KEY as usize {
+            let owner = uart.next_owner();
+            hand_over(uart, owner);
+            return Ok(0);
+        }
+        Ok(val)
+    }
+
+    fn handle_write(
+        &self,
+        _vm: &VMRef,
+        addr: GuestPhysAddr,
+        width: AccessWidth,
+        val: usize,
+    ) -> AxResult {
+        let mut uart = UART.lock();
+        let uart = uart.as_mut().ok_or_else(|| ax_err_type!(BadState))?;
+        let regs = uart.regs;
+        let owner = uart.owner;
+        let sharer = uart
+            .sharers
+            .get_mut(&self.vm_id)
+            .ok_or_else(|| ax_err_type!(BadState))?;
+        let offset = addr.as_usize() - sharer.gpa.as_usize();
+        sharer.accesses += 1;
+        if regs.kind == UartKind::Ns16550 {
+            if offset == regs.offset(UART_FCR) {
+                sharer.fcr = val as u32;
+            } else if offset == regs.offset(UART_LCR) {
+                sharer.dlab = val as u32 & UART_LCR_DLAB != 0;
+            }
+        }
+        if owner == Some(self.vm_id) {
+            regs.write(offset, width, val);
+        }
+        Ok(())
+    }
+}
+
+/// Shares the UART with the VM as described in the `[shared_uart]` section of `raw_cfg`.
+///
+/// Does nothing if the VM config has no `[shared_uart]` section.
+pub fn setup_vm_shared_uart(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("shared_uart").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let int = |key: &str| {
+        cfg.get(key)
+            .and_then(|v| v.as_integer())
+            .map(|v| v as usize)
+    };
+    let get = |key: &str| {
+        int(key).ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                format!("shared_uart config: missing `{}`", key)
+            )
+        })
+    };
+    let hpa = get("hpa")?;
+    let gpa = get("gpa")?;
+    let irq = get("irq")?;
+    let reg_shift = int("reg_shift").unwrap_or(0);
+    let kind = match cfg.get("kind").and_then(|v| v.as_str()) {
+        Some("pl011") => UartKind::Pl011,
+        Some("16550") => UartKind::Ns16550,
+        _ => {
+            return ax_err!(
+                InvalidInput,
+                "shared_uart config: `kind` must be \"pl011\" or \"16550\""
+            );
+        }
+    };
+    if hpa % PAGE_SIZE_4K != 0 || gpa % PAGE_SIZE_4K != 0 {
+        return ax_err!(
+            InvalidInput,
+            "shared_uart config: `hpa` and `gpa` must be page aligned"
+        );
+    }
+    if reg_shift > 2 {
+        return ax_err!(InvalidInput, "shared_uart config: `reg_shift` at most 2");
+    }
+    if vm.memory_regions().iter().any(|region| {
+        gpa < region.gpa.as_usize() + region.size() && region.gpa.as_usize() < gpa + PAGE_SIZE_4K
+    }) {
+        return ax_err!(
+            InvalidInput,
+            format!("shared_uart config: {:#x} overlaps guest memory", gpa)
+        );
+    }
+
+    let mut uart = UART.lock();
+    let regs = Regs {
+        kind,
+        base: axhal::mem::phys_to_virt(hpa.into()).as_usize(),
+        reg_shift,
+    };
+    if let Some(shared) = uart.as_ref()
+        && (shared.hpa != HostPhysAddr::from(hpa)
+            || shared.regs.kind != kind
+            || shared.regs.reg_shift != reg_shift)
+    {
+        return ax_err!(
+            InvalidInput,
+            format!(
+                "shared_uart config: the UART is a {:?} at {:#x}",
+                shared.regs.kind,
+                shared.hpa.as_usize()
+            )
+        );
+    }
+    mmio::register_trap(
+        vm.id(),
+        GuestPhysAddr::from(gpa),
+        PAGE_SIZE_4K,
+        Arc::new(UartTrap { vm_id: vm.id() }),
+    )?;
+    let shared = uart.get_or_insert_with(|| SharedUart {
+        regs,
+        hpa: hpa.into(),
+        host_irq: axhal::console::irq_num(),
+        owner: None,
+        host_saved: None,
+        masked: false,
+        sharers: BTreeMap::new(),
+    });
+    shared.sharers.insert(
+        vm.id(),
+        Sharer {
+            gpa: GuestPhysAddr::from(gpa),
+            irq,
+            saved: None,
+            fcr: 0,
+            dlab: false,
+            handovers: 0,
+            accesses: 0,
+        },
+    );
+    info!(
+        "VM[{}] shares the {:?} UART at {:#x} as {:#x}, irq {}",
+        vm.id(),
+        kind,
+        hpa,
+        gpa,
+        irq
+    );
+    Ok(())
+}
+
+/// Stops sharing the UART with a VM, called when the VM is destroyed. The UART goes back to the
+/// hypervisor if the VM owned it.
+pub fn teardown_vm_shared_uart(vm_id: usize) {
+    let mut uart = UART.lock();
+    let Some(shared) = uart.as_mut() else {
+        return;
+    };
+    if shared.owner == Some(vm_id) {
+        hand_over(shared, None);
+    }
+    shared.sharers.remove(&vm_id);
+    if shared.sharers.is_empty() {
+        *uart = None;
+    }
+}
+
+/// Returns the sharing of the UART by VM `vm_id`, if it shares it.
+pub fn shared_uart_stats(vm_id: usize) -> Option<SharedUartStats> {
+    let uart = UART.lock();
+    let uart = uart.as_ref()?;
+    uart.sharers.get(&vm_id).map(|sharer| SharedUartStats {
+        owner: uart.owner == Some(vm_id),
+        handovers: sharer.handovers,
+        accesses: sharer.accesses,
+    })
+}
@@ -0,0 +1,413 @@
+//! CFI flash holding the UEFI variable store.
+//!
+//! `ArmVirtQemu` keeps its variables in the second bank of the NOR flash of the QEMU `virt`
+//! machine, which its `VirtNorFlashDxe` driver programs with the Intel/Sharp command set (CFI
+//! primary command set 0x0001). The flash is emulated like QEMU's `pflash_cfi01` with a bank width
+//! of 4 bytes, two interleaved 16-bit chips: commands are taken from the low byte of the written
+//! value, status and identification data are replicated for both chips. Programming only clears
+//! bits, as on real flash, and blocks of [`BLOCK_SIZE`] bytes are erased to 0xff.
+//!
+//! All accesses trap, reads of the array included, which the driver does with aligned single
+//! loads. Only the blocks holding programmed data take memory. With a `vars_path`, the blocks
+//! written by the guest are written back to the file when it puts the flash back in array mode,
+//! and when the VM is destroyed.
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::{String, ToString};
+
+use axaddrspace::GuestPhysAddr;
+use axaddrspace::device::AccessWidth;
+use axerrno::{AxResult, ax_err};
+use spin::Mutex;
+
+use crate::vmm::VMRef;
+use crate::vmm::memstat::{self, MemSubsystem};
+use crate::vmm::mmio::MmioTrapHandler;
+
+/// Size of an erase block of the bank, both chips.
+pub const BLOCK_SIZE: usize = 0x4_0000;
+/// Bytes accessed at once in the bank, both chips.
+const BANK_WIDTH: usize = 4;
+/// Bytes of the write buffer of the bank, both chips.
+const WRITE_BUFFER_SIZE: usize = 128;
+
+const CMD_READ_ARRAY: u8 = 0xff;
+const CMD_READ_ID: u8 = 0x90;
+const CMD_READ_CFI: u8 = 0x98;
+const CMD_READ_STATUS: u8 = 0x70;
+const CMD_CLEAR_STATUS: u8 = 0x50;
+const CMD_PROGRAM: u8 = 0x40;
+const CMD_PROGRAM_ALT: u8 = 0x10;
+const CMD_BUFFERED_PROGRAM: u8 = 0xe8;
+const CMD_BLOCK_ERASE: u8 = 0x20;
+const CMD_LOCK_SETUP: u8 = 0x60;
+const CMD_CONFIRM: u8 = 0xd0;
+
+const STATUS_READY: u8 = 0x80;
+const STATUS_ERASE_ERROR: u8 = 0x20;
+const STATUS_PROGRAM_ERROR: u8 = 0x10;
+
+/// Intel manufacturer ID.
+const MANUFACTURER_ID: u8 = 0x89;
+const DEVICE_ID: u8 = 0x18;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    ReadArray,
+    ReadStatus,
+    ReadId,
+    ReadCfi,
+    /// Waiting for the data of a single program.
+    Program,
+    /// Waiting for the confirmation of a block erase.
+    Erase {
+        block: usize,
+    },
+    /// Waiting for the confirmation of a lock bit command, which is accepted and ignored.
+    Lock,
+    /// Waiting for the word count of a buffered program.
+    BufferCount,
+    /// Waiting for `remaining` words of a buffered program, then its confirmation.
+    BufferData {
+        remaining: usize,
+    },
+}
+
+struct FlashState {
+    mode: Mode,
+    status: u8,
+    /// Blocks holding programmed data, indexed by block number. Missing blocks are erased.
+    blocks: BTreeMap<usize, Box<[u8]>>,
+    /// Blocks not written back to the file of the store yet.
+    dirty: BTreeSet<usize>,
+}
+
+impl FlashState {
+    fn read_byte(&self, offset: usize) -> u8 {
+        self.blocks
+            .get(&(offset / BLOCK_SIZE))
+            .map_or(0xff, |block| block[offset % BLOCK_SIZE])
+    }
+}
+
+/// Byte of the CFI query table of a chip at word `idx`.
+fn cfi_byte(idx: usize, size: usize) -> u8 {
+    let chip_size = size / 2;
+    let chip_blocks = size / BLOCK_SIZE - 1;
+    let chip_block_size = BLOCK_SIZE / 2 / 256;
+    match idx {
+        0x10 => b'Q',
+        0x11 => b'R',
+        0x12 => b'Y',
+        // Primary command set: Intel/Sharp extended.
+        0x13 => 0x01,
+        0x27 => chip_size.trailing_zeros() as u8,
+        // x16 interface.
+        0x28 => 0x01,
+        0x2a => (WRITE_BUFFER_SIZE / 2).trailing_zeros() as u8,
+        // A single erase block region.
+        0x2c => 0x01,
+        0x2d => chip_blocks as u8,
+        0x2e => (chip_blocks >> 8) as u8,
+        0x2f => chip_block_size as u8,
+        0x30 => (chip_block_size >> 8) as u8,
+        _ => 0,
+    }
+}
+
+fn width_bytes(width: AccessWidth) -> usize {
+    match width {
+        AccessWidth::Byte => 1,
+        AccessWidth::Word => 2,
+        AccessWidth::Dword => 4,
+        AccessWidth::Qword => 8,
+    }
+}
+
+/// Replicates a byte of a chip for both chips of the bank.
+fn replicate(byte: u8) -> usize {
+    (byte as usize) << 16 | byte as usize
+}
+
+/// The flash bank holding the variable store of a VM.
+pub struct VarsFlash {
+    vm_id: usize,
+    base: usize,
+    size: usize,
+    path: Option<String>,
+    state: Mutex<FlashState>,
+}
+
+impl VarsFlash {
+    /// Creates the variable store at guest physical address `base`, loaded from `path` if the
+    /// file exists.
+    pub fn new(vm_id: usize, base: usize, size: usize, path: Option<&str>) -> AxResult<Self> {
+        let mut flash = Self {
+            vm_id,
+            base,
+            size,
+            path: path.map(ToString::to_string),
+            state: Mutex::new(FlashState {
+                mode: Mode::ReadArray,
+                status: STATUS_READY,
+                blocks: BTreeMap::new(),
+                dirty: BTreeSet::new(),
+            }),
+        };
+        if let Some(path) = path {
+            load(vm_id, path, size, flash.state.get_mut())?;
+        }
+        Ok(flash)
+    }
+
+    /// Writes the blocks written by the guest back to the file of the store, if it has one.
+    pub fn flush(&self) {
+        let mut state = self.state.lock();
+        if let Some(path) = &self.path
+            && !state.dirty.is_empty()
+        {
+            if let Err(e) = store(path, &state) {
+                warn!(
+                    "VM[{}] UEFI variable store not written to {}: {:?}",
+                    self.vm_id, path, e
+                );
+                return;
+            }
+            debug!(
+                "VM[{}] {} blocks of the UEFI variable store written to {}",
+                self.vm_id,
+                state.dirty.len(),
+                path
+            );
+            state.dirty.clear();
+        }
+    }
+
+    /// Programs the `len` low bytes of `val` at `offset`, clearing bits only.
+    fn program(&self, state: &mut FlashState, offset: usize, len: usize, val: usize) {
+        let block_idx = offset / BLOCK_SIZE;
+        let block = state.blocks.entry(block_idx).or_insert_with(|| {
+            memstat::charge(MemSubsystem::Devices, Some(self.vm_id), BLOCK_SIZE);
+            alloc::vec![0xff; BLOCK_SIZE].into_boxed_slice()
+        });
+        for (i, byte) in val.to_le_bytes()[..len].iter().enumerate() {
+            block[(offset + i) % BLOCK_SIZE] &= byte;
+        }
+        state.dirty.insert(block_idx);
+    }
+
+    fn erase(&self, state: &mut FlashState, block_idx: usize) {
+        if state.blocks.remove(&block_idx).is_some() {
+            memstat::uncharge(MemSubsystem::Devices, Some(self.vm_id), BLOCK_SIZE);
+        }
+        state.dirty.insert(block_idx);
+    }
+}
+
+impl Drop for VarsFlash {
+    fn drop(&mut self) {
+        let blocks = self.state.get_mut().blocks.len();
+        memstat::uncharge(MemSubsystem::Devices, Some(self.vm_id), blocks * BLOCK_SIZE);
+    }
+}
+
+impl MmioTrapHandler for VarsFlash {
+    fn handle_read(&self, _vm: &VMRef, addr: GuestPhysAddr, width: AccessWidth) -> AxResult<usize> {
+        let offset = addr.as_usize() - self.base;
+        let len = width_bytes(width);
+        if offset + len > self.size {
+            return ax_err!(InvalidInput, "UEFI variable store read out of the flash");
+        }
+        let state = self.state.lock();
+        let val = match state.mode {
+            Mode::ReadArray => (0..len).fold(0, |val, i| {
+                val | (state.read_byte(offset + i) as usize) << (8 * i)
+            }),
+            Mode::ReadId => replicate(match offset / BANK_WIDTH % (BLOCK_SIZE / BANK_WIDTH) {
+                0 => MANUFACTURER_ID,
+                1 => DEVICE_ID,
+                // Block lock status: unlocked.
+                _ => 0,
+            }),
+            Mode::ReadCfi => replicate(cfi_byte(offset / BANK_WIDTH, self.size)),
+            _ => replicate(state.status),
+        };
+        Ok(if len < size_of::<usize>() {
+            val & ((1 << (8 * len)) - 1)
+        } else {
+            val
+        })
+    }
+
+    fn handle_write(
+        &self,
+        _vm: &VMRef,
+        addr: GuestPhysAddr,
+        width: AccessWidth,
+        val: usize,
+    ) -> AxResult {
+        let offset = addr.as_usize() - self.base;
+        let len = width_bytes(width);
+        if offset + len > self.size {
+            return ax_err!(InvalidInput, "UEFI variable store write out of the flash");
+        }
+        let mut state = self.state.lock();
+        let cmd = val as u8;
+        let mut flush = false;
+        state.mode = match state.mode {
+            Mode::Program => {
+                self.program(&mut state, offset, len, val);
+                Mode::ReadStatus
+            }
+            Mode::Erase { block } => {
+                if cmd == CMD_CONFIRM {
+                    self.erase(&mut state, block);
+                } else {
+                    state.status |= STATUS_ERASE_ERROR | STATUS_PROGRAM_ERROR;
+                }
+                Mode::ReadStatus
+            }
+            Mode::Lock => Mode::ReadStatus,
+            Mode::BufferCount => {
+                let words = cmd as usize + 1;
+                if words * BANK_WIDTH > WRITE_BUFFER_SIZE {
+                    state.status |= STATUS_PROGRAM_ERROR;
+                    Mode::ReadStatus
+                } else {
+                    Mode::BufferData { remaining: words }
+                }
+            }
+            Mode::BufferData { remaining: 0 } => {
+                if cmd != CMD_CONFIRM {
+                    state.status |= STATUS_PROGRAM_ERROR;
+                }
+                Mode::ReadStatus
+            }
+            Mode::BufferData { remaining } => {
+                self.program(&mut state, offset, len, val);
+                Mode::BufferData {
+                    remaining: remaining - 1,
+                }
+            }
+            Mode::ReadArray | Mode::ReadStatus | Mode::ReadId | Mode::ReadCfi => match cmd {
+                CMD_READ_ARRAY => {
+                    flush = true;
+                    Mode::ReadArray
+                }
+                CMD_READ_ID => Mode::ReadId,
+                CMD_READ_CFI => Mode::ReadCfi,
+                CMD_READ_STATUS => Mode::ReadStatus,
+                CMD_CLEAR_STATUS => {
+                    state.status = STATUS_READY;
+                    state.mode
+                }
+                CMD_PROGRAM | CMD_PROGRAM_ALT => Mode::Program,
+                CMD_BUFFERED_PROGRAM => Mode::BufferCount,
+                CMD_BLOCK_ERASE => Mode::Erase {
+                    block: offset / BLOCK_SIZE,
+                },
+                CMD_LOCK_SETUP => Mode::Lock,
+                _ => {
+                    debug!(
+                        "VM[{}] UEFI variable store: unknown command {:#x} at {:#x}",
+                        self.vm_id, cmd, offset
+                    );
+                    state.mode
+                }
+            },
+        };
+        if flush {
+            drop(state);
+            self.flush();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "fs")]
+fn load(vm_id: usize, path: &str, size: usize, state: &mut FlashState) -> AxResult {
+    use std::io::Read;
+
+    let mut file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(_) => {
+            info!(
+                "VM[{}] UEFI variable store {} not found, starting erased",
+                vm_id, path
+            );
+            // The whole store is written on the first flush, which creates the file.
+            state.dirty.extend(0..size / BLOCK_SIZE);
+            return Ok(());
+        }
+    };
+    for block_idx in 0..size / BLOCK_SIZE {
+        let mut block = alloc::vec![0xff; BLOCK_SIZE].into_boxed_slice();
+        let mut len = 0;
+        while len < BLOCK_SIZE {
+            match file.read(&mut block[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) => {
+                    return ax_err!(
+                        Io,
+                        format!("Failed to read UEFI variable store {}, err {:?}", path, e)
+                    );
+                }
+            }
+        }
+        if len < BLOCK_SIZE {
+            // The file is shorter than the store, write the rest on the first flush so that
+            // blocks are always written within or right at the end of the file.
+            state.dirty.extend(block_idx..size / BLOCK_SIZE);
+        }
+        if block.iter().any(|byte| *byte != 0xff) {
+            memstat::charge(MemSubsystem::Devices, Some(vm_id), BLOCK_SIZE);
+            state.blocks.insert(block_idx, block);
+        }
+        if len < BLOCK_SIZE {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "fs"))]
+fn load(_vm_id: usize, path: &str, _size: usize, _state: &mut FlashState) -> AxResult {
+    ax_err!(
+        Unsupported,
+        format!(
+            "uefi config: `vars_path` {} requires the `fs` feature",
+            path
+        )
+    )
+}
+
+#[cfg(feature = "fs")]
+fn store(path: &str, state: &FlashState) -> AxResult {
+    use axerrno::ax_err_type;
+    use std::io::{Seek, SeekFrom, Write};
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| ax_err_type!(Io, format!("open failed, err {:?}", e)))?;
+    let erased = [0xffu8; 4096];
+    for block_idx in &state.dirty {
+        let result = file
+            .seek(SeekFrom::Start((block_idx * BLOCK_SIZE) as u64))
+            .and_then(|_| match state.blocks.get(block_idx) {
+                Some(block) => file.write_all(block),
+                None => (0..BLOCK_SIZE / erased.len()).try_for_each(|_| file.write_all(&erased)),
+            });
+        result.map_err(|e| ax_err_type!(Io, format!("write failed, err {:?}", e)))?;
+    }
+    file.flush()
+        .map_err(|e| ax_err_type!(Io, format!("flush failed, err {:?}", e)))
+}
+
+#[cfg(not(feature = "fs"))]
+fn store(_path: &str, _state: &FlashState) -> AxResult {
+    Ok(())
+}
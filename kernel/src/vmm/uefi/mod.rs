@@ -0,0 +1,166 @@
+//! UEFI boot of guests.
+//!
+//! A VM with a `[uefi]` section boots an EDK2 firmware (the `ArmVirtQemu` platform, e.g.
+//! `QEMU_EFI.fd`) instead of a kernel directly, so that it can boot stock distro images from its
+//! disks. The firmware volume is the BIOS image of the VM (`bios_path` and `bios_load_addr` in the
+//! `[kernel]` section), loaded in a memory region of the VM that stands for the code flash; the
+//! kernel image is not loaded. The boot vCPU starts at the base of the firmware, in the reset
+//! state set up by `axvcpu`, and the firmware finds the device tree of the VM at the base of RAM.
+//!
+//! The hypervisor emulates the devices the firmware needs besides its disks:
+//!
+//! ```toml
+//! [uefi]
+//! # CFI flash holding the UEFI variable store, see `flash`.
+//! vars_base = 0x400_0000
+//! vars_size = 0x400_0000
+//! # File the variable store is loaded from and written back to (`fs` feature), the store starts
+//! # erased if not set or if the file doesn't exist.
+//! vars_path = "QEMU_VARS.fd"
+//! # PL031 real-time clock, see `rtc`.
+//! rtc_base = 0x901_0000
+//! # PL011 serial port writing to the hypervisor console, see `serial`. Optional, the UART of the
+//! # board may be passed through instead.
+//! serial_base = 0x900_0000
+//! ```
+//!
+//! The device tree of the VM must describe the devices at these addresses and exclude them from
+//! passthrough. UEFI boot is supported on aarch64 only.
+mod flash;
+mod rtc;
+mod serial;
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::vmm::VMRef;
+use crate::vmm::mmio;
+
+use flash::VarsFlash;
+
+/// Variable stores of the VMs booting UEFI, indexed by VM ID.
+static VARS: Mutex<BTreeMap<usize, Arc<VarsFlash>>> = Mutex::new(BTreeMap::new());
+
+/// Returns whether VM `vm_id` boots UEFI, in which case its kernel image is not loaded.
+pub fn is_uefi_boot(vm_id: usize) -> bool {
+    VARS.lock().contains_key(&vm_id)
+}
+
+/// Sets up the UEFI boot described in the `[uefi]` section of `raw_cfg` for the VM: the boot
+/// entry and the emulated devices. Called before the images of the VM are loaded.
+///
+/// Does nothing if the VM config has no `[uefi]` section.
+pub fn setup_vm_uefi_boot(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("uefi").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    if !cfg!(target_arch = "aarch64") {
+        return ax_err!(Unsupported, "UEFI boot is only supported on aarch64");
+    }
+    let get = |key: &str| {
+        cfg.get(key)
+            .and_then(|v| v.as_integer())
+            .map(|v| v as usize)
+    };
+    let require = |key: &str| {
+        get(key)
+            .ok_or_else(|| ax_err_type!(InvalidInput, format!("uefi config: missing `{}`", key)))
+    };
+    let vars_base = require("vars_base")?;
+    let vars_size = require("vars_size")?;
+    let rtc_base = require("rtc_base")?;
+    let serial_base = get("serial_base");
+    let vars_path = match cfg.get("vars_path") {
+        None => None,
+        Some(v) => Some(
+            v.as_str()
+                .ok_or_else(|| ax_err_type!(InvalidInput, "uefi config: invalid `vars_path`"))?,
+        ),
+    };
+    if [Some(vars_base), Some(rtc_base), serial_base]
+        .into_iter()
+        .flatten()
+        .any(|base| base % PAGE_SIZE_4K != 0)
+    {
+        return ax_err!(
+            InvalidInput,
+            "uefi config: device bases must be page-aligned"
+        );
+    }
+    if vars_size == 0 || vars_size % flash::BLOCK_SIZE != 0 {
+        return ax_err!(
+            InvalidInput,
+            format!(
+                "uefi config: `vars_size` must be a non-zero multiple of {:#x}",
+                flash::BLOCK_SIZE
+            )
+        );
+    }
+
+    let (firmware, dtb) = vm.with_config(|config| {
+        (
+            config.image_config.bios_load_gpa,
+            config.image_config.dtb_load_gpa,
+        )
+    });
+    let Some(firmware) = firmware else {
+        return ax_err!(
+            InvalidInput,
+            "uefi config: the firmware volume must be given as the BIOS image of the VM"
+        );
+    };
+    match dtb {
+        Some(dtb) if vm.memory_regions().iter().any(|region| region.gpa == dtb) => {}
+        Some(dtb) => warn!(
+            "VM[{}] device tree at {:#x}, not at the base of RAM where the firmware looks for it",
+            vm.id(),
+            dtb
+        ),
+        None => return ax_err!(InvalidInput, "uefi config: the VM has no device tree"),
+    }
+
+    let vars = Arc::new(VarsFlash::new(vm.id(), vars_base, vars_size, vars_path)?);
+    mmio::register_trap(vm.id(), vars_base.into(), vars_size, vars.clone())?;
+    mmio::register_trap(
+        vm.id(),
+        rtc_base.into(),
+        PAGE_SIZE_4K,
+        Arc::new(rtc::Pl031::new()),
+    )?;
+    if let Some(base) = serial_base {
+        mmio::register_trap(
+            vm.id(),
+            base.into(),
+            PAGE_SIZE_4K,
+            Arc::new(serial::Pl011::new(vm.id())?),
+        )?;
+    }
+    vm.with_config(|config| {
+        config.cpu_config.bsp_entry = firmware;
+    });
+    VARS.lock().insert(vm.id(), vars);
+
+    info!(
+        "VM[{}] UEFI boot: firmware at {:#x}, variable store at {:#x} ({:#x} bytes{}), rtc at {:#x}, serial {}",
+        vm.id(),
+        firmware,
+        vars_base,
+        vars_size,
+        vars_path.map_or_else(String::new, |path| format!(", {}", path)),
+        rtc_base,
+        serial_base.map_or_else(|| "passed through".into(), |base| format!("at {:#x}", base))
+    );
+    Ok(())
+}
+
+/// Writes the variable store of the VM back and drops it, called when the VM is destroyed.
+pub fn teardown_vm_uefi_boot(vm_id: usize) {
+    if let Some(vars) = VARS.lock().remove(&vm_id) {
+        vars.flush();
+    }
+}
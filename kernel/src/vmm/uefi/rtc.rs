@@ -0,0 +1,89 @@
+//! PL031 real-time clock.
+//!
+//! The clock counts seconds from the wall time of the host. A guest setting the clock only moves
+//! its own clock. The match interrupt is not emulated: UEFI and Linux only read and set the time.
+use core::sync::atomic::{AtomicI64, AtomicU32, Ordering};
+
+use std::os::arceos::api::time::ax_wall_time;
+
+use axaddrspace::GuestPhysAddr;
+use axaddrspace::device::AccessWidth;
+use axerrno::{AxResult, ax_err};
+use memory_addr::PAGE_SIZE_4K;
+
+use crate::vmm::VMRef;
+use crate::vmm::mmio::MmioTrapHandler;
+
+const REG_DR: usize = 0x000;
+const REG_MR: usize = 0x004;
+const REG_LR: usize = 0x008;
+const REG_CR: usize = 0x00c;
+const REG_IMSC: usize = 0x010;
+const REG_RIS: usize = 0x014;
+const REG_MIS: usize = 0x018;
+const REG_ICR: usize = 0x01c;
+/// Peripheral and PrimeCell identification registers, probed by AMBA bus drivers.
+const REG_ID: usize = 0xfe0;
+const ID: [u8; 8] = [0x31, 0x10, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+pub struct Pl031 {
+    /// Offset of the clock of the guest from the wall time of the host, in seconds.
+    offset: AtomicI64,
+    mr: AtomicU32,
+    imsc: AtomicU32,
+}
+
+impl Pl031 {
+    pub fn new() -> Self {
+        Self {
+            offset: AtomicI64::new(0),
+            mr: AtomicU32::new(0),
+            imsc: AtomicU32::new(0),
+        }
+    }
+
+    fn host_secs() -> i64 {
+        ax_wall_time().as_secs() as i64
+    }
+}
+
+impl MmioTrapHandler for Pl031 {
+    fn handle_read(&self, _vm: &VMRef, addr: GuestPhysAddr, width: AccessWidth) -> AxResult<usize> {
+        if !matches!(width, AccessWidth::Dword) {
+            return ax_err!(InvalidInput, "PL031 registers are 32-bit");
+        }
+        let val = match addr.as_usize() & (PAGE_SIZE_4K - 1) {
+            REG_DR => (Self::host_secs() + self.offset.load(Ordering::Relaxed)) as u32,
+            REG_MR => self.mr.load(Ordering::Relaxed),
+            // The clock always runs.
+            REG_CR => 1,
+            REG_IMSC => self.imsc.load(Ordering::Relaxed),
+            REG_RIS | REG_MIS => 0,
+            reg @ REG_ID..PAGE_SIZE_4K if reg % 4 == 0 => ID[(reg - REG_ID) / 4] as u32,
+            _ => 0,
+        };
+        Ok(val as usize)
+    }
+
+    fn handle_write(
+        &self,
+        _vm: &VMRef,
+        addr: GuestPhysAddr,
+        width: AccessWidth,
+        val: usize,
+    ) -> AxResult {
+        if !matches!(width, AccessWidth::Dword) {
+            return ax_err!(InvalidInput, "PL031 registers are 32-bit");
+        }
+        match addr.as_usize() & (PAGE_SIZE_4K - 1) {
+            REG_LR => self
+                .offset
+                .store(val as u32 as i64 - Self::host_secs(), Ordering::Relaxed),
+            REG_MR => self.mr.store(val as u32, Ordering::Relaxed),
+            REG_IMSC => self.imsc.store(val as u32 & 1, Ordering::Relaxed),
+            REG_CR | REG_ICR => {}
+            reg => trace!("PL031 write to {:#x} ignored", reg),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+//! PL011 serial port, output only.
+//!
+//! What the guest writes is printed on the hypervisor console line by line, prefixed with the VM
+//! ID so that it doesn't interleave with the output of the hypervisor and of other VMs mid-line.
+//! The port never receives anything and raises no interrupt: both FIFOs always read as empty.
+use alloc::vec::Vec;
+
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::GuestPhysAddr;
+use axaddrspace::device::AccessWidth;
+use axerrno::AxResult;
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::vmm::{VMRef, conmux};
+use crate::vmm::memstat::{self, MemSubsystem};
+use crate::vmm::mmio::MmioTrapHandler;
+
+/// Longest line printed at once, longer lines are split.
+const LINE_LEN: usize = 256;
+
+const REG_DR: usize = 0x000;
+const REG_FR: usize = 0x018;
+const REG_IBRD: usize = 0x024;
+const REG_FBRD: usize = 0x028;
+const REG_LCR_H: usize = 0x02c;
+const REG_CR: usize = 0x030;
+const REG_IFLS: usize = 0x034;
+const REG_IMSC: usize = 0x038;
+/// Peripheral and PrimeCell identification registers, probed by AMBA bus drivers.
+const REG_ID: usize = 0xfe0;
+const ID: [u8; 8] = [0x11, 0x10, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+/// Flag register: transmit FIFO empty, receive FIFO empty.
+const FR_IDLE: u32 = 1 << 7 | 1 << 4;
+
+/// Registers the guest configures, read back as written.
+#[derive(Default)]
+struct Pl011Regs {
+    ibrd: u32,
+    fbrd: u32,
+    lcr_h: u32,
+    cr: u32,
+    ifls: u32,
+    imsc: u32,
+}
+
+pub struct Pl011 {
+    vm_id: usize,
+    regs: Mutex<Pl011Regs>,
+    line: Mutex<Vec<u8>>,
+}
+
+impl Pl011 {
+    pub fn new(vm_id: usize) -> AxResult<Self> {
+        memstat::try_charge(MemSubsystem::Console, vm_id, LINE_LEN)?;
+        Ok(Self {
+            vm_id,
+            regs: Mutex::new(Pl011Regs::default()),
+            line: Mutex::new(Vec::with_capacity(LINE_LEN)),
+        })
+    }
+
+    fn put(&self, byte: u8) {
+        let mut line = self.line.lock();
+        match byte {
+            b'\r' => {}
+            b'\n' => self.print(&mut line),
+            _ => {
+                line.push(byte);
+                if line.len() == LINE_LEN {
+                    self.print(&mut line);
+                }
+            }
+        }
+    }
+
+    fn print(&self, line: &mut Vec<u8>) {
+        conmux::feed(self.vm_id, conmux::CONSOLE_SRC_UEFI_SERIAL, line);
+        let mut out = format!("[VM[{}]] ", self.vm_id).into_bytes();
+        out.extend_from_slice(line);
+        out.extend_from_slice(b"\r\n");
+        axhal::console::write_bytes(&out);
+        line.clear();
+    }
+}
+
+impl Drop for Pl011 {
+    fn drop(&mut self) {
+        let mut line = core::mem::take(self.line.get_mut());
+        if !line.is_empty() {
+            self.print(&mut line);
+        }
+        memstat::uncharge(MemSubsystem::Console, Some(self.vm_id), LINE_LEN);
+    }
+}
+
+impl MmioTrapHandler for Pl011 {
+    fn handle_read(
+        &self,
+        _vm: &VMRef,
+        addr: GuestPhysAddr,
+        _width: AccessWidth,
+    ) -> AxResult<usize> {
+        let regs = self.regs.lock();
+        let val = match addr.as_usize() & (PAGE_SIZE_4K - 1) {
+            REG_FR => FR_IDLE,
+            REG_IBRD => regs.ibrd,
+            REG_FBRD => regs.fbrd,
+            REG_LCR_H => regs.lcr_h,
+            REG_CR => regs.cr,
+            REG_IFLS => regs.ifls,
+            REG_IMSC => regs.imsc,
+            reg @ REG_ID..PAGE_SIZE_4K if reg % 4 == 0 => ID[(reg - REG_ID) / 4] as u32,
+            // Nothing is ever received, nor pending.
+            _ => 0,
+        };
+        Ok(val as usize)
+    }
+
+    fn handle_write(
+        &self,
+        _vm: &VMRef,
+        addr: GuestPhysAddr,
+        _width: AccessWidth,
+        val: usize,
+    ) -> AxResult {
+        let val = val as u32;
+        let mut regs = self.regs.lock();
+        match addr.as_usize() & (PAGE_SIZE_4K - 1) {
+            REG_DR => {
+                drop(regs);
+                self.put(val as u8);
+            }
+            REG_IBRD => regs.ibrd = val,
+            REG_FBRD => regs.fbrd = val,
+            REG_LCR_H => regs.lcr_h = val,
+            REG_CR => regs.cr = val,
+            REG_IFLS => regs.ifls = val,
+            REG_IMSC => regs.imsc = val,
+            reg => trace!("VM[{}] PL011 write to {:#x} ignored", self.vm_id, reg),
+        }
+        Ok(())
+    }
+}
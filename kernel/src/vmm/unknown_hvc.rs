@@ -0,0 +1,437 @@
+//! What the hypervisor does with the hypercalls it doesn't implement.
+//!
+//! By default, a hypercall the hypervisor doesn't implement is rejected: the guest gets -1. A VM
+//! may instead have its unknown hypercalls logged before they are rejected, or forwarded to a
+//! handler VM which answers them from its guest, so that a new paravirtual service can be
+//! prototyped in a guest before it is moved into the hypervisor:
+//!
+//! ```toml
+//! [hypercalls]
+//! # "reject" (the default), "log-and-reject" or "forward".
+//! unknown = "forward"
+//! # Peer handle of the handler VM, see `crate::vmm::peers`. Required to forward.
+//! handler = 0
+//! # Time the handler has to answer in milliseconds, 100 by default.
+//! timeout_ms = 100
+//! ```
+//!
+//! A handler VM is a manager VM (see [`crate::vmm::vmdef`]) with a request page, which is its
+//! management channel for forwarded hypercalls:
+//!
+//! ```toml
+//! [hypercall_handler]
+//! # Guest physical address of the request page, page aligned and outside of guest memory.
+//! gpa = 0x0900_5000
+//! # Interrupt raised in the VM when a hypercall is forwarded, none by default.
+//! irq = 0x34
+//! ```
+//!
+//! The page is a [`ForwardHeader`] followed, from offset [`FORWARD_SLOTS_OFFSET`], by
+//! [`FORWARD_SLOTS`] [`ForwardSlot`]s. The hypervisor fills a free slot with the hypercall, sets
+//! its state to [`FORWARD_PENDING`] and raises the interrupt of the handler. The handler writes
+//! the return value of the hypercall to `ret`, sets the state to [`FORWARD_DONE`] and completes
+//! the slot with the [`HVC_FORWARD_COMPLETE`](crate::vmm::hvc::HVC_FORWARD_COMPLETE) hypercall.
+//! The hypervisor frees the slot when the caller resumes, the handler only writes pending slots.
+//!
+//! The calling vCPU is blocked until the handler completes the hypercall. It gets -1 if the
+//! handler doesn't answer in time, has no free slot or is not running, and its VM shows the
+//! failure in the log. Forwarded hypercalls are handled by the vCPU loop, outside of
+//! [`HyperCall::execute`](crate::vmm::hvc::HyperCall::execute).
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::{AxMmHal, GuestPhysAddr, HostPhysAddr, MappingFlags};
+use axerrno::{AxResult, ax_err, ax_err_type};
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::hal::AxMmHalImpl;
+use crate::vmm::hvc::{HVC_FORWARD_COMPLETE, HvcService};
+use crate::vmm::irq::IrqLine;
+use crate::vmm::memstat::{self, MemSubsystem};
+use crate::vmm::{VMRef, peers, reclaim, stage2, timer, vcpus, vmdef};
+
+/// `magic` of [`ForwardHeader`], "AXFW".
+pub const FORWARD_MAGIC: u32 = u32::from_le_bytes(*b"AXFW");
+/// Version of the layouts of the request page.
+pub const FORWARD_VERSION: u16 = 1;
+/// Offset of the first slot in the request page.
+pub const FORWARD_SLOTS_OFFSET: usize = 64;
+/// Slots in the request page.
+pub const FORWARD_SLOTS: usize = (PAGE_SIZE_4K - FORWARD_SLOTS_OFFSET) / size_of::<ForwardSlot>();
+
+/// States of a slot, in `ForwardSlot::state`.
+pub const FORWARD_FREE: u32 = 0;
+pub const FORWARD_PENDING: u32 = 1;
+pub const FORWARD_DONE: u32 = 2;
+
+const DEFAULT_TIMEOUT_MS: u64 = 100;
+
+/// What is done with the unknown hypercalls of a VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownHvcAction {
+    Reject,
+    LogAndReject,
+    Forward,
+}
+
+/// Header of the request page.
+#[repr(C)]
+pub struct ForwardHeader {
+    pub magic: u32,
+    pub version: u16,
+    /// Size of a [`ForwardSlot`].
+    pub slot_size: u16,
+    /// Slots in the page.
+    pub slots: u32,
+    pub _reserved: u32,
+}
+
+/// A forwarded hypercall.
+#[repr(C)]
+pub struct ForwardSlot {
+    /// [`FORWARD_FREE`], [`FORWARD_PENDING`] or [`FORWARD_DONE`].
+    pub state: AtomicU32,
+    /// VM ID of the caller.
+    pub vm_id: u32,
+    pub vcpu_id: u32,
+    pub _reserved: u32,
+    /// Number of the hypercall.
+    pub code: u64,
+    pub args: [u64; 6],
+    /// Return value of the hypercall, written by the handler.
+    pub ret: i64,
+}
+
+struct Policy {
+    action: UnknownHvcAction,
+    /// Peer handle of the handler VM.
+    handler: usize,
+    timeout_ns: u64,
+}
+
+/// A caller waiting for the handler to complete its hypercall.
+struct Waiter {
+    vm_id: usize,
+    woken: Arc<AtomicBool>,
+}
+
+struct Handler {
+    frame: HostPhysAddr,
+    irq: Option<usize>,
+    /// Callers of the slots, `None` for free slots.
+    waiters: Vec<Option<Waiter>>,
+}
+
+impl Handler {
+    fn slot(&self, index: usize) -> *mut ForwardSlot {
+        let page = AxMmHalImpl::phys_to_virt(self.frame).as_mut_ptr();
+        // SAFETY: the slot is within the request page, allocated as long as the handler is.
+        unsafe { page.add(FORWARD_SLOTS_OFFSET + index * size_of::<ForwardSlot>()).cast() }
+    }
+}
+
+/// Policies of the VMs with a `[hypercalls]` section, indexed by VM ID.
+static POLICIES: Mutex<BTreeMap<usize, Policy>> = Mutex::new(BTreeMap::new());
+/// Handler VMs, indexed by VM ID.
+static HANDLERS: Mutex<BTreeMap<usize, Handler>> = Mutex::new(BTreeMap::new());
+
+/// Sets the policy of the VM for unknown hypercalls, and makes it a handler VM, as described in
+/// the `[hypercalls]` and `[hypercall_handler]` sections of `raw_cfg`.
+///
+/// Must run after [`vmdef::setup_vm_manager`].
+pub fn setup_vm_unknown_hvc(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    if let Some(cfg) = raw_cfg.get("hypercalls").and_then(|v| v.as_table()) {
+        let action = match cfg.get("unknown").map(|v| v.as_str()) {
+            None | Some(Some("reject")) => UnknownHvcAction::Reject,
+            Some(Some("log-and-reject")) => UnknownHvcAction::LogAndReject,
+            Some(Some("forward")) => UnknownHvcAction::Forward,
+            _ => {
+                return ax_err!(
+                    InvalidInput,
+                    "hypercalls config: `unknown` must be \"reject\", \"log-and-reject\" or \"forward\""
+                );
+            }
+        };
+        let handler = match cfg.get("handler") {
+            None => None,
+            Some(v) => Some(v.as_integer().filter(|h| *h >= 0).ok_or_else(|| {
+                ax_err_type!(InvalidInput, "hypercalls config: invalid `handler`")
+            })? as usize),
+        };
+        if action == UnknownHvcAction::Forward && handler.is_none() {
+            return ax_err!(InvalidInput, "hypercalls config: forwarding needs a `handler`");
+        }
+        let timeout_ms = match cfg.get("timeout_ms") {
+            None => DEFAULT_TIMEOUT_MS,
+            Some(v) => v.as_integer().filter(|t| *t > 0).ok_or_else(|| {
+                ax_err_type!(InvalidInput, "hypercalls config: invalid `timeout_ms`")
+            })? as u64,
+        };
+        POLICIES.lock().insert(
+            vm.id(),
+            Policy {
+                action,
+                handler: handler.unwrap_or(0),
+                timeout_ns: timeout_ms * 1_000_000,
+            },
+        );
+        info!("VM[{}] unknown hypercalls: {:?}", vm.id(), action);
+    }
+
+    let Some(cfg) = raw_cfg.get("hypercall_handler").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let get = |key: &str| {
+        cfg.get(key)
+            .and_then(|v| v.as_integer())
+            .map(|v| v as usize)
+    };
+    if !vmdef::is_manager(vm.id()) {
+        return ax_err!(
+            PermissionDenied,
+            "hypercall handler config: only manager VMs may handle hypercalls"
+        );
+    }
+    let Some(gpa) = get("gpa") else {
+        return ax_err!(InvalidInput, "hypercall handler config: missing `gpa`");
+    };
+    if gpa % PAGE_SIZE_4K != 0 {
+        return ax_err!(InvalidInput, "hypercall handler config: `gpa` must be page aligned");
+    }
+    if vm.memory_regions().iter().any(|region| {
+        gpa < region.gpa.as_usize() + region.size() && region.gpa.as_usize() < gpa + PAGE_SIZE_4K
+    }) {
+        return ax_err!(
+            InvalidInput,
+            format!("hypercall handler config: {:#x} overlaps guest memory", gpa)
+        );
+    }
+
+    let frame = reclaim::alloc_or_reclaim(AxMmHalImpl::alloc_frame)
+        .ok_or_else(|| ax_err_type!(NoMemory, "failed to allocate the request page"))?;
+    memstat::charge(MemSubsystem::HvInfo, Some(vm.id()), PAGE_SIZE_4K);
+    let page = AxMmHalImpl::phys_to_virt(frame).as_mut_ptr();
+    // SAFETY: the frame was just allocated and is mapped in the linear mapping of the host.
+    unsafe {
+        core::ptr::write_bytes(page, 0, PAGE_SIZE_4K);
+        page.cast::<ForwardHeader>().write(ForwardHeader {
+            magic: FORWARD_MAGIC,
+            version: FORWARD_VERSION,
+            slot_size: size_of::<ForwardSlot>() as u16,
+            slots: FORWARD_SLOTS as u32,
+            _reserved: 0,
+        })
+    };
+    if let Err(e) = stage2::map_region(
+        vm,
+        GuestPhysAddr::from(gpa),
+        frame,
+        PAGE_SIZE_4K,
+        MappingFlags::READ | MappingFlags::WRITE,
+    ) {
+        AxMmHalImpl::dealloc_frame(frame);
+        memstat::uncharge(MemSubsystem::HvInfo, Some(vm.id()), PAGE_SIZE_4K);
+        return Err(e);
+    }
+    HANDLERS.lock().insert(
+        vm.id(),
+        Handler {
+            frame,
+            irq: get("irq"),
+            waiters: (0..FORWARD_SLOTS).map(|_| None).collect(),
+        },
+    );
+    info!("VM[{}] hypercall request page at {:#x}", vm.id(), gpa);
+    Ok(())
+}
+
+/// Removes the policy of the VM and, if it is a handler VM, fails the hypercalls forwarded to it,
+/// called when the VM is destroyed.
+pub fn teardown_vm_unknown_hvc(vm_id: usize) {
+    POLICIES.lock().remove(&vm_id);
+    let Some(handler) = HANDLERS.lock().remove(&vm_id) else {
+        return;
+    };
+    let mut callers = Vec::new();
+    for waiter in handler.waiters.into_iter().flatten() {
+        waiter.woken.store(true, Ordering::Release);
+        if !callers.contains(&waiter.vm_id) {
+            callers.push(waiter.vm_id);
+        }
+    }
+    // The callers find the handler gone, the page is not used anymore.
+    AxMmHalImpl::dealloc_frame(handler.frame);
+    memstat::uncharge(MemSubsystem::HvInfo, Some(vm_id), PAGE_SIZE_4K);
+    for caller in callers {
+        vcpus::notify_all_vcpus(caller);
+    }
+}
+
+/// Handles the hypercall `nr` of vCPU `vcpu_id` of VM `vm_id`, which the hypervisor doesn't
+/// implement, as the VM is configured to. Returns the return value of the hypercall.
+///
+/// `block` blocks the vCPU until the flag it is given is set or its VM stops, as for
+/// [`crate::vmm::ivc::wait_space`].
+pub fn handle_unknown(
+    vm_id: usize,
+    vcpu_id: usize,
+    nr: u64,
+    args: [u64; 6],
+    block: impl Fn(&AtomicBool),
+) -> isize {
+    let (action, handler, timeout_ns) = match POLICIES.lock().get(&vm_id) {
+        Some(policy) => (policy.action, policy.handler, policy.timeout_ns),
+        None => (UnknownHvcAction::Reject, 0, 0),
+    };
+    match action {
+        UnknownHvcAction::Reject => {
+            debug!("VM[{vm_id}] VCpu[{vcpu_id}] unknown hypercall {nr:#x} rejected");
+            -1
+        }
+        UnknownHvcAction::LogAndReject => {
+            warn!("VM[{vm_id}] VCpu[{vcpu_id}] unknown hypercall {nr:#x} args {args:x?} rejected");
+            -1
+        }
+        UnknownHvcAction::Forward => {
+            match forward(vm_id, vcpu_id, handler, nr, args, timeout_ns, block) {
+                Ok(ret) => ret as isize,
+                Err(e) => {
+                    warn!("VM[{vm_id}] VCpu[{vcpu_id}] unknown hypercall {nr:#x} failed: {e:?}");
+                    -1
+                }
+            }
+        }
+    }
+}
+
+/// Forwards a hypercall to the handler VM of peer handle `handle` and waits for its answer.
+fn forward(
+    vm_id: usize,
+    vcpu_id: usize,
+    handle: usize,
+    nr: u64,
+    args: [u64; 6],
+    timeout_ns: u64,
+    block: impl Fn(&AtomicBool),
+) -> AxResult<i64> {
+    let handler_id = peers::resolve(vm_id, handle)?;
+    if handler_id == vm_id {
+        return ax_err!(InvalidInput, "a VM cannot handle its own hypercalls");
+    }
+    let woken = Arc::new(AtomicBool::new(false));
+    let (index, irq) = {
+        let mut handlers = HANDLERS.lock();
+        let handler = handlers.get_mut(&handler_id).ok_or_else(|| {
+            ax_err_type!(NotFound, format!("VM[{}] is not a hypercall handler", handler_id))
+        })?;
+        let index = handler
+            .waiters
+            .iter()
+            .position(Option::is_none)
+            .ok_or_else(|| ax_err_type!(ResourceBusy, "no free forwarding slot"))?;
+        handler.waiters[index] = Some(Waiter {
+            vm_id,
+            woken: woken.clone(),
+        });
+        let slot = handler.slot(index);
+        // SAFETY: the slot is free, the handler doesn't write it until it is pending.
+        unsafe {
+            (*slot).vm_id = vm_id as u32;
+            (*slot).vcpu_id = vcpu_id as u32;
+            (*slot).code = nr;
+            (*slot).args = args;
+            (*slot).ret = -1;
+            (*slot).state.store(FORWARD_PENDING, Ordering::Release);
+        }
+        (index, handler.irq)
+    };
+    if let Some(irq) = irq {
+        IrqLine::new(handler_id, irq).raise();
+    }
+
+    let deadline = axhal::time::monotonic_time_nanos().saturating_add(timeout_ns);
+    let timeout = Arc::downgrade(&woken);
+    timer::register_timer(deadline, move |_| {
+        if let Some(woken) = timeout.upgrade() {
+            woken.store(true, Ordering::Release);
+            vcpus::notify_all_vcpus(vm_id);
+        }
+    });
+    // Woken by the handler, the timeout, the handler going away or the VM stopping.
+    block(&woken);
+
+    let mut handlers = HANDLERS.lock();
+    let Some(handler) = handlers.get_mut(&handler_id).filter(|handler| {
+        handler.waiters[index]
+            .as_ref()
+            .is_some_and(|waiter| Arc::ptr_eq(&waiter.woken, &woken))
+    }) else {
+        return ax_err!(BadState, format!("hypercall handler VM[{}] is gone", handler_id));
+    };
+    handler.waiters[index] = None;
+    let slot = handler.slot(index);
+    // SAFETY: the slot is within the request page, the handler may still write it.
+    let (state, ret) = unsafe {
+        let state = (*slot).state.swap(FORWARD_FREE, Ordering::AcqRel);
+        (state, core::ptr::read_volatile(&raw const (*slot).ret))
+    };
+    if state != FORWARD_DONE {
+        return ax_err!(TimedOut, format!("hypercall handler VM[{}] did not answer", handler_id));
+    }
+    Ok(ret)
+}
+
+/// The [`HVC_FORWARD_COMPLETE`](crate::vmm::hvc::HVC_FORWARD_COMPLETE) hypercall, for handler
+/// VMs.
+pub static HVC_SERVICE: HvcService = HvcService {
+    name: "forwarded hypercall completion",
+    codes: HVC_FORWARD_COMPLETE..HVC_FORWARD_COMPLETE + 1,
+    permit: |vm| {
+        if !is_handler(vm.id()) {
+            return ax_err!(
+                PermissionDenied,
+                format!("VM[{}] is not a hypercall handler", vm.id())
+            );
+        }
+        Ok(())
+    },
+    deferrable: false,
+    handler: |vm, _, _, args| complete(vm.id(), args).map(|_| 0),
+};
+
+/// Handles the [`HVC_FORWARD_COMPLETE`](crate::vmm::hvc::HVC_FORWARD_COMPLETE) hypercall of the
+/// handler VM `vm_id`, `args[0]` is the slot completed.
+fn complete(vm_id: usize, args: [u64; 6]) -> AxResult {
+    let caller = {
+        let handlers = HANDLERS.lock();
+        let handler = handlers.get(&vm_id).ok_or_else(|| {
+            ax_err_type!(PermissionDenied, format!("VM[{}] is not a hypercall handler", vm_id))
+        })?;
+        let Some(Some(waiter)) = handler.waiters.get(args[0] as usize) else {
+            return ax_err!(InvalidInput, format!("forwarding slot {} is not pending", args[0]));
+        };
+        waiter.woken.store(true, Ordering::Release);
+        waiter.vm_id
+    };
+    vcpus::notify_all_vcpus(caller);
+    Ok(())
+}
+
+/// The unknown hypercall action of a VM, for the shell.
+pub fn action_of(vm_id: usize) -> UnknownHvcAction {
+    POLICIES
+        .lock()
+        .get(&vm_id)
+        .map_or(UnknownHvcAction::Reject, |policy| policy.action)
+}
+
+/// Whether VM `vm_id` is a hypercall handler VM.
+pub fn is_handler(vm_id: usize) -> bool {
+    HANDLERS.lock().contains_key(&vm_id)
+}
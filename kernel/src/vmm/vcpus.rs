@@ -1,9 +1,8 @@
-use alloc::{collections::BTreeMap, vec::Vec};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
 use cpumask::CpuMask;
 
 use core::{
-    cell::UnsafeCell,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
     time::Duration,
 };
 use std::os::arceos::{
@@ -17,32 +16,43 @@ use std::os::arceos::{
 use axaddrspace::GuestPhysAddr;
 use axtask::{AxTaskRef, TaskInner, WaitQueue};
 use axvcpu::{AxVCpuExitReason, VCpuState};
+use kspin::SpinNoIrq;
 
-use crate::{hal::arch::inject_interrupt, task::VCpuTask};
+#[cfg(not(target_arch = "x86_64"))]
+use crate::hal::arch::inject_interrupt;
 use crate::{
     task::AsVCpuTask,
     vmm::{VCpuRef, VMRef, sub_running_vm_count},
 };
+use crate::{
+    task::VCpuTask,
+    vmm::hvc::{
+        HVC_FS_QUIESCE, HVC_IVC_BROADCAST, HVC_IVC_KICK, HVC_IVC_WAIT_SPACE, HVC_RT_DOORBELL,
+        HVC_SYSTEM_SHUTDOWN, HVC_TRACE_CONTEXT, HVC_VCPU_SET_AFFINITY, HVC_VIRTIO_HOTPLUG,
+        HVC_VM_READY, HVC_VM_SET_SHARES, HVC_WATCHDOG_KICK,
+    },
+};
 
 const KERNEL_STACK_SIZE: usize = 0x40000; // 256 KiB
+/// Boot delay between two consecutive VMs.
+const BOOT_DELAY_STEP_SECS: usize = 5;
+/// Upper bound of the boot delay, so that the last of many VMs does not wait for minutes.
+const MAX_BOOT_DELAY_SECS: usize = 30;
 
 /// A global static BTreeMap that holds the wait queues for VCpus
 /// associated with their respective VMs, identified by their VM IDs.
-///
-/// TODO: find a better data structure to replace the `static mut`, something like a conditional
-/// variable.
 static VM_VCPU_TASK_WAIT_QUEUE: Queue = Queue::new();
 
 /// A thread-safe queue that manages wait queues for VCpus across multiple VMs.
 ///
-/// This structure wraps a BTreeMap that maps VM IDs to their corresponding VMVCpus structures.
-/// It provides thread-safe access to the mapping through interior mutability using UnsafeCell.
-/// Each VM is identified by its unique ID, and the queue manages the VCpu tasks and wait
-/// operations for all VMs in the system.
-struct Queue(UnsafeCell<BTreeMap<usize, VMVCpus>>);
-
-unsafe impl Sync for Queue {}
-unsafe impl Send for Queue {}
+/// This structure wraps a BTreeMap that maps VM IDs to their corresponding VMVCpus structures,
+/// behind a lock taken with IRQs disabled, as interrupt handlers notify the VCpus of a VM. Each VM
+/// is identified by its unique ID, and the queue manages the VCpu tasks and wait operations for all
+/// VMs in the system.
+///
+/// The lock is only held to look up, insert or remove an entry: the entries are shared, so that a
+/// VCpu blocks on the wait queue of its VM, or the tasks of a removed VM are joined, without it.
+struct Queue(SpinNoIrq<BTreeMap<usize, Arc<VMVCpus>>>);
 
 impl Queue {
     /// Creates a new empty Queue.
@@ -51,30 +61,22 @@ impl Queue {
     ///
     /// A new Queue instance with an empty BTreeMap.
     const fn new() -> Self {
-        Self(UnsafeCell::new(BTreeMap::new()))
+        Self(SpinNoIrq::new(BTreeMap::new()))
     }
 
-    /// Retrieves a reference to the VMVCpus for the specified VM ID.
-    fn get(&self, vm_id: &usize) -> Option<&VMVCpus> {
-        unsafe { (*self.0.get()).get(vm_id) }
+    /// Retrieves the VMVCpus for the specified VM ID.
+    fn get(&self, vm_id: &usize) -> Option<Arc<VMVCpus>> {
+        self.0.lock().get(vm_id).cloned()
     }
 
-    /// Retrieves a mutable reference to the VMVCpus for the specified VM ID.
-    #[allow(clippy::mut_from_ref)]
-    fn get_mut(&self, vm_id: &usize) -> Option<&mut VMVCpus> {
-        unsafe { (*self.0.get()).get_mut(vm_id) }
-    }
-
-    /// Inserts a new VMVCpus entry for the specified VM ID.
-    fn insert(&self, vm_id: usize, vcpus: VMVCpus) {
-        unsafe {
-            (*self.0.get()).insert(vm_id, vcpus);
-        }
+    /// Inserts a new VMVCpus entry for the specified VM ID, returns the entry it replaces.
+    fn insert(&self, vm_id: usize, vcpus: VMVCpus) -> Option<Arc<VMVCpus>> {
+        self.0.lock().insert(vm_id, Arc::new(vcpus))
     }
 
     /// Removes the VMVCpus entry for the specified VM ID.
-    fn remove(&self, vm_id: &usize) -> Option<VMVCpus> {
-        unsafe { (*self.0.get()).remove(vm_id) }
+    fn remove(&self, vm_id: &usize) -> Option<Arc<VMVCpus>> {
+        self.0.lock().remove(vm_id)
     }
 }
 
@@ -86,13 +88,16 @@ pub struct VMVCpus {
     // A wait queue to manage task scheduling for the VCpus.
     wait_queue: WaitQueue,
     // A list of tasks associated with the VCpus of this VM.
-    vcpu_task_list: Vec<AxTaskRef>,
+    vcpu_task_list: SpinNoIrq<Vec<AxTaskRef>>,
     /// The number of currently running or halting VCpus. Used to track when the VM is fully
     /// shutdown.
     ///
     /// This number is incremented when a VCpu starts running and decremented when it exits because
     /// of the VM being shutdown.
     running_halting_vcpu_count: AtomicUsize,
+    /// The number of times the VCpus were notified, for a halting VCpu to tell whether it was
+    /// notified since it last entered the guest.
+    notifications: AtomicUsize,
 }
 
 impl VMVCpus {
@@ -109,8 +114,9 @@ impl VMVCpus {
         Self {
             _vm_id: vm.id(),
             wait_queue: WaitQueue::new(),
-            vcpu_task_list: Vec::with_capacity(vm.vcpu_num()),
+            vcpu_task_list: SpinNoIrq::new(Vec::with_capacity(vm.vcpu_num())),
             running_halting_vcpu_count: AtomicUsize::new(0),
+            notifications: AtomicUsize::new(0),
         }
     }
 
@@ -119,11 +125,13 @@ impl VMVCpus {
     /// # Arguments
     ///
     /// * `vcpu_task` - A reference to the task associated with a VCpu that is to be added.
-    fn add_vcpu_task(&mut self, vcpu_task: AxTaskRef) {
-        // It may be dangerous to go lock-free here, as two VCpus may invoke `CpuUp` at the same
-        // time. However, in most scenarios, only the bsp will `CpuUp` other VCpus, making this
-        // operation single-threaded. Therefore, we just tolerate this as for now.
-        self.vcpu_task_list.push(vcpu_task);
+    fn add_vcpu_task(&self, vcpu_task: AxTaskRef) {
+        self.vcpu_task_list.lock().push(vcpu_task);
+    }
+
+    /// Returns the tasks of the VCpus of this VM.
+    fn tasks(&self) -> Vec<AxTaskRef> {
+        self.vcpu_task_list.lock().clone()
     }
 
     /// Blocks the current thread on the wait queue associated with the VCpus of this VM.
@@ -140,16 +148,27 @@ impl VMVCpus {
         self.wait_queue.wait_until(condition)
     }
 
+    /// Blocks the current thread on the wait queue associated with the VCpus of this VM
+    /// until the provided condition is met or `timeout` elapsed. Returns whether it timed out.
+    fn wait_timeout_until<F>(&self, timeout: Duration, condition: F) -> bool
+    where
+        F: Fn() -> bool,
+    {
+        self.wait_queue.wait_timeout_until(timeout, condition)
+    }
+
     #[allow(dead_code)]
-    fn notify_one(&mut self) {
+    fn notify_one(&self) {
         // FIXME: `WaitQueue::len` is removed
         // info!("Current wait queue length: {}", self.wait_queue.len());
+        self.notifications.fetch_add(1, Ordering::Release);
         self.wait_queue.notify_one(false);
     }
 
     /// Notify all waiting vCPU threads to wake up.
     /// This is useful when shutting down a VM to ensure all vCPUs can check the shutdown flag.
-    fn notify_all(&mut self) {
+    fn notify_all(&self) {
+        self.notifications.fetch_add(1, Ordering::Release);
         self.wait_queue.notify_all(false);
     }
 
@@ -180,7 +199,77 @@ impl VMVCpus {
 /// * `vm_id` - The ID of the VM whose VCpu wait queue is used to block the current thread.
 ///
 fn wait(vm_id: usize) {
-    VM_VCPU_TASK_WAIT_QUEUE.get(&vm_id).unwrap().wait()
+    let curr = axtask::current();
+    let progress = &curr.as_vcpu_task().progress;
+    progress.set_blocked(true);
+    VM_VCPU_TASK_WAIT_QUEUE.get(&vm_id).unwrap().wait();
+    progress.set_blocked(false);
+}
+
+/// Returns the number of times the VCpus of the specified VM were notified, see [`wait_halted`].
+pub(crate) fn notifications(vm_id: usize) -> usize {
+    VM_VCPU_TASK_WAIT_QUEUE
+        .get(&vm_id)
+        .map_or(0, |vm_vcpus| vm_vcpus.notifications.load(Ordering::Acquire))
+}
+
+/// Blocks the current thread, a halted VCpu, until the VCpus of the specified VM are notified, a
+/// doorbell is pending for the VCpu or `deadline_ns` (monotonic) passed. Returns whether the
+/// deadline passed.
+///
+/// A notification since [`notifications`] returned `since`, e.g. while the VCpu was still in the
+/// guest, isn't lost: the thread doesn't block.
+///
+/// # Arguments
+///
+/// * `vm_id` - The ID of the VM whose VCpu wait queue is used to block the current thread.
+/// * `vcpu_id` - The ID of the halted VCpu.
+/// * `since` - The number of notifications when the VCpu entered the guest.
+/// * `deadline_ns` - The monotonic time in nanoseconds to wake up at the latest, if any.
+///
+pub(crate) fn wait_halted(
+    vm_id: usize,
+    vcpu_id: usize,
+    since: usize,
+    deadline_ns: Option<u64>,
+) -> bool {
+    let vm_vcpus = VM_VCPU_TASK_WAIT_QUEUE.get(&vm_id).unwrap();
+    wait_for_deadline(vm_id, deadline_ns, || {
+        vm_vcpus.notifications.load(Ordering::Acquire) != since
+            || super::doorbell::has_pending(vm_id, vcpu_id)
+    })
+}
+
+/// Blocks the current thread until the provided condition is met or `deadline_ns` (monotonic)
+/// passed, using the wait queue associated with the VCpus of the specified VM. Returns whether the
+/// deadline passed.
+///
+/// # Arguments
+///
+/// * `vm_id` - The ID of the VM whose VCpu wait queue is used to block the current thread.
+/// * `deadline_ns` - The monotonic time in nanoseconds to wake up at the latest, if any.
+/// * `condition` - A closure that returns a boolean value indicating whether the condition is met.
+///
+pub(crate) fn wait_for_deadline<F>(vm_id: usize, deadline_ns: Option<u64>, condition: F) -> bool
+where
+    F: Fn() -> bool,
+{
+    let curr = axtask::current();
+    let progress = &curr.as_vcpu_task().progress;
+    let vm_vcpus = VM_VCPU_TASK_WAIT_QUEUE.get(&vm_id).unwrap();
+    progress.set_blocked(true);
+    let timed_out = match deadline_ns {
+        Some(deadline_ns) => {
+            let timeout = deadline_ns.saturating_sub(axhal::time::monotonic_time_nanos());
+            vm_vcpus.wait_timeout_until(Duration::from_nanos(timeout), condition)
+        }
+        None => {
+            vm_vcpus.wait_until(condition);
+            false
+        }
+    };
+    progress.set_blocked(false);
+    timed_out
 }
 
 /// Blocks the current thread until the provided condition is met, using the wait queue
@@ -191,14 +280,18 @@ fn wait(vm_id: usize) {
 /// * `vm_id` - The ID of the VM whose VCpu wait queue is used to block the current thread.
 /// * `condition` - A closure that returns a boolean value indicating whether the condition is met.
 ///
-fn wait_for<F>(vm_id: usize, condition: F)
+pub(crate) fn wait_for<F>(vm_id: usize, condition: F)
 where
     F: Fn() -> bool,
 {
+    let curr = axtask::current();
+    let progress = &curr.as_vcpu_task().progress;
+    progress.set_blocked(true);
     VM_VCPU_TASK_WAIT_QUEUE
         .get(&vm_id)
         .unwrap()
-        .wait_until(condition)
+        .wait_until(condition);
+    progress.set_blocked(false);
 }
 
 /// Notifies the primary VCpu task associated with the specified VM to wake up and resume execution.
@@ -210,10 +303,7 @@ where
 ///
 pub(crate) fn notify_primary_vcpu(vm_id: usize) {
     // Generally, the primary VCpu is the first and **only** VCpu in the list.
-    VM_VCPU_TASK_WAIT_QUEUE
-        .get_mut(&vm_id)
-        .unwrap()
-        .notify_one()
+    VM_VCPU_TASK_WAIT_QUEUE.get(&vm_id).unwrap().notify_one()
 }
 
 /// Notifies all VCpu tasks associated with the specified VM to wake up.
@@ -224,11 +314,38 @@ pub(crate) fn notify_primary_vcpu(vm_id: usize) {
 /// * `vm_id` - The ID of the VM whose VCpus should be notified.
 ///
 pub(crate) fn notify_all_vcpus(vm_id: usize) {
-    if let Some(vm_vcpus) = VM_VCPU_TASK_WAIT_QUEUE.get_mut(&vm_id) {
+    if let Some(vm_vcpus) = VM_VCPU_TASK_WAIT_QUEUE.get(&vm_id) {
         vm_vcpus.notify_all();
     }
 }
 
+/// Makes VCpu `vcpu_id` of VM `vm_id` handle an event set for it promptly, without taking the
+/// lock of the wait queue unless the VCpu is blocked (the lock of the VCpus of the VMs is only held
+/// to look the VCpu up):
+///
+/// - in the guest on another CPU, the CPU is interrupted, which makes the VCpu exit;
+/// - blocked, e.g. halted, the VCpus of the VM are notified;
+/// - otherwise, the VCpu handles the event before it enters the guest again.
+///
+/// The event must be set with `SeqCst` ordering, and checked the same way by the VCpu after
+/// [`super::percpu::enter`] and in the wakeup condition of its wait: either the VCpu sees the event,
+/// or this sees the VCpu in the guest or blocked.
+pub(crate) fn kick(vm_id: usize, vcpu_id: usize) {
+    if let Some(cpu) = super::percpu::guest_cpu(vm_id, vcpu_id) {
+        if cpu != axhal::percpu::this_cpu_id() {
+            // The interrupt itself makes the VCpu exit, there's nothing to run on the target CPU.
+            axipi::send_ipi_event_to_one(cpu, || {});
+        }
+        return;
+    }
+    let blocked = with_vcpu_task(vm_id, vcpu_id, |task| {
+        task.as_vcpu_task().progress.is_blocked()
+    });
+    if blocked == Some(true) {
+        notify_all_vcpus(vm_id);
+    }
+}
+
 /// Cleans up VCpu resources for a VM that is being deleted.
 /// This removes the VM's entry from the global VCpu wait queue.
 ///
@@ -242,12 +359,13 @@ pub(crate) fn notify_all_vcpus(vm_id: usize) {
 /// It will join all VCpu tasks to ensure they are fully cleaned up.
 pub(crate) fn cleanup_vm_vcpus(vm_id: usize) {
     if let Some(vm_vcpus) = VM_VCPU_TASK_WAIT_QUEUE.remove(&vm_id) {
-        let task_count = vm_vcpus.vcpu_task_list.len();
+        let tasks = vm_vcpus.tasks();
+        let task_count = tasks.len();
 
         info!("VM[{}] Joining {} VCpu tasks...", vm_id, task_count);
 
         // Join all VCpu tasks to ensure they have fully exited and cleaned up
-        for (idx, task) in vm_vcpus.vcpu_task_list.iter().enumerate() {
+        for (idx, task) in tasks.iter().enumerate() {
             debug!(
                 "VM[{}] Joining VCpu task[{}]: {}",
                 vm_id,
@@ -297,7 +415,7 @@ fn mark_vcpu_exiting(vm_id: usize) -> bool {
 /// * `entry_point` - The entry point of the VCpu.
 /// * `arg` - The argument to be passed to the VCpu.
 ///
-fn vcpu_on(vm: VMRef, vcpu_id: usize, entry_point: GuestPhysAddr, arg: usize) {
+pub(super) fn vcpu_on(vm: VMRef, vcpu_id: usize, entry_point: GuestPhysAddr, arg: usize) {
     let vcpu = vm.vcpu_list()[vcpu_id].clone();
     assert_eq!(
         vcpu.state(),
@@ -324,7 +442,7 @@ fn vcpu_on(vm: VMRef, vcpu_id: usize, entry_point: GuestPhysAddr, arg: usize) {
     let vcpu_task = alloc_vcpu_task(&vm, vcpu);
 
     VM_VCPU_TASK_WAIT_QUEUE
-        .get_mut(&vm.id())
+        .get(&vm.id())
         .unwrap()
         .add_vcpu_task(vcpu_task);
 }
@@ -340,7 +458,7 @@ fn vcpu_on(vm: VMRef, vcpu_id: usize, entry_point: GuestPhysAddr, arg: usize) {
 pub fn setup_vm_primary_vcpu(vm: VMRef) {
     info!("Initializing VM[{}]'s {} vcpus", vm.id(), vm.vcpu_num());
     let vm_id = vm.id();
-    let mut vm_vcpus = VMVCpus::new(vm.clone());
+    let vm_vcpus = VMVCpus::new(vm.clone());
 
     let primary_vcpu_id = 0;
 
@@ -348,7 +466,9 @@ pub fn setup_vm_primary_vcpu(vm: VMRef) {
     let primary_vcpu_task = alloc_vcpu_task(&vm, primary_vcpu);
     vm_vcpus.add_vcpu_task(primary_vcpu_task);
 
-    VM_VCPU_TASK_WAIT_QUEUE.insert(vm_id, vm_vcpus);
+    if VM_VCPU_TASK_WAIT_QUEUE.insert(vm_id, vm_vcpus).is_some() {
+        warn!("VM[{}] VCpu tasks replaced without being joined", vm_id);
+    }
 }
 
 /// Finds the [`AxTaskRef`] associated with the specified vCPU of the specified VM.
@@ -361,16 +481,17 @@ pub fn with_vcpu_task<T, F: FnOnce(&AxTaskRef) -> T>(
     vcpu_id: usize,
     f: F,
 ) -> Option<T> {
-    VM_VCPU_TASK_WAIT_QUEUE
-        .get(&vm_id)
-        .unwrap()
+    let task = VM_VCPU_TASK_WAIT_QUEUE
+        .get(&vm_id)?
         .vcpu_task_list
+        .lock()
         .get(vcpu_id)
-        .map(f)
+        .cloned()?;
+    Some(f(&task))
 }
 
 /// Allocates arceos task for vcpu, set the task's entry function to [`vcpu_run()`],
-/// also initializes the CPU mask if the VCpu has a dedicated physical CPU set.
+/// also initializes the CPU mask of the VCpu, see [`super::affinity`].
 ///
 /// # Arguments
 ///
@@ -394,9 +515,9 @@ fn alloc_vcpu_task(vm: &VMRef, vcpu: VCpuRef) -> AxTaskRef {
         KERNEL_STACK_SIZE,
     );
 
-    if let Some(phys_cpu_set) = vcpu.phys_cpu_set() {
-        vcpu_task.set_cpumask(AxCpuMask::from_raw_bits(phys_cpu_set));
-    }
+    vcpu_task.set_cpumask(AxCpuMask::from_raw_bits(super::affinity::vcpu_mask(
+        vm, &vcpu,
+    )));
 
     // Use Weak reference in TaskExt to avoid keeping VM alive
     let inner = VCpuTask::new(vm, vcpu);
@@ -410,6 +531,50 @@ fn alloc_vcpu_task(vm: &VMRef, vcpu: VCpuRef) -> AxTaskRef {
     axtask::spawn_task(vcpu_task)
 }
 
+/// Carries out the result of a PSCI call of `vcpu`.
+#[cfg(target_arch = "aarch64")]
+fn handle_psci_action(vm: &VMRef, vcpu: &VCpuRef, action: super::psci::PsciAction) {
+    use super::psci::{self, PsciAction};
+    use super::reboot::PowerRequest;
+
+    let vm_id = vm.id();
+    let vcpu_id = vcpu.id();
+    match action {
+        PsciAction::Return(ret) => vcpu.set_return_value(ret as usize),
+        PsciAction::CpuOff => {
+            wait_for(vm_id, || {
+                vm.stopping() || psci::resume_pending(vm_id, vcpu_id)
+            });
+            if let Some((entry, context_id)) = psci::resume(vm_id, vcpu_id) {
+                if let Err(err) = vcpu.set_entry(entry) {
+                    error!("VM[{vm_id}] VCpu[{vcpu_id}] failed to set entry {entry:?}: {err:?}");
+                }
+                vcpu.set_gpr(0, context_id);
+            }
+        }
+        PsciAction::Suspend => {
+            wait(vm_id);
+            vcpu.set_return_value(0);
+        }
+        PsciAction::SystemSuspend { entry, context_id } => {
+            info!("VM[{vm_id}] VCpu[{vcpu_id}] SYSTEM_SUSPEND");
+            super::sleep::suspend(vm);
+            if let Err(err) = vcpu.set_entry(entry) {
+                error!("VM[{vm_id}] VCpu[{vcpu_id}] failed to set entry {entry:?}: {err:?}");
+            }
+            vcpu.set_gpr(0, context_id);
+        }
+        PsciAction::SystemOff => {
+            warn!("VM[{vm_id}] VCpu[{vcpu_id}] SYSTEM_OFF");
+            super::reboot::on_guest_request(vm, PowerRequest::PowerOff);
+        }
+        PsciAction::SystemReset => {
+            warn!("VM[{vm_id}] VCpu[{vcpu_id}] SYSTEM_RESET");
+            super::reboot::on_guest_request(vm, PowerRequest::Reboot);
+        }
+    }
+}
+
 /// The main routine for VCpu task.
 /// This function is the entry point for the VCpu tasks, which are spawned for each VCpu of a VM.
 ///
@@ -424,19 +589,296 @@ fn vcpu_run() {
     let vcpu_id = vcpu.id();
 
     // boot delay
-    let boot_delay_sec = (vm_id - 1) * 5;
+    let boot_delay_sec = (vm_id.saturating_sub(1) * BOOT_DELAY_STEP_SECS).min(MAX_BOOT_DELAY_SECS);
     info!("VM[{vm_id}] boot delay: {boot_delay_sec}s");
-    busy_wait(Duration::from_secs(boot_delay_sec as _));
+    for _ in 0..boot_delay_sec {
+        busy_wait(Duration::from_secs(1));
+        // The delay holds the CPU, beat for it.
+        super::lockup::heartbeat();
+    }
 
     info!("VM[{}] VCpu[{}] waiting for running", vm.id(), vcpu.id());
     wait_for(vm_id, || vm.running());
 
     info!("VM[{}] VCpu[{}] running...", vm.id(), vcpu.id());
     mark_vcpu_running(vm_id);
+    if vcpu_id == 0 {
+        super::guest_time::start(vm_id);
+        super::crash::rearm(vm_id);
+        super::lifecycle::emit(vm_id, super::lifecycle::LifecycleEvent::Booted, 0);
+    }
+    let mut slice = super::sched::VCpuSlice::new(vm_id);
+    let share = super::sched::vm_share(vm_id);
+    let mut gang = super::sched::VCpuGang::new(vm_id, vcpu_id);
+    let mut clock = super::guest_time::VCpuClock::new(vm_id, vcpu_id);
+    let posted = super::posted::VCpuPosted::new(vm_id, vcpu_id);
+    let mut pmu = super::pmu::VCpuPmu::new(vm_id, vcpu_id);
+    let mut instructions = super::hang::VCpuInstructions::new(vm_id);
+    let freq = super::cpufreq::VCpuFreq::new(vm_id);
+    #[cfg(target_arch = "aarch64")]
+    let mut spe = super::spe::VCpuSpe::new(vm_id, vcpu_id);
+    let stats = super::stats::VCpuStats::new(vm_id, vcpu_id);
+    let mut budget = super::exit_budget::VCpuExitBudget::new(vm_id, vcpu_id);
 
     loop {
-        match vm.run_vcpu(vcpu_id) {
+        super::affinity::apply_pending(curr.as_vcpu_task());
+        #[cfg(target_arch = "aarch64")]
+        super::vtimer::deliver_pending(vm_id, &vcpu);
+        #[cfg(target_arch = "riscv64")]
+        super::vintc::deliver_pending(vm_id, &vcpu);
+        #[cfg(target_arch = "x86_64")]
+        super::x2apic::deliver_pending(vm_id, &vcpu);
+
+        if let Some(gang) = &mut gang {
+            let since_ns = axhal::time::monotonic_time_nanos();
+            gang.assemble(|| vm.stopping() || vm.suspending());
+            if let Some(clock) = &clock {
+                clock.steal_since(since_ns);
+            }
+        }
+        if let Some(clock) = &mut clock {
+            clock.update_record(&vm);
+        }
+        if let Some(posted) = &posted {
+            posted.enter(&vcpu);
+        }
+        if let Some(pmu) = &mut pmu {
+            pmu.enter();
+        }
+        if let Some(instructions) = &mut instructions {
+            instructions.enter();
+        }
+        if let Some(freq) = &freq {
+            freq.enter();
+        }
+        #[cfg(target_arch = "aarch64")]
+        if let Some(spe) = &mut spe {
+            spe.enter();
+        }
+        super::lockup::trace_entry(vm_id, vcpu_id);
+        let notified = notifications(vm_id);
+        let entry_ns = axhal::time::monotonic_time_nanos();
+        super::percpu::enter(vm_id, vcpu_id);
+        // After the entry is recorded, so that a doorbell rung from now on kicks the vCPU.
+        let doorbells = super::doorbell::deliver_pending(vm_id, &vcpu);
+        if let Some(stats) = &stats {
+            stats.count_irqs(doorbells);
+        }
+        let result = vm.run_vcpu(vcpu_id);
+        // Before anything else runs on the CPU and changes the timer.
+        let guest_deadline = match &result {
+            Ok(AxVCpuExitReason::Halt) => super::idle::guest_deadline(),
+            _ => None,
+        };
+        // The registers a vCPU stops with, see `crate::vmm::regs`.
+        let stopped = matches!(result, Err(_) | Ok(AxVCpuExitReason::Halt)) || vm.suspending();
+        curr.as_vcpu_task().regs.on_exit(&vcpu, stopped);
+        super::percpu::exit(vm_id, vcpu_id, result.as_ref().ok());
+        #[cfg(target_arch = "aarch64")]
+        if let Some(spe) = &mut spe {
+            spe.exit();
+        }
+        if let Some(instructions) = &mut instructions {
+            instructions.exit(&curr.as_vcpu_task().progress);
+        }
+        if let Some(pmu) = &mut pmu {
+            pmu.exit();
+        }
+        if let Some(posted) = &posted {
+            posted.exit();
+        }
+        let run_ns = axhal::time::monotonic_time_nanos() - entry_ns;
+        if let Some(share) = &share {
+            share.charge(vcpu_id, run_ns);
+        }
+        if let Ok(exit_reason) = &result {
+            curr.as_vcpu_task().progress.record_exit(exit_reason);
+            let exit_code = super::hang::exit_code(exit_reason);
+            if let Some(stats) = &stats {
+                stats.record_exit(exit_code, run_ns);
+            }
+            if let Some(budget) = &mut budget {
+                budget.record_exit(exit_reason);
+            }
+            super::lockup::trace_exit(vm_id, vcpu_id, exit_code);
+            super::trace::record(
+                super::trace::TRACE_CLASS_EXIT,
+                vm_id,
+                vcpu_id,
+                exit_code as u64,
+                0,
+            );
+            if let AxVCpuExitReason::Hypercall { nr, args } = exit_reason {
+                super::trace::record(
+                    super::trace::TRACE_CLASS_HYPERCALL,
+                    vm_id,
+                    vcpu_id,
+                    *nr,
+                    args[0],
+                );
+                super::tracectx::stamp_hypercall(vm_id, vcpu_id, *nr);
+            }
+            super::vmi::on_exit(&vm, vcpu_id, exit_reason);
+        }
+        match result {
             Ok(exit_reason) => match exit_reason {
+                AxVCpuExitReason::Hypercall { nr, args } if nr == HVC_RT_DOORBELL => {
+                    // Real-time fast path, no logging here.
+                    vcpu.set_return_value(super::doorbell::ring(vm_id, args[0] as usize) as usize);
+                }
+                AxVCpuExitReason::Hypercall { nr, args } if nr == HVC_IVC_KICK => {
+                    let ret_val = if !super::irqpolicy::admit(vm_id) {
+                        super::irqpolicy::IRQ_THROTTLED
+                    } else {
+                        match super::hvc::ivc_kick(vm_id, vcpu_id, args) {
+                            Ok(()) => 0,
+                            Err(err) => {
+                                warn!("VM[{vm_id}] IVC kick failed: {err:?}");
+                                -1
+                            }
+                        }
+                    };
+                    vcpu.set_return_value(ret_val as usize);
+                }
+                AxVCpuExitReason::Hypercall { nr, args } if nr == HVC_IVC_WAIT_SPACE => {
+                    let block = |woken: &AtomicBool| {
+                        wait_for(vm_id, || vm.stopping() || woken.load(Ordering::Acquire))
+                    };
+                    let ret_val = match super::hvc::ivc_wait_space(vm_id, args, block) {
+                        Ok(free) => free as isize,
+                        Err(err) => {
+                            warn!("VM[{vm_id}] IVC space wait failed: {err:?}");
+                            -1
+                        }
+                    };
+                    vcpu.set_return_value(ret_val as usize);
+                }
+                AxVCpuExitReason::Hypercall { nr, args } if nr == HVC_IVC_BROADCAST => {
+                    let ret_val = match super::hvc::ivc_broadcast(vm_id, vcpu_id, args) {
+                        Ok(()) => 0,
+                        Err(err) => {
+                            warn!("VM[{vm_id}] IVC broadcast update failed: {err:?}");
+                            -1
+                        }
+                    };
+                    vcpu.set_return_value(ret_val as usize);
+                }
+                AxVCpuExitReason::Hypercall { nr, args: _ } if nr == HVC_WATCHDOG_KICK => {
+                    let ret_val = match super::watchdog::kick(vm_id) {
+                        Ok(()) => 0,
+                        Err(err) => {
+                            warn!("VM[{vm_id}] watchdog kick failed: {err:?}");
+                            -1
+                        }
+                    };
+                    vcpu.set_return_value(ret_val as usize);
+                }
+                AxVCpuExitReason::Hypercall { nr, args } if nr == HVC_VCPU_SET_AFFINITY => {
+                    let ret_val = match super::affinity::set_vcpu_affinity(vm_id, args) {
+                        Ok(()) => 0,
+                        Err(err) => {
+                            warn!("VM[{vm_id}] vCPU affinity update failed: {err:?}");
+                            -1
+                        }
+                    };
+                    vcpu.set_return_value(ret_val as usize);
+                }
+                AxVCpuExitReason::Hypercall { nr, args } if nr == HVC_VM_SET_SHARES => {
+                    let ret_val = match super::sched::handle_set_shares(vm_id, args) {
+                        Ok(()) => 0,
+                        Err(err) => {
+                            warn!("VM[{vm_id}] CPU share update failed: {err:?}");
+                            -1
+                        }
+                    };
+                    vcpu.set_return_value(ret_val as usize);
+                }
+                AxVCpuExitReason::Hypercall { nr, args } if nr == HVC_TRACE_CONTEXT => {
+                    let ret_val = match super::tracectx::handle_call(&vm, vcpu_id, args) {
+                        Ok(ret) => ret as isize,
+                        Err(err) => {
+                            warn!("VM[{vm_id}] trace context call failed: {err:?}");
+                            -1
+                        }
+                    };
+                    vcpu.set_return_value(ret_val as usize);
+                }
+                AxVCpuExitReason::Hypercall { nr, args: _ } if nr == HVC_VM_READY => {
+                    super::lifecycle::emit(vm_id, super::lifecycle::LifecycleEvent::Ready, 0);
+                    vcpu.set_return_value(0);
+                }
+                AxVCpuExitReason::Hypercall { nr, args } if nr == HVC_SYSTEM_SHUTDOWN => {
+                    let ret_val = match super::shutdown::handle_hypercall(vm_id, args) {
+                        Ok(()) => 0,
+                        Err(err) => {
+                            warn!("VM[{vm_id}] system shutdown failed: {err:?}");
+                            -1
+                        }
+                    };
+                    vcpu.set_return_value(ret_val as usize);
+                }
+                AxVCpuExitReason::Hypercall { nr, args } if nr == HVC_VIRTIO_HOTPLUG => {
+                    let ret_val = match super::virtio::handle_hotplug(&vm, args) {
+                        Ok(slot) => slot as isize,
+                        Err(err) => {
+                            warn!("VM[{vm_id}] virtio hot-plug failed: {err:?}");
+                            -1
+                        }
+                    };
+                    vcpu.set_return_value(ret_val as usize);
+                }
+                AxVCpuExitReason::Hypercall { nr, args } if nr == HVC_FS_QUIESCE => {
+                    let ret_val = match super::quiesce::handle_hypercall(&vm, args) {
+                        Ok(()) => 0,
+                        Err(err) => {
+                            warn!("VM[{vm_id}] quiesce report failed: {err:?}");
+                            -1
+                        }
+                    };
+                    vcpu.set_return_value(ret_val as usize);
+                }
+                #[cfg(target_arch = "aarch64")]
+                AxVCpuExitReason::Hypercall { nr, args }
+                    if super::guest_time::is_pv_time_call(nr) =>
+                {
+                    let ret_val = super::guest_time::handle_pv_time_call(&vm, vcpu_id, nr, args);
+                    vcpu.set_return_value(ret_val as usize);
+                }
+                #[cfg(target_arch = "aarch64")]
+                AxVCpuExitReason::Hypercall { nr, args } if super::psci::is_psci_call(nr) => {
+                    let action = super::psci::handle_call(&vm, vcpu_id, nr, args);
+                    handle_psci_action(&vm, &vcpu, action);
+                }
+                #[cfg(target_arch = "x86_64")]
+                AxVCpuExitReason::Hypercall { nr, args }
+                    if super::pvcompat::is_pv_call(vm_id, nr) =>
+                {
+                    let ret_val = super::pvcompat::handle_call(&vm, vcpu_id, nr, args);
+                    vcpu.set_return_value(ret_val as usize);
+                }
+                #[cfg(target_arch = "aarch64")]
+                AxVCpuExitReason::Hypercall { nr, args } if super::smc::is_filtered(vm_id, nr) => {
+                    let ret = super::smc::handle_call(&vm, nr, args);
+                    vcpu.set_return_value(ret[0] as usize);
+                    for (reg, val) in ret.iter().enumerate().skip(1) {
+                        vcpu.set_gpr(reg, *val as usize);
+                    }
+                }
+                AxVCpuExitReason::Hypercall { nr, args } if super::hvc::has_service(nr) => {
+                    let ret_val = match super::hvc::call_service(&vm, vcpu_id, nr, args) {
+                        Ok(ret) => ret as isize,
+                        Err(_) => -1,
+                    };
+                    vcpu.set_return_value(ret_val as usize);
+                }
+                AxVCpuExitReason::Hypercall { nr, args } if !super::hvc::is_implemented(nr) => {
+                    let block = |woken: &AtomicBool| {
+                        wait_for(vm_id, || vm.stopping() || woken.load(Ordering::Acquire))
+                    };
+                    let ret_val =
+                        super::unknown_hvc::handle_unknown(vm_id, vcpu_id, nr, args, block);
+                    vcpu.set_return_value(ret_val as usize);
+                }
                 AxVCpuExitReason::Hypercall { nr, args } => {
                     debug!("Hypercall [{nr}] args {args:x?}");
                     use crate::vmm::hvc::HyperCall;
@@ -463,23 +905,131 @@ fn vcpu_run() {
                     warn!(
                         "VM[{vm_id}] VCpu[{vcpu_id}] run failed with exit code {hardware_entry_failure_reason}"
                     );
+                    let handled = super::crash::on_crash(
+                        &vm,
+                        vcpu_id,
+                        super::crash::CrashReason::FailEntry,
+                        format!("entry failure reason {hardware_entry_failure_reason:#x}"),
+                    );
+                    // The backend reports a triple fault as a failed entry, real hardware resets
+                    // the machine then.
+                    if !handled && cfg!(target_arch = "x86_64") {
+                        super::reboot::on_guest_request(&vm, super::reboot::PowerRequest::Reboot);
+                    }
+                }
+                AxVCpuExitReason::NestedPageFault { addr, access_flags } => {
+                    // An access to a page watched for introspection, a write to a page protected
+                    // for dirty tracking or heat sampling, or a page to populate.
+                    let handled = super::vmi::handle_fault(&vm, vcpu_id, addr, access_flags);
+                    let handled = match handled {
+                        Ok(false) => super::dirty::handle_fault(&vm, addr, access_flags),
+                        handled => handled,
+                    };
+                    let handled = match handled {
+                        Ok(false) => super::heatmap::handle_fault(&vm, addr, access_flags),
+                        handled => handled,
+                    };
+                    let handled = match handled {
+                        Ok(false) => super::lazymem::handle_fault(&vm, addr),
+                        handled => handled,
+                    };
+                    match handled {
+                        Ok(true) => {}
+                        Ok(false) => warn!(
+                            "VM[{vm_id}] VCpu[{vcpu_id}] unhandled stage-2 fault at {addr:#x} ({access_flags:?})"
+                        ),
+                        Err(err) => {
+                            let crash = super::crash::CrashReason::RunError;
+                            let detail = format!("stage-2 fault at {addr:#x}: {err:?}");
+                            if !super::crash::on_crash(&vm, vcpu_id, crash, detail) {
+                                vm.shutdown().expect("VM shutdown failed");
+                            }
+                        }
+                    }
                 }
                 AxVCpuExitReason::ExternalInterrupt { vector } => {
                     debug!("VM[{vm_id}] run VCpu[{vcpu_id}] get irq {vector}");
 
+                    #[cfg(target_arch = "aarch64")]
+                    let spe_irq = super::spe::handle_host_irq(&vm, vcpu_id, vector as usize);
+                    #[cfg(not(target_arch = "aarch64"))]
+                    let spe_irq = false;
                     // TODO: maybe move this irq dispatcher to lower layer to accelerate the interrupt handling
-                    axhal::irq::irq_handler(vector as usize);
+                    if !spe_irq
+                        && !super::direct_irq::handle_host_irq(vector as usize)
+                        && !super::shutdown::handle_host_irq(vector as usize)
+                    {
+                        axhal::irq::irq_handler(vector as usize);
+                    }
                     super::timer::check_events();
                 }
+                AxVCpuExitReason::MmioRead {
+                    addr,
+                    width,
+                    reg,
+                    reg_width: _,
+                    signed_ext: _,
+                } => match super::mmio::handle_mmio_read(&vm, addr, width) {
+                    Ok(val) => vcpu.set_gpr(reg, val),
+                    Err(err) => {
+                        warn!(
+                            "VM[{vm_id}] VCpu[{vcpu_id}] unhandled MMIO read at {addr:?}: {err:?}"
+                        )
+                    }
+                },
+                AxVCpuExitReason::MmioWrite { addr, width, data } => {
+                    if let Err(err) =
+                        super::mmio::handle_mmio_write(&vm, addr, width, data as usize)
+                    {
+                        warn!(
+                            "VM[{vm_id}] VCpu[{vcpu_id}] unhandled MMIO write at {addr:?}: {err:?}"
+                        );
+                    }
+                }
+                #[cfg(target_arch = "x86_64")]
+                AxVCpuExitReason::SysRegRead { addr, reg: _ } => {
+                    let msr = addr.addr();
+                    match super::x2apic::handle_msr_read(vm_id, vcpu_id, msr)
+                        .or_else(|| pmu.as_ref()?.handle_msr_read(msr))
+                        .or_else(|| super::cpufreq::handle_msr_read(vm_id, msr))
+                    {
+                        // RDMSR returns the value in EDX:EAX.
+                        Some(Ok(val)) => {
+                            vcpu.set_gpr(0, val as u32 as usize);
+                            vcpu.set_gpr(2, (val >> 32) as usize);
+                        }
+                        Some(Err(err)) => {
+                            warn!("VM[{vm_id}] VCpu[{vcpu_id}] MSR read {addr:?} failed: {err:?}")
+                        }
+                        None => warn!("VM[{vm_id}] VCpu[{vcpu_id}] unhandled MSR read {addr:?}"),
+                    }
+                }
+                #[cfg(target_arch = "x86_64")]
+                AxVCpuExitReason::SysRegWrite { addr, value } => {
+                    let msr = addr.addr();
+                    match super::x2apic::handle_msr_write(&vm, vcpu_id, msr, value)
+                        .or_else(|| pmu.as_mut()?.handle_msr_write(msr, value))
+                        .or_else(|| super::cpufreq::handle_msr_write(&vm, msr, value))
+                    {
+                        Some(Ok(())) => {}
+                        Some(Err(err)) => {
+                            warn!("VM[{vm_id}] VCpu[{vcpu_id}] MSR write {addr:?} failed: {err:?}")
+                        }
+                        None => warn!("VM[{vm_id}] VCpu[{vcpu_id}] unhandled MSR write {addr:?}"),
+                    }
+                }
                 AxVCpuExitReason::Halt => {
                     debug!("VM[{vm_id}] run VCpu[{vcpu_id}] Halt");
-                    wait(vm_id)
+                    let pending = posted.as_ref().is_some_and(|posted| posted.pending());
+                    super::idle::halt(vm_id, vcpu_id, notified, pending, guest_deadline);
                 }
                 AxVCpuExitReason::Nothing => {}
+                #[cfg(not(target_arch = "aarch64"))]
                 AxVCpuExitReason::CpuDown { _state } => {
                     warn!("VM[{vm_id}] run VCpu[{vcpu_id}] CpuDown state {_state:#x}");
                     wait(vm_id)
                 }
+                #[cfg(not(target_arch = "aarch64"))]
                 AxVCpuExitReason::CpuUp {
                     target_cpu,
                     entry_point,
@@ -509,9 +1059,28 @@ fn vcpu_run() {
                     vcpu_on(vm.clone(), target_vcpu_id, entry_point, arg as _);
                     vcpu.set_gpr(0, 0);
                 }
+                #[cfg(not(target_arch = "aarch64"))]
                 AxVCpuExitReason::SystemDown => {
                     warn!("VM[{vm_id}] run VCpu[{vcpu_id}] SystemDown");
-                    vm.shutdown().expect("VM shutdown failed");
+                    super::reboot::on_guest_request(&vm, super::reboot::PowerRequest::PowerOff);
+                }
+                #[cfg(target_arch = "aarch64")]
+                AxVCpuExitReason::CpuDown { _state } => {
+                    let action = super::psci::cpu_off(&vm, vcpu_id);
+                    handle_psci_action(&vm, &vcpu, action);
+                }
+                #[cfg(target_arch = "aarch64")]
+                AxVCpuExitReason::CpuUp {
+                    target_cpu,
+                    entry_point,
+                    arg,
+                } => {
+                    let ret = super::psci::cpu_on(&vm, target_cpu, entry_point, arg as _);
+                    vcpu.set_gpr(0, ret as usize);
+                }
+                #[cfg(target_arch = "aarch64")]
+                AxVCpuExitReason::SystemDown => {
+                    handle_psci_action(&vm, &vcpu, super::psci::PsciAction::SystemOff);
                 }
                 AxVCpuExitReason::SendIPI {
                     target_cpu,
@@ -523,18 +1092,35 @@ fn vcpu_run() {
                     debug!(
                         "VM[{vm_id}] run VCpu[{vcpu_id}] SendIPI, target_cpu={target_cpu:#x}, target_cpu_aux={target_cpu_aux:#x}, vector={vector}",
                     );
-                    if send_to_all {
-                        unimplemented!("Send IPI to all CPUs is not implemented yet");
-                    }
-
-                    if target_cpu == vcpu_id as u64 || send_to_self {
-                        inject_interrupt(vector as _);
+                    let targets: Vec<usize> = if send_to_all {
+                        // A broadcast excludes the sender unless it asked for itself too.
+                        (0..vm.vcpu_num())
+                            .filter(|&target| target != vcpu_id || send_to_self)
+                            .collect()
+                    } else if send_to_self {
+                        vec![vcpu_id]
                     } else {
-                        vm.inject_interrupt_to_vcpu(
-                            CpuMask::one_shot(target_cpu as _),
-                            vector as _,
-                        )
-                        .unwrap();
+                        vec![target_cpu as usize]
+                    };
+                    for target in targets {
+                        if target == vcpu_id {
+                            // On x86 the vector must reach this vCPU (its local APIC if emulated),
+                            // not the physical CPU.
+                            #[cfg(target_arch = "x86_64")]
+                            if let Err(err) = super::posted::post(vm_id, vcpu_id, vector as _) {
+                                warn!("VM[{vm_id}] VCpu[{vcpu_id}] self IPI failed: {err:?}");
+                                continue;
+                            }
+                            #[cfg(not(target_arch = "x86_64"))]
+                            inject_interrupt(vector as _);
+                        } else {
+                            #[cfg(target_arch = "aarch64")]
+                            super::vsgi::send(&vm, target, vector as _);
+                            #[cfg(not(target_arch = "aarch64"))]
+                            vm.inject_interrupt_to_vcpu(CpuMask::one_shot(target), vector as _)
+                                .unwrap();
+                        }
+                        super::stats::count_irq(vm_id, target);
                     }
                 }
                 e => {
@@ -543,11 +1129,30 @@ fn vcpu_run() {
             },
             Err(err) => {
                 error!("VM[{vm_id}] run VCpu[{vcpu_id}] get error {err:?}");
-                // wait(vm_id)
-                vm.shutdown().expect("VM shutdown failed");
+                let crash = super::crash::CrashReason::RunError;
+                if !super::crash::on_crash(&vm, vcpu_id, crash, format!("{err:?}")) {
+                    vm.shutdown().expect("VM shutdown failed");
+                }
             }
         }
 
+        let resched_ns = axhal::time::monotonic_time_nanos();
+        if let Some(slice) = &mut slice {
+            slice.yield_if_expired();
+        }
+        if let Some(share) = &share {
+            share.enforce();
+        }
+        if let Some(gang) = &mut gang {
+            gang.yield_if_expired();
+        }
+        if let Some(budget) = &mut budget {
+            budget.enforce();
+        }
+        if let Some(clock) = &clock {
+            clock.steal_since(resched_ns);
+        }
+
         // Check if the VM is suspended
         if vm.suspending() {
             debug!(
@@ -573,8 +1178,14 @@ fn vcpu_run() {
                 vm.set_vm_status(axvm::VMStatus::Stopped);
                 info!("VM[{}] state changed to Stopped", vm_id);
 
-                sub_running_vm_count(1);
-                ax_wait_queue_wake(&super::VMM, 1);
+                super::ivc::teardown_vm_channels(vm_id);
+                #[cfg(target_arch = "aarch64")]
+                super::psci::teardown_vm_psci(vm_id);
+
+                if !super::watchdog::on_vm_stopped(&vm) && !super::reboot::on_vm_stopped(&vm) {
+                    sub_running_vm_count(1);
+                    ax_wait_queue_wake(&super::VMM, 1);
+                }
             }
 
             break;
@@ -0,0 +1,182 @@
+//! Emulated CLINT.
+//!
+//! The machine software interrupt (`msip`) and timer compare (`mtimecmp`) registers of hart `n`
+//! drive the VS-level software and timer interrupt lines of vCPU `n`. `mtime` counts at the
+//! configured timebase frequency from the host monotonic clock and is read-only. A compare value
+//! in the future arms a host timer, which raises the timer line when it expires unless the compare
+//! value has been written again since.
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::GuestPhysAddr;
+use axaddrspace::device::AccessWidth;
+use axerrno::{AxResult, ax_err};
+use spin::Mutex;
+
+use super::{HVIP_VSSIP, HVIP_VSTIP, VCpuLines};
+use crate::vmm::VMRef;
+use crate::vmm::mmio::MmioTrapHandler;
+use crate::vmm::timer;
+
+/// Size of the register window of the CLINT.
+pub const CLINT_SIZE: usize = 0x1_0000;
+
+const MSIP_BASE: usize = 0x0000;
+const MTIMECMP_BASE: usize = 0x4000;
+const MTIME: usize = 0xbff8;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+struct HartTimer {
+    mtimecmp: u64,
+    /// Bumped on every write of `mtimecmp`, so that timers armed for a stale value are ignored.
+    generation: u64,
+}
+
+/// A CLINT emulated for one VM.
+pub struct VClint {
+    /// The CLINT itself, handed out to the timers it arms.
+    this: Weak<VClint>,
+    base: GuestPhysAddr,
+    timebase_freq: u64,
+    lines: Arc<VCpuLines>,
+    timers: Mutex<Vec<HartTimer>>,
+}
+
+impl VClint {
+    pub(super) fn new(base: GuestPhysAddr, timebase_freq: u64, lines: Arc<VCpuLines>) -> Arc<Self> {
+        let timers = (0..lines.num_vcpus())
+            .map(|_| HartTimer {
+                mtimecmp: u64::MAX,
+                generation: 0,
+            })
+            .collect();
+        Arc::new_cyclic(|this| Self {
+            this: this.clone(),
+            base,
+            timebase_freq,
+            lines,
+            timers: Mutex::new(timers),
+        })
+    }
+
+    /// The hart whose `mtimecmp` is at `offset`, if any.
+    fn mtimecmp_hart(&self, offset: usize) -> Option<usize> {
+        offset
+            .checked_sub(MTIMECMP_BASE)
+            .map(|off| off / 8)
+            .filter(|hart| *hart < self.lines.num_vcpus() && offset < MTIME)
+    }
+
+    fn mtime(&self) -> u64 {
+        let nanos = axhal::time::monotonic_time_nanos() as u128;
+        (nanos * self.timebase_freq as u128 / NANOS_PER_SEC) as u64
+    }
+
+    /// Host monotonic time in nanoseconds at which `mtime` reaches `ticks`.
+    fn ticks_to_nanos(&self, ticks: u64) -> u64 {
+        (ticks as u128 * NANOS_PER_SEC / self.timebase_freq as u128).min(u64::MAX as u128) as u64
+    }
+
+    fn set_mtimecmp(&self, hart: usize, mtimecmp: u64) {
+        let generation = {
+            let mut timers = self.timers.lock();
+            let timer = &mut timers[hart];
+            timer.mtimecmp = mtimecmp;
+            timer.generation += 1;
+            timer.generation
+        };
+        if mtimecmp <= self.mtime() {
+            self.lines.set(hart, HVIP_VSTIP, true);
+            return;
+        }
+        self.lines.set(hart, HVIP_VSTIP, false);
+        let clint = self.this.clone();
+        timer::register_timer(self.ticks_to_nanos(mtimecmp), move |_| {
+            Self::expire(clint, hart, generation)
+        });
+    }
+
+    fn expire(clint: Weak<Self>, hart: usize, generation: u64) {
+        let Some(clint) = clint.upgrade() else {
+            return;
+        };
+        if clint.timers.lock()[hart].generation == generation {
+            clint.lines.set(hart, HVIP_VSTIP, true);
+        }
+    }
+
+    /// Disarms the timers of all harts, called when the VM is destroyed.
+    pub(super) fn stop(&self) {
+        for timer in self.timers.lock().iter_mut() {
+            timer.generation += 1;
+        }
+    }
+
+    fn read(&self, offset: usize, width: AccessWidth) -> AxResult<usize> {
+        let val = match offset {
+            MSIP_BASE..MTIMECMP_BASE => {
+                (self.lines.get((offset - MSIP_BASE) / 4) & HVIP_VSSIP != 0) as u64
+            }
+            MTIME..=0xbfff => self.mtime(),
+            _ => match self.mtimecmp_hart(offset) {
+                Some(hart) => self.timers.lock()[hart].mtimecmp,
+                None => 0,
+            },
+        };
+        // 32-bit accesses to either half of a 64-bit register.
+        Ok(match width {
+            AccessWidth::Qword => val as usize,
+            _ if offset % 8 == 4 && offset >= MTIMECMP_BASE => (val >> 32) as u32 as usize,
+            _ => val as u32 as usize,
+        })
+    }
+
+    fn write(&self, offset: usize, width: AccessWidth, val: usize) {
+        match offset {
+            MSIP_BASE..MTIMECMP_BASE => {
+                let hart = (offset - MSIP_BASE) / 4;
+                self.lines.set(hart, HVIP_VSSIP, val & 1 != 0);
+            }
+            // `mtime` is read-only.
+            MTIME..=0xbfff => {}
+            _ => {
+                let Some(hart) = self.mtimecmp_hart(offset) else {
+                    return;
+                };
+                let old = self.timers.lock()[hart].mtimecmp;
+                let new = match width {
+                    AccessWidth::Qword => val as u64,
+                    _ if offset % 8 == 4 => (old & 0xffff_ffff) | (val as u64) << 32,
+                    _ => (old & !0xffff_ffff) | val as u32 as u64,
+                };
+                self.set_mtimecmp(hart, new);
+            }
+        }
+    }
+}
+
+impl MmioTrapHandler for VClint {
+    fn handle_read(&self, _vm: &VMRef, addr: GuestPhysAddr, width: AccessWidth) -> AxResult<usize> {
+        if matches!(width, AccessWidth::Byte | AccessWidth::Word) {
+            return ax_err!(InvalidInput, "CLINT registers are 32 or 64-bit");
+        }
+        self.read(addr.as_usize() - self.base.as_usize(), width)
+    }
+
+    fn handle_write(
+        &self,
+        _vm: &VMRef,
+        addr: GuestPhysAddr,
+        width: AccessWidth,
+        val: usize,
+    ) -> AxResult {
+        if matches!(width, AccessWidth::Byte | AccessWidth::Word) {
+            return ax_err!(InvalidInput, "CLINT registers are 32 or 64-bit");
+        }
+        self.write(addr.as_usize() - self.base.as_usize(), width, val);
+        Ok(())
+    }
+}
@@ -0,0 +1,364 @@
+//! Virtual interrupt controllers of riscv64 guests.
+//!
+//! Guests see the interrupt controllers declared in the `[vintc]` section of their VM config:
+//!
+//! ```toml
+//! [vintc]
+//! # Guest physical base of an emulated PLIC, none if omitted.
+//! plic_base = 0x0c00_0000
+//! # Number of interrupt sources of the PLIC, defaults to 96.
+//! plic_sources = 96
+//! # Guest physical base of an emulated CLINT, none if omitted.
+//! clint_base = 0x0200_0000
+//! # Frequency of the CLINT `mtime` counter, defaults to 10 MHz.
+//! timebase_frequency = 10_000_000
+//! # Guest physical base of the S-level IMSIC interrupt files, one page per vCPU.
+//! imsic_base = 0x2800_0000
+//! # Host physical addresses of the IMSIC guest interrupt files backing them, in vCPU order.
+//! imsic_guest_files = [0x2800_1000, 0x2800_3000]
+//! ```
+//!
+//! The emulated PLIC and CLINT ("classic" guests) are MMIO traps whose outputs are the VS-level
+//! external, software and timer interrupt lines of each vCPU. Lines may change from any context, a
+//! device completing a request on a worker task or a host timer, so they are only recorded here
+//! and written to `hvip` by the vCPU task itself before it enters the guest, see
+//! [`deliver_pending`]. The devices emulated by the hypervisor raise their [`IrqLine`] as PLIC
+//! sources when the VM has a PLIC.
+//!
+//! With AIA, guest interrupt files of the host IMSICs are mapped into the guest instead, so that
+//! MSIs (from passthrough devices whose `host_msi_addr` targets a guest file, or IPIs written by the
+//! guest itself) are delivered by the hardware without exiting to the hypervisor. Selecting the
+//! guest file of a vCPU (`hstatus.VGEIN`) when it is scheduled in is up to the vCPU backend.
+//!
+//! The interrupts of the hypervisor itself (IVC notifications, emulated devices, interrupts posted
+//! to a vCPU) are delivered as follows, so that the interrupt numbers of the VM config mean the
+//! same whatever the architecture of the guest:
+//!
+//! - with a PLIC, an [`IrqLine`] sets its source pending;
+//! - otherwise, with IMSIC guest files, the interrupt number is the identity written to the guest
+//!   file of the vCPU, as an MSI would be, see [`inject`];
+//! - otherwise, only the supervisor software interrupt (1) can be injected, through `hvip`.
+//!
+//! [`IrqLine`]: crate::vmm::irq::IrqLine
+mod clint;
+mod plic;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::{GuestPhysAddr, HostPhysAddr, MappingFlags};
+use axerrno::{AxResult, ax_err, ax_err_type};
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::hal::arch::{self, IRQ_S_EXT, IRQ_S_SOFT, IRQ_S_TIMER, set_vs_interrupts};
+use crate::vmm::{VCpuRef, VMRef, mmio, posted, stage2};
+
+use clint::VClint;
+use plic::VPlic;
+
+const DEFAULT_PLIC_SOURCES: usize = 96;
+const DEFAULT_TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+/// Offset of `seteipnum_le` in an IMSIC interrupt file, writing an identity there makes it pending.
+const IMSIC_SETEIPNUM_LE: usize = 0x0;
+/// Interrupt identities are 1 to 2047 at most, fewer may be implemented by the IMSIC.
+const IMSIC_MAX_IDENTITY: usize = 2047;
+
+/// `hvip` bits of the VS-level interrupts, one bit above their supervisor-level causes.
+const HVIP_VSSIP: usize = 1 << (IRQ_S_SOFT + 1);
+const HVIP_VSTIP: usize = 1 << (IRQ_S_TIMER + 1);
+const HVIP_VSEIP: usize = 1 << (IRQ_S_EXT + 1);
+
+/// The VS-level interrupt lines of the vCPUs of a VM, as `hvip` bits.
+struct VCpuLines {
+    vm_id: usize,
+    lines: Vec<AtomicUsize>,
+}
+
+impl VCpuLines {
+    fn new(vm_id: usize, num_vcpus: usize) -> Self {
+        Self {
+            vm_id,
+            lines: (0..num_vcpus).map(|_| AtomicUsize::new(0)).collect(),
+        }
+    }
+
+    fn num_vcpus(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Sets the line `bit` of vCPU `vcpu_id` to `level`, notifying the vCPU if it is raised.
+    fn set(&self, vcpu_id: usize, bit: usize, level: bool) {
+        let Some(line) = self.lines.get(vcpu_id) else {
+            return;
+        };
+        let prev = if level {
+            line.fetch_or(bit, Ordering::AcqRel)
+        } else {
+            line.fetch_and(!bit, Ordering::AcqRel)
+        };
+        if level && prev & bit == 0 {
+            posted::kick(self.vm_id, vcpu_id);
+        }
+    }
+
+    fn get(&self, vcpu_id: usize) -> usize {
+        self.lines
+            .get(vcpu_id)
+            .map_or(0, |line| line.load(Ordering::Acquire))
+    }
+}
+
+struct VmIntc {
+    plic: Option<Arc<VPlic>>,
+    clint: Option<Arc<VClint>>,
+    lines: Arc<VCpuLines>,
+    /// `hvip` bits driven by the emulated controllers.
+    mask: usize,
+    /// Host physical addresses of the IMSIC guest files of the vCPUs, if mapped.
+    imsic_files: Vec<HostPhysAddr>,
+}
+
+static INTCS: Mutex<BTreeMap<usize, VmIntc>> = Mutex::new(BTreeMap::new());
+/// Whether any VM has an emulated interrupt controller, checked without the lock on every guest
+/// entry.
+static HAS_INTCS: AtomicBool = AtomicBool::new(false);
+
+fn get_usize(table: &toml::Table, key: &str) -> Option<usize> {
+    table
+        .get(key)
+        .and_then(|v| v.as_integer())
+        .map(|v| v as usize)
+}
+
+/// Creates the interrupt controllers described in the `[vintc]` section of `raw_cfg` for the VM.
+pub fn setup_vm_intc(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("vintc").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let vm_id = vm.id();
+    let lines = Arc::new(VCpuLines::new(vm_id, vm.vcpu_num()));
+    let mut mask = 0;
+
+    let plic = match get_usize(cfg, "plic_base") {
+        Some(base) => {
+            let num_sources = get_usize(cfg, "plic_sources").unwrap_or(DEFAULT_PLIC_SOURCES);
+            let plic = Arc::new(VPlic::new(base.into(), num_sources, lines.clone())?);
+            mmio::register_trap(vm_id, base.into(), plic.size(), plic.clone())?;
+            mask |= HVIP_VSEIP;
+            info!(
+                "VM[{}] PLIC at {:#x} with {} sources",
+                vm_id, base, num_sources
+            );
+            Some(plic)
+        }
+        None => None,
+    };
+
+    let clint = match get_usize(cfg, "clint_base") {
+        Some(base) => {
+            let freq = get_usize(cfg, "timebase_frequency")
+                .map_or(DEFAULT_TIMEBASE_FREQUENCY, |f| f as u64);
+            if freq == 0 {
+                return ax_err!(InvalidInput, "vintc config: `timebase_frequency` is 0");
+            }
+            let clint = VClint::new(base.into(), freq, lines.clone());
+            mmio::register_trap(vm_id, base.into(), clint::CLINT_SIZE, clint.clone())?;
+            mask |= HVIP_VSSIP | HVIP_VSTIP;
+            info!("VM[{}] CLINT at {:#x}, timebase {} Hz", vm_id, base, freq);
+            Some(clint)
+        }
+        None => None,
+    };
+
+    let imsic_files = match get_usize(cfg, "imsic_base") {
+        Some(imsic_base) => map_imsic_files(vm, imsic_base, cfg)?,
+        None => Vec::new(),
+    };
+
+    if mask != 0 || !imsic_files.is_empty() {
+        INTCS.lock().insert(
+            vm_id,
+            VmIntc {
+                plic,
+                clint,
+                lines,
+                mask,
+                imsic_files,
+            },
+        );
+        HAS_INTCS.store(true, Ordering::Release);
+    }
+    Ok(())
+}
+
+/// Maps the IMSIC guest interrupt files listed in `imsic_guest_files` at `imsic_base`, returns
+/// them in vCPU order.
+fn map_imsic_files(
+    vm: &VMRef,
+    imsic_base: usize,
+    cfg: &toml::Table,
+) -> AxResult<Vec<HostPhysAddr>> {
+    let files = cfg
+        .get("imsic_guest_files")
+        .and_then(|v| v.as_array())
+        .ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                "vintc config: `imsic_base` without `imsic_guest_files`"
+            )
+        })?;
+    if files.len() != vm.vcpu_num() {
+        return ax_err!(
+            InvalidInput,
+            format!(
+                "vintc config: {} IMSIC guest files for {} vCPUs",
+                files.len(),
+                vm.vcpu_num()
+            )
+        );
+    }
+    let mut hpas = Vec::with_capacity(files.len());
+    for (vcpu_id, file) in files.iter().enumerate() {
+        let hpa = file
+            .as_integer()
+            .map(|v| v as usize)
+            .filter(|hpa| hpa % PAGE_SIZE_4K == 0)
+            .ok_or_else(|| {
+                ax_err_type!(
+                    InvalidInput,
+                    "vintc config: IMSIC guest files must be page-aligned addresses"
+                )
+            })?;
+        let gpa = imsic_base + vcpu_id * PAGE_SIZE_4K;
+        stage2::map_region(
+            vm,
+            GuestPhysAddr::from(gpa),
+            HostPhysAddr::from(hpa),
+            PAGE_SIZE_4K,
+            MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+        )?;
+        info!(
+            "VM[{}] VCpu[{}] IMSIC file at {:#x} -> guest file {:#x}",
+            vm.id(),
+            vcpu_id,
+            gpa,
+            hpa
+        );
+        hpas.push(HostPhysAddr::from(hpa));
+    }
+    Ok(hpas)
+}
+
+/// Removes the interrupt controllers of a VM, called when the VM is destroyed.
+pub fn teardown_vm_intc(vm_id: usize) {
+    let mut intcs = INTCS.lock();
+    if let Some(intc) = intcs.remove(&vm_id)
+        && let Some(clint) = intc.clint
+    {
+        clint.stop();
+    }
+    HAS_INTCS.store(!intcs.is_empty(), Ordering::Release);
+}
+
+/// Raises the PLIC source `irq` of VM `vm_id`, or sends it to the IMSIC guest file of vCPU 0
+/// without a PLIC. Returns `false` if the VM has neither.
+pub fn raise(vm_id: usize, irq: usize) -> bool {
+    if !HAS_INTCS.load(Ordering::Acquire) {
+        return false;
+    }
+    let (plic, imsic_file) = match INTCS.lock().get(&vm_id) {
+        Some(intc) => (intc.plic.clone(), intc.imsic_files.first().copied()),
+        None => return false,
+    };
+    if let Some(plic) = plic {
+        plic.set_pending(irq);
+        return true;
+    }
+    match imsic_file {
+        Some(file) => {
+            if let Err(e) = send_msi(file, irq) {
+                trace!("VM[{}] irq {} dropped: {:?}", vm_id, irq, e);
+            }
+            // Delivered by the hardware if the vCPU is in the guest, woken up otherwise.
+            posted::kick(vm_id, 0);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Makes the interrupt identity `irq` pending in the IMSIC guest interrupt file at `file`.
+fn send_msi(file: HostPhysAddr, irq: usize) -> AxResult {
+    if irq == 0 || irq > IMSIC_MAX_IDENTITY {
+        return ax_err!(
+            InvalidInput,
+            format!("{} is not an IMSIC interrupt identity", irq)
+        );
+    }
+    let reg = axhal::mem::phys_to_virt(file).as_usize() + IMSIC_SETEIPNUM_LE;
+    // SAFETY: the guest file was mapped into the guest as a device page of the host, the register
+    // only latches the identity written to it.
+    unsafe { (reg as *mut u32).write_volatile(irq as u32) };
+    Ok(())
+}
+
+/// Injects the interrupt `irq` posted to `vcpu` of VM `vm_id`, called by the vCPU task before it
+/// enters the guest.
+///
+/// `irq` goes to the IMSIC guest file of the vCPU if the VM has them. Otherwise it can only be the
+/// supervisor software interrupt, which the guest acknowledges by clearing `sip.SSIP`, or the
+/// `msip` of its hart with a CLINT: the external and timer interrupts of `hvip` are levels the
+/// guest can't clear.
+pub fn inject(vm_id: usize, vcpu: &VCpuRef, irq: usize) -> AxResult {
+    let (file, lines) = match HAS_INTCS.load(Ordering::Acquire) {
+        true => match INTCS.lock().get(&vm_id) {
+            Some(intc) => (
+                intc.imsic_files.get(vcpu.id()).copied(),
+                (intc.mask & HVIP_VSSIP != 0).then(|| intc.lines.clone()),
+            ),
+            None => (None, None),
+        },
+        false => (None, None),
+    };
+    match (file, irq) {
+        (Some(file), _) => send_msi(file, irq),
+        (None, IRQ_S_SOFT) => {
+            // Kept by the CLINT line too, or the next entry would clear it.
+            if let Some(lines) = lines {
+                lines.set(vcpu.id(), HVIP_VSSIP, true);
+            }
+            arch::inject_interrupt(irq);
+            Ok(())
+        }
+        (None, _) => ax_err!(
+            Unsupported,
+            format!(
+                "VM[{}] has no PLIC or IMSIC to inject interrupt {} with",
+                vm_id, irq
+            )
+        ),
+    }
+}
+
+/// Writes the interrupt lines of `vcpu` of VM `vm_id` to `hvip`, called by the vCPU task before
+/// it enters the guest.
+#[inline]
+pub fn deliver_pending(vm_id: usize, vcpu: &VCpuRef) {
+    if !HAS_INTCS.load(Ordering::Acquire) {
+        return;
+    }
+    let Some((lines, mask)) = INTCS
+        .lock()
+        .get(&vm_id)
+        .map(|intc| (intc.lines.clone(), intc.mask))
+    else {
+        return;
+    };
+    set_vs_interrupts(mask, lines.get(vcpu.id()));
+}
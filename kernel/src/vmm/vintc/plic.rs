@@ -0,0 +1,253 @@
+//! Emulated PLIC.
+//!
+//! Each vCPU has two contexts, as on the QEMU `virt` machine: context `2 * n` is the M-mode
+//! context of hart `n`, which guests never use, and context `2 * n + 1` its S-mode context, which
+//! drives the VS-level external interrupt line of vCPU `n`. Sources behave as edge-triggered: a
+//! raised source stays pending until it is claimed, and is not signaled again before the claim
+//! is completed.
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use axaddrspace::GuestPhysAddr;
+use axaddrspace::device::AccessWidth;
+use axerrno::{AxResult, ax_err};
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use super::{HVIP_VSEIP, VCpuLines};
+use crate::vmm::VMRef;
+use crate::vmm::mmio::MmioTrapHandler;
+
+/// Largest number of interrupt sources, including the reserved source 0.
+const MAX_SOURCES: usize = 1024;
+
+const PRIORITY_BASE: usize = 0x00_0000;
+const PENDING_BASE: usize = 0x00_1000;
+const ENABLE_BASE: usize = 0x00_2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0x0;
+const CONTEXT_CLAIM: usize = 0x4;
+
+#[derive(Default)]
+struct Context {
+    /// Bitmap of the enabled sources.
+    enable: Vec<u32>,
+    threshold: u32,
+}
+
+struct PlicState {
+    priority: Vec<u32>,
+    /// Bitmap of the pending sources.
+    pending: Vec<u32>,
+    /// Bitmap of the claimed sources whose completion has not been signaled yet.
+    claimed: Vec<u32>,
+    contexts: Vec<Context>,
+}
+
+fn test_bit(bitmap: &[u32], bit: usize) -> bool {
+    bitmap[bit / 32] & (1 << (bit % 32)) != 0
+}
+
+fn set_bit(bitmap: &mut [u32], bit: usize, val: bool) {
+    if val {
+        bitmap[bit / 32] |= 1 << (bit % 32);
+    } else {
+        bitmap[bit / 32] &= !(1 << (bit % 32));
+    }
+}
+
+impl PlicState {
+    /// The highest-priority pending source context `ctx` should take, lowest ID first on ties.
+    fn best_source(&self, ctx: usize) -> Option<usize> {
+        let context = &self.contexts[ctx];
+        (1..self.priority.len())
+            .filter(|src| {
+                test_bit(&self.pending, *src)
+                    && !test_bit(&self.claimed, *src)
+                    && test_bit(&context.enable, *src)
+                    && self.priority[*src] > context.threshold
+            })
+            .fold(None, |best: Option<usize>, src| match best {
+                Some(b) if self.priority[b] >= self.priority[src] => Some(b),
+                _ => Some(src),
+            })
+    }
+}
+
+/// A PLIC emulated for one VM.
+pub struct VPlic {
+    base: GuestPhysAddr,
+    lines: Arc<VCpuLines>,
+    state: Mutex<PlicState>,
+}
+
+impl VPlic {
+    pub(super) fn new(
+        base: GuestPhysAddr,
+        num_sources: usize,
+        lines: Arc<VCpuLines>,
+    ) -> AxResult<Self> {
+        // Source 0 does not exist.
+        let num_sources = num_sources + 1;
+        if num_sources > MAX_SOURCES {
+            return ax_err!(
+                InvalidInput,
+                format!("vintc config: PLIC has at most {} sources", MAX_SOURCES - 1)
+            );
+        }
+        let words = num_sources.div_ceil(32);
+        let contexts = (0..lines.num_vcpus() * 2)
+            .map(|_| Context {
+                enable: vec![0; words],
+                threshold: 0,
+            })
+            .collect();
+        Ok(Self {
+            base,
+            lines,
+            state: Mutex::new(PlicState {
+                priority: vec![0; num_sources],
+                pending: vec![0; words],
+                claimed: vec![0; words],
+                contexts,
+            }),
+        })
+    }
+
+    /// Size of the register window of the PLIC.
+    pub(super) fn size(&self) -> usize {
+        (CONTEXT_BASE + self.lines.num_vcpus() * 2 * CONTEXT_STRIDE).next_multiple_of(PAGE_SIZE_4K)
+    }
+
+    /// Marks source `irq` pending.
+    pub(super) fn set_pending(&self, irq: usize) {
+        let mut state = self.state.lock();
+        if irq == 0 || irq >= state.priority.len() {
+            warn!("VM[{}] PLIC source {} out of range", self.lines.vm_id, irq);
+            return;
+        }
+        set_bit(&mut state.pending, irq, true);
+        self.update(&state);
+    }
+
+    /// Recomputes the external interrupt line of every vCPU.
+    fn update(&self, state: &PlicState) {
+        for vcpu_id in 0..self.lines.num_vcpus() {
+            let level = state.best_source(vcpu_id * 2 + 1).is_some();
+            self.lines.set(vcpu_id, HVIP_VSEIP, level);
+        }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        let mut state = self.state.lock();
+        let words = state.pending.len();
+        match offset {
+            PRIORITY_BASE..PENDING_BASE => state
+                .priority
+                .get((offset - PRIORITY_BASE) / 4)
+                .copied()
+                .unwrap_or(0),
+            PENDING_BASE..ENABLE_BASE => state
+                .pending
+                .get((offset - PENDING_BASE) / 4)
+                .copied()
+                .unwrap_or(0),
+            ENABLE_BASE..CONTEXT_BASE => {
+                let ctx = (offset - ENABLE_BASE) / ENABLE_STRIDE;
+                let word = (offset - ENABLE_BASE) % ENABLE_STRIDE / 4;
+                match state.contexts.get(ctx) {
+                    Some(context) if word < words => context.enable[word],
+                    _ => 0,
+                }
+            }
+            _ => {
+                let ctx = (offset - CONTEXT_BASE) / CONTEXT_STRIDE;
+                if ctx >= state.contexts.len() {
+                    return 0;
+                }
+                match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                    CONTEXT_THRESHOLD => state.contexts[ctx].threshold,
+                    CONTEXT_CLAIM => {
+                        let Some(src) = state.best_source(ctx) else {
+                            return 0;
+                        };
+                        set_bit(&mut state.pending, src, false);
+                        set_bit(&mut state.claimed, src, true);
+                        self.update(&state);
+                        src as u32
+                    }
+                    _ => 0,
+                }
+            }
+        }
+    }
+
+    fn write(&self, offset: usize, val: u32) {
+        let mut state = self.state.lock();
+        let words = state.pending.len();
+        match offset {
+            PRIORITY_BASE..PENDING_BASE => {
+                if let Some(priority) = state.priority.get_mut((offset - PRIORITY_BASE) / 4) {
+                    *priority = val;
+                }
+            }
+            // Pending bits are read-only.
+            PENDING_BASE..ENABLE_BASE => return,
+            ENABLE_BASE..CONTEXT_BASE => {
+                let ctx = (offset - ENABLE_BASE) / ENABLE_STRIDE;
+                let word = (offset - ENABLE_BASE) % ENABLE_STRIDE / 4;
+                match state.contexts.get_mut(ctx) {
+                    Some(context) if word < words => context.enable[word] = val,
+                    _ => return,
+                }
+            }
+            _ => {
+                let ctx = (offset - CONTEXT_BASE) / CONTEXT_STRIDE;
+                if ctx >= state.contexts.len() {
+                    return;
+                }
+                match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                    CONTEXT_THRESHOLD => state.contexts[ctx].threshold = val,
+                    CONTEXT_CLAIM => {
+                        // Completion, ignored for sources the context does not have enabled.
+                        let src = val as usize;
+                        if src == 0
+                            || src >= state.priority.len()
+                            || !test_bit(&state.contexts[ctx].enable, src)
+                        {
+                            return;
+                        }
+                        set_bit(&mut state.claimed, src, false);
+                    }
+                    _ => return,
+                }
+            }
+        }
+        self.update(&state);
+    }
+}
+
+impl MmioTrapHandler for VPlic {
+    fn handle_read(&self, _vm: &VMRef, addr: GuestPhysAddr, width: AccessWidth) -> AxResult<usize> {
+        if !matches!(width, AccessWidth::Dword) {
+            return ax_err!(InvalidInput, "PLIC registers are 32-bit");
+        }
+        Ok(self.read(addr.as_usize() - self.base.as_usize()) as usize)
+    }
+
+    fn handle_write(
+        &self,
+        _vm: &VMRef,
+        addr: GuestPhysAddr,
+        width: AccessWidth,
+        val: usize,
+    ) -> AxResult {
+        if !matches!(width, AccessWidth::Dword) {
+            return ax_err!(InvalidInput, "PLIC registers are 32-bit");
+        }
+        self.write(addr.as_usize() - self.base.as_usize(), val as u32);
+        Ok(())
+    }
+}
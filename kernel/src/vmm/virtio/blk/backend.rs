@@ -0,0 +1,315 @@
+//! Storage virtio-blk devices are backed by, and the image formats of backing files.
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err};
+
+use crate::vmm::memstat::{self, MemSubsystem};
+use crate::vmm::security::{self, ScrubMode};
+
+/// Storage a virtio-blk device is backed by.
+pub(super) trait BlockBackend: Send {
+    /// Size of the storage in bytes.
+    fn capacity(&self) -> u64;
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> AxResult;
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> AxResult;
+
+    /// Makes previous writes durable.
+    fn flush(&mut self) -> AxResult {
+        Ok(())
+    }
+}
+
+/// A ramdisk of VM `vm_id`, charged to it.
+pub(super) struct RamDisk {
+    vm_id: usize,
+    data: Vec<u8>,
+    /// How the ramdisk is scrubbed when freed, possibly after the VM is destroyed.
+    scrub: ScrubMode,
+}
+
+impl RamDisk {
+    pub fn new(vm_id: usize, size: usize) -> AxResult<Self> {
+        memstat::try_charge(MemSubsystem::Devices, vm_id, size)?;
+        Ok(Self {
+            vm_id,
+            data: vec![0u8; size],
+            scrub: security::scrub_mode(vm_id),
+        })
+    }
+}
+
+impl Drop for RamDisk {
+    fn drop(&mut self) {
+        self.scrub.scrub(&mut self.data);
+        memstat::uncharge(MemSubsystem::Devices, Some(self.vm_id), self.data.len());
+    }
+}
+
+impl BlockBackend for RamDisk {
+    fn capacity(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> AxResult {
+        let offset = offset as usize;
+        buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> AxResult {
+        let offset = offset as usize;
+        self.data[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "fs")]
+pub(super) use file::{BackingImage, FileDisk, ImageFormat, open_image};
+
+#[cfg(feature = "fs")]
+mod file {
+    use alloc::boxed::Box;
+    use alloc::collections::BTreeMap;
+    use alloc::string::{String, ToString};
+    use alloc::sync::{Arc, Weak};
+
+    use axerrno::{AxResult, ax_err, ax_err_type};
+    use spin::Mutex;
+
+    use super::super::qcow2::{QCOW2_MAGIC, Qcow2Image};
+    use super::BlockBackend;
+
+    /// A file of the host filesystem, holding the storage as is.
+    pub struct FileDisk {
+        file: std::fs::File,
+        size: u64,
+    }
+
+    impl FileDisk {
+        pub fn open(path: &str, read_only: bool) -> AxResult<Self> {
+            use std::io::{Seek, SeekFrom};
+
+            let mut file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(!read_only)
+                .open(path)
+                .map_err(|err| {
+                    ax_err_type!(
+                        NotFound,
+                        format!("Failed to open disk image {}, err {:?}", path, err)
+                    )
+                })?;
+            let size = file.seek(SeekFrom::End(0)).map_err(|err| {
+                ax_err_type!(Io, format!("Failed to seek {}, err {:?}", path, err))
+            })?;
+            Ok(Self { file, size })
+        }
+
+        /// Creates the file `path`, which must not exist.
+        pub fn create(path: &str) -> AxResult<Self> {
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .map_err(|err| {
+                    ax_err_type!(
+                        Io,
+                        format!("Failed to create disk image {}, err {:?}", path, err)
+                    )
+                })?;
+            Ok(Self { file, size: 0 })
+        }
+    }
+
+    impl BlockBackend for FileDisk {
+        fn capacity(&self) -> u64 {
+            self.size
+        }
+
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> AxResult {
+            use std::io::{Read, Seek, SeekFrom};
+
+            self.file
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| self.file.read_exact(buf))
+                .map_err(|err| ax_err_type!(Io, format!("disk read failed, err {:?}", err)))
+        }
+
+        fn write_at(&mut self, offset: u64, data: &[u8]) -> AxResult {
+            use std::io::{Seek, SeekFrom, Write};
+
+            self.file
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| self.file.write_all(data))
+                .map_err(|err| ax_err_type!(Io, format!("disk write failed, err {:?}", err)))?;
+            self.size = self.size.max(offset + data.len() as u64);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> AxResult {
+            use std::io::Write;
+
+            self.file
+                .flush()
+                .map_err(|err| ax_err_type!(Io, format!("disk flush failed, err {:?}", err)))
+        }
+    }
+
+    /// Format of an image file.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ImageFormat {
+        Raw,
+        Qcow2,
+    }
+
+    impl ImageFormat {
+        pub fn from_name(name: &str) -> Option<Self> {
+            match name {
+                "raw" => Some(Self::Raw),
+                "qcow2" => Some(Self::Qcow2),
+                _ => None,
+            }
+        }
+
+        /// Detects the format of the image in `file` from its magic number.
+        pub fn probe(file: &mut FileDisk) -> Self {
+            let mut magic = [0u8; 4];
+            if file.capacity() >= 4
+                && file.read_at(0, &mut magic).is_ok()
+                && u32::from_be_bytes(magic) == QCOW2_MAGIC
+            {
+                Self::Qcow2
+            } else {
+                Self::Raw
+            }
+        }
+    }
+
+    /// A read-only image shared by the images layered on it.
+    type SharedImage = Arc<Mutex<Box<dyn BlockBackend>>>;
+
+    /// Backing images opened by other images, indexed by path, so that the guests sharing a base
+    /// image share its file and metadata caches.
+    static BACKING_IMAGES: Mutex<BTreeMap<String, Weak<Mutex<Box<dyn BlockBackend>>>>> =
+        Mutex::new(BTreeMap::new());
+
+    /// The read-only backing image of another image.
+    pub struct BackingImage(SharedImage);
+
+    impl BackingImage {
+        /// Opens the backing image `path`, or shares it if already open. Its caches are shared
+        /// too and not charged to a VM.
+        pub fn open(path: &str, format: Option<ImageFormat>) -> AxResult<Self> {
+            if let Some(image) = BACKING_IMAGES.lock().get(path).and_then(Weak::upgrade) {
+                return Ok(Self(image));
+            }
+            // Not locked while opening, the image may have a backing image of its own.
+            let image: SharedImage = Arc::new(Mutex::new(open_image(None, path, format, true)?));
+            let mut images = BACKING_IMAGES.lock();
+            if let Some(opened) = images.get(path).and_then(Weak::upgrade) {
+                return Ok(Self(opened));
+            }
+            images.retain(|_, image| image.strong_count() > 0);
+            images.insert(path.to_string(), Arc::downgrade(&image));
+            Ok(Self(image))
+        }
+    }
+
+    impl BlockBackend for BackingImage {
+        fn capacity(&self) -> u64 {
+            self.0.lock().capacity()
+        }
+
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> AxResult {
+            self.0.lock().read_at(offset, buf)
+        }
+
+        fn write_at(&mut self, _offset: u64, _data: &[u8]) -> AxResult {
+            ax_err!(PermissionDenied, "backing images are read-only")
+        }
+    }
+
+    /// Opens the image file `path`, of the format `format` or of the detected one. Its caches are
+    /// charged to VM `vm_id`, if any.
+    pub fn open_image(
+        vm_id: Option<usize>,
+        path: &str,
+        format: Option<ImageFormat>,
+        read_only: bool,
+    ) -> AxResult<Box<dyn BlockBackend>> {
+        let mut file = FileDisk::open(path, read_only)?;
+        match format.unwrap_or_else(|| ImageFormat::probe(&mut file)) {
+            ImageFormat::Raw => Ok(Box::new(file)),
+            ImageFormat::Qcow2 => Ok(Box::new(Qcow2Image::open(vm_id, path, file, read_only)?)),
+        }
+    }
+}
+
+/// Opens the storage described by the `[[virtio_blk]]` entry `entry` of VM `vm_id`.
+#[cfg_attr(not(feature = "fs"), allow(unused_variables))]
+pub(super) fn open_backend(
+    vm_id: usize,
+    entry: &toml::Value,
+    read_only: bool,
+) -> AxResult<Box<dyn BlockBackend>> {
+    let path = entry.get("path").and_then(|v| v.as_str());
+    let ramdisk_size = entry.get("ramdisk_size").and_then(|v| v.as_integer());
+    match (path, ramdisk_size) {
+        #[cfg(feature = "fs")]
+        (Some(path), _) => open_file_backend(vm_id, path, entry, read_only),
+        #[cfg(not(feature = "fs"))]
+        (Some(path), _) => ax_err!(
+            Unsupported,
+            format!(
+                "virtio_blk config: `path` {} requires the `fs` feature",
+                path
+            )
+        ),
+        (None, Some(size)) if size > 0 => Ok(Box::new(RamDisk::new(vm_id, size as usize)?)),
+        _ => ax_err!(
+            InvalidInput,
+            "virtio_blk config: either `path` or `ramdisk_size` is required"
+        ),
+    }
+}
+
+/// Opens the image file `path`, creating it as a qcow2 overlay of `backing` first if the entry
+/// names one and the file doesn't exist.
+#[cfg(feature = "fs")]
+fn open_file_backend(
+    vm_id: usize,
+    path: &str,
+    entry: &toml::Value,
+    read_only: bool,
+) -> AxResult<Box<dyn BlockBackend>> {
+    use axerrno::ax_err_type;
+
+    use super::qcow2::Qcow2Image;
+
+    let format = match entry.get("format").and_then(|v| v.as_str()) {
+        None => None,
+        Some(name) => Some(ImageFormat::from_name(name).ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                format!("virtio_blk config: unknown `format` {:?}", name)
+            )
+        })?),
+    };
+    if let Some(backing) = entry.get("backing").and_then(|v| v.as_str())
+        && std::fs::metadata(path).is_err()
+    {
+        if format == Some(ImageFormat::Raw) {
+            return ax_err!(
+                InvalidInput,
+                "virtio_blk config: overlays of `backing` are qcow2 images"
+            );
+        }
+        Qcow2Image::create_overlay(path, backing)?;
+        info!("VM[{}] created overlay {} of {}", vm_id, path, backing);
+    }
+    open_image(Some(vm_id), path, format, read_only)
+}
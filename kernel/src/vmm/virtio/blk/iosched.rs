@@ -0,0 +1,381 @@
+//! I/O scheduling of the virtio-blk devices.
+//!
+//! The requests of the devices go through a scheduler before reaching their backend:
+//!
+//! - The worker of a device merges the adjacent reads, or writes, found in its queue into a single
+//!   backend operation.
+//! - The requests of a VM are limited in operations and bytes per second, with bursts of up to a
+//!   second worth of either. The limits cover all the virtio-blk devices of the VM.
+//! - The devices backed by the same host storage take turns, the VMs getting shares of it
+//!   proportional to their weight: the next operation is the one of the VM that used the least
+//!   of the storage, weighted, among those waiting for it.
+//!
+//! Limits and weight are set per VM with a `[blk_io]` section, and devices name their storage:
+//!
+//! ```toml
+//! [blk_io]
+//! # Requests per second of all the virtio-blk devices of the VM, unlimited by default.
+//! iops = 2000
+//! # Bytes per second, unlimited by default.
+//! bandwidth = 100_000_000
+//! # Share of the storage against the other VMs using it, 100 by default.
+//! weight = 100
+//!
+//! [[virtio_blk]]
+//! # Host storage the device is backed by, "default" for image files and none for ramdisks.
+//! storage = "nvme0"
+//! ```
+//!
+//! The counters of each VM, see [`BlkIoStats`], are shown by `vm show --stats`.
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use std::os::arceos::modules::{axhal, axtask::WaitQueue};
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use spin::Mutex;
+
+use crate::vmm::VMRef;
+
+/// Storage of the devices backed by image files without a `storage`.
+pub(super) const DEFAULT_STORAGE: &str = "default";
+
+const DEFAULT_WEIGHT: u64 = 100;
+const MAX_WEIGHT: u64 = 10_000;
+/// Cost of an operation besides its bytes in the share of a storage, so that small requests
+/// aren't free.
+const OP_COST: u64 = 4096;
+/// Largest burst allowed by the limits of a VM.
+const BURST_NS: u64 = 1_000_000_000;
+
+/// Block I/O counters of a VM.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BlkIoStats {
+    /// Read requests.
+    pub reads: u64,
+    /// Write requests.
+    pub writes: u64,
+    /// Flush requests.
+    pub flushes: u64,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    /// Requests merged into the backend operation of a preceding one.
+    pub merged: u64,
+    /// Time requests were delayed by the limits of the VM.
+    pub throttled_ns: u64,
+    /// Time requests waited for their storage, used by other VMs.
+    pub queued_ns: u64,
+}
+
+/// A rate limit, as a generic cell rate algorithm: `tat` is the theoretical arrival time of the
+/// next operation, past the current time by the outstanding burst.
+struct RateLimit {
+    per_sec: u64,
+    tat: u64,
+}
+
+impl RateLimit {
+    /// Reserves `amount` at `now`, returns how long the caller must wait for it.
+    fn reserve(&mut self, now: u64, amount: u64) -> u64 {
+        let cost = (amount as u128 * 1_000_000_000 / self.per_sec as u128) as u64;
+        self.tat = self.tat.max(now) + cost;
+        self.tat.saturating_sub(BURST_NS).saturating_sub(now)
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    flushes: AtomicU64,
+    read_bytes: AtomicU64,
+    write_bytes: AtomicU64,
+    merged: AtomicU64,
+    throttled_ns: AtomicU64,
+    queued_ns: AtomicU64,
+}
+
+/// Limits, weight and counters of a VM.
+struct VmIo {
+    iops: Option<Mutex<RateLimit>>,
+    bandwidth: Option<Mutex<RateLimit>>,
+    weight: u64,
+    counters: Counters,
+}
+
+impl VmIo {
+    fn unlimited() -> Self {
+        Self {
+            iops: None,
+            bandwidth: None,
+            weight: DEFAULT_WEIGHT,
+            counters: Counters::default(),
+        }
+    }
+}
+
+/// I/O settings of the VMs, indexed by VM ID.
+static VM_IO: Mutex<BTreeMap<usize, Arc<VmIo>>> = Mutex::new(BTreeMap::new());
+
+#[derive(Default)]
+struct StorageVm {
+    /// Devices of the VM on the storage.
+    devices: usize,
+    /// Operations of the VM waiting for the storage.
+    waiting: usize,
+    /// Weighted usage of the storage.
+    vtime: u64,
+}
+
+#[derive(Default)]
+struct StorageState {
+    busy: bool,
+    /// Weighted usage of the last VM served, where VMs starting to wait catch up.
+    vclock: u64,
+    vms: BTreeMap<usize, StorageVm>,
+}
+
+impl StorageState {
+    /// Whether VM `vm_id` is next in line.
+    fn is_next(&self, vm_id: usize) -> bool {
+        let mine = &self.vms[&vm_id];
+        !self.busy
+            && self
+                .vms
+                .iter()
+                .filter(|(id, vm)| vm.waiting > 0 && **id != vm_id)
+                .all(|(id, vm)| (mine.vtime, vm_id) < (vm.vtime, *id))
+    }
+}
+
+/// A host storage shared by devices.
+struct Storage {
+    state: Mutex<StorageState>,
+    wait_queue: WaitQueue,
+}
+
+/// Host storages in use, indexed by name.
+static STORAGES: Mutex<BTreeMap<String, Weak<Storage>>> = Mutex::new(BTreeMap::new());
+
+impl Storage {
+    fn get(name: &str) -> Arc<Storage> {
+        let mut storages = STORAGES.lock();
+        if let Some(storage) = storages.get(name).and_then(Weak::upgrade) {
+            return storage;
+        }
+        let storage = Arc::new(Storage {
+            state: Mutex::new(StorageState::default()),
+            wait_queue: WaitQueue::new(),
+        });
+        storages.retain(|_, storage| storage.strong_count() > 0);
+        storages.insert(name.to_string(), Arc::downgrade(&storage));
+        storage
+    }
+
+    fn acquire(&self, vm_id: usize) {
+        {
+            let mut state = self.state.lock();
+            let vclock = state.vclock;
+            let vm = state.vms.entry(vm_id).or_default();
+            if vm.waiting == 0 {
+                // Idle time doesn't earn a share.
+                vm.vtime = vm.vtime.max(vclock);
+            }
+            vm.waiting += 1;
+        }
+        self.wait_queue.wait_until(|| {
+            let mut state = self.state.lock();
+            if !state.is_next(vm_id) {
+                return false;
+            }
+            state.busy = true;
+            true
+        });
+    }
+
+    fn release(&self, vm_id: usize, cost: u64, weight: u64) {
+        {
+            let mut state = self.state.lock();
+            let vm = state.vms.entry(vm_id).or_default();
+            vm.waiting -= 1;
+            let start = vm.vtime;
+            vm.vtime += cost * DEFAULT_WEIGHT / weight;
+            state.vclock = state.vclock.max(start);
+            state.busy = false;
+        }
+        self.wait_queue.notify_all(true);
+    }
+}
+
+/// Kinds of requests, for the counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum IoKind {
+    Read,
+    Write,
+    Flush,
+}
+
+/// The scheduling context of a device.
+pub(super) struct IoQueue {
+    vm_id: usize,
+    vm: Arc<VmIo>,
+    storage: Option<Arc<Storage>>,
+}
+
+impl IoQueue {
+    /// Creates the scheduling context of a device of VM `vm_id` backed by `storage`, if shared.
+    pub fn new(vm_id: usize, storage: Option<&str>) -> Self {
+        let vm = VM_IO
+            .lock()
+            .entry(vm_id)
+            .or_insert_with(|| Arc::new(VmIo::unlimited()))
+            .clone();
+        let storage = storage.map(Storage::get);
+        if let Some(storage) = &storage {
+            storage.state.lock().vms.entry(vm_id).or_default().devices += 1;
+        }
+        Self { vm_id, vm, storage }
+    }
+
+    /// Runs `op`, a backend operation serving `requests` requests of `kind` and `bytes` bytes,
+    /// once the limits of the VM and the storage allow it.
+    pub fn run<R>(&self, kind: IoKind, requests: usize, bytes: usize, op: impl FnOnce() -> R) -> R {
+        let counters = &self.vm.counters;
+        let now = axhal::time::monotonic_time_nanos();
+        let delay = [
+            self.vm
+                .iops
+                .as_ref()
+                .map(|limit| limit.lock().reserve(now, requests as u64)),
+            self.vm
+                .bandwidth
+                .as_ref()
+                .map(|limit| limit.lock().reserve(now, bytes as u64)),
+        ]
+        .into_iter()
+        .flatten()
+        .max()
+        .unwrap_or(0);
+        if delay > 0 {
+            std::thread::sleep(Duration::from_nanos(delay));
+            counters.throttled_ns.fetch_add(delay, Ordering::Relaxed);
+        }
+
+        let result = match &self.storage {
+            Some(storage) => {
+                let queued = axhal::time::monotonic_time_nanos();
+                storage.acquire(self.vm_id);
+                counters.queued_ns.fetch_add(
+                    axhal::time::monotonic_time_nanos() - queued,
+                    Ordering::Relaxed,
+                );
+                let result = op();
+                storage.release(
+                    self.vm_id,
+                    bytes as u64 + requests as u64 * OP_COST,
+                    self.vm.weight,
+                );
+                result
+            }
+            None => op(),
+        };
+
+        let (count, byte_count) = match kind {
+            IoKind::Read => (&counters.reads, Some(&counters.read_bytes)),
+            IoKind::Write => (&counters.writes, Some(&counters.write_bytes)),
+            IoKind::Flush => (&counters.flushes, None),
+        };
+        count.fetch_add(requests as u64, Ordering::Relaxed);
+        if let Some(byte_count) = byte_count {
+            byte_count.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+        counters
+            .merged
+            .fetch_add(requests.saturating_sub(1) as u64, Ordering::Relaxed);
+        result
+    }
+}
+
+impl Drop for IoQueue {
+    fn drop(&mut self) {
+        if let Some(storage) = &self.storage {
+            let mut state = storage.state.lock();
+            if let Some(vm) = state.vms.get_mut(&self.vm_id) {
+                vm.devices -= 1;
+                if vm.devices == 0 && vm.waiting == 0 {
+                    state.vms.remove(&self.vm_id);
+                }
+            }
+        }
+    }
+}
+
+/// Sets the I/O limits and weight of the VM from the `[blk_io]` section of `raw_cfg`.
+pub(super) fn setup_vm_blk_io(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("blk_io").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let get = |key: &str| {
+        cfg.get(key)
+            .map(|v| {
+                v.as_integer()
+                    .filter(|v| *v > 0)
+                    .map(|v| v as u64)
+                    .ok_or_else(|| {
+                        ax_err_type!(
+                            InvalidInput,
+                            format!("blk_io config: `{}` must be a positive integer", key)
+                        )
+                    })
+            })
+            .transpose()
+    };
+    let limit = |per_sec| Mutex::new(RateLimit { per_sec, tat: 0 });
+    let weight = get("weight")?.unwrap_or(DEFAULT_WEIGHT);
+    if weight > MAX_WEIGHT {
+        return ax_err!(
+            InvalidInput,
+            format!("blk_io config: `weight` must be at most {}", MAX_WEIGHT)
+        );
+    }
+    let io = VmIo {
+        iops: get("iops")?.map(limit),
+        bandwidth: get("bandwidth")?.map(limit),
+        weight,
+        counters: Counters::default(),
+    };
+    info!(
+        "VM[{}] block I/O: {:?} IOPS, {:?} bytes/s, weight {}",
+        vm.id(),
+        io.iops.as_ref().map(|l| l.lock().per_sec),
+        io.bandwidth.as_ref().map(|l| l.lock().per_sec),
+        io.weight
+    );
+    VM_IO.lock().insert(vm.id(), Arc::new(io));
+    Ok(())
+}
+
+/// Drops the I/O settings of a VM, called when the VM is destroyed.
+pub(super) fn teardown_vm_blk_io(vm_id: usize) {
+    VM_IO.lock().remove(&vm_id);
+}
+
+/// Returns the block I/O counters of VM `vm_id`, if it has virtio-blk devices.
+pub(super) fn vm_io_stats(vm_id: usize) -> Option<BlkIoStats> {
+    let io = VM_IO.lock().get(&vm_id).cloned()?;
+    let c = &io.counters;
+    let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    Some(BlkIoStats {
+        reads: get(&c.reads),
+        writes: get(&c.writes),
+        flushes: get(&c.flushes),
+        read_bytes: get(&c.read_bytes),
+        write_bytes: get(&c.write_bytes),
+        merged: get(&c.merged),
+        throttled_ns: get(&c.throttled_ns),
+        queued_ns: get(&c.queued_ns),
+    })
+}
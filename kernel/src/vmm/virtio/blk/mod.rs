@@ -0,0 +1,568 @@
+//! Virtio-blk devices backed by host storage.
+//!
+//! Requests are served by a worker task per device, so that disk I/O does not stall the vCPU
+//! which notified the queue. Completions are signaled by the device interrupt. A guest gets a
+//! device with a `[[virtio_blk]]` entry in its VM config, backed either by a file of the host
+//! filesystem (an image file, or a partition or ramdisk exposed as a file by the block layer) or
+//! by a ramdisk allocated by the hypervisor:
+//!
+//! ```toml
+//! [[virtio_blk]]
+//! # Guest physical base of the virtio-mmio register page.
+//! base = 0x0a00_9000
+//! # Interrupt injected to the guest on completions.
+//! irq = 0x31
+//! # Backing file on the host filesystem, requires the `fs` feature.
+//! path = "/guest/rootfs.img"
+//! # Or a zero-filled ramdisk of the given size in bytes.
+//! # ramdisk_size = 0x400_0000
+//! # Image format of `path`: "raw" or "qcow2", detected from the image by default.
+//! format = "qcow2"
+//! # Base image `path` is created on, as an empty qcow2 overlay, if it doesn't exist.
+//! backing = "/guest/base.qcow2"
+//! # Write caching: "writeback", the guest flushes to make its writes durable, or
+//! # "writethrough", writes are durable when they complete. Defaults to "writeback".
+//! cache = "writeback"
+//! # Reject writes, defaults to false.
+//! read_only = false
+//! # Host storage the device shares with others, see [`iosched`].
+//! storage = "default"
+//! ```
+//!
+//! Raw images hold the disk as is. qcow2 images, see [`qcow2`], are sparse and copy-on-write: many
+//! guests can share a read-only base image, each writing to a small overlay of its own. The base
+//! images of overlays are opened once, whatever the number of overlays on them.
+//!
+//! The worker merges adjacent requests into single backend operations, which go through the I/O
+//! scheduler of the VM, see [`iosched`], for its limits and its share of the storage.
+mod backend;
+mod iosched;
+#[cfg(feature = "fs")]
+mod qcow2;
+
+use alloc::boxed::Box;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use std::os::arceos::modules::axtask::WaitQueue;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+
+use super::queue::DescChain;
+use super::{VirtioDevice, VirtioMmio, register_device};
+use crate::vmm::{VMRef, vm_list};
+
+use backend::{BlockBackend, open_backend};
+use iosched::{DEFAULT_STORAGE, IoKind, IoQueue};
+
+pub use iosched::BlkIoStats;
+
+const VIRTIO_ID_BLOCK: u32 = 2;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const REQUEST_QUEUE: usize = 0;
+
+/// Size of `struct virtio_blk_req` before the data.
+const REQ_HDR_LEN: usize = 16;
+const SECTOR_SIZE: u64 = 512;
+/// Length of the device ID string returned by `VIRTIO_BLK_T_GET_ID`.
+const DEVICE_ID_LEN: usize = 20;
+/// Upper bound of the data of a request, larger requests fail.
+const MAX_REQUEST_LEN: usize = 4 << 20;
+
+/// Requests popped at once by the worker, among which adjacent ones are merged.
+const MAX_BATCH: usize = 32;
+
+const WORKER_STACK_SIZE: usize = 0x10000;
+
+/// Wakes the worker task of a device.
+struct WorkerSignal {
+    pending: AtomicBool,
+    stopped: AtomicBool,
+    wait_queue: WaitQueue,
+}
+
+impl WorkerSignal {
+    fn new() -> Self {
+        Self {
+            pending: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            wait_queue: WaitQueue::new(),
+        }
+    }
+
+    fn kick(&self) {
+        self.pending.store(true, Ordering::Release);
+        self.wait_queue.notify_one(true);
+    }
+
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::Release);
+        self.wait_queue.notify_one(true);
+    }
+}
+
+/// What a device does with the writes of the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WritePolicy {
+    /// Writes fail.
+    ReadOnly,
+    /// Writes are durable once the guest flushes them.
+    WriteBack,
+    /// Writes are durable when they complete, the device doesn't offer flushes.
+    WriteThrough,
+}
+
+struct VirtioBlk {
+    capacity: u64,
+    policy: WritePolicy,
+    signal: Arc<WorkerSignal>,
+}
+
+impl VirtioDevice for VirtioBlk {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn device_features(&self) -> u64 {
+        match self.policy {
+            WritePolicy::ReadOnly => VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_RO,
+            WritePolicy::WriteBack => VIRTIO_BLK_F_FLUSH,
+            WritePolicy::WriteThrough => 0,
+        }
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, offset: usize) -> u8 {
+        // Only `capacity`, in 512-byte sectors, is provided.
+        match offset {
+            0..8 => (self.capacity / SECTOR_SIZE).to_le_bytes()[offset],
+            _ => 0,
+        }
+    }
+
+    fn queue_notify(&self, _transport: &VirtioMmio, _vm: &VMRef, queue: usize) {
+        if queue == REQUEST_QUEUE {
+            self.signal.kick();
+        }
+    }
+
+    fn detach(&self) {
+        self.signal.stop();
+    }
+}
+
+/// What a request asks for, checked against the device.
+enum Op {
+    Read {
+        offset: u64,
+        len: usize,
+    },
+    Write {
+        offset: u64,
+        data: Vec<u8>,
+    },
+    Flush,
+    GetId {
+        len: usize,
+    },
+    /// The request fails with the given status without reaching the backend.
+    Fail(u8),
+}
+
+/// A request chain popped from the queue.
+struct Request {
+    chain: DescChain,
+    /// Writable bytes of the chain, the last being the status.
+    writable: usize,
+    op: Op,
+}
+
+impl Request {
+    /// Parses the request in `chain`.
+    fn parse(vm: &VMRef, chain: DescChain, capacity: u64, policy: WritePolicy) -> Self {
+        // The last writable byte is the status, it is preceded by the data for reads.
+        let writable = chain.writable_len();
+        let op = match writable {
+            0 => Op::Fail(VIRTIO_BLK_S_IOERR),
+            _ => {
+                Self::parse_op(vm, &chain, capacity, policy, writable - 1).unwrap_or_else(Op::Fail)
+            }
+        };
+        Self {
+            chain,
+            writable,
+            op,
+        }
+    }
+
+    fn parse_op(
+        vm: &VMRef,
+        chain: &DescChain,
+        capacity: u64,
+        policy: WritePolicy,
+        data_len: usize,
+    ) -> Result<Op, u8> {
+        let hdr = chain
+            .read(vm, 0, REQ_HDR_LEN)
+            .map_err(|_| VIRTIO_BLK_S_IOERR)?;
+        if hdr.len() < REQ_HDR_LEN {
+            return Err(VIRTIO_BLK_S_IOERR);
+        }
+        let req_type = u32::from_le_bytes(hdr[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(hdr[8..16].try_into().unwrap());
+        let in_range = |len: usize| {
+            sector
+                .checked_mul(SECTOR_SIZE)
+                .filter(|offset| offset.saturating_add(len as u64) <= capacity)
+                .ok_or(VIRTIO_BLK_S_IOERR)
+        };
+
+        match req_type {
+            VIRTIO_BLK_T_IN => {
+                if data_len > MAX_REQUEST_LEN {
+                    return Err(VIRTIO_BLK_S_IOERR);
+                }
+                Ok(Op::Read {
+                    offset: in_range(data_len)?,
+                    len: data_len,
+                })
+            }
+            VIRTIO_BLK_T_OUT => {
+                if policy == WritePolicy::ReadOnly {
+                    return Err(VIRTIO_BLK_S_IOERR);
+                }
+                let data = chain
+                    .read(vm, REQ_HDR_LEN, MAX_REQUEST_LEN + 1)
+                    .map_err(|_| VIRTIO_BLK_S_IOERR)?;
+                if data.len() > MAX_REQUEST_LEN {
+                    return Err(VIRTIO_BLK_S_IOERR);
+                }
+                Ok(Op::Write {
+                    offset: in_range(data.len())?,
+                    data,
+                })
+            }
+            VIRTIO_BLK_T_FLUSH => Ok(Op::Flush),
+            VIRTIO_BLK_T_GET_ID => Ok(Op::GetId { len: data_len }),
+            _ => Err(VIRTIO_BLK_S_UNSUPP),
+        }
+    }
+
+    /// Returns the range of the disk the request reads, or writes, if any.
+    fn extent(&self) -> Option<(bool, u64, usize)> {
+        match &self.op {
+            Op::Read { offset, len } => Some((false, *offset, *len)),
+            Op::Write { offset, data } => Some((true, *offset, data.len())),
+            _ => None,
+        }
+    }
+
+    /// Whether `next` continues the reads, or writes, of the requests `run` up to `len` bytes,
+    /// so that they are served by a single backend operation.
+    fn merges(run: &[Request], len: usize, next: &Request) -> bool {
+        let (Some((write, offset, _)), Some((next_write, next_offset, next_len))) =
+            (run[0].extent(), next.extent())
+        else {
+            return false;
+        };
+        write == next_write
+            && next_offset == offset + len as u64
+            && len + next_len <= MAX_REQUEST_LEN
+    }
+}
+
+/// Executes the requests `run`, merged by [`Request::merges`] if more than one, returns the data
+/// to be returned to the driver of each.
+fn execute(
+    run: &[Request],
+    backend: &mut dyn BlockBackend,
+    policy: WritePolicy,
+    io: &IoQueue,
+) -> Vec<Result<Vec<u8>, u8>> {
+    match &run[0].op {
+        Op::Read { offset, .. } => {
+            let lens = run.iter().map(|req| req.extent().map_or(0, |e| e.2));
+            let total = lens.clone().sum();
+            let mut buf = vec![0u8; total];
+            match io.run(IoKind::Read, run.len(), total, || {
+                backend.read_at(*offset, &mut buf)
+            }) {
+                Ok(()) => {
+                    let mut pos = 0;
+                    lens.map(|len| {
+                        pos += len;
+                        Ok(buf[pos - len..pos].to_vec())
+                    })
+                    .collect()
+                }
+                Err(_) => vec![Err(VIRTIO_BLK_S_IOERR); run.len()],
+            }
+        }
+        Op::Write { offset, data } => {
+            let merged;
+            let data = if run.len() == 1 {
+                data
+            } else {
+                merged = run
+                    .iter()
+                    .filter_map(|req| match &req.op {
+                        Op::Write { data, .. } => Some(data.as_slice()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .concat();
+                &merged
+            };
+            let result = io.run(IoKind::Write, run.len(), data.len(), || {
+                backend.write_at(*offset, data)?;
+                if policy == WritePolicy::WriteThrough {
+                    backend.flush()?;
+                }
+                Ok(())
+            });
+            match result {
+                Ok(()) => vec![Ok(Vec::new()); run.len()],
+                Err(_) => vec![Err(VIRTIO_BLK_S_IOERR); run.len()],
+            }
+        }
+        Op::Flush => vec![
+            io.run(IoKind::Flush, 1, 0, || backend.flush())
+                .map(|_| Vec::new())
+                .map_err(|_| VIRTIO_BLK_S_IOERR),
+        ],
+        Op::GetId { len } => {
+            let mut id = vec![0u8; DEVICE_ID_LEN];
+            let name = b"axvisor-virtio-blk";
+            id[..name.len()].copy_from_slice(name);
+            id.truncate(*len);
+            vec![Ok(id)]
+        }
+        Op::Fail(status) => vec![Err(*status)],
+    }
+}
+
+/// Completes a request with `result`, returns the number of bytes written into its chain.
+fn complete(vm: &VMRef, req: &Request, result: Result<Vec<u8>, u8>) -> u32 {
+    if req.writable == 0 {
+        return 0;
+    }
+    let (data, status) = match result {
+        Ok(data) => (data, VIRTIO_BLK_S_OK),
+        Err(status) => (Vec::new(), status),
+    };
+    let mut resp = vec![0u8; req.writable];
+    resp[..data.len()].copy_from_slice(&data);
+    resp[req.writable - 1] = status;
+    req.chain.write(vm, &resp).unwrap_or(0) as u32
+}
+
+/// The worker task of a device, serving requests until the device is detached.
+fn worker(
+    transport: Weak<VirtioMmio>,
+    mut backend: Box<dyn BlockBackend>,
+    signal: Arc<WorkerSignal>,
+    policy: WritePolicy,
+    io: IoQueue,
+) {
+    let capacity = backend.capacity();
+    loop {
+        signal.wait_queue.wait_until(|| {
+            signal.pending.load(Ordering::Acquire) || signal.stopped.load(Ordering::Acquire)
+        });
+        if signal.stopped.load(Ordering::Acquire) {
+            break;
+        }
+        // Cleared before popping, so that requests made available meanwhile are not missed.
+        signal.pending.store(false, Ordering::Release);
+
+        let Some(transport) = transport.upgrade() else {
+            break;
+        };
+        let Some(vm) = vm_list::get_vm_by_id(transport.vm_id()) else {
+            continue;
+        };
+
+        loop {
+            // The queue is not locked during the I/O.
+            let mut batch = Vec::new();
+            while batch.len() < MAX_BATCH {
+                match transport.with_queue(REQUEST_QUEUE, |q| q.pop_avail(&vm)) {
+                    Some(Ok(Some(chain))) => {
+                        batch.push(Request::parse(&vm, chain, capacity, policy))
+                    }
+                    Some(Err(e)) => {
+                        warn!("VM[{}] virtio-blk queue: {:?}", vm.id(), e);
+                        break;
+                    }
+                    _ => break,
+                }
+            }
+            if batch.is_empty() {
+                break;
+            }
+
+            let mut start = 0;
+            while start < batch.len() {
+                let mut end = start + 1;
+                let mut len = batch[start].extent().map_or(0, |e| e.2);
+                while end < batch.len() && Request::merges(&batch[start..end], len, &batch[end]) {
+                    len += batch[end].extent().map_or(0, |e| e.2);
+                    end += 1;
+                }
+                let run = &batch[start..end];
+                for (req, result) in run.iter().zip(execute(run, backend.as_mut(), policy, &io)) {
+                    let len = complete(&vm, req, result);
+                    if let Some(Err(e)) = transport
+                        .with_queue(REQUEST_QUEUE, |q| q.push_used(&vm, req.chain.head, len))
+                    {
+                        warn!("VM[{}] virtio-blk used ring: {:?}", vm.id(), e);
+                    }
+                }
+                start = end;
+            }
+            transport.notify_used(&vm, REQUEST_QUEUE);
+            if batch.len() < MAX_BATCH {
+                break;
+            }
+        }
+    }
+    debug!("virtio-blk worker exited");
+}
+
+/// Creates a virtio-blk device of the VM from its config `entry`, attached by `attach`. `label`
+/// tells the device apart in the name of its worker task and in logs.
+pub(super) fn add_virtio_blk(
+    vm: &VMRef,
+    entry: &toml::Value,
+    label: &str,
+    attach: impl FnOnce(Box<dyn VirtioDevice>) -> AxResult<Arc<VirtioMmio>>,
+) -> AxResult<Arc<VirtioMmio>> {
+    let read_only = entry
+        .get("read_only")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+
+    let policy = match (read_only, entry.get("cache").and_then(|v| v.as_str())) {
+        (true, _) => WritePolicy::ReadOnly,
+        (false, None | Some("writeback")) => WritePolicy::WriteBack,
+        (false, Some("writethrough")) => WritePolicy::WriteThrough,
+        (false, Some(cache)) => {
+            return ax_err!(
+                InvalidInput,
+                format!("virtio_blk config: unknown `cache` {:?}", cache)
+            );
+        }
+    };
+
+    // Ramdisks don't share a storage unless told to.
+    let storage = match entry.get("storage") {
+        None => entry.get("path").map(|_| DEFAULT_STORAGE),
+        Some(storage) => Some(storage.as_str().ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                "virtio_blk config: `storage` must be a string"
+            )
+        })?),
+    };
+
+    let backend = open_backend(vm.id(), entry, read_only)?;
+    let capacity = backend.capacity();
+    if capacity % SECTOR_SIZE != 0 {
+        warn!(
+            "VM[{}] virtio-blk {} size is not a multiple of {} bytes, the tail is not accessible",
+            vm.id(),
+            label,
+            SECTOR_SIZE
+        );
+    }
+
+    let signal = Arc::new(WorkerSignal::new());
+    let device = Box::new(VirtioBlk {
+        capacity,
+        policy,
+        signal: signal.clone(),
+    });
+    let transport = attach(device)?;
+    let weak = Arc::downgrade(&transport);
+    let io = IoQueue::new(vm.id(), storage);
+    std::thread::Builder::new()
+        .name(format!("VM[{}]-virtio-blk{}", vm.id(), label))
+        .stack_size(WORKER_STACK_SIZE)
+        .spawn(move || worker(weak, backend, signal, policy, io))
+        .map_err(|err| {
+            ax_err_type!(
+                NoMemory,
+                format!("Failed to spawn virtio-blk worker, err {:?}", err)
+            )
+        })?;
+
+    info!(
+        "VM[{}] virtio-blk {}: {} sectors{}",
+        vm.id(),
+        label,
+        capacity / SECTOR_SIZE,
+        if read_only { " (read-only)" } else { "" }
+    );
+    Ok(transport)
+}
+
+/// Creates the virtio-blk devices described in the `[[virtio_blk]]` array of `raw_cfg`.
+pub(super) fn setup_vm_virtio_blk(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    iosched::setup_vm_blk_io(vm, raw_cfg)?;
+    let Some(entries) = raw_cfg.get("virtio_blk").and_then(|v| v.as_array()) else {
+        return Ok(());
+    };
+    for (idx, entry) in entries.iter().enumerate() {
+        let get = |key: &str| {
+            entry
+                .get(key)
+                .and_then(|v| v.as_integer())
+                .map(|v| v as usize)
+                .ok_or_else(|| {
+                    ax_err_type!(
+                        InvalidInput,
+                        format!("virtio_blk config: missing `{}`", key)
+                    )
+                })
+        };
+        let base = get("base")?;
+        let irq = get("irq")?;
+        add_virtio_blk(vm, entry, &format!("{}", idx), |device| {
+            register_device(vm, base, irq, device)
+        })?;
+        info!(
+            "VM[{}] virtio-blk {} at {:#x}, irq {}",
+            vm.id(),
+            idx,
+            base,
+            irq
+        );
+    }
+    Ok(())
+}
+
+/// Drops the I/O settings of a VM, called when the VM is destroyed.
+pub(super) fn teardown_vm_virtio_blk(vm_id: usize) {
+    iosched::teardown_vm_blk_io(vm_id);
+}
+
+/// Returns the block I/O counters of VM `vm_id`, if it has virtio-blk devices.
+pub(super) fn vm_blk_io_stats(vm_id: usize) -> Option<BlkIoStats> {
+    iosched::vm_io_stats(vm_id)
+}
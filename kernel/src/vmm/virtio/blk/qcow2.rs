@@ -0,0 +1,579 @@
+//! Sparse copy-on-write images in the qcow2 format.
+//!
+//! A qcow2 image maps the clusters of the virtual disk to clusters of the image file through a
+//! two-level table (L1, then L2 tables), and only holds the clusters written to: the others read
+//! as zeros, or from the backing image of the image if it has one. Clusters are allocated at the
+//! end of the file when first written, copying the rest of the cluster from the backing image, and
+//! their refcounts are kept up to date so that `qemu-img check` finds the image consistent.
+//!
+//! Images made with `qemu-img create -f qcow2` are supported, in versions 2 and 3, except for
+//! compressed clusters, encryption, external data files and extended L2 entries. Images with
+//! internal snapshots are read-only. The L2 tables and refcount blocks are cached and written back
+//! on flush: refcounts first, then the L2 tables and the L1 table, so that a crash leaks clusters
+//! at worst.
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+
+use super::backend::{BackingImage, BlockBackend, FileDisk, ImageFormat};
+use crate::vmm::memstat::{self, MemSubsystem};
+
+/// Magic number at the start of qcow2 images, "QFI\xfb".
+pub const QCOW2_MAGIC: u32 = u32::from_be_bytes(*b"QFI\xfb");
+
+const HEADER_V2_LEN: usize = 72;
+const HEADER_V3_LEN: usize = 104;
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+/// Only 16-bit refcounts, the default, are supported.
+const REFCOUNT_ORDER: u32 = 4;
+
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const REFCOUNT_TABLE_OFFSET_MASK: u64 = !0x1ff;
+const OFLAG_COPIED: u64 = 1 << 63;
+const OFLAG_COMPRESSED: u64 = 1 << 62;
+const OFLAG_ZERO: u64 = 1;
+
+const EXT_END: u32 = 0;
+const EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+/// Cluster size of the overlays created by [`Qcow2Image::create_overlay`], 64 KiB as `qemu-img`.
+const OVERLAY_CLUSTER_BITS: u32 = 16;
+
+/// Number of cached L2 tables and refcount blocks.
+const L2_CACHE_TABLES: usize = 16;
+const REFCOUNT_CACHE_BLOCKS: usize = 4;
+
+fn be32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn be64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn to_be_bytes(table: &[u64]) -> Vec<u8> {
+    table.iter().flat_map(|e| e.to_be_bytes()).collect()
+}
+
+fn from_be_bytes(bytes: &[u8]) -> Vec<u64> {
+    bytes.chunks_exact(8).map(|e| be64(e, 0)).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TableKind {
+    L2,
+    Refcount,
+}
+
+/// A cluster of metadata, an L2 table or a refcount block, as stored in the file.
+struct CachedTable {
+    offset: u64,
+    data: Vec<u8>,
+    dirty: bool,
+    last_use: u64,
+}
+
+/// Least recently used metadata clusters.
+struct TableCache {
+    tables: Vec<CachedTable>,
+    capacity: usize,
+    clock: u64,
+}
+
+impl TableCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            tables: Vec::with_capacity(capacity),
+            capacity,
+            clock: 0,
+        }
+    }
+}
+
+/// A qcow2 image file.
+pub struct Qcow2Image {
+    path: String,
+    /// VM charged for the caches, `None` for shared backing images.
+    vm_id: Option<usize>,
+    file: FileDisk,
+    backing: Option<BackingImage>,
+    read_only: bool,
+    size: u64,
+    cluster_bits: u32,
+    l1_table_offset: u64,
+    l1: Vec<u64>,
+    l1_dirty: bool,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    refcount_table_dirty: bool,
+    l2_cache: TableCache,
+    refcount_cache: TableCache,
+    /// Offset of the next allocated cluster, clusters are appended to the file.
+    next_cluster: u64,
+    charged: usize,
+}
+
+impl Qcow2Image {
+    /// Opens the qcow2 image `path`, already opened as `file`.
+    pub fn open(
+        vm_id: Option<usize>,
+        path: &str,
+        mut file: FileDisk,
+        read_only: bool,
+    ) -> AxResult<Self> {
+        let invalid =
+            |what: &str| ax_err_type!(InvalidData, format!("qcow2 image {}: {}", path, what));
+        if file.capacity() < HEADER_V2_LEN as u64 {
+            return Err(invalid("truncated header"));
+        }
+        let mut header = vec![0u8; HEADER_V3_LEN.min(file.capacity() as usize)];
+        file.read_at(0, &mut header)?;
+        if be32(&header, 0) != QCOW2_MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        let version = be32(&header, 4);
+        if version != 2 && version != 3 {
+            return Err(invalid(&format!("unsupported version {}", version)));
+        }
+        let cluster_bits = be32(&header, 20);
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+            return Err(invalid(&format!("invalid cluster bits {}", cluster_bits)));
+        }
+        let cluster_size = 1u64 << cluster_bits;
+        if be32(&header, 32) != 0 {
+            return Err(invalid("encrypted images are not supported"));
+        }
+        let header_len = if version == 3 {
+            if header.len() < HEADER_V3_LEN {
+                return Err(invalid("truncated header"));
+            }
+            let incompatible = be64(&header, 72);
+            if incompatible != 0 {
+                return Err(invalid(&format!(
+                    "unsupported incompatible features {:#x}, see `qemu-img check`",
+                    incompatible
+                )));
+            }
+            if be32(&header, 96) != REFCOUNT_ORDER {
+                return Err(invalid("only 16-bit refcounts are supported"));
+            }
+            be32(&header, 100) as usize
+        } else {
+            HEADER_V2_LEN
+        };
+        if be32(&header, 60) != 0 && !read_only {
+            return Err(invalid("images with snapshots can only be used read-only"));
+        }
+
+        let size = be64(&header, 24);
+        let l2_entries = cluster_size / 8;
+        let l1_size = be32(&header, 36) as u64;
+        if l1_size < size.div_ceil(cluster_size * l2_entries) {
+            return Err(invalid("L1 table too small"));
+        }
+        let l1_table_offset = be64(&header, 40);
+        let mut l1 = vec![0u8; l1_size as usize * 8];
+        file.read_at(l1_table_offset, &mut l1)?;
+        let refcount_table_offset = be64(&header, 48);
+        let mut refcount_table = vec![0u8; be32(&header, 56) as usize * cluster_size as usize];
+        file.read_at(refcount_table_offset, &mut refcount_table)?;
+
+        // The backing file name, relative to the directory of the image, and its format.
+        let backing_file_offset = be64(&header, 8);
+        let backing = if backing_file_offset != 0 {
+            let mut name = vec![0u8; be32(&header, 16) as usize];
+            file.read_at(backing_file_offset, &mut name)?;
+            let name = String::from_utf8(name).map_err(|_| invalid("invalid backing file name"))?;
+            let backing_path = match (name.starts_with('/'), path.rfind('/')) {
+                (false, Some(dir_end)) => format!("{}/{}", &path[..dir_end], name),
+                _ => name,
+            };
+            let format = backing_format(&mut file, header_len, cluster_size)?;
+            Some(BackingImage::open(&backing_path, format)?)
+        } else {
+            None
+        };
+
+        let l1 = from_be_bytes(&l1);
+        let refcount_table = from_be_bytes(&refcount_table);
+        let charged = (l1.len() + refcount_table.len()) * 8
+            + (L2_CACHE_TABLES + REFCOUNT_CACHE_BLOCKS) * cluster_size as usize;
+        memstat::charge(MemSubsystem::Devices, vm_id, charged);
+        Ok(Self {
+            path: path.to_string(),
+            vm_id,
+            next_cluster: file.capacity().next_multiple_of(cluster_size),
+            file,
+            backing,
+            read_only,
+            size,
+            cluster_bits,
+            l1_table_offset,
+            l1,
+            l1_dirty: false,
+            refcount_table_offset,
+            refcount_table,
+            refcount_table_dirty: false,
+            l2_cache: TableCache::new(L2_CACHE_TABLES),
+            refcount_cache: TableCache::new(REFCOUNT_CACHE_BLOCKS),
+            charged,
+        })
+    }
+
+    /// Creates the qcow2 image `path`, empty and backed by the image `backing`, of its size.
+    pub fn create_overlay(path: &str, backing: &str) -> AxResult {
+        let size = BackingImage::open(backing, None)?.capacity();
+        let cluster_size = 1u64 << OVERLAY_CLUSTER_BITS;
+        let l1_size = size.div_ceil(cluster_size * (cluster_size / 8));
+        let l1_clusters = (l1_size * 8).div_ceil(cluster_size).max(1);
+        // Header, refcount table, refcount block, then the L1 table.
+        let refcount_table_offset = cluster_size;
+        let refcount_block_offset = 2 * cluster_size;
+        let l1_table_offset = 3 * cluster_size;
+        let clusters = 3 + l1_clusters;
+        if clusters > cluster_size / 2 {
+            return ax_err!(
+                InvalidInput,
+                format!("backing image {} is too large for an overlay", backing)
+            );
+        }
+
+        let backing_offset = HEADER_V3_LEN as u64 + 8; // after the end of the extensions
+        let mut header = vec![0u8; backing_offset as usize + backing.len()];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            header[offset..offset + bytes.len()].copy_from_slice(bytes)
+        };
+        put(0, &QCOW2_MAGIC.to_be_bytes());
+        put(4, &3u32.to_be_bytes());
+        put(8, &backing_offset.to_be_bytes());
+        put(16, &(backing.len() as u32).to_be_bytes());
+        put(20, &OVERLAY_CLUSTER_BITS.to_be_bytes());
+        put(24, &size.to_be_bytes());
+        put(36, &(l1_size as u32).to_be_bytes());
+        put(40, &l1_table_offset.to_be_bytes());
+        put(48, &refcount_table_offset.to_be_bytes());
+        put(56, &1u32.to_be_bytes());
+        put(96, &REFCOUNT_ORDER.to_be_bytes());
+        put(100, &(HEADER_V3_LEN as u32).to_be_bytes());
+        put(backing_offset as usize, backing.as_bytes());
+
+        let mut refcount_block = vec![0u8; cluster_size as usize];
+        for cluster in 0..clusters as usize {
+            refcount_block[cluster * 2..cluster * 2 + 2].copy_from_slice(&1u16.to_be_bytes());
+        }
+
+        let mut file = FileDisk::create(path)?;
+        file.write_at(0, &header)?;
+        file.write_at(refcount_table_offset, &refcount_block_offset.to_be_bytes())?;
+        file.write_at(refcount_block_offset, &refcount_block)?;
+        file.write_at(
+            l1_table_offset,
+            &vec![0u8; (l1_clusters * cluster_size) as usize],
+        )?;
+        file.flush()
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn cache(&mut self, kind: TableKind) -> &mut TableCache {
+        match kind {
+            TableKind::L2 => &mut self.l2_cache,
+            TableKind::Refcount => &mut self.refcount_cache,
+        }
+    }
+
+    /// Loads the metadata cluster at `offset` in the cache, a zeroed one if `new`, and returns
+    /// its index in the cache.
+    fn load_table(&mut self, kind: TableKind, offset: u64, new: bool) -> AxResult<usize> {
+        let cluster_size = self.cluster_size() as usize;
+        let cache = self.cache(kind);
+        cache.clock += 1;
+        let clock = cache.clock;
+        if let Some(idx) = cache.tables.iter().position(|t| t.offset == offset) {
+            cache.tables[idx].last_use = clock;
+            return Ok(idx);
+        }
+        if cache.tables.len() == cache.capacity {
+            let lru = (0..cache.tables.len())
+                .min_by_key(|idx| cache.tables[*idx].last_use)
+                .unwrap();
+            let evicted = cache.tables.swap_remove(lru);
+            if evicted.dirty {
+                if kind == TableKind::L2 {
+                    // The refcounts of the clusters the table points to are written first.
+                    self.flush_refcounts()?;
+                }
+                self.file.write_at(evicted.offset, &evicted.data)?;
+            }
+        }
+        let mut data = vec![0u8; cluster_size];
+        if !new {
+            self.file.read_at(offset, &mut data)?;
+        }
+        let cache = self.cache(kind);
+        cache.tables.push(CachedTable {
+            offset,
+            data,
+            dirty: new,
+            last_use: clock,
+        });
+        Ok(cache.tables.len() - 1)
+    }
+
+    fn write_back(&mut self, kind: TableKind) -> AxResult {
+        let cache = match kind {
+            TableKind::L2 => &mut self.l2_cache,
+            TableKind::Refcount => &mut self.refcount_cache,
+        };
+        for table in cache.tables.iter_mut().filter(|t| t.dirty) {
+            self.file.write_at(table.offset, &table.data)?;
+            table.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn flush_refcounts(&mut self) -> AxResult {
+        self.write_back(TableKind::Refcount)?;
+        if self.refcount_table_dirty {
+            let table = to_be_bytes(&self.refcount_table);
+            self.file.write_at(self.refcount_table_offset, &table)?;
+            self.refcount_table_dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Writes the dirty metadata back, refcounts first.
+    fn flush_metadata(&mut self) -> AxResult {
+        self.flush_refcounts()?;
+        self.write_back(TableKind::L2)?;
+        if self.l1_dirty {
+            let l1 = to_be_bytes(&self.l1);
+            self.file.write_at(self.l1_table_offset, &l1)?;
+            self.l1_dirty = false;
+        }
+        Ok(())
+    }
+
+    fn set_refcount(&mut self, offset: u64, refcount: u16) -> AxResult {
+        let cluster = offset >> self.cluster_bits;
+        let per_block = self.cluster_size() / 2;
+        let table_idx = (cluster / per_block) as usize;
+        if table_idx >= self.refcount_table.len() {
+            return ax_err!(
+                NoMemory,
+                format!("qcow2 image {}: refcount table full", self.path)
+            );
+        }
+        let mut block = self.refcount_table[table_idx] & REFCOUNT_TABLE_OFFSET_MASK;
+        let new = block == 0;
+        if new {
+            block = self.next_cluster;
+            self.next_cluster += self.cluster_size();
+            self.refcount_table[table_idx] = block;
+            self.refcount_table_dirty = true;
+        }
+        let idx = self.load_table(TableKind::Refcount, block, new)?;
+        let entry = (cluster % per_block) as usize * 2;
+        let table = &mut self.refcount_cache.tables[idx];
+        table.data[entry..entry + 2].copy_from_slice(&refcount.to_be_bytes());
+        table.dirty = true;
+        if new {
+            // The new refcount block is a cluster in use too.
+            self.set_refcount(block, 1)?;
+        }
+        Ok(())
+    }
+
+    fn alloc_cluster(&mut self) -> AxResult<u64> {
+        let offset = self.next_cluster;
+        self.next_cluster += self.cluster_size();
+        self.set_refcount(offset, 1)?;
+        Ok(offset)
+    }
+
+    /// Returns the indexes in the L1 and L2 tables of the virtual disk offset `offset`.
+    fn table_indexes(&self, offset: u64) -> (usize, usize) {
+        let l2_bits = self.cluster_bits - 3;
+        let cluster = offset >> self.cluster_bits;
+        (
+            (cluster >> l2_bits) as usize,
+            (cluster & ((1 << l2_bits) - 1)) as usize,
+        )
+    }
+
+    /// Returns the L2 entry of the cluster at `offset`, 0 if it has no L2 table.
+    fn l2_entry(&mut self, offset: u64) -> AxResult<u64> {
+        let (l1_idx, l2_idx) = self.table_indexes(offset);
+        let l2_offset = self.l1[l1_idx] & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+        let idx = self.load_table(TableKind::L2, l2_offset, false)?;
+        Ok(be64(&self.l2_cache.tables[idx].data, l2_idx * 8))
+    }
+
+    /// Returns the cache index of the L2 table of the cluster at `offset`, allocated if needed.
+    fn l2_table(&mut self, offset: u64) -> AxResult<usize> {
+        let (l1_idx, _) = self.table_indexes(offset);
+        let mut l2_offset = self.l1[l1_idx] & OFFSET_MASK;
+        let new = l2_offset == 0;
+        if new {
+            l2_offset = self.alloc_cluster()?;
+            self.l1[l1_idx] = l2_offset | OFLAG_COPIED;
+            self.l1_dirty = true;
+        }
+        self.load_table(TableKind::L2, l2_offset, new)
+    }
+
+    /// Reads from the backing image, zeros past its end or without one.
+    fn read_backing(&mut self, offset: u64, buf: &mut [u8]) -> AxResult {
+        buf.fill(0);
+        let Some(backing) = self.backing.as_mut() else {
+            return Ok(());
+        };
+        let capacity = backing.capacity();
+        if offset >= capacity {
+            return Ok(());
+        }
+        let len = buf.len().min((capacity - offset) as usize);
+        backing.read_at(offset, &mut buf[..len])
+    }
+
+    fn check_entry(&self, entry: u64) -> AxResult {
+        if entry & OFLAG_COMPRESSED != 0 {
+            return ax_err!(
+                Unsupported,
+                format!("qcow2 image {}: compressed clusters", self.path)
+            );
+        }
+        Ok(())
+    }
+
+    /// Splits `len` bytes from `offset` into pieces within a cluster.
+    fn pieces(&self, offset: u64, len: usize) -> impl Iterator<Item = (u64, usize)> + use<> {
+        let cluster_size = self.cluster_size();
+        let end = offset + len as u64;
+        let mut pos = offset;
+        core::iter::from_fn(move || {
+            if pos >= end {
+                return None;
+            }
+            let piece = (cluster_size - pos % cluster_size).min(end - pos);
+            let item = (pos, piece as usize);
+            pos += piece;
+            Some(item)
+        })
+    }
+}
+
+impl BlockBackend for Qcow2Image {
+    fn capacity(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> AxResult {
+        let mut done = 0;
+        for (pos, len) in self.pieces(offset, buf.len()) {
+            let piece = &mut buf[done..done + len];
+            done += len;
+            let entry = self.l2_entry(pos)?;
+            self.check_entry(entry)?;
+            let host = entry & OFFSET_MASK;
+            if host != 0 {
+                self.file.read_at(host + pos % self.cluster_size(), piece)?;
+            } else if entry & OFLAG_ZERO != 0 {
+                piece.fill(0);
+            } else {
+                self.read_backing(pos, piece)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> AxResult {
+        if self.read_only {
+            return ax_err!(PermissionDenied, "read-only qcow2 image");
+        }
+        let cluster_size = self.cluster_size();
+        let mut done = 0;
+        for (pos, len) in self.pieces(offset, data.len()) {
+            let piece = &data[done..done + len];
+            done += len;
+            let (_, l2_idx) = self.table_indexes(pos);
+            let table = self.l2_table(pos)?;
+            let entry = be64(&self.l2_cache.tables[table].data, l2_idx * 8);
+            self.check_entry(entry)?;
+            let host = entry & OFFSET_MASK;
+            if host != 0 {
+                self.file.write_at(host + pos % cluster_size, piece)?;
+                continue;
+            }
+
+            // Copy on write: the rest of the cluster comes from the backing image.
+            let cluster_start = pos - pos % cluster_size;
+            let mut cluster = vec![0u8; cluster_size as usize];
+            if len as u64 != cluster_size && entry & OFLAG_ZERO == 0 {
+                self.read_backing(cluster_start, &mut cluster)?;
+            }
+            let in_cluster = (pos - cluster_start) as usize;
+            cluster[in_cluster..in_cluster + len].copy_from_slice(piece);
+            let host = self.alloc_cluster()?;
+            self.file.write_at(host, &cluster)?;
+            // Allocations only touch the refcount cache, `table` is still valid.
+            let table = &mut self.l2_cache.tables[table];
+            table.data[l2_idx * 8..l2_idx * 8 + 8]
+                .copy_from_slice(&(host | OFLAG_COPIED).to_be_bytes());
+            table.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> AxResult {
+        self.flush_metadata()?;
+        self.file.flush()
+    }
+}
+
+impl Drop for Qcow2Image {
+    fn drop(&mut self) {
+        if !self.read_only
+            && let Err(err) = self.flush()
+        {
+            warn!(
+                "qcow2 image {}: metadata not written back: {:?}",
+                self.path, err
+            );
+        }
+        memstat::uncharge(MemSubsystem::Devices, self.vm_id, self.charged);
+    }
+}
+
+/// Returns the format in the backing format header extension of the image, if any.
+fn backing_format(
+    file: &mut FileDisk,
+    header_len: usize,
+    cluster_size: u64,
+) -> AxResult<Option<ImageFormat>> {
+    let mut offset = header_len as u64;
+    while offset + 8 <= cluster_size.min(file.capacity()) {
+        let mut ext = [0u8; 8];
+        file.read_at(offset, &mut ext)?;
+        let (kind, len) = (be32(&ext, 0), be32(&ext, 4) as usize);
+        match kind {
+            EXT_END => break,
+            EXT_BACKING_FORMAT => {
+                let mut name = vec![0u8; len];
+                file.read_at(offset + 8, &mut name)?;
+                return Ok(core::str::from_utf8(&name)
+                    .ok()
+                    .and_then(ImageFormat::from_name));
+            }
+            _ => offset += 8 + len.next_multiple_of(8) as u64,
+        }
+    }
+    Ok(None)
+}
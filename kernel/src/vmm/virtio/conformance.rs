@@ -0,0 +1,212 @@
+//! Runtime conformance checks of the virtio-mmio transport, run by [`crate::vmm::abi::check_vm`].
+//!
+//! The registers of each device are accessed through its trap handler, exactly as a guest access
+//! is, so the checks see what the guest sees on this architecture. The selectors are restored
+//! afterwards. Feature negotiation and reset are only checked on devices the driver of the guest
+//! hasn't started to initialize: they go through the states of a driver probe and are left reset,
+//! as they were found.
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use axaddrspace::GuestPhysAddr;
+use axaddrspace::device::AccessWidth;
+
+use super::*;
+use crate::vmm::abi::{CheckOutcome, ConformanceCheck};
+
+const STATUS_ACKNOWLEDGE: u32 = 1 << 0;
+const STATUS_DRIVER: u32 = 1 << 1;
+
+/// The registers of a device, as accessed by the guest.
+struct Regs<'a> {
+    vm: &'a VMRef,
+    transport: &'a VirtioMmio,
+}
+
+impl Regs<'_> {
+    fn read(&self, reg: usize) -> Result<u32, String> {
+        self.transport
+            .handle_read(self.vm, GuestPhysAddr::from(reg), AccessWidth::Dword)
+            .map(|val| val as u32)
+            .map_err(|e| format!("read of {:#x} failed: {:?}", reg, e))
+    }
+
+    fn write(&self, reg: usize, val: u32) -> Result<(), String> {
+        self.transport
+            .handle_write(
+                self.vm,
+                GuestPhysAddr::from(reg),
+                AccessWidth::Dword,
+                val as usize,
+            )
+            .map_err(|e| format!("write of {:#x} failed: {:?}", reg, e))
+    }
+
+    fn expect(&self, reg: usize, expected: u32) -> Result<(), String> {
+        match self.read(reg)? {
+            val if val == expected => Ok(()),
+            val => Err(format!(
+                "register {:#x} reads {:#x}, expected {:#x}",
+                reg, val, expected
+            )),
+        }
+    }
+
+    fn write_driver_features(&self, features: u64) -> Result<(), String> {
+        self.write(REG_DRIVER_FEATURES_SEL, 0)?;
+        self.write(REG_DRIVER_FEATURES, features as u32)?;
+        self.write(REG_DRIVER_FEATURES_SEL, 1)?;
+        self.write(REG_DRIVER_FEATURES, (features >> 32) as u32)
+    }
+
+    /// Offers `features` with the status up to FEATURES_OK, returns whether the device accepted
+    /// them.
+    fn negotiate(&self, features: u64) -> Result<bool, String> {
+        self.write(REG_STATUS, 0)?;
+        self.write(REG_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER)?;
+        self.write_driver_features(features)?;
+        self.write(
+            REG_STATUS,
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK,
+        )?;
+        Ok(self.read(REG_STATUS)? & STATUS_FEATURES_OK != 0)
+    }
+}
+
+fn identity(regs: &Regs) -> Result<(), String> {
+    regs.expect(REG_MAGIC, MAGIC)?;
+    regs.expect(REG_VERSION, VERSION)?;
+    regs.expect(REG_VENDOR_ID, VENDOR_ID)?;
+    regs.expect(REG_DEVICE_ID, regs.transport.device.device_id())?;
+    regs.expect(REG_CONFIG_GENERATION, 0)
+}
+
+fn register_width(regs: &Regs) -> Result<(), String> {
+    let addr = GuestPhysAddr::from(REG_STATUS);
+    for width in [AccessWidth::Byte, AccessWidth::Word] {
+        if regs.transport.handle_read(regs.vm, addr, width).is_ok() {
+            return Err(format!("{:?} read of a register accepted", width));
+        }
+        if regs.transport.handle_write(regs.vm, addr, width, 0).is_ok() {
+            return Err(format!("{:?} write of a register accepted", width));
+        }
+    }
+    Ok(())
+}
+
+fn device_features(regs: &Regs) -> Result<(), String> {
+    let offered = regs.transport.device.device_features() | VIRTIO_F_VERSION_1;
+    regs.write(REG_DEVICE_FEATURES_SEL, 0)?;
+    let low = regs.read(REG_DEVICE_FEATURES)?;
+    regs.write(REG_DEVICE_FEATURES_SEL, 1)?;
+    let high = regs.read(REG_DEVICE_FEATURES)?;
+    regs.write(REG_DEVICE_FEATURES_SEL, 2)?;
+    regs.expect(REG_DEVICE_FEATURES, 0)?;
+    match (high as u64) << 32 | low as u64 {
+        features if features == offered => Ok(()),
+        features => Err(format!(
+            "features {:#x} offered, expected {:#x}",
+            features, offered
+        )),
+    }
+}
+
+fn queue_select(regs: &Regs) -> Result<(), String> {
+    let num_queues = regs.transport.queues.len();
+    if num_queues > 0 {
+        regs.write(REG_QUEUE_SEL, 0)?;
+        regs.expect(REG_QUEUE_NUM_MAX, queue::QUEUE_SIZE_MAX as u32)?;
+    }
+    // A missing queue has no size and can't be made ready.
+    regs.write(REG_QUEUE_SEL, num_queues as u32)?;
+    regs.expect(REG_QUEUE_NUM_MAX, 0)?;
+    regs.write(REG_QUEUE_READY, 1)?;
+    regs.expect(REG_QUEUE_READY, 0)
+}
+
+fn negotiation(regs: &Regs) -> Result<(), String> {
+    let offered = regs.transport.device.device_features() | VIRTIO_F_VERSION_1;
+    if regs.negotiate(offered & !VIRTIO_F_VERSION_1)? {
+        return Err("legacy driver accepted".into());
+    }
+    let unknown = !offered & offered.wrapping_add(1);
+    if unknown != 0 && regs.negotiate(offered | unknown)? {
+        return Err(format!("unknown feature {:#x} accepted", unknown));
+    }
+    if !regs.negotiate(offered)? {
+        return Err("offered features refused".into());
+    }
+    Ok(())
+}
+
+fn queue_setup_and_reset(regs: &Regs) -> Result<(), String> {
+    if !regs.transport.queues.is_empty() {
+        regs.write(REG_QUEUE_SEL, 0)?;
+        regs.write(REG_QUEUE_NUM, u16::MAX as u32)?;
+        let size = regs.transport.with_queue(0, |q| q.size).unwrap_or(0);
+        if size != queue::QUEUE_SIZE_MAX {
+            return Err(format!("queue size {} not clamped", size));
+        }
+        regs.write(REG_QUEUE_READY, 1)?;
+        regs.expect(REG_QUEUE_READY, 1)?;
+    }
+    regs.write(REG_STATUS, 0)?;
+    regs.expect(REG_STATUS, 0)?;
+    regs.expect(REG_INTERRUPT_STATUS, 0)?;
+    if !regs.transport.queues.is_empty() {
+        regs.write(REG_QUEUE_SEL, 0)?;
+        regs.expect(REG_QUEUE_READY, 0)?;
+    }
+    Ok(())
+}
+
+fn outcome(result: Result<(), String>) -> CheckOutcome {
+    match result {
+        Ok(()) => CheckOutcome::Passed,
+        Err(why) => CheckOutcome::Failed(why),
+    }
+}
+
+/// Checks the transport of each virtio device of `vm`, whose vCPUs must be out of the guest, and
+/// appends the outcomes to `checks`.
+pub fn check_vm(vm: &VMRef, checks: &mut Vec<ConformanceCheck>) {
+    let transports = VIRTIO_DEVICES
+        .lock()
+        .get(&vm.id())
+        .cloned()
+        .unwrap_or_default();
+    for (idx, transport) in transports.iter().enumerate() {
+        let regs = Regs { vm, transport };
+        let mut check = |what: &str, outcome: CheckOutcome| {
+            checks.push(ConformanceCheck {
+                name: format!(
+                    "virtio{} (device {}): {}",
+                    idx,
+                    transport.device.device_id(),
+                    what
+                ),
+                outcome,
+            })
+        };
+        check("identity", outcome(identity(&regs)));
+        check("register width", outcome(register_width(&regs)));
+
+        let saved = transport.state.lock().clone();
+        check("device features", outcome(device_features(&regs)));
+        check("queue select", outcome(queue_select(&regs)));
+        if saved.status != 0 {
+            *transport.state.lock() = saved;
+            let skipped = || CheckOutcome::Skipped("initialized by the guest");
+            check("feature negotiation", skipped());
+            check("queue setup and reset", skipped());
+            continue;
+        }
+        check("feature negotiation", outcome(negotiation(&regs)));
+        check(
+            "queue setup and reset",
+            outcome(queue_setup_and_reset(&regs)),
+        );
+        // Leave the device as the driver will find it, whatever failed.
+        let _ = regs.write(REG_STATUS, 0);
+    }
+}
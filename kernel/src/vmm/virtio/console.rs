@@ -0,0 +1,166 @@
+//! Virtio-console devices printing to the hypervisor console.
+//!
+//! What the guest transmits is printed on the hypervisor console line by line, prefixed with the
+//! VM ID, as for the serial port of UEFI guests. The device has a single port and never receives
+//! anything: buffers made available in the receive queue stay there. A guest gets a device with a
+//! `[[virtio_console]]` entry in its VM config:
+//!
+//! ```toml
+//! [[virtio_console]]
+//! # Guest physical base of the virtio-mmio register page.
+//! base = 0x0a00_a000
+//! # Interrupt injected to the guest for used buffers.
+//! irq = 0x32
+//! ```
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use std::os::arceos::modules::axhal;
+
+use axerrno::{AxResult, ax_err_type};
+use spin::Mutex;
+
+use super::{VirtioDevice, VirtioMmio, register_device};
+use crate::vmm::{VMRef, conmux};
+use crate::vmm::memstat::{self, MemSubsystem};
+
+const VIRTIO_ID_CONSOLE: u32 = 3;
+
+const TX_QUEUE: usize = 1;
+
+/// Longest line printed at once, longer lines are split.
+const LINE_LEN: usize = 256;
+
+struct VirtioConsole {
+    vm_id: usize,
+    line: Mutex<Vec<u8>>,
+}
+
+impl VirtioConsole {
+    fn new(vm_id: usize) -> AxResult<Self> {
+        memstat::try_charge(MemSubsystem::Console, vm_id, LINE_LEN)?;
+        Ok(Self {
+            vm_id,
+            line: Mutex::new(Vec::with_capacity(LINE_LEN)),
+        })
+    }
+
+    fn put(&self, line: &mut Vec<u8>, byte: u8) {
+        match byte {
+            b'\r' => {}
+            b'\n' => self.print(line),
+            _ => {
+                line.push(byte);
+                if line.len() == LINE_LEN {
+                    self.print(line);
+                }
+            }
+        }
+    }
+
+    fn print(&self, line: &mut Vec<u8>) {
+        conmux::feed(self.vm_id, conmux::CONSOLE_SRC_VIRTIO, line);
+        let mut out = format!("[VM[{}]] ", self.vm_id).into_bytes();
+        out.extend_from_slice(line);
+        out.extend_from_slice(b"\r\n");
+        axhal::console::write_bytes(&out);
+        line.clear();
+    }
+}
+
+impl Drop for VirtioConsole {
+    fn drop(&mut self) {
+        let mut line = core::mem::take(self.line.get_mut());
+        if !line.is_empty() {
+            self.print(&mut line);
+        }
+        memstat::uncharge(MemSubsystem::Console, Some(self.vm_id), LINE_LEN);
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn device_features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn read_config(&self, _offset: usize) -> u8 {
+        // Neither the size nor multiple ports are offered.
+        0
+    }
+
+    fn queue_notify(&self, transport: &VirtioMmio, vm: &VMRef, queue: usize) {
+        if queue != TX_QUEUE {
+            return;
+        }
+        let mut completed = false;
+        let mut line = self.line.lock();
+        loop {
+            let chain = match transport.with_queue(TX_QUEUE, |q| q.pop_avail(vm)) {
+                Some(Ok(Some(chain))) => chain,
+                Some(Err(e)) => {
+                    warn!("VM[{}] virtio-console queue: {:?}", vm.id(), e);
+                    break;
+                }
+                _ => break,
+            };
+            if let Ok(data) = chain.read(vm, 0, LINE_LEN * 16) {
+                for byte in data {
+                    self.put(&mut line, byte);
+                }
+            }
+            if let Some(Err(e)) = transport.with_queue(TX_QUEUE, |q| q.push_used(vm, chain.head, 0))
+            {
+                warn!("VM[{}] virtio-console used ring: {:?}", vm.id(), e);
+                break;
+            }
+            completed = true;
+        }
+        drop(line);
+        if completed {
+            transport.notify_used(vm, TX_QUEUE);
+        }
+    }
+}
+
+/// Creates a virtio-console device of the VM, attached by `attach`.
+pub(super) fn add_virtio_console(
+    vm: &VMRef,
+    attach: impl FnOnce(Box<dyn VirtioDevice>) -> AxResult<Arc<VirtioMmio>>,
+) -> AxResult<Arc<VirtioMmio>> {
+    attach(Box::new(VirtioConsole::new(vm.id())?))
+}
+
+/// Creates the virtio-console devices described in the `[[virtio_console]]` array of `raw_cfg`.
+pub(super) fn setup_vm_virtio_console(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(entries) = raw_cfg.get("virtio_console").and_then(|v| v.as_array()) else {
+        return Ok(());
+    };
+    for entry in entries {
+        let get = |key: &str| {
+            entry
+                .get(key)
+                .and_then(|v| v.as_integer())
+                .map(|v| v as usize)
+                .ok_or_else(|| {
+                    ax_err_type!(
+                        InvalidInput,
+                        format!("virtio_console config: missing `{}`", key)
+                    )
+                })
+        };
+        let base = get("base")?;
+        let irq = get("irq")?;
+        add_virtio_console(vm, |device| register_device(vm, base, irq, device))?;
+        info!("VM[{}] virtio-console at {:#x}, irq {}", vm.id(), base, irq);
+    }
+    Ok(())
+}
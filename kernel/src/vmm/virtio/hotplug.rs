@@ -0,0 +1,546 @@
+//! Virtio devices plugged into and unplugged from running guests.
+//!
+//! A VM accepting hot-plugged devices has a window of guest physical pages for them, declared
+//! with a `[virtio_hotplug]` section in its VM config:
+//!
+//! ```toml
+//! [virtio_hotplug]
+//! # Guest physical base of the window, page aligned.
+//! base = 0x0a10_0000
+//! # Number of device slots, 8 by default and at most 32.
+//! slots = 4
+//! # Interrupt of the hot-plug controller, slot `n` raises `irq + 1 + n`.
+//! irq = 0x40
+//! ```
+//!
+//! The first page of the window is the hot-plug controller, the following ones the virtio-mmio
+//! register pages of the slots. An empty slot reads as a virtio-mmio device of ID 0, which drivers
+//! skip. Manager VMs (see [`crate::vmm::vmdef`]) plug and unplug devices with the
+//! [`HVC_VIRTIO_HOTPLUG`](crate::vmm::hvc::HVC_VIRTIO_HOTPLUG) hypercall (`HVirtioHotplug`):
+//! `args[0]` is the peer handle of the VM and `args[1]` the operation:
+//!
+//! - [`HOTPLUG_PLUG`]: `args[2]` and `args[3]` are the GPA and size of the definition of the
+//!   device, the keys of its entry in a VM config without `base` and `irq`, plus its `type`:
+//!   `"console"`, `"net"`, `"blk"` or `"rng"`. Returns the slot of the device.
+//! - [`HOTPLUG_UNPLUG`]: asks the guest to release the device of slot `args[2]`. The device is
+//!   removed once the guest ejects it, or at once if its driver never set it up.
+//! - [`HOTPLUG_FORCE_UNPLUG`]: removes the device of slot `args[2]` without waiting for the guest.
+//!
+//! A removed device is quiesced before its resources are released: its page reads as an empty
+//! slot again, so the guest can't notify it, its queues are reset and its backend is detached.
+//!
+//! The controller registers are 32-bit:
+//!
+//! | Offset | Name              | Access | Description                                            |
+//! |--------|-------------------|--------|--------------------------------------------------------|
+//! | 0x00   | `MAGIC`           | RO     | `"AXHP"`                                               |
+//! | 0x04   | `VERSION`         | RO     | Controller version, currently 1                        |
+//! | 0x08   | `SLOTS`           | RO     | Number of slots                                        |
+//! | 0x0c   | `PRESENT`         | RO     | Slots holding a device, one bit per slot               |
+//! | 0x10   | `EVENT`           | RW1C   | Pending [`HotplugEvent`]s, raising the controller irq  |
+//! | 0x14   | `UNPLUG_PENDING`  | RO     | Slots whose device the guest is asked to release       |
+//! | 0x18   | `EJECT`           | WO     | Slot whose device the guest released                   |
+//! | 0x1c   | `SLOT_SEL`        | RW     | Slot described by the registers below                  |
+//! | 0x20   | `SLOT_BASE_LO`    | RO     | Low half of the GPA of the register page of the slot   |
+//! | 0x24   | `SLOT_BASE_HI`    | RO     | High half of the GPA                                   |
+//! | 0x28   | `SLOT_IRQ`        | RO     | Interrupt of the slot                                  |
+//! | 0x2c   | `SLOT_DEVICE_ID`  | RO     | Virtio device ID in the slot, 0 if empty               |
+//! | 0x30   | `OVERLAY_SIZE`    | RO     | Size of the device tree overlay of the slot            |
+//! | 0x800  | `OVERLAY`         | RO     | Device tree overlay adding the node of the slot        |
+//!
+//! The overlay is a `virtio,mmio` node under `/`, for guests applying it to their live device
+//! tree instead of probing the slot pages themselves. There is none on x86_64. The services page
+//! of the VM (see [`crate::vmm::services`]) only describes the devices of its config.
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use axaddrspace::GuestPhysAddr;
+use axaddrspace::device::AccessWidth;
+use axerrno::{AxResult, ax_err, ax_err_type};
+use bitflags::bitflags;
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use super::{
+    VirtioDevice, VirtioMmio, blk, console, net, read_guest_bytes, rng, track_device,
+    untrack_device,
+};
+use crate::vmm::irq::IrqLine;
+use crate::vmm::mmio::{self, MmioTrapHandler};
+use crate::vmm::{VMRef, peers, vm_list, vmdef};
+
+/// Plugs a device, see the module documentation.
+pub const HOTPLUG_PLUG: u64 = 0;
+/// Asks the guest to release a device.
+pub const HOTPLUG_UNPLUG: u64 = 1;
+/// Removes a device without waiting for the guest.
+pub const HOTPLUG_FORCE_UNPLUG: u64 = 2;
+
+/// `MAGIC` of the controller, "AXHP".
+pub const HOTPLUG_MAGIC: u32 = u32::from_le_bytes(*b"AXHP");
+/// `VERSION` of the controller.
+pub const HOTPLUG_VERSION: u32 = 1;
+/// Maximum number of slots of a window.
+pub const MAX_HOTPLUG_SLOTS: usize = 32;
+/// Maximum size of a device definition.
+pub const MAX_DEVICE_DEFINITION_SIZE: usize = PAGE_SIZE_4K;
+
+/// Number of slots of a window without `slots`.
+pub const DEFAULT_HOTPLUG_SLOTS: usize = 8;
+
+const REG_MAGIC: usize = 0x00;
+const REG_VERSION: usize = 0x04;
+const REG_SLOTS: usize = 0x08;
+const REG_PRESENT: usize = 0x0c;
+const REG_EVENT: usize = 0x10;
+const REG_UNPLUG_PENDING: usize = 0x14;
+const REG_EJECT: usize = 0x18;
+const REG_SLOT_SEL: usize = 0x1c;
+const REG_SLOT_BASE_LO: usize = 0x20;
+const REG_SLOT_BASE_HI: usize = 0x24;
+const REG_SLOT_IRQ: usize = 0x28;
+const REG_SLOT_DEVICE_ID: usize = 0x2c;
+const REG_OVERLAY_SIZE: usize = 0x30;
+const REG_OVERLAY: usize = 0x800;
+
+/// Maximum size of the overlay of a slot.
+const MAX_OVERLAY_SIZE: usize = PAGE_SIZE_4K - REG_OVERLAY;
+
+bitflags! {
+    /// Events of the hot-plug controller, in its `EVENT` register.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct HotplugEvent: u32 {
+        /// A device was plugged.
+        const PLUGGED = 1 << 0;
+        /// The guest is asked to release a device, see `UNPLUG_PENDING`.
+        const UNPLUG_REQUESTED = 1 << 1;
+        /// A device was removed.
+        const UNPLUGGED = 1 << 2;
+    }
+}
+
+struct Slot {
+    transport: Arc<VirtioMmio>,
+    device_id: u32,
+    overlay: Vec<u8>,
+    unplug_pending: bool,
+}
+
+#[derive(Default)]
+struct ControllerState {
+    event: u32,
+    slot_sel: u32,
+    /// Slots whose device is being created, one bit per slot.
+    reserved: u32,
+}
+
+/// The hot-plug window of a VM.
+struct HotplugWindow {
+    vm_id: usize,
+    base: usize,
+    irq: usize,
+    controller_irq: IrqLine,
+    slots: Mutex<Vec<Option<Slot>>>,
+    state: Mutex<ControllerState>,
+}
+
+/// Hot-plug windows of the VMs with a `[virtio_hotplug]` section, indexed by VM ID.
+static WINDOWS: Mutex<BTreeMap<usize, Arc<HotplugWindow>>> = Mutex::new(BTreeMap::new());
+
+impl HotplugWindow {
+    fn slot_base(&self, slot: usize) -> usize {
+        self.base + (slot + 1) * PAGE_SIZE_4K
+    }
+
+    fn slot_irq(&self, slot: usize) -> usize {
+        self.irq + 1 + slot
+    }
+
+    fn signal(&self, event: HotplugEvent) {
+        self.state.lock().event |= event.bits();
+        self.controller_irq.raise();
+    }
+
+    fn transport(&self, slot: usize) -> Option<Arc<VirtioMmio>> {
+        self.slots.lock()[slot]
+            .as_ref()
+            .map(|slot| slot.transport.clone())
+    }
+
+    /// Removes the device of `slot`, quiescing it first.
+    fn remove(&self, slot: usize) -> AxResult {
+        let Some(removed) = self.slots.lock()[slot].take() else {
+            return ax_err!(NotFound, format!("hot-plug slot {} is empty", slot));
+        };
+        // The page of the slot reads as empty from now on, the guest can't reach the device.
+        removed.transport.reset();
+        removed.transport.device.detach();
+        untrack_device(self.vm_id, &removed.transport);
+        info!("VM[{}] hot-plug slot {} removed", self.vm_id, slot);
+        self.signal(HotplugEvent::UNPLUGGED);
+        Ok(())
+    }
+
+    fn read_controller(&self, reg: usize) -> u32 {
+        let slots = self.slots.lock();
+        let state = self.state.lock();
+        let bitmap = |f: fn(&Slot) -> bool| {
+            slots
+                .iter()
+                .enumerate()
+                .filter(|(_, slot)| slot.as_ref().is_some_and(f))
+                .fold(0u32, |bits, (i, _)| bits | 1 << i)
+        };
+        let sel = state.slot_sel as usize;
+        let selected = slots.get(sel).and_then(|slot| slot.as_ref());
+        match reg {
+            REG_MAGIC => HOTPLUG_MAGIC,
+            REG_VERSION => HOTPLUG_VERSION,
+            REG_SLOTS => slots.len() as u32,
+            REG_PRESENT => bitmap(|_| true),
+            REG_EVENT => state.event,
+            REG_UNPLUG_PENDING => bitmap(|slot| slot.unplug_pending),
+            REG_SLOT_SEL => state.slot_sel,
+            REG_SLOT_BASE_LO if sel < slots.len() => self.slot_base(sel) as u32,
+            REG_SLOT_BASE_HI if sel < slots.len() => (self.slot_base(sel) as u64 >> 32) as u32,
+            REG_SLOT_IRQ if sel < slots.len() => self.slot_irq(sel) as u32,
+            REG_SLOT_DEVICE_ID => selected.map_or(0, |slot| slot.device_id),
+            REG_OVERLAY_SIZE => selected.map_or(0, |slot| slot.overlay.len() as u32),
+            _ => 0,
+        }
+    }
+
+    fn read_overlay(&self, offset: usize, width: usize) -> usize {
+        let sel = self.state.lock().slot_sel as usize;
+        let slots = self.slots.lock();
+        let Some(Some(slot)) = slots.get(sel) else {
+            return 0;
+        };
+        (0..width)
+            .filter_map(|i| {
+                slot.overlay
+                    .get(offset + i)
+                    .map(|b| (*b as usize) << (i * 8))
+            })
+            .fold(0, |val, b| val | b)
+    }
+
+    fn write_controller(&self, reg: usize, val: u32) {
+        match reg {
+            REG_EVENT => self.state.lock().event &= !val,
+            REG_SLOT_SEL => self.state.lock().slot_sel = val,
+            REG_EJECT => {
+                let slot = val as usize;
+                let pending = self
+                    .slots
+                    .lock()
+                    .get(slot)
+                    .and_then(|slot| slot.as_ref())
+                    .is_some_and(|slot| slot.unplug_pending);
+                if pending {
+                    let _ = self.remove(slot);
+                } else {
+                    warn!(
+                        "VM[{}] ejected hot-plug slot {} without unplug request",
+                        self.vm_id, slot
+                    );
+                }
+            }
+            reg => trace!(
+                "VM[{}] hot-plug controller write to {:#x} ignored",
+                self.vm_id, reg
+            ),
+        }
+    }
+}
+
+impl MmioTrapHandler for HotplugWindow {
+    fn handle_read(&self, vm: &VMRef, addr: GuestPhysAddr, width: AccessWidth) -> AxResult<usize> {
+        let offset = addr.as_usize() - self.base;
+        let page = offset / PAGE_SIZE_4K;
+        if page > 0 {
+            // An empty slot is a device of ID 0.
+            return match self.transport(page - 1) {
+                Some(transport) => transport.handle_read(vm, addr, width),
+                None => Ok(0),
+            };
+        }
+        let reg = offset % PAGE_SIZE_4K;
+        if reg >= REG_OVERLAY {
+            return Ok(self.read_overlay(reg - REG_OVERLAY, width.size()));
+        }
+        if !matches!(width, AccessWidth::Dword) {
+            return ax_err!(InvalidInput, "hot-plug controller registers are 32-bit");
+        }
+        Ok(self.read_controller(reg) as usize)
+    }
+
+    fn handle_write(
+        &self,
+        vm: &VMRef,
+        addr: GuestPhysAddr,
+        width: AccessWidth,
+        val: usize,
+    ) -> AxResult {
+        let offset = addr.as_usize() - self.base;
+        let page = offset / PAGE_SIZE_4K;
+        if page > 0 {
+            return match self.transport(page - 1) {
+                Some(transport) => transport.handle_write(vm, addr, width, val),
+                None => Ok(()),
+            };
+        }
+        if !matches!(width, AccessWidth::Dword) {
+            return ax_err!(InvalidInput, "hot-plug controller registers are 32-bit");
+        }
+        self.write_controller(offset % PAGE_SIZE_4K, val as u32);
+        Ok(())
+    }
+}
+
+/// Sets up the hot-plug window of the VM if `raw_cfg` has a `[virtio_hotplug]` section.
+pub(super) fn setup_vm_virtio_hotplug(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("virtio_hotplug").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let get = |key: &str, default: Option<usize>| {
+        match cfg.get(key) {
+            None => default,
+            Some(v) => v.as_integer().filter(|v| *v >= 0).map(|v| v as usize),
+        }
+        .ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                format!("virtio_hotplug config: invalid or missing `{}`", key)
+            )
+        })
+    };
+    let base = get("base", None)?;
+    let slots = get("slots", Some(DEFAULT_HOTPLUG_SLOTS))?;
+    let irq = get("irq", None)?;
+    if base % PAGE_SIZE_4K != 0 {
+        return ax_err!(
+            InvalidInput,
+            "virtio_hotplug config: `base` must be page-aligned"
+        );
+    }
+    if slots == 0 || slots > MAX_HOTPLUG_SLOTS {
+        return ax_err!(
+            InvalidInput,
+            format!(
+                "virtio_hotplug config: `slots` must be 1 to {}",
+                MAX_HOTPLUG_SLOTS
+            )
+        );
+    }
+
+    let window = Arc::new(HotplugWindow {
+        vm_id: vm.id(),
+        base,
+        irq,
+        controller_irq: IrqLine::new(vm.id(), irq),
+        slots: Mutex::new((0..slots).map(|_| None).collect()),
+        state: Mutex::new(ControllerState::default()),
+    });
+    mmio::register_trap(
+        vm.id(),
+        base.into(),
+        (slots + 1) * PAGE_SIZE_4K,
+        window.clone(),
+    )?;
+    WINDOWS.lock().insert(vm.id(), window);
+    info!(
+        "VM[{}] virtio hot-plug window at {:#x}, {} slots, irq {}",
+        vm.id(),
+        base,
+        slots,
+        irq
+    );
+    Ok(())
+}
+
+/// Drops the hot-plug window of a VM, its devices are detached with the other virtio devices.
+pub(super) fn teardown_vm_virtio_hotplug(vm_id: usize) {
+    WINDOWS.lock().remove(&vm_id);
+}
+
+/// Returns the device tree overlay adding a `virtio,mmio` node for the slot at `base`.
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+fn slot_overlay(base: usize, irq: usize) -> Vec<u8> {
+    use vm_fdt::FdtWriter;
+
+    let mut fdt = FdtWriter::new().unwrap();
+    let root = fdt.begin_node("").unwrap();
+    let fragment = fdt.begin_node("fragment@0").unwrap();
+    fdt.property_string("target-path", "/").unwrap();
+    let overlay = fdt.begin_node("__overlay__").unwrap();
+    let node = fdt.begin_node(&format!("virtio_mmio@{:x}", base)).unwrap();
+    fdt.property_string("compatible", "virtio,mmio").unwrap();
+    fdt.property_array_u64("reg", &[base as u64, PAGE_SIZE_4K as u64])
+        .unwrap();
+    // A GIC SPI, rising edge
+    #[cfg(target_arch = "aarch64")]
+    fdt.property_array_u32("interrupts", &[0, irq as u32 - 32, 1])
+        .unwrap();
+    // A PLIC source
+    #[cfg(target_arch = "riscv64")]
+    fdt.property_u32("interrupts", irq as u32).unwrap();
+    fdt.property_null("dma-coherent").unwrap();
+    fdt.end_node(node).unwrap();
+    fdt.end_node(overlay).unwrap();
+    fdt.end_node(fragment).unwrap();
+    fdt.end_node(root).unwrap();
+    fdt.finish().unwrap()
+}
+
+#[cfg(target_arch = "x86_64")]
+fn slot_overlay(_base: usize, _irq: usize) -> Vec<u8> {
+    Vec::new()
+}
+
+fn plug(vm: &VMRef, window: &HotplugWindow, definition: &str) -> AxResult<usize> {
+    let entry: toml::Value = definition
+        .parse::<toml::Table>()
+        .map(toml::Value::Table)
+        .map_err(|e| ax_err_type!(InvalidInput, format!("invalid device definition: {}", e)))?;
+    let kind = entry
+        .get("type")
+        .and_then(|v| v.as_str())
+        .map(String::from)
+        .ok_or_else(|| ax_err_type!(InvalidInput, "device definition: missing `type`"))?;
+
+    // The slot is reserved while the device is created, so that it's taken only once.
+    let slot = {
+        let slots = window.slots.lock();
+        let mut state = window.state.lock();
+        let Some(slot) =
+            (0..slots.len()).find(|i| slots[*i].is_none() && state.reserved & 1 << i == 0)
+        else {
+            return ax_err!(
+                NoMemory,
+                format!("VM[{}] has no free hot-plug slot", vm.id())
+            );
+        };
+        state.reserved |= 1 << slot;
+        slot
+    };
+    let irq = window.slot_irq(slot);
+    let mut overlay = slot_overlay(window.slot_base(slot), irq);
+    if overlay.len() > MAX_OVERLAY_SIZE {
+        warn!(
+            "VM[{}] hot-plug slot {} overlay of {} bytes dropped",
+            vm.id(),
+            slot,
+            overlay.len()
+        );
+        overlay.clear();
+    }
+    let attach = |device: Box<dyn VirtioDevice>| {
+        let device_id = device.device_id();
+        let transport = Arc::new(VirtioMmio::new(vm.id(), irq, device));
+        track_device(vm.id(), transport.clone());
+        window.slots.lock()[slot] = Some(Slot {
+            transport: transport.clone(),
+            device_id,
+            overlay,
+            unplug_pending: false,
+        });
+        Ok(transport)
+    };
+    let result = match kind.as_str() {
+        "console" => console::add_virtio_console(vm, attach),
+        "net" => net::add_virtio_net(vm, &entry, 0x80 | slot, attach),
+        "blk" => blk::add_virtio_blk(vm, &entry, &format!("-hp{}", slot), attach),
+        "rng" => rng::add_virtio_rng(attach),
+        kind => ax_err!(
+            InvalidInput,
+            format!("device definition: unknown `type` {:?}", kind)
+        ),
+    };
+    window.state.lock().reserved &= !(1 << slot);
+    if let Err(err) = result {
+        // The device may have been attached before its setup failed.
+        if let Some(removed) = window.slots.lock()[slot].take() {
+            removed.transport.device.detach();
+            untrack_device(vm.id(), &removed.transport);
+        }
+        return Err(err);
+    }
+
+    info!(
+        "VM[{}] virtio-{} plugged in hot-plug slot {} at {:#x}, irq {}",
+        vm.id(),
+        kind,
+        slot,
+        window.slot_base(slot),
+        irq
+    );
+    window.signal(HotplugEvent::PLUGGED);
+    Ok(slot)
+}
+
+fn unplug(window: &HotplugWindow, slot: usize, force: bool) -> AxResult {
+    let driver_ok = {
+        let mut slots = window.slots.lock();
+        let Some(Some(entry)) = slots.get_mut(slot) else {
+            return ax_err!(NotFound, format!("hot-plug slot {} is empty", slot));
+        };
+        if !force {
+            entry.unplug_pending = true;
+        }
+        entry.transport.driver_ok()
+    };
+    if force || !driver_ok {
+        return window.remove(slot);
+    }
+    info!(
+        "VM[{}] asked to release hot-plug slot {}",
+        window.vm_id, slot
+    );
+    window.signal(HotplugEvent::UNPLUG_REQUESTED);
+    Ok(())
+}
+
+/// Handles the `HVirtioHotplug` hypercall of the manager VM `vm`, returns the slot of a plugged
+/// device.
+pub fn handle_hotplug(vm: &VMRef, args: [u64; 6]) -> AxResult<usize> {
+    if !vmdef::is_manager(vm.id()) {
+        return ax_err!(
+            PermissionDenied,
+            format!("VM[{}] is not allowed to hot-plug devices", vm.id())
+        );
+    }
+    let target_id = peers::resolve(vm.id(), args[0] as usize)?;
+    let Some(target) = vm_list::get_vm_by_id(target_id) else {
+        return ax_err!(NotFound, format!("VM[{}] not found", target_id));
+    };
+    let Some(window) = WINDOWS.lock().get(&target_id).cloned() else {
+        return ax_err!(
+            Unsupported,
+            format!("VM[{}] has no hot-plug window", target_id)
+        );
+    };
+    match args[1] {
+        HOTPLUG_PLUG => {
+            let size = args[3] as usize;
+            if size == 0 || size > MAX_DEVICE_DEFINITION_SIZE {
+                return ax_err!(
+                    InvalidInput,
+                    format!("invalid device definition size {:#x}", size)
+                );
+            }
+            let mut buf = vec![0u8; size];
+            read_guest_bytes(vm, GuestPhysAddr::from_usize(args[2] as usize), &mut buf)?;
+            let definition = String::from_utf8(buf)
+                .map_err(|_| ax_err_type!(InvalidInput, "device definition is not valid UTF-8"))?;
+            plug(&target, &window, &definition)
+        }
+        op @ (HOTPLUG_UNPLUG | HOTPLUG_FORCE_UNPLUG) => {
+            unplug(&window, args[2] as usize, op == HOTPLUG_FORCE_UNPLUG).map(|_| 0)
+        }
+        op => ax_err!(InvalidInput, format!("invalid hot-plug operation {}", op)),
+    }
+}
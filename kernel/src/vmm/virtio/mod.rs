@@ -0,0 +1,412 @@
+//! Virtio devices emulated by the hypervisor, over the virtio-mmio transport (version 2).
+//!
+//! Each device occupies one trapped page of guest physical address space and raises a single
+//! interrupt. The guest finds the devices through its device tree, which must describe them as
+//! `virtio,mmio` nodes at the configured addresses. Devices are declared per type in the VM
+//! config, see [`net`] for virtio-net, [`blk`] for virtio-blk, [`vsock`] for virtio-vsock,
+//! [`console`] for virtio-console and [`rng`] for virtio-rng. Devices can also be plugged into a
+//! running VM, see [`hotplug`], or served by a driver VM instead of the hypervisor, see [`vhost`].
+//!
+//! Devices may complete requests outside of the vCPU tasks of their VM, on the vCPU of a peer VM
+//! or on a worker task, so they raise their interrupt through an [`IrqLine`].
+mod blk;
+mod conformance;
+mod console;
+mod hotplug;
+mod net;
+mod queue;
+mod rng;
+mod switch;
+mod vhost;
+mod vsock;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use axaddrspace::GuestPhysAddr;
+use axaddrspace::device::AccessWidth;
+use axerrno::{AxResult, ax_err};
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::vmm::irq::IrqLine;
+use crate::vmm::mmio::{self, MmioTrapHandler};
+use crate::vmm::{VMRef, dirty, lazymem};
+
+use queue::VirtQueue;
+
+pub use blk::BlkIoStats;
+pub use conformance::check_vm as check_conformance;
+pub use hotplug::{
+    DEFAULT_HOTPLUG_SLOTS, HOTPLUG_FORCE_UNPLUG, HOTPLUG_MAGIC, HOTPLUG_PLUG, HOTPLUG_UNPLUG,
+    HOTPLUG_VERSION, HotplugEvent, MAX_HOTPLUG_SLOTS, handle_hotplug,
+};
+pub use switch::PortStats;
+pub use vhost::{
+    HVC_SERVICE as VHOST_HVC_SERVICE, MAX_VHOST_MEM_REGIONS, MAX_VHOST_QUEUES, VHOST_ATTACH,
+    VHOST_CONFIG_OFFSET, VHOST_CONFIG_SIZE, VHOST_DETACH, VHOST_EVENT_GONE, VHOST_EVENT_STATUS,
+    VHOST_MAGIC, VHOST_MEM_OFFSET, VHOST_NOTIFY, VHOST_QUEUES_OFFSET, VHOST_VERSION, VhostHeader,
+    VhostMemRegion, VhostQueue, VhostStats, has_vhost_backend, vhost_stats,
+};
+pub use vsock::default_cid;
+
+const MAGIC: u32 = u32::from_le_bytes(*b"virt");
+const VERSION: u32 = 2;
+const VENDOR_ID: u32 = u32::from_le_bytes(*b"AXVS");
+
+const REG_MAGIC: usize = 0x000;
+const REG_VERSION: usize = 0x004;
+const REG_DEVICE_ID: usize = 0x008;
+const REG_VENDOR_ID: usize = 0x00c;
+const REG_DEVICE_FEATURES: usize = 0x010;
+const REG_DEVICE_FEATURES_SEL: usize = 0x014;
+const REG_DRIVER_FEATURES: usize = 0x020;
+const REG_DRIVER_FEATURES_SEL: usize = 0x024;
+const REG_QUEUE_SEL: usize = 0x030;
+const REG_QUEUE_NUM_MAX: usize = 0x034;
+const REG_QUEUE_NUM: usize = 0x038;
+const REG_QUEUE_READY: usize = 0x044;
+const REG_QUEUE_NOTIFY: usize = 0x050;
+const REG_INTERRUPT_STATUS: usize = 0x060;
+const REG_INTERRUPT_ACK: usize = 0x064;
+const REG_STATUS: usize = 0x070;
+const REG_QUEUE_DESC_LOW: usize = 0x080;
+const REG_QUEUE_DESC_HIGH: usize = 0x084;
+const REG_QUEUE_DRIVER_LOW: usize = 0x090;
+const REG_QUEUE_DRIVER_HIGH: usize = 0x094;
+const REG_QUEUE_DEVICE_LOW: usize = 0x0a0;
+const REG_QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const REG_CONFIG_GENERATION: usize = 0x0fc;
+const REG_CONFIG: usize = 0x100;
+
+/// Interrupt status bit of used buffer notifications.
+const INT_USED_BUFFER: u32 = 1 << 0;
+
+const STATUS_FEATURES_OK: u32 = 1 << 3;
+const STATUS_DRIVER_OK: u32 = 1 << 2;
+
+/// Feature bit of virtio 1.0 compliance, required by the mmio transport version 2.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// The device-specific part of a virtio device.
+pub trait VirtioDevice: Send + Sync {
+    /// The virtio device ID.
+    fn device_id(&self) -> u32;
+
+    /// Device feature bits, [`VIRTIO_F_VERSION_1`] is always offered by the transport.
+    fn device_features(&self) -> u64;
+
+    /// Number of virtqueues of the device.
+    fn num_queues(&self) -> usize;
+
+    /// Reads a byte of the device configuration space.
+    fn read_config(&self, offset: usize) -> u8;
+
+    /// Writes a byte of the device configuration space.
+    fn write_config(&self, _offset: usize, _val: u8) {}
+
+    /// Handles a notification of the driver for queue `queue`.
+    fn queue_notify(&self, transport: &VirtioMmio, vm: &VMRef, queue: usize);
+
+    /// Called when the driver changed the device status to `status`, 0 for a reset.
+    fn status_changed(&self, _transport: &VirtioMmio, _status: u32) {}
+
+    /// Releases the resources shared with other VMs, called when the VM is destroyed.
+    fn detach(&self) {}
+}
+
+#[derive(Clone, Default)]
+struct TransportState {
+    status: u32,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    interrupt_status: u32,
+}
+
+/// A virtio-mmio transport and the device behind it.
+pub struct VirtioMmio {
+    vm_id: usize,
+    irq: IrqLine,
+    device: Box<dyn VirtioDevice>,
+    state: Mutex<TransportState>,
+    queues: Vec<Mutex<VirtQueue>>,
+}
+
+impl VirtioMmio {
+    fn new(vm_id: usize, irq: usize, device: Box<dyn VirtioDevice>) -> Self {
+        let queues = (0..device.num_queues())
+            .map(|_| Mutex::new(VirtQueue::default()))
+            .collect();
+        Self {
+            vm_id,
+            irq: IrqLine::new(vm_id, irq),
+            device,
+            state: Mutex::new(TransportState::default()),
+            queues,
+        }
+    }
+
+    /// ID of the VM owning the device.
+    pub fn vm_id(&self) -> usize {
+        self.vm_id
+    }
+
+    /// Whether the driver has completed the initialization of the device.
+    pub fn driver_ok(&self) -> bool {
+        self.state.lock().status & STATUS_DRIVER_OK != 0
+    }
+
+    /// Runs `f` on queue `idx`, returns `None` if there is no such queue.
+    pub fn with_queue<R>(&self, idx: usize, f: impl FnOnce(&mut VirtQueue) -> R) -> Option<R> {
+        self.queues.get(idx).map(|q| f(&mut q.lock()))
+    }
+
+    /// Signals used buffers in queue `idx` to the driver, unless it suppressed the interrupt.
+    pub fn notify_used(&self, vm: &VMRef, idx: usize) {
+        if self.with_queue(idx, |q| q.needs_interrupt(vm)) != Some(true) {
+            return;
+        }
+        self.state.lock().interrupt_status |= INT_USED_BUFFER;
+        self.irq.raise();
+    }
+
+    fn reset(&self) {
+        *self.state.lock() = TransportState::default();
+        for queue in &self.queues {
+            queue.lock().reset();
+        }
+    }
+
+    fn read_reg(&self, reg: usize) -> u32 {
+        let state = self.state.lock();
+        let with_sel_queue = |f: fn(&VirtQueue) -> u32| {
+            self.with_queue(state.queue_sel as usize, |q| f(q))
+                .unwrap_or(0)
+        };
+        match reg {
+            REG_MAGIC => MAGIC,
+            REG_VERSION => VERSION,
+            REG_DEVICE_ID => self.device.device_id(),
+            REG_VENDOR_ID => VENDOR_ID,
+            REG_DEVICE_FEATURES => {
+                let features = self.device.device_features() | VIRTIO_F_VERSION_1;
+                match state.device_features_sel {
+                    0 => features as u32,
+                    1 => (features >> 32) as u32,
+                    _ => 0,
+                }
+            }
+            REG_QUEUE_NUM_MAX => with_sel_queue(|_| queue::QUEUE_SIZE_MAX as u32),
+            REG_QUEUE_READY => with_sel_queue(|q| q.ready as u32),
+            REG_INTERRUPT_STATUS => state.interrupt_status,
+            REG_STATUS => state.status,
+            // The configuration space never changes under the driver.
+            REG_CONFIG_GENERATION => 0,
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&self, vm: &VMRef, reg: usize, val: u32) {
+        let mut state = self.state.lock();
+        let queue_sel = state.queue_sel as usize;
+        let set_low = |v: &mut u64| *v = (*v & !0xffff_ffff) | val as u64;
+        let set_high = |v: &mut u64| *v = (*v & 0xffff_ffff) | (val as u64) << 32;
+        match reg {
+            REG_DEVICE_FEATURES_SEL => state.device_features_sel = val,
+            REG_DRIVER_FEATURES => match state.driver_features_sel {
+                0 => set_low(&mut state.driver_features),
+                1 => set_high(&mut state.driver_features),
+                _ => {}
+            },
+            REG_DRIVER_FEATURES_SEL => state.driver_features_sel = val,
+            REG_QUEUE_SEL => state.queue_sel = val,
+            REG_QUEUE_NUM => {
+                self.with_queue(queue_sel, |q| {
+                    q.size = (val as u16).min(queue::QUEUE_SIZE_MAX)
+                });
+            }
+            REG_QUEUE_READY => {
+                self.with_queue(queue_sel, |q| q.ready = val & 1 != 0);
+            }
+            REG_QUEUE_DESC_LOW => {
+                self.with_queue(queue_sel, |q| set_low(&mut q.desc));
+            }
+            REG_QUEUE_DESC_HIGH => {
+                self.with_queue(queue_sel, |q| set_high(&mut q.desc));
+            }
+            REG_QUEUE_DRIVER_LOW => {
+                self.with_queue(queue_sel, |q| set_low(&mut q.driver));
+            }
+            REG_QUEUE_DRIVER_HIGH => {
+                self.with_queue(queue_sel, |q| set_high(&mut q.driver));
+            }
+            REG_QUEUE_DEVICE_LOW => {
+                self.with_queue(queue_sel, |q| set_low(&mut q.device));
+            }
+            REG_QUEUE_DEVICE_HIGH => {
+                self.with_queue(queue_sel, |q| set_high(&mut q.device));
+            }
+            REG_QUEUE_NOTIFY => {
+                // The device may raise interrupts while handling the notification.
+                drop(state);
+                self.device.queue_notify(self, vm, val as usize);
+            }
+            REG_INTERRUPT_ACK => state.interrupt_status &= !val,
+            REG_STATUS if val == 0 => {
+                drop(state);
+                self.reset();
+                self.device.status_changed(self, 0);
+            }
+            REG_STATUS => {
+                // Refuse the features if the driver accepted unknown ones or is a legacy one.
+                let offered = self.device.device_features() | VIRTIO_F_VERSION_1;
+                let accepted = state.driver_features;
+                state.status = if accepted & !offered != 0 || accepted & VIRTIO_F_VERSION_1 == 0 {
+                    val & !STATUS_FEATURES_OK
+                } else {
+                    val
+                };
+                let status = state.status;
+                drop(state);
+                self.device.status_changed(self, status);
+            }
+            _ => trace!("VM[{}] virtio write to {:#x} ignored", self.vm_id, reg),
+        }
+    }
+}
+
+impl MmioTrapHandler for VirtioMmio {
+    fn handle_read(&self, _vm: &VMRef, addr: GuestPhysAddr, width: AccessWidth) -> AxResult<usize> {
+        let offset = addr.as_usize() & (PAGE_SIZE_4K - 1);
+        if offset >= REG_CONFIG {
+            let mut val = 0;
+            for i in 0..width.size() {
+                val |= (self.device.read_config(offset - REG_CONFIG + i) as usize) << (i * 8);
+            }
+            return Ok(val);
+        }
+        if !matches!(width, AccessWidth::Dword) {
+            return ax_err!(InvalidInput, "virtio-mmio registers are 32-bit");
+        }
+        Ok(self.read_reg(offset) as usize)
+    }
+
+    fn handle_write(
+        &self,
+        vm: &VMRef,
+        addr: GuestPhysAddr,
+        width: AccessWidth,
+        val: usize,
+    ) -> AxResult {
+        let offset = addr.as_usize() & (PAGE_SIZE_4K - 1);
+        if offset >= REG_CONFIG {
+            for i in 0..width.size() {
+                self.device
+                    .write_config(offset - REG_CONFIG + i, (val >> (i * 8)) as u8);
+            }
+            return Ok(());
+        }
+        if !matches!(width, AccessWidth::Dword) {
+            return ax_err!(InvalidInput, "virtio-mmio registers are 32-bit");
+        }
+        self.write_reg(vm, offset, val as u32);
+        Ok(())
+    }
+}
+
+/// Virtio devices of all VMs, indexed by VM ID.
+static VIRTIO_DEVICES: Mutex<BTreeMap<usize, Vec<Arc<VirtioMmio>>>> = Mutex::new(BTreeMap::new());
+
+/// Creates a virtio-mmio device at the guest physical page `base` of the VM.
+fn register_device(
+    vm: &VMRef,
+    base: usize,
+    irq: usize,
+    device: Box<dyn VirtioDevice>,
+) -> AxResult<Arc<VirtioMmio>> {
+    if base % PAGE_SIZE_4K != 0 {
+        return ax_err!(InvalidInput, "virtio config: `base` must be page-aligned");
+    }
+    let transport = Arc::new(VirtioMmio::new(vm.id(), irq, device));
+    mmio::register_trap(vm.id(), base.into(), PAGE_SIZE_4K, transport.clone())?;
+    track_device(vm.id(), transport.clone());
+    Ok(transport)
+}
+
+/// Adds a device to the virtio devices of VM `vm_id`, whose guest accesses are dispatched by the
+/// caller.
+fn track_device(vm_id: usize, transport: Arc<VirtioMmio>) {
+    VIRTIO_DEVICES
+        .lock()
+        .entry(vm_id)
+        .or_default()
+        .push(transport);
+}
+
+/// Removes a device from the virtio devices of VM `vm_id`, once detached.
+fn untrack_device(vm_id: usize, transport: &Arc<VirtioMmio>) {
+    if let Some(devices) = VIRTIO_DEVICES.lock().get_mut(&vm_id) {
+        devices.retain(|device| !Arc::ptr_eq(device, transport));
+    }
+}
+
+/// Creates the virtio devices described in `raw_cfg` for the VM.
+pub fn setup_vm_virtio_devices(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    net::setup_vm_virtio_net(vm, raw_cfg)?;
+    blk::setup_vm_virtio_blk(vm, raw_cfg)?;
+    vsock::setup_vm_virtio_vsock(vm, raw_cfg)?;
+    console::setup_vm_virtio_console(vm, raw_cfg)?;
+    rng::setup_vm_virtio_rng(vm, raw_cfg)?;
+    vhost::setup_vm_virtio_vhost(vm, raw_cfg)?;
+    hotplug::setup_vm_virtio_hotplug(vm, raw_cfg)
+}
+
+/// Removes the virtio devices of a VM, called when the VM is destroyed.
+pub fn teardown_vm_virtio_devices(vm_id: usize) {
+    hotplug::teardown_vm_virtio_hotplug(vm_id);
+    for transport in VIRTIO_DEVICES.lock().remove(&vm_id).unwrap_or_default() {
+        transport.device.detach();
+    }
+    vhost::teardown_vm_vhost(vm_id);
+    blk::teardown_vm_virtio_blk(vm_id);
+}
+
+/// Returns the switch name and port counters of each virtio-net device of a VM.
+pub fn net_stats(vm_id: usize) -> Vec<(String, PortStats)> {
+    net::vm_net_stats(vm_id)
+}
+
+/// Returns the block I/O counters of a VM, if it has virtio-blk devices.
+pub fn blk_io_stats(vm_id: usize) -> Option<BlkIoStats> {
+    blk::vm_blk_io_stats(vm_id)
+}
+
+/// Copies guest memory at `gpa` into `buf`.
+fn read_guest_bytes(vm: &VMRef, gpa: GuestPhysAddr, buf: &mut [u8]) -> AxResult {
+    lazymem::populate(vm, gpa, buf.len())?;
+    let mut pos = 0;
+    for region in vm.get_image_load_region(gpa, buf.len())? {
+        let len = region.len().min(buf.len() - pos);
+        buf[pos..pos + len].copy_from_slice(&region[..len]);
+        pos += len;
+    }
+    Ok(())
+}
+
+/// Copies `data` into guest memory at `gpa`.
+fn write_guest_bytes(vm: &VMRef, gpa: GuestPhysAddr, data: &[u8]) -> AxResult {
+    lazymem::populate(vm, gpa, data.len())?;
+    dirty::mark(vm.id(), gpa, data.len());
+    let mut pos = 0;
+    for region in vm.get_image_load_region(gpa, data.len())? {
+        let len = region.len().min(data.len() - pos);
+        region[..len].copy_from_slice(&data[pos..pos + len]);
+        pos += len;
+    }
+    Ok(())
+}
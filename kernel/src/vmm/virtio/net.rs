@@ -0,0 +1,264 @@
+//! Virtio-net devices bridged between VMs.
+//!
+//! Frames transmitted by a guest are forwarded by a hypervisor-internal learning switch into the
+//! receive queues of the guests attached to the same switch, no physical NIC is involved. A guest
+//! gets a device with a `[[virtio_net]]` entry in its VM config:
+//!
+//! ```toml
+//! [[virtio_net]]
+//! # Guest physical base of the virtio-mmio register page.
+//! base = 0x0a00_8000
+//! # Interrupt injected to the guest for used buffers.
+//! irq = 0x30
+//! # MAC address, defaults to 52:54:00 followed by the VM ID and the device index. Clones keep
+//! # the first three bytes only, see `crate::vmm::identity`.
+//! mac = "52:54:00:12:34:56"
+//! # The switch the device is plugged into, devices on the same switch reach each other.
+//! bridge = "br0"
+//! ```
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err_type};
+
+use super::switch::{self, MacAddr, PortStats, Switch};
+use super::{VirtioDevice, VirtioMmio, register_device};
+use crate::vmm::{VMRef, identity, vm_list};
+
+const VIRTIO_ID_NET: u32 = 1;
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+/// Size of `struct virtio_net_hdr` with `VIRTIO_F_VERSION_1`.
+const NET_HDR_LEN: usize = 12;
+/// Offset of `num_buffers` in the header.
+const NET_HDR_NUM_BUFFERS: usize = 10;
+/// Maximum Ethernet frame length without the FCS (1500 bytes MTU).
+const MAX_FRAME_LEN: usize = 1514;
+
+const DEFAULT_BRIDGE: &str = "br0";
+
+struct VirtioNet {
+    mac: MacAddr,
+    switch: Arc<Switch>,
+    /// Port ID on the switch.
+    port: usize,
+}
+
+impl VirtioNet {
+    /// Pops all frames from the transmit queue and forwards them through the switch.
+    fn transmit(&self, transport: &VirtioMmio, vm: &VMRef) {
+        let mut frames = Vec::new();
+        transport.with_queue(TX_QUEUE, |q| {
+            loop {
+                match q.pop_avail(vm) {
+                    Ok(Some(chain)) => {
+                        match chain.read(vm, NET_HDR_LEN, MAX_FRAME_LEN) {
+                            Ok(frame) => frames.push(frame),
+                            Err(e) => warn!("VM[{}] virtio-net bad TX buffer: {:?}", vm.id(), e),
+                        }
+                        if let Err(e) = q.push_used(vm, chain.head, 0) {
+                            warn!("VM[{}] virtio-net TX used ring: {:?}", vm.id(), e);
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("VM[{}] virtio-net TX queue: {:?}", vm.id(), e);
+                        break;
+                    }
+                }
+            }
+        });
+        if frames.is_empty() {
+            return;
+        }
+        transport.notify_used(vm, TX_QUEUE);
+
+        for frame in &frames {
+            self.switch.forward(self.port, frame, receive);
+        }
+    }
+}
+
+/// Delivers a frame into the receive queue of `transport`, returns `false` if it's dropped.
+fn receive(transport: &VirtioMmio, frame: &[u8]) -> bool {
+    if !transport.driver_ok() {
+        return false;
+    }
+    let Some(vm) = vm_list::get_vm_by_id(transport.vm_id()) else {
+        return false;
+    };
+
+    let mut packet = vec![0u8; NET_HDR_LEN + frame.len()];
+    packet[NET_HDR_NUM_BUFFERS..NET_HDR_LEN].copy_from_slice(&1u16.to_le_bytes());
+    packet[NET_HDR_LEN..].copy_from_slice(frame);
+
+    let delivered = transport.with_queue(RX_QUEUE, |q| {
+        let Ok(Some(chain)) = q.pop_avail(&vm) else {
+            return false;
+        };
+        // Frames not fitting the buffer are truncated by `write`, drop them instead.
+        let written = if chain.writable_len() >= packet.len() {
+            chain.write(&vm, &packet).unwrap_or(0)
+        } else {
+            0
+        };
+        q.push_used(&vm, chain.head, written as u32).is_ok() && written > 0
+    });
+    if delivered == Some(true) {
+        transport.notify_used(&vm, RX_QUEUE);
+        true
+    } else {
+        false
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_NET
+    }
+
+    fn device_features(&self) -> u64 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn read_config(&self, offset: usize) -> u8 {
+        match offset {
+            0..6 => self.mac[offset],
+            6..8 => VIRTIO_NET_S_LINK_UP.to_le_bytes()[offset - 6],
+            _ => 0,
+        }
+    }
+
+    fn queue_notify(&self, transport: &VirtioMmio, vm: &VMRef, queue: usize) {
+        match queue {
+            TX_QUEUE => self.transmit(transport, vm),
+            // Receive buffers are consumed as frames arrive, frames arriving while the queue is
+            // empty are dropped.
+            _ => {}
+        }
+    }
+
+    fn detach(&self) {
+        self.switch.remove_port(self.port);
+    }
+}
+
+fn parse_mac(s: &str) -> Option<MacAddr> {
+    let mut mac = [0u8; 6];
+    let mut parts = s.split(':');
+    for byte in mac.iter_mut() {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    parts.next().is_none().then_some(mac)
+}
+
+/// Creates a virtio-net device of the VM from its config `entry`, attached by `attach`. `idx`
+/// makes its default MAC address unique in the VM.
+pub(super) fn add_virtio_net(
+    vm: &VMRef,
+    entry: &toml::Value,
+    idx: usize,
+    attach: impl FnOnce(Box<dyn VirtioDevice>) -> AxResult<Arc<VirtioMmio>>,
+) -> AxResult<Arc<VirtioMmio>> {
+    let mac = match entry.get("mac").and_then(|v| v.as_str()) {
+        Some(s) => identity::instance_mac(
+            vm.id(),
+            parse_mac(s)
+                .ok_or_else(|| ax_err_type!(InvalidInput, "virtio_net config: invalid `mac`"))?,
+            idx,
+        ),
+        None => [
+            0x52,
+            0x54,
+            0x00,
+            (vm.id() >> 8) as u8,
+            vm.id() as u8,
+            idx as u8,
+        ],
+    };
+    let bridge = entry
+        .get("bridge")
+        .and_then(|v| v.as_str())
+        .unwrap_or(DEFAULT_BRIDGE);
+
+    let switch = switch::get_or_create(bridge);
+    let port = switch.add_port(vm.id());
+    let device = Box::new(VirtioNet {
+        mac,
+        switch: switch.clone(),
+        port,
+    });
+    let transport = attach(device).inspect_err(|_| {
+        switch.remove_port(port);
+    })?;
+    switch.connect(port, Arc::downgrade(&transport));
+
+    info!(
+        "VM[{}] virtio-net mac {:02x?} on {:?}",
+        vm.id(),
+        mac,
+        bridge
+    );
+    Ok(transport)
+}
+
+/// Creates the virtio-net devices described in the `[[virtio_net]]` array of `raw_cfg`.
+pub(super) fn setup_vm_virtio_net(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(entries) = raw_cfg.get("virtio_net").and_then(|v| v.as_array()) else {
+        return Ok(());
+    };
+    for (idx, entry) in entries.iter().enumerate() {
+        let get = |key: &str| {
+            entry
+                .get(key)
+                .and_then(|v| v.as_integer())
+                .map(|v| v as usize)
+                .ok_or_else(|| {
+                    ax_err_type!(
+                        InvalidInput,
+                        format!("virtio_net config: missing `{}`", key)
+                    )
+                })
+        };
+        let base = get("base")?;
+        let irq = get("irq")?;
+        add_virtio_net(vm, entry, idx, |device| {
+            register_device(vm, base, irq, device)
+        })?;
+        info!(
+            "VM[{}] virtio-net {} at {:#x}, irq {}",
+            vm.id(),
+            idx,
+            base,
+            irq
+        );
+    }
+    Ok(())
+}
+
+/// Returns the (switch name, port counters) of the virtio-net devices of a VM.
+pub(super) fn vm_net_stats(vm_id: usize) -> Vec<(String, PortStats)> {
+    switch::switches()
+        .iter()
+        .flat_map(|switch| {
+            switch
+                .port_stats()
+                .into_iter()
+                .filter(|(id, _)| *id == vm_id)
+                .map(|(_, stats)| (String::from(switch.name()), stats))
+        })
+        .collect()
+}
@@ -0,0 +1,171 @@
+//! Split virtqueues living in guest memory.
+use alloc::vec::Vec;
+use core::sync::atomic::{Ordering, fence};
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+
+use super::{read_guest_bytes, write_guest_bytes};
+use crate::vmm::{VMRef, dirty};
+
+/// Maximum queue size offered to guests.
+pub const QUEUE_SIZE_MAX: u16 = 256;
+
+const DESC_SIZE: usize = 16;
+const DESC_F_NEXT: u16 = 1 << 0;
+const DESC_F_WRITE: u16 = 1 << 1;
+const AVAIL_F_NO_INTERRUPT: u16 = 1 << 0;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Desc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A guest buffer of a descriptor chain.
+#[derive(Debug, Clone, Copy)]
+struct ChainBuf {
+    addr: GuestPhysAddr,
+    len: usize,
+    writable: bool,
+}
+
+/// A descriptor chain popped from the available ring.
+#[derive(Debug)]
+pub struct DescChain {
+    /// Index of the head descriptor, to be returned in the used ring.
+    pub head: u16,
+    bufs: Vec<ChainBuf>,
+}
+
+impl DescChain {
+    /// Reads the device-readable buffers of the chain, skipping the first `skip` bytes and
+    /// reading at most `max` bytes.
+    pub fn read(&self, vm: &VMRef, skip: usize, max: usize) -> AxResult<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut skip = skip;
+        for buf in self.bufs.iter().filter(|b| !b.writable) {
+            if skip >= buf.len {
+                skip -= buf.len;
+                continue;
+            }
+            let len = (buf.len - skip).min(max - data.len());
+            let start = data.len();
+            data.resize(start + len, 0);
+            read_guest_bytes(vm, buf.addr + skip, &mut data[start..])?;
+            skip = 0;
+            if data.len() == max {
+                break;
+            }
+        }
+        Ok(data)
+    }
+
+    /// Writes `data` to the device-writable buffers of the chain, returns the number of bytes
+    /// written, which is less than `data.len()` if the buffers are too small.
+    pub fn write(&self, vm: &VMRef, data: &[u8]) -> AxResult<usize> {
+        let mut written = 0;
+        for buf in self.bufs.iter().filter(|b| b.writable) {
+            if written == data.len() {
+                break;
+            }
+            let len = buf.len.min(data.len() - written);
+            write_guest_bytes(vm, buf.addr, &data[written..written + len])?;
+            written += len;
+        }
+        Ok(written)
+    }
+
+    /// Total length of the device-writable buffers.
+    pub fn writable_len(&self) -> usize {
+        self.bufs.iter().filter(|b| b.writable).map(|b| b.len).sum()
+    }
+}
+
+/// The device side of a split virtqueue.
+#[derive(Debug, Default)]
+pub struct VirtQueue {
+    pub size: u16,
+    pub ready: bool,
+    /// Guest physical address of the descriptor table.
+    pub desc: u64,
+    /// Guest physical address of the available ring.
+    pub driver: u64,
+    /// Guest physical address of the used ring.
+    pub device: u64,
+    last_avail_idx: u16,
+    used_idx: u16,
+}
+
+impl VirtQueue {
+    /// Resets the queue to its initial state, as on device reset.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    fn gpa(addr: u64) -> GuestPhysAddr {
+        GuestPhysAddr::from(addr as usize)
+    }
+
+    /// Pops the next descriptor chain made available by the driver, if any.
+    pub fn pop_avail(&mut self, vm: &VMRef) -> AxResult<Option<DescChain>> {
+        if !self.ready || self.size == 0 {
+            return Ok(None);
+        }
+        let avail_idx: u16 = vm.read_from_guest_of(Self::gpa(self.driver + 2))?;
+        if avail_idx == self.last_avail_idx {
+            return Ok(None);
+        }
+        // Read the ring entry only after observing the index.
+        fence(Ordering::Acquire);
+
+        let slot = (self.last_avail_idx % self.size) as u64;
+        let head: u16 = vm.read_from_guest_of(Self::gpa(self.driver + 4 + 2 * slot))?;
+        self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+
+        let mut bufs = Vec::new();
+        let mut idx = head;
+        loop {
+            if idx >= self.size || bufs.len() >= self.size as usize {
+                return ax_err!(InvalidData, "malformed virtqueue descriptor chain");
+            }
+            let desc: Desc =
+                vm.read_from_guest_of(Self::gpa(self.desc + (idx as usize * DESC_SIZE) as u64))?;
+            bufs.push(ChainBuf {
+                addr: Self::gpa(desc.addr),
+                len: desc.len as usize,
+                writable: desc.flags & DESC_F_WRITE != 0,
+            });
+            if desc.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            idx = desc.next;
+        }
+        Ok(Some(DescChain { head, bufs }))
+    }
+
+    /// Returns a chain to the driver through the used ring, `len` being the number of bytes
+    /// written into it.
+    pub fn push_used(&mut self, vm: &VMRef, head: u16, len: u32) -> AxResult {
+        let slot = (self.used_idx % self.size) as u64;
+        let elem = self.device + 4 + 8 * slot;
+        vm.write_to_guest_of(Self::gpa(elem), &(head as u32))?;
+        vm.write_to_guest_of(Self::gpa(elem + 4), &len)?;
+        self.used_idx = self.used_idx.wrapping_add(1);
+        // Publish the element before the index.
+        fence(Ordering::Release);
+        vm.write_to_guest_of(Self::gpa(self.device + 2), &self.used_idx)?;
+        dirty::mark(vm.id(), Self::gpa(elem), 8);
+        dirty::mark(vm.id(), Self::gpa(self.device + 2), 2);
+        Ok(())
+    }
+
+    /// Whether the driver wants an interrupt for used buffers.
+    pub fn needs_interrupt(&self, vm: &VMRef) -> bool {
+        vm.read_from_guest_of::<u16>(Self::gpa(self.driver))
+            .is_ok_and(|flags| flags & AVAIL_F_NO_INTERRUPT == 0)
+    }
+}
@@ -0,0 +1,226 @@
+//! Virtio-rng devices feeding guests entropy.
+//!
+//! Guests without a hardware entropy source of their own, e.g. headless VMs without input
+//! devices, may stall at boot waiting for their RNG to be initialized. A virtio-rng device fills
+//! the buffers of the guest at once. A guest gets a device with a `[[virtio_rng]]` entry in its VM
+//! config:
+//!
+//! ```toml
+//! [[virtio_rng]]
+//! # Guest physical base of the virtio-mmio register page.
+//! base = 0x0a00_e000
+//! # Interrupt injected to the guest for used buffers.
+//! irq = 0x36
+//! ```
+//!
+//! The devices of all VMs draw from a single generator, AES-256 in counter mode, which is rekeyed
+//! from its own output after each request, so that the bytes given to a guest can't be recovered
+//! from the state of the generator afterwards. The generator is seeded, and reseeded every
+//! [`RESEED_BYTES`], from the generator of the CPU where there is one (FEAT_RNG on aarch64,
+//! RDRAND on x86_64), mixed with the host clocks. Without one, e.g. on riscv64, it is seeded from
+//! the host clocks at its first use only: the output is unpredictable enough for a guest to boot,
+//! but no substitute for a hardware source, and a warning is logged.
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use std::os::arceos::api::time::ax_wall_time;
+use std::os::arceos::modules::axhal;
+
+use aes_gcm::aes::Aes256;
+use aes_gcm::aes::cipher::{BlockEncrypt, KeyInit, generic_array::GenericArray};
+use axerrno::{AxResult, ax_err_type};
+use spin::Mutex;
+
+use super::{VirtioDevice, VirtioMmio, register_device};
+use crate::hal::arch::hw_random;
+use crate::vmm::VMRef;
+
+const VIRTIO_ID_ENTROPY: u32 = 4;
+
+const REQUEST_QUEUE: usize = 0;
+
+/// Bytes given to a request at most, the guest asks again for more.
+const MAX_REQUEST_LEN: usize = 0x1000;
+/// Bytes generated between two reseeds from the generator of the CPU.
+const RESEED_BYTES: u64 = 0x10_0000;
+
+/// The generator shared by the devices of all VMs, created at the first request.
+static GENERATOR: Mutex<Option<Generator>> = Mutex::new(None);
+
+struct Generator {
+    cipher: Aes256,
+    counter: u128,
+    /// Bytes generated since the last reseed.
+    generated: u64,
+    /// Whether the generator of the CPU seeds the generator.
+    hardware: bool,
+}
+
+impl Generator {
+    fn new() -> Self {
+        let (key, hardware) = fresh_key();
+        if hardware {
+            info!("virtio-rng generator seeded from the CPU");
+        } else {
+            warn!("virtio-rng generator seeded from the host clocks, the CPU has no generator");
+        }
+        Self {
+            cipher: Aes256::new(GenericArray::from_slice(&key)),
+            counter: 0,
+            generated: 0,
+            hardware,
+        }
+    }
+
+    fn next_block(&mut self) -> [u8; 16] {
+        let mut block = GenericArray::from(self.counter.to_le_bytes());
+        self.counter = self.counter.wrapping_add(1);
+        self.cipher.encrypt_block(&mut block);
+        block.into()
+    }
+
+    /// Fills `buf` with random bytes and rekeys the generator.
+    fn fill(&mut self, buf: &mut [u8]) {
+        if self.hardware && self.generated >= RESEED_BYTES {
+            self.reseed();
+        }
+        for chunk in buf.chunks_mut(16) {
+            let block = self.next_block();
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+        self.generated += buf.len() as u64;
+
+        let mut key = [0; 32];
+        key[..16].copy_from_slice(&self.next_block());
+        key[16..].copy_from_slice(&self.next_block());
+        self.cipher = Aes256::new(GenericArray::from_slice(&key));
+    }
+
+    /// Mixes a fresh key from the generator of the CPU into the key of the generator.
+    fn reseed(&mut self) {
+        let (fresh, hardware) = fresh_key();
+        let mut key = [0; 32];
+        key[..16].copy_from_slice(&self.next_block());
+        key[16..].copy_from_slice(&self.next_block());
+        for (byte, fresh) in key.iter_mut().zip(fresh) {
+            *byte ^= fresh;
+        }
+        self.cipher = Aes256::new(GenericArray::from_slice(&key));
+        self.generated = 0;
+        if !hardware {
+            warn!("virtio-rng generator not reseeded, the generator of the CPU failed");
+        }
+    }
+}
+
+/// Returns a key from the generator of the CPU mixed with the host clocks, and whether the
+/// generator of the CPU provided it. The key comes from the clocks only if it didn't.
+fn fresh_key() -> ([u8; 32], bool) {
+    let mut key = [0; 32];
+    key[..16].copy_from_slice(&ax_wall_time().as_nanos().to_le_bytes());
+    key[16..24].copy_from_slice(&axhal::time::monotonic_time_nanos().to_le_bytes());
+    key[24..].copy_from_slice(&axhal::time::current_ticks().to_le_bytes());
+    let mut hardware = true;
+    for chunk in key.chunks_mut(8) {
+        let Some(random) = hw_random() else {
+            hardware = false;
+            break;
+        };
+        for (byte, random) in chunk.iter_mut().zip(random.to_le_bytes()) {
+            *byte ^= random;
+        }
+    }
+    (key, hardware)
+}
+
+struct VirtioRng;
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_ENTROPY
+    }
+
+    fn device_features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, _offset: usize) -> u8 {
+        // The device has no configuration space.
+        0
+    }
+
+    fn queue_notify(&self, transport: &VirtioMmio, vm: &VMRef, queue: usize) {
+        if queue != REQUEST_QUEUE {
+            return;
+        }
+        let mut completed = false;
+        let mut buf = [0; MAX_REQUEST_LEN];
+        loop {
+            let chain = match transport.with_queue(REQUEST_QUEUE, |q| q.pop_avail(vm)) {
+                Some(Ok(Some(chain))) => chain,
+                Some(Err(e)) => {
+                    warn!("VM[{}] virtio-rng queue: {:?}", vm.id(), e);
+                    break;
+                }
+                _ => break,
+            };
+            let len = chain.writable_len().min(MAX_REQUEST_LEN);
+            GENERATOR
+                .lock()
+                .get_or_insert_with(Generator::new)
+                .fill(&mut buf[..len]);
+            let written = chain.write(vm, &buf[..len]).unwrap_or_else(|e| {
+                warn!("VM[{}] virtio-rng bad buffer: {:?}", vm.id(), e);
+                0
+            });
+            buf[..len].fill(0);
+            let used = transport.with_queue(REQUEST_QUEUE, |q| {
+                q.push_used(vm, chain.head, written as u32)
+            });
+            if let Some(Err(e)) = used {
+                warn!("VM[{}] virtio-rng used ring: {:?}", vm.id(), e);
+                break;
+            }
+            completed = true;
+        }
+        if completed {
+            transport.notify_used(vm, REQUEST_QUEUE);
+        }
+    }
+}
+
+/// Creates a virtio-rng device of the VM, attached by `attach`.
+pub(super) fn add_virtio_rng(
+    attach: impl FnOnce(Box<dyn VirtioDevice>) -> AxResult<Arc<VirtioMmio>>,
+) -> AxResult<Arc<VirtioMmio>> {
+    attach(Box::new(VirtioRng))
+}
+
+/// Creates the virtio-rng devices described in the `[[virtio_rng]]` array of `raw_cfg`.
+pub(super) fn setup_vm_virtio_rng(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(entries) = raw_cfg.get("virtio_rng").and_then(|v| v.as_array()) else {
+        return Ok(());
+    };
+    for entry in entries {
+        let get = |key: &str| {
+            entry
+                .get(key)
+                .and_then(|v| v.as_integer())
+                .map(|v| v as usize)
+                .ok_or_else(|| {
+                    ax_err_type!(
+                        InvalidInput,
+                        format!("virtio_rng config: missing `{}`", key)
+                    )
+                })
+        };
+        let base = get("base")?;
+        let irq = get("irq")?;
+        add_virtio_rng(|device| register_device(vm, base, irq, device))?;
+        info!("VM[{}] virtio-rng at {:#x}, irq {}", vm.id(), base, irq);
+    }
+    Ok(())
+}
@@ -0,0 +1,164 @@
+//! Hypervisor-internal learning Ethernet switch bridging the virtio-net devices of VMs.
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::VirtioMmio;
+
+/// An Ethernet MAC address.
+pub type MacAddr = [u8; 6];
+
+const BROADCAST: MacAddr = [0xff; 6];
+
+/// Traffic counters of a switch port.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PortStats {
+    /// Frames sent by the VM into the switch.
+    pub tx_frames: u64,
+    /// Frames delivered to the VM.
+    pub rx_frames: u64,
+    /// Frames to the VM dropped because it had no receive buffer.
+    pub rx_dropped: u64,
+}
+
+struct Port {
+    vm_id: usize,
+    /// The virtio-net device of the port, weak as the device owns the switch.
+    device: Weak<VirtioMmio>,
+    stats: PortStats,
+}
+
+#[derive(Default)]
+struct SwitchInner {
+    next_port: usize,
+    ports: BTreeMap<usize, Port>,
+    /// Learnt source MAC addresses, mapped to their port.
+    fdb: BTreeMap<MacAddr, usize>,
+}
+
+/// A learning switch: frames to a learnt unicast address go to its port only, others are
+/// flooded to all ports but the source one.
+pub struct Switch {
+    name: String,
+    inner: Mutex<SwitchInner>,
+}
+
+impl Switch {
+    /// Name of the switch, as referenced by `bridge` in VM configs.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Adds a port for a virtio-net device of VM `vm_id`, returns the port ID.
+    ///
+    /// The port does not receive frames until it is [connected](Self::connect) to the device.
+    pub fn add_port(&self, vm_id: usize) -> usize {
+        let mut inner = self.inner.lock();
+        let port = inner.next_port;
+        inner.next_port += 1;
+        inner.ports.insert(
+            port,
+            Port {
+                vm_id,
+                device: Weak::new(),
+                stats: PortStats::default(),
+            },
+        );
+        port
+    }
+
+    /// Connects a port to its device.
+    pub fn connect(&self, port: usize, device: Weak<VirtioMmio>) {
+        if let Some(port) = self.inner.lock().ports.get_mut(&port) {
+            port.device = device;
+        }
+    }
+
+    /// Removes a port and forgets the addresses learnt on it.
+    pub fn remove_port(&self, port: usize) {
+        let mut inner = self.inner.lock();
+        inner.ports.remove(&port);
+        inner.fdb.retain(|_, p| *p != port);
+    }
+
+    /// Forwards a frame sent by the VM of port `src`.
+    ///
+    /// `deliver` is called for every destination device without the switch lock held, and
+    /// returns whether the frame was accepted by the device.
+    pub fn forward(&self, src: usize, frame: &[u8], deliver: impl Fn(&VirtioMmio, &[u8]) -> bool) {
+        if frame.len() < 14 {
+            return;
+        }
+        let dst_mac: MacAddr = frame[0..6].try_into().unwrap();
+        let src_mac: MacAddr = frame[6..12].try_into().unwrap();
+
+        let targets: Vec<(usize, Arc<VirtioMmio>)> = {
+            let mut inner = self.inner.lock();
+            let Some(port) = inner.ports.get_mut(&src) else {
+                return;
+            };
+            port.stats.tx_frames += 1;
+            // Multicast source addresses are invalid and never learnt.
+            if src_mac[0] & 1 == 0 {
+                inner.fdb.insert(src_mac, src);
+            }
+
+            let unicast_port = (dst_mac != BROADCAST && dst_mac[0] & 1 == 0)
+                .then(|| inner.fdb.get(&dst_mac).copied())
+                .flatten();
+            inner
+                .ports
+                .iter()
+                .filter(|(id, _)| **id != src && unicast_port.is_none_or(|p| p == **id))
+                .filter_map(|(id, port)| port.device.upgrade().map(|dev| (*id, dev)))
+                .collect()
+        };
+
+        for (id, device) in targets {
+            let accepted = deliver(&device, frame);
+            if let Some(port) = self.inner.lock().ports.get_mut(&id) {
+                if accepted {
+                    port.stats.rx_frames += 1;
+                } else {
+                    port.stats.rx_dropped += 1;
+                }
+            }
+        }
+    }
+
+    /// Returns the (VM ID, counters) of every port.
+    pub fn port_stats(&self) -> Vec<(usize, PortStats)> {
+        self.inner
+            .lock()
+            .ports
+            .values()
+            .map(|port| (port.vm_id, port.stats))
+            .collect()
+    }
+}
+
+/// All switches, indexed by name.
+static SWITCHES: Mutex<BTreeMap<String, Arc<Switch>>> = Mutex::new(BTreeMap::new());
+
+/// Returns the switch named `name`, creates it if it does not exist.
+pub fn get_or_create(name: &str) -> Arc<Switch> {
+    SWITCHES
+        .lock()
+        .entry(name.into())
+        .or_insert_with(|| {
+            info!("Created virtual switch {:?}", name);
+            Arc::new(Switch {
+                name: name.into(),
+                inner: Mutex::new(SwitchInner::default()),
+            })
+        })
+        .clone()
+}
+
+/// Returns all switches.
+pub fn switches() -> Vec<Arc<Switch>> {
+    SWITCHES.lock().values().cloned().collect()
+}
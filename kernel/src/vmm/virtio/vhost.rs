@@ -0,0 +1,633 @@
+//! Virtio devices whose backend runs in a driver VM, in the manner of vhost-user.
+//!
+//! The hypervisor only emulates the virtio-mmio transport of the device: the virtqueues are
+//! processed by the backend VM, which keeps device emulation out of the hypervisor. A guest gets
+//! such a device with a `[[virtio_vhost]]` entry in its VM config, its index in the array naming
+//! it for the backend:
+//!
+//! ```toml
+//! [[virtio_vhost]]
+//! # Guest physical base of the virtio-mmio register page.
+//! base = 0x0a00_c000
+//! # Interrupt injected to the guest for used buffers.
+//! irq = 0x34
+//! # Virtio device ID, 1 for virtio-net or 2 for virtio-blk.
+//! device_id = 1
+//! # Virtqueues of the device, 2 by default for virtio-net and 1 for virtio-blk.
+//! queues = 2
+//! ```
+//!
+//! A manager VM (see [`crate::vmm::vmdef`]) serves the device with the [`HVC_VHOST_CONTROL`]
+//! hypercall, `args[0]` being the peer handle of the frontend VM, `args[1]` the operation and
+//! `args[2]` the index of the device:
+//!
+//! - [`VHOST_ATTACH`]: the device is served through the raw IVC channel with key `args[3]`
+//!   published by the caller, offering the feature bits `args[4]` ([`VIRTIO_F_VERSION_1`] is
+//!   always offered). The RAM of the frontend VM is mapped into the caller, where the virtqueues
+//!   and the buffers they point to live. Returns the GPA of the RAM in the caller. A device has
+//!   one backend at a time;
+//! - [`VHOST_DETACH`]: stops serving the device, the RAM is unmapped from the caller;
+//! - [`VHOST_NOTIFY`]: signals used buffers in queue `args[3]` to the frontend, which is
+//!   interrupted unless it suppressed the interrupt.
+//!
+//! The data of the channel is a [`VhostHeader`], followed by a [`VhostQueue`] per virtqueue from
+//! offset [`VHOST_QUEUES_OFFSET`], the memory table of the frontend from [`VHOST_MEM_OFFSET`], a
+//! [`VhostMemRegion`] per region of its RAM, and the configuration space of the device from
+//! [`VHOST_CONFIG_OFFSET`]. The backend writes the configuration space, which the frontend reads
+//! and writes through the transport. The hypervisor notifies the backend with the vector it
+//! registered for the channel:
+//!
+//! - when the driver changes the status of the device, with [`VHOST_EVENT_STATUS`] set in
+//!   `events`, after updating `status`, the features accepted by the driver and the queues. The
+//!   queues are only valid once the driver sets `DRIVER_OK`, and a status of 0 is a reset;
+//! - when the driver notifies queue `n`, with bit `n` set in `kicks`;
+//! - when the frontend VM is destroyed, with [`VHOST_EVENT_GONE`] set in `events`, after its RAM
+//!   was unmapped.
+//!
+//! The backend clears the bits it handled. Until a backend attaches, the device has the device ID
+//! 0 of a placeholder, which drivers skip, so the backend attaches before the frontend boots.
+//!
+//! The backend reaches all of the RAM of the frontend, which must allow full introspection, see
+//! [`crate::vmm::security::Introspection`]. Only the regions allocated at VM creation are mapped,
+//! the regions populated on demand (see [`crate::vmm::lazymem`]) are not, so the driver keeps its
+//! buffers out of them. The writes of the backend are not seen by dirty tracking (see
+//! [`crate::vmm::dirty`]), which is refused while a device of the VM has a backend.
+//!
+//! [`HVC_VHOST_CONTROL`]: crate::vmm::hvc::HVC_VHOST_CONTROL
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use axaddrspace::{AxMmHal, GuestPhysAddr, MappingFlags};
+use axerrno::{AxResult, ax_err, ax_err_type};
+use spin::Mutex;
+
+use super::{VIRTIO_F_VERSION_1, VirtioDevice, VirtioMmio, register_device};
+use crate::hal::AxMmHalImpl;
+use crate::vmm::hvc::{self, HVC_VHOST_CONTROL, HvcService};
+use crate::vmm::ivc::{self, IVCMapping};
+use crate::vmm::security::Introspection;
+use crate::vmm::{VMRef, dirty, introspect, iommu};
+
+const VIRTIO_ID_NET: u32 = 1;
+const VIRTIO_ID_BLK: u32 = 2;
+
+/// Operations of [`HVC_VHOST_CONTROL`](crate::vmm::hvc::HVC_VHOST_CONTROL), in `args[1]`.
+pub const VHOST_ATTACH: u64 = 0;
+pub const VHOST_DETACH: u64 = 1;
+pub const VHOST_NOTIFY: u64 = 2;
+
+/// Bits of `VhostHeader::events`.
+pub const VHOST_EVENT_STATUS: u32 = 1 << 0;
+pub const VHOST_EVENT_GONE: u32 = 1 << 1;
+
+/// `magic` of [`VhostHeader`], "AXVH".
+pub const VHOST_MAGIC: u32 = u32::from_le_bytes(*b"AXVH");
+/// Version of the layouts of the channel.
+pub const VHOST_VERSION: u16 = 1;
+/// Virtqueues of a device, at most.
+pub const MAX_VHOST_QUEUES: usize = 8;
+/// Regions of the memory table, at most.
+pub const MAX_VHOST_MEM_REGIONS: usize = 8;
+/// Offset of the first [`VhostQueue`] in the data of the channel.
+pub const VHOST_QUEUES_OFFSET: usize = 64;
+/// Offset of the first [`VhostMemRegion`] in the data of the channel.
+pub const VHOST_MEM_OFFSET: usize = 320;
+/// Offset of the configuration space in the data of the channel.
+pub const VHOST_CONFIG_OFFSET: usize = 512;
+/// Size of the configuration space.
+pub const VHOST_CONFIG_SIZE: usize = 256;
+
+/// Header of the data of the channel.
+#[repr(C)]
+pub struct VhostHeader {
+    pub magic: u32,
+    pub version: u16,
+    /// Virtqueues of the device, entries of the queue table.
+    pub queues: u16,
+    /// Virtio device ID.
+    pub device_id: u32,
+    /// Device status written by the driver.
+    pub status: u32,
+    /// Feature bits accepted by the driver.
+    pub driver_features: u64,
+    /// Bit `n` set when the driver notified queue `n`, cleared by the backend.
+    pub kicks: AtomicU32,
+    /// `VHOST_EVENT_*` bits, cleared by the backend.
+    pub events: AtomicU32,
+    /// Entries of the memory table.
+    pub mem_regions: u32,
+    pub _reserved: u32,
+    /// Notifications sent since the backend attached.
+    pub notifications: AtomicU64,
+}
+
+/// The layout of a virtqueue in the RAM of the frontend, as set up by the driver.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VhostQueue {
+    pub size: u16,
+    /// 1 once the driver made the queue ready.
+    pub ready: u16,
+    pub _reserved: u32,
+    /// GPAs of the descriptor table, the available ring and the used ring, in the frontend.
+    pub desc: u64,
+    pub driver: u64,
+    pub device: u64,
+}
+
+/// A region of the RAM of the frontend.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct VhostMemRegion {
+    /// GPA of the region in the frontend.
+    pub gpa: u64,
+    pub size: u64,
+    /// GPA of the region in the backend.
+    pub backend_gpa: u64,
+}
+
+/// The backend of a device.
+struct Backend {
+    vm_id: usize,
+    key: usize,
+    /// Feature bits offered, [`VIRTIO_F_VERSION_1`] excluded.
+    features: u64,
+    /// The window of the RAM of the frontend in the backend.
+    window: GuestPhysAddr,
+    window_size: usize,
+    notifications: u64,
+}
+
+/// The state of a device shared by its transport and the hypercall.
+struct Vhost {
+    vm_id: usize,
+    index: usize,
+    device_id: u32,
+    queues: usize,
+    backend: Mutex<Option<Backend>>,
+    kicks: AtomicU64,
+    interrupts: AtomicU64,
+}
+
+/// The devices served by backends, by ID of the frontend VM and index.
+static VHOST_DEVICES: Mutex<BTreeMap<usize, Vec<(Arc<Vhost>, Arc<VirtioMmio>)>>> =
+    Mutex::new(BTreeMap::new());
+
+/// A device of a VM and its backend, for monitoring.
+#[derive(Debug, Clone, Copy)]
+pub struct VhostStats {
+    pub device_id: u32,
+    /// `None` while the device has no backend.
+    pub backend_vm_id: Option<usize>,
+    /// Queue notifications of the driver.
+    pub kicks: u64,
+    /// Used buffer notifications of the backend.
+    pub interrupts: u64,
+}
+
+/// The vhost hypercall, for manager VMs.
+pub static HVC_SERVICE: HvcService = HvcService {
+    name: "vhost",
+    codes: HVC_VHOST_CONTROL..HVC_VHOST_CONTROL + 1,
+    permit: hvc::permit_managers,
+    deferrable: false,
+    handler: |vm, _, _, args| handle_control(vm, args),
+};
+
+/// The transport side of a device, forwarding to the backend.
+struct VhostDevice(Arc<Vhost>);
+
+impl Vhost {
+    /// Runs `f` on the data of the channel of the backend and notifies it, does nothing without
+    /// a backend.
+    fn notify_backend(&self, f: impl FnOnce(&mut [u8])) {
+        let mut backend = self.backend.lock();
+        let Some(backend) = backend.as_mut() else {
+            return;
+        };
+        backend.notifications += 1;
+        let notifications = backend.notifications;
+        let written = ivc::write_raw_channel(backend.vm_id, backend.key, |data| {
+            f(data);
+            header(data)
+                .notifications
+                .store(notifications, Ordering::Release);
+        });
+        if let Err(e) = written {
+            warn!(
+                "VM[{}] vhost device {}: backend VM[{}] not notified: {:?}",
+                self.vm_id, self.index, backend.vm_id, e
+            );
+        }
+    }
+
+    /// Runs `f` on the configuration space in the channel of the backend, `None` without a
+    /// backend or out of the configuration space.
+    fn with_config<R>(&self, offset: usize, f: impl FnOnce(&mut u8) -> R) -> Option<R> {
+        if offset >= VHOST_CONFIG_SIZE {
+            return None;
+        }
+        let backend = self.backend.lock();
+        let backend = backend.as_ref()?;
+        ivc::access_raw_channel(backend.vm_id, backend.key, |data| {
+            f(&mut data[VHOST_CONFIG_OFFSET + offset])
+        })
+        .ok()
+    }
+}
+
+/// Returns the header of the data of the channel.
+fn header(data: &mut [u8]) -> &mut VhostHeader {
+    // SAFETY: the channel was checked to hold the whole layout by `attach`, the header is 8-byte
+    // aligned at the start of the data.
+    unsafe { &mut *data.as_mut_ptr().cast::<VhostHeader>() }
+}
+
+impl VirtioDevice for VhostDevice {
+    fn device_id(&self) -> u32 {
+        // A placeholder until the device has a backend.
+        match *self.0.backend.lock() {
+            Some(_) => self.0.device_id,
+            None => 0,
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        self.0
+            .backend
+            .lock()
+            .as_ref()
+            .map_or(0, |backend| backend.features)
+    }
+
+    fn num_queues(&self) -> usize {
+        self.0.queues
+    }
+
+    fn read_config(&self, offset: usize) -> u8 {
+        self.0.with_config(offset, |byte| *byte).unwrap_or(0)
+    }
+
+    fn write_config(&self, offset: usize, val: u8) {
+        self.0.with_config(offset, |byte| *byte = val);
+    }
+
+    fn queue_notify(&self, _transport: &VirtioMmio, _vm: &VMRef, queue: usize) {
+        if queue >= self.0.queues {
+            return;
+        }
+        self.0.kicks.fetch_add(1, Ordering::Relaxed);
+        self.0.notify_backend(|data| {
+            header(data).kicks.fetch_or(1 << queue, Ordering::Release);
+        });
+    }
+
+    fn status_changed(&self, transport: &VirtioMmio, status: u32) {
+        let driver_features = transport.state.lock().driver_features;
+        let queues: Vec<_> = (0..self.0.queues)
+            .map(|idx| {
+                transport
+                    .with_queue(idx, |q| VhostQueue {
+                        size: q.size,
+                        ready: q.ready as u16,
+                        _reserved: 0,
+                        desc: q.desc,
+                        driver: q.driver,
+                        device: q.device,
+                    })
+                    .unwrap_or_default()
+            })
+            .collect();
+        self.0.notify_backend(|data| {
+            let table = data[VHOST_QUEUES_OFFSET..].as_mut_ptr().cast::<VhostQueue>();
+            for (idx, queue) in queues.into_iter().enumerate() {
+                // SAFETY: the queue table was sized for the queues of the device by `attach`.
+                unsafe { table.add(idx).write_unaligned(queue) };
+            }
+            let header = header(data);
+            header.status = status;
+            header.driver_features = driver_features;
+            header.events.fetch_or(VHOST_EVENT_STATUS, Ordering::Release);
+        });
+    }
+
+    fn detach(&self) {
+        let Some(backend) = self.0.backend.lock().take() else {
+            return;
+        };
+        unmap_ram(&self.0, &backend);
+        let gone = ivc::write_raw_channel(backend.vm_id, backend.key, |data| {
+            header(data)
+                .events
+                .fetch_or(VHOST_EVENT_GONE, Ordering::Release);
+        });
+        if let Err(e) = gone {
+            warn!(
+                "VM[{}] vhost device {}: backend VM[{}] not notified: {:?}",
+                self.0.vm_id, self.0.index, backend.vm_id, e
+            );
+        }
+    }
+}
+
+/// Unmaps the RAM of the frontend from the backend.
+fn unmap_ram(vhost: &Vhost, backend: &Backend) {
+    let unmapped = crate::vmm::with_vm(backend.vm_id, |vm| {
+        iommu::unmap_region(&vm, backend.window, backend.window_size)
+    });
+    if let Some(Err(e)) = unmapped {
+        warn!(
+            "VM[{}] vhost device {}: RAM not unmapped from VM[{}]: {:?}",
+            vhost.vm_id, vhost.index, backend.vm_id, e
+        );
+    }
+}
+
+/// Returns the device `index` of VM `vm_id` and its transport.
+fn device_of(vm_id: usize, index: usize) -> AxResult<(Arc<Vhost>, Arc<VirtioMmio>)> {
+    VHOST_DEVICES
+        .lock()
+        .get(&vm_id)
+        .and_then(|devices| devices.get(index))
+        .cloned()
+        .ok_or_else(|| {
+            ax_err_type!(
+                NotFound,
+                format!("VM[{}] has no vhost device {}", vm_id, index)
+            )
+        })
+}
+
+/// Handles the `HVhostControl` hypercall of the manager `vm`.
+fn handle_control(vm: &VMRef, args: [u64; 6]) -> AxResult<usize> {
+    let target = introspect::target_vm(vm, args[0], Introspection::Full)?;
+    if target.id() == vm.id() {
+        return ax_err!(InvalidInput, "a VM cannot serve its own devices");
+    }
+    let (vhost, transport) = device_of(target.id(), args[2] as usize)?;
+    if args[1] == VHOST_ATTACH {
+        return attach(vm, &target, &vhost, args[3] as usize, args[4]);
+    }
+
+    let mut backend = vhost.backend.lock();
+    if backend.as_ref().is_none_or(|backend| backend.vm_id != vm.id()) {
+        return ax_err!(
+            NotFound,
+            format!(
+                "VM[{}] doesn't serve vhost device {} of VM[{}]",
+                vm.id(),
+                vhost.index,
+                target.id()
+            )
+        );
+    }
+    match args[1] {
+        VHOST_DETACH => {
+            let backend = backend.take().unwrap();
+            unmap_ram(&vhost, &backend);
+            info!(
+                "VM[{}] stopped serving vhost device {} of VM[{}]",
+                vm.id(),
+                vhost.index,
+                target.id()
+            );
+        }
+        VHOST_NOTIFY => {
+            drop(backend);
+            let queue = args[3] as usize;
+            if queue >= vhost.queues {
+                return ax_err!(InvalidInput, format!("no virtqueue {}", queue));
+            }
+            vhost.interrupts.fetch_add(1, Ordering::Relaxed);
+            transport.notify_used(&target, queue);
+        }
+        op => {
+            return ax_err!(InvalidInput, format!("unknown vhost operation {}", op));
+        }
+    }
+    Ok(0)
+}
+
+/// Makes `vm` the backend of the device of `target`, returns the GPA of the RAM of `target` in
+/// `vm`.
+fn attach(
+    vm: &VMRef,
+    target: &VMRef,
+    vhost: &Vhost,
+    key: usize,
+    features: u64,
+) -> AxResult<usize> {
+    if dirty::is_tracking(target.id()) {
+        return ax_err!(
+            ResourceBusy,
+            format!("VM[{}] is tracked for dirty pages", target.id())
+        );
+    }
+    // `(gpa, hpa, size)` of the regions of RAM.
+    let mut regions: Vec<_> = target
+        .memory_regions()
+        .iter()
+        .map(|region| {
+            let hpa = AxMmHalImpl::virt_to_phys(region.hva);
+            (region.gpa.as_usize(), hpa, region.size())
+        })
+        .collect();
+    regions.sort_by_key(|(gpa, ..)| *gpa);
+    if regions.len() > MAX_VHOST_MEM_REGIONS {
+        return ax_err!(
+            Unsupported,
+            format!("at most {} RAM regions", MAX_VHOST_MEM_REGIONS)
+        );
+    }
+
+    let mut backend = vhost.backend.lock();
+    if let Some(other) = backend.as_ref() {
+        return ax_err!(
+            ResourceBusy,
+            format!(
+                "vhost device {} of VM[{}] is served by VM[{}]",
+                vhost.index,
+                target.id(),
+                other.vm_id
+            )
+        );
+    }
+
+    let window_size = regions.iter().map(|(_, _, size)| size).sum();
+    let (window, _) = vm.alloc_ivc_channel(window_size)?;
+    let mut offset = 0;
+    let mut mappings = Vec::new();
+    let mut table = Vec::new();
+    for (gpa, hpa, size) in regions {
+        mappings.push(IVCMapping {
+            offset,
+            hpa,
+            size,
+            flags: MappingFlags::READ | MappingFlags::WRITE,
+        });
+        table.push(VhostMemRegion {
+            gpa: gpa as u64,
+            size: size as u64,
+            backend_gpa: (window + offset).as_usize() as u64,
+        });
+        offset += size;
+    }
+
+    let fits = ivc::access_raw_channel(vm.id(), key, |data| {
+        if data.len() < VHOST_CONFIG_OFFSET + VHOST_CONFIG_SIZE {
+            return false;
+        }
+        // The configuration space is left to the backend.
+        data[..VHOST_CONFIG_OFFSET].fill(0);
+        let header = header(data);
+        header.magic = VHOST_MAGIC;
+        header.version = VHOST_VERSION;
+        header.queues = vhost.queues as u16;
+        header.device_id = vhost.device_id;
+        header.mem_regions = table.len() as u32;
+        let entries = data[VHOST_MEM_OFFSET..].as_mut_ptr().cast::<VhostMemRegion>();
+        for (idx, entry) in table.iter().enumerate() {
+            // SAFETY: the memory table holds `MAX_VHOST_MEM_REGIONS` entries.
+            unsafe { entries.add(idx).write_unaligned(*entry) };
+        }
+        true
+    })?;
+    if !fits {
+        return ax_err!(InvalidInput, "IVC channel too small for a vhost device");
+    }
+    ivc::map_window(vm, window, window_size, &mappings)?;
+
+    *backend = Some(Backend {
+        vm_id: vm.id(),
+        key,
+        features: features & !VIRTIO_F_VERSION_1,
+        window,
+        window_size,
+        notifications: 0,
+    });
+    info!(
+        "VM[{}] serves vhost device {} of VM[{}], channel {:#x}, RAM at {:#x}",
+        vm.id(),
+        vhost.index,
+        target.id(),
+        key,
+        window
+    );
+    Ok(window.as_usize())
+}
+
+/// Creates the devices described in the `[[virtio_vhost]]` array of `raw_cfg`.
+pub(super) fn setup_vm_virtio_vhost(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(entries) = raw_cfg.get("virtio_vhost").and_then(|v| v.as_array()) else {
+        return Ok(());
+    };
+    for (index, entry) in entries.iter().enumerate() {
+        let int = |key: &str| {
+            entry
+                .get(key)
+                .and_then(|v| v.as_integer())
+                .map(|v| v as usize)
+        };
+        let get = |key: &str| {
+            int(key).ok_or_else(|| {
+                ax_err_type!(
+                    InvalidInput,
+                    format!("virtio_vhost config: missing `{}`", key)
+                )
+            })
+        };
+        let base = get("base")?;
+        let irq = get("irq")?;
+        let device_id = get("device_id")? as u32;
+        let queues = match (device_id, int("queues")) {
+            (VIRTIO_ID_NET | VIRTIO_ID_BLK, Some(queues)) => queues,
+            (VIRTIO_ID_NET, None) => 2,
+            (VIRTIO_ID_BLK, None) => 1,
+            _ => {
+                return ax_err!(
+                    Unsupported,
+                    format!("virtio_vhost config: unsupported device ID {}", device_id)
+                );
+            }
+        };
+        if queues == 0 || queues > MAX_VHOST_QUEUES {
+            return ax_err!(
+                InvalidInput,
+                format!("virtio_vhost config: 1 to {} queues", MAX_VHOST_QUEUES)
+            );
+        }
+        let vhost = Arc::new(Vhost {
+            vm_id: vm.id(),
+            index,
+            device_id,
+            queues,
+            backend: Mutex::new(None),
+            kicks: AtomicU64::new(0),
+            interrupts: AtomicU64::new(0),
+        });
+        let transport = register_device(vm, base, irq, Box::new(VhostDevice(vhost.clone())))?;
+        VHOST_DEVICES
+            .lock()
+            .entry(vm.id())
+            .or_default()
+            .push((vhost, transport));
+        info!(
+            "VM[{}] vhost device {} (device ID {}) at {:#x}, irq {}",
+            vm.id(),
+            index,
+            device_id,
+            base,
+            irq
+        );
+    }
+    Ok(())
+}
+
+/// Removes the devices of a VM and detaches the VM from the devices it serves, called when the
+/// VM is destroyed, after the devices were detached.
+pub(super) fn teardown_vm_vhost(vm_id: usize) {
+    let mut devices = VHOST_DEVICES.lock();
+    devices.remove(&vm_id);
+    for (vhost, _) in devices.values().flatten() {
+        let mut backend = vhost.backend.lock();
+        // The RAM of the frontend goes with the stage-2 of the backend.
+        if backend.as_ref().is_some_and(|backend| backend.vm_id == vm_id) {
+            warn!(
+                "VM[{}] vhost device {} lost its backend VM[{}]",
+                vhost.vm_id, vhost.index, vm_id
+            );
+            *backend = None;
+        }
+    }
+}
+
+/// Whether a device of VM `vm_id` has a backend.
+pub fn has_vhost_backend(vm_id: usize) -> bool {
+    VHOST_DEVICES.lock().get(&vm_id).is_some_and(|devices| {
+        devices
+            .iter()
+            .any(|(vhost, _)| vhost.backend.lock().is_some())
+    })
+}
+
+/// Returns the devices of VM `vm_id` served by backends.
+pub fn vhost_stats(vm_id: usize) -> Vec<VhostStats> {
+    VHOST_DEVICES
+        .lock()
+        .get(&vm_id)
+        .map(|devices| {
+            devices
+                .iter()
+                .map(|(vhost, _)| VhostStats {
+                    device_id: vhost.device_id,
+                    backend_vm_id: vhost.backend.lock().as_ref().map(|backend| backend.vm_id),
+                    kicks: vhost.kicks.load(Ordering::Relaxed),
+                    interrupts: vhost.interrupts.load(Ordering::Relaxed),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
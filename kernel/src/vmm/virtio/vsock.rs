@@ -0,0 +1,553 @@
+//! Virtio-vsock devices connecting guests to the hypervisor and to each other.
+//!
+//! Every guest with a vsock device has a context ID (CID). Stream packets sent to the CID of
+//! another guest are forwarded to its device unchanged, so flow control is handled end to end by
+//! the two guest drivers. Packets sent to [`HOST_CID`] are served by the hypervisor: a guest can
+//! connect to any port on which a [`VsockService`] is registered with [`register_service`]. An
+//! echo service is registered on [`ECHO_PORT`].
+//!
+//! A guest gets a device with a `[virtio_vsock]` section in its VM config:
+//!
+//! ```toml
+//! [virtio_vsock]
+//! # Guest physical base of the virtio-mmio register page.
+//! base = 0x0a00_a000
+//! # Interrupt injected to the guest for used buffers.
+//! irq = 0x32
+//! # CID of the guest, unique among all VMs, defaults to the VM ID + 3.
+//! cid = 3
+//! ```
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use spin::Mutex;
+
+use super::{VirtioDevice, VirtioMmio, register_device};
+use crate::vmm::{VMRef, vm_list};
+
+const VIRTIO_ID_VSOCK: u32 = 19;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+/// The CID of the hypervisor.
+pub const HOST_CID: u64 = 2;
+/// First CID available to guests.
+const MIN_GUEST_CID: u64 = 3;
+/// Port of the built-in echo service.
+pub const ECHO_PORT: u32 = 7;
+
+const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+const VIRTIO_VSOCK_OP_RST: u16 = 3;
+const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+const VIRTIO_VSOCK_OP_RW: u16 = 5;
+const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+/// Size of `struct virtio_vsock_hdr`.
+const HDR_LEN: usize = 44;
+/// Maximum payload of a packet sent by a driver.
+const MAX_PKT_PAYLOAD: usize = 64 * 1024;
+/// Maximum payload of a packet sent by the hypervisor.
+const HOST_PKT_PAYLOAD: usize = 4096;
+/// Receive buffer space of hypervisor connections, data is consumed as soon as it arrives.
+const HOST_BUF_ALLOC: u32 = 64 * 1024;
+/// Maximum number of packets queued for a guest without receive buffers.
+const MAX_RX_BACKLOG: usize = 1024;
+
+/// `struct virtio_vsock_hdr`.
+#[derive(Debug, Clone, Copy, Default)]
+struct Hdr {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    type_: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+impl Hdr {
+    fn parse(b: &[u8]) -> Option<Self> {
+        if b.len() < HDR_LEN {
+            return None;
+        }
+        let u16_at = |o: usize| u16::from_le_bytes(b[o..o + 2].try_into().unwrap());
+        let u32_at = |o: usize| u32::from_le_bytes(b[o..o + 4].try_into().unwrap());
+        let u64_at = |o: usize| u64::from_le_bytes(b[o..o + 8].try_into().unwrap());
+        Some(Self {
+            src_cid: u64_at(0),
+            dst_cid: u64_at(8),
+            src_port: u32_at(16),
+            dst_port: u32_at(20),
+            len: u32_at(24),
+            type_: u16_at(28),
+            op: u16_at(30),
+            flags: u32_at(32),
+            buf_alloc: u32_at(36),
+            fwd_cnt: u32_at(40),
+        })
+    }
+
+    fn write_to(&self, b: &mut [u8]) {
+        b[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+        b[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+        b[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+        b[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+        b[24..28].copy_from_slice(&self.len.to_le_bytes());
+        b[28..30].copy_from_slice(&self.type_.to_le_bytes());
+        b[30..32].copy_from_slice(&self.op.to_le_bytes());
+        b[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        b[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+        b[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+    }
+
+    /// Builds a packet with this header and `payload`.
+    fn packet(mut self, payload: &[u8]) -> Vec<u8> {
+        self.len = payload.len() as u32;
+        let mut pkt = vec![0u8; HDR_LEN + payload.len()];
+        self.write_to(&mut pkt);
+        pkt[HDR_LEN..].copy_from_slice(payload);
+        pkt
+    }
+
+    /// Header of a reply to a packet with this header.
+    fn reply(&self, op: u16) -> Self {
+        Self {
+            src_cid: self.dst_cid,
+            dst_cid: self.src_cid,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+            type_: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            ..Default::default()
+        }
+    }
+}
+
+/// A service of the hypervisor guests connect to on a port of [`HOST_CID`].
+pub trait VsockService: Send + Sync {
+    /// Handles data received on a connection from guest `peer_cid`, returns the data to send back.
+    fn on_data(&self, peer_cid: u64, data: &[u8]) -> Vec<u8>;
+}
+
+/// Sends back whatever it receives.
+struct EchoService;
+
+impl VsockService for EchoService {
+    fn on_data(&self, _peer_cid: u64, data: &[u8]) -> Vec<u8> {
+        data.to_vec()
+    }
+}
+
+/// Registers [`EchoService`] once, on the creation of the first vsock device.
+static ECHO_SERVICE: spin::Once = spin::Once::new();
+
+/// Packets waiting for receive buffers of a guest.
+#[derive(Default)]
+struct RxBacklog(Mutex<VecDeque<Vec<u8>>>);
+
+/// A guest vsock device, as seen by the router.
+struct Endpoint {
+    transport: Weak<VirtioMmio>,
+    rx: Arc<RxBacklog>,
+}
+
+/// Guest vsock devices indexed by CID.
+static ENDPOINTS: Mutex<BTreeMap<u64, Endpoint>> = Mutex::new(BTreeMap::new());
+
+/// Connection key: (guest CID, guest port, host port).
+type ConnKey = (u64, u32, u32);
+
+struct HostConn {
+    service: Arc<dyn VsockService>,
+    /// Receive buffer space of the guest.
+    peer_buf_alloc: u32,
+    /// Bytes consumed by the guest.
+    peer_fwd_cnt: u32,
+    /// Bytes sent to the guest.
+    tx_cnt: u32,
+    /// Bytes received from the guest.
+    fwd_cnt: u32,
+    /// `fwd_cnt` last reported to the guest.
+    reported_fwd_cnt: u32,
+    /// Data not yet sent for lack of credit.
+    backlog: VecDeque<u8>,
+}
+
+impl HostConn {
+    fn credit(&self) -> usize {
+        self.peer_buf_alloc
+            .saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt)) as usize
+    }
+
+    fn header(&mut self, key: ConnKey, op: u16) -> Hdr {
+        self.reported_fwd_cnt = self.fwd_cnt;
+        Hdr {
+            src_cid: HOST_CID,
+            dst_cid: key.0,
+            src_port: key.2,
+            dst_port: key.1,
+            type_: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            buf_alloc: HOST_BUF_ALLOC,
+            fwd_cnt: self.fwd_cnt,
+            ..Default::default()
+        }
+    }
+
+    /// Packs as much of the backlog as the guest credit allows into packets.
+    fn flush(&mut self, key: ConnKey, out: &mut Vec<Vec<u8>>) {
+        while !self.backlog.is_empty() {
+            let len = self.backlog.len().min(self.credit()).min(HOST_PKT_PAYLOAD);
+            if len == 0 {
+                break;
+            }
+            let data: Vec<u8> = self.backlog.drain(..len).collect();
+            self.tx_cnt = self.tx_cnt.wrapping_add(len as u32);
+            out.push(self.header(key, VIRTIO_VSOCK_OP_RW).packet(&data));
+        }
+    }
+}
+
+struct HostState {
+    services: BTreeMap<u32, Arc<dyn VsockService>>,
+    conns: BTreeMap<ConnKey, HostConn>,
+}
+
+static HOST: Mutex<HostState> = Mutex::new(HostState {
+    services: BTreeMap::new(),
+    conns: BTreeMap::new(),
+});
+
+/// Registers a hypervisor service on `port` of [`HOST_CID`].
+pub fn register_service(port: u32, service: Arc<dyn VsockService>) -> AxResult {
+    let mut host = HOST.lock();
+    if host.services.contains_key(&port) {
+        return ax_err!(
+            AlreadyExists,
+            format!("vsock port {} already registered", port)
+        );
+    }
+    host.services.insert(port, service);
+    Ok(())
+}
+
+/// Handles a packet sent by a guest to the hypervisor, returns the packets to send back.
+fn host_receive(hdr: &Hdr, payload: &[u8]) -> Vec<Vec<u8>> {
+    let key = (hdr.src_cid, hdr.src_port, hdr.dst_port);
+    let mut out = Vec::new();
+    let mut host = HOST.lock();
+
+    if hdr.op == VIRTIO_VSOCK_OP_REQUEST {
+        let Some(service) = host.services.get(&hdr.dst_port).cloned() else {
+            return vec![hdr.reply(VIRTIO_VSOCK_OP_RST).packet(&[])];
+        };
+        let mut conn = HostConn {
+            service,
+            peer_buf_alloc: hdr.buf_alloc,
+            peer_fwd_cnt: hdr.fwd_cnt,
+            tx_cnt: 0,
+            fwd_cnt: 0,
+            reported_fwd_cnt: 0,
+            backlog: VecDeque::new(),
+        };
+        out.push(conn.header(key, VIRTIO_VSOCK_OP_RESPONSE).packet(&[]));
+        host.conns.insert(key, conn);
+        return out;
+    }
+
+    let Some(conn) = host.conns.get_mut(&key) else {
+        if hdr.op != VIRTIO_VSOCK_OP_RST {
+            out.push(hdr.reply(VIRTIO_VSOCK_OP_RST).packet(&[]));
+        }
+        return out;
+    };
+    // Every packet carries the credit of the guest.
+    conn.peer_buf_alloc = hdr.buf_alloc;
+    conn.peer_fwd_cnt = hdr.fwd_cnt;
+
+    match hdr.op {
+        VIRTIO_VSOCK_OP_RW => {
+            conn.fwd_cnt = conn.fwd_cnt.wrapping_add(payload.len() as u32);
+            let reply = conn.service.on_data(hdr.src_cid, payload);
+            conn.backlog.extend(reply);
+            conn.flush(key, &mut out);
+            // Tell the guest about the consumed data if nothing else did.
+            if out.is_empty()
+                && conn.fwd_cnt.wrapping_sub(conn.reported_fwd_cnt) >= HOST_BUF_ALLOC / 2
+            {
+                out.push(conn.header(key, VIRTIO_VSOCK_OP_CREDIT_UPDATE).packet(&[]));
+            }
+        }
+        VIRTIO_VSOCK_OP_CREDIT_UPDATE => conn.flush(key, &mut out),
+        VIRTIO_VSOCK_OP_CREDIT_REQUEST => {
+            out.push(conn.header(key, VIRTIO_VSOCK_OP_CREDIT_UPDATE).packet(&[]));
+        }
+        VIRTIO_VSOCK_OP_SHUTDOWN => {
+            host.conns.remove(&key);
+            out.push(hdr.reply(VIRTIO_VSOCK_OP_RST).packet(&[]));
+        }
+        VIRTIO_VSOCK_OP_RST => {
+            host.conns.remove(&key);
+        }
+        _ => {
+            host.conns.remove(&key);
+            out.push(hdr.reply(VIRTIO_VSOCK_OP_RST).packet(&[]));
+        }
+    }
+    out
+}
+
+/// Queues a packet for the guest of CID `cid` and delivers what its receive buffers can take.
+///
+/// Returns `false` if there is no such guest.
+fn send_to_guest(cid: u64, pkt: Vec<u8>) -> bool {
+    let Some((transport, rx)) = ENDPOINTS
+        .lock()
+        .get(&cid)
+        .and_then(|ep| Some((ep.transport.upgrade()?, ep.rx.clone())))
+    else {
+        return false;
+    };
+    {
+        let mut backlog = rx.0.lock();
+        if backlog.len() >= MAX_RX_BACKLOG {
+            warn!("vsock CID {} receive backlog full, packet dropped", cid);
+            return true;
+        }
+        backlog.push_back(pkt);
+    }
+    flush_rx(&transport, &rx);
+    true
+}
+
+/// Moves queued packets into the receive buffers of a guest.
+fn flush_rx(transport: &VirtioMmio, rx: &RxBacklog) {
+    if !transport.driver_ok() {
+        return;
+    }
+    let Some(vm) = vm_list::get_vm_by_id(transport.vm_id()) else {
+        return;
+    };
+    let mut delivered = false;
+    let mut backlog = rx.0.lock();
+    transport.with_queue(RX_QUEUE, |q| {
+        while let Some(pkt) = backlog.front_mut() {
+            let Ok(Some(chain)) = q.pop_avail(&vm) else {
+                break;
+            };
+            let room = chain.writable_len();
+            let written = if room >= pkt.len() {
+                let written = chain.write(&vm, pkt).unwrap_or(0);
+                backlog.pop_front();
+                written
+            } else if room > HDR_LEN
+                && let Some(mut hdr) = Hdr::parse(pkt)
+                && hdr.op == VIRTIO_VSOCK_OP_RW
+            {
+                // Split stream data across the receive buffers.
+                let part = room - HDR_LEN;
+                let head = hdr.packet(&pkt[HDR_LEN..HDR_LEN + part]);
+                let written = chain.write(&vm, &head).unwrap_or(0);
+                hdr.len -= part as u32;
+                hdr.write_to(pkt);
+                pkt.drain(HDR_LEN..HDR_LEN + part);
+                written
+            } else {
+                warn!("VM[{}] vsock receive buffer too small", vm.id());
+                backlog.pop_front();
+                0
+            };
+            if q.push_used(&vm, chain.head, written as u32).is_err() {
+                break;
+            }
+            delivered = true;
+        }
+    });
+    drop(backlog);
+    if delivered {
+        transport.notify_used(&vm, RX_QUEUE);
+    }
+}
+
+/// Routes a packet sent by the guest of CID `src_cid`.
+fn route(src_cid: u64, pkt: Vec<u8>) {
+    let Some(mut hdr) = Hdr::parse(&pkt) else {
+        return;
+    };
+    let payload = &pkt[HDR_LEN..];
+    if hdr.len as usize != payload.len() {
+        return;
+    }
+    if hdr.src_cid != src_cid {
+        warn!(
+            "vsock CID {} sent a packet from CID {}",
+            src_cid, hdr.src_cid
+        );
+        hdr.src_cid = src_cid;
+    }
+    if hdr.type_ != VIRTIO_VSOCK_TYPE_STREAM {
+        send_to_guest(src_cid, hdr.reply(VIRTIO_VSOCK_OP_RST).packet(&[]));
+        return;
+    }
+
+    if hdr.dst_cid == HOST_CID {
+        for reply in host_receive(&hdr, payload) {
+            send_to_guest(src_cid, reply);
+        }
+    } else if hdr.dst_cid == src_cid || !send_to_guest(hdr.dst_cid, hdr.packet(payload)) {
+        if hdr.op != VIRTIO_VSOCK_OP_RST {
+            send_to_guest(src_cid, hdr.reply(VIRTIO_VSOCK_OP_RST).packet(&[]));
+        }
+    }
+}
+
+struct VirtioVsock {
+    cid: u64,
+    rx: Arc<RxBacklog>,
+}
+
+impl VirtioVsock {
+    fn transmit(&self, transport: &VirtioMmio, vm: &VMRef) {
+        let mut packets = Vec::new();
+        transport.with_queue(TX_QUEUE, |q| {
+            loop {
+                match q.pop_avail(vm) {
+                    Ok(Some(chain)) => {
+                        match chain.read(vm, 0, HDR_LEN + MAX_PKT_PAYLOAD) {
+                            Ok(pkt) => packets.push(pkt),
+                            Err(e) => warn!("VM[{}] vsock bad TX buffer: {:?}", vm.id(), e),
+                        }
+                        if q.push_used(vm, chain.head, 0).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("VM[{}] vsock TX queue: {:?}", vm.id(), e);
+                        break;
+                    }
+                }
+            }
+        });
+        if packets.is_empty() {
+            return;
+        }
+        transport.notify_used(vm, TX_QUEUE);
+        for pkt in packets {
+            route(self.cid, pkt);
+        }
+    }
+}
+
+impl VirtioDevice for VirtioVsock {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_VSOCK
+    }
+
+    fn device_features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize {
+        3
+    }
+
+    fn read_config(&self, offset: usize) -> u8 {
+        match offset {
+            0..8 => self.cid.to_le_bytes()[offset],
+            _ => 0,
+        }
+    }
+
+    fn queue_notify(&self, transport: &VirtioMmio, vm: &VMRef, queue: usize) {
+        match queue {
+            TX_QUEUE => self.transmit(transport, vm),
+            RX_QUEUE => flush_rx(transport, &self.rx),
+            // No transport events are ever raised on `EVENT_QUEUE`.
+            _ => {}
+        }
+    }
+
+    fn detach(&self) {
+        ENDPOINTS.lock().remove(&self.cid);
+        HOST.lock().conns.retain(|key, _| key.0 != self.cid);
+    }
+}
+
+/// CID of the virtio-vsock device of VM `vm_id` when its config has none.
+pub fn default_cid(vm_id: usize) -> u64 {
+    vm_id as u64 + MIN_GUEST_CID
+}
+
+/// Creates the virtio-vsock device described in the `[virtio_vsock]` section of `raw_cfg`.
+pub(super) fn setup_vm_virtio_vsock(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(entry) = raw_cfg.get("virtio_vsock").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let get = |key: &str| {
+        entry
+            .get(key)
+            .and_then(|v| v.as_integer())
+            .map(|v| v as usize)
+            .ok_or_else(|| {
+                ax_err_type!(
+                    InvalidInput,
+                    format!("virtio_vsock config: missing `{}`", key)
+                )
+            })
+    };
+    let base = get("base")?;
+    let irq = get("irq")?;
+    let cid = get("cid").map_or(default_cid(vm.id()), |cid| cid as u64);
+    if cid < MIN_GUEST_CID || cid >= u32::MAX as u64 {
+        return ax_err!(
+            InvalidInput,
+            format!("virtio_vsock config: invalid `cid` {}", cid)
+        );
+    }
+
+    ECHO_SERVICE.call_once(|| {
+        register_service(ECHO_PORT, Arc::new(EchoService)).expect("echo port already in use")
+    });
+
+    let rx = Arc::new(RxBacklog::default());
+    let mut endpoints = ENDPOINTS.lock();
+    if endpoints.contains_key(&cid) {
+        return ax_err!(
+            AlreadyExists,
+            format!("virtio_vsock config: CID {} already in use", cid)
+        );
+    }
+    let device = Box::new(VirtioVsock {
+        cid,
+        rx: rx.clone(),
+    });
+    let transport = register_device(vm, base, irq, device)?;
+    endpoints.insert(
+        cid,
+        Endpoint {
+            transport: Arc::downgrade(&transport),
+            rx,
+        },
+    );
+
+    info!(
+        "VM[{}] virtio-vsock at {:#x}, irq {}, CID {}",
+        vm.id(),
+        base,
+        irq,
+        cid
+    );
+    Ok(())
+}
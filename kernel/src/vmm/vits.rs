@@ -0,0 +1,429 @@
+//! Virtual GICv3 ITS of aarch64 guests.
+//!
+//! A VM whose config has an `[its]` section gets an emulated Interrupt Translation Service, so
+//! that its MSI-capable PCI devices (see [`crate::vmm::pci`]) can signal LPIs:
+//!
+//! ```toml
+//! [its]
+//! # Guest physical base of the ITS, a 64 KiB control frame followed by the translation frame.
+//! base = 0x0808_0000
+//! # Number of interrupt ID bits of the LPIs, defaults to 16.
+//! id_bits = 16
+//! ```
+//!
+//! The guest driver maps devices, collections and events through the command queue, which is
+//! processed when the guest advances `GITS_CWRITER`; the translation tables are kept here, so the
+//! `GITS_BASER<n>` registers report no table to allocate and the collections are all held "in
+//! hardware" (`GITS_TYPER.HCC`), one per vCPU. A collection targets a vCPU by number
+//! (`GITS_TYPER.PTA` is 0).
+//!
+//! The physical MSI of a passthrough device is still taken by the hypervisor: the guest message is
+//! shadowed, and when it targets `GITS_TRANSLATER` the event is translated here with the guest
+//! device ID of the device (bus 0, its guest slot) and the resulting LPI is injected to the vCPU
+//! of its collection. The LPI configuration and pending tables belong to the redistributors of
+//! the vGIC backend, which aren't consulted: a mapped LPI is injected whether or not the guest
+//! enabled it.
+//!
+//! The virtio devices emulated by the hypervisor use the virtio-mmio transport, which has no MSI,
+//! and keep their SPIs.
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use axaddrspace::GuestPhysAddr;
+use axaddrspace::device::AccessWidth;
+use axerrno::{AxResult, ax_err};
+use cpumask::CpuMask;
+use spin::Mutex;
+
+use crate::vmm::mmio::{self, MmioTrapHandler};
+use crate::vmm::{VMRef, stats, vm_list};
+
+/// Size of the two ITS frames.
+const ITS_SIZE: usize = 0x2_0000;
+const DEFAULT_ID_BITS: u32 = 16;
+/// The first LPI.
+const LPI_BASE: u32 = 8192;
+/// Size of a command in the command queue.
+const CMD_SIZE: u64 = 32;
+
+// ITS registers, as offsets from the base.
+const GITS_CTLR: usize = 0x0000;
+const GITS_TYPER: usize = 0x0008;
+const GITS_CBASER: usize = 0x0080;
+const GITS_CWRITER: usize = 0x0088;
+const GITS_CREADR: usize = 0x0090;
+const GITS_PIDR2: usize = 0xffe8;
+const GITS_TRANSLATER: usize = 0x1_0040;
+
+const CTLR_ENABLED: u32 = 1 << 0;
+const CTLR_QUIESCENT: u32 = 1 << 31;
+const CBASER_VALID: u64 = 1 << 63;
+const CBASER_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+/// Implementer ARM, product ID 0x1f: an emulated ITS.
+const IIDR_VALUE: u32 = 0x1f << 24 | 0x43b;
+/// GICv3 architecture revision.
+const PIDR2_VALUE: u32 = 0x3b;
+/// Device ID bits reported in `GITS_TYPER`, the guest bus number and slot fit in 16.
+const DEVICE_ID_BITS: u64 = 16;
+/// Size of an ITT entry reported in `GITS_TYPER`, the ITTs are never accessed.
+const ITT_ENTRY_SIZE: u64 = 8;
+
+// ITS commands.
+const CMD_MOVI: u64 = 0x01;
+const CMD_INT: u64 = 0x03;
+const CMD_CLEAR: u64 = 0x04;
+const CMD_SYNC: u64 = 0x05;
+const CMD_MAPD: u64 = 0x08;
+const CMD_MAPC: u64 = 0x09;
+const CMD_MAPTI: u64 = 0x0a;
+const CMD_MAPI: u64 = 0x0b;
+const CMD_INV: u64 = 0x0c;
+const CMD_INVALL: u64 = 0x0d;
+const CMD_MOVALL: u64 = 0x0e;
+const CMD_DISCARD: u64 = 0x0f;
+
+/// A mapped device, with its events as (LPI, collection) pairs.
+struct ItsDevice {
+    /// Number of event ID bits of the device's ITT.
+    event_bits: u32,
+    events: BTreeMap<u32, (u32, u16)>,
+}
+
+#[derive(Default)]
+struct ItsState {
+    ctlr: u32,
+    cbaser: u64,
+    creadr: u64,
+    cwriter: u64,
+    devices: BTreeMap<u32, ItsDevice>,
+    /// Target vCPUs of the mapped collections.
+    collections: BTreeMap<u16, usize>,
+}
+
+/// An emulated ITS.
+struct VIts {
+    vm_id: usize,
+    base: GuestPhysAddr,
+    num_vcpus: usize,
+    id_bits: u32,
+    state: Mutex<ItsState>,
+    /// LPIs injected.
+    lpis: AtomicU64,
+    /// MSIs dropped for lack of a mapping.
+    dropped: AtomicU64,
+}
+
+impl VIts {
+    fn typer(&self) -> u64 {
+        1 // Physical LPIs.
+            | (ITT_ENTRY_SIZE - 1) << 4
+            | (self.id_bits as u64 - 1) << 8
+            | (DEVICE_ID_BITS - 1) << 13
+            | (self.num_vcpus.min(255) as u64) << 24
+    }
+
+    fn queue_size(cbaser: u64) -> u64 {
+        ((cbaser & 0xff) + 1) * 0x1000
+    }
+
+    fn read(&self, offset: usize, width: AccessWidth) -> usize {
+        let state = self.state.lock();
+        let val = match offset & !0x7 {
+            // `GITS_IIDR` follows `GITS_CTLR`.
+            GITS_CTLR => {
+                let ctlr = (state.ctlr | CTLR_QUIESCENT) as u64;
+                ctlr | (IIDR_VALUE as u64) << 32
+            }
+            GITS_TYPER => self.typer(),
+            GITS_CBASER => state.cbaser,
+            GITS_CWRITER => state.cwriter,
+            GITS_CREADR => state.creadr,
+            _ if offset == GITS_PIDR2 => PIDR2_VALUE as u64,
+            // `GITS_BASER<n>` included: no table for the guest to allocate.
+            _ => 0,
+        };
+        let val = if offset & 0x4 != 0 { val >> 32 } else { val };
+        match width {
+            AccessWidth::Qword => val as usize,
+            _ => val as u32 as usize,
+        }
+    }
+
+    fn write(&self, vm: &VMRef, offset: usize, width: AccessWidth, val: usize) {
+        let mut state = self.state.lock();
+        let merge = |reg: u64| match (width, offset & 0x4 != 0) {
+            (AccessWidth::Qword, _) => val as u64,
+            (_, false) => (reg & !0xffff_ffff) | val as u32 as u64,
+            (_, true) => (reg & 0xffff_ffff) | (val as u64) << 32,
+        };
+        match offset & !0x7 {
+            GITS_CTLR if offset == GITS_CTLR => {
+                state.ctlr = val as u32 & CTLR_ENABLED;
+                self.process_commands(vm, &mut state);
+            }
+            // Only writable while the ITS is disabled, resets the queue.
+            GITS_CBASER if state.ctlr & CTLR_ENABLED == 0 => {
+                state.cbaser = merge(state.cbaser);
+                state.creadr = 0;
+            }
+            GITS_CWRITER => {
+                let cwriter = merge(state.cwriter) & 0x000f_ffe0;
+                state.cwriter = cwriter % Self::queue_size(state.cbaser);
+                self.process_commands(vm, &mut state);
+            }
+            _ => trace!(
+                "VM[{}] ignored ITS write {:#x} at {:#x}",
+                self.vm_id, val, offset
+            ),
+        }
+    }
+
+    /// Runs the commands between `GITS_CREADR` and `GITS_CWRITER`.
+    fn process_commands(&self, vm: &VMRef, state: &mut ItsState) {
+        if state.ctlr & CTLR_ENABLED == 0 || state.cbaser & CBASER_VALID == 0 {
+            return;
+        }
+        let queue = state.cbaser & CBASER_ADDR_MASK;
+        let size = Self::queue_size(state.cbaser);
+        while state.creadr != state.cwriter {
+            let gpa = queue + state.creadr;
+            let mut cmd = [0u64; 4];
+            for (i, dw) in cmd.iter_mut().enumerate() {
+                match vm
+                    .read_from_guest_of::<u64>(GuestPhysAddr::from((gpa + 8 * i as u64) as usize))
+                {
+                    Ok(val) => *dw = val,
+                    Err(e) => {
+                        // Stall: leave `GITS_CREADR` on the faulting command.
+                        warn!(
+                            "VM[{}] ITS command queue at {:#x} unreadable: {:?}",
+                            self.vm_id, gpa, e
+                        );
+                        return;
+                    }
+                }
+            }
+            self.run_command(vm, state, cmd);
+            state.creadr = (state.creadr + CMD_SIZE) % size;
+        }
+    }
+
+    fn run_command(&self, vm: &VMRef, state: &mut ItsState, cmd: [u64; 4]) {
+        let device_id = (cmd[0] >> 32) as u32;
+        let event_id = cmd[1] as u32;
+        let icid = cmd[2] as u16;
+        match cmd[0] & 0xff {
+            CMD_MAPD => {
+                if cmd[2] >> 63 == 0 {
+                    state.devices.remove(&device_id);
+                } else {
+                    let event_bits = (cmd[1] & 0x1f) as u32 + 1;
+                    state.devices.insert(
+                        device_id,
+                        ItsDevice {
+                            event_bits,
+                            events: BTreeMap::new(),
+                        },
+                    );
+                }
+            }
+            CMD_MAPC => {
+                if cmd[2] >> 63 == 0 {
+                    state.collections.remove(&icid);
+                } else {
+                    let vcpu = ((cmd[2] >> 16) & 0x7_ffff_ffff) as usize;
+                    if vcpu < self.num_vcpus {
+                        state.collections.insert(icid, vcpu);
+                    } else {
+                        warn!(
+                            "VM[{}] ITS collection {} mapped to missing vCPU {}",
+                            self.vm_id, icid, vcpu
+                        );
+                    }
+                }
+            }
+            cmd_id @ (CMD_MAPTI | CMD_MAPI) => {
+                let lpi = if cmd_id == CMD_MAPTI {
+                    (cmd[1] >> 32) as u32
+                } else {
+                    event_id
+                };
+                let max_lpi = ((1u64 << self.id_bits) - 1) as u32;
+                match state.devices.get_mut(&device_id) {
+                    Some(dev)
+                        if (event_id as u64) < 1 << dev.event_bits
+                            && (LPI_BASE..=max_lpi).contains(&lpi) =>
+                    {
+                        dev.events.insert(event_id, (lpi, icid));
+                    }
+                    _ => warn!(
+                        "VM[{}] ITS invalid mapping of device {:#x} event {} to LPI {}",
+                        self.vm_id, device_id, event_id, lpi
+                    ),
+                }
+            }
+            CMD_MOVI => {
+                if let Some(event) = state
+                    .devices
+                    .get_mut(&device_id)
+                    .and_then(|dev| dev.events.get_mut(&event_id))
+                {
+                    event.1 = icid;
+                }
+            }
+            CMD_DISCARD => {
+                if let Some(dev) = state.devices.get_mut(&device_id) {
+                    dev.events.remove(&event_id);
+                }
+            }
+            CMD_INT => self.translate_locked(vm, state, device_id, event_id),
+            // Pending state and LPI configuration are the redistributors', caches don't exist.
+            CMD_CLEAR | CMD_SYNC | CMD_INV | CMD_INVALL | CMD_MOVALL => {}
+            cmd_id => warn!("VM[{}] ITS command {:#x} not supported", self.vm_id, cmd_id),
+        }
+    }
+
+    /// Injects the LPI event `event_id` of device `device_id` is mapped to.
+    fn translate_locked(&self, vm: &VMRef, state: &ItsState, device_id: u32, event_id: u32) {
+        let target = state
+            .devices
+            .get(&device_id)
+            .and_then(|dev| dev.events.get(&event_id))
+            .and_then(|&(lpi, icid)| Some((lpi, *state.collections.get(&icid)?)));
+        let Some((lpi, vcpu)) = target else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            trace!(
+                "VM[{}] ITS unmapped device {:#x} event {}, dropped",
+                self.vm_id, device_id, event_id
+            );
+            return;
+        };
+        self.lpis.fetch_add(1, Ordering::Relaxed);
+        match vm.inject_interrupt_to_vcpu(CpuMask::one_shot(vcpu), lpi as usize) {
+            Ok(()) => stats::count_irq(self.vm_id, vcpu),
+            Err(e) => warn!(
+                "VM[{}] failed to inject LPI {} to VCpu[{}]: {:?}",
+                self.vm_id, lpi, vcpu, e
+            ),
+        }
+    }
+
+    fn translate(&self, vm: &VMRef, device_id: u32, event_id: u32) {
+        let state = self.state.lock();
+        if state.ctlr & CTLR_ENABLED == 0 {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.translate_locked(vm, &state, device_id, event_id);
+    }
+}
+
+impl MmioTrapHandler for VIts {
+    fn handle_read(&self, _vm: &VMRef, addr: GuestPhysAddr, width: AccessWidth) -> AxResult<usize> {
+        if matches!(width, AccessWidth::Byte | AccessWidth::Word) {
+            return ax_err!(InvalidInput, "ITS registers are 32 or 64-bit");
+        }
+        Ok(self.read(addr.as_usize() - self.base.as_usize(), width))
+    }
+
+    fn handle_write(
+        &self,
+        vm: &VMRef,
+        addr: GuestPhysAddr,
+        width: AccessWidth,
+        val: usize,
+    ) -> AxResult {
+        if matches!(width, AccessWidth::Byte | AccessWidth::Word) {
+            return ax_err!(InvalidInput, "ITS registers are 32 or 64-bit");
+        }
+        match addr.as_usize() - self.base.as_usize() {
+            // The requester of a CPU write is not a device, there's no device ID to translate.
+            GITS_TRANSLATER => trace!(
+                "VM[{}] ignored CPU write {:#x} to GITS_TRANSLATER",
+                self.vm_id, val
+            ),
+            offset => self.write(vm, offset, width, val),
+        }
+        Ok(())
+    }
+}
+
+static ITSES: Mutex<BTreeMap<usize, Arc<VIts>>> = Mutex::new(BTreeMap::new());
+
+/// Creates the ITS described in the `[its]` section of `raw_cfg` for the VM.
+///
+/// Does nothing if the VM config has no `[its]` section.
+pub fn setup_vm_its(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("its").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let Some(base) = cfg
+        .get("base")
+        .and_then(|v| v.as_integer())
+        .map(|v| v as usize)
+    else {
+        return ax_err!(InvalidInput, "its config: missing `base`");
+    };
+    if base % ITS_SIZE != 0 {
+        return ax_err!(InvalidInput, "its config: `base` must be 128 KiB aligned");
+    }
+    let id_bits = match cfg.get("id_bits").map(|v| v.as_integer()) {
+        None => DEFAULT_ID_BITS,
+        Some(Some(bits @ 14..=32)) => bits as u32,
+        Some(_) => {
+            return ax_err!(
+                InvalidInput,
+                "its config: `id_bits` must be between 14 and 32"
+            );
+        }
+    };
+
+    let vm_id = vm.id();
+    let its = Arc::new(VIts {
+        vm_id,
+        base: base.into(),
+        num_vcpus: vm.vcpu_num(),
+        id_bits,
+        state: Mutex::new(ItsState::default()),
+        lpis: AtomicU64::new(0),
+        dropped: AtomicU64::new(0),
+    });
+    mmio::register_trap(vm_id, its.base, ITS_SIZE, its.clone())?;
+    ITSES.lock().insert(vm_id, its);
+    info!("VM[{}] ITS at {:#x}, {} LPI ID bits", vm_id, base, id_bits);
+    Ok(())
+}
+
+/// Removes the ITS of a VM, called when the VM is destroyed.
+///
+/// Its MMIO trap goes away with the others of the VM.
+pub fn teardown_vm_its(vm_id: usize) {
+    ITSES.lock().remove(&vm_id);
+}
+
+/// Translates an MSI of device `device_id` of VM `vm_id` through its ITS and injects the
+/// resulting LPI, returns `false` if `addr` is not the `GITS_TRANSLATER` of an ITS of the VM.
+pub fn deliver_msi(vm_id: usize, device_id: u32, addr: u64, data: u32) -> bool {
+    let Some(its) = ITSES.lock().get(&vm_id).cloned() else {
+        return false;
+    };
+    if addr != (its.base.as_usize() + GITS_TRANSLATER) as u64 {
+        return false;
+    }
+    if let Some(vm) = vm_list::get_vm_by_id(vm_id) {
+        its.translate(&vm, device_id, data);
+    }
+    true
+}
+
+/// Returns the base, LPIs injected and MSIs dropped of the ITS of VM `vm_id`, if any.
+pub fn its_stats(vm_id: usize) -> Option<(GuestPhysAddr, u64, u64)> {
+    ITSES.lock().get(&vm_id).map(|its| {
+        (
+            its.base,
+            its.lpis.load(Ordering::Relaxed),
+            its.dropped.load(Ordering::Relaxed),
+        )
+    })
+}
@@ -103,11 +103,12 @@ pub fn get_vm_by_id(vm_id: usize) -> Option<VMRef> {
     GLOBAL_VM_LIST.lock().get_vm_by_id(vm_id)
 }
 
+/// Returns references to all VMs, ordered by VM ID.
 pub fn get_vm_list() -> Vec<VMRef> {
-    let global_vm_list = GLOBAL_VM_LIST.lock().vm_list.clone();
-    let mut vm_list = Vec::with_capacity(global_vm_list.len());
-    for (_id, vm) in global_vm_list {
-        vm_list.push(vm.clone());
-    }
-    vm_list
+    GLOBAL_VM_LIST.lock().vm_list.values().cloned().collect()
+}
+
+/// Returns the number of VMs in the global VM list.
+pub fn vm_count() -> usize {
+    GLOBAL_VM_LIST.lock().vm_list.len()
 }
@@ -0,0 +1,470 @@
+//! VMs defined at runtime by a manager VM.
+//!
+//! Besides the configs compiled in or read from the filesystem at boot, VMs can be defined at
+//! runtime by a manager VM, i.e. a VM whose config has:
+//!
+//! ```toml
+//! [manager]
+//! define_vms = true
+//! ```
+//!
+//! The manager passes the definition with the [`HVC_VM_DEFINE`](crate::vmm::hvc::HVC_VM_DEFINE)
+//! hypercall (`HVmDefine`): `args[0]` is the GPA of the definition, `args[1]` its size (at most
+//! [`MAX_DEFINITION_SIZE`]) and `args[2]` its format, [`VM_DEF_TOML`] for a VM config as read by
+//! `vm create` or [`VM_DEF_DTBO`] for a device tree overlay. The VM is booted once created if
+//! `args[3]` is 1. The hypercall returns the ID of the VM.
+//!
+//! Defining a VM with the ID of an existing VM reloads it: the existing VM must not be running
+//! (`Loaded` or `Stopped`), it is destroyed and recreated from the new definition.
+//!
+//! Nothing is created before the definition is validated against the remaining capacity of the
+//! host: the vCPUs must fit the host CPUs, the allocated memory regions the free host memory, the
+//! kernel image must be found and the PCI devices must not be assigned to another VM. The memory
+//! of a reloaded VM is only freed once it's destroyed, so it doesn't count as free.
+//!
+//! A device tree overlay defines the VM in an `axvisor-vm` node, whose properties are the keys of
+//! the `[base]`, `[kernel]` and `[devices]` sections of a VM config. The sections handled by the
+//! hypervisor itself (`[pci]`, `[peers]`, ...) are only available to TOML definitions:
+//!
+//! ```dts
+//! /dts-v1/;
+//! /plugin/;
+//!
+//! &{/} {
+//!     axvisor-vm {
+//!         id = <5>;
+//!         name = "rtos";
+//!         cpu-num = <1>;
+//!         phys-cpu-ids = <2>;
+//!         entry-point = /bits/ 64 <0x80200000>;
+//!         image-location = "fs";
+//!         kernel-path = "/guest/rtos.bin";
+//!         kernel-load-addr = /bits/ 64 <0x80200000>;
+//!         /* GPA, size, flags and map type of each region. */
+//!         memory-regions = /bits/ 64 <0x80000000 0x1000000 0x7 0>;
+//!         passthrough-devices = "/soc/serial@9000000";
+//!     };
+//! };
+//! ```
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use std::os::arceos::modules::axalloc;
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err, ax_err_type};
+use axvm::VMStatus;
+use axvm::config::{AxVMCrateConfig, VmMemMappingType};
+use fdt_parser::Fdt;
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::vmm::hvc::{self, HVC_VM_DEFINE, HvcService};
+use crate::vmm::{VMRef, add_running_vm_count, config, images, lazymem, pci, vcpus, vm_list};
+
+/// Format of a VM config in TOML.
+pub const VM_DEF_TOML: u64 = 0;
+/// Format of a device tree overlay with an `axvisor-vm` node.
+pub const VM_DEF_DTBO: u64 = 1;
+/// Maximum size of a VM definition.
+pub const MAX_DEFINITION_SIZE: usize = 0x10000;
+
+/// Name of the node of a device tree overlay defining a VM.
+const OVERLAY_NODE: &str = "axvisor-vm";
+
+/// Type of a property of the `axvisor-vm` node.
+#[derive(Clone, Copy)]
+enum PropKind {
+    U32,
+    U32Array,
+    /// A 32-bit or 64-bit integer.
+    U64,
+    /// Groups of four 64-bit integers, i.e. memory regions.
+    U64Quads,
+    Str,
+    /// A list of strings, each one becoming a single-element array like device paths.
+    StrList,
+}
+
+/// Properties of the `axvisor-vm` node: name, section and key in the VM config, and type.
+const OVERLAY_PROPS: &[(&str, &str, &str, PropKind)] = &[
+    ("id", "base", "id", PropKind::U32),
+    ("name", "base", "name", PropKind::Str),
+    ("vm-type", "base", "vm_type", PropKind::U32),
+    ("cpu-num", "base", "cpu_num", PropKind::U32),
+    ("phys-cpu-ids", "base", "phys_cpu_ids", PropKind::U32Array),
+    ("entry-point", "kernel", "entry_point", PropKind::U64),
+    ("image-location", "kernel", "image_location", PropKind::Str),
+    ("kernel-path", "kernel", "kernel_path", PropKind::Str),
+    (
+        "kernel-load-addr",
+        "kernel",
+        "kernel_load_addr",
+        PropKind::U64,
+    ),
+    ("dtb-path", "kernel", "dtb_path", PropKind::Str),
+    ("dtb-load-addr", "kernel", "dtb_load_addr", PropKind::U64),
+    ("ramdisk-path", "kernel", "ramdisk_path", PropKind::Str),
+    (
+        "ramdisk-load-addr",
+        "kernel",
+        "ramdisk_load_addr",
+        PropKind::U64,
+    ),
+    (
+        "memory-regions",
+        "kernel",
+        "memory_regions",
+        PropKind::U64Quads,
+    ),
+    (
+        "passthrough-devices",
+        "devices",
+        "passthrough_devices",
+        PropKind::StrList,
+    ),
+    (
+        "excluded-devices",
+        "devices",
+        "excluded_devices",
+        PropKind::StrList,
+    ),
+    ("interrupt-mode", "devices", "interrupt_mode", PropKind::Str),
+];
+
+/// VMs allowed to define VMs.
+static MANAGERS: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+/// Serializes definitions, so that a definition is validated against the VMs that exist when it
+/// is instantiated.
+static DEFINE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Allows the VM to define VMs if the `[manager]` section of `raw_cfg` sets `define_vms`.
+pub fn setup_vm_manager(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("manager").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let define_vms = match cfg.get("define_vms") {
+        None => false,
+        Some(v) => v.as_bool().ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                "manager config: `define_vms` must be a boolean"
+            )
+        })?,
+    };
+    if define_vms {
+        MANAGERS.lock().insert(vm.id());
+        info!("VM[{}] may define VMs", vm.id());
+    }
+    Ok(())
+}
+
+/// Removes the VM from the manager VMs, called when the VM is destroyed.
+pub fn teardown_vm_manager(vm_id: usize) {
+    MANAGERS.lock().remove(&vm_id);
+}
+
+/// Returns whether VM `vm_id` is a manager VM.
+pub fn is_manager(vm_id: usize) -> bool {
+    MANAGERS.lock().contains(&vm_id)
+}
+
+/// The [`HVC_VM_DEFINE`](crate::vmm::hvc::HVC_VM_DEFINE) hypercall, for manager VMs.
+pub static HVC_SERVICE: HvcService = HvcService {
+    name: "VM definition",
+    codes: HVC_VM_DEFINE..HVC_VM_DEFINE + 1,
+    permit: hvc::permit_managers,
+    deferrable: true,
+    handler: |vm, _, _, args| handle_define(vm, args),
+};
+
+/// Handles the `HVmDefine` hypercall of `vm`, returns the ID of the defined VM.
+fn handle_define(vm: &VMRef, args: [u64; 6]) -> AxResult<usize> {
+    let size = args[1] as usize;
+    if size == 0 || size > MAX_DEFINITION_SIZE {
+        return ax_err!(
+            InvalidInput,
+            format!("invalid VM definition size {:#x}", size)
+        );
+    }
+    let blob = read_guest(vm, args[0] as usize, size)?;
+    let raw_cfg = match args[2] {
+        VM_DEF_TOML => String::from_utf8(blob)
+            .map_err(|_| ax_err_type!(InvalidInput, "VM definition is not valid UTF-8"))?,
+        VM_DEF_DTBO => overlay_to_toml(&blob)?,
+        format => {
+            return ax_err!(
+                InvalidInput,
+                format!("unknown VM definition format {}", format)
+            );
+        }
+    };
+    info!(
+        "VM[{}] defines a VM from a {}-byte definition",
+        vm.id(),
+        size
+    );
+    define_vm(&raw_cfg, args[3] == 1)
+}
+
+/// Validates the VM config `raw_cfg` and instantiates it, replacing the existing VMs with the same
+/// IDs. Boots the VMs if `boot` is set. Returns the ID of the first VM.
+pub fn define_vm(raw_cfg: &str, boot: bool) -> AxResult<usize> {
+    let _guard = DEFINE_LOCK.lock();
+    let crate_cfg = AxVMCrateConfig::from_toml(raw_cfg)
+        .map_err(|e| ax_err_type!(InvalidInput, format!("invalid VM config: {:?}", e)))?;
+    let raw_table = config::parse_raw_vm_config(raw_cfg)?;
+    let instances = config::instance_count(&raw_table);
+
+    let replaced = validate(&crate_cfg, &raw_table, instances)?;
+    for vm in replaced {
+        info!("VM[{}] reloading", vm.id());
+        destroy_vm(vm);
+    }
+
+    let vm_id = config::init_guest_vm(raw_cfg)?;
+    if boot {
+        for id in vm_id..vm_id + instances {
+            if let Some(vm) = vm_list::get_vm_by_id(id) {
+                boot_vm(vm)?;
+            }
+        }
+    }
+    Ok(vm_id)
+}
+
+/// Checks the VM config against the remaining capacity of the host. Returns the existing VMs the
+/// config replaces.
+pub fn validate(
+    cfg: &AxVMCrateConfig,
+    raw_table: &toml::Table,
+    instances: usize,
+) -> AxResult<Vec<VMRef>> {
+    let host_cpus = axruntime::cpu_count();
+    let cpu_num = cfg.base.cpu_num;
+    if cpu_num == 0 || cpu_num > host_cpus {
+        return ax_err!(
+            InvalidInput,
+            format!(
+                "{} vCPUs requested, the host has {} CPUs",
+                cpu_num, host_cpus
+            )
+        );
+    }
+    if let Some(ids) = &cfg.base.phys_cpu_ids {
+        if ids.len() != cpu_num {
+            return ax_err!(
+                InvalidInput,
+                format!("{} physical CPU IDs for {} vCPUs", ids.len(), cpu_num)
+            );
+        }
+        // Physical CPU IDs are MPIDRs on aarch64, matched against the host device tree later.
+        #[cfg(not(target_arch = "aarch64"))]
+        if let Some(id) = ids.iter().find(|&&id| id >= host_cpus) {
+            return ax_err!(InvalidInput, format!("no host CPU {}", id));
+        }
+    }
+
+    let mut replaced = Vec::new();
+    for id in cfg.base.id..cfg.base.id + instances {
+        let Some(vm) = vm_list::get_vm_by_id(id) else {
+            continue;
+        };
+        match vm.vm_status() {
+            VMStatus::Loaded | VMStatus::Stopped => replaced.push(vm),
+            status => {
+                return ax_err!(ResourceBusy, format!("VM[{}] is {:?}", id, status));
+            }
+        }
+    }
+
+    let memory = instances
+        * cfg
+            .kernel
+            .memory_regions
+            .iter()
+            .enumerate()
+            .filter(|(idx, region)| {
+                !matches!(region.map_type, VmMemMappingType::MapReserved)
+                    && !lazymem::is_lazy(raw_table, *idx, region)
+            })
+            .map(|(_, region)| region.size)
+            .sum::<usize>();
+    let available = axalloc::global_allocator().available_pages() * PAGE_SIZE_4K;
+    if memory > available {
+        return ax_err!(
+            NoMemory,
+            format!(
+                "{:#x} bytes of memory requested, {:#x} available",
+                memory, available
+            )
+        );
+    }
+
+    images::check_kernel_image(cfg)?;
+    let releasing: Vec<usize> = replaced.iter().map(|vm| vm.id()).collect();
+    pci::check_vm_passthrough(raw_table, &releasing)?;
+    Ok(replaced)
+}
+
+/// Destroys a VM that is not running, like `vm delete` does.
+///
+/// Called by the vCPU of the defining VM, or a thread of its own, while the vCPUs of the other VMs
+/// run: the vCPUs of the VM are removed and re-created by [`boot_vm`] under the lock of the vCPUs
+/// of the VMs (see [`vcpus::cleanup_vm_vcpus`]), a kick meanwhile finds no vCPU to notify.
+pub fn destroy_vm(vm: VMRef) {
+    let vm_id = vm.id();
+    if vm.vm_status() == VMStatus::Loaded {
+        vm.set_vm_status(VMStatus::Stopped);
+    }
+    vm_list::remove_vm(vm_id);
+    vcpus::cleanup_vm_vcpus(vm_id);
+    super::teardown_vm_resources(&vm);
+}
+
+/// Boots a newly defined VM, like `vm start` does.
+pub fn boot_vm(vm: VMRef) -> AxResult {
+    vcpus::setup_vm_primary_vcpu(vm.clone());
+    vm.boot()?;
+    vcpus::notify_primary_vcpu(vm.id());
+    add_running_vm_count(1);
+    Ok(())
+}
+
+/// Copies `size` bytes at `gpa` out of the guest memory of `vm`.
+fn read_guest(vm: &VMRef, gpa: usize, size: usize) -> AxResult<Vec<u8>> {
+    let mut buf = Vec::with_capacity(size);
+    let mut offset = 0;
+    while offset + 8 <= size {
+        let word: u64 = vm.read_from_guest_of(GuestPhysAddr::from_usize(gpa + offset))?;
+        buf.extend_from_slice(&word.to_ne_bytes());
+        offset += 8;
+    }
+    while offset < size {
+        let byte: u8 = vm.read_from_guest_of(GuestPhysAddr::from_usize(gpa + offset))?;
+        buf.push(byte);
+        offset += 1;
+    }
+    Ok(buf)
+}
+
+fn be_cells<const N: usize>(raw: &[u8]) -> impl Iterator<Item = u64> + '_ {
+    raw.chunks_exact(N).map(|cell| {
+        cell.iter()
+            .fold(0u64, |value, &byte| (value << 8) | byte as u64)
+    })
+}
+
+fn overlay_string(name: &str, raw: &[u8]) -> AxResult<Vec<String>> {
+    raw.split(|&b| b == 0)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            core::str::from_utf8(s)
+                .ok()
+                .filter(|s| !s.chars().any(|c| c.is_control() || c == '"' || c == '\\'))
+                .map(|s| format!("\"{}\"", s))
+                .ok_or_else(|| {
+                    ax_err_type!(
+                        InvalidInput,
+                        format!("VM overlay: invalid string in `{}`", name)
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Converts the `axvisor-vm` node of a device tree overlay to a VM config in TOML.
+fn overlay_to_toml(dtbo: &[u8]) -> AxResult<String> {
+    let fdt = Fdt::from_bytes(dtbo)
+        .map_err(|e| ax_err_type!(InvalidInput, format!("invalid VM overlay: {:?}", e)))?;
+    let node = fdt
+        .all_nodes()
+        .find(|node| node.name() == OVERLAY_NODE)
+        .ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                format!("VM overlay: no `{}` node", OVERLAY_NODE)
+            )
+        })?;
+
+    let mut sections: [(&str, String); 3] = [
+        ("base", String::new()),
+        ("kernel", String::new()),
+        ("devices", String::new()),
+    ];
+    for prop in node.propertys() {
+        let Some(&(name, section, key, kind)) =
+            OVERLAY_PROPS.iter().find(|(name, ..)| *name == prop.name)
+        else {
+            continue;
+        };
+        let raw = prop.raw_value();
+        let value = match kind {
+            PropKind::U32 if raw.len() == 4 => format!("{:#x}", be_cells::<4>(raw).next().unwrap()),
+            PropKind::U64 if raw.len() == 4 => format!("{:#x}", be_cells::<4>(raw).next().unwrap()),
+            PropKind::U64 if raw.len() == 8 => format!("{:#x}", be_cells::<8>(raw).next().unwrap()),
+            PropKind::U32Array if raw.len() % 4 == 0 => {
+                let cells: Vec<String> = be_cells::<4>(raw).map(|c| format!("{:#x}", c)).collect();
+                format!("[{}]", cells.join(", "))
+            }
+            PropKind::U64Quads if raw.len() % 32 == 0 => {
+                let cells: Vec<u64> = be_cells::<8>(raw).collect();
+                let regions: Vec<String> = cells
+                    .chunks_exact(4)
+                    .map(|r| format!("[{:#x}, {:#x}, {:#x}, {}]", r[0], r[1], r[2], r[3]))
+                    .collect();
+                format!("[{}]", regions.join(", "))
+            }
+            PropKind::Str => {
+                let strings = overlay_string(name, raw)?;
+                match strings.as_slice() {
+                    [s] => s.clone(),
+                    _ => {
+                        return ax_err!(
+                            InvalidInput,
+                            format!("VM overlay: `{}` must be a single string", name)
+                        );
+                    }
+                }
+            }
+            PropKind::StrList => {
+                let entries: Vec<String> = overlay_string(name, raw)?
+                    .into_iter()
+                    .map(|s| format!("[{}]", s))
+                    .collect();
+                format!("[{}]", entries.join(", "))
+            }
+            _ => {
+                return ax_err!(
+                    InvalidInput,
+                    format!("VM overlay: invalid size of `{}`", name)
+                );
+            }
+        };
+        let (_, lines) = sections.iter_mut().find(|(s, _)| *s == section).unwrap();
+        let _ = writeln!(lines, "{} = {}", key, value);
+    }
+
+    let mut toml = String::new();
+    for (section, lines) in sections.iter() {
+        let _ = writeln!(toml, "[{}]\n{}", section, lines);
+        match *section {
+            "base" if !lines.contains("vm_type =") => toml.push_str("vm_type = 1\n"),
+            "devices" => {
+                for key in [
+                    "passthrough_devices",
+                    "passthrough_addresses",
+                    "emu_devices",
+                ] {
+                    if !lines.contains(&format!("{} =", key)) {
+                        let _ = writeln!(toml, "{} = []", key);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    debug!("VM overlay converted to config:\n{}", toml);
+    Ok(toml)
+}
@@ -0,0 +1,554 @@
+//! Introspection events of guests, delivered to a manager VM.
+//!
+//! A manager VM (see [`crate::vmm::vmdef`]) watching another VM for a security monitor subscribes
+//! to its events with the [`HVC_VMI_CONTROL`] hypercall, `args[0]` being the peer handle of the
+//! watched VM and `args[1]` the operation:
+//!
+//! - [`VMI_SUBSCRIBE`]: the events go to the raw IVC channel with key `args[2]` published by the
+//!   caller. A VM has one subscriber at a time, and a channel one watched VM;
+//! - [`VMI_UNSUBSCRIBE`]: stops the events and removes the watches;
+//! - [`VMI_WATCH_MEM`]: watches the accesses `args[4]` (`VMI_ACCESS_*`, 0 to stop watching) to
+//!   the `args[3]` bytes of guest RAM at GPA `args[2]`, both page aligned;
+//! - [`VMI_WATCH_REG`]: watches (`args[3] == 1`) or stops watching (`args[3] == 0`) the writes
+//!   to the system register `args[2]`, named as in the system register exits of the vCPUs: the
+//!   MSR index on x86, the `ESR_EL2` encoding on aarch64, the CSR number on riscv64;
+//! - [`VMI_SINGLE_STEP`]: single-steps vCPU `args[2]`.
+//!
+//! The data of the channel is a [`VmiLogHeader`] followed, from offset [`VMI_EVENTS_OFFSET`], by
+//! `capacity` [`VmiEvent`]s used as a ring: `head` is the number of events written since the
+//! subscription and event `n` is in slot `n % capacity`, so a reader more than `capacity` events
+//! behind `head` lost events and resumes at `head`. The subscriber is notified of new events with
+//! the vector it registered for the channel.
+//!
+//! Watched pages are mapped without the watched accesses in the stage-2 of the VM; an access of
+//! the guest faults, is reported and the page is opened until the next exit of the vCPU, so that
+//! the access completes, then protected again. Accesses of other vCPUs while the page is open are
+//! missed. Reads can't be watched: the hypervisor reads guest memory through the stage-2 too.
+//! Watched pages are split from their block mappings (see [`crate::vmm::blocks`]), and watches
+//! are refused with passthrough DMA, dirty tracking or heat sampling, which protect pages too.
+//!
+//! Register events are raised for the writes the vCPU backend reports as system register exits.
+//! The page table base registers only exit when the backend traps them (`HCR_EL2.TVM` on
+//! aarch64, `hstatus.VTVM` on riscv64), which the backends don't do for axvisor yet, so watching
+//! `TTBR0_EL1`, `TTBR1_EL1` or `satp` fails with `Unsupported`; CR3 is no MSR and can't be named
+//! on x86, where CR3-load exiting isn't enabled either. Single stepping needs a debug exit the
+//! backends don't have: [`VMI_SINGLE_STEP`] fails with `Unsupported` too.
+//!
+//! The manager needs the watched VM to allow introspection, see
+//! [`crate::vmm::security::Introspection`].
+//!
+//! [`HVC_VMI_CONTROL`]: crate::vmm::hvc::HVC_VMI_CONTROL
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use core::mem::{offset_of, size_of};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::{AxMmHal, GuestPhysAddr, HostPhysAddr, MappingFlags};
+use axerrno::{AxResult, ax_err};
+use axvcpu::AxVCpuExitReason;
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::hal::AxMmHalImpl;
+use crate::vmm::blocks::{self, RAM_FLAGS};
+use crate::vmm::dirty::{self, remap};
+use crate::vmm::hvc::{self, HVC_VMI_CONTROL, HvcService};
+use crate::vmm::security::Introspection;
+use crate::vmm::{VMRef, heatmap, introspect, iommu, ivc, vm_list};
+
+/// Operations of [`HVC_VMI_CONTROL`](crate::vmm::hvc::HVC_VMI_CONTROL), in `args[1]`.
+pub const VMI_SUBSCRIBE: u64 = 0;
+pub const VMI_UNSUBSCRIBE: u64 = 1;
+pub const VMI_WATCH_MEM: u64 = 2;
+pub const VMI_WATCH_REG: u64 = 3;
+pub const VMI_SINGLE_STEP: u64 = 4;
+
+/// Accesses of [`VMI_WATCH_MEM`] and of the memory events.
+pub const VMI_ACCESS_READ: u64 = 1 << 0;
+pub const VMI_ACCESS_WRITE: u64 = 1 << 1;
+pub const VMI_ACCESS_EXEC: u64 = 1 << 2;
+
+/// Events, in `VmiEvent::kind`.
+pub const VMI_EVENT_MEM: u32 = 1;
+pub const VMI_EVENT_REG: u32 = 2;
+
+/// `magic` of [`VmiLogHeader`], "AXVI".
+pub const VMI_MAGIC: u32 = u32::from_le_bytes(*b"AXVI");
+/// Version of the layouts of the log.
+pub const VMI_VERSION: u16 = 1;
+/// Offset of the first event in the data of the channel.
+pub const VMI_EVENTS_OFFSET: usize = 64;
+/// Pages watched in a VM, at most.
+pub const MAX_VMI_WATCHED_PAGES: usize = 4096;
+/// Registers watched in a VM, at most.
+pub const MAX_VMI_WATCHED_REGS: usize = 16;
+
+/// Page table base registers, whose writes don't exit: `TTBR0_EL1` and `TTBR1_EL1` as encoded in
+/// `ESR_EL2`, `satp`.
+#[cfg(target_arch = "aarch64")]
+const UNTRAPPED_REGS: [u64; 2] = [0x30_0800, 0x32_0800];
+#[cfg(target_arch = "riscv64")]
+const UNTRAPPED_REGS: [u64; 1] = [0x180];
+#[cfg(target_arch = "x86_64")]
+const UNTRAPPED_REGS: [u64; 0] = [];
+
+/// Header of the data of the channel.
+#[repr(C)]
+pub struct VmiLogHeader {
+    pub magic: u32,
+    pub version: u16,
+    /// Size of a [`VmiEvent`].
+    pub event_size: u16,
+    /// Events in the ring.
+    pub capacity: u32,
+    pub _reserved: u32,
+    /// Events written since the subscription.
+    pub head: AtomicU64,
+}
+
+/// An introspection event.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct VmiEvent {
+    /// `VMI_EVENT_*`.
+    pub kind: u32,
+    pub vcpu_id: u32,
+    /// Host time of the event in nanoseconds.
+    pub time_ns: u64,
+    /// GPA accessed, or the register written.
+    pub addr: u64,
+    /// Access faulting (`VMI_ACCESS_*`), or the value written.
+    pub value: u64,
+}
+
+struct Subscription {
+    manager_vm_id: usize,
+    key: usize,
+    capacity: usize,
+    head: u64,
+    /// Watched accesses of the watched pages, indexed by GPA.
+    pages: BTreeMap<usize, u64>,
+    regs: BTreeSet<u64>,
+    /// Page opened for the access of a vCPU, protected again at its next exit.
+    open: BTreeMap<usize, usize>,
+}
+
+/// Subscriptions, indexed by the ID of the watched VM.
+static SUBSCRIPTIONS: Mutex<BTreeMap<usize, Subscription>> = Mutex::new(BTreeMap::new());
+/// Number of subscriptions, so that exits skip the lock when there are none.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Introspection events of a VM, for monitoring.
+#[derive(Debug, Clone, Copy)]
+pub struct VmiStats {
+    pub manager_vm_id: usize,
+    pub watched_pages: usize,
+    pub watched_regs: usize,
+    pub events: u64,
+}
+
+/// The introspection event hypercall, for manager VMs.
+pub static HVC_SERVICE: HvcService = HvcService {
+    name: "vmi",
+    codes: HVC_VMI_CONTROL..HVC_VMI_CONTROL + 1,
+    permit: hvc::permit_managers,
+    deferrable: false,
+    handler: |vm, _, _, args| handle_control(vm, args).map(|_| 0),
+};
+
+/// Flags of a watched page in the stage-2.
+fn watched_flags(access: u64) -> MappingFlags {
+    let mut flags = RAM_FLAGS;
+    if access & VMI_ACCESS_WRITE != 0 {
+        flags -= MappingFlags::WRITE;
+    }
+    if access & VMI_ACCESS_EXEC != 0 {
+        flags -= MappingFlags::EXECUTE;
+    }
+    flags
+}
+
+/// Host physical address of `page` in the RAM of `vm`.
+fn ram_hpa(vm: &VMRef, page: usize) -> Option<HostPhysAddr> {
+    vm.memory_regions().iter().find_map(|region| {
+        let gpa = region.gpa.as_usize();
+        (gpa..gpa + region.size())
+            .contains(&page)
+            .then(|| AxMmHalImpl::virt_to_phys(region.hva) + (page - gpa))
+    })
+}
+
+/// Maps `page` of `vm` with `flags`.
+fn remap_page(vm: &VMRef, page: usize, flags: MappingFlags) -> AxResult {
+    let Some(hpa) = ram_hpa(vm, page) else {
+        return ax_err!(InvalidInput, format!("{:#x} is not guest RAM", page));
+    };
+    remap(vm, page, hpa, PAGE_SIZE_4K, flags)
+}
+
+/// Handles the `HVMIControl` hypercall of the manager `vm`.
+fn handle_control(vm: &VMRef, args: [u64; 6]) -> AxResult {
+    let target = introspect::target_vm(vm, args[0], Introspection::Read)?;
+    match args[1] {
+        VMI_SUBSCRIBE => subscribe(vm.id(), &target, args[2] as usize),
+        VMI_UNSUBSCRIBE => unsubscribe(vm.id(), &target),
+        VMI_WATCH_MEM => watch_mem(vm.id(), &target, args),
+        VMI_WATCH_REG => watch_reg(vm.id(), &target, args),
+        VMI_SINGLE_STEP => ax_err!(
+            Unsupported,
+            "VMI single step: the vCPU backend has no single-step exit"
+        ),
+        op => ax_err!(InvalidInput, format!("unknown VMI operation {}", op)),
+    }
+}
+
+fn subscribe(manager_vm_id: usize, target: &VMRef, key: usize) -> AxResult {
+    let mut subscriptions = SUBSCRIPTIONS.lock();
+    if let Some(other) = subscriptions.get(&target.id()) {
+        return ax_err!(
+            ResourceBusy,
+            format!(
+                "VM[{}] is watched by VM[{}]",
+                target.id(),
+                other.manager_vm_id
+            )
+        );
+    }
+    if subscriptions
+        .values()
+        .any(|sub| sub.manager_vm_id == manager_vm_id && sub.key == key)
+    {
+        return ax_err!(
+            ResourceBusy,
+            format!("IVC channel {:#x} already receives VMI events", key)
+        );
+    }
+    let capacity = ivc::write_raw_channel(manager_vm_id, key, |data| {
+        let capacity = data.len().saturating_sub(VMI_EVENTS_OFFSET) / size_of::<VmiEvent>();
+        if capacity == 0 {
+            return 0;
+        }
+        data.fill(0);
+        let header = VmiLogHeader {
+            magic: VMI_MAGIC,
+            version: VMI_VERSION,
+            event_size: size_of::<VmiEvent>() as u16,
+            capacity: capacity as u32,
+            _reserved: 0,
+            head: AtomicU64::new(0),
+        };
+        // SAFETY: the header fits in `data`, which is longer than `VMI_EVENTS_OFFSET`.
+        unsafe { data.as_mut_ptr().cast::<VmiLogHeader>().write_unaligned(header) };
+        capacity
+    })?;
+    if capacity == 0 {
+        return ax_err!(InvalidInput, "IVC channel too small for the VMI events");
+    }
+    subscriptions.insert(
+        target.id(),
+        Subscription {
+            manager_vm_id,
+            key,
+            capacity,
+            head: 0,
+            pages: BTreeMap::new(),
+            regs: BTreeSet::new(),
+            open: BTreeMap::new(),
+        },
+    );
+    ACTIVE.fetch_add(1, Ordering::Relaxed);
+    info!(
+        "VM[{}] subscribed to the VMI events of VM[{}], channel {:#x}",
+        manager_vm_id,
+        target.id(),
+        key
+    );
+    Ok(())
+}
+
+/// Returns the subscription of `manager_vm_id` to the events of VM `target_id`.
+fn subscription_of(
+    subscriptions: &mut BTreeMap<usize, Subscription>,
+    manager_vm_id: usize,
+    target_id: usize,
+) -> AxResult<&mut Subscription> {
+    match subscriptions.get_mut(&target_id) {
+        Some(sub) if sub.manager_vm_id == manager_vm_id => Ok(sub),
+        _ => ax_err!(
+            NotFound,
+            format!(
+                "VM[{}] is not subscribed to the VMI events of VM[{}]",
+                manager_vm_id, target_id
+            )
+        ),
+    }
+}
+
+/// Removes the subscription to the events of `target`, its watched pages mapped as RAM again.
+fn remove(target: &VMRef, sub: Subscription) -> AxResult {
+    ACTIVE.fetch_sub(1, Ordering::Relaxed);
+    for page in sub.pages.keys() {
+        remap_page(target, *page, RAM_FLAGS)?;
+    }
+    Ok(())
+}
+
+fn unsubscribe(manager_vm_id: usize, target: &VMRef) -> AxResult {
+    let sub = {
+        let mut subscriptions = SUBSCRIPTIONS.lock();
+        subscription_of(&mut subscriptions, manager_vm_id, target.id())?;
+        subscriptions.remove(&target.id()).unwrap()
+    };
+    info!(
+        "VM[{}] unsubscribed from the VMI events of VM[{}]",
+        manager_vm_id,
+        target.id()
+    );
+    remove(target, sub)
+}
+
+fn watch_mem(manager_vm_id: usize, target: &VMRef, args: [u64; 6]) -> AxResult {
+    let access = args[4];
+    if access & !(VMI_ACCESS_READ | VMI_ACCESS_WRITE | VMI_ACCESS_EXEC) != 0 {
+        return ax_err!(InvalidInput, format!("invalid VMI accesses {:#x}", access));
+    }
+    if access & VMI_ACCESS_READ != 0 {
+        return ax_err!(Unsupported, "reads of guest memory can't be watched");
+    }
+    let size = hvc::arg_count(
+        args[3],
+        MAX_VMI_WATCHED_PAGES * PAGE_SIZE_4K,
+        "VMI watch size",
+    )?;
+    if size % PAGE_SIZE_4K != 0 {
+        return ax_err!(InvalidInput, "VMI watch size not page aligned");
+    }
+    let gpa = hvc::guest_range(target, args[2], size, PAGE_SIZE_4K)?;
+    let target_id = target.id();
+    if access != 0 {
+        if iommu::has_domain(target_id) {
+            return ax_err!(Unsupported, format!("VM[{}] has passthrough DMA", target_id));
+        }
+        if dirty::is_tracking(target_id) || heatmap::is_sampling(target_id) {
+            return ax_err!(
+                ResourceBusy,
+                format!("VM[{}] pages are protected for tracking", target_id)
+            );
+        }
+    }
+
+    let mut subscriptions = SUBSCRIPTIONS.lock();
+    let sub = subscription_of(&mut subscriptions, manager_vm_id, target_id)?;
+    let pages = (gpa.as_usize()..gpa.as_usize() + size).step_by(PAGE_SIZE_4K);
+    if access == 0 {
+        for page in pages {
+            if sub.pages.remove(&page).is_some() {
+                remap_page(target, page, RAM_FLAGS)?;
+            }
+        }
+        return Ok(());
+    }
+    let added = pages
+        .clone()
+        .filter(|page| !sub.pages.contains_key(page))
+        .count();
+    if sub.pages.len() + added > MAX_VMI_WATCHED_PAGES {
+        return ax_err!(
+            NoMemory,
+            format!("at most {} watched pages", MAX_VMI_WATCHED_PAGES)
+        );
+    }
+    blocks::split_blocks(target, gpa, size)?;
+    for page in pages {
+        sub.pages.insert(page, access);
+        remap_page(target, page, watched_flags(access))?;
+    }
+    debug!(
+        "VM[{}] watches {:#x} bytes at {:?} of VM[{}], accesses {:#x}",
+        manager_vm_id, size, gpa, target_id, access
+    );
+    Ok(())
+}
+
+fn watch_reg(manager_vm_id: usize, target: &VMRef, args: [u64; 6]) -> AxResult {
+    if args[3] == 1 && UNTRAPPED_REGS.contains(&args[2]) {
+        return ax_err!(
+            Unsupported,
+            format!(
+                "VMI register watch: writes to page table base register {:#x} don't exit",
+                args[2]
+            )
+        );
+    }
+    let mut subscriptions = SUBSCRIPTIONS.lock();
+    let sub = subscription_of(&mut subscriptions, manager_vm_id, target.id())?;
+    match args[3] {
+        0 => {
+            sub.regs.remove(&args[2]);
+        }
+        1 if sub.regs.len() < MAX_VMI_WATCHED_REGS || sub.regs.contains(&args[2]) => {
+            sub.regs.insert(args[2]);
+        }
+        1 => {
+            return ax_err!(
+                NoMemory,
+                format!("at most {} watched registers", MAX_VMI_WATCHED_REGS)
+            );
+        }
+        _ => return ax_err!(InvalidInput, "VMI register watch must be 0 or 1"),
+    }
+    Ok(())
+}
+
+/// Appends `event` to the log of `sub`.
+fn emit(sub: &mut Subscription, event: VmiEvent) {
+    let written = ivc::write_raw_channel(sub.manager_vm_id, sub.key, |data| {
+        let (header, events) = data.split_at_mut(VMI_EVENTS_OFFSET);
+        let slot = (sub.head % sub.capacity as u64) as usize * size_of::<VmiEvent>();
+        // SAFETY: the slot is in the ring, `subscribe` sized it to the channel.
+        unsafe {
+            events[slot..]
+                .as_mut_ptr()
+                .cast::<VmiEvent>()
+                .write_unaligned(event)
+        };
+        let head = header[offset_of!(VmiLogHeader, head)..].as_mut_ptr();
+        // SAFETY: `head` is 8-byte aligned in the page of the channel, and guests only read it.
+        unsafe { (*head.cast::<AtomicU64>()).store(sub.head + 1, Ordering::Release) };
+    });
+    match written {
+        Ok(()) => sub.head += 1,
+        Err(e) => warn!("VM[{}] lost a VMI event: {:?}", sub.manager_vm_id, e),
+    }
+}
+
+/// Reports the exit of vCPU `vcpu_id` of `vm`, called for every exit before it is handled.
+///
+/// Protects the page the vCPU faulted on again and reports the watched register writes.
+pub fn on_exit(vm: &VMRef, vcpu_id: usize, reason: &AxVCpuExitReason) {
+    if ACTIVE.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let mut subscriptions = SUBSCRIPTIONS.lock();
+    let Some(sub) = subscriptions.get_mut(&vm.id()) else {
+        return;
+    };
+    if let Some(page) = sub.open.remove(&vcpu_id)
+        && let Some(access) = sub.pages.get(&page).copied()
+        && let Err(e) = remap_page(vm, page, watched_flags(access))
+    {
+        warn!(
+            "VM[{}] VCpu[{}] failed to protect watched page {:#x}: {:?}",
+            vm.id(),
+            vcpu_id,
+            page,
+            e
+        );
+    }
+    if let AxVCpuExitReason::SysRegWrite { addr, value } = reason
+        && sub.regs.contains(&(addr.addr() as u64))
+    {
+        let event = VmiEvent {
+            kind: VMI_EVENT_REG,
+            vcpu_id: vcpu_id as u32,
+            time_ns: axhal::time::monotonic_time_nanos(),
+            addr: addr.addr() as u64,
+            value: *value,
+        };
+        emit(sub, event);
+    }
+}
+
+/// Handles a stage-2 fault of vCPU `vcpu_id` of the VM at `gpa` with `access`.
+///
+/// Returns false if it's not a watched access, in which case the fault is for someone else.
+pub fn handle_fault(
+    vm: &VMRef,
+    vcpu_id: usize,
+    gpa: GuestPhysAddr,
+    access: MappingFlags,
+) -> AxResult<bool> {
+    if ACTIVE.load(Ordering::Relaxed) == 0 {
+        return Ok(false);
+    }
+    let mut subscriptions = SUBSCRIPTIONS.lock();
+    let Some(sub) = subscriptions.get_mut(&vm.id()) else {
+        return Ok(false);
+    };
+    let page = gpa.as_usize() & !(PAGE_SIZE_4K - 1);
+    let Some(watched) = sub.pages.get(&page).copied() else {
+        return Ok(false);
+    };
+    let mut faulting = 0;
+    if access.contains(MappingFlags::WRITE) {
+        faulting |= VMI_ACCESS_WRITE;
+    }
+    if access.contains(MappingFlags::EXECUTE) {
+        faulting |= VMI_ACCESS_EXEC;
+    }
+    if faulting & watched == 0 {
+        return Ok(false);
+    }
+    // Opened until the next exit of the vCPU, so that the access completes.
+    remap_page(vm, page, RAM_FLAGS)?;
+    sub.open.insert(vcpu_id, page);
+    let event = VmiEvent {
+        kind: VMI_EVENT_MEM,
+        vcpu_id: vcpu_id as u32,
+        time_ns: axhal::time::monotonic_time_nanos(),
+        addr: gpa.as_usize() as u64,
+        value: faulting,
+    };
+    emit(sub, event);
+    Ok(true)
+}
+
+/// Whether pages of VM `vm_id` are watched.
+pub fn has_watches(vm_id: usize) -> bool {
+    SUBSCRIPTIONS
+        .lock()
+        .get(&vm_id)
+        .is_some_and(|sub| !sub.pages.is_empty())
+}
+
+/// Returns the introspection events of VM `vm_id`, `None` if no manager subscribed to them.
+pub fn vmi_stats(vm_id: usize) -> Option<VmiStats> {
+    SUBSCRIPTIONS.lock().get(&vm_id).map(|sub| VmiStats {
+        manager_vm_id: sub.manager_vm_id,
+        watched_pages: sub.pages.len(),
+        watched_regs: sub.regs.len(),
+        events: sub.head,
+    })
+}
+
+/// Removes the subscriptions of and to the VM, called when the VM is destroyed.
+pub fn teardown_vm_vmi(vm_id: usize) {
+    let removed: Vec<_> = {
+        let mut subscriptions = SUBSCRIPTIONS.lock();
+        if subscriptions.remove(&vm_id).is_some() {
+            ACTIVE.fetch_sub(1, Ordering::Relaxed);
+        }
+        let targets: Vec<_> = subscriptions
+            .iter()
+            .filter(|(_, sub)| sub.manager_vm_id == vm_id)
+            .map(|(target_id, _)| *target_id)
+            .collect();
+        targets
+            .into_iter()
+            .filter_map(|target_id| Some((target_id, subscriptions.remove(&target_id)?)))
+            .collect()
+    };
+    for (target_id, sub) in removed {
+        let Some(target) = vm_list::get_vm_by_id(target_id) else {
+            ACTIVE.fetch_sub(1, Ordering::Relaxed);
+            continue;
+        };
+        if let Err(e) = remove(&target, sub) {
+            warn!(
+                "VM[{}] failed to unwatch the pages of VM[{}]: {:?}",
+                vm_id, target_id, e
+            );
+        }
+    }
+}
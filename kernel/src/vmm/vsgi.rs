@@ -0,0 +1,128 @@
+//! Delivery of the SGIs aarch64 guests send between their own vCPUs.
+//!
+//! A guest sends an SGI, its IPI, by writing `ICC_SGI1R_EL1`, which traps to the hypervisor. An
+//! SGI to the sender itself is written to a list register of the CPU right away. How an SGI
+//! reaches another vCPU is the [`SgiMode`] of the platform:
+//!
+//! - [`SgiMode::ListRegs`]: the SGI is posted to the target vCPU (see [`crate::vmm::posted`]),
+//!   which interrupts the target only if it runs in the guest on another CPU, and is written to a
+//!   list register by the target itself at its next guest entry;
+//! - [`SgiMode::Software`]: the SGI is injected through the VM like the interrupts of the
+//!   emulated devices, which is slower but leaves every SGI to the vGIC backend.
+//!
+//! The list registers are used by default, with any GIC. A board selects the software path with
+//! the `soft-sgi` feature in its config, e.g. to work around a GIC erratum, and the `sgi` shell
+//! command switches the mode at run time.
+//!
+//! A GICv4.1 could deliver the SGIs of a VM without the hypervisor, as vSGIs of the vPEs of the
+//! vCPUs, which the redistributors make pending in a resident vPE directly. The vPEs belong to the
+//! vCPU backend, which doesn't set them up yet: a GICv4.1 is detected and reported, see
+//! [`vsgi_capable`], and its SGIs take the list registers until then.
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+
+use cpumask::CpuMask;
+
+use crate::hal::arch::has_gicv4;
+use crate::vmm::{VMRef, posted};
+
+/// How the SGIs of a vCPU to another vCPU of its VM are delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SgiMode {
+    /// Injected through the VM.
+    Software = 0,
+    /// Posted to the target vCPU, which writes them to its list registers.
+    ListRegs = 1,
+}
+
+impl SgiMode {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Software,
+            _ => Self::ListRegs,
+        }
+    }
+}
+
+/// Counters of the SGIs delivered to another vCPU, see [`stats`].
+#[derive(Debug, Clone, Copy)]
+pub struct SgiStats {
+    /// SGIs posted to another vCPU.
+    pub posted: u64,
+    /// SGIs injected through the VM, in software mode or when posting failed.
+    pub software: u64,
+}
+
+static MODE: AtomicU8 = AtomicU8::new(SgiMode::ListRegs as u8);
+static VSGI_CAPABLE: AtomicBool = AtomicBool::new(false);
+static POSTED: AtomicU64 = AtomicU64::new(0);
+static SOFTWARE: AtomicU64 = AtomicU64::new(0);
+
+/// Selects the SGI mode of the platform.
+pub fn init() {
+    if cfg!(feature = "soft-sgi") {
+        set_mode(SgiMode::Software);
+    }
+    let gicv3 = rdrive::get_one::<rdif_intc::Intc>().is_some_and(|gic| {
+        gic.lock()
+            .unwrap()
+            .typed_mut::<arm_gic_driver::v3::Gic>()
+            .is_some()
+    });
+    VSGI_CAPABLE.store(gicv3 && has_gicv4(), Ordering::Relaxed);
+    if vsgi_capable() {
+        info!(
+            "SGI delivery: {:?}, GICv4.1 vSGIs available but not set up by the vCPU backend",
+            mode()
+        );
+    } else {
+        info!("SGI delivery: {:?}", mode());
+    }
+}
+
+/// Returns how the SGIs between vCPUs are delivered.
+pub fn mode() -> SgiMode {
+    SgiMode::from_u8(MODE.load(Ordering::Relaxed))
+}
+
+/// Sets how the SGIs between vCPUs are delivered, from the next SGI on.
+pub fn set_mode(mode: SgiMode) {
+    MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+/// Whether the GIC could deliver the SGIs of the guests as GICv4.1 vSGIs.
+pub fn vsgi_capable() -> bool {
+    VSGI_CAPABLE.load(Ordering::Relaxed)
+}
+
+/// Returns the SGI delivery counters of all the VMs.
+pub fn stats() -> SgiStats {
+    SgiStats {
+        posted: POSTED.load(Ordering::Relaxed),
+        software: SOFTWARE.load(Ordering::Relaxed),
+    }
+}
+
+/// Delivers SGI `sgi` of a vCPU of `vm` to another vCPU `target` of the VM.
+pub fn send(vm: &VMRef, target: usize, sgi: usize) {
+    if mode() == SgiMode::ListRegs {
+        match posted::post(vm.id(), target, sgi) {
+            Ok(()) => {
+                POSTED.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            Err(e) => debug!("VM[{}] SGI {} not posted: {:?}", vm.id(), sgi, e),
+        }
+    }
+    if let Err(e) = vm.inject_interrupt_to_vcpu(CpuMask::one_shot(target), sgi) {
+        warn!(
+            "VM[{}] SGI {} to VCpu[{}] dropped: {:?}",
+            vm.id(),
+            sgi,
+            target,
+            e
+        );
+        return;
+    }
+    SOFTWARE.fetch_add(1, Ordering::Relaxed);
+}
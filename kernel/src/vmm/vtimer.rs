@@ -0,0 +1,95 @@
+//! Expiry of the emulated guest physical timers.
+//!
+//! The virtual timer (`CNTV_CTL_EL0`, `CNTV_CVAL_EL0`) and the virtual offset (`CNTVOFF_EL2`) are
+//! part of the vCPU context, saved and restored by the vCPU on every exit and entry: each vCPU has
+//! its own timer state which follows it across physical CPUs, and guest accesses to the virtual
+//! timer never trap.
+//!
+//! The EL1 physical timer (`CNTP_*`) is emulated with host timers instead. A host timer expires on
+//! the physical CPU which registered it, which does not necessarily run the vCPU anymore, so the
+//! expiry is only recorded here and the timer interrupt is injected by the vCPU task itself before
+//! it enters the guest, see [`deliver_pending`]. While the VM is suspended, expirations stay
+//! pending and are delivered on resume.
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use crate::vmm::{VCpuRef, stats, vcpus};
+
+/// The non-secure EL1 physical timer PPI.
+const PHYS_TIMER_PPI: usize = 30;
+
+#[derive(Default)]
+struct VmTimers {
+    /// Bitmap of the vCPUs with an expired timer not yet injected.
+    pending: u64,
+    /// Number of expirations per vCPU.
+    expirations: BTreeMap<usize, u64>,
+}
+
+static TIMERS: Mutex<BTreeMap<usize, VmTimers>> = Mutex::new(BTreeMap::new());
+/// Whether any VM has pending expirations, checked without the lock on every guest entry.
+static HAS_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Records the expiry of the physical timer of a vCPU and wakes the vCPU if it is halted.
+pub fn notify_expired(vm_id: usize, vcpu_id: usize) {
+    if vcpu_id >= u64::BITS as usize {
+        warn!("VM[{}] VCpu[{}] timer expiry ignored", vm_id, vcpu_id);
+        return;
+    }
+    {
+        let mut timers = TIMERS.lock();
+        let vm_timers = timers.entry(vm_id).or_default();
+        vm_timers.pending |= 1 << vcpu_id;
+        *vm_timers.expirations.entry(vcpu_id).or_default() += 1;
+        HAS_PENDING.store(true, Ordering::Release);
+    }
+    vcpus::notify_all_vcpus(vm_id);
+}
+
+/// Injects the timer interrupt of `vcpu` of VM `vm_id` if its timer expired, called by the vCPU
+/// task before it enters the guest.
+#[inline]
+pub fn deliver_pending(vm_id: usize, vcpu: &VCpuRef) {
+    if !HAS_PENDING.load(Ordering::Acquire) || vcpu.id() >= u64::BITS as usize {
+        return;
+    }
+    let expired = {
+        let mut timers = TIMERS.lock();
+        let Some(vm_timers) = timers.get_mut(&vm_id) else {
+            return;
+        };
+        let bit = 1u64 << vcpu.id();
+        let expired = vm_timers.pending & bit != 0;
+        vm_timers.pending &= !bit;
+        HAS_PENDING.store(timers.values().any(|t| t.pending != 0), Ordering::Release);
+        expired
+    };
+    if !expired {
+        return;
+    }
+    match vcpu.inject_interrupt(PHYS_TIMER_PPI) {
+        Ok(()) => stats::count_irq(vm_id, vcpu.id()),
+        Err(e) => warn!(
+            "VM[{}] VCpu[{}] failed to inject timer interrupt: {:?}",
+            vm_id,
+            vcpu.id(),
+            e
+        ),
+    }
+}
+
+/// Forgets the timers of a VM, called when the VM is destroyed.
+pub fn teardown_vm_timers(vm_id: usize) {
+    TIMERS.lock().remove(&vm_id);
+}
+
+/// Returns the number of physical timer expirations of each vCPU of a VM.
+pub fn timer_stats(vm_id: usize) -> BTreeMap<usize, u64> {
+    TIMERS
+        .lock()
+        .get(&vm_id)
+        .map(|t| t.expirations.clone())
+        .unwrap_or_default()
+}
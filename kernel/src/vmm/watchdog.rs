@@ -0,0 +1,266 @@
+//! Hypervisor watchdogs of guests.
+//!
+//! A guest with a `[watchdog]` section in its VM config has to kick its watchdog with the
+//! [`HVC_WATCHDOG_KICK`](crate::vmm::hvc::HVC_WATCHDOG_KICK) hypercall (`HWatchdogKick`) at least
+//! every `timeout_ms`. The watchdog is armed by the first kick, so a slow boot doesn't trip it.
+//! When it expires, the configured action runs and the watchdog is disarmed until the next kick:
+//!
+//! ```toml
+//! [watchdog]
+//! timeout_ms = 5000
+//! # "log", "interrupt", "nmi" (x86_64 with `[x2apic]` only), "reset" or "reset-notify".
+//! action = "reset-notify"
+//! # Interrupt raised in the guest by "interrupt".
+//! irq = 0x30
+//! # Peer handle of the manager VM interrupted by "reset-notify", see `crate::vmm::peers`.
+//! manager = 0
+//! manager_irq = 0x31
+//! ```
+//!
+//! A reset stops the VM and restarts it from the BSP entry point once all its vCPUs have exited,
+//! like `vm restart` does. The images are not reloaded.
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::{Arc, Weak};
+use std::os::arceos::api::task::ax_wait_queue_wake;
+use std::os::arceos::modules::axhal;
+use std::thread;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use spin::Mutex;
+
+use crate::vmm::irq::IrqLine;
+use crate::vmm::{VMRef, peers, sub_running_vm_count, timer, vcpus, vm_list};
+
+/// What a watchdog does when it expires.
+#[derive(Debug, Clone, Copy)]
+enum WatchdogAction {
+    Log,
+    Interrupt(IrqLine),
+    #[cfg(target_arch = "x86_64")]
+    Nmi,
+    Reset,
+    /// Resets the VM and interrupts the manager VM, given by a peer handle of the VM.
+    ResetNotify {
+        manager: usize,
+        irq: usize,
+    },
+}
+
+struct WatchdogState {
+    /// Host time at which the watchdog expires in nanoseconds, 0 if it is not armed.
+    deadline_ns: u64,
+    /// Whether a host timer is pending. Kicks only move the deadline, the timer rearms itself
+    /// when it fires before the deadline.
+    timer_pending: bool,
+    expirations: u64,
+}
+
+struct Watchdog {
+    vm_id: usize,
+    timeout_ns: u64,
+    action: WatchdogAction,
+    state: Mutex<WatchdogState>,
+    this: Weak<Watchdog>,
+}
+
+impl Watchdog {
+    fn kick(&self) {
+        let mut state = self.state.lock();
+        state.deadline_ns = axhal::time::monotonic_time_nanos() + self.timeout_ns;
+        if !state.timer_pending {
+            self.arm_timer(&mut state);
+        }
+    }
+
+    fn arm_timer(&self, state: &mut WatchdogState) {
+        state.timer_pending = true;
+        let watchdog = self.this.clone();
+        timer::register_timer(state.deadline_ns, move |_| {
+            if let Some(watchdog) = watchdog.upgrade() {
+                watchdog.timer_fired();
+            }
+        });
+    }
+
+    fn timer_fired(&self) {
+        let expirations = {
+            let mut state = self.state.lock();
+            state.timer_pending = false;
+            if state.deadline_ns == 0 {
+                return;
+            }
+            if axhal::time::monotonic_time_nanos() < state.deadline_ns {
+                // Kicked since the timer was armed.
+                self.arm_timer(&mut state);
+                return;
+            }
+            state.deadline_ns = 0;
+            state.expirations += 1;
+            state.expirations
+        };
+        self.expire(expirations);
+    }
+
+    fn expire(&self, expirations: u64) {
+        let vm_id = self.vm_id;
+        error!(
+            "VM[{}] watchdog expired ({} times), no kick for {} ms, action {:?}",
+            vm_id,
+            expirations,
+            self.timeout_ns / 1_000_000,
+            self.action
+        );
+        match self.action {
+            WatchdogAction::Log => {}
+            WatchdogAction::Interrupt(line) => line.raise(),
+            #[cfg(target_arch = "x86_64")]
+            WatchdogAction::Nmi => {
+                if !super::x2apic::raise_nmi(vm_id, 0) {
+                    warn!("VM[{}] watchdog: no local APIC to raise an NMI", vm_id);
+                }
+            }
+            WatchdogAction::Reset => reset_vm(vm_id),
+            WatchdogAction::ResetNotify { manager, irq } => {
+                reset_vm(vm_id);
+                match peers::resolve(vm_id, manager) {
+                    Ok(manager_vm_id) => IrqLine::new(manager_vm_id, irq).raise(),
+                    Err(e) => warn!("VM[{}] watchdog: manager not found: {:?}", vm_id, e),
+                }
+            }
+        }
+    }
+}
+
+/// Watchdogs of all VMs that have one, indexed by VM ID.
+static WATCHDOGS: Mutex<BTreeMap<usize, Arc<Watchdog>>> = Mutex::new(BTreeMap::new());
+/// VMs stopped by [`reset_vm`], to be restarted once all their vCPUs have exited.
+static RESET_PENDING: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+
+/// Creates the watchdog described in the `[watchdog]` section of `raw_cfg` for the VM.
+///
+/// Does nothing if the VM config has no `[watchdog]` section.
+pub fn setup_vm_watchdog(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("watchdog").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let get = |key: &str| {
+        cfg.get(key)
+            .and_then(|v| v.as_integer())
+            .map(|v| v as usize)
+            .ok_or_else(|| {
+                ax_err_type!(InvalidInput, format!("watchdog config: missing `{}`", key))
+            })
+    };
+    let timeout_ms = get("timeout_ms")?;
+    if timeout_ms == 0 {
+        return ax_err!(InvalidInput, "watchdog config: zero `timeout_ms`");
+    }
+    let action = match cfg.get("action").and_then(|v| v.as_str()).unwrap_or("log") {
+        "log" => WatchdogAction::Log,
+        "interrupt" => WatchdogAction::Interrupt(IrqLine::new(vm.id(), get("irq")?)),
+        #[cfg(target_arch = "x86_64")]
+        "nmi" => WatchdogAction::Nmi,
+        "reset" => WatchdogAction::Reset,
+        "reset-notify" => WatchdogAction::ResetNotify {
+            manager: get("manager")?,
+            irq: get("manager_irq")?,
+        },
+        action => {
+            return ax_err!(
+                InvalidInput,
+                format!("watchdog config: unsupported action `{}`", action)
+            );
+        }
+    };
+
+    let watchdog = Arc::new_cyclic(|this| Watchdog {
+        vm_id: vm.id(),
+        timeout_ns: timeout_ms as u64 * 1_000_000,
+        action,
+        state: Mutex::new(WatchdogState {
+            deadline_ns: 0,
+            timer_pending: false,
+            expirations: 0,
+        }),
+        this: this.clone(),
+    });
+    WATCHDOGS.lock().insert(vm.id(), watchdog);
+    info!(
+        "VM[{}] watchdog: timeout {} ms, action {:?}",
+        vm.id(),
+        timeout_ms,
+        action
+    );
+    Ok(())
+}
+
+/// Removes the watchdog of a VM, called when the VM is destroyed.
+pub fn teardown_vm_watchdog(vm_id: usize) {
+    WATCHDOGS.lock().remove(&vm_id);
+    RESET_PENDING.lock().remove(&vm_id);
+}
+
+/// Kicks the watchdog of VM `vm_id`, handles the `HWatchdogKick` hypercall.
+pub fn kick(vm_id: usize) -> AxResult {
+    let watchdog = WATCHDOGS
+        .lock()
+        .get(&vm_id)
+        .cloned()
+        .ok_or_else(|| ax_err_type!(NotFound, format!("VM[{}] has no watchdog", vm_id)))?;
+    watchdog.kick();
+    Ok(())
+}
+
+/// Stops VM `vm_id` and restarts it once all its vCPUs have exited, see [`on_vm_stopped`].
+///
+/// Used by the watchdogs and by the crash policies (see [`crate::vmm::crash`]).
+pub fn reset_vm(vm_id: usize) {
+    let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+        return;
+    };
+    RESET_PENDING.lock().insert(vm_id);
+    if let Err(e) = vm.shutdown() {
+        RESET_PENDING.lock().remove(&vm_id);
+        error!("VM[{}] reset failed: {:?}", vm_id, e);
+        return;
+    }
+    vcpus::notify_all_vcpus(vm_id);
+}
+
+/// Restarts the VM if it was stopped by [`reset_vm`], called by its last vCPU task when the VM
+/// reaches the `Stopped` state. Returns true if the VM is restarting, in which case it still
+/// counts as running. VMs are not restarted during a system shutdown (see
+/// [`crate::vmm::shutdown`]).
+pub fn on_vm_stopped(vm: &VMRef) -> bool {
+    if !RESET_PENDING.lock().remove(&vm.id()) || super::shutdown::in_progress() {
+        return false;
+    }
+    let vm = vm.clone();
+    thread::spawn(move || restart_vm(vm));
+    true
+}
+
+fn restart_vm(vm: VMRef) {
+    let vm_id = vm.id();
+    info!("VM[{}] restarting after a reset", vm_id);
+
+    // The vCPU tasks of the previous run are joined, their entry replaced under the lock of the
+    // vCPUs of the VMs, which the vCPUs of the other VMs look up concurrently.
+    vcpus::cleanup_vm_vcpus(vm_id);
+    vcpus::setup_vm_primary_vcpu(vm.clone());
+    let entry = vm.with_config(|cfg| cfg.bsp_entry());
+    if let Some(vcpu) = vm.vcpu(0)
+        && let Err(e) = vcpu.set_entry(entry)
+    {
+        warn!("VM[{}] failed to reset the BSP entry: {:?}", vm_id, e);
+    }
+
+    match vm.boot() {
+        Ok(()) => vcpus::notify_primary_vcpu(vm_id),
+        Err(e) => {
+            error!("VM[{}] restart failed: {:?}", vm_id, e);
+            sub_running_vm_count(1);
+            ax_wait_queue_wake(&super::VMM, 1);
+        }
+    }
+}
@@ -0,0 +1,646 @@
+//! x2APIC and TSC-deadline timer virtualization for x86 guests.
+//!
+//! A VM whose config has an `[x2apic]` section gets a local APIC per vCPU, emulated in x2APIC
+//! mode through the MSR exits of its vCPUs:
+//!
+//! ```toml
+//! [x2apic]
+//! # Frequency of the guest TSC in kHz, defaults to the host TSC frequency.
+//! tsc_khz = 2_000_000
+//! # Offset added to the (scaled) host TSC to form the guest TSC, defaults to 0.
+//! tsc_offset = 0
+//! # Frequency of the APIC timer in one-shot and periodic modes in kHz, defaults to 1 GHz.
+//! bus_khz = 1_000_000
+//! ```
+//!
+//! The guest TSC is `(host TSC * multiplier >> 48) + tsc_offset`, the multiplier being derived
+//! from `tsc_khz`; the vCPU backend programs the same offset and multiplier into the TSC
+//! offsetting and scaling controls of the vCPU, so that `RDTSC` does not exit. The
+//! `IA32_TSC_DEADLINE` MSR is converted back to host time with them, and the APIC timer is backed
+//! by host timers in all three modes (one-shot, periodic and TSC-deadline).
+//!
+//! Interrupts raised for an APIC (by its timer, an IPI, a self IPI, an MSI of a passthrough
+//! device, see [`deliver_msi`], or an interrupt posted by the hypervisor, e.g. an IVC notification,
+//! see [`raise`]) are latched in its IRR and injected by the vCPU task itself before
+//! it enters the guest, highest priority first and only when above the processor priority, see
+//! [`deliver_pending`]. `INIT`/`SIPI` IPIs start secondary vCPUs.
+//!
+//! When the CPU has APIC virtualization (Intel APICv or AMD AVIC, see
+//! [`crate::hal::arch::apic_assist`]), the vCPU backend lets the guest access the x2APIC registers
+//! without exits and only the MSRs it does not virtualize reach this module.
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+use spin::Mutex;
+
+use crate::vmm::{VCpuRef, VMRef, posted, stats, timer, vcpus};
+
+const MSR_IA32_APIC_BASE: usize = 0x1b;
+const MSR_IA32_TSC_DEADLINE: usize = 0x6e0;
+const MSR_X2APIC_BASE: usize = 0x800;
+const MSR_X2APIC_END: usize = 0x8ff;
+
+const APIC_BASE_DEFAULT: u64 = 0xfee0_0000;
+const APIC_BASE_BSP: u64 = 1 << 8;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// x2APIC registers, as offsets from `MSR_X2APIC_BASE`.
+const REG_ID: usize = 0x02;
+const REG_VERSION: usize = 0x03;
+const REG_TPR: usize = 0x08;
+const REG_PPR: usize = 0x0a;
+const REG_EOI: usize = 0x0b;
+const REG_LDR: usize = 0x0d;
+const REG_SVR: usize = 0x0f;
+const REG_ISR: usize = 0x10;
+const REG_TMR: usize = 0x18;
+const REG_IRR: usize = 0x20;
+const REG_ESR: usize = 0x28;
+const REG_LVT_CMCI: usize = 0x2f;
+const REG_ICR: usize = 0x30;
+const REG_LVT_TIMER: usize = 0x32;
+const REG_LVT_ERROR: usize = 0x37;
+const REG_TIMER_INITIAL: usize = 0x38;
+const REG_TIMER_CURRENT: usize = 0x39;
+const REG_TIMER_DIVIDE: usize = 0x3e;
+const REG_SELF_IPI: usize = 0x3f;
+
+/// Version 0x14 with 7 LVT entries, the directed EOI is not supported.
+const APIC_VERSION: u32 = 0x0006_0014;
+const SVR_RESET: u32 = 0xff;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_MODE_SHIFT: u32 = 17;
+const LVT_TIMER_ONE_SHOT: u32 = 0;
+const LVT_TIMER_PERIODIC: u32 = 1;
+const LVT_TIMER_TSC_DEADLINE: u32 = 2;
+
+const ICR_DELIVERY_FIXED: u64 = 0;
+const ICR_DELIVERY_NMI: u64 = 4;
+const ICR_DELIVERY_INIT: u64 = 5;
+const ICR_DELIVERY_STARTUP: u64 = 6;
+const ICR_SHORTHAND_SELF: u64 = 1;
+const ICR_SHORTHAND_ALL: u64 = 2;
+const ICR_SHORTHAND_ALL_BUT_SELF: u64 = 3;
+const X2APIC_BROADCAST: u32 = u32::MAX;
+/// MSI delivery modes share the ICR encoding.
+const MSI_DELIVERY_LOWEST: u64 = 1;
+const MSI_ADDR_DEST_SHIFT: u64 = 12;
+const MSI_DEST_BROADCAST: u32 = 0xff;
+
+const NMI_VECTOR: usize = 2;
+const DEFAULT_BUS_KHZ: u64 = 1_000_000;
+/// Fraction bits of the TSC multiplier, as in the VMX TSC-scaling control.
+const TSC_MULTIPLIER_SHIFT: u32 = 48;
+
+/// Conversion between the guest and host TSC of a VM.
+#[derive(Debug, Clone, Copy)]
+struct TscParams {
+    multiplier: u64,
+    offset: u64,
+}
+
+impl TscParams {
+    /// Host monotonic time in nanoseconds at which the guest TSC reaches `guest_tsc`.
+    fn guest_tsc_to_nanos(&self, guest_tsc: u64) -> u64 {
+        let scaled = guest_tsc.wrapping_sub(self.offset) as u128;
+        let host_ticks = (scaled << TSC_MULTIPLIER_SHIFT) / self.multiplier as u128;
+        axhal::time::ticks_to_nanos(host_ticks.min(u64::MAX as u128) as u64)
+    }
+}
+
+struct LapicState {
+    apic_base: u64,
+    tpr: u32,
+    svr: u32,
+    esr: u32,
+    icr: u64,
+    isr: [u32; 8],
+    tmr: [u32; 8],
+    irr: [u32; 8],
+    /// LVT entries, from CMCI (0x2f) to error (0x37).
+    lvt: [u32; 9],
+    nmi_pending: bool,
+    /// Whether a `SIPI` will start the vCPU, set by `INIT`.
+    wait_for_sipi: bool,
+    timer_initial: u32,
+    timer_divide: u32,
+    tsc_deadline: u64,
+    /// Host time of the next expiry of the timer in nanoseconds, 0 if it is not armed.
+    timer_deadline_ns: u64,
+    /// Bumped whenever the timer is rearmed, so that host timers armed before are ignored.
+    timer_generation: u64,
+}
+
+impl LapicState {
+    fn new(vcpu_id: usize) -> Self {
+        let mut apic_base = APIC_BASE_DEFAULT | APIC_BASE_ENABLE | APIC_BASE_X2APIC;
+        if vcpu_id == 0 {
+            apic_base |= APIC_BASE_BSP;
+        }
+        Self {
+            apic_base,
+            tpr: 0,
+            svr: SVR_RESET,
+            esr: 0,
+            icr: 0,
+            isr: [0; 8],
+            tmr: [0; 8],
+            irr: [0; 8],
+            lvt: [LVT_MASKED; 9],
+            nmi_pending: false,
+            wait_for_sipi: vcpu_id != 0,
+            timer_initial: 0,
+            timer_divide: 0,
+            tsc_deadline: 0,
+            timer_deadline_ns: 0,
+            timer_generation: 0,
+        }
+    }
+
+    fn lvt_timer(&self) -> u32 {
+        self.lvt[REG_LVT_TIMER - REG_LVT_CMCI]
+    }
+
+    fn timer_mode(&self) -> u32 {
+        (self.lvt_timer() >> LVT_TIMER_MODE_SHIFT) & 0b11
+    }
+
+    /// Divisor of the bus clock selected by the divide configuration register.
+    fn timer_divisor(&self) -> u64 {
+        let code = (self.timer_divide & 0b11) | ((self.timer_divide >> 1) & 0b100);
+        match code {
+            0b111 => 1,
+            code => 2 << code,
+        }
+    }
+
+    fn highest(bitmap: &[u32; 8]) -> Option<usize> {
+        (0..8)
+            .rev()
+            .find(|i| bitmap[*i] != 0)
+            .map(|i| i * 32 + 31 - bitmap[i].leading_zeros() as usize)
+    }
+
+    fn ppr(&self) -> u32 {
+        let isrv = Self::highest(&self.isr).unwrap_or(0) as u32;
+        if self.tpr & 0xf0 >= isrv & 0xf0 {
+            self.tpr
+        } else {
+            isrv & 0xf0
+        }
+    }
+
+    fn software_enabled(&self) -> bool {
+        self.svr & (1 << 8) != 0
+    }
+
+    fn accept(&mut self, vector: usize) {
+        if vector < 16 {
+            self.esr |= 1 << 6; // Received illegal vector.
+            return;
+        }
+        self.irr[vector / 32] |= 1 << (vector % 32);
+    }
+}
+
+/// A local APIC emulated for one vCPU.
+struct VLapic {
+    this: Weak<VLapic>,
+    vm_id: usize,
+    vcpu_id: usize,
+    bus_khz: u64,
+    tsc: TscParams,
+    state: Mutex<LapicState>,
+}
+
+impl VLapic {
+    fn new(vm_id: usize, vcpu_id: usize, bus_khz: u64, tsc: TscParams) -> Arc<Self> {
+        Arc::new_cyclic(|this| Self {
+            this: this.clone(),
+            vm_id,
+            vcpu_id,
+            bus_khz,
+            tsc,
+            state: Mutex::new(LapicState::new(vcpu_id)),
+        })
+    }
+
+    /// Latches `vector` and notifies the vCPU.
+    fn raise(&self, vector: usize) {
+        self.state.lock().accept(vector);
+        self.kick();
+    }
+
+    /// Notifies the vCPU of a latched interrupt, see [`posted::kick`].
+    fn kick(&self) {
+        posted::kick(self.vm_id, self.vcpu_id);
+    }
+
+    /// Nanoseconds per tick of the APIC timer in one-shot and periodic modes.
+    fn timer_tick_ns(&self, state: &LapicState) -> u64 {
+        (state.timer_divisor() * 1_000_000 / self.bus_khz).max(1)
+    }
+
+    /// Arms the host timer for the current timer configuration.
+    fn rearm_timer(&self, state: &mut LapicState) {
+        state.timer_generation += 1;
+        let now = axhal::time::monotonic_time_nanos();
+        state.timer_deadline_ns = match state.timer_mode() {
+            LVT_TIMER_ONE_SHOT | LVT_TIMER_PERIODIC if state.timer_initial != 0 => {
+                now + state.timer_initial as u64 * self.timer_tick_ns(state)
+            }
+            LVT_TIMER_TSC_DEADLINE if state.tsc_deadline != 0 => {
+                self.tsc.guest_tsc_to_nanos(state.tsc_deadline).max(now)
+            }
+            _ => 0,
+        };
+        if state.timer_deadline_ns == 0 {
+            return;
+        }
+        let lapic = self.this.clone();
+        let generation = state.timer_generation;
+        timer::register_timer(state.timer_deadline_ns, move |_| {
+            if let Some(lapic) = lapic.upgrade() {
+                lapic.timer_expired(generation);
+            }
+        });
+    }
+
+    fn timer_expired(&self, generation: u64) {
+        let mut state = self.state.lock();
+        if state.timer_generation != generation {
+            return;
+        }
+        let lvt = state.lvt_timer();
+        if lvt & LVT_MASKED == 0 {
+            state.accept((lvt & 0xff) as usize);
+        }
+        match state.timer_mode() {
+            LVT_TIMER_PERIODIC => self.rearm_timer(&mut state),
+            LVT_TIMER_TSC_DEADLINE => {
+                state.tsc_deadline = 0;
+                state.timer_deadline_ns = 0;
+            }
+            _ => state.timer_deadline_ns = 0,
+        }
+        drop(state);
+        self.kick();
+    }
+
+    fn read(&self, msr: usize) -> AxResult<u64> {
+        let state = self.state.lock();
+        if msr == MSR_IA32_APIC_BASE {
+            return Ok(state.apic_base);
+        }
+        if msr == MSR_IA32_TSC_DEADLINE {
+            return Ok(match state.timer_mode() {
+                LVT_TIMER_TSC_DEADLINE => state.tsc_deadline,
+                _ => 0,
+            });
+        }
+        let val = match msr - MSR_X2APIC_BASE {
+            REG_ID => self.vcpu_id as u32,
+            REG_VERSION => APIC_VERSION,
+            REG_TPR => state.tpr,
+            REG_PPR => state.ppr(),
+            REG_LDR => {
+                let id = self.vcpu_id as u32;
+                ((id >> 4) << 16) | (1 << (id & 0xf))
+            }
+            REG_SVR => state.svr,
+            reg @ REG_ISR..REG_TMR => state.isr[reg - REG_ISR],
+            reg @ REG_TMR..REG_IRR => state.tmr[reg - REG_TMR],
+            reg @ REG_IRR..REG_ESR => state.irr[reg - REG_IRR],
+            REG_ESR => state.esr,
+            REG_ICR => return Ok(state.icr),
+            reg @ REG_LVT_CMCI..=REG_LVT_ERROR if reg != 0x31 => state.lvt[reg - REG_LVT_CMCI],
+            REG_TIMER_INITIAL => state.timer_initial,
+            REG_TIMER_CURRENT => {
+                let now = axhal::time::monotonic_time_nanos();
+                if state.timer_mode() == LVT_TIMER_TSC_DEADLINE || state.timer_deadline_ns <= now {
+                    0
+                } else {
+                    ((state.timer_deadline_ns - now) / self.timer_tick_ns(&state)) as u32
+                }
+            }
+            REG_TIMER_DIVIDE => state.timer_divide,
+            reg => {
+                return ax_err!(
+                    InvalidInput,
+                    format!("read of unsupported x2APIC register {:#x}", reg)
+                );
+            }
+        };
+        Ok(val as u64)
+    }
+
+    fn write(&self, vm: &VMRef, msr: usize, val: u64) -> AxResult {
+        let mut state = self.state.lock();
+        match msr {
+            MSR_IA32_APIC_BASE => {
+                if val & APIC_BASE_ENABLE != 0 && val & APIC_BASE_X2APIC == 0 {
+                    warn!(
+                        "VM[{}] VCpu[{}] xAPIC mode is not supported, staying in x2APIC mode",
+                        self.vm_id, self.vcpu_id
+                    );
+                }
+                state.apic_base = (state.apic_base & !APIC_BASE_ENABLE) | (val & APIC_BASE_ENABLE);
+                return Ok(());
+            }
+            MSR_IA32_TSC_DEADLINE => {
+                if state.timer_mode() == LVT_TIMER_TSC_DEADLINE {
+                    state.tsc_deadline = val;
+                    self.rearm_timer(&mut state);
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
+        let val32 = val as u32;
+        match msr - MSR_X2APIC_BASE {
+            REG_TPR => state.tpr = val32 & 0xff,
+            REG_EOI => {
+                if let Some(vector) = LapicState::highest(&state.isr) {
+                    state.isr[vector / 32] &= !(1 << (vector % 32));
+                }
+            }
+            REG_SVR => state.svr = val32 & 0x1ff,
+            REG_ESR => state.esr = 0,
+            REG_ICR => {
+                state.icr = val;
+                drop(state);
+                return self.send_ipi(vm, val);
+            }
+            reg @ REG_LVT_CMCI..=REG_LVT_ERROR if reg != 0x31 => {
+                let old_mode = state.timer_mode();
+                let val32 = if state.software_enabled() {
+                    val32
+                } else {
+                    val32 | LVT_MASKED
+                };
+                state.lvt[reg - REG_LVT_CMCI] = val32;
+                if reg == REG_LVT_TIMER && state.timer_mode() != old_mode {
+                    // Switching modes disarms the timer.
+                    state.timer_initial = 0;
+                    state.tsc_deadline = 0;
+                    self.rearm_timer(&mut state);
+                }
+            }
+            REG_TIMER_INITIAL => {
+                if state.timer_mode() != LVT_TIMER_TSC_DEADLINE {
+                    state.timer_initial = val32;
+                    self.rearm_timer(&mut state);
+                }
+            }
+            REG_TIMER_DIVIDE => state.timer_divide = val32 & 0b1011,
+            REG_SELF_IPI => state.accept((val32 & 0xff) as usize),
+            reg => {
+                return ax_err!(
+                    InvalidInput,
+                    format!(
+                        "write of read-only or unsupported x2APIC register {:#x}",
+                        reg
+                    )
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn send_ipi(&self, vm: &VMRef, icr: u64) -> AxResult {
+        let vector = (icr & 0xff) as usize;
+        let delivery = (icr >> 8) & 0b111;
+        let shorthand = (icr >> 18) & 0b11;
+        let dest = (icr >> 32) as u32;
+
+        let Some(lapics) = vm_lapics(self.vm_id) else {
+            return Ok(());
+        };
+        let targets = lapics.iter().filter(|lapic| match shorthand {
+            ICR_SHORTHAND_SELF => lapic.vcpu_id == self.vcpu_id,
+            ICR_SHORTHAND_ALL => true,
+            ICR_SHORTHAND_ALL_BUT_SELF => lapic.vcpu_id != self.vcpu_id,
+            // Physical destination mode only, the x2APIC ID is the vCPU ID.
+            _ => dest == X2APIC_BROADCAST || dest as usize == lapic.vcpu_id,
+        });
+        for target in targets {
+            match delivery {
+                ICR_DELIVERY_FIXED => target.raise(vector),
+                ICR_DELIVERY_NMI => {
+                    target.state.lock().nmi_pending = true;
+                    target.kick();
+                }
+                ICR_DELIVERY_INIT => target.state.lock().wait_for_sipi = true,
+                ICR_DELIVERY_STARTUP => {
+                    let start = core::mem::replace(&mut target.state.lock().wait_for_sipi, false);
+                    if start {
+                        info!(
+                            "VM[{}] VCpu[{}] SIPI to VCpu[{}] vector {:#x}",
+                            self.vm_id, self.vcpu_id, target.vcpu_id, vector
+                        );
+                        vcpus::vcpu_on(
+                            vm.clone(),
+                            target.vcpu_id,
+                            GuestPhysAddr::from(vector << 12),
+                            0,
+                        );
+                    }
+                }
+                mode => warn!(
+                    "VM[{}] VCpu[{}] IPI delivery mode {} not supported",
+                    self.vm_id, self.vcpu_id, mode
+                ),
+            }
+        }
+        Ok(())
+    }
+}
+
+static LAPICS: Mutex<BTreeMap<usize, Arc<Vec<Arc<VLapic>>>>> = Mutex::new(BTreeMap::new());
+/// Whether any VM has emulated local APICs, checked without the lock on every guest entry.
+static HAS_LAPICS: AtomicBool = AtomicBool::new(false);
+
+fn vm_lapics(vm_id: usize) -> Option<Arc<Vec<Arc<VLapic>>>> {
+    if !HAS_LAPICS.load(Ordering::Acquire) {
+        return None;
+    }
+    LAPICS.lock().get(&vm_id).cloned()
+}
+
+fn get_u64(table: &toml::Table, key: &str) -> Option<u64> {
+    table
+        .get(key)
+        .and_then(|v| v.as_integer())
+        .map(|v| v as u64)
+}
+
+/// Creates the local APICs of the VM if `raw_cfg` has an `[x2apic]` section.
+pub fn setup_vm_x2apic(vm: &VMRef, raw_cfg: &toml::Table) -> AxResult {
+    let Some(cfg) = raw_cfg.get("x2apic").and_then(|v| v.as_table()) else {
+        return Ok(());
+    };
+    let host_khz = axhal::time::nanos_to_ticks(1_000_000);
+    let tsc_khz = get_u64(cfg, "tsc_khz").unwrap_or(host_khz);
+    let bus_khz = get_u64(cfg, "bus_khz").unwrap_or(DEFAULT_BUS_KHZ);
+    if tsc_khz == 0 || bus_khz == 0 {
+        return ax_err!(InvalidInput, "x2apic config: zero TSC or bus frequency");
+    }
+    let tsc = TscParams {
+        multiplier: (((tsc_khz as u128) << TSC_MULTIPLIER_SHIFT) / host_khz as u128) as u64,
+        offset: get_u64(cfg, "tsc_offset").unwrap_or(0),
+    };
+
+    let lapics = (0..vm.vcpu_num())
+        .map(|vcpu_id| VLapic::new(vm.id(), vcpu_id, bus_khz, tsc))
+        .collect();
+    LAPICS.lock().insert(vm.id(), Arc::new(lapics));
+    HAS_LAPICS.store(true, Ordering::Release);
+
+    let assist = crate::hal::arch::apic_assist();
+    info!(
+        "VM[{}] x2APIC: guest TSC {} kHz (host {} kHz), bus {} kHz, hardware assist {:?}",
+        vm.id(),
+        tsc_khz,
+        host_khz,
+        bus_khz,
+        assist
+    );
+    Ok(())
+}
+
+/// Removes the local APICs of a VM, called when the VM is destroyed.
+pub fn teardown_vm_x2apic(vm_id: usize) {
+    let mut lapics = LAPICS.lock();
+    if let Some(vm_lapics) = lapics.remove(&vm_id) {
+        for lapic in vm_lapics.iter() {
+            lapic.state.lock().timer_generation += 1;
+        }
+    }
+    HAS_LAPICS.store(!lapics.is_empty(), Ordering::Release);
+}
+
+/// Latches the fixed interrupt `vector` in the local APIC of vCPU `vcpu_id` of VM `vm_id` and
+/// notifies the vCPU, returns false if the VM has no emulated local APICs.
+pub fn raise(vm_id: usize, vcpu_id: usize, vector: usize) -> bool {
+    if !HAS_LAPICS.load(Ordering::Acquire) {
+        return false;
+    }
+    let Some(lapic) = vm_lapics(vm_id).and_then(|lapics| lapics.get(vcpu_id).cloned()) else {
+        return false;
+    };
+    lapic.raise(vector);
+    true
+}
+
+/// Raises an NMI on vCPU `vcpu_id` of VM `vm_id`, returns false if the VM has no emulated local
+/// APICs.
+pub fn raise_nmi(vm_id: usize, vcpu_id: usize) -> bool {
+    let Some(lapic) = vm_lapics(vm_id).and_then(|lapics| lapics.get(vcpu_id).cloned()) else {
+        return false;
+    };
+    lapic.state.lock().nmi_pending = true;
+    lapic.kick();
+    true
+}
+
+/// Delivers an MSI with address `addr` and data `data` to the local APICs of VM `vm_id`, returns
+/// false if the VM has no emulated local APICs.
+///
+/// Only physical destinations are supported, the x2APIC ID being the vCPU ID; a lowest priority
+/// message goes to the first of its destinations.
+pub fn deliver_msi(vm_id: usize, addr: u64, data: u32) -> bool {
+    let Some(lapics) = vm_lapics(vm_id) else {
+        return false;
+    };
+    let vector = (data & 0xff) as usize;
+    let dest = ((addr >> MSI_ADDR_DEST_SHIFT) & 0xff) as u32;
+    let mut targets = lapics
+        .iter()
+        .filter(|lapic| dest == MSI_DEST_BROADCAST || dest as usize == lapic.vcpu_id);
+    match (data as u64 >> 8) & 0b111 {
+        ICR_DELIVERY_FIXED => targets.for_each(|target| target.raise(vector)),
+        MSI_DELIVERY_LOWEST => {
+            if let Some(target) = targets.next() {
+                target.raise(vector);
+            }
+        }
+        ICR_DELIVERY_NMI => targets.for_each(|target| {
+            target.state.lock().nmi_pending = true;
+            target.kick();
+        }),
+        mode => warn!("VM[{}] MSI delivery mode {} not supported", vm_id, mode),
+    }
+    true
+}
+
+fn is_lapic_msr(msr: usize) -> bool {
+    matches!(msr, MSR_IA32_APIC_BASE | MSR_IA32_TSC_DEADLINE)
+        || (MSR_X2APIC_BASE..=MSR_X2APIC_END).contains(&msr)
+}
+
+/// Handles a read of `msr` by vCPU `vcpu_id` of VM `vm_id`, returns `None` if the MSR is not
+/// emulated here.
+pub fn handle_msr_read(vm_id: usize, vcpu_id: usize, msr: usize) -> Option<AxResult<u64>> {
+    if !is_lapic_msr(msr) {
+        return None;
+    }
+    let lapics = vm_lapics(vm_id)?;
+    Some(lapics.get(vcpu_id)?.read(msr))
+}
+
+/// Handles a write of `val` to `msr` by vCPU `vcpu_id` of `vm`, returns `None` if the MSR is not
+/// emulated here.
+pub fn handle_msr_write(vm: &VMRef, vcpu_id: usize, msr: usize, val: u64) -> Option<AxResult> {
+    if !is_lapic_msr(msr) {
+        return None;
+    }
+    let lapics = vm_lapics(vm.id())?;
+    Some(lapics.get(vcpu_id)?.write(vm, msr, val))
+}
+
+/// Injects the highest-priority interrupt pending in the local APIC of `vcpu` of VM `vm_id`, if
+/// it is above the processor priority, called by the vCPU task before it enters the guest.
+#[inline]
+pub fn deliver_pending(vm_id: usize, vcpu: &VCpuRef) {
+    let Some(lapics) = vm_lapics(vm_id) else {
+        return;
+    };
+    let Some(lapic) = lapics.get(vcpu.id()) else {
+        return;
+    };
+    let vector = {
+        let mut state = lapic.state.lock();
+        if core::mem::replace(&mut state.nmi_pending, false) {
+            Some(NMI_VECTOR)
+        } else {
+            match LapicState::highest(&state.irr) {
+                Some(vector) if vector as u32 & 0xf0 > state.ppr() & 0xf0 => {
+                    state.irr[vector / 32] &= !(1 << (vector % 32));
+                    state.isr[vector / 32] |= 1 << (vector % 32);
+                    Some(vector)
+                }
+                _ => None,
+            }
+        }
+    };
+    let Some(vector) = vector else {
+        return;
+    };
+    match vcpu.inject_interrupt(vector) {
+        Ok(()) => stats::count_irq(vm_id, vcpu.id()),
+        Err(e) => warn!(
+            "VM[{}] VCpu[{}] failed to inject vector {:#x}: {:?}",
+            vm_id,
+            vcpu.id(),
+            vector,
+            e
+        ),
+    }
+}
@@ -1,7 +1,16 @@
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by the first panic, so that a panic in the hook doesn't run it again.
+static PANICKING: AtomicBool = AtomicBool::new(false);
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     error!("{info}");
+    if !PANICKING.swap(true, Ordering::AcqRel)
+        && let Some(hook) = crate::panic_hook()
+    {
+        hook(info);
+    }
     axhal::power::system_off()
 }
@@ -90,7 +90,8 @@ impl axlog::LogIf for LogIfImpl {
     }
 }
 
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 
 static INITED_CPUS: AtomicUsize = AtomicUsize::new(0);
 
@@ -263,25 +264,65 @@ fn init_allocator() {
 }
 
 #[cfg(feature = "irq")]
-fn init_interrupt() {
-    // Setup timer interrupt handler
-    const PERIODIC_INTERVAL_NANOS: u64 =
-        axhal::time::NANOS_PER_SEC / axconfig::TICKS_PER_SEC as u64;
+const PERIODIC_INTERVAL_NANOS: u64 = axhal::time::NANOS_PER_SEC / axconfig::TICKS_PER_SEC as u64;
+
+/// The tick after the one the timer of the CPU is programmed for.
+#[cfg(feature = "irq")]
+#[percpu::def_percpu]
+static NEXT_DEADLINE: u64 = 0;
 
-    #[percpu::def_percpu]
-    static NEXT_DEADLINE: u64 = 0;
+/// Deadline of the event the timer of the CPU is programmed for before its tick, 0 for none.
+#[cfg(feature = "irq")]
+#[percpu::def_percpu]
+static NEXT_EVENT: u64 = 0;
 
-    fn update_timer() {
-        let now_ns = axhal::time::monotonic_time_nanos();
-        // Safety: we have disabled preemption in IRQ handler.
-        let mut deadline = unsafe { NEXT_DEADLINE.read_current_raw() };
+#[cfg(feature = "irq")]
+fn update_timer() {
+    let now_ns = axhal::time::monotonic_time_nanos();
+    // Safety: we have disabled preemption in IRQ handler.
+    let mut deadline = unsafe { NEXT_DEADLINE.read_current_raw() };
+    let tick = deadline.saturating_sub(PERIODIC_INTERVAL_NANOS);
+    if now_ns < tick {
+        // An event fired before the tick, which is still to come.
+        deadline = tick;
+    } else {
         if now_ns >= deadline {
             deadline = now_ns + PERIODIC_INTERVAL_NANOS;
         }
         unsafe { NEXT_DEADLINE.write_current_raw(deadline + PERIODIC_INTERVAL_NANOS) };
+    }
+    let event = unsafe { NEXT_EVENT.read_current_raw() };
+    if event > now_ns && event < deadline {
+        axhal::time::set_oneshot_timer(event);
+    } else {
+        unsafe { NEXT_EVENT.write_current_raw(0) };
         axhal::time::set_oneshot_timer(deadline);
     }
+}
 
+/// Makes the timer of the current CPU fire at `deadline_ns` (monotonic) if that is before its next
+/// tick, so that the tasks sleeping until then are woken up on time rather than on the tick.
+///
+/// The ticks stay periodic, the event is an extra timer interrupt. Only the earliest pending event
+/// of a CPU is kept.
+#[cfg(feature = "irq")]
+pub fn set_next_event(deadline_ns: u64) {
+    let _guard = kernel_guard::IrqSave::new();
+    // Safety: IRQs are disabled, so is preemption.
+    let next_deadline = unsafe { NEXT_DEADLINE.read_current_raw() };
+    let tick = next_deadline.saturating_sub(PERIODIC_INTERVAL_NANOS);
+    let event = unsafe { NEXT_EVENT.read_current_raw() };
+    let now_ns = axhal::time::monotonic_time_nanos();
+    if deadline_ns <= now_ns || deadline_ns >= tick || (event > now_ns && event <= deadline_ns) {
+        return;
+    }
+    unsafe { NEXT_EVENT.write_current_raw(deadline_ns) };
+    axhal::time::set_oneshot_timer(deadline_ns);
+}
+
+#[cfg(feature = "irq")]
+fn init_interrupt() {
+    // Setup timer interrupt handler
     // axhal::irq::register(axconfig::devices::TIMER_IRQ, || {
     axhal::irq::register(axhal::time::irq_num(), || {
         update_timer();
@@ -335,3 +376,22 @@ pub fn cpu_count() -> usize {
 
     cpu_count
 }
+
+/// Hook called by the panic handler after the panic message is logged, with the panic, e.g. to
+/// dump the application state. Null until set.
+static PANIC_HOOK: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Sets the hook called when the kernel panics, replacing the previous one.
+///
+/// The hook runs in the panic context: it should neither allocate nor take locks.
+pub fn set_panic_hook(hook: fn(&PanicInfo)) {
+    PANIC_HOOK.store(hook as *mut (), Ordering::Release);
+}
+
+/// Returns the panic hook, if set.
+#[cfg(all(target_os = "none", not(test)))]
+fn panic_hook() -> Option<fn(&PanicInfo)> {
+    let hook = PANIC_HOOK.load(Ordering::Acquire);
+    // SAFETY: the pointer was stored by `set_panic_hook` from a `fn(&PanicInfo)`.
+    (!hook.is_null()).then(|| unsafe { core::mem::transmute::<*mut (), fn(&PanicInfo)>(hook) })
+}
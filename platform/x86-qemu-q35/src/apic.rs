@@ -5,7 +5,7 @@ use core::mem::MaybeUninit;
 use axplat::mem::{PhysAddr, pa, phys_to_virt};
 use kspin::SpinNoIrq;
 use lazyinit::LazyInit;
-use x2apic::ioapic::IoApic;
+use x2apic::ioapic::{IoApic, IrqFlags, IrqMode};
 use x2apic::lapic::{LocalApic, LocalApicBuilder, xapic_base};
 use x86_64::instructions::port::Port;
 
@@ -15,8 +15,13 @@ pub(super) mod vectors {
     pub const APIC_TIMER_VECTOR: u8 = 0xf0;
     pub const APIC_SPURIOUS_VECTOR: u8 = 0xf1;
     pub const APIC_ERROR_VECTOR: u8 = 0xf2;
+    /// Vector of the COM1 interrupt, routed from the I/O APIC.
+    pub const COM1_VECTOR: u8 = 0x24;
 }
 
+/// I/O APIC input of the COM1 interrupt (ISA IRQ 4).
+const COM1_IRQ: u8 = 4;
+
 const IO_APIC_BASE: PhysAddr = pa!(0xFEC0_0000);
 
 static mut LOCAL_APIC: MaybeUninit<LocalApic> = MaybeUninit::uninit();
@@ -28,11 +33,16 @@ static IO_APIC: LazyInit<SpinNoIrq<IoApic>> = LazyInit::new();
 pub fn set_enable(vector: usize, enabled: bool) {
     // should not affect LAPIC interrupts
     if vector < APIC_TIMER_VECTOR as _ {
+        let irq = if vector == COM1_VECTOR as usize {
+            COM1_IRQ
+        } else {
+            vector as u8
+        };
         unsafe {
             if enabled {
-                IO_APIC.lock().enable_irq(vector as u8);
+                IO_APIC.lock().enable_irq(irq);
             } else {
-                IO_APIC.lock().disable_irq(vector as u8);
+                IO_APIC.lock().disable_irq(irq);
             }
         }
     }
@@ -86,6 +96,7 @@ pub fn init_primary() {
     }
 
     let mut lapic = builder.build().unwrap();
+    let bsp_apic_id = unsafe { lapic.id() };
     unsafe {
         lapic.enable();
         #[allow(static_mut_refs)]
@@ -93,7 +104,16 @@ pub fn init_primary() {
     }
 
     info!("Initialize IO APIC...");
-    let io_apic = unsafe { IoApic::new(phys_to_virt(IO_APIC_BASE).as_usize() as u64) };
+    let mut io_apic = unsafe { IoApic::new(phys_to_virt(IO_APIC_BASE).as_usize() as u64) };
+    // Route COM1 to the BSP, masked until a handler is registered for it.
+    unsafe {
+        let mut entry = io_apic.table_entry(COM1_IRQ);
+        entry.set_vector(COM1_VECTOR);
+        entry.set_mode(IrqMode::Fixed);
+        entry.set_flags(IrqFlags::MASKED);
+        entry.set_dest(bsp_apic_id as u8);
+        io_apic.set_table_entry(COM1_IRQ, entry);
+    }
     IO_APIC.init_once(SpinNoIrq::new(io_apic));
 }
 
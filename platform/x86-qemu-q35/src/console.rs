@@ -49,9 +49,9 @@ impl ConsoleIf for ConsoleIfImpl {
 
     /// Returns the IRQ number for the console input interrupt.
     ///
-    /// Returns `None` if input interrupt is not supported.
+    /// The UART raises it when it receives data, see [`SerialPort::init`].
     #[cfg(feature = "irq")]
     fn irq_num() -> Option<usize> {
-        None
+        Some(crate::apic::vectors::COM1_VECTOR as usize)
     }
 }
@@ -0,0 +1,697 @@
+//! Validation of a set of VM configs on the host, without booting the hypervisor.
+//!
+//! The configs are parsed with `axvmconfig` as the hypervisor parses them, and the sections the
+//! hypervisor handles itself are read from the raw TOML. Each VM is summarized, then the set is
+//! checked as a whole for what the hypervisor would only find on the target, or not at all:
+//!
+//! - memory: guest physical regions of a VM overlapping each other (memory, emulated and
+//!   passthrough devices, the devices of the hypervisor), and host physical ranges owned by two
+//!   VMs (reserved memory, passthrough devices);
+//! - interrupts: an interrupt injected by two devices of a VM, or both injected and passed through,
+//!   and host interrupts passed through, or taken directly, by two VMs;
+//! - policies: peer handles naming VMs that are not in the set, managers and hypercall handlers
+//!   that are not manager VMs, vsock CIDs and passthrough consoles shared by two VMs;
+//! - CPUs: partitioned and gang VMs without a CPU of their own per vCPU, dedicated CPUs shared by
+//!   two VMs or leaving a VM without CPU and, with `--cpus`, CPUs the host doesn't have and
+//!   overcommitted VMs.
+//!
+//! The regions of `MAP_IDENTICAL` memory are placed at boot and the passthrough devices given by
+//! name only are resolved from the device tree at boot, neither is checked.
+
+use anyhow::{Context, Result, anyhow, bail};
+use axvmconfig::{AxVMCrateConfig, VmMemMappingType};
+use colored::*;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const PAGE_SIZE: usize = 0x1000;
+/// Slots of a virtio hot-plug window without `slots`.
+const DEFAULT_HOTPLUG_SLOTS: usize = 8;
+/// Offset of the default vsock CID from the VM ID.
+const VSOCK_CID_OFFSET: usize = 3;
+
+/// A VM of the set, one per instance of a config with `[scale]`.
+struct Vm {
+    file: PathBuf,
+    cfg: AxVMCrateConfig,
+    raw: toml::Table,
+}
+
+impl Vm {
+    fn id(&self) -> usize {
+        self.cfg.base.id
+    }
+
+    fn label(&self) -> String {
+        format!("VM[{}] {}", self.id(), self.cfg.base.name)
+    }
+
+    fn section(&self, name: &str) -> Option<&toml::Table> {
+        self.raw.get(name).and_then(|v| v.as_table())
+    }
+
+    /// The tables of an optional section, which may be a table or an array of tables.
+    fn entries(&self, name: &str) -> Vec<&toml::Table> {
+        match self.raw.get(name) {
+            Some(toml::Value::Table(table)) => vec![table],
+            Some(toml::Value::Array(entries)) => {
+                entries.iter().filter_map(|v| v.as_table()).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn is_manager(&self) -> bool {
+        self.section("manager")
+            .and_then(|cfg| cfg.get("define_vms"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false)
+    }
+
+    fn sched_mode(&self) -> &str {
+        self.section("scheduling")
+            .and_then(|cfg| cfg.get("mode"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("shared")
+    }
+
+    fn is_dedicated(&self) -> bool {
+        self.sched_mode() == "partitioned"
+            || self
+                .section("affinity")
+                .and_then(|cfg| cfg.get("dedicated"))
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false)
+    }
+
+    /// The physical CPU mask of each vCPU, 0 for any CPU.
+    fn cpu_masks(&self) -> Vec<u64> {
+        let affinity = self.section("affinity");
+        let phys_cpu_ids = self.cfg.base.phys_cpu_ids.as_deref().unwrap_or(&[]);
+        (0..self.cfg.base.cpu_num)
+            .map(|vcpu| {
+                let mask = affinity
+                    .and_then(|cfg| cfg.get(&format!("vcpu{vcpu}")))
+                    .and_then(|v| v.as_integer());
+                match (mask, phys_cpu_ids.get(vcpu)) {
+                    (Some(mask), _) => mask as u64,
+                    (None, Some(cpu)) => 1 << cpu,
+                    (None, None) => 0,
+                }
+            })
+            .collect()
+    }
+
+    /// Guest physical regions of the VM, `(base, size, what)`.
+    fn gpa_regions(&self) -> Vec<(usize, usize, String)> {
+        let mut regions = Vec::new();
+        for (idx, region) in self.cfg.kernel.memory_regions.iter().enumerate() {
+            if !matches!(region.map_type, VmMemMappingType::MapIdentical) {
+                regions.push((region.gpa, region.size, format!("memory region {idx}")));
+            }
+        }
+        for device in &self.cfg.devices.emu_devices {
+            let what = format!("emulated device {}", device.name);
+            regions.push((device.base_gpa, device.length, what));
+        }
+        for device in &self.cfg.devices.passthrough_devices {
+            if device.length > 0 {
+                let what = format!("passthrough device {}", device.name);
+                regions.push((device.base_gpa, device.length, what));
+            }
+        }
+        for kind in [
+            "virtio_console",
+            "virtio_net",
+            "virtio_blk",
+            "virtio_vsock",
+            "virtio_rng",
+            "virtio_vhost",
+            "power",
+        ] {
+            for entry in self.entries(kind) {
+                if let Some(base) = int(entry, "base") {
+                    regions.push((base, PAGE_SIZE, format!("[{kind}]")));
+                }
+            }
+        }
+        if let Some(cfg) = self.section("virtio_hotplug")
+            && let Some(base) = int(cfg, "base")
+        {
+            let slots = int(cfg, "slots").unwrap_or(DEFAULT_HOTPLUG_SLOTS);
+            regions.push((base, (slots + 1) * PAGE_SIZE, "[virtio_hotplug]".into()));
+        }
+        if let Some(cfg) = self.section("framebuffer")
+            && let (Some(base), Some(width), Some(height)) =
+                (int(cfg, "base"), int(cfg, "width"), int(cfg, "height"))
+        {
+            let stride = int(cfg, "stride").unwrap_or(width * 4);
+            let buffers = int(cfg, "buffers").unwrap_or(1);
+            let size = buffers * (stride * height).next_multiple_of(PAGE_SIZE);
+            regions.push((base, size, "[framebuffer]".into()));
+        }
+        for kind in ["lifecycle", "hypercall_handler", "shared_uart"] {
+            if let Some(gpa) = self.section(kind).and_then(|cfg| int(cfg, "gpa")) {
+                regions.push((gpa, PAGE_SIZE, format!("[{kind}]")));
+            }
+        }
+        regions
+    }
+
+    /// Interrupts injected by the emulated devices of the VM, `(irq, what)`.
+    fn injected_irqs(&self) -> Vec<(usize, String)> {
+        let mut irqs = Vec::new();
+        for device in &self.cfg.devices.emu_devices {
+            if device.irq_id != 0 {
+                irqs.push((device.irq_id, format!("emulated device {}", device.name)));
+            }
+        }
+        for kind in [
+            "virtio_console",
+            "virtio_net",
+            "virtio_blk",
+            "virtio_vsock",
+            "virtio_rng",
+            "virtio_vhost",
+            "power",
+            "framebuffer",
+            "watchdog",
+            "lifecycle",
+            "hypercall_handler",
+            "shared_uart",
+        ] {
+            for entry in self.entries(kind) {
+                if let Some(irq) = int(entry, "irq") {
+                    irqs.push((irq, format!("[{kind}]")));
+                }
+            }
+        }
+        if let Some(cfg) = self.section("virtio_hotplug")
+            && let Some(irq) = int(cfg, "irq")
+        {
+            let slots = int(cfg, "slots").unwrap_or(DEFAULT_HOTPLUG_SLOTS);
+            irqs.extend((irq..=irq + slots).map(|irq| (irq, "[virtio_hotplug]".into())));
+        }
+        irqs
+    }
+
+    /// Host interrupts passed through to the VM, `(irq, direct)`.
+    fn host_irqs(&self) -> Vec<(usize, bool)> {
+        let mut irqs: Vec<_> = self
+            .cfg
+            .devices
+            .passthrough_devices
+            .iter()
+            .filter(|device| device.irq_id != 0)
+            .map(|device| (device.irq_id, false))
+            .collect();
+        if let Some(direct) = self
+            .section("irq")
+            .and_then(|cfg| cfg.get("direct"))
+            .and_then(|v| v.as_array())
+        {
+            irqs.extend(
+                direct
+                    .iter()
+                    .filter_map(|v| v.as_integer())
+                    .map(|irq| (irq as usize, true)),
+            );
+        }
+        irqs
+    }
+}
+
+fn int(table: &toml::Table, key: &str) -> Option<usize> {
+    table
+        .get(key)
+        .and_then(|v| v.as_integer())
+        .filter(|v| *v >= 0)
+        .map(|v| v as usize)
+}
+
+fn overlaps((a, a_size): (usize, usize), (b, b_size): (usize, usize)) -> bool {
+    a < b.saturating_add(b_size) && b < a.saturating_add(a_size)
+}
+
+fn cpu_list(mask: u64) -> String {
+    let cpus: Vec<_> = (0..64).filter(|cpu| mask & (1 << cpu) != 0).collect();
+    format!("{cpus:?}")
+}
+
+/// The problems found, printed as they are reported.
+#[derive(Default)]
+struct Report {
+    errors: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn error(&mut self, msg: String) {
+        self.errors += 1;
+        println!("  {} {msg}", "error:".red().bold());
+    }
+
+    fn warn(&mut self, msg: String) {
+        self.warnings += 1;
+        println!("  {} {msg}", "warning:".yellow().bold());
+    }
+}
+
+pub fn run(configs: &[PathBuf], host_cpus: Option<usize>) -> Result<()> {
+    let mut vms = Vec::new();
+    for file in configs {
+        vms.extend(load(file)?);
+    }
+
+    println!("{}", "=== VMs ===".bold().cyan());
+    for vm in &vms {
+        summarize(vm);
+    }
+
+    println!("\n{}", "=== Checks ===".bold().cyan());
+    let mut report = Report::default();
+    check_ids(&vms, &mut report);
+    check_memory(&vms, &mut report);
+    check_irqs(&vms, &mut report);
+    check_policies(&vms, &mut report);
+    check_cpus(&vms, host_cpus, &mut report);
+
+    println!(
+        "\n{} VMs, {} errors, {} warnings",
+        vms.len(),
+        report.errors,
+        report.warnings
+    );
+    if report.errors > 0 {
+        bail!("the VM configs are not valid");
+    }
+    println!("{}", "✅ The VM configs are valid".bold().green());
+    Ok(())
+}
+
+/// Parses a config into its VMs, see `init_guest_vm` in `kernel/src/vmm/config.rs`.
+fn load(file: &Path) -> Result<Vec<Vm>> {
+    let text =
+        fs::read_to_string(file).with_context(|| format!("Failed to read {}", file.display()))?;
+    let cfg = AxVMCrateConfig::from_toml(&text)
+        .map_err(|e| anyhow!("{}: invalid VM config: {e:?}", file.display()))?;
+    let raw: toml::Table = text
+        .parse()
+        .with_context(|| format!("{}: invalid TOML", file.display()))?;
+    let instances = raw
+        .get("scale")
+        .and_then(|v| v.get("instances"))
+        .and_then(|v| v.as_integer())
+        .map_or(1, |v| v.max(1) as usize);
+    Ok((0..instances)
+        .map(|idx| {
+            let mut cfg = cfg.clone();
+            if idx > 0 {
+                cfg.base.id += idx;
+                cfg.base.name = format!("{}-{idx}", cfg.base.name);
+            }
+            Vm {
+                file: file.to_path_buf(),
+                cfg,
+                raw: raw.clone(),
+            }
+        })
+        .collect())
+}
+
+fn summarize(vm: &Vm) {
+    let memory: usize = vm.cfg.kernel.memory_regions.iter().map(|r| r.size).sum();
+    let masks = vm.cpu_masks();
+    let cpus = masks.iter().fold(0, |cpus, mask| cpus | mask);
+    println!(
+        "{} ({})",
+        vm.label().bold(),
+        vm.file.display().to_string().dimmed()
+    );
+    println!(
+        "  {} vCPUs on CPUs {}, {} scheduling{}",
+        vm.cfg.base.cpu_num,
+        if cpus == 0 { "any".into() } else { cpu_list(cpus) },
+        vm.sched_mode(),
+        if vm.is_dedicated() { ", dedicated" } else { "" }
+    );
+    println!(
+        "  {:#x} bytes of memory in {} regions, {} emulated and {} passthrough devices",
+        memory,
+        vm.cfg.kernel.memory_regions.len(),
+        vm.cfg.devices.emu_devices.len(),
+        vm.cfg.devices.passthrough_devices.len()
+    );
+    let injected: Vec<_> = vm.injected_irqs().into_iter().map(|(irq, _)| irq).collect();
+    let host: Vec<_> = vm.host_irqs().into_iter().map(|(irq, _)| irq).collect();
+    if !injected.is_empty() || !host.is_empty() {
+        println!("  interrupts: injected {injected:#x?}, passed through {host:#x?}");
+    }
+    if vm.is_manager() {
+        println!("  manager VM");
+    }
+}
+
+fn check_ids(vms: &[Vm], report: &mut Report) {
+    let mut ids = BTreeMap::new();
+    let mut names = BTreeMap::new();
+    for vm in vms {
+        if let Some(other) = ids.insert(vm.id(), vm) {
+            report.error(format!("{} and {} have the same ID", other.label(), vm.label()));
+        }
+        if let Some(other) = names.insert(vm.cfg.base.name.as_str(), vm) {
+            report.error(format!("{} and {} have the same name", other.label(), vm.label()));
+        }
+    }
+}
+
+fn check_memory(vms: &[Vm], report: &mut Report) {
+    for vm in vms {
+        let regions = vm.gpa_regions();
+        for (i, (base, size, what)) in regions.iter().enumerate() {
+            if *size == 0 {
+                report.error(format!("{}: {what} is empty", vm.label()));
+            }
+            for (other_base, other_size, other) in &regions[i + 1..] {
+                if overlaps((*base, *size), (*other_base, *other_size)) {
+                    report.error(format!(
+                        "{}: {what} [{base:#x}, {:#x}) overlaps {other} [{other_base:#x}, {:#x})",
+                        vm.label(),
+                        base + size,
+                        other_base + other_size
+                    ));
+                }
+            }
+        }
+    }
+
+    // Host physical ranges, reserved memory being mapped at the same address.
+    let owned: Vec<_> = vms
+        .iter()
+        .flat_map(|vm| {
+            let reserved = vm
+                .cfg
+                .kernel
+                .memory_regions
+                .iter()
+                .filter(|r| matches!(r.map_type, VmMemMappingType::MapReserved))
+                .map(move |r| (vm, r.gpa, r.size, "reserved memory".to_string()));
+            let devices = vm
+                .cfg
+                .devices
+                .passthrough_devices
+                .iter()
+                .filter(|d| d.length > 0)
+                .map(move |d| (vm, d.base_hpa, d.length, format!("device {}", d.name)));
+            reserved.chain(devices)
+        })
+        .collect();
+    for (i, (vm, base, size, what)) in owned.iter().enumerate() {
+        for (other_vm, other_base, other_size, other) in &owned[i + 1..] {
+            if vm.id() != other_vm.id() && overlaps((*base, *size), (*other_base, *other_size)) {
+                report.error(format!(
+                    "{} {what} and {} {other} share host memory at {:#x}",
+                    vm.label(),
+                    other_vm.label(),
+                    base.max(other_base)
+                ));
+            }
+        }
+    }
+}
+
+fn check_irqs(vms: &[Vm], report: &mut Report) {
+    let mut host_owners: BTreeMap<usize, &Vm> = BTreeMap::new();
+    let power_button = vms
+        .iter()
+        .find_map(|vm| vm.section("shutdown").and_then(|cfg| int(cfg, "power_button_irq")));
+    for vm in vms {
+        let injected = vm.injected_irqs();
+        for (i, (irq, what)) in injected.iter().enumerate() {
+            if let Some((_, other)) = injected[i + 1..].iter().find(|(o, _)| o == irq) {
+                report.error(format!(
+                    "{}: interrupt {irq:#x} is injected by {what} and {other}",
+                    vm.label()
+                ));
+            }
+        }
+        for (irq, direct) in vm.host_irqs() {
+            if let Some((_, what)) = injected.iter().find(|(o, _)| *o == irq) {
+                report.error(format!(
+                    "{}: interrupt {irq:#x} is both passed through and injected by {what}",
+                    vm.label()
+                ));
+            }
+            if power_button == Some(irq) {
+                report.error(format!(
+                    "{}: interrupt {irq:#x} is the power button of the hypervisor",
+                    vm.label()
+                ));
+            }
+            if direct && vm.sched_mode() != "partitioned" {
+                report.error(format!(
+                    "{}: direct interrupt {irq:#x} needs partitioned scheduling",
+                    vm.label()
+                ));
+            }
+            match host_owners.insert(irq, vm) {
+                Some(other) if other.id() != vm.id() => report.error(format!(
+                    "interrupt {irq:#x} is passed through to {} and {}",
+                    other.label(),
+                    vm.label()
+                )),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Returns the VM peer handle `handle` of `vm` refers to, `Err` with the target if it's not in
+/// the set, see `kernel/src/vmm/peers.rs`.
+fn resolve<'a>(vms: &'a [Vm], vm: &Vm, handle: usize) -> Result<&'a Vm, String> {
+    let target = vm
+        .section("peers")
+        .map(|peers| peers.get(&format!("peer{handle}")));
+    match target {
+        None => vms
+            .iter()
+            .find(|other| other.id() == handle)
+            .ok_or(format!("VM[{handle}]")),
+        Some(Some(toml::Value::Integer(id))) => vms
+            .iter()
+            .find(|other| other.id() as i64 == *id)
+            .ok_or(format!("VM[{id}]")),
+        Some(Some(toml::Value::String(name))) => vms
+            .iter()
+            .find(|other| other.cfg.base.name == *name)
+            .ok_or(format!("VM {name:?}")),
+        Some(_) => Err(format!("undeclared peer{handle}")),
+    }
+}
+
+fn check_policies(vms: &[Vm], report: &mut Report) {
+    for vm in vms {
+        if let Some(peers) = vm.section("peers") {
+            for (key, value) in peers {
+                let missing = match value {
+                    toml::Value::Integer(id) => !vms.iter().any(|o| o.id() as i64 == *id),
+                    toml::Value::String(name) if name.contains('@') => false,
+                    toml::Value::String(name) => !vms.iter().any(|o| o.cfg.base.name == *name),
+                    _ => {
+                        report.error(format!("{}: `{key}` must be a VM ID or name", vm.label()));
+                        continue;
+                    }
+                };
+                if missing {
+                    report.warn(format!(
+                        "{}: {key} = {value} is not in the set, it must be defined at runtime",
+                        vm.label()
+                    ));
+                }
+            }
+        }
+
+        for section in ["watchdog", "reboot", "crash"] {
+            let Some(handle) = vm.section(section).and_then(|cfg| int(cfg, "manager")) else {
+                continue;
+            };
+            match resolve(vms, vm, handle) {
+                Ok(manager) if !manager.is_manager() => report.warn(format!(
+                    "{}: the manager of [{section}], {}, is not a manager VM",
+                    vm.label(),
+                    manager.label()
+                )),
+                Ok(_) => {}
+                Err(target) => report.warn(format!(
+                    "{}: the manager of [{section}], {target}, is not in the set",
+                    vm.label()
+                )),
+            }
+        }
+
+        let hypercalls = vm.section("hypercalls");
+        let forward = hypercalls
+            .and_then(|cfg| cfg.get("unknown"))
+            .and_then(|v| v.as_str())
+            == Some("forward");
+        if forward {
+            match hypercalls.and_then(|cfg| int(cfg, "handler")) {
+                None => report.error(format!("{}: forwarding needs a `handler`", vm.label())),
+                Some(handle) => match resolve(vms, vm, handle) {
+                    Ok(handler) if handler.section("hypercall_handler").is_none() => {
+                        report.error(format!(
+                            "{}: {} has no [hypercall_handler]",
+                            vm.label(),
+                            handler.label()
+                        ))
+                    }
+                    Ok(_) => {}
+                    Err(target) => report.warn(format!(
+                        "{}: the hypercall handler, {target}, is not in the set",
+                        vm.label()
+                    )),
+                },
+            }
+        }
+        if vm.section("hypercall_handler").is_some() && !vm.is_manager() {
+            report.error(format!(
+                "{}: only manager VMs may handle hypercalls",
+                vm.label()
+            ));
+        }
+    }
+
+    let mut cids = BTreeMap::new();
+    let mut consoles = BTreeMap::new();
+    for vm in vms {
+        if let Some(cfg) = vm.section("virtio_vsock") {
+            let cid = int(cfg, "cid").unwrap_or(vm.id() + VSOCK_CID_OFFSET);
+            if let Some(other) = cids.insert(cid, vm) {
+                report.error(format!(
+                    "{} and {} have the same vsock CID {cid}",
+                    other.label(),
+                    vm.label()
+                ));
+            }
+        }
+        let stdout = vm
+            .section("dtb_builder")
+            .and_then(|cfg| cfg.get("stdout_path"))
+            .and_then(|v| v.as_str());
+        if let Some(stdout) = stdout
+            && let Some(other) = consoles.insert(stdout, vm)
+        {
+            report.warn(format!(
+                "{} and {} share the console {stdout}, their output interleaves",
+                other.label(),
+                vm.label()
+            ));
+        }
+    }
+}
+
+fn check_cpus(vms: &[Vm], host_cpus: Option<usize>, report: &mut Report) {
+    let mut dedicated: Vec<(&Vm, u64)> = Vec::new();
+    let mut gangs: Vec<(&Vm, u64)> = Vec::new();
+    for vm in vms {
+        let masks = vm.cpu_masks();
+        let cpus = masks.iter().fold(0, |cpus, mask| cpus | mask);
+        if let Some(ids) = &vm.cfg.base.phys_cpu_ids
+            && ids.len() != vm.cfg.base.cpu_num
+        {
+            report.warn(format!(
+                "{}: {} `phys_cpu_ids` for {} vCPUs",
+                vm.label(),
+                ids.len(),
+                vm.cfg.base.cpu_num
+            ));
+        }
+        if let Some(host_cpus) = host_cpus {
+            let missing = cpus & u64::MAX.checked_shl(host_cpus as u32).unwrap_or(0);
+            if missing != 0 {
+                report.error(format!(
+                    "{}: the host has no CPUs {}",
+                    vm.label(),
+                    cpu_list(missing)
+                ));
+            }
+        }
+        let mode = vm.sched_mode();
+        if mode == "partitioned" || mode == "gang" {
+            let pinned = masks.iter().all(|mask| mask.count_ones() == 1)
+                && cpus.count_ones() as usize == masks.len();
+            if !pinned {
+                report.error(format!(
+                    "{}: each vCPU of a {mode} VM needs a CPU of its own",
+                    vm.label()
+                ));
+            }
+        }
+        if mode == "gang" {
+            gangs.push((vm, cpus));
+        }
+        if vm.is_dedicated() {
+            if masks.contains(&0) {
+                report.error(format!(
+                    "{}: a dedicated VM needs a CPU for every vCPU",
+                    vm.label()
+                ));
+            }
+            dedicated.push((vm, cpus));
+        }
+    }
+
+    for (i, (vm, cpus)) in dedicated.iter().enumerate() {
+        for (other, other_cpus) in &dedicated[i + 1..] {
+            if cpus & other_cpus != 0 {
+                report.error(format!(
+                    "{} and {} both have CPUs {} dedicated",
+                    vm.label(),
+                    other.label(),
+                    cpu_list(cpus & other_cpus)
+                ));
+            }
+        }
+    }
+    for (i, (vm, cpus)) in gangs.iter().enumerate() {
+        for (other, other_cpus) in &gangs[i + 1..] {
+            if cpus & other_cpus != 0 {
+                report.error(format!(
+                    "gangs {} and {} share CPUs {}",
+                    vm.label(),
+                    other.label(),
+                    cpu_list(cpus & other_cpus)
+                ));
+            }
+        }
+    }
+
+    // The CPUs of a dedicated VM are removed from the masks of the other VMs.
+    let all_dedicated = dedicated.iter().fold(0, |all, (_, cpus)| all | cpus);
+    let mut shared_vcpus = 0;
+    for vm in vms.iter().filter(|vm| !vm.is_dedicated()) {
+        shared_vcpus += vm.cfg.base.cpu_num;
+        for (vcpu, mask) in vm.cpu_masks().into_iter().enumerate() {
+            if mask != 0 && mask & !all_dedicated == 0 {
+                report.error(format!(
+                    "{}: VCpu[{vcpu}] only runs on CPUs {} dedicated to other VMs",
+                    vm.label(),
+                    cpu_list(mask)
+                ));
+            }
+        }
+    }
+    if let Some(host_cpus) = host_cpus {
+        let free = host_cpus.saturating_sub(all_dedicated.count_ones() as usize);
+        if free == 0 && shared_vcpus > 0 {
+            report.error(format!(
+                "all {host_cpus} CPUs are dedicated, {shared_vcpus} shared vCPUs have none left"
+            ));
+        } else if shared_vcpus > free {
+            report.warn(format!(
+                "{shared_vcpus} shared vCPUs on {free} CPUs, the shared VMs are overcommitted"
+            ));
+        }
+    }
+}
@@ -9,11 +9,13 @@ use std::fs;
 use std::path::{Path, PathBuf};
 
 mod cargo;
+mod check_config;
 mod clippy;
 mod ctx;
 mod devspace;
 mod image;
 mod menuconfig;
+mod panic_decode;
 mod tbuld;
 mod vmconfig;
 
@@ -48,6 +50,20 @@ enum Commands {
     Image(image::ImageArgs),
     /// Manage local devspace dependencies
     Devspace(DevspaceArgs),
+    /// Decode the panic reports in a serial log of the hypervisor
+    PanicDecode {
+        /// Path to the serial log
+        log: PathBuf,
+    },
+    /// Validate a set of VM configs without starting them
+    CheckConfig {
+        /// Paths to the VM configs
+        #[arg(required = true)]
+        configs: Vec<PathBuf>,
+        /// Number of host CPUs, to check CPU IDs and oversubscription
+        #[arg(long)]
+        cpus: Option<usize>,
+    },
 }
 
 #[derive(Parser)]
@@ -179,6 +195,12 @@ async fn main() -> Result<()> {
             DevspaceCommand::Start => devspace::start()?,
             DevspaceCommand::Stop => devspace::stop()?,
         },
+        Commands::PanicDecode { log } => {
+            panic_decode::run(&log)?;
+        }
+        Commands::CheckConfig { configs, cpus } => {
+            check_config::run(&configs, cpus)?;
+        }
     }
 
     Ok(())
@@ -0,0 +1,258 @@
+//! Decoding of the panic reports found in a serial log of axvisor.
+//!
+//! The hypervisor ends a panic with a base64 blob of a CBOR report between
+//! `-----BEGIN AXVISOR PANIC REPORT-----` and `-----END AXVISOR PANIC REPORT-----`, see
+//! `kernel/src/vmm/panic_report.rs` for its layout. Each report of the log is printed as JSON.
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde_json::{Map, Value, json};
+use std::fs;
+use std::path::Path;
+
+const BEGIN: &str = "-----BEGIN AXVISOR PANIC REPORT-----";
+const END: &str = "-----END AXVISOR PANIC REPORT-----";
+
+/// Names of the fields of the entries of the CPUs, key 5 of the report.
+const CPU_FIELDS: &[&str] = &[
+    "cpu",
+    "vm",
+    "vcpu",
+    "in_guest",
+    "last_exit",
+    "scratch0",
+    "scratch1",
+    "entries",
+    "exits",
+    "inconsistencies",
+];
+/// Names of the fields of the trace events, in key 6 of the report.
+const EVENT_FIELDS: &[&str] = &["age_us", "kind", "vm", "vcpu", "exit"];
+const EVENT_KINDS: &[&str] = &["heartbeat", "entry", "exit"];
+
+pub fn run(log: &Path) -> Result<()> {
+    let text =
+        fs::read_to_string(log).with_context(|| format!("Failed to read {}", log.display()))?;
+    let blobs = find_blobs(&text);
+    if blobs.is_empty() {
+        bail!("No panic report found in {}", log.display());
+    }
+    for (i, blob) in blobs.iter().enumerate() {
+        let bytes = decode_base64(blob).with_context(|| format!("Report {i}: invalid base64"))?;
+        let mut cbor = Cbor::new(&bytes);
+        let report = cbor
+            .value()
+            .with_context(|| format!("Report {i}: invalid CBOR"))?;
+        println!("{}", serde_json::to_string_pretty(&name_fields(report)?)?);
+    }
+    Ok(())
+}
+
+/// Returns the base64 blobs of the log, without their line breaks.
+fn find_blobs(text: &str) -> Vec<String> {
+    let mut blobs = Vec::new();
+    let mut blob: Option<String> = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.ends_with(BEGIN) {
+            blob = Some(String::new());
+        } else if line.ends_with(END) {
+            blobs.extend(blob.take());
+        } else if let Some(blob) = blob.as_mut() {
+            blob.push_str(line);
+        }
+    }
+    blobs
+}
+
+fn decode_base64(blob: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut word = 0u32;
+    let mut bits = 0;
+    for c in blob.bytes().filter(|c| *c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => bail!("unexpected character {:?}", c as char),
+        };
+        word = word << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((word >> bits) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+/// A decoder of the CBOR subset written by the hypervisor.
+struct Cbor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cbor<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let byte = *self
+            .bytes
+            .get(self.pos)
+            .ok_or_else(|| anyhow!("truncated at byte {}", self.pos))?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn argument(&mut self, info: u8) -> Result<Option<u64>> {
+        let len = match info {
+            0..=23 => return Ok(Some(info as u64)),
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            31 => return Ok(None),
+            _ => bail!("invalid additional information {info}"),
+        };
+        let mut value = 0;
+        for _ in 0..len {
+            value = value << 8 | self.byte()? as u64;
+        }
+        Ok(Some(value))
+    }
+
+    fn is_break(&self) -> bool {
+        self.bytes.get(self.pos) == Some(&0xff)
+    }
+
+    /// Decodes the items of an array or a map, of length `len` or up to a break if `None`.
+    fn items(
+        &mut self,
+        len: Option<u64>,
+        mut f: impl FnMut(&mut Self) -> Result<()>,
+    ) -> Result<()> {
+        match len {
+            Some(len) => (0..len).try_for_each(|_| f(self)),
+            None => {
+                while !self.is_break() {
+                    f(self)?;
+                }
+                self.pos += 1;
+                Ok(())
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        let initial = self.byte()?;
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        let arg = self.argument(info)?;
+        match (major, arg) {
+            (0, Some(value)) => Ok(json!(value)),
+            (3, Some(len)) => {
+                let end = self.pos + len as usize;
+                let text = self
+                    .bytes
+                    .get(self.pos..end)
+                    .ok_or_else(|| anyhow!("truncated text at byte {}", self.pos))?;
+                self.pos = end;
+                Ok(json!(String::from_utf8_lossy(text)))
+            }
+            (4, len) => {
+                let mut items = Vec::new();
+                self.items(len, |cbor| Ok(items.push(cbor.value()?)))?;
+                Ok(Value::Array(items))
+            }
+            (5, len) => {
+                let mut map = Map::new();
+                self.items(len, |cbor| {
+                    let key = match cbor.value()? {
+                        Value::String(key) => key,
+                        key => key.to_string(),
+                    };
+                    map.insert(key, cbor.value()?);
+                    Ok(())
+                })?;
+                Ok(Value::Object(map))
+            }
+            (7, Some(20)) => Ok(json!(false)),
+            (7, Some(21)) => Ok(json!(true)),
+            (7, Some(22)) => Ok(Value::Null),
+            _ => bail!("unexpected item {initial:#04x} at byte {}", self.pos - 1),
+        }
+    }
+}
+
+/// Names the fields of an array of values.
+fn named(fields: &[&str], value: &Value) -> Value {
+    match value {
+        Value::Array(items) => Value::Object(
+            fields
+                .iter()
+                .zip(items)
+                .map(|(field, item)| (field.to_string(), item.clone()))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+/// Names the fields of the report, see `kernel/src/vmm/panic_report.rs`.
+fn name_fields(report: Value) -> Result<Value> {
+    let Value::Object(report) = report else {
+        bail!("the report is not a map");
+    };
+    let get = |key: &str| report.get(key).cloned().unwrap_or(Value::Null);
+    let version = get("0");
+    if version != json!(1) {
+        bail!("unsupported report version {version}");
+    }
+    let cpus: Vec<Value> = get("5")
+        .as_array()
+        .map(|cpus| cpus.iter().map(|cpu| named(CPU_FIELDS, cpu)).collect())
+        .unwrap_or_default();
+    let trace: Vec<Value> = get("6")
+        .as_array()
+        .map(|rings| {
+            rings
+                .iter()
+                .map(|ring| {
+                    let events: Vec<Value> = ring
+                        .get(1)
+                        .and_then(Value::as_array)
+                        .map(|events| {
+                            events
+                                .iter()
+                                .map(|event| {
+                                    let mut event = named(EVENT_FIELDS, event);
+                                    if let Some(kind) = event.get_mut("kind") {
+                                        let name = kind
+                                            .as_u64()
+                                            .and_then(|kind| EVENT_KINDS.get(kind as usize));
+                                        if let Some(name) = name {
+                                            *kind = json!(name);
+                                        }
+                                    }
+                                    event
+                                })
+                                .collect()
+                        })
+                        .unwrap_or_default();
+                    json!({ "cpu": ring.get(0).cloned(), "events": events })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(json!({
+        "message": get("1"),
+        "location": get("2"),
+        "uptime_ns": get("3"),
+        "cpu": get("4"),
+        "cpus": cpus,
+        "trace": trace,
+        "truncated": get("7").as_bool().unwrap_or(false),
+    }))
+}